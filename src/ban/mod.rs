@@ -0,0 +1,258 @@
+//! ban keeps track of peers we refuse to talk to.  The list is saved to the state directory so
+//! bans survive restarts.  Peers that keep sending data that fails hash checks are scored here
+//! too, so they can be banned.
+use crate::boostencode::{DecodeError, FromValue, Value};
+use derive_error::Error;
+use log::warn;
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Points against a peer for sending every block of a piece that failed its hash check
+const SOLE_FAILURE_POINTS: u32 = 3;
+
+/// Points against a peer for sending some of the blocks of a piece that failed.  Any of the
+/// other peers could be to blame
+const SHARED_FAILURE_POINTS: u32 = 1;
+
+/// Peers are banned once they have this many points
+const BAN_POINTS: u32 = 6;
+
+#[derive(Debug, Error)]
+pub enum BanListError {
+    /// The ban list file could not be read or written
+    Io(io::Error),
+    /// The ban list file could not be bdecoded
+    DecodeError(DecodeError),
+    /// The contents of the ban list file are not correct
+    #[error(non_std, no_from)]
+    InvalidBanList(String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ban {
+    // The address of the banned peer
+    pub ip: IpAddr,
+    // Why the peer was banned
+    pub reason: String,
+    // The UNIX epoch timestamp of when the ban is lifted.  None means the ban is permanent
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut map = hashmap! {
+            Vec::from("ip") => Value::BString(Vec::from(self.ip.to_string())),
+            Vec::from("reason") => Value::BString(Vec::from(self.reason.as_bytes())),
+        };
+        if let Some(expires) = self.expires {
+            map.insert(Vec::from("expires"), Value::Integer(expires as i64));
+        }
+        Value::Dict(map)
+    }
+}
+
+impl FromValue for Ban {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Ban not a dictionary".to_string())?;
+
+        let ip = map.get("ip".as_bytes()).and_then(Value::bstring_utf8)
+            .map(|s| s.parse())
+            .ok_or("Missing key: ip".to_string())?
+            .map_err(|_| "Invalid ip addr".to_string())?;
+
+        let reason = map.get("reason".as_bytes()).and_then(Value::bstring_utf8)
+            .unwrap_or_default();
+
+        let expires = map.get("expires".as_bytes()).and_then(Value::integer)
+            .map(|i| *i as u64);
+
+        Ok(Ban {
+            ip,
+            reason,
+            expires,
+        })
+    }
+}
+
+/// The set of banned peers, backed by a file
+pub struct BanList {
+    // Where the list is saved.  None if the list only lives in memory
+    path: Option<PathBuf>,
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    /// Create an empty ban list that is never saved
+    pub fn new() -> Self {
+        BanList {
+            path: None,
+            bans: Vec::new(),
+        }
+    }
+
+    /// Load the ban list saved at `path`, dropping any bans that have expired since.  A missing
+    /// file is treated as an empty list.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BanListError> {
+        let path = path.as_ref().to_path_buf();
+        let bans = match fs::read(&path) {
+            Ok(bytes) => {
+                let val = Value::decode(&bytes)?;
+                val.dict()
+                    .and_then(|map| map.get("bans".as_bytes()))
+                    .and_then(Value::list)
+                    .ok_or(BanListError::InvalidBanList("Missing key: bans".to_string()))?
+                    .iter()
+                    .map(Ban::from_value)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(BanListError::InvalidBanList)?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(BanListError::Io(e)),
+        };
+
+        let mut list = BanList {
+            path: Some(path),
+            bans,
+        };
+        list.prune();
+        Ok(list)
+    }
+
+    /// Write the ban list to disk
+    pub fn save(&self) -> Result<(), BanListError> {
+        match &self.path {
+            Some(path) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(path, self.to_value().encode())?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Ban a peer.  A `duration` of None bans the peer permanently.  The list is saved right away
+    /// so the ban isn't lost if we crash.
+    pub fn ban(&mut self, ip: IpAddr, reason: &str, duration: Option<Duration>) {
+        let expires = duration.map(|d| now() + d.as_secs());
+        self.bans.retain(|b| b.ip != ip);
+        self.bans.push(Ban {
+            ip,
+            reason: reason.to_owned(),
+            expires,
+        });
+        if let Err(e) = self.save() {
+            warn!("Could not save the ban list: {:?}", e);
+        }
+    }
+
+    /// Lift the ban on a peer
+    pub fn unban(&mut self, ip: IpAddr) {
+        self.bans.retain(|b| b.ip != ip);
+        if let Err(e) = self.save() {
+            warn!("Could not save the ban list: {:?}", e);
+        }
+    }
+
+    /// Returns the ban on this address, if there is one that hasn't expired
+    pub fn get(&self, ip: IpAddr) -> Option<&Ban> {
+        let now = now();
+        self.bans.iter().find(|b| b.ip == ip && !b.is_expired(now))
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.get(ip).is_some()
+    }
+
+    /// Remove all expired bans
+    pub fn prune(&mut self) {
+        let now = now();
+        self.bans.retain(|b| !b.is_expired(now));
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Dict(hashmap! {
+            Vec::from("bans") => Value::List(self.bans.iter().map(Ban::to_value).collect()),
+        })
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        BanList::new()
+    }
+}
+
+/// Scores peers by the bad data they send.  Failed pieces count against every peer that sent part
+/// of them, and good pieces win a point back, so a peer caught up in the odd bad piece isn't
+/// banned along with the one that sent the bad block
+#[derive(Default)]
+pub struct BadData {
+    points: HashMap<IpAddr, u32>,
+}
+
+impl BadData {
+    pub fn new() -> Self {
+        BadData::default()
+    }
+
+    /// A piece with blocks from `sources` failed its hash check.  Returns the peers that should
+    /// be banned for it, who are forgotten
+    pub fn failed(&mut self, sources: &[IpAddr]) -> Vec<IpAddr> {
+        let points = if sources.len() == 1 { SOLE_FAILURE_POINTS } else { SHARED_FAILURE_POINTS };
+        let mut banned = Vec::new();
+        for ip in sources {
+            let total = self.points.entry(*ip).or_insert(0);
+            *total += points;
+            if *total >= BAN_POINTS {
+                banned.push(*ip);
+            }
+        }
+        for ip in &banned {
+            self.points.remove(ip);
+        }
+        banned
+    }
+
+    /// A piece with blocks from `sources` was good
+    pub fn passed(&mut self, sources: &[IpAddr]) {
+        for ip in sources {
+            if let Some(total) = self.points.get_mut(ip) {
+                *total -= 1;
+                if *total == 0 {
+                    self.points.remove(ip);
+                }
+            }
+        }
+    }
+
+    pub fn points(&self, ip: IpAddr) -> u32 {
+        self.points.get(&ip).cloned().unwrap_or(0)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
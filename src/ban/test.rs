@@ -0,0 +1,51 @@
+use std::env;
+use super::*;
+
+#[test]
+fn test_ban_expires() {
+    let ip: IpAddr = [10, 0, 0, 1].into();
+    let mut list = BanList::new();
+    list.ban(ip, "sent bad data", Some(Duration::from_secs(60)));
+    assert!(list.is_banned(ip));
+    assert!(!list.is_banned([10, 0, 0, 2].into()));
+
+    list.bans[0].expires = Some(now() - 1);
+    assert!(!list.is_banned(ip));
+    list.prune();
+    assert!(list.bans.is_empty());
+}
+
+#[test]
+fn test_ban_round_trip() {
+    let path = env::temp_dir().join(format!("boosttorrent2-test-bans-{}", now()));
+    let ip: IpAddr = [10, 0, 0, 1].into();
+    {
+        let mut list = BanList::load(&path).unwrap();
+        list.ban(ip, "sent bad data", None);
+        list.ban([10, 0, 0, 2].into(), "expired", Some(Duration::from_secs(0)));
+    }
+
+    let list = BanList::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(list.bans, vec![Ban {
+        ip,
+        reason: "sent bad data".to_string(),
+        expires: None,
+    }]);
+}
+
+#[test]
+fn test_bad_data() {
+    let bad: IpAddr = [10, 0, 0, 1].into();
+    let unlucky: IpAddr = [10, 0, 0, 2].into();
+    let mut scores = BadData::new();
+    assert!(scores.failed(&[bad, unlucky]).is_empty());
+    assert!(scores.failed(&[bad]).is_empty());
+    assert_eq!((scores.points(bad), scores.points(unlucky)), (4, 1));
+    // Good pieces win points back
+    scores.passed(&[unlucky]);
+    assert_eq!(scores.points(unlucky), 0);
+    assert!(scores.failed(&[bad, unlucky]).is_empty());
+    assert_eq!(scores.failed(&[bad, unlucky]), vec![bad]);
+    assert_eq!((scores.points(bad), scores.points(unlucky)), (0, 2));
+}
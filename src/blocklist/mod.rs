@@ -0,0 +1,230 @@
+//! blocklist keeps peers in ranges of addresses we never talk to, loaded from an eMule
+//! (ipfilter.dat) or PeerGuardian (.p2p) list.  Lists may be gzipped, and can be loaded again
+//! while we run, so a list that is updated by a cron job takes effect without a restart.
+use derive_error::Error;
+use flate2::read::GzDecoder;
+use log::warn;
+use std::fs;
+use std::io::{
+    self,
+    Read,
+};
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::{
+    Arc,
+    RwLock,
+};
+
+#[cfg(test)]
+mod test;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// eMule lists give each range an access level, and ranges below this are blocked
+const EMULE_BLOCK_LEVEL: u32 = 128;
+
+#[derive(Debug, Error)]
+pub enum BlocklistError {
+    /// The list could not be read
+    Io(io::Error),
+    /// Not a single range in the list could be understood
+    #[error(non_std, no_from)]
+    InvalidList(String),
+}
+
+/// Sorted, non-overlapping ranges of blocked addresses, with the ends included.  IPv4 addresses
+/// are kept apart from IPv6 ones, rather than mapped into them
+#[derive(Debug, Default, PartialEq)]
+pub struct IpFilter {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpFilter {
+    /// Parse a list.  Each line is either eMule format:
+    ///
+    ///   001.009.096.105 - 001.009.096.105 , 000 , Some organisation
+    ///
+    /// or PeerGuardian format:
+    ///
+    ///   Some organisation:1.9.96.105-1.9.96.105
+    ///
+    /// Comments start with # or //.  Lines that can't be understood are skipped, and their count
+    /// is returned with the filter
+    pub fn parse(text: &str) -> (Self, usize) {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        let mut skipped = 0;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some((IpAddr::V4(start), IpAddr::V4(end)))) =>
+                    v4.push((u32::from(start) as u128, u32::from(end) as u128)),
+                Some(Some((IpAddr::V6(start), IpAddr::V6(end)))) =>
+                    v6.push((u128::from(start), u128::from(end))),
+                // A range the list allows
+                Some(None) => (),
+                _ => skipped += 1,
+            }
+        }
+        (IpFilter { v4: merge(v4), v6: merge(v6) }, skipped)
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(ip) as u128),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip) as u128),
+                None => contains(&self.v6, u128::from(ip)),
+            },
+        }
+    }
+
+    /// How many ranges are blocked, once overlapping ones are merged
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A blocked range from one line of a list.  Some(None) if the line is a range the list allows,
+/// and None if the line can't be understood
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    let (range, level) = if line.contains(',') {
+        let mut fields = line.split(',');
+        let range = fields.next()?;
+        let level = match fields.next() {
+            Some(level) => level.trim().parse::<u32>().ok()?,
+            None => 0,
+        };
+        (range, level)
+    } else {
+        // The description can have colons in it, but IPv4 addresses can't
+        (&line[line.rfind(':')? + 1..], 0)
+    };
+    let mut ends = range.splitn(2, '-');
+    let start = parse_ip(ends.next()?)?;
+    let end = parse_ip(ends.next()?)?;
+    if start.is_ipv4() != end.is_ipv4() || start > end {
+        return None;
+    }
+    if level >= EMULE_BLOCK_LEVEL {
+        return Some(None);
+    }
+    Some(Some((start, end)))
+}
+
+/// eMule lists pad IPv4 addresses with zeroes, which the standard parser doesn't allow
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    if ip.contains(':') {
+        return ip.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let octets: Vec<u8> = ip.split('.').map(|octet| octet.parse().ok()).collect::<Option<_>>()?;
+    match octets.as_slice() {
+        [a, b, c, d] => Some(IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d))),
+        _ => None,
+    }
+}
+
+/// Sort ranges and join the ones that overlap or touch
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn contains(ranges: &[(u128, u128)], ip: u128) -> bool {
+    let after = ranges.partition_point(|(start, _)| *start <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+/// The blocklist the client runs with.  Clones share the same list, so loading it again changes
+/// it for every torrent
+#[derive(Clone, Default)]
+pub struct Blocklist {
+    // Where the list is loaded from.  None if no list is in use
+    path: Option<PathBuf>,
+    filter: Arc<RwLock<IpFilter>>,
+}
+
+impl Blocklist {
+    /// A blocklist that blocks nothing
+    pub fn new() -> Self {
+        Blocklist::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BlocklistError> {
+        let list = Blocklist {
+            path: Some(path.as_ref().to_path_buf()),
+            filter: Arc::new(RwLock::new(IpFilter::default())),
+        };
+        list.reload()?;
+        Ok(list)
+    }
+
+    /// Read the list from its file again.  Returns how many ranges it blocks.  If the file can't
+    /// be read, the old list stays in use
+    pub fn reload(&self) -> Result<usize, BlocklistError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let bytes = fs::read(path)?;
+        let bytes = if bytes.starts_with(GZIP_MAGIC) {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed)?;
+            decompressed
+        } else {
+            bytes
+        };
+        // Descriptions aren't always UTF-8, and they don't matter
+        let (filter, skipped) = IpFilter::parse(&String::from_utf8_lossy(&bytes));
+        if filter.is_empty() && skipped > 0 {
+            return Err(BlocklistError::InvalidList(format!("None of the {} lines in {} are ranges",
+                                                           skipped, path.display())));
+        }
+        if skipped > 0 {
+            warn!("Skipped {} lines of {} that aren't ranges", skipped, path.display());
+        }
+        let ranges = filter.len();
+        *self.filter.write().unwrap() = filter;
+        Ok(ranges)
+    }
+
+    /// True if a list is in use, so it can be reloaded
+    pub fn is_loaded(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.filter.read().unwrap().is_blocked(ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.filter.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,67 @@
+use flate2::{
+    write::GzEncoder,
+    Compression,
+};
+use std::env;
+use std::io::Write;
+use super::*;
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+fn test_parse_emule() {
+    let (filter, skipped) = IpFilter::parse("# comment\n\
+        001.009.096.105 - 001.009.096.110 , 000 , Some organisation\n\
+        010.000.000.000 - 010.255.255.255 , 200 , Allowed\n\
+        2001:db8::1 - 2001:db8::ff , 100 , IPv6\n\
+        not a range , 000 , Broken\n");
+    assert_eq!(skipped, 1);
+    assert_eq!(filter.len(), 2);
+    assert!(filter.is_blocked(ip("1.9.96.105")));
+    assert!(filter.is_blocked(ip("1.9.96.110")));
+    assert!(!filter.is_blocked(ip("1.9.96.111")));
+    assert!(!filter.is_blocked(ip("10.1.2.3")));
+    assert!(filter.is_blocked(ip("2001:db8::20")));
+    assert!(filter.is_blocked(ip("::ffff:1.9.96.106")));
+}
+
+#[test]
+fn test_parse_peerguardian() {
+    let (filter, skipped) = IpFilter::parse("Some: organisation:1.2.3.0-1.2.3.255\n\
+        Overlapping:1.2.3.128-1.2.4.10\n\
+        Touching:1.2.4.11-1.2.4.20\n\
+        Backwards:5.0.0.0-4.0.0.0\n");
+    assert_eq!(skipped, 1);
+    // The three ranges are joined
+    assert_eq!(filter.len(), 1);
+    assert!(filter.is_blocked(ip("1.2.4.20")));
+    assert!(!filter.is_blocked(ip("1.2.4.21")));
+    assert!(!filter.is_blocked(ip("1.2.2.255")));
+}
+
+#[test]
+fn test_reload_gzipped() {
+    let path = env::temp_dir().join(format!("boosttorrent2-test-blocklist-{}.p2p.gz", std::process::id()));
+    let write = |text: &str| {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(text.as_bytes()).unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+    };
+    write("A:1.2.3.4-1.2.3.4\n");
+    let list = Blocklist::load(&path).unwrap();
+    let shared = list.clone();
+    assert!(shared.is_blocked(ip("1.2.3.4")));
+
+    write("B:5.6.7.8-5.6.7.8\nC:9.9.9.9-9.9.9.9\n");
+    assert_eq!(list.reload().unwrap(), 2);
+    assert!(!shared.is_blocked(ip("1.2.3.4")));
+    assert!(shared.is_blocked(ip("5.6.7.8")));
+
+    // A broken list leaves the old one in place
+    fs::write(&path, "nothing useful\n").unwrap();
+    assert!(list.reload().is_err());
+    fs::remove_file(&path).unwrap();
+    assert!(shared.is_blocked(ip("9.9.9.9")));
+}
@@ -1,4 +1,4 @@
-use crate::boostencode::parse::parse_val;
+use crate::boostencode::parse::Parser;
 use derive_error::Error;
 use std::cmp;
 use std::cmp::Ordering;
@@ -6,11 +6,13 @@ use std::collections::HashMap;
 use std::fmt::Display;
 use std::fmt::Error;
 use std::fmt::Formatter;
+use std::ops::Range;
 use std::str;
 
 #[cfg(test)]
 mod test;
 mod parse;
+pub mod stream;
 
 pub trait FromValue {
     type Error;
@@ -20,11 +22,44 @@ pub trait FromValue {
 #[derive(Debug, PartialEq, Clone)]
 pub enum Value {
     BString(Vec<u8>),
-    Integer(i32),
+    Integer(i64),
     List(Vec<Value>),
     Dict(HashMap<Vec<u8>, Value>),
 }
 
+/// Where a decoded value and the values inside it lie in the bytes they were decoded from
+#[derive(Debug, PartialEq, Clone)]
+pub enum Spans {
+    Leaf(Range<usize>),
+    List(Range<usize>, Vec<Spans>),
+    Dict(Range<usize>, HashMap<Vec<u8>, Spans>),
+}
+
+impl Spans {
+    /// The bytes the whole value takes up
+    pub fn range(&self) -> Range<usize> {
+        match self {
+            Spans::Leaf(range) | Spans::List(range, _) | Spans::Dict(range, _) => range.clone(),
+        }
+    }
+
+    /// The spans of a dictionary's value
+    pub fn get(&self, key: &[u8]) -> Option<&Spans> {
+        match self {
+            Spans::Dict(_, map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    /// The spans of a list's item
+    pub fn index(&self, i: usize) -> Option<&Spans> {
+        match self {
+            Spans::List(_, list) => list.get(i),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Error, PartialEq)]
 pub enum DecodeError {
     /// The encoded string was not formatted correctly
@@ -37,21 +72,44 @@ pub enum DecodeError {
     InvalidList,
     /// Error parsing dict value
     InvalidDict,
+    /// The value is nested too deeply or is too large
+    LimitExceeded,
 }
 
 
 impl Value {
+    /// Decode a value.  Encodings that aren't canonical, like integers with leading zeroes, are
+    /// accepted, since plenty of torrents and trackers produce them
     pub fn decode(bytes: &[u8]) -> Result<Value, DecodeError> {
-        let mut bytes: Vec<u8> = Vec::from(bytes);
-        let val = parse_val(&mut bytes)?;
+        Parser::new(bytes).parse_all()
+    }
 
-        if bytes.len() > 0 {
+    /// Decode a value, accepting only its canonical encoding.  Encoding the value gives back
+    /// exactly `bytes`
+    pub fn decode_strict(bytes: &[u8]) -> Result<Value, DecodeError> {
+        Parser::new(bytes).strict().parse_all()
+    }
+
+    /// Decode a value, along with where each part of it lies in `bytes`.  Hashing a slice of the
+    /// original bytes doesn't depend on the value encoding the same way again
+    pub fn decode_with_spans(bytes: &[u8]) -> Result<(Value, Spans), DecodeError> {
+        Parser::new(bytes).parse_all_with_spans()
+    }
+
+    /// Decode a value from the start of `bytes`, where other data may follow it.  Returns the
+    /// value and how many bytes it took up
+    pub fn decode_prefix(bytes: &[u8]) -> Result<(Value, usize), DecodeError> {
+        if bytes.is_empty() {
             return Err(DecodeError::InvalidValue);
         }
-
-        Ok(val)
+        let mut parser = Parser::new(bytes);
+        let val = parser.parse_val()?;
+        Ok((val, parser.position()))
     }
 
+    /// Encode the value canonically: dictionary keys sorted by their raw bytes, and no leading
+    /// zeroes.  A value from `decode` may encode differently from the bytes it came from, so
+    /// hashes of encoded data should be taken over the original bytes
     pub fn encode(&self) -> Vec<u8> {
         match self {
             Value::BString(bytes) => {
@@ -87,7 +145,7 @@ impl Value {
         }
     }
 
-    pub fn integer(&self) -> Option<&i32> {
+    pub fn integer(&self) -> Option<&i64> {
         if let Value::Integer(i) = self {
             return Some(i);
         }
@@ -2,121 +2,248 @@
 use crate::boostencode::compare_bytes_slice;
 use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::str::FromStr;
+use std::convert::TryFrom;
+use std::str::{
+    self,
+    FromStr,
+};
+use super::Spans;
+use super::stream::Limits;
 use super::Value;
 use super::DecodeError;
 
 #[cfg(test)]
 mod test;
 
-pub fn parse_val(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    match bytes[0] as char {
-        'i' => parse_integer(bytes),
-        'l' => parse_list(bytes),
-        'd' => parse_dict(bytes),
-        '0'...'9' => parse_bstring(bytes),
-        _ => return Err(DecodeError::InvalidValue)
-    }
+/// Parses bencode out of a byte string, keeping track of how far into it we are
+pub struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // How many lists and dictionaries we are inside of, and how many we allow.  Parsing recurses,
+    // so without a limit a few kilobytes of nested lists would overflow the stack
+    depth: usize,
+    max_depth: usize,
+    // If true, only the canonical encoding of each value is accepted
+    strict: bool,
+    // If true, we note where each value lies as we go.  The spans of the last list or dictionary
+    // parsed wait here for the one it is in to take them
+    record_spans: bool,
+    spans: Option<Spans>,
 }
 
-// assured of a bstring, we take it off the stack of bytes and return it
-fn parse_bstring(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    let len = parse_integer_literal(bytes)?;
-    if bytes.remove(0) as char != ':' {
-        return Err(DecodeError::InvalidString);
+impl<'a> Parser<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Parser {
+            bytes,
+            position: 0,
+            depth: 0,
+            max_depth: Limits::default().max_depth,
+            strict: false,
+            record_spans: false,
+            spans: None,
+        }
     }
 
-    let bstring: Vec<u8> = bytes.drain(0..len).collect();
+    /// Refuse leading zeroes, negative zero and dictionary keys out of order, so that encoding a
+    /// parsed value gives back exactly the bytes it came from
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
 
-    Ok(Value::BString(bstring))
-}
+    /// How many bytes have been parsed so far
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Parse a value that takes up all of the bytes
+    pub fn parse_all(&mut self) -> Result<Value, DecodeError> {
+        let val = self.parse_val()?;
 
-fn parse_integer(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    if bytes.remove(0) as char != 'i' {
-        return Err(DecodeError::InvalidInteger);
+        if self.position < self.bytes.len() {
+            return Err(DecodeError::InvalidValue);
+        }
+
+        Ok(val)
     }
 
-    let is_negative = bytes[0] as char == '-';
-    if is_negative {
-        bytes.remove(0);
+    /// Parse a value that takes up all of the bytes, along with where it and each value inside it
+    /// lie
+    pub fn parse_all_with_spans(&mut self) -> Result<(Value, Spans), DecodeError> {
+        self.record_spans = true;
+        let start = self.position;
+        let val = self.parse_all()?;
+        let spans = self.spans.take().unwrap_or(Spans::Leaf(start..self.position));
+        Ok((val, spans))
     }
 
-    if bytes[0] as char == '0' {
-        if is_negative || bytes[1] as char != 'e' {
-            return Err(DecodeError::InvalidInteger);
+    pub fn parse_val(&mut self) -> Result<Value, DecodeError> {
+        match self.peek().map(|b| b as char) {
+            Some('i') => self.parse_integer(),
+            Some('l') => self.parse_list(),
+            Some('d') => self.parse_dict(),
+            Some('0'..='9') => self.parse_bstring(),
+            _ => Err(DecodeError::InvalidValue)
         }
-
-        bytes.remove(0);
-        bytes.remove(0);
-        return Ok(Value::Integer(0));
     }
 
-    let num = parse_integer_literal(bytes)? as i32;
+    // parse a value inside a list or dictionary, along with where it lies if we are noting that
+    fn parse_child(&mut self) -> Result<(Value, Option<Spans>), DecodeError> {
+        let start = self.position;
+        let val = self.parse_val()?;
+        if !self.record_spans {
+            return Ok((val, None));
+        }
+        let spans = self.spans.take().unwrap_or(Spans::Leaf(start..self.position));
+        Ok((val, Some(spans)))
+    }
 
-    if bytes.remove(0) as char != 'e' {
-        return Err(DecodeError::InvalidInteger);
+    // go into a list or dictionary
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth >= self.max_depth {
+            return Err(DecodeError::LimitExceeded);
+        }
+        self.depth += 1;
+        Ok(())
     }
 
-    Ok(Value::Integer(if is_negative { -num } else { num }))
-}
+    // look at the next byte without taking it
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).cloned()
+    }
 
-fn parse_list(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    let mut list = Vec::new();
-    if bytes.remove(0) as char != 'l' {
-        return Err(DecodeError::InvalidList);
+    // take the next byte, if there is one
+    fn next_byte(&mut self) -> Option<char> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Some(byte as char)
     }
 
-    while bytes[0] as char != 'e' {
-        list.push(parse_val(bytes)?)
+    // assured of a bstring, we take it off the bytes and return it
+    fn parse_bstring(&mut self) -> Result<Value, DecodeError> {
+        let len = self.parse_integer_literal().map_err(|_| DecodeError::InvalidString)?;
+        if self.next_byte() != Some(':') || len > self.bytes.len() - self.position {
+            return Err(DecodeError::InvalidString);
+        }
+
+        let bstring = self.bytes[self.position..self.position + len].to_vec();
+        self.position += len;
+
+        Ok(Value::BString(bstring))
     }
 
-    if bytes.remove(0) as char != 'e' {
-        return Err(DecodeError::InvalidList);
+    fn parse_integer(&mut self) -> Result<Value, DecodeError> {
+        if self.next_byte() != Some('i') {
+            return Err(DecodeError::InvalidInteger);
+        }
+
+        let is_negative = self.peek() == Some(b'-');
+        if is_negative {
+            self.position += 1;
+        }
+
+        let num = i64::try_from(self.parse_integer_literal()?).map_err(|_| DecodeError::InvalidInteger)?;
+        if self.strict && is_negative && num == 0 {
+            return Err(DecodeError::InvalidInteger);
+        }
+        let num = if is_negative { -num } else { num };
+
+        if self.next_byte() != Some('e') {
+            return Err(DecodeError::InvalidInteger);
+        }
+
+        Ok(Value::Integer(num))
     }
 
-    Ok(Value::List(list))
-}
+    fn parse_list(&mut self) -> Result<Value, DecodeError> {
+        let start = self.position;
+        let mut list = Vec::new();
+        let mut spans = Vec::new();
+        if self.next_byte() != Some('l') {
+            return Err(DecodeError::InvalidList);
+        }
+        self.enter()?;
 
-fn parse_dict(bytes: &mut Vec<u8>) -> Result<Value, DecodeError> {
-    let mut map = HashMap::new();
+        while self.peek() != Some(b'e') {
+            let (val, val_spans) = self.parse_child()?;
+            list.push(val);
+            spans.extend(val_spans);
+        }
 
-    if bytes.remove(0) as char != 'd' {
-        return Err(DecodeError::InvalidDict);
+        if self.next_byte() != Some('e') {
+            return Err(DecodeError::InvalidList);
+        }
+        self.depth -= 1;
+        if self.record_spans {
+            self.spans = Some(Spans::List(start..self.position, spans));
+        }
+
+        Ok(Value::List(list))
     }
 
-    let mut last_key: Option<Vec<u8>> = None;
+    fn parse_dict(&mut self) -> Result<Value, DecodeError> {
+        let start = self.position;
+        let mut map = HashMap::new();
+        let mut spans = HashMap::new();
+
+        if self.next_byte() != Some('d') {
+            return Err(DecodeError::InvalidDict);
+        }
+        self.enter()?;
 
-    while bytes[0] as char != 'e' {
-        let key = parse_bstring(bytes)?;
-        let val = parse_val(bytes)?;
+        let mut last_key: Option<Vec<u8>> = None;
 
-        if let Value::BString(key) = key {
-            if let Some(last) = last_key {
-                if compare_bytes_slice(&last, key.as_ref()) != Ordering::Less {
+        while self.peek() != Some(b'e') {
+            if !self.peek().is_some_and(|b| b.is_ascii_digit()) {
+                return Err(DecodeError::InvalidDict);
+            }
+            let key = self.parse_bstring()?;
+            let (val, val_spans) = self.parse_child()?;
+
+            if let Value::BString(key) = key {
+                // A key given twice has no one value
+                if map.contains_key(&key) {
                     return Err(DecodeError::InvalidDict);
                 }
+                if let Some(last) = last_key {
+                    if self.strict && compare_bytes_slice(&last, key.as_ref()) != Ordering::Less {
+                        return Err(DecodeError::InvalidDict);
+                    }
+                }
+
+                last_key = Some(key.clone());
+                if let Some(val_spans) = val_spans {
+                    spans.insert(key.clone(), val_spans);
+                }
+                map.insert(key, val);
+            } else {
+                return Err(DecodeError::InvalidDict);
             }
+        }
 
-            last_key = Some(key.clone());
-            map.insert(key, val);
-        } else {
+        if self.next_byte() != Some('e') {
             return Err(DecodeError::InvalidDict);
         }
-    }
+        self.depth -= 1;
+        if self.record_spans {
+            self.spans = Some(Spans::Dict(start..self.position, spans));
+        }
 
-    if bytes.remove(0) as char != 'e' {
-        return Err(DecodeError::InvalidDict);
+        Ok(Value::Dict(map))
     }
 
-    Ok(Value::Dict(map))
-}
+    // parse the integer literal at the current position.  Leading zeroes are refused when parsing
+    // strictly, since they would be lost when the value is encoded again
+    fn parse_integer_literal(&mut self) -> Result<usize, DecodeError> {
+        let digits = self.bytes[self.position..].iter().take_while(|b| b.is_ascii_digit()).count();
+        let num = &self.bytes[self.position..self.position + digits];
+        if self.strict && num.len() > 1 && num[0] == b'0' {
+            return Err(DecodeError::InvalidInteger);
+        }
+        self.position += digits;
 
-// parse an integer literal on the top of the stack
-fn parse_integer_literal(bytes: &mut Vec<u8>) -> Result<usize, DecodeError> {
-    let mut num = String::new();
-    while bytes.len() > 0 && bytes[0] as char >= '0' && bytes[0] as char <= '9' {
-        num.push(bytes.remove(0) as char);
+        str::from_utf8(num).ok()
+            .and_then(|num| usize::from_str(num).ok())
+            .ok_or(DecodeError::InvalidInteger)
     }
-
-    usize::from_str(num.as_str()).map_err(|_| DecodeError::InvalidInteger)
-}
\ No newline at end of file
+}
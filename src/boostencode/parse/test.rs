@@ -2,65 +2,68 @@ use super::*;
 
 #[test]
 fn test_parse_integer_literal() {
-    let mut s123 = "123e".to_string().into_bytes();
-    let res = parse_integer_literal(s123.as_mut()).unwrap();
+    let mut parser = Parser::new(b"123e");
+    let res = parser.parse_integer_literal().unwrap();
     assert_eq!(res, 123);
 }
 
 #[test]
 fn test_parse_bstring() {
-    let mut s1 = "4:spam".to_string().into_bytes();
+    let s1 = b"4:spam";
+    let mut parser = Parser::new(s1);
 
-    let val = parse_bstring(s1.as_mut()).unwrap();
+    let val = parser.parse_bstring().unwrap();
 
     assert_eq!(val, Value::BString(vec!['s' as u8, 'p' as u8, 'a' as u8, 'm' as u8]));
-    assert_eq!(0, s1.len());
+    assert_eq!(s1.len(), parser.position());
 }
 
 #[test]
 fn test_parse_integer() {
-    let mut s1 = "i123e".to_string().into_bytes();
-    let mut s2 = "i-4e".to_string().into_bytes();
-    let mut s3 = "i0e".to_string().into_bytes();
+    let (s1, s2, s3) = (b"i123e", b"i-4e", b"i0e");
+    let mut p1 = Parser::new(s1);
+    let mut p2 = Parser::new(s2);
+    let mut p3 = Parser::new(s3);
 
-    let val1 = parse_integer(s1.as_mut()).unwrap();
-    let val2 = parse_integer(s2.as_mut()).unwrap();
-    let val3 = parse_integer(s3.as_mut()).unwrap();
+    let val1 = p1.parse_integer().unwrap();
+    let val2 = p2.parse_integer().unwrap();
+    let val3 = p3.parse_integer().unwrap();
 
     assert_eq!(val1, Value::Integer(123));
     assert_eq!(val2, Value::Integer(-4));
     assert_eq!(val3, Value::Integer(0));
-    assert_eq!(0, s1.len());
-    assert_eq!(0, s2.len());
-    assert_eq!(0, s3.len());
+    assert_eq!(s1.len(), p1.position());
+    assert_eq!(s2.len(), p2.position());
+    assert_eq!(s3.len(), p3.position());
 }
 
 #[test]
 fn test_parse_integer_negative_zero() {
-    let mut s1 = "i-0e".to_string().into_bytes();
-    assert_eq!(parse_integer(s1.as_mut()), Err(DecodeError::InvalidInteger));
-
+    let mut p1 = Parser::new(b"i-0e").strict();
+    assert_eq!(p1.parse_integer(), Err(DecodeError::InvalidInteger));
+    assert_eq!(Parser::new(b"i-0e").parse_integer(), Ok(Value::Integer(0)));
 }
 
 #[test]
 fn test_parse_integer_leading_zero() {
-    let mut s1 = "i023e".to_string().into_bytes();
-    assert_eq!(parse_integer(s1.as_mut()), Err(DecodeError::InvalidInteger));
+    let mut p1 = Parser::new(b"i023e").strict();
+    assert_eq!(p1.parse_integer(), Err(DecodeError::InvalidInteger));
+    assert_eq!(Parser::new(b"i023e").parse_integer(), Ok(Value::Integer(23)));
 }
 
 #[test]
 fn test_parse_list() {
-    let mut s1 = "l4:spami123ee".to_string().into_bytes();
+    let mut p1 = Parser::new(b"l4:spami123ee");
 
-    let val1 = parse_list(s1.as_mut()).unwrap();
+    let val1 = p1.parse_list().unwrap();
 
     assert_eq!(val1, Value::List(vec![Value::BString(vec!['s' as u8, 'p' as u8, 'a' as u8, 'm' as u8]), Value::Integer(123)]))
 }
 
 #[test]
 fn test_parses_dict() {
-    let mut s1 = "d5:hello5:world4:spami123ee".to_string().into_bytes();
-    let val1 = parse_dict(s1.as_mut()).unwrap();
+    let mut p1 = Parser::new(b"d5:hello5:world4:spami123ee");
+    let val1 = p1.parse_dict().unwrap();
 
     let mut map = HashMap::new();
     map.insert(vec!['h' as u8, 'e' as u8, 'l' as u8, 'l' as u8, 'o' as u8], Value::BString(vec!['w' as u8, 'o' as u8, 'r' as u8, 'l' as u8, 'd' as u8]));
@@ -70,6 +73,33 @@ fn test_parses_dict() {
 
 #[test]
 fn test_parse_dict_not_ascending() {
-    let mut s1 = "d5:worldi1e5:helloi2ee".to_string().into_bytes();
-    assert_eq!(parse_dict(s1.as_mut()), Err(DecodeError::InvalidDict));
-}
\ No newline at end of file
+    let mut p1 = Parser::new(b"d5:worldi1e5:helloi2ee").strict();
+    assert_eq!(p1.parse_dict(), Err(DecodeError::InvalidDict));
+    assert!(Parser::new(b"d5:worldi1e5:helloi2ee").parse_dict().is_ok());
+}
+
+#[test]
+fn test_parse_long_list() {
+    // Parsing takes time in proportion to the input, not its square
+    let mut encoded = b"l".to_vec();
+    for _ in 0..100_000 {
+        encoded.extend_from_slice(b"4:spami1e");
+    }
+    encoded.push(b'e');
+    let mut parser = Parser::new(&encoded);
+    let val = parser.parse_val().unwrap();
+    assert_eq!(val.list().map(Vec::len), Some(200_000));
+    assert_eq!(parser.position(), encoded.len());
+}
+
+#[test]
+fn test_parse_too_deep() {
+    let depth = Limits::default().max_depth;
+    let mut nested = vec![b'l'; depth];
+    nested.extend(vec![b'e'; depth]);
+    assert!(Parser::new(&nested).parse_val().is_ok());
+    let mut nested = vec![b'l'; depth + 1];
+    nested.extend(vec![b'e'; depth + 1]);
+    assert_eq!(Parser::new(&nested).parse_val(), Err(DecodeError::LimitExceeded));
+    assert_eq!(Parser::new(&[b'l'; 100_000]).parse_val(), Err(DecodeError::LimitExceeded));
+}
@@ -0,0 +1,280 @@
+//! An incremental bencode decoder.  Bytes are pushed in as they arrive, so a value can be decoded
+//! without holding the whole encoding in one buffer, and limits stop hostile input from nesting
+//! forever or claiming huge strings.
+use crate::boostencode::compare_bytes_slice;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::{
+    self,
+    Read,
+};
+use std::str;
+use super::{
+    DecodeError,
+    Value,
+};
+
+#[cfg(test)]
+mod test;
+
+// How much is read at a time by decode_from
+const READ_SIZE: usize = 16 * 1024;
+
+// Integers fit in an i64, so anything longer than this can't be valid
+const MAX_INTEGER_DIGITS: usize = 20;
+
+/// Bounds on what a decoder will accept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    // How many lists and dictionaries may be nested inside each other
+    pub max_depth: usize,
+    // How many bytes the encoded value may take up
+    pub max_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: 64,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A list or dictionary that is still being decoded
+enum Frame {
+    List(Vec<Value>),
+    Dict {
+        map: HashMap<Vec<u8>, Value>,
+        // The key waiting for its value
+        key: Option<Vec<u8>>,
+        // Keys must come in order
+        last_key: Option<Vec<u8>>,
+    },
+}
+
+/// Decodes one value from bytes pushed in with `feed`
+pub struct Decoder {
+    limits: Limits,
+    // Bytes that haven't made up a whole token yet
+    buf: Vec<u8>,
+    stack: Vec<Frame>,
+    // Bytes taken up by the value so far
+    consumed: usize,
+    done: bool,
+    // If true, only the canonical encoding of each value is accepted
+    strict: bool,
+}
+
+impl Decoder {
+    pub fn new(limits: Limits) -> Self {
+        Decoder {
+            limits,
+            buf: Vec::new(),
+            stack: Vec::new(),
+            consumed: 0,
+            done: false,
+            strict: false,
+        }
+    }
+
+    /// Refuse leading zeroes, negative zero and dictionary keys out of order, so that encoding the
+    /// decoded value gives back exactly the bytes it came from
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Push more of the encoding in.  Returns the value once it is complete, and None if more
+    /// bytes are needed.  Bytes after the end of the value are kept, and can be taken with
+    /// `remaining`
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<Option<Value>, DecodeError> {
+        self.buf.extend_from_slice(bytes);
+        if self.done {
+            return Ok(None);
+        }
+        let mut position = 0;
+        let res = self.decode(&mut position);
+        self.consumed += position;
+        self.buf.drain(..position);
+        res
+    }
+
+    /// Bytes fed in after the end of the value
+    pub fn remaining(&self) -> &[u8] {
+        if self.done {
+            &self.buf
+        } else {
+            &[]
+        }
+    }
+
+    /// Decode as many tokens as are in the buffer, starting at `position`
+    fn decode(&mut self, position: &mut usize) -> Result<Option<Value>, DecodeError> {
+        loop {
+            let token = match self.token(*position)? {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+            let (value, len) = match token {
+                Token::Start(frame) => {
+                    if self.stack.len() >= self.limits.max_depth {
+                        return Err(DecodeError::LimitExceeded);
+                    }
+                    self.stack.push(frame);
+                    *position += 1;
+                    continue;
+                }
+                Token::End => {
+                    let value = match self.stack.pop() {
+                        Some(Frame::List(list)) => Value::List(list),
+                        Some(Frame::Dict { map, key: None, .. }) => Value::Dict(map),
+                        Some(Frame::Dict { .. }) => return Err(DecodeError::InvalidDict),
+                        None => return Err(DecodeError::InvalidValue),
+                    };
+                    (value, 1)
+                }
+                Token::Value(value, len) => (value, len),
+            };
+            if self.consumed + *position + len > self.limits.max_size {
+                return Err(DecodeError::LimitExceeded);
+            }
+            *position += len;
+            if let Some(value) = self.add(value)? {
+                self.done = true;
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    /// Put a finished value into the container it belongs to.  Returns it if it is the top value
+    fn add(&mut self, value: Value) -> Result<Option<Value>, DecodeError> {
+        match self.stack.last_mut() {
+            None => Ok(Some(value)),
+            Some(Frame::List(list)) => {
+                list.push(value);
+                Ok(None)
+            }
+            Some(Frame::Dict { map, key, last_key }) => {
+                match key.take() {
+                    Some(key) => {
+                        map.insert(key, value);
+                    }
+                    None => {
+                        let new_key = match value {
+                            Value::BString(bytes) => bytes,
+                            _ => return Err(DecodeError::InvalidDict),
+                        };
+                        // A key given twice has no one value
+                        if map.contains_key(&new_key) {
+                            return Err(DecodeError::InvalidDict);
+                        }
+                        if let Some(last) = last_key {
+                            if self.strict && compare_bytes_slice(last, &new_key) != Ordering::Less {
+                                return Err(DecodeError::InvalidDict);
+                            }
+                        }
+                        *last_key = Some(new_key.clone());
+                        *key = Some(new_key);
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the token at `position`, if all of it has arrived
+    fn token(&self, position: usize) -> Result<Option<Token>, DecodeError> {
+        let bytes = &self.buf[position..];
+        let first = match bytes.first() {
+            Some(first) => *first,
+            None => return Ok(None),
+        };
+        match first {
+            b'i' => {
+                let end = match bytes.iter().position(|b| *b == b'e') {
+                    Some(end) => end,
+                    None if bytes.len() > MAX_INTEGER_DIGITS + 1 => return Err(DecodeError::InvalidInteger),
+                    None => return Ok(None),
+                };
+                let num = parse_integer(&bytes[1..end], self.strict).ok_or(DecodeError::InvalidInteger)?;
+                Ok(Some(Token::Value(Value::Integer(num), end + 1)))
+            }
+            b'0'..=b'9' => {
+                let colon = match bytes.iter().position(|b| *b == b':') {
+                    Some(colon) => colon,
+                    None if bytes.len() > MAX_INTEGER_DIGITS => return Err(DecodeError::InvalidString),
+                    None => return Ok(None),
+                };
+                // Leading zeroes would be lost when the value is encoded again
+                if self.strict && colon > 1 && first == b'0' {
+                    return Err(DecodeError::InvalidString);
+                }
+                let len = str::from_utf8(&bytes[..colon]).ok()
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or(DecodeError::InvalidString)?;
+                // Refuse strings that can't fit before they all arrive
+                if self.consumed + position + colon + 1 + len > self.limits.max_size {
+                    return Err(DecodeError::LimitExceeded);
+                }
+                if bytes.len() < colon + 1 + len {
+                    return Ok(None);
+                }
+                let string = bytes[colon + 1..colon + 1 + len].to_vec();
+                Ok(Some(Token::Value(Value::BString(string), colon + 1 + len)))
+            }
+            b'l' => Ok(Some(Token::Start(Frame::List(Vec::new())))),
+            b'd' => Ok(Some(Token::Start(Frame::Dict {
+                map: HashMap::new(),
+                key: None,
+                last_key: None,
+            }))),
+            b'e' => Ok(Some(Token::End)),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+}
+
+enum Token {
+    // A string or integer, and how many bytes it took up
+    Value(Value, usize),
+    // The start of a list or dictionary
+    Start(Frame),
+    End,
+}
+
+/// Parse the digits of an integer.  Strictly, they may not have leading zeroes or be negative zero
+fn parse_integer(digits: &[u8], strict: bool) -> Option<i64> {
+    let (negative, digits) = match digits.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, digits),
+    };
+    match digits {
+        [] => None,
+        [b'0'] if !negative => Some(0),
+        [b'0', ..] if strict => None,
+        _ if digits.iter().all(u8::is_ascii_digit) => {
+            let num = str::from_utf8(digits).ok()?.parse::<i64>().ok()?;
+            Some(if negative { -num } else { num })
+        }
+        _ => None,
+    }
+}
+
+/// Decode one value from a reader, a piece at a time.  Anything the reader has after the value
+/// is thrown away
+pub fn decode_from<R: Read>(mut reader: R, limits: Limits) -> io::Result<Value> {
+    let mut decoder = Decoder::new(limits);
+    let mut buf = vec![0; READ_SIZE];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bencoded value ended early"));
+        }
+        match decoder.feed(&buf[..read]) {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => (),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use maplit::hashmap;
+use super::*;
+
+#[test]
+fn test_decode_in_pieces() {
+    let encoded = b"d4:listli1ei-20e3:abce3:numi42ee";
+    let expected = Value::Dict(hashmap! {
+        Vec::from("list") => Value::List(vec![Value::Integer(1), Value::Integer(-20), Value::BString(Vec::from("abc"))]),
+        Vec::from("num") => Value::Integer(42),
+    });
+    // Every split point, including ones in the middle of tokens
+    for split in 0..encoded.len() {
+        let mut decoder = Decoder::new(Limits::default());
+        assert_eq!(decoder.feed(&encoded[..split]), Ok(None));
+        assert_eq!(decoder.feed(&encoded[split..]), Ok(Some(expected.clone())));
+    }
+    // A byte at a time
+    let mut decoder = Decoder::new(Limits::default());
+    let decoded: Vec<_> = encoded.iter().map(|b| decoder.feed(&[*b]).unwrap()).collect();
+    assert_eq!(decoded.last(), Some(&Some(expected)));
+    assert!(decoded[..decoded.len() - 1].iter().all(Option::is_none));
+}
+
+#[test]
+fn test_remaining() {
+    let mut decoder = Decoder::new(Limits::default());
+    assert_eq!(decoder.feed(b"4:spamtail"), Ok(Some(Value::BString(Vec::from("spam")))));
+    assert_eq!(decoder.remaining(), b"tail");
+}
+
+#[test]
+fn test_invalid() {
+    for encoded in &[&b"ie"[..], b"i-e", b"x", b"di1ei2ee", b"d1:ae", b"e", b"d1:ai1e1:ai2ee"] {
+        let mut decoder = Decoder::new(Limits::default());
+        assert!(decoder.feed(encoded).is_err(), "{:?}", str::from_utf8(encoded));
+    }
+}
+
+#[test]
+fn test_strict() {
+    for encoded in &[&b"i03e"[..], b"i-0e", b"d1:bi1e1:ai2ee", b"04:spam"] {
+        let mut decoder = Decoder::new(Limits::default());
+        assert!(decoder.feed(encoded).unwrap().is_some(), "{:?}", str::from_utf8(encoded));
+        let mut decoder = Decoder::new(Limits::default()).strict();
+        assert!(decoder.feed(encoded).is_err(), "{:?}", str::from_utf8(encoded));
+    }
+}
+
+#[test]
+fn test_large_integers() {
+    let mut decoder = Decoder::new(Limits::default());
+    assert_eq!(decoder.feed(b"d6:lengthi4294967296ee"), Ok(Some(Value::Dict(hashmap! {
+        Vec::from("length") => Value::Integer(4_294_967_296),
+    }))));
+    assert_eq!(Decoder::new(Limits::default()).feed(b"i99999999999999999999e"), Err(DecodeError::InvalidInteger));
+}
+
+#[test]
+fn test_limits() {
+    let limits = Limits {
+        max_depth: 2,
+        max_size: 10,
+    };
+    assert_eq!(Decoder::new(limits).feed(b"lli1eee"), Ok(Some(Value::List(vec![Value::List(vec![Value::Integer(1)])]))));
+    assert_eq!(Decoder::new(limits).feed(b"llle"), Err(DecodeError::LimitExceeded));
+    // The string is refused before its bytes arrive
+    assert_eq!(Decoder::new(limits).feed(b"100:"), Err(DecodeError::LimitExceeded));
+    assert_eq!(Decoder::new(limits).feed(b"li1ei2ei3ei4ee"), Err(DecodeError::LimitExceeded));
+}
+
+#[test]
+fn test_decode_from() {
+    let encoded: &[u8] = b"d3:key5:valuee";
+    assert_eq!(decode_from(encoded, Limits::default()).unwrap(), Value::Dict(hashmap! {
+        Vec::from("key") => Value::BString(Vec::from("value")),
+    }));
+    assert!(decode_from(&encoded[..5], Limits::default()).is_err());
+}
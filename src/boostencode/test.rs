@@ -38,4 +38,81 @@ fn test_compare_bstring() {
     assert_eq!(Ordering::Greater, compare_bytes_slice(v4.as_ref(), v3.as_ref()));
     assert_eq!(Ordering::Less, compare_bytes_slice(vs.as_ref(), vl.as_ref()));
     assert_eq!(Ordering::Greater, compare_bytes_slice(vl.as_ref(), vs.as_ref()));
-}
\ No newline at end of file
+}
+#[test]
+fn test_decode_prefix() {
+    let (val, len) = Value::decode_prefix(b"d3:fooi1eeraw bytes").unwrap();
+    assert_eq!(val.dict().and_then(|map| map.get("foo".as_bytes())), Some(&Value::Integer(1)));
+    assert_eq!(len, 10);
+    assert!(Value::decode_prefix(b"").is_err());
+}
+
+#[test]
+fn test_round_trip() {
+    let inputs: &[&[u8]] = &[
+        b"i-2147483648e",
+        b"0:",
+        b"d0:le1:ai1e2:aai-1e1:bd1:ci0eee",
+        b"d2:\x01\xffd6:lengthi100eee",
+    ];
+    for input in inputs {
+        assert_eq!(&Value::decode(input).unwrap().encode()[..], *input);
+    }
+}
+
+#[test]
+fn test_large_integers() {
+    // Files over 4 GiB have lengths that don't fit in 32 bits
+    let val = Value::decode(b"i4294967296e").unwrap();
+    assert_eq!(val, Value::Integer(4_294_967_296));
+    assert_eq!(val.encode(), b"i4294967296e".to_vec());
+    assert_eq!(Value::decode(b"i-9223372036854775807e"), Ok(Value::Integer(-9_223_372_036_854_775_807)));
+}
+
+#[test]
+fn test_non_canonical_accepted() {
+    assert_eq!(Value::decode(b"04:spam"), Ok(Value::BString(Vec::from("spam"))));
+    assert_eq!(Value::decode(b"i007e"), Ok(Value::Integer(7)));
+    assert_eq!(Value::decode(b"i-0e"), Ok(Value::Integer(0)));
+    assert_eq!(Value::decode(b"d1:bi1e1:ai2ee").unwrap().encode(), b"d1:ai2e1:bi1ee".to_vec());
+}
+
+#[test]
+fn test_non_canonical_refused_when_strict() {
+    // Each of these would encode differently if it were accepted
+    let inputs: &[&[u8]] = &[b"04:spam", b"i007e", b"i-0e", b"d1:bi1e1:ai1ee"];
+    for input in inputs {
+        assert!(Value::decode(input).is_ok(), "{:?}", str::from_utf8(input));
+        assert!(Value::decode_strict(input).is_err(), "{:?}", str::from_utf8(input));
+    }
+    assert!(Value::decode_strict(b"d1:ai1e1:bi1ee").is_ok());
+}
+
+#[test]
+fn test_unrepresentable_refused() {
+    let inputs: &[&[u8]] = &[b"i9223372036854775808e", b"i18446744073709551615e", b"d1:ai1e1:ai2ee", b"d1:ai1e1:bi1e1:ai2ee"];
+    for input in inputs {
+        assert!(Value::decode(input).is_err(), "{:?}", str::from_utf8(input));
+    }
+}
+
+#[test]
+fn test_truncated_refused() {
+    let inputs: &[&[u8]] = &[b"", b"l", b"d", b"i12", b"5:spam", b"d3:foo", b"li1e", b"i-"];
+    for input in inputs {
+        assert!(Value::decode(input).is_err(), "{:?}", str::from_utf8(input));
+    }
+}
+
+#[test]
+fn test_decode_with_spans() {
+    let bytes = b"d4:infod6:lengthi100ee4:listl1:ai2eee";
+    let (val, spans) = Value::decode_with_spans(bytes).unwrap();
+    assert_eq!(val, Value::decode(bytes).unwrap());
+    assert_eq!(spans.range(), 0..bytes.len());
+    let info = spans.get("info".as_bytes()).unwrap().range();
+    assert_eq!(&bytes[info], &b"d6:lengthi100ee"[..]);
+    let list = spans.get("list".as_bytes()).unwrap();
+    assert_eq!(&bytes[list.index(1).unwrap().range()], &b"i2e"[..]);
+    assert_eq!(list.index(2), None);
+}
@@ -0,0 +1,102 @@
+//! bufpool keeps the buffers blocks are uploaded in, so they are reused instead of allocated for
+//! every block.  The reader fills a buffer from the pool with a block a peer asked for, and the
+//! peer's codec hands it back once the block has been encoded onto the connection.
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Size of each buffer.  Peers ask for 16 KiB blocks
+pub const BUFFER_SIZE: usize = 1 << 14;
+
+/// How often buffers were reused and allocated, for tuning the pool size
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    // Buffers handed out from the pool
+    pub reused: u64,
+    // Buffers allocated because the pool was empty
+    pub allocated: u64,
+    // Buffers given back that the pool had no room for, or that were still in use
+    pub dropped: u64,
+    // Buffers waiting in the pool right now
+    pub free: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    reused: AtomicU64,
+    allocated: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// A pool of block buffers, shared by the reader and every peer of a torrent
+#[derive(Clone)]
+pub struct BufferPool {
+    free: Arc<Mutex<Vec<BytesMut>>>,
+    // Most buffers to keep.  Past this, buffers given back are freed
+    max_free: usize,
+    counters: Arc<Counters>,
+}
+
+impl BufferPool {
+    pub fn new(max_free: usize) -> Self {
+        BufferPool {
+            free: Arc::new(Mutex::new(Vec::new())),
+            max_free,
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// An empty buffer with room for a block
+    pub fn get(&self) -> BytesMut {
+        match self.free.lock().unwrap().pop() {
+            Some(buffer) => {
+                self.counters.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.counters.allocated.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(BUFFER_SIZE)
+            }
+        }
+    }
+
+    /// Give a block back once it is no longer needed.  It only goes back in the pool if nothing
+    /// else holds on to it and it is big enough to hold another block
+    pub fn put(&self, block: Bytes) {
+        let mut buffer = match block.try_into_mut() {
+            Ok(buffer) if buffer.capacity() >= BUFFER_SIZE => buffer,
+            _ => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        buffer.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_free {
+            free.push(buffer);
+        } else {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            reused: self.counters.reused.load(Ordering::Relaxed),
+            allocated: self.counters.allocated.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            free: self.free.lock().unwrap().len(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use super::*;
+
+#[test]
+fn test_reuse() {
+    let pool = BufferPool::new(2);
+    let mut buffer = pool.get();
+    buffer.extend_from_slice(&[1; 100]);
+    pool.put(buffer.freeze());
+    let buffer = pool.get();
+    // Buffers come back empty, with their room kept
+    assert!(buffer.is_empty());
+    assert!(buffer.capacity() >= BUFFER_SIZE);
+    assert_eq!(pool.stats(), PoolStats {
+        reused: 1,
+        allocated: 1,
+        dropped: 0,
+        free: 0,
+    });
+}
+
+#[test]
+fn test_blocks_in_use_arent_reused() {
+    let pool = BufferPool::new(2);
+    let mut buffer = pool.get();
+    buffer.extend_from_slice(&[1; 100]);
+    let block = buffer.freeze();
+    let held = block.clone();
+    pool.put(block);
+    assert_eq!(&held[..], &[1; 100][..]);
+    // Blocks that didn't come from the pool are too small to be worth keeping
+    pool.put(Bytes::from(vec![0; 100]));
+    assert_eq!(pool.stats().dropped, 2);
+    assert_eq!(pool.stats().free, 0);
+}
+
+#[test]
+fn test_max_free() {
+    let pool = BufferPool::new(1);
+    let (first, second) = (pool.get(), pool.get());
+    pool.put(first.freeze());
+    pool.put(second.freeze());
+    assert_eq!(pool.stats(), PoolStats {
+        reused: 0,
+        allocated: 2,
+        dropped: 1,
+        free: 1,
+    });
+}
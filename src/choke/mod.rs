@@ -0,0 +1,222 @@
+//! choke decides which peers we upload to
+use rand::Rng;
+use std::cmp::{
+    Ordering,
+    Reverse,
+};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How to pick which peers to upload to once we are seeding.  Different seeding goals want the
+/// upload spread across the swarm in different ways.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum SeedStrategy {
+    /// Upload to the peers that take data from us the fastest
+    #[default]
+    FastestUpload,
+    /// Take turns, unchoking the peers that have waited the longest
+    RoundRobin,
+    /// Favor peers that have the least of the torrent, so new peers get going quickly
+    AntiLeech,
+    /// For the first seed of a torrent.  Each peer is shown one piece at a time, so pieces spread
+    /// across the swarm instead of everyone downloading the same ones from us.  Peers take turns
+    /// like in RoundRobin
+    SuperSeed,
+}
+
+impl FromStr for SeedStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fastest-upload" => Ok(SeedStrategy::FastestUpload),
+            "round-robin" => Ok(SeedStrategy::RoundRobin),
+            "anti-leech" => Ok(SeedStrategy::AntiLeech),
+            "super-seed" => Ok(SeedStrategy::SuperSeed),
+            _ => Err(format!("Invalid seed strategy: {}", s)),
+        }
+    }
+}
+
+/// How often to recompute which peers are unchoked
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many recomputes the optimistic unchoke lasts, so it rotates every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// What the choker knows about a peer
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerStats {
+    // The server's id for the peer
+    pub id: usize,
+    // Bytes per second we are uploading to the peer
+    pub upload_rate: u64,
+    // Bytes per second we are downloading from the peer
+    pub download_rate: u64,
+    // When we last unchoked the peer, if ever
+    pub last_unchoked: Option<Instant>,
+    // How much of the torrent the peer has, from 0 to 1
+    pub completion: f32,
+    // Whether the peer wants data from us
+    pub interested: bool,
+    // Whether the peer stopped sending us the blocks we asked for
+    pub snubbed: bool,
+}
+
+/// Pick up to `slots` interested peers to unchoke while seeding
+pub fn seeding_unchokes(strategy: SeedStrategy, peers: &[PeerStats], slots: usize) -> Vec<usize> {
+    let mut candidates: Vec<&PeerStats> = peers.iter().filter(|p| p.interested).collect();
+    match strategy {
+        SeedStrategy::FastestUpload => candidates.sort_by_key(|peer| Reverse(peer.upload_rate)),
+        SeedStrategy::RoundRobin | SeedStrategy::SuperSeed => candidates.sort_by(|a, b| {
+            // Never unchoked sorts first, then the longest ago
+            match (a.last_unchoked, b.last_unchoked) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (Some(a), Some(b)) => a.cmp(&b),
+            }
+        }),
+        SeedStrategy::AntiLeech => candidates.sort_by(|a, b| {
+            a.completion.partial_cmp(&b.completion).unwrap_or(Ordering::Equal)
+                .then(b.upload_rate.cmp(&a.upload_rate))
+        }),
+    }
+    candidates.into_iter().take(slots).map(|p| p.id).collect()
+}
+
+/// Pick up to `slots` interested peers to unchoke while downloading.  Tit-for-tat: the peers that
+/// send us data the fastest get data back.  Peers snubbing us go last, whatever their rate
+pub fn leeching_unchokes(peers: &[PeerStats], slots: usize) -> Vec<usize> {
+    let mut candidates: Vec<&PeerStats> = peers.iter().filter(|p| p.interested).collect();
+    candidates.sort_by(|a, b| a.snubbed.cmp(&b.snubbed).then(b.download_rate.cmp(&a.download_rate)));
+    candidates.into_iter().take(slots).map(|p| p.id).collect()
+}
+
+/// Keeps track of how fast each peer is, and decides which peers are unchoked.  Every
+/// recompute unchokes the best peers for all but one of the slots.  The last slot is an
+/// optimistic unchoke, which goes to a random peer so new peers get a chance to show how fast
+/// they are
+pub struct Choker {
+    slots: usize,
+    peers: HashMap<usize, PeerStats>,
+    unchoked: Vec<usize>,
+    optimistic: Option<usize>,
+    // Recomputes until the optimistic unchoke moves on
+    optimistic_rounds: u32,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            peers: HashMap::new(),
+            unchoked: Vec::new(),
+            optimistic: None,
+            optimistic_rounds: 0,
+        }
+    }
+
+    pub fn add_peer(&mut self, id: usize) {
+        self.peers.insert(id, PeerStats {
+            id,
+            upload_rate: 0,
+            download_rate: 0,
+            last_unchoked: None,
+            completion: 0.0,
+            interested: false,
+            snubbed: false,
+        });
+    }
+
+    pub fn remove_peer(&mut self, id: usize) {
+        self.peers.remove(&id);
+        self.unchoked.retain(|peer| *peer != id);
+        if self.optimistic == Some(id) {
+            self.optimistic = None;
+        }
+    }
+
+    /// Forget who is unchoked, for when every peer was choked behind our back.  The next
+    /// recompute unchokes peers as if for the first time
+    pub fn choke_all(&mut self) {
+        self.unchoked.clear();
+        self.optimistic = None;
+    }
+
+    /// How fast we upload to and download from a peer, in bytes per second
+    pub fn set_rates(&mut self, id: usize, upload_rate: u64, download_rate: u64) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.upload_rate = upload_rate;
+            peer.download_rate = download_rate;
+        }
+    }
+
+    pub fn set_interested(&mut self, id: usize, interested: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.interested = interested;
+        }
+    }
+
+    pub fn set_snubbed(&mut self, id: usize, snubbed: bool) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.snubbed = snubbed;
+        }
+    }
+
+    pub fn set_completion(&mut self, id: usize, completion: f32) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.completion = completion;
+        }
+    }
+
+    /// Pick the peers to unchoke, going by the last rates we were given.  Returns the peers whose
+    /// state changed, with true for the ones to choke
+    pub fn recompute(&mut self, seeding: bool, strategy: SeedStrategy, now: Instant) -> Vec<(usize, bool)> {
+        let stats: Vec<PeerStats> = self.peers.values().cloned().collect();
+        let regular_slots = self.slots.saturating_sub(1);
+        let mut unchoked = if seeding {
+            seeding_unchokes(strategy, &stats, regular_slots)
+        } else {
+            leeching_unchokes(&stats, regular_slots)
+        };
+
+        self.optimistic_rounds = self.optimistic_rounds.saturating_sub(1);
+        let optimistic_lost = self.optimistic.is_none_or(|id| {
+            unchoked.contains(&id) || !self.peers.get(&id).is_some_and(|peer| peer.interested)
+        });
+        if self.optimistic_rounds == 0 || optimistic_lost {
+            let choices: Vec<usize> = stats.iter()
+                .filter(|peer| peer.interested && !unchoked.contains(&peer.id))
+                .map(|peer| peer.id)
+                .collect();
+            self.optimistic = rand::thread_rng().choose(&choices).cloned();
+            self.optimistic_rounds = OPTIMISTIC_ROUNDS;
+        }
+        if self.slots > 0 {
+            unchoked.extend(self.optimistic);
+        }
+
+        let mut changes: Vec<(usize, bool)> = self.unchoked.iter()
+            .filter(|id| !unchoked.contains(id))
+            .map(|id| (*id, true))
+            .collect();
+        for id in &unchoked {
+            if !self.unchoked.contains(id) {
+                changes.push((*id, false));
+            }
+            if let Some(peer) = self.peers.get_mut(id) {
+                peer.last_unchoked = Some(now);
+            }
+        }
+        self.unchoked = unchoked;
+        changes
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+use super::*;
+
+fn peer(id: usize, upload_rate: u64, last_unchoked: Option<Instant>, completion: f32) -> PeerStats {
+    PeerStats {
+        id,
+        upload_rate,
+        download_rate: 0,
+        last_unchoked,
+        completion,
+        interested: true,
+        snubbed: false,
+    }
+}
+
+#[test]
+fn test_fastest_upload() {
+    let peers = vec![peer(1, 10, None, 0.5), peer(2, 30, None, 0.5), peer(3, 20, None, 0.5)];
+    assert_eq!((seeding_unchokes(SeedStrategy::FastestUpload, &peers, 2)), vec![2, 3]);
+}
+
+#[test]
+fn test_round_robin() {
+    let now = Instant::now();
+    let peers = vec![
+        peer(1, 0, Some(now), 0.5),
+        peer(2, 0, Some(now - Duration::from_secs(30)), 0.5),
+        peer(3, 0, None, 0.5),
+    ];
+    assert_eq!((seeding_unchokes(SeedStrategy::RoundRobin, &peers, 2)), vec![3, 2]);
+    // Super-seeding takes turns the same way
+    assert_eq!((seeding_unchokes(SeedStrategy::SuperSeed, &peers, 2)), vec![3, 2]);
+}
+
+#[test]
+fn test_anti_leech() {
+    let peers = vec![peer(1, 50, None, 0.9), peer(2, 10, None, 0.1), peer(3, 20, None, 0.1)];
+    assert_eq!((seeding_unchokes(SeedStrategy::AntiLeech, &peers, 2)), vec![3, 2]);
+}
+
+#[test]
+fn test_uninterested_peers_are_skipped() {
+    let mut uninterested = peer(1, 100, None, 0.0);
+    uninterested.interested = false;
+    let peers = vec![uninterested, peer(2, 10, None, 0.0)];
+    assert_eq!((seeding_unchokes(SeedStrategy::FastestUpload, &peers, 2)), vec![2]);
+}
+
+#[test]
+fn test_leeching_unchokes() {
+    let mut peers = vec![peer(1, 90, None, 0.5), peer(2, 0, None, 0.5), peer(3, 0, None, 0.5)];
+    peers[1].download_rate = 30;
+    peers[2].download_rate = 20;
+    // While leeching, what we upload to a peer doesn't matter, only what it gives us
+    assert_eq!(leeching_unchokes(&peers, 2), vec![2, 3]);
+    // A snubbing peer's old rate doesn't keep it unchoked
+    peers[1].snubbed = true;
+    assert_eq!(leeching_unchokes(&peers, 2), vec![3, 1]);
+}
+
+#[test]
+fn test_choker() {
+    let now = Instant::now();
+    let mut choker = Choker::new(3);
+    for id in 0..5 {
+        choker.add_peer(id);
+        choker.set_interested(id, true);
+    }
+    choker.set_rates(3, 0, 3_000);
+    choker.set_rates(4, 0, 2_000);
+    choker.set_rates(0, 0, 1_000);
+    let changes = choker.recompute(false, SeedStrategy::FastestUpload, now);
+    // The two fastest peers, and one optimistic unchoke
+    assert_eq!(changes.len(), 3);
+    assert!(changes.iter().all(|(_, choke)| !choke));
+    assert!(choker.unchoked.contains(&3));
+    assert!(choker.unchoked.contains(&4));
+
+    // Peer 1 got fast, so the slowest regular peer is choked
+    choker.set_rates(1, 0, 5_000);
+    let optimistic = choker.optimistic.unwrap();
+    let changes = choker.recompute(false, SeedStrategy::FastestUpload, now + UNCHOKE_INTERVAL);
+    assert!(choker.unchoked.contains(&1));
+    assert!(choker.unchoked.contains(&3));
+    if optimistic != 1 {
+        assert!(choker.unchoked.contains(&optimistic));
+    }
+    assert_eq!(choker.unchoked.len(), 3);
+    assert!(changes.iter().any(|(id, choke)| *id == 1 && !choke) || optimistic == 1);
+
+    choker.remove_peer(1);
+    assert!(!choker.unchoked.contains(&1));
+}
+
+#[test]
+fn test_optimistic_unchoke_moves_on() {
+    let now = Instant::now();
+    let mut choker = Choker::new(1);
+    choker.add_peer(0);
+    choker.add_peer(1);
+    choker.set_interested(0, true);
+    choker.recompute(false, SeedStrategy::FastestUpload, now);
+    assert_eq!(choker.unchoked, vec![0]);
+    // A peer that loses interest gives up the optimistic unchoke early
+    choker.set_interested(1, true);
+    choker.set_interested(0, false);
+    let changes = choker.recompute(false, SeedStrategy::FastestUpload, now + UNCHOKE_INTERVAL);
+    assert_eq!(changes, vec![(0, true), (1, false)]);
+}
+
+#[test]
+fn test_choke_all() {
+    let now = Instant::now();
+    let mut choker = Choker::new(2);
+    choker.add_peer(0);
+    choker.set_interested(0, true);
+    choker.set_rates(0, 0, 1_000);
+    assert_eq!(choker.recompute(false, SeedStrategy::FastestUpload, now), vec![(0, false)]);
+    choker.choke_all();
+    assert!(choker.unchoked.is_empty());
+    // The peer was choked, so it has to be unchoked again
+    let changes = choker.recompute(false, SeedStrategy::FastestUpload, now + UNCHOKE_INTERVAL);
+    assert_eq!(changes, vec![(0, false)]);
+}
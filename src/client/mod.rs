@@ -0,0 +1,512 @@
+//! client runs torrents for programs embedding the engine.  A session of torrents runs on its own
+//! runtime, and each running torrent can be steered and watched through a handle.  The command
+//! line program is one of these programs.
+use crate::ban::{
+    BanList,
+    BanListError,
+};
+use crate::dht::Dht;
+use crate::events::Event;
+use crate::geoip::{
+    GeoIp,
+    GeoIpError,
+};
+use crate::listen::{
+    self,
+    Routes,
+};
+use crate::metainfo::MetaInfo;
+use crate::ratelimit::schedule::Scheduler;
+use crate::reachability::Reachability;
+use crate::rpc;
+use crate::server::{
+    self,
+    control::ServerHandle,
+    Server,
+};
+use crate::session::{
+    self,
+    QueueMove,
+    SessionError,
+    TorrentEntry,
+};
+use crate::stats::{
+    Snapshot,
+    StatsHandle,
+};
+use crate::storage::FileMap;
+use crate::tracker::passkey::{
+    PasskeyError,
+    Passkeys,
+};
+use derive_error::Error;
+use futures::{
+    channel::{
+        mpsc::{
+            unbounded,
+            UnboundedReceiver,
+        },
+        oneshot,
+    },
+    future,
+    StreamExt,
+};
+use log::error;
+use rand::prelude::*;
+use std::collections::HashSet;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use tokio::runtime::{
+    Handle,
+    Runtime,
+};
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The session could not be read or written
+    Session(SessionError),
+    /// The ban list could not be read
+    BanList(BanListError),
+    /// A GeoIP database could not be read
+    GeoIp(GeoIpError),
+    /// The passkeys could not be read
+    Passkey(PasskeyError),
+    /// The runtime could not be started
+    Io(io::Error),
+    /// The torrent file could not be used
+    #[error(non_std, no_from)]
+    InvalidTorrent(String),
+    /// The torrent is already in the session
+    #[error(non_std, no_from)]
+    AlreadyAdded(String),
+}
+
+/// What a session needs to run its torrents
+pub struct Settings {
+    // Where the session, bans and passkeys are kept
+    pub state_dir: PathBuf,
+    // Where added torrents are downloaded, unless they say otherwise
+    pub download_dir: String,
+    // How many torrents run at once, not counting force started, paused or finished ones.  None
+    // runs them all
+    pub max_active: Option<usize>,
+    // GeoIP databases, to look up where peers are
+    pub geoip: Option<Vec<String>>,
+    // The DHT node every torrent shares.  Its handle goes in the torrent settings
+    pub dht: Option<Dht>,
+    // Changes the rate limits in the torrent settings by the time of day
+    pub schedule: Option<Scheduler>,
+    // The settings every torrent starts from
+    pub torrent: server::Config,
+}
+
+/// A running torrent.  Clones steer the same torrent
+#[derive(Clone)]
+pub struct TorrentHandle {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    pub stats: StatsHandle,
+    pub handle: ServerHandle,
+}
+
+impl TorrentHandle {
+    /// Stop requesting pieces and choke every peer.  Peers are disconnected if `drop_peers` is
+    /// set
+    pub fn pause(&self, drop_peers: bool) {
+        self.handle.pause(drop_peers);
+    }
+
+    pub fn resume(&self) {
+        self.handle.resume();
+    }
+
+    /// Hash the data on disk again
+    pub fn verify(&self) {
+        self.handle.verify();
+    }
+
+    /// Stop the torrent for good, telling the tracker we left.  It stays in the session
+    pub fn stop(&self) {
+        self.handle.stop();
+    }
+
+    /// The torrent's latest progress and transfer stats
+    pub fn stats(&self) -> Snapshot {
+        self.stats.latest()
+    }
+}
+
+/// Every torrent we were given, and the ones running.  Torrents are kept in the state directory,
+/// so they come back in the next session
+pub struct Session {
+    launcher: Arc<Launcher>,
+    runtime: Runtime,
+    download_dir: String,
+}
+
+impl Session {
+    /// Read the session from the state directory and start the runtime.  No torrents are started
+    /// until asked for.  Torrents paused when the session last ran start paused, and rate limits
+    /// not given in `settings` are the ones last set
+    pub fn new(settings: Settings) -> Result<Self, ClientError> {
+        let store = session::Session::load(&settings.state_dir)?;
+        // Limits set through the API last until they are set again on the command line
+        let (download_limit, upload_limit) = store.limits();
+        if settings.torrent.download_throttle.rate().is_none() {
+            settings.torrent.download_throttle.set_rate(download_limit);
+        }
+        if settings.torrent.upload_throttle.rate().is_none() {
+            settings.torrent.upload_throttle.set_rate(upload_limit);
+        }
+        let passkeys = Passkeys::load(settings.state_dir.join("passkeys"))?;
+        let bans = BanList::load(settings.state_dir.join("bans"))?;
+        let bans = Arc::new(Mutex::new(bans));
+        let runtime = Runtime::new()?;
+        // Sockets are registered with the runtime they are made in
+        let entered = runtime.enter();
+        let mut config = settings.torrent;
+        // Every torrent looks peers up in the same databases
+        config.geoip = match &settings.geoip {
+            Some(paths) => Some(Arc::new(GeoIp::open(paths)?)),
+            None => None,
+        };
+        // Every torrent takes its peers' connections on the one port
+        if !config.refuse_incoming && config.i2p.is_none() {
+            // Looking up our address would go around the proxy
+            let ipv6 = config.proxy.is_none() && listen::global_ipv6().is_some();
+            let listener = listen::bind(config.port, ipv6)?;
+            let routes = Routes::new();
+            let reachability = Reachability::new();
+            // Torrents start the test when a tracker tells them our address, if the user didn't
+            if let Some(ip) = config.external_ip {
+                reachability.test(SocketAddr::new(ip, config.port));
+            }
+            runtime.spawn(listen::serve(listener, routes.clone(), reachability.clone(), bans.clone(),
+                                        config.blocklist.clone(), config.encryption));
+            config.routes = Some(routes);
+            config.reachability = Some(reachability);
+        }
+        if let Some(dht) = settings.dht {
+            runtime.spawn(dht);
+        }
+        if let Some(schedule) = settings.schedule {
+            runtime.spawn(schedule);
+        }
+        let events = config.events.subscribe();
+        let launcher = Arc::new(Launcher {
+            config,
+            max_active: settings.max_active,
+            peer_id: gen_peer_id(),
+            store: Arc::new(Mutex::new(store)),
+            passkeys: Arc::new(Mutex::new(passkeys)),
+            bans,
+            executor: runtime.handle().clone(),
+            torrents: Arc::new(Mutex::new(Vec::new())),
+            stopped: Mutex::new(Vec::new()),
+            completed: Mutex::new(HashSet::new()),
+            launching: Mutex::new(()),
+        });
+        // Torrents that finish or are paused make room for the next ones in the queue
+        if launcher.max_active.is_some() {
+            let queue = launcher.clone();
+            runtime.spawn(events.for_each(move |event| {
+                match event {
+                    Event::Completed { info_hash } => {
+                        queue.completed.lock().unwrap().insert(info_hash);
+                        queue.admit(|_, _| ());
+                    }
+                    Event::Paused { .. } => {
+                        queue.admit(|_, _| ());
+                    }
+                    _ => (),
+                }
+                future::ready(())
+            }));
+        }
+        drop(entered);
+        Ok(Session {
+            launcher,
+            runtime,
+            download_dir: settings.download_dir,
+        })
+    }
+
+    /// Every torrent in the session, running or not
+    pub fn entries(&self) -> Vec<TorrentEntry> {
+        self.launcher.store.lock().unwrap().torrents().to_vec()
+    }
+
+    /// Bytes uploaded and downloaded by every torrent of the session, over every run
+    pub fn totals(&self) -> (u64, u64) {
+        self.launcher.store.lock().unwrap().totals()
+    }
+
+    /// Use `key` as the passkey for every tracker on `host`
+    pub fn set_passkey(&self, host: &str, key: &str) {
+        self.launcher.passkeys.lock().unwrap().set(host, key);
+    }
+
+    /// Start the torrents of the session that should be running and aren't yet.  `prepare` sees
+    /// each torrent's files before it starts, to rename them
+    pub fn start<F: FnMut(&TorrentEntry, &mut FileMap)>(&self, prepare: F) -> Vec<TorrentHandle> {
+        self.launcher.admit(prepare)
+    }
+
+    /// Add a torrent file to the session, downloading into `download_dir` or the default
+    /// download directory, and start it
+    pub fn add_torrent(&self, contents: &[u8], download_dir: Option<&str>) -> Result<TorrentHandle, ClientError> {
+        let metainfo = MetaInfo::from_bytes(contents)
+            .map_err(|e| ClientError::InvalidTorrent(format!("Not a torrent file: {}", e)))?;
+        if !metainfo.info.is_v1() {
+            return Err(ClientError::InvalidTorrent("v2-only torrents can't be downloaded yet".to_string()));
+        }
+        let name = metainfo.info.file_info.name();
+        let added = self.launcher.store.lock().unwrap()
+            .add(metainfo.info_hash, name, contents, download_dir.unwrap_or(&self.download_dir))?;
+        if !added {
+            return Err(ClientError::AlreadyAdded(name.to_owned()));
+        }
+        self.launcher.start_torrent(&metainfo.info_hash)
+            .ok_or_else(|| ClientError::InvalidTorrent(format!("Could not start {}", name)))
+    }
+
+    /// Stop a torrent if it is running, and take it out of the session.  Its files stay on disk.
+    /// Returns false if it wasn't in the session
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Result<bool, ClientError> {
+        {
+            let mut store = self.launcher.store.lock().unwrap();
+            if store.get(info_hash).is_none() {
+                return Ok(false);
+            }
+            let mut torrents = self.launcher.torrents.lock().unwrap();
+            if let Some(i) = torrents.iter().position(|torrent| &torrent.info_hash == info_hash) {
+                torrents.remove(i).stop();
+            }
+            store.remove(info_hash)?;
+        }
+        self.launcher.completed.lock().unwrap().remove(info_hash);
+        // The next torrent in the queue can have its place
+        self.launcher.admit(|_, _| ());
+        Ok(true)
+    }
+
+    /// Move a torrent up or down the queue, and start what can run now
+    pub fn move_torrent(&self, info_hash: &[u8; 20], direction: QueueMove) -> Result<(), ClientError> {
+        self.launcher.store.lock().unwrap().move_torrent(info_hash, direction)?;
+        self.launcher.admit(|_, _| ());
+        Ok(())
+    }
+
+    /// Let a torrent run however many others are active, or put it back in the queue
+    pub fn set_force_start(&self, info_hash: &[u8; 20], force_start: bool) -> Result<(), ClientError> {
+        self.launcher.store.lock().unwrap().set_force_start(info_hash, force_start)?;
+        self.launcher.admit(|_, _| ());
+        Ok(())
+    }
+
+    /// The running torrents
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.launcher.torrents.lock().unwrap().clone()
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.launcher.torrents.lock().unwrap().iter().find(|torrent| &torrent.info_hash == info_hash).cloned()
+    }
+
+    /// What the torrents do from now on
+    pub fn events(&self) -> UnboundedReceiver<Event> {
+        self.launcher.config.events.subscribe()
+    }
+
+    /// Serve the HTTP API on `address`.  Torrents added through it are started in this session
+    pub fn serve_api(&self, address: &SocketAddr) -> hyper::Result<()> {
+        let (starts, start_requests) = unbounded();
+        let (queue, queue_changes) = unbounded();
+        let config = &self.launcher.config;
+        let api = rpc::Api {
+            torrents: self.launcher.torrents.clone(),
+            session: self.launcher.store.clone(),
+            download_throttle: config.download_throttle.clone(),
+            upload_throttle: config.upload_throttle.clone(),
+            download_dir: self.download_dir.clone(),
+            starts,
+            queue,
+            blocklist: config.blocklist.clone(),
+        };
+        let _entered = self.runtime.enter();
+        self.runtime.spawn(rpc::serve(api, address)?);
+        let launcher = self.launcher.clone();
+        self.runtime.spawn(start_requests.for_each(move |info_hash| {
+            launcher.start_torrent(&info_hash);
+            future::ready(())
+        }));
+        let launcher = self.launcher.clone();
+        self.runtime.spawn(queue_changes.for_each(move |()| {
+            launcher.admit(|_, _| ());
+            future::ready(())
+        }));
+        Ok(())
+    }
+
+    /// Wait for `stop`, then for every torrent to stop, and shut the runtime down.  Torrents stop
+    /// when their settings' shutdown future resolves, or when stopped through their handles.  The
+    /// torrents that ran are returned, with their final stats
+    pub fn run_until<F: Future>(self, stop: F) -> Vec<TorrentHandle> {
+        self.runtime.block_on(stop);
+        // Torrents can still be started while the others stop
+        loop {
+            let stopped: Vec<_> = self.launcher.stopped.lock().unwrap().drain(..).collect();
+            if stopped.is_empty() {
+                break;
+            }
+            let _res = self.runtime.block_on(future::join_all(stopped));
+        }
+        let torrents = self.torrents();
+        // Peer connections would keep the runtime going, so it is shut down without waiting for
+        // them
+        self.runtime.shutdown_background();
+        torrents
+    }
+}
+
+/// What it takes to start a torrent in the session.  Torrents added through the API are started
+/// on the runtime, so this owns everything it needs
+struct Launcher {
+    // The settings every torrent starts from
+    config: server::Config,
+    // How many torrents run at once, not counting force started, paused or finished ones
+    max_active: Option<usize>,
+    peer_id: [u8; 20],
+    store: Arc<Mutex<session::Session>>,
+    passkeys: Arc<Mutex<Passkeys>>,
+    // Peers every torrent refuses to talk to
+    bans: Arc<Mutex<BanList>>,
+    executor: Handle,
+    // The running torrents, shared with the API
+    torrents: Arc<Mutex<Vec<TorrentHandle>>>,
+    // Resolve as each started torrent stops
+    stopped: Mutex<Vec<oneshot::Receiver<()>>>,
+    // Torrents that got every wanted piece while running
+    completed: Mutex<HashSet<[u8; 20]>>,
+    // Held while a torrent starts, so the queue and the API can't start it twice
+    launching: Mutex<()>,
+}
+
+impl Launcher {
+    /// Start the torrents of the session that should be running and aren't yet.  `prepare` sees
+    /// each torrent's files before it starts
+    fn admit<F: FnMut(&TorrentEntry, &mut FileMap)>(&self, mut prepare: F) -> Vec<TorrentHandle> {
+        let finished = self.finished();
+        let entries: Vec<TorrentEntry> = self.store.lock().unwrap().active(self.max_active, &finished)
+            .into_iter()
+            .cloned()
+            .collect();
+        entries.iter()
+            .filter_map(|entry| self.launch(entry, |files| prepare(entry, files)))
+            .collect()
+    }
+
+    /// The running torrents that have every wanted piece, and only seed
+    fn finished(&self) -> HashSet<[u8; 20]> {
+        let mut finished = self.completed.lock().unwrap().clone();
+        // Torrents that were complete when they started never say they completed
+        finished.extend(self.torrents.lock().unwrap().iter()
+            .filter(|torrent| {
+                let stats = torrent.stats();
+                stats.size > 0 && stats.left == 0
+            })
+            .map(|torrent| torrent.info_hash));
+        finished
+    }
+
+    /// Start a torrent of the session by its info hash.  None if it can't be started
+    fn start_torrent(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        let entry = self.store.lock().unwrap().get(info_hash).cloned()?;
+        self.launch(&entry, |_| ())
+    }
+
+    /// Load a torrent, let `prepare` see its files, and run it on the runtime.  None if it is
+    /// already running
+    fn launch<F: FnOnce(&mut FileMap)>(&self, entry: &TorrentEntry, prepare: F) -> Option<TorrentHandle> {
+        let _launching = self.launching.lock().unwrap();
+        if self.torrents.lock().unwrap().iter().any(|torrent| torrent.info_hash == entry.info_hash) {
+            return None;
+        }
+        let (metainfo, mut files) = self.load(entry)?;
+        prepare(&mut files);
+        let _entered = self.executor.enter();
+        let server = self.server(entry, metainfo, files);
+        let torrent = TorrentHandle {
+            info_hash: entry.info_hash,
+            name: entry.name.clone(),
+            stats: server.stats(),
+            handle: server.handle(),
+        };
+        self.torrents.lock().unwrap().push(torrent.clone());
+        let (done, stopped) = oneshot::channel();
+        self.stopped.lock().unwrap().push(stopped);
+        self.executor.spawn(async move {
+            let _res = server.await;
+            let _res = done.send(());
+        });
+        Some(torrent)
+    }
+
+    /// Read a torrent's metainfo from the session, and map its files.  None if it can't be started
+    fn load(&self, entry: &TorrentEntry) -> Option<(MetaInfo, FileMap)> {
+        let contents = match fs::read(&entry.torrent_file) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Could not restore {}: {}", entry.name, e);
+                return None;
+            }
+        };
+        let metainfo = match MetaInfo::from_bytes(&contents) {
+            Ok(metainfo) => metainfo,
+            Err(e) => {
+                error!("Could not restore {}: {}", entry.name, e);
+                return None;
+            }
+        };
+
+        if !metainfo.info.is_v1() {
+            error!("Could not restore {}: v2-only torrents can't be downloaded yet", entry.name);
+            return None;
+        }
+
+        let files = FileMap::new(&entry.download_dir, &metainfo.info.file_info);
+        Some((metainfo, files))
+    }
+
+    /// Make the server for a loaded torrent
+    fn server(&self, entry: &TorrentEntry, metainfo: MetaInfo, files: FileMap) -> Server {
+        let mut config = self.config.clone();
+        config.file_priorities = entry.file_priorities.clone();
+        config.paused = entry.paused;
+        if let Some(priority) = entry.bandwidth_priority {
+            config.bandwidth_priority = priority;
+        }
+        config.seed_time = Duration::from_secs(entry.seed_time);
+        Server::new(self.peer_id, metainfo, files, self.bans.clone(), self.store.clone(), self.passkeys.clone(), config)
+    }
+}
+
+/// Make a peer id for this run of the client
+pub fn gen_peer_id() -> [u8; 20] {
+    // Generate peer id in Azures style ("-<2 letter client code><4 digit version number>-<12 random digits>")
+    let mut id = "-BO0001-".to_owned();
+    for _ in 0..12 {
+        id.push_str(&thread_rng().gen_range::<u8>(0, 9).to_string());
+    }
+    let mut res = [0; 20];
+    res.copy_from_slice(id.as_bytes());
+    res
+}
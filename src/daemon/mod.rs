@@ -0,0 +1,138 @@
+//! daemon lets the client run in the background: it detaches from the terminal, and keeps a pid
+//! file so scripts can find it and a second copy doesn't start on the same state directory
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Fork into the background and leave the terminal.  Only the child returns, in a session of its
+/// own with stdin, stdout and stderr on /dev/null.  It keeps the working directory, so relative
+/// paths given on the command line still work.  Nothing may have started a thread yet
+#[cfg(unix)]
+pub fn detach() -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let null = fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        _ => std::process::exit(0),
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Forking again means we aren't a session leader, so opening a terminal can't make it ours
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => (),
+        _ => std::process::exit(0),
+    }
+    for fd in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), *fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Running as a daemon is not supported on this platform"))
+}
+
+/// A file holding our pid, locked while we run and removed when this is dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Write our pid to `path`.  Fails if another process holds the file's lock, or the file names
+    /// a process that is still running, which is most likely another copy of the client.  A pid
+    /// file left by a crash is replaced.  The lock is kept across detach, so the file can be
+    /// claimed before forking and rewritten with the new pid after
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        if !lock(&file)? {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} is locked by another process", path.display())));
+        }
+        let running = fs::read_to_string(&path).ok()
+            .and_then(|contents| contents.trim().parse::<i32>().ok())
+            .filter(|pid| *pid != std::process::id() as i32 && is_running(*pid));
+        if let Some(pid) = running {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} says process {} is running", path.display(), pid)));
+        }
+        let mut pid_file = PidFile { path, file };
+        pid_file.rewrite()?;
+        Ok(pid_file)
+    }
+
+    /// Write our pid again.  Called after detaching, when it has changed
+    pub fn rewrite(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", std::process::id())?;
+        self.file.flush()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _res = fs::remove_file(&self.path);
+    }
+}
+
+/// Take an exclusive lock on the file without waiting.  Returns false if someone else holds it
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EWOULDBLOCK) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    // Signal 0 only checks whether the process exists.  EPERM means it does, but isn't ours
+    pid > 0 && (unsafe { libc::kill(pid, 0) } == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: i32) -> bool {
+    false
+}
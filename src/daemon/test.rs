@@ -0,0 +1,44 @@
+use super::*;
+use std::env;
+
+fn pid_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("boosttorrent2-test-daemon-{}-{}.pid", name, std::process::id()))
+}
+
+#[test]
+fn test_pid_file_is_removed_when_dropped() {
+    let path = pid_path("drop");
+    let pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    drop(pid_file);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_stale_pid_file_is_replaced() {
+    let path = pid_path("stale");
+    // No process can have the largest pid
+    fs::write(&path, format!("{}\n", i32::MAX)).unwrap();
+    let _pid_file = PidFile::create(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+}
+
+#[test]
+fn test_locked_pid_file_is_refused() {
+    let path = pid_path("locked");
+    let _pid_file = PidFile::create(&path).unwrap();
+    // Our own pid is in the file, so only the lock keeps a second copy out
+    let err = PidFile::create(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+}
+
+#[test]
+fn test_running_process_keeps_its_pid_file() {
+    let path = pid_path("running");
+    // pid 1 is always running
+    fs::write(&path, "1\n").unwrap();
+    let err = PidFile::create(&path).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,76 @@
+//! bloom holds the bloom filters of DHT scrapes (BEP 33).  Nodes answer a scrape with one filter
+//! of the ips of the seeds they store for a torrent and one of the other peers, and the size of
+//! each swarm is estimated from how many bits are set once the answers are merged.  Merging
+//! filters counts a peer stored by several nodes only once.
+use crypto::{
+    digest::Digest,
+    sha1::Sha1,
+};
+use std::net::IpAddr;
+
+#[cfg(test)]
+mod test;
+
+/// Size of a filter in bytes
+pub const FILTER_BYTES: usize = 256;
+
+/// Bits in a filter
+const M: usize = FILTER_BYTES * 8;
+
+/// Bits set per ip
+const K: f64 = 2.0;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct BloomFilter(pub [u8; FILTER_BYTES]);
+
+impl BloomFilter {
+    pub fn new() -> Self {
+        BloomFilter([0; FILTER_BYTES])
+    }
+
+    /// A filter sent by another node.  None if it is the wrong size
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != FILTER_BYTES {
+            return None;
+        }
+        let mut filter = [0; FILTER_BYTES];
+        filter.copy_from_slice(bytes);
+        Some(BloomFilter(filter))
+    }
+
+    pub fn insert(&mut self, ip: IpAddr) {
+        let mut hasher = Sha1::new();
+        match ip {
+            IpAddr::V4(ip) => hasher.input(&ip.octets()),
+            IpAddr::V6(ip) => hasher.input(&ip.octets()),
+        }
+        let mut hash = [0; 20];
+        hasher.result(&mut hash);
+        for index in &[hash[0] as usize | (hash[1] as usize) << 8, hash[2] as usize | (hash[3] as usize) << 8] {
+            let index = index % M;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Add the ips in `other` to ours
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *byte |= *other;
+        }
+    }
+
+    /// About how many different ips were inserted
+    pub fn estimate(&self) -> u32 {
+        let zeros: usize = self.0.iter().map(|byte| byte.count_zeros() as usize).sum();
+        // A full filter would estimate infinitely many
+        let zeros = zeros.max(1) as f64;
+        let m = M as f64;
+        ((zeros / m).ln() / (K * (1.0 - 1.0 / m).ln())).round() as u32
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        BloomFilter::new()
+    }
+}
@@ -0,0 +1,34 @@
+use super::*;
+use std::net::{
+    Ipv4Addr,
+    Ipv6Addr,
+};
+
+#[test]
+fn test_estimate() {
+    // The example from BEP 33
+    let mut filter = BloomFilter::new();
+    for i in 0..=255 {
+        filter.insert(Ipv4Addr::new(192, 0, 2, i).into());
+    }
+    for i in 0..=0x3e7 {
+        filter.insert(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i).into());
+    }
+    assert_eq!(filter.estimate(), 1225);
+    assert_eq!(BloomFilter::new().estimate(), 0);
+}
+
+#[test]
+fn test_union() {
+    let mut a = BloomFilter::new();
+    a.insert([10, 0, 0, 1].into());
+    a.insert([10, 0, 0, 2].into());
+    let mut b = BloomFilter::new();
+    b.insert([10, 0, 0, 2].into());
+    b.insert([10, 0, 0, 3].into());
+    // An ip in both only counts once
+    a.union(&b);
+    assert_eq!(a.estimate(), 3);
+    assert_eq!(BloomFilter::from_bytes(&a.0), Some(a));
+    assert_eq!(BloomFilter::from_bytes(&[0; 10]), None);
+}
@@ -0,0 +1,271 @@
+//! krpc is the message format of the DHT: bencoded dictionaries sent over UDP, each either a
+//! query, a response to a query, or an error
+use crate::boostencode::Value;
+use crate::types::{
+    decode_compact,
+    encode_compact,
+};
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use super::{
+    bloom::BloomFilter,
+    NodeId,
+};
+
+#[cfg(test)]
+mod test;
+
+/// A node's id and address, as found in the "nodes" key of responses
+pub type NodeInfo = (NodeId, SocketAddr);
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+        // If true, the sender wants bloom filters of the swarm too (BEP 33)
+        scrape: bool,
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+        // If true, the port to use is the one the query came from
+        implied_port: bool,
+        // If true, the sender has the whole torrent (BEP 33)
+        seed: bool,
+    },
+}
+
+/// The contents of a response.  Which keys are set depends on the query it answers
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Response {
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // The seeds and other peers the node stores, for a get_peers query that asked to scrape
+    pub seeds: Option<Box<BloomFilter>>,
+    pub peers: Option<Box<BloomFilter>>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(i64, String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    // Matches a response to its query
+    pub transaction: Vec<u8>,
+    // The sender's id.  Errors don't carry one
+    pub id: Option<NodeId>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let id = || Value::BString(self.id.map_or(Vec::new(), |id| id.0.to_vec()));
+        let mut map = hashmap! {
+            Vec::from("t") => Value::BString(self.transaction.clone()),
+        };
+        match &self.body {
+            Body::Query(query) => {
+                let mut args = hashmap! { Vec::from("id") => id() };
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.insert(Vec::from("target"), Value::BString(target.0.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash, scrape } => {
+                        args.insert(Vec::from("info_hash"), Value::BString(info_hash.to_vec()));
+                        if *scrape {
+                            args.insert(Vec::from("scrape"), Value::Integer(1));
+                        }
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer { info_hash, port, token, implied_port, seed } => {
+                        args.insert(Vec::from("info_hash"), Value::BString(info_hash.to_vec()));
+                        args.insert(Vec::from("port"), Value::Integer(*port as i64));
+                        args.insert(Vec::from("token"), Value::BString(token.clone()));
+                        args.insert(Vec::from("implied_port"), Value::Integer(*implied_port as i64));
+                        if *seed {
+                            args.insert(Vec::from("seed"), Value::Integer(1));
+                        }
+                        "announce_peer"
+                    }
+                };
+                map.insert(Vec::from("y"), Value::BString(Vec::from("q")));
+                map.insert(Vec::from("q"), Value::BString(Vec::from(name)));
+                map.insert(Vec::from("a"), Value::Dict(args));
+            }
+            Body::Response(response) => {
+                let mut values = hashmap! { Vec::from("id") => id() };
+                if !response.nodes.is_empty() {
+                    values.insert(Vec::from("nodes"), Value::BString(encode_nodes(&response.nodes)));
+                }
+                if !response.values.is_empty() {
+                    values.insert(Vec::from("values"), Value::List(response.values.iter()
+                        .filter_map(|address| encode_address(*address))
+                        .map(Value::BString)
+                        .collect()));
+                }
+                if let Some(token) = &response.token {
+                    values.insert(Vec::from("token"), Value::BString(token.clone()));
+                }
+                if let Some(seeds) = &response.seeds {
+                    values.insert(Vec::from("BFsd"), Value::BString(seeds.0.to_vec()));
+                }
+                if let Some(peers) = &response.peers {
+                    values.insert(Vec::from("BFpe"), Value::BString(peers.0.to_vec()));
+                }
+                map.insert(Vec::from("y"), Value::BString(Vec::from("r")));
+                map.insert(Vec::from("r"), Value::Dict(values));
+            }
+            Body::Error(code, msg) => {
+                map.insert(Vec::from("y"), Value::BString(Vec::from("e")));
+                map.insert(Vec::from("e"), Value::List(vec![
+                    Value::Integer(*code),
+                    Value::BString(Vec::from(msg.as_bytes())),
+                ]));
+            }
+        }
+        Value::Dict(map).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("{:?}", e))?;
+        let map = val.dict().ok_or("KRPC message not a dictionary".to_string())?;
+        let transaction = map.get("t".as_bytes()).and_then(Value::bstring)
+            .ok_or("Missing key: t".to_string())?
+            .clone();
+        let kind = map.get("y".as_bytes()).and_then(Value::bstring)
+            .ok_or("Missing key: y".to_string())?;
+
+        match kind.as_slice() {
+            b"q" => {
+                let args = map.get("a".as_bytes()).and_then(Value::dict)
+                    .ok_or("Missing key: a".to_string())?;
+                let id = node_id(args, "id")?;
+                let query = match map.get("q".as_bytes()).and_then(Value::bstring).map(Vec::as_slice) {
+                    Some(b"ping") => Query::Ping,
+                    Some(b"find_node") => Query::FindNode {
+                        target: node_id(args, "target")?,
+                    },
+                    Some(b"get_peers") => Query::GetPeers {
+                        info_hash: node_id(args, "info_hash")?.0,
+                        scrape: flag(args, "scrape"),
+                    },
+                    Some(b"announce_peer") => Query::AnnouncePeer {
+                        info_hash: node_id(args, "info_hash")?.0,
+                        port: args.get("port".as_bytes()).and_then(Value::integer)
+                            .filter(|port| **port > 0 && **port <= 65535)
+                            .map_or(0, |port| *port as u16),
+                        token: args.get("token".as_bytes()).and_then(Value::bstring)
+                            .ok_or("Missing key: token".to_string())?
+                            .clone(),
+                        implied_port: flag(args, "implied_port"),
+                        seed: flag(args, "seed"),
+                    },
+                    _ => return Err("Unknown query".to_string()),
+                };
+                Ok(Message {
+                    transaction,
+                    id: Some(id),
+                    body: Body::Query(query),
+                })
+            }
+            b"r" => {
+                let values = map.get("r".as_bytes()).and_then(Value::dict)
+                    .ok_or("Missing key: r".to_string())?;
+                let response = Response {
+                    nodes: values.get("nodes".as_bytes()).and_then(Value::bstring)
+                        .map_or(Vec::new(), |nodes| decode_nodes(nodes)),
+                    values: values.get("values".as_bytes()).and_then(Value::list)
+                        .map_or(Vec::new(), |list| list.iter()
+                            .filter_map(Value::bstring)
+                            .filter_map(|address| decode_address(address))
+                            .collect()),
+                    token: values.get("token".as_bytes()).and_then(Value::bstring).cloned(),
+                    seeds: values.get("BFsd".as_bytes()).and_then(Value::bstring)
+                        .and_then(|filter| BloomFilter::from_bytes(filter))
+                        .map(Box::new),
+                    peers: values.get("BFpe".as_bytes()).and_then(Value::bstring)
+                        .and_then(|filter| BloomFilter::from_bytes(filter))
+                        .map(Box::new),
+                };
+                Ok(Message {
+                    transaction,
+                    id: Some(node_id(values, "id")?),
+                    body: Body::Response(response),
+                })
+            }
+            b"e" => {
+                let error = map.get("e".as_bytes()).and_then(Value::list)
+                    .ok_or("Missing key: e".to_string())?;
+                let code = error.first().and_then(Value::integer).cloned().unwrap_or(0);
+                let msg = error.get(1).and_then(Value::bstring_utf8).unwrap_or_default();
+                Ok(Message {
+                    transaction,
+                    id: None,
+                    body: Body::Error(code, msg),
+                })
+            }
+            _ => Err("Invalid message type".to_string()),
+        }
+    }
+}
+
+fn node_id(map: &HashMap<Vec<u8>, Value>, key: &str) -> Result<NodeId, String> {
+    let bytes = map.get(key.as_bytes()).and_then(Value::bstring)
+        .filter(|bytes| bytes.len() == 20)
+        .ok_or(format!("Missing key: {}", key))?;
+    let mut id = [0; 20];
+    id.copy_from_slice(bytes);
+    Ok(NodeId(id))
+}
+
+/// Flags are integers, set when they are 1
+fn flag(map: &HashMap<Vec<u8>, Value>, key: &str) -> bool {
+    map.get(key.as_bytes()).and_then(Value::integer) == Some(&1)
+}
+
+/// Nodes are 20 bytes of id followed by a compact IPv4 address.  IPv6 nodes are left out
+pub fn encode_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(nodes.len() * 26);
+    for (id, address) in nodes {
+        if let Some(address) = encode_address(*address) {
+            res.extend_from_slice(&id.0);
+            res.extend_from_slice(&address);
+        }
+    }
+    res
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<NodeInfo> {
+    bytes.chunks_exact(26).filter_map(|chunk| {
+        let mut id = [0; 20];
+        id.copy_from_slice(&chunk[..20]);
+        Some((NodeId(id), decode_address(&chunk[20..])?))
+    }).collect()
+}
+
+fn encode_address(address: SocketAddr) -> Option<Vec<u8>> {
+    match address {
+        SocketAddr::V4(_) => Some(encode_compact(address)),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+fn decode_address(bytes: &[u8]) -> Option<SocketAddr> {
+    if bytes.len() != 6 {
+        return None;
+    }
+    decode_compact(bytes, 4).pop()
+}
@@ -0,0 +1,98 @@
+use super::*;
+
+fn round_trip(message: Message) {
+    assert_eq!(Message::decode(&message.encode()), Ok(message));
+}
+
+#[test]
+fn test_decode_ping() {
+    // The example from BEP 5
+    let message = Message::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+    assert_eq!(message, Message {
+        transaction: b"aa".to_vec(),
+        id: Some(NodeId(*b"abcdefghij0123456789")),
+        body: Body::Query(Query::Ping),
+    });
+    assert_eq!(message.encode(), b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe".to_vec());
+}
+
+#[test]
+fn test_queries_round_trip() {
+    let query = |query| Message {
+        transaction: b"tx".to_vec(),
+        id: Some(NodeId([1; 20])),
+        body: Body::Query(query),
+    };
+    round_trip(query(Query::FindNode { target: NodeId([2; 20]) }));
+    round_trip(query(Query::GetPeers { info_hash: [3; 20], scrape: false }));
+    round_trip(query(Query::GetPeers { info_hash: [3; 20], scrape: true }));
+    round_trip(query(Query::AnnouncePeer {
+        info_hash: [3; 20],
+        port: 6881,
+        token: b"secret".to_vec(),
+        implied_port: true,
+        seed: false,
+    }));
+    round_trip(query(Query::AnnouncePeer {
+        info_hash: [3; 20],
+        port: 6881,
+        token: b"secret".to_vec(),
+        implied_port: false,
+        seed: true,
+    }));
+}
+
+#[test]
+fn test_response_round_trip() {
+    round_trip(Message {
+        transaction: b"tx".to_vec(),
+        id: Some(NodeId([1; 20])),
+        body: Body::Response(Response {
+            nodes: vec![(NodeId([4; 20]), "1.2.3.4:5".parse().unwrap())],
+            values: vec!["5.6.7.8:6881".parse().unwrap()],
+            token: Some(b"token".to_vec()),
+            ..Response::default()
+        }),
+    });
+}
+
+#[test]
+fn test_scrape_response_round_trip() {
+    let mut seeds = BloomFilter::new();
+    seeds.insert([1, 2, 3, 4].into());
+    round_trip(Message {
+        transaction: b"tx".to_vec(),
+        id: Some(NodeId([1; 20])),
+        body: Body::Response(Response {
+            token: Some(b"token".to_vec()),
+            seeds: Some(Box::new(seeds)),
+            peers: Some(Box::new(BloomFilter::new())),
+            ..Response::default()
+        }),
+    });
+    // Filters of the wrong size are left out
+    let message = Message::decode(b"d1:rd4:BFsd3:abc2:id20:abcdefghij0123456789e1:t2:aa1:y1:re").unwrap();
+    assert_eq!(message.body, Body::Response(Response::default()));
+}
+
+#[test]
+fn test_decode_error() {
+    let message = Message::decode(b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee").unwrap();
+    assert_eq!(message.body, Body::Error(201, "A Generic Error Ocurred".to_string()));
+}
+
+#[test]
+fn test_reject_invalid() {
+    assert!(Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+    assert!(Message::decode(b"d1:t2:aa1:y1:xe").is_err());
+    assert!(Message::decode(b"garbage").is_err());
+}
+
+#[test]
+fn test_reject_deep_nesting() {
+    // Anyone can send us a datagram like this, and it must not overflow the stack
+    assert!(Message::decode(&[b'l'; 10_000]).is_err());
+    let mut nested = vec![b'l'; 10_000];
+    nested.extend_from_slice(&[b'e'; 10_000]);
+    assert!(Message::decode(&nested).is_err());
+}
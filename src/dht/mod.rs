@@ -0,0 +1,807 @@
+//! dht is a node of the mainline DHT (BEP 5), a Kademlia network that stores which peers are in
+//! which swarm.  It lets us find peers for torrents without a tracker, and for torrents whose
+//! trackers are down.
+use crate::tracker::ScrapeInfo;
+use crypto::{
+    digest::Digest,
+    sha1::Sha1,
+};
+use futures::channel::mpsc::{
+    unbounded,
+    UnboundedReceiver,
+    UnboundedSender,
+};
+use futures::channel::oneshot;
+use futures::StreamExt;
+use log::{
+    debug,
+    info,
+    trace,
+    warn,
+};
+use std::collections::{
+    HashMap,
+    VecDeque,
+};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::{
+    IpAddr,
+    SocketAddr,
+    ToSocketAddrs,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::pin::Pin;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+};
+use tokio::{
+    io::ReadBuf,
+    net::UdpSocket,
+    time::{
+        self,
+        Interval,
+    },
+};
+
+pub mod bloom;
+pub mod krpc;
+pub mod routing;
+#[cfg(test)]
+mod test;
+
+use self::bloom::BloomFilter;
+use self::krpc::{
+    Body,
+    Message,
+    NodeInfo,
+    Query,
+    Response,
+};
+use self::routing::{
+    K,
+    RoutingTable,
+};
+
+/// Well known nodes to join the DHT through
+pub const BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "router.utorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+];
+
+/// How many queries a lookup has in flight at once
+const ALPHA: usize = 3;
+
+/// How long a node gets to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often timeouts and refreshes are checked
+const TICK: Duration = Duration::from_secs(1);
+
+/// Buckets nothing has happened in for this long are refreshed with a lookup
+const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+
+/// How often the secret behind our tokens changes.  Tokens from the previous secret are still
+/// accepted, so a token is good for between one and two of these
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+/// Most peers to store per torrent, and most torrents to store peers for
+const MAX_STORED_PEERS: usize = 100;
+const MAX_STORED_TORRENTS: usize = 1000;
+
+/// The id of a node, or the info hash of a torrent, which live in the same space
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        NodeId(rand::random())
+    }
+
+    /// The Kademlia distance between two ids, which compares like a big endian number
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut res = [0; 20];
+        for (i, byte) in res.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        res
+    }
+
+    /// Which bucket of our routing table `other` goes in: the number of leading bits the ids
+    /// share.  An id identical to ours is 160
+    pub fn bucket(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        match distance.iter().position(|byte| *byte != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+
+    /// A random id that would go in bucket `bucket` of our routing table
+    pub fn random_in_bucket(&self, bucket: usize) -> NodeId {
+        let mut res: [u8; 20] = rand::random();
+        for bit in 0..=bucket.min(159) {
+            let mask = 0x80 >> (bit % 8);
+            let ours = self.0[bit / 8] & mask;
+            // Share every bit before `bucket`, and differ at `bucket`
+            let wanted = if bit == bucket { ours ^ mask } else { ours };
+            res[bit / 8] = (res[bit / 8] & !mask) | wanted;
+        }
+        NodeId(res)
+    }
+}
+
+enum Command {
+    GetPeers {
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        seed: bool,
+        reply: UnboundedSender<Vec<SocketAddr>>,
+    },
+    Scrape {
+        info_hash: [u8; 20],
+        reply: oneshot::Sender<ScrapeInfo>,
+    },
+    AddNode(SocketAddr),
+}
+
+/// Used by torrents to ask the DHT node for peers.  Clones talk to the same node
+#[derive(Clone)]
+pub struct DhtHandle {
+    commands: UnboundedSender<Command>,
+}
+
+impl DhtHandle {
+    /// Look for peers in a torrent's swarm.  Peers come out of the receiver in batches as they are
+    /// found, and it ends when the lookup is done.  If `announce_port` is set, we are added to the
+    /// swarm at that port too, as a seed if `seed` is set
+    pub fn get_peers(&self, info_hash: [u8; 20], announce_port: Option<u16>, seed: bool) -> UnboundedReceiver<Vec<SocketAddr>> {
+        let (reply, receiver) = unbounded();
+        let _res = self.commands.unbounded_send(Command::GetPeers {
+            info_hash,
+            announce_port,
+            seed,
+            reply,
+        });
+        receiver
+    }
+
+    /// Estimate how many seeds and leechers a torrent's swarm has from what the nodes closest to
+    /// it store (BEP 33).  The DHT doesn't count downloads, so `downloaded` is always 0.  The
+    /// receiver is cancelled if no node answered
+    pub fn scrape(&self, info_hash: [u8; 20]) -> oneshot::Receiver<ScrapeInfo> {
+        let (reply, receiver) = oneshot::channel();
+        let _res = self.commands.unbounded_send(Command::Scrape {
+            info_hash,
+            reply,
+        });
+        receiver
+    }
+
+    /// Tell the node about another node, such as one a peer told us about with a Port message
+    pub fn add_node(&self, address: SocketAddr) {
+        let _res = self.commands.unbounded_send(Command::AddNode(address));
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum NodeState {
+    New,
+    Waiting,
+    Responded,
+    Failed,
+}
+
+struct LookupNode {
+    // Bootstrap nodes are queried before we know their ids
+    id: Option<NodeId>,
+    address: SocketAddr,
+    state: NodeState,
+    // The token the node gave us, to announce to it with
+    token: Option<Vec<u8>>,
+}
+
+/// What a get_peers lookup does with what it finds
+struct GetPeers {
+    // Set if we join the swarm at this port once the lookup is done
+    announce_port: Option<u16>,
+    seed: bool,
+    reply: UnboundedSender<Vec<SocketAddr>>,
+}
+
+/// The swarm a scrape lookup has heard about so far.  Each node's filters are merged in, so a peer
+/// stored by several nodes counts once
+struct Scrape {
+    seeds: BloomFilter,
+    peers: BloomFilter,
+    // Whether any node answered at all
+    answered: bool,
+    reply: oneshot::Sender<ScrapeInfo>,
+}
+
+/// An iterative search for the nodes closest to a target.  Each step queries the closest nodes
+/// that haven't been asked yet, and they answer with nodes that are closer still
+struct Lookup {
+    target: NodeId,
+    nodes: Vec<LookupNode>,
+    // Set for get_peers lookups
+    get_peers: Option<GetPeers>,
+    // Set for scrape lookups, which are get_peers lookups that ask for bloom filters
+    scrape: Option<Scrape>,
+}
+
+impl Lookup {
+    fn new(target: NodeId, start: Vec<NodeInfo>, bootstrap: &[SocketAddr]) -> Self {
+        let mut lookup = Lookup {
+            target,
+            nodes: Vec::new(),
+            get_peers: None,
+            scrape: None,
+        };
+        lookup.add_nodes(start);
+        for address in bootstrap {
+            lookup.nodes.push(LookupNode {
+                id: None,
+                address: *address,
+                state: NodeState::New,
+                token: None,
+            });
+        }
+        lookup
+    }
+
+    /// Add nodes we heard about, keeping the list sorted by distance to the target
+    fn add_nodes(&mut self, nodes: Vec<NodeInfo>) {
+        for (id, address) in nodes {
+            if self.nodes.iter().any(|node| node.address == address || node.id == Some(id)) {
+                continue;
+            }
+            self.nodes.push(LookupNode {
+                id: Some(id),
+                address,
+                state: NodeState::New,
+                token: None,
+            });
+        }
+        let target = self.target;
+        // Nodes without ids go last
+        self.nodes.sort_by_key(|node| node.id.map(|id| id.distance(&target)).unwrap_or([0xff; 20]));
+    }
+
+    fn in_flight(&self) -> usize {
+        self.nodes.iter().filter(|node| node.state == NodeState::Waiting).count()
+    }
+
+    /// The next nodes to query.  Only the K closest nodes that haven't failed are ever queried
+    fn next(&mut self) -> Vec<SocketAddr> {
+        let mut wanted = ALPHA.saturating_sub(self.in_flight());
+        let mut res = Vec::new();
+        for node in self.nodes.iter_mut().filter(|node| node.state != NodeState::Failed).take(K) {
+            if wanted == 0 {
+                break;
+            }
+            if node.state == NodeState::New {
+                node.state = NodeState::Waiting;
+                res.push(node.address);
+                wanted -= 1;
+            }
+        }
+        res
+    }
+
+    fn responded(&mut self, address: SocketAddr, id: NodeId, response: Response) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.address == address) {
+            node.id = Some(id);
+            node.state = NodeState::Responded;
+            node.token = response.token;
+        }
+        if !response.values.is_empty() {
+            if let Some(get_peers) = &self.get_peers {
+                let _res = get_peers.reply.unbounded_send(response.values);
+            }
+        }
+        if let Some(scrape) = &mut self.scrape {
+            scrape.answered = true;
+            if let Some(seeds) = &response.seeds {
+                scrape.seeds.union(seeds);
+            }
+            if let Some(peers) = &response.peers {
+                scrape.peers.union(peers);
+            }
+        }
+        self.add_nodes(response.nodes);
+    }
+
+    fn failed(&mut self, address: SocketAddr) {
+        if let Some(node) = self.nodes.iter_mut().find(|node| node.address == address) {
+            node.state = NodeState::Failed;
+        }
+    }
+
+    /// The closest nodes that answered, with the tokens to announce to them with
+    fn announce_targets(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.nodes.iter()
+            .filter(|node| node.state == NodeState::Responded)
+            .take(K)
+            .filter_map(|node| Some((node.address, node.token.clone()?)))
+            .collect()
+    }
+}
+
+/// A query we are waiting on an answer to
+struct Pending {
+    address: SocketAddr,
+    id: Option<NodeId>,
+    lookup: Option<u64>,
+    sent: Instant,
+}
+
+/// Hands out and checks the tokens nodes need to announce to us.  A token is tied to the address
+/// it was given to, so nobody can announce someone else
+struct Tokens {
+    secret: [u8; 20],
+    previous: [u8; 20],
+    rotated: Instant,
+}
+
+impl Tokens {
+    fn new(now: Instant) -> Self {
+        Tokens {
+            secret: rand::random(),
+            previous: rand::random(),
+            rotated: now,
+        }
+    }
+
+    fn rotate_if_due(&mut self, now: Instant) {
+        if now.duration_since(self.rotated) >= TOKEN_ROTATION {
+            self.previous = self.secret;
+            self.secret = rand::random();
+            self.rotated = now;
+        }
+    }
+
+    fn token(&self, ip: IpAddr) -> Vec<u8> {
+        make_token(&self.secret, ip)
+    }
+
+    fn is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == &make_token(&self.secret, ip)[..] || token == &make_token(&self.previous, ip)[..]
+    }
+}
+
+fn make_token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.input(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.input(&ip.octets()),
+        IpAddr::V6(ip) => hasher.input(&ip.octets()),
+    }
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+    hash[..8].to_vec()
+}
+
+/// A DHT node.  It runs until every handle to it is dropped
+pub struct Dht {
+    // Bound when the node is made, so errors show up right away, and handed to the runtime on the
+    // first poll
+    bound: Option<std::net::UdpSocket>,
+    socket: Option<UdpSocket>,
+    own_id: NodeId,
+    table: RoutingTable,
+    commands: UnboundedReceiver<Command>,
+    // Packets waiting for room in the socket's send buffer
+    outbox: VecDeque<(Vec<u8>, SocketAddr)>,
+    pending: HashMap<Vec<u8>, Pending>,
+    next_transaction: u16,
+    lookups: HashMap<u64, Lookup>,
+    next_lookup: u64,
+    // Peers announced to us by info hash, and whether each is a seed
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, bool)>>,
+    tokens: Tokens,
+    // Made on the first poll, as timers need the runtime
+    tick: Option<Interval>,
+    // Nodes to join through when the routing table is empty
+    bootstrap: Vec<SocketAddr>,
+    // Where the routing table is saved when the node stops
+    state_path: Option<PathBuf>,
+}
+
+impl Dht {
+    /// Start a node on `port`.  `extra_nodes` are nodes to bootstrap from on top of the well known
+    /// ones, like the nodes listed in a torrent file.  If `state_path` is set, the routing table
+    /// saved there last time is loaded, and saved again when the node stops
+    pub fn new(port: u16, extra_nodes: &[String], state_path: Option<PathBuf>) -> io::Result<(Dht, DhtHandle)> {
+        let socket = std::net::UdpSocket::bind(SocketAddr::new([0, 0, 0, 0].into(), port))?;
+        socket.set_nonblocking(true)?;
+        // Looking up the bootstrap nodes is blocking, but only done once at startup
+        let bootstrap = BOOTSTRAP_NODES.iter()
+            .map(|node| node.to_string())
+            .chain(extra_nodes.iter().cloned())
+            .filter_map(|node| node.to_socket_addrs().ok()?.find(SocketAddr::is_ipv4))
+            .collect();
+        let (commands_sender, commands) = unbounded();
+        let now = Instant::now();
+        let table = state_path.as_ref().and_then(|path| load_table(path));
+        let own_id = table.as_ref().map_or_else(NodeId::random, RoutingTable::own_id);
+        let mut dht = Dht {
+            bound: Some(socket),
+            socket: None,
+            own_id,
+            table: table.unwrap_or_else(|| RoutingTable::new(own_id)),
+            commands,
+            outbox: VecDeque::new(),
+            pending: HashMap::new(),
+            next_transaction: rand::random(),
+            lookups: HashMap::new(),
+            next_lookup: 0,
+            peers: HashMap::new(),
+            tokens: Tokens::new(now),
+            tick: None,
+            bootstrap,
+            state_path,
+        };
+        // Saved nodes get us started, and the lookup fills the table back in with live ones
+        dht.start_lookup(own_id, None, None);
+        Ok((dht, DhtHandle { commands: commands_sender }))
+    }
+
+    fn start_lookup(&mut self, target: NodeId, get_peers: Option<GetPeers>, scrape: Option<Scrape>) {
+        let closest = self.table.closest(&target, K);
+        let bootstrap = if closest.len() < K {
+            self.bootstrap.clone()
+        } else {
+            Vec::new()
+        };
+        let mut lookup = Lookup::new(target, closest, &bootstrap);
+        lookup.get_peers = get_peers;
+        lookup.scrape = scrape;
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+        self.lookups.insert(id, lookup);
+        self.step_lookup(id);
+    }
+
+    /// Send the next queries of a lookup, or finish it
+    fn step_lookup(&mut self, id: u64) {
+        let (target, addresses, is_get_peers, scrape) = match self.lookups.get_mut(&id) {
+            Some(lookup) => (lookup.target, lookup.next(), lookup.get_peers.is_some(), lookup.scrape.is_some()),
+            None => return,
+        };
+        for address in addresses {
+            let query = if is_get_peers || scrape {
+                Query::GetPeers { info_hash: target.0, scrape }
+            } else {
+                Query::FindNode { target }
+            };
+            self.send_query(address, None, query, Some(id));
+        }
+        // With nothing in flight after asking for more, there is nobody closer left to ask
+        if self.lookups.get(&id).is_some_and(|lookup| lookup.in_flight() == 0) {
+            let lookup = self.lookups.remove(&id).unwrap();
+            trace!("DHT lookup finished with {} nodes", lookup.nodes.len());
+            if let Some(GetPeers { announce_port: Some(port), seed, .. }) = lookup.get_peers {
+                for (address, token) in lookup.announce_targets() {
+                    self.send_query(address, None, Query::AnnouncePeer {
+                        info_hash: target.0,
+                        port,
+                        token,
+                        implied_port: false,
+                        seed,
+                    }, None);
+                }
+            }
+            if let Some(scrape) = lookup.scrape {
+                if scrape.answered {
+                    let _res = scrape.reply.send(ScrapeInfo {
+                        complete: scrape.seeds.estimate(),
+                        incomplete: scrape.peers.estimate(),
+                        downloaded: 0,
+                    });
+                }
+            }
+        }
+    }
+
+    fn send_query(&mut self, address: SocketAddr, id: Option<NodeId>, query: Query, lookup: Option<u64>) {
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let transaction = self.next_transaction.to_be_bytes().to_vec();
+        let message = Message {
+            transaction: transaction.clone(),
+            id: Some(self.own_id),
+            body: Body::Query(query),
+        };
+        self.outbox.push_back((message.encode(), address));
+        self.pending.insert(transaction, Pending {
+            address,
+            id,
+            lookup,
+            sent: Instant::now(),
+        });
+    }
+
+    fn handle_packet(&mut self, packet: &[u8], from: SocketAddr) {
+        let message = match Message::decode(packet) {
+            Ok(message) => message,
+            Err(e) => {
+                trace!("Invalid DHT message from {}: {}", from, e);
+                return;
+            }
+        };
+        let now = SystemTime::now();
+        match message.body {
+            Body::Query(query) => {
+                if let Some(id) = message.id {
+                    self.table.insert(id, from, now);
+                }
+                let body = self.answer(query, from);
+                let reply = Message {
+                    transaction: message.transaction,
+                    id: Some(self.own_id),
+                    body,
+                };
+                self.outbox.push_back((reply.encode(), from));
+            }
+            Body::Response(response) => {
+                let pending = match self.pending.remove(&message.transaction) {
+                    Some(pending) if pending.address == from => pending,
+                    Some(pending) => {
+                        // Not from the node we asked, so keep waiting for the real answer
+                        self.pending.insert(message.transaction, pending);
+                        return;
+                    }
+                    None => return,
+                };
+                let id = match message.id {
+                    Some(id) => id,
+                    None => return,
+                };
+                self.table.insert(id, from, now);
+                if let Some(lookup_id) = pending.lookup {
+                    if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                        lookup.responded(from, id, response);
+                    }
+                    self.step_lookup(lookup_id);
+                }
+            }
+            Body::Error(code, msg) => {
+                debug!("DHT node {} answered with error {}: {}", from, code, msg);
+                if let Some(pending) = self.pending.remove(&message.transaction) {
+                    self.query_failed(pending);
+                }
+            }
+        }
+    }
+
+    /// What to answer a query from another node with
+    fn answer(&mut self, query: Query, from: SocketAddr) -> Body {
+        match query {
+            Query::Ping => Body::Response(Response::default()),
+            Query::FindNode { target } => Body::Response(Response {
+                nodes: self.table.closest(&target, K),
+                ..Response::default()
+            }),
+            Query::GetPeers { info_hash, scrape } => {
+                let stored = self.peers.get(&info_hash).map_or(&[][..], Vec::as_slice);
+                let values: Vec<SocketAddr> = stored.iter().map(|(address, _)| *address).collect();
+                let nodes = if values.is_empty() {
+                    self.table.closest(&NodeId(info_hash), K)
+                } else {
+                    Vec::new()
+                };
+                // Nodes that store nothing for the torrent have nothing to add to a scrape
+                let (seeds, peers) = if scrape && !stored.is_empty() {
+                    let mut seeds = BloomFilter::new();
+                    let mut peers = BloomFilter::new();
+                    for (address, seed) in stored {
+                        if *seed {
+                            seeds.insert(address.ip());
+                        } else {
+                            peers.insert(address.ip());
+                        }
+                    }
+                    (Some(Box::new(seeds)), Some(Box::new(peers)))
+                } else {
+                    (None, None)
+                };
+                Body::Response(Response {
+                    nodes,
+                    values,
+                    token: Some(self.tokens.token(from.ip())),
+                    seeds,
+                    peers,
+                })
+            }
+            Query::AnnouncePeer { info_hash, port, token, implied_port, seed } => {
+                if !self.tokens.is_valid(&token, from.ip()) {
+                    return Body::Error(203, "Bad token".to_string());
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Body::Error(203, "Bad port".to_string());
+                }
+                if self.peers.len() < MAX_STORED_TORRENTS || self.peers.contains_key(&info_hash) {
+                    let peers = self.peers.entry(info_hash).or_default();
+                    let address = SocketAddr::new(from.ip(), port);
+                    match peers.iter_mut().find(|(stored, _)| *stored == address) {
+                        // A leecher that finished announces again as a seed
+                        Some(stored) => stored.1 = seed,
+                        None => {
+                            if peers.len() >= MAX_STORED_PEERS {
+                                peers.remove(0);
+                            }
+                            peers.push((address, seed));
+                        }
+                    }
+                }
+                Body::Response(Response::default())
+            }
+        }
+    }
+
+    fn query_failed(&mut self, pending: Pending) {
+        if let Some(id) = pending.id {
+            self.table.failed(&id);
+        }
+        if let Some(lookup_id) = pending.lookup {
+            if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                if let Some(node) = lookup.nodes.iter().find(|node| node.address == pending.address) {
+                    if let Some(id) = node.id {
+                        self.table.failed(&id);
+                    }
+                }
+                lookup.failed(pending.address);
+            }
+            self.step_lookup(lookup_id);
+        }
+    }
+
+    /// Give up on queries that weren't answered in time, keep the buckets fresh, and rejoin the
+    /// network if we lost every node
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Vec<u8>> = self.pending.iter()
+            .filter(|(_, pending)| now.duration_since(pending.sent) >= QUERY_TIMEOUT)
+            .map(|(transaction, _)| transaction.clone())
+            .collect();
+        for transaction in expired {
+            if let Some(pending) = self.pending.remove(&transaction) {
+                self.query_failed(pending);
+            }
+        }
+        self.tokens.rotate_if_due(now);
+        if self.table.is_empty() && self.lookups.is_empty() {
+            self.start_lookup(self.own_id, None, None);
+        } else {
+            for target in self.table.refresh_targets(BUCKET_REFRESH, SystemTime::now()) {
+                self.start_lookup(target, None, None);
+            }
+        }
+    }
+}
+
+/// Load the routing table saved at `path`.  None if there is none, or it can't be used
+fn load_table(path: &Path) -> Option<RoutingTable> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Could not read the DHT routing table: {}", e);
+            return None;
+        }
+    };
+    match RoutingTable::decode(&bytes, SystemTime::now()) {
+        Ok(table) => {
+            info!("Loaded {} DHT nodes", table.len());
+            Some(table)
+        }
+        Err(e) => {
+            warn!("Could not load the DHT routing table: {}", e);
+            None
+        }
+    }
+}
+
+impl Drop for Dht {
+    /// The runtime drops the node when the client stops, whether or not it finished
+    fn drop(&mut self) {
+        let path = match &self.state_path {
+            Some(path) => path,
+            None => return,
+        };
+        // Written next to the old table first, so a crash while saving doesn't lose it
+        let tmp = path.with_extension("tmp");
+        let res = path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::write(&tmp, self.table.encode()))
+            .and_then(|()| fs::rename(&tmp, path));
+        match res {
+            Ok(()) => debug!("Saved {} DHT nodes", self.table.len()),
+            Err(e) => warn!("Could not save the DHT routing table: {}", e),
+        }
+    }
+}
+
+impl Future for Dht {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            match this.commands.poll_next_unpin(cx) {
+                Poll::Ready(Some(Command::GetPeers { info_hash, announce_port, seed, reply })) => {
+                    this.start_lookup(NodeId(info_hash), Some(GetPeers { announce_port, seed, reply }), None);
+                }
+                Poll::Ready(Some(Command::Scrape { info_hash, reply })) => {
+                    this.start_lookup(NodeId(info_hash), None, Some(Scrape {
+                        seeds: BloomFilter::new(),
+                        peers: BloomFilter::new(),
+                        answered: false,
+                        reply,
+                    }));
+                }
+                Poll::Ready(Some(Command::AddNode(address))) => {
+                    this.send_query(address, None, Query::Ping, None);
+                }
+                // Nobody can use the node anymore
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some(bound) = this.bound.take() {
+            match UdpSocket::from_std(bound) {
+                Ok(socket) => this.socket = Some(socket),
+                Err(e) => {
+                    warn!("Could not start the DHT node: {}", e);
+                    return Poll::Ready(());
+                }
+            }
+        }
+        if this.socket.is_none() {
+            return Poll::Ready(());
+        }
+
+        let mut buf = [0u8; 2048];
+        loop {
+            let mut read = ReadBuf::new(&mut buf);
+            match this.socket.as_ref().unwrap().poll_recv_from(cx, &mut read) {
+                Poll::Ready(Ok(from)) => {
+                    let len = read.filled().len();
+                    this.handle_packet(&buf[..len], from)
+                }
+                Poll::Pending => break,
+                // Errors like ICMP port unreachable only concern one packet
+                Poll::Ready(Err(e)) => trace!("DHT socket error: {}", e),
+            }
+        }
+
+        let start = time::Instant::now() + TICK;
+        while this.tick.get_or_insert_with(|| time::interval_at(start, TICK)).poll_tick(cx).is_ready() {
+            this.tick();
+        }
+
+        while let Some((packet, address)) = this.outbox.pop_front() {
+            match this.socket.as_ref().unwrap().poll_send_to(cx, &packet, address) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Pending => {
+                    this.outbox.push_front((packet, address));
+                    break;
+                }
+                Poll::Ready(Err(e)) => trace!("Could not send to DHT node {}: {}", address, e),
+            }
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,245 @@
+//! routing is the Kademlia routing table.  Nodes are sorted into buckets by how many leading bits
+//! of their id they share with ours, and each bucket holds at most K nodes, so we know many nodes
+//! close to us and a few far away.
+//!
+//! The table is saved when the node stops, so the next run starts out knowing the network instead
+//! of bootstrapping through the well known routers.  Times are wall clock times for that reason:
+//! they have to mean something after a restart.
+use crate::boostencode::Value;
+use crate::types::{
+    decode_compact,
+    encode_compact,
+};
+use maplit::hashmap;
+use std::net::SocketAddr;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::{
+    krpc::NodeInfo,
+    NodeId,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Most nodes in a bucket
+pub const K: usize = 8;
+
+/// Nodes that fail to answer this many queries in a row can be replaced
+const MAX_FAILURES: u32 = 3;
+
+/// Nodes we haven't heard from in this long are questionable (BEP 5), and give up their place in
+/// a full bucket to a node that just got in touch
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Saved nodes we last heard from longer ago than this are not loaded
+const MAX_SAVED_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+struct Node {
+    id: NodeId,
+    address: SocketAddr,
+    failures: u32,
+    // When the node last sent us a query or answered one of ours
+    last_seen: SystemTime,
+}
+
+impl Node {
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+struct Bucket {
+    nodes: Vec<Node>,
+    // When a node in the bucket was last added or heard from
+    last_changed: SystemTime,
+}
+
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        let now = SystemTime::now();
+        RoutingTable {
+            own_id,
+            buckets: (0..160).map(|_| Bucket {
+                nodes: Vec::new(),
+                last_changed: now,
+            }).collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(|bucket| bucket.nodes.is_empty())
+    }
+
+    /// Add a node we heard from, or mark it as alive if we know it.  Returns false if its bucket is
+    /// full of good nodes, in which case the node is left out
+    pub fn insert(&mut self, id: NodeId, address: SocketAddr, now: SystemTime) -> bool {
+        if id == self.own_id {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.own_id.bucket(&id)];
+        if let Some(node) = bucket.nodes.iter_mut().find(|node| node.id == id) {
+            node.address = address;
+            node.failures = 0;
+            node.last_seen = now;
+            bucket.last_changed = now;
+            return true;
+        }
+        let node = Node {
+            id,
+            address,
+            failures: 0,
+            last_seen: now,
+        };
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(node);
+        } else if let Some(bad) = bucket.nodes.iter_mut().find(|node| node.is_bad()) {
+            *bad = node;
+        } else {
+            // Without a bad node to replace, the one we haven't heard from the longest goes, as
+            // long as it is questionable
+            let oldest = bucket.nodes.iter_mut().min_by_key(|node| node.last_seen).unwrap();
+            if now.duration_since(oldest.last_seen).unwrap_or_default() < QUESTIONABLE_AFTER {
+                return false;
+            }
+            *oldest = node;
+        }
+        bucket.last_changed = now;
+        true
+    }
+
+    /// A node didn't answer a query
+    pub fn failed(&mut self, id: &NodeId) {
+        let bucket = &mut self.buckets[self.own_id.bucket(id)];
+        if let Some(node) = bucket.nodes.iter_mut().find(|node| node.id == *id) {
+            node.failures += 1;
+        }
+    }
+
+    /// The `count` good nodes closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| !node.is_bad())
+            .map(|node| (node.id, node.address))
+            .collect();
+        nodes.sort_by_key(|(id, _)| id.distance(target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Random ids in every bucket nothing has happened in for `max_age`, to look up so the
+    /// buckets fill with live nodes
+    pub fn refresh_targets(&mut self, max_age: Duration, now: SystemTime) -> Vec<NodeId> {
+        let own_id = self.own_id;
+        let mut targets = Vec::new();
+        // Only buckets up to the first empty one are worth refreshing.  Past that, there are
+        // almost certainly no nodes to find
+        for (i, bucket) in self.buckets.iter_mut().enumerate() {
+            if now.duration_since(bucket.last_changed).unwrap_or_default() >= max_age {
+                bucket.last_changed = now;
+                targets.push(own_id.random_in_bucket(i));
+            }
+            if bucket.nodes.is_empty() {
+                break;
+            }
+        }
+        targets
+    }
+    /// Our id and the nodes that aren't bad, with when we last heard from each, to be loaded with
+    /// `decode` next time
+    pub fn encode(&self) -> Vec<u8> {
+        let nodes = self.buckets.iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| !node.is_bad())
+            .map(|node| Value::Dict(hashmap! {
+                Vec::from("id") => Value::BString(node.id.0.to_vec()),
+                Vec::from("address") => Value::BString(encode_compact(node.address)),
+                Vec::from("last_seen") => Value::Integer(unix_time(node.last_seen) as i64),
+            }))
+            .collect();
+        Value::Dict(hashmap! {
+            Vec::from("id") => Value::BString(self.own_id.0.to_vec()),
+            Vec::from("nodes") => Value::List(nodes),
+        }).encode()
+    }
+
+    /// Load a table saved with `encode`.  Nodes too old to be worth trying are left out, and
+    /// each bucket counts as last changed when its newest node was last heard from, so buckets
+    /// that went quiet are refreshed right away
+    pub fn decode(bytes: &[u8], now: SystemTime) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("{:?}", e))?;
+        let map = val.dict().ok_or("Routing table not a dictionary".to_string())?;
+        let own_id = map.get("id".as_bytes()).and_then(Value::bstring)
+            .and_then(|id| node_id(id))
+            .ok_or("Missing key: id".to_string())?;
+        let nodes = map.get("nodes".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: nodes".to_string())?;
+
+        let mut table = RoutingTable::new(own_id);
+        for bucket in &mut table.buckets {
+            bucket.last_changed = UNIX_EPOCH;
+        }
+        for node in nodes {
+            let node = node.dict().ok_or("Node not a dictionary".to_string())?;
+            let id = node.get("id".as_bytes()).and_then(Value::bstring)
+                .and_then(|id| node_id(id))
+                .ok_or("Node missing key: id".to_string())?;
+            let address = node.get("address".as_bytes()).and_then(Value::bstring)
+                .and_then(|address| match address.len() {
+                    6 => decode_compact(address, 4).pop(),
+                    18 => decode_compact(address, 16).pop(),
+                    _ => None,
+                })
+                .ok_or("Node missing key: address".to_string())?;
+            let last_seen = node.get("last_seen".as_bytes()).and_then(Value::integer)
+                .filter(|secs| **secs >= 0)
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs as u64))
+                .ok_or("Node missing key: last_seen".to_string())?;
+            if now.duration_since(last_seen).unwrap_or_default() > MAX_SAVED_AGE {
+                continue;
+            }
+            let bucket = &mut table.buckets[own_id.bucket(&id)];
+            if id == own_id || bucket.nodes.len() >= K || bucket.nodes.iter().any(|node| node.id == id) {
+                continue;
+            }
+            bucket.nodes.push(Node {
+                id,
+                address,
+                failures: 0,
+                last_seen,
+            });
+            bucket.last_changed = bucket.last_changed.max(last_seen);
+        }
+        Ok(table)
+    }
+
+    pub fn own_id(&self) -> NodeId {
+        self.own_id
+    }
+}
+
+fn node_id(bytes: &[u8]) -> Option<NodeId> {
+    if bytes.len() != 20 {
+        return None;
+    }
+    let mut id = [0; 20];
+    id.copy_from_slice(bytes);
+    Some(NodeId(id))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,107 @@
+use super::*;
+
+fn id(first: u8) -> NodeId {
+    let mut id = [0; 20];
+    id[0] = first;
+    NodeId(id)
+}
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::new([1, 2, 3, 4].into(), port)
+}
+
+#[test]
+fn test_closest() {
+    let now = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    for i in 1..=5 {
+        assert!(table.insert(id(i), address(i as u16), now));
+    }
+    // Our own id is never stored
+    assert!(!table.insert(id(0), address(0), now));
+    assert_eq!(table.len(), 5);
+    let closest: Vec<_> = table.closest(&id(4), 3).into_iter().map(|(id, _)| id.0[0]).collect();
+    assert_eq!(closest, vec![4, 5, 1]);
+}
+
+#[test]
+fn test_full_bucket_replaces_failed_nodes() {
+    let now = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    // Every id with the top bit set goes in the same bucket
+    for i in 0..K as u8 {
+        assert!(table.insert(id(0x80 | i), address(i as u16), now));
+    }
+    assert!(!table.insert(id(0xff), address(100), now));
+    for _ in 0..MAX_FAILURES {
+        table.failed(&id(0x80));
+    }
+    assert!(table.insert(id(0xff), address(100), now));
+    assert_eq!(table.len(), K);
+    assert!(table.closest(&id(0x80), K).iter().all(|(id, _)| *id != self::id(0x80)));
+}
+
+#[test]
+fn test_refresh_targets() {
+    let start = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    table.insert(id(0x80), address(1), start);
+    let later = start + Duration::from_secs(60);
+    // Bucket 0 and bucket 1, the first empty one, have both gone quiet
+    let targets = table.refresh_targets(Duration::from_secs(30), later);
+    assert_eq!(targets.len(), 2);
+    assert_eq!(id(0).bucket(&targets[0]), 0);
+    assert_eq!(id(0).bucket(&targets[1]), 1);
+    assert!(table.refresh_targets(Duration::from_secs(30), later).is_empty());
+}
+
+#[test]
+fn test_full_bucket_replaces_questionable_nodes() {
+    let start = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    for i in 0..K as u8 {
+        assert!(table.insert(id(0x80 | i), address(i as u16), start));
+    }
+    let later = start + QUESTIONABLE_AFTER;
+    // Every node but the first is heard from again, so only the first is questionable
+    for i in 1..K as u8 {
+        assert!(table.insert(id(0x80 | i), address(i as u16), later));
+    }
+    assert!(table.insert(id(0xff), address(100), later));
+    assert!(!table.insert(id(0xfe), address(101), later));
+    assert!(table.closest(&id(0x80), K).iter().all(|(id, _)| *id != self::id(0x80)));
+}
+
+#[test]
+fn test_save_and_load() {
+    let now = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    table.insert(id(0x80), address(1), now);
+    table.insert(id(0x40), address(2), now - MAX_SAVED_AGE * 2);
+    table.insert(id(0x20), SocketAddr::new("2001:db8::1".parse().unwrap(), 3), now);
+    table.insert(id(0x10), address(4), now);
+    for _ in 0..MAX_FAILURES {
+        table.failed(&id(0x10));
+    }
+
+    let loaded = RoutingTable::decode(&table.encode(), now).unwrap();
+    assert_eq!(loaded.own_id(), id(0));
+    // Bad nodes aren't saved and nodes too old aren't loaded
+    let mut nodes = loaded.closest(&id(0), K);
+    nodes.sort_by_key(|(id, _)| id.0[0]);
+    assert_eq!(nodes, vec![
+        (id(0x20), SocketAddr::new("2001:db8::1".parse().unwrap(), 3)),
+        (id(0x80), address(1)),
+    ]);
+    assert!(RoutingTable::decode(b"d2:id3:abc5:nodeslee", now).is_err());
+}
+
+#[test]
+fn test_loaded_buckets_keep_their_age() {
+    let now = SystemTime::now();
+    let mut table = RoutingTable::new(id(0));
+    table.insert(id(0x80), address(1), now - Duration::from_secs(60 * 60));
+    let mut loaded = RoutingTable::decode(&table.encode(), now).unwrap();
+    // The bucket went quiet an hour ago, not when it was loaded
+    assert_eq!(loaded.refresh_targets(Duration::from_secs(30 * 60), now).len(), 2);
+}
@@ -0,0 +1,125 @@
+use super::*;
+use futures::executor::{
+    block_on,
+    block_on_stream,
+};
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::new([10, 0, 0, 1].into(), port)
+}
+
+#[test]
+fn test_node_id_bucket() {
+    let own = NodeId([0; 20]);
+    let mut other = [0; 20];
+    other[0] = 0x80;
+    assert_eq!(own.bucket(&NodeId(other)), 0);
+    other[0] = 0;
+    other[1] = 0x10;
+    assert_eq!(own.bucket(&NodeId(other)), 11);
+    assert_eq!(own.bucket(&own), 160);
+}
+
+#[test]
+fn test_random_in_bucket() {
+    let own = NodeId::random();
+    for bucket in &[0, 7, 8, 100, 159] {
+        assert_eq!(own.bucket(&own.random_in_bucket(*bucket)), *bucket);
+    }
+}
+
+#[test]
+fn test_tokens() {
+    let now = Instant::now();
+    let mut tokens = Tokens::new(now);
+    let ip: IpAddr = [10, 0, 0, 1].into();
+    let token = tokens.token(ip);
+    assert!(tokens.is_valid(&token, ip));
+    assert!(!tokens.is_valid(&token, [10, 0, 0, 2].into()));
+    // Still good for one rotation, but not two
+    tokens.rotate_if_due(now + TOKEN_ROTATION);
+    assert!(tokens.is_valid(&token, ip));
+    tokens.rotate_if_due(now + TOKEN_ROTATION * 2);
+    assert!(!tokens.is_valid(&token, ip));
+}
+
+#[test]
+fn test_lookup() {
+    let target = NodeId([0; 20]);
+    let mut far = [0; 20];
+    far[0] = 0x80;
+    let mut near = [0; 20];
+    near[19] = 1;
+    let mut lookup = Lookup::new(target, vec![(NodeId(far), address(1))], &[address(2)]);
+    assert_eq!(lookup.next(), vec![address(1), address(2)]);
+    assert_eq!(lookup.next(), vec![]);
+
+    // The far node points us somewhere closer
+    lookup.responded(address(1), NodeId(far), Response {
+        nodes: vec![(NodeId(near), address(3))],
+        token: Some(vec![1, 2]),
+        ..Response::default()
+    });
+    lookup.failed(address(2));
+    assert_eq!(lookup.next(), vec![address(3)]);
+    lookup.responded(address(3), NodeId(near), Response::default());
+    assert_eq!(lookup.next(), vec![]);
+    assert_eq!(lookup.in_flight(), 0);
+    // Only nodes that gave us a token can be announced to
+    assert_eq!(lookup.announce_targets(), vec![(address(1), vec![1, 2])]);
+}
+
+#[test]
+fn test_lookup_reports_peers() {
+    let (reply, receiver) = unbounded();
+    let mut lookup = Lookup::new(NodeId([0; 20]), vec![(NodeId([1; 20]), address(1))], &[]);
+    lookup.get_peers = Some(GetPeers {
+        announce_port: None,
+        seed: false,
+        reply,
+    });
+    lookup.next();
+    lookup.responded(address(1), NodeId([1; 20]), Response {
+        values: vec![address(6881)],
+        ..Response::default()
+    });
+    drop(lookup);
+    assert_eq!(block_on_stream(receiver).collect::<Vec<_>>(), vec![vec![address(6881)]]);
+}
+
+#[test]
+fn test_lookup_merges_scrapes() {
+    let (reply, receiver) = oneshot::channel();
+    let mut lookup = Lookup::new(NodeId([0; 20]), vec![(NodeId([1; 20]), address(1)), (NodeId([2; 20]), address(2))], &[]);
+    lookup.scrape = Some(Scrape {
+        seeds: BloomFilter::new(),
+        peers: BloomFilter::new(),
+        answered: false,
+        reply,
+    });
+    lookup.next();
+    let filter = |ips: &[[u8; 4]]| {
+        let mut filter = BloomFilter::new();
+        for ip in ips {
+            filter.insert((*ip).into());
+        }
+        Some(Box::new(filter))
+    };
+    // Both nodes store the same seed
+    lookup.responded(address(1), NodeId([1; 20]), Response {
+        seeds: filter(&[[10, 0, 0, 1]]),
+        peers: filter(&[[10, 0, 0, 2], [10, 0, 0, 3]]),
+        ..Response::default()
+    });
+    lookup.responded(address(2), NodeId([2; 20]), Response {
+        seeds: filter(&[[10, 0, 0, 1]]),
+        peers: filter(&[[10, 0, 0, 4]]),
+        ..Response::default()
+    });
+    let scrape = lookup.scrape.take().unwrap();
+    assert!(scrape.answered);
+    assert_eq!(scrape.seeds.estimate(), 1);
+    assert_eq!(scrape.peers.estimate(), 3);
+    drop(scrape);
+    assert!(block_on(receiver).is_err());
+}
@@ -0,0 +1,293 @@
+//! dialer decides which of the peers we heard about to connect to.  It keeps the pool of
+//! addresses from trackers, the DHT and peer exchange, hands out a few at a time to dial, and
+//! waits longer before each retry of an address that keeps failing.  Once we know our own
+//! address, the addresses ready to dial are tried in BEP 40 priority order.  ConnectionLimits caps
+//! the connections of every torrent together.
+use crate::peer::priority;
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+use std::net::{
+    IpAddr,
+    SocketAddr,
+};
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Most connections to have opening at once
+pub const MAX_DIALING: usize = 8;
+
+/// How long to wait for a connection to open
+pub const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait before the first retry of an address that failed
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// An address that fails this many times in a row is forgotten
+const MAX_FAILURES: u32 = 4;
+
+/// How long to wait before dialing a peer again after it disconnects
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    /// Waiting to be dialed, not before the given time if there is one
+    Idle(Option<Instant>),
+    Dialing,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    state: State,
+    // How many dials in a row have failed
+    failures: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct Dialer {
+    candidates: HashMap<SocketAddr, Candidate>,
+    // The order to dial in.  Addresses are put back at the end after each dial
+    queue: VecDeque<SocketAddr>,
+    dialing: usize,
+    // Addresses that turned out to be us
+    never: HashSet<SocketAddr>,
+    // Our address as the swarm sees it, to rank candidates by
+    own: Option<SocketAddr>,
+}
+
+impl Dialer {
+    pub fn new() -> Self {
+        Dialer::default()
+    }
+
+    /// Remember an address to dial.  Returns false if we already know it
+    pub fn add(&mut self, address: SocketAddr) -> bool {
+        if self.candidates.contains_key(&address) || self.never.contains(&address) {
+            return false;
+        }
+        self.candidates.insert(address, Candidate {
+            state: State::Idle(None),
+            failures: 0,
+        });
+        self.queue.push_back(address);
+        true
+    }
+
+    /// Rank the addresses to dial by their priority to `own`, our address as the swarm sees it
+    pub fn set_own_address(&mut self, own: SocketAddr) {
+        self.own = Some(own);
+    }
+
+    /// Pick up to `wanted` addresses to dial now, never going over MAX_DIALING at once.  They
+    /// count as dialing until `connected` or `failed` is called for them
+    pub fn next(&mut self, wanted: usize, now: Instant) -> Vec<SocketAddr> {
+        let wanted = wanted.min(MAX_DIALING.saturating_sub(self.dialing));
+        if wanted == 0 {
+            return Vec::new();
+        }
+        // Addresses removed since they were queued leave the queue, and so do the ones being
+        // dialed or connected, which are queued again once the connection ends
+        let candidates = &self.candidates;
+        let mut queued = HashSet::new();
+        self.queue.retain(|address| {
+            candidates.get(address).is_some_and(|candidate| matches!(candidate.state, State::Idle(_)))
+                && queued.insert(*address)
+        });
+        let mut ready: Vec<SocketAddr> = self.queue.iter()
+            .filter(|address| match candidates[*address].state {
+                State::Idle(retry_at) => retry_at.is_none_or(|at| at <= now),
+                State::Dialing | State::Connected => false,
+            })
+            .cloned()
+            .collect();
+        if let Some(own) = self.own {
+            priority::rank(own, &mut ready);
+        }
+        ready.truncate(wanted);
+        // The rest keep their place in the queue
+        self.queue.retain(|address| !ready.contains(address));
+        for address in &ready {
+            if let Some(candidate) = self.candidates.get_mut(address) {
+                candidate.state = State::Dialing;
+            }
+        }
+        self.dialing += ready.len();
+        ready
+    }
+
+    /// The connection to an address opened, or it connected to us some other way
+    pub fn connected(&mut self, address: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            if candidate.state == State::Dialing {
+                self.dialing -= 1;
+            }
+            candidate.state = State::Connected;
+            candidate.failures = 0;
+        }
+    }
+
+    /// Dialing an address failed or timed out.  It is tried again later, waiting twice as long
+    /// after each failure, until it has failed too often
+    pub fn failed(&mut self, address: SocketAddr, now: Instant) {
+        let candidate = match self.candidates.get_mut(&address) {
+            Some(candidate) if candidate.state == State::Dialing => candidate,
+            _ => return,
+        };
+        self.dialing -= 1;
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            self.candidates.remove(&address);
+            return;
+        }
+        candidate.state = State::Idle(Some(now + retry_delay(candidate.failures)));
+        self.queue.push_back(address);
+    }
+
+    /// The connection to an address closed, so it can be dialed again after a while
+    pub fn disconnected(&mut self, address: SocketAddr, now: Instant) {
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            if candidate.state == State::Connected {
+                candidate.state = State::Idle(Some(now + RECONNECT_DELAY));
+                self.queue.push_back(address);
+            }
+        }
+    }
+
+    /// Forget every address of an ip, for when it is banned
+    pub fn remove_ip(&mut self, ip: IpAddr) {
+        let dialing = &mut self.dialing;
+        self.candidates.retain(|address, candidate| {
+            if address.ip() != ip {
+                return true;
+            }
+            if candidate.state == State::Dialing {
+                *dialing -= 1;
+            }
+            false
+        });
+    }
+
+    /// Forget an address and refuse it from now on, for when dialing it reached ourselves
+    pub fn never_dial(&mut self, address: SocketAddr) {
+        if let Some(candidate) = self.candidates.remove(&address) {
+            if candidate.state == State::Dialing {
+                self.dialing -= 1;
+            }
+        }
+        self.never.insert(address);
+    }
+
+    /// How many addresses we know
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    /// Whether we know no addresses at all
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+
+    /// How many connections are being opened
+    pub fn dialing(&self) -> usize {
+        self.dialing
+    }
+}
+
+/// How long to wait before dialing again after this many failures in a row
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_DELAY * 2u32.pow(failures.saturating_sub(1).min(8))
+}
+
+/// Caps on connections, shared by every torrent.  A connection holds a Permit for as long as it
+/// is open, and gives its place back when the permit is dropped
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimits {
+    peers: Counter,
+    half_open: Counter,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Counter {
+    max: Option<usize>,
+    open: Arc<AtomicUsize>,
+}
+
+/// A place under one of the limits
+#[derive(Debug)]
+pub struct Permit {
+    open: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimits {
+    /// Limits of `peers` connections to peers and `half_open` connections being opened at once.
+    /// None is no limit
+    pub fn new(peers: Option<usize>, half_open: Option<usize>) -> Self {
+        ConnectionLimits {
+            peers: Counter {
+                max: peers,
+                open: Arc::new(AtomicUsize::new(0)),
+            },
+            half_open: Counter {
+                max: half_open,
+                open: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+
+    /// A place for a peer connection, if there is one
+    pub fn peer(&self) -> Option<Permit> {
+        self.peers.acquire()
+    }
+
+    /// A place for a connection being opened, if there is one
+    pub fn dial(&self) -> Option<Permit> {
+        self.half_open.acquire()
+    }
+
+    /// Whether every torrent together has as many peers as allowed
+    pub fn peers_full(&self) -> bool {
+        self.peers.available() == 0
+    }
+}
+
+impl Counter {
+    fn available(&self) -> usize {
+        match self.max {
+            Some(max) => max.saturating_sub(self.open.load(Ordering::SeqCst)),
+            None => usize::MAX,
+        }
+    }
+
+    fn acquire(&self) -> Option<Permit> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+            if open < max {
+                Some(open + 1)
+            } else {
+                None
+            }
+        }).ok()?;
+        Some(Permit {
+            open: self.open.clone(),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
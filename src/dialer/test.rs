@@ -0,0 +1,152 @@
+use super::*;
+
+fn address(port: u16) -> SocketAddr {
+    ([10, 0, 0, 1], port).into()
+}
+
+#[test]
+fn test_add_deduplicates() {
+    let mut dialer = Dialer::new();
+    assert!(dialer.add(address(1)));
+    assert!(!dialer.add(address(1)));
+    assert_eq!(dialer.len(), 1);
+    assert_eq!(dialer.next(10, Instant::now()), vec![address(1)]);
+    // Already being dialed
+    assert!(dialer.next(10, Instant::now()).is_empty());
+}
+
+#[test]
+fn test_next_limits_concurrent_dials() {
+    let mut dialer = Dialer::new();
+    for port in 0..20 {
+        dialer.add(address(port));
+    }
+    let now = Instant::now();
+    assert_eq!(dialer.next(3, now), vec![address(0), address(1), address(2)]);
+    assert_eq!(dialer.next(100, now).len(), MAX_DIALING - 3);
+    assert_eq!(dialer.dialing(), MAX_DIALING);
+    assert!(dialer.next(100, now).is_empty());
+    dialer.connected(address(0));
+    assert_eq!(dialer.next(100, now), vec![address(MAX_DIALING as u16)]);
+}
+
+#[test]
+fn test_next_ranks_by_priority() {
+    let own: SocketAddr = ([123, 213, 32, 10], 6881).into();
+    let peers: Vec<SocketAddr> = (1..20).map(|i| ([98, 76, 54, i * 10], 6881).into()).collect();
+    let mut ranked = peers.clone();
+    priority::rank(own, &mut ranked);
+    assert_ne!(ranked, peers);
+    let mut dialer = Dialer::new();
+    for peer in &peers {
+        dialer.add(*peer);
+    }
+    // First come, first dialed until we know our address
+    assert_eq!(dialer.next(1, Instant::now()), vec![peers[0]]);
+    dialer.set_own_address(own);
+    let next = dialer.next(3, Instant::now());
+    let expected: Vec<SocketAddr> = ranked.into_iter().filter(|peer| *peer != peers[0]).take(3).collect();
+    assert_eq!(next, expected);
+}
+
+#[test]
+fn test_failed_backs_off_then_forgets() {
+    let mut dialer = Dialer::new();
+    dialer.add(address(1));
+    let mut now = Instant::now();
+    for failures in 1..MAX_FAILURES {
+        assert_eq!(dialer.next(1, now), vec![address(1)]);
+        dialer.failed(address(1), now);
+        assert_eq!(dialer.dialing(), 0);
+        let delay = retry_delay(failures);
+        assert!(dialer.next(1, now + delay - Duration::from_secs(1)).is_empty());
+        now += delay;
+    }
+    assert_eq!(dialer.next(1, now), vec![address(1)]);
+    dialer.failed(address(1), now);
+    assert_eq!(dialer.len(), 0);
+}
+
+#[test]
+fn test_retry_delay_doubles() {
+    assert_eq!(retry_delay(1), RETRY_DELAY);
+    assert_eq!(retry_delay(2), RETRY_DELAY * 2);
+    assert_eq!(retry_delay(3), RETRY_DELAY * 4);
+}
+
+#[test]
+fn test_not_ready_keeps_its_place() {
+    let mut dialer = Dialer::new();
+    dialer.add(address(1));
+    dialer.add(address(2));
+    let now = Instant::now();
+    assert_eq!(dialer.next(1, now), vec![address(1)]);
+    dialer.failed(address(1), now);
+    assert_eq!(dialer.next(2, now), vec![address(2)]);
+}
+
+#[test]
+fn test_disconnected_redials_later() {
+    let mut dialer = Dialer::new();
+    dialer.add(address(1));
+    let now = Instant::now();
+    dialer.next(1, now);
+    dialer.connected(address(1));
+    assert!(dialer.next(1, now).is_empty());
+    dialer.disconnected(address(1), now);
+    assert!(dialer.next(1, now).is_empty());
+    assert_eq!(dialer.next(1, now + RECONNECT_DELAY), vec![address(1)]);
+}
+
+#[test]
+fn test_remove_ip() {
+    let mut dialer = Dialer::new();
+    dialer.add(address(1));
+    dialer.add(address(2));
+    dialer.add(([10, 0, 0, 2], 1).into());
+    dialer.next(1, Instant::now());
+    dialer.remove_ip([10, 0, 0, 1].into());
+    assert_eq!(dialer.len(), 1);
+    assert_eq!(dialer.dialing(), 0);
+    assert_eq!(dialer.next(10, Instant::now()), vec![([10, 0, 0, 2], 1).into()]);
+}
+
+#[test]
+fn test_never_dial() {
+    let mut dialer = Dialer::new();
+    dialer.add(address(1));
+    dialer.add(address(2));
+    assert_eq!(dialer.next(1, Instant::now()), vec![address(1)]);
+    dialer.connected(address(1));
+    dialer.never_dial(address(1));
+    dialer.disconnected(address(1), Instant::now());
+    assert!(!dialer.add(address(1)));
+    assert_eq!(dialer.len(), 1);
+    assert_eq!(dialer.next(10, Instant::now()), vec![address(2)]);
+}
+
+#[test]
+fn test_limits_give_places_back() {
+    let limits = ConnectionLimits::new(Some(2), Some(1));
+    let first = limits.peer().expect("a place for the first peer");
+    let other_torrent = limits.clone();
+    let _second = other_torrent.peer().expect("a place for the second peer");
+    assert!(limits.peers_full());
+    assert!(limits.peer().is_none());
+    drop(first);
+    assert!(!limits.peers_full());
+    assert!(other_torrent.peer().is_some());
+
+    let dial = limits.dial().expect("a place for a dial");
+    assert!(limits.dial().is_none());
+    drop(dial);
+    assert!(limits.dial().is_some());
+}
+
+#[test]
+fn test_no_limits() {
+    let limits = ConnectionLimits::default();
+    let permits: Vec<Permit> = (0..100).filter_map(|_| limits.peer()).collect();
+    assert_eq!(permits.len(), 100);
+    assert!(!limits.peers_full());
+}
@@ -0,0 +1,316 @@
+//! doctor runs a set of connectivity checks and says what to fix, for when downloads are slow or
+//! don't start.  Every check is a one-off blocking test, since nothing else is running.
+use crate::dht::{
+    self,
+    krpc::{
+        Body,
+        Message,
+        Query,
+    },
+    NodeId,
+};
+use crate::reachability::{
+    self,
+    PortStatus,
+};
+use crate::tracker::{
+    udp::UdpAnnouncer,
+    TrackerError,
+};
+use byteorder::{ByteOrder, NetworkEndian};
+use std::fmt;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    Write,
+};
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    SocketAddr,
+    TcpListener,
+    TcpStream,
+    ToSocketAddrs,
+    UdpSocket,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::runtime::{
+    self,
+    Runtime,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How long to wait for anything on the network
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How much to write when testing the disk
+const DISK_TEST_SIZE: usize = 64 * 1024 * 1024;
+
+/// Below this many bytes per second, the disk will hold downloads back
+const SLOW_DISK: f64 = 10.0 * 1024.0 * 1024.0;
+
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+
+const NAT_PMP_PORT: u16 = 5351;
+
+/// What to check
+pub struct DoctorConfig {
+    // Announce urls of the trackers in use
+    pub trackers: Vec<String>,
+    // Our address as seen from the internet, if the user told us
+    pub external_ip: Option<IpAddr>,
+    pub port: u16,
+    pub download_dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    // What went wrong, and what to do about it
+    Fail(String),
+    // Why the check couldn't be run
+    Skip(String),
+}
+
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Pass(msg) => write!(f, "[PASS] {}: {}", self.name, msg),
+            Outcome::Fail(msg) => write!(f, "[FAIL] {}: {}", self.name, msg),
+            Outcome::Skip(msg) => write!(f, "[SKIP] {}: {}", self.name, msg),
+        }
+    }
+}
+
+/// Run every check
+pub fn run(config: &DoctorConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+    if config.trackers.is_empty() {
+        checks.push(check("trackers", Outcome::Skip("no torrents to take trackers from".to_string())));
+    }
+    for url in &config.trackers {
+        checks.push(check(&format!("tracker {}", url), check_tracker(url)));
+    }
+    checks.push(check("listen port", check_port(config.external_ip, config.port)));
+    let gateway = fs::read_to_string("/proc/net/route").ok().and_then(|table| default_gateway(&table));
+    checks.push(check("NAT-PMP", check_nat_pmp(gateway)));
+    checks.push(check("UPnP", check_upnp()));
+    checks.push(check("DHT bootstrap", check_dht()));
+    checks.push(check("disk write speed", check_disk(&config.download_dir)));
+    checks
+}
+
+fn check(name: &str, outcome: Outcome) -> Check {
+    Check {
+        name: name.to_owned(),
+        outcome,
+    }
+}
+
+fn check_tracker(url: &str) -> Outcome {
+    let (scheme, host) = match tracker_address(url) {
+        Some(address) => address,
+        None => return Outcome::Skip("not an http or udp tracker url".to_string()),
+    };
+    if scheme == "udp" {
+        return check_udp_tracker(url, &host);
+    }
+    let address = match host.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(address) => address,
+        None => return Outcome::Fail(format!("could not resolve {} — check your DNS settings", host)),
+    };
+    match TcpStream::connect_timeout(&address, TIMEOUT) {
+        Ok(_) => Outcome::Pass(format!("connected to {}", address)),
+        Err(e) => Outcome::Fail(format!("could not connect to {}: {} — the tracker may be down, \
+                                         or a firewall is blocking it", address, e)),
+    }
+}
+
+/// Do the connect exchange the client starts every announce with
+fn check_udp_tracker(url: &str, host: &str) -> Outcome {
+    let announcer = match UdpAnnouncer::new(url) {
+        Ok(announcer) => announcer,
+        Err(e) => return Outcome::Skip(format!("not a udp tracker url: {}", e)),
+    };
+    let runtime = match runtime() {
+        Ok(runtime) => runtime,
+        Err(e) => return Outcome::Skip(format!("could not start the test: {}", e)),
+    };
+    match runtime.block_on(announcer.ping(TIMEOUT)) {
+        Ok(address) => Outcome::Pass(format!("{} answered", address)),
+        Err(TrackerError::UnknownHost(_)) => Outcome::Fail(format!("could not resolve {} — check your DNS settings", host)),
+        Err(e @ TrackerError::InvalidResponse) | Err(e @ TrackerError::Refused(_)) => {
+            Outcome::Fail(format!("{} sent an invalid reply: {}", host, e))
+        }
+        Err(e) => Outcome::Fail(format!("no answer from {}: {} — UDP may be blocked by a firewall", host, e)),
+    }
+}
+
+fn check_port(external_ip: Option<IpAddr>, port: u16) -> Outcome {
+    let external_ip = match external_ip {
+        Some(ip) => ip,
+        None => return Outcome::Skip("pass --external-ip to test whether peers can reach us".to_string()),
+    };
+    // Something has to be listening for the connection to be accepted
+    let _listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(e) => return Outcome::Skip(format!("could not listen on port {}, is boosttorrent already running? ({})", port, e)),
+    };
+    // The same test the client runs once it learns our address
+    let runtime = match runtime() {
+        Ok(runtime) => runtime,
+        Err(e) => return Outcome::Skip(format!("could not start the test: {}", e)),
+    };
+    match runtime.block_on(reachability::self_test(SocketAddr::new(external_ip, port))) {
+        PortStatus::Open => Outcome::Pass(format!("port {} is reachable", port)),
+        _ => Outcome::Fail(format!("port {} is closed — forward it to this machine on your router, \
+                                    or allow it through your firewall", port)),
+    }
+}
+
+fn check_nat_pmp(gateway: Option<Ipv4Addr>) -> Outcome {
+    let gateway = match gateway {
+        Some(gateway) => gateway,
+        None => return Outcome::Skip("could not find the default gateway".to_string()),
+    };
+    // An external address request: version 0, opcode 0
+    match udp_exchange(SocketAddr::new(gateway.into(), NAT_PMP_PORT), &[0, 0]) {
+        Ok(ref reply) if reply.len() >= 12 && reply[1] == 128 && NetworkEndian::read_u16(&reply[2..4]) == 0 => {
+            let external = Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11]);
+            Outcome::Pass(format!("{} says our external address is {}", gateway, external))
+        }
+        Ok(_) => Outcome::Fail(format!("{} refused the request — enable NAT-PMP on your router", gateway)),
+        Err(_) => Outcome::Fail(format!("{} did not answer — your router may not support NAT-PMP", gateway)),
+    }
+}
+
+fn check_upnp() -> Outcome {
+    let request = format!("M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+                           ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n", SSDP_ADDRESS);
+    let address = SSDP_ADDRESS.parse().unwrap();
+    match udp_exchange(address, request.as_bytes()) {
+        Ok(reply) => {
+            let reply = String::from_utf8_lossy(&reply);
+            let location = reply.lines()
+                .find(|line| line.to_ascii_lowercase().starts_with("location:"))
+                .map_or("", |line| line["location:".len()..].trim());
+            Outcome::Pass(format!("found an internet gateway {}", location))
+        }
+        Err(_) => Outcome::Fail("no internet gateway answered — enable UPnP on your router, or forward the \
+                                 port by hand".to_string()),
+    }
+}
+
+fn check_dht() -> Outcome {
+    let ping = Message {
+        transaction: b"dr".to_vec(),
+        id: Some(NodeId::random()),
+        body: Body::Query(Query::Ping),
+    }.encode();
+    for router in dht::BOOTSTRAP_NODES.iter() {
+        let address = match router.to_socket_addrs().ok().and_then(|mut addrs| addrs.find(SocketAddr::is_ipv4)) {
+            Some(address) => address,
+            None => continue,
+        };
+        let answered = udp_exchange(address, &ping).ok()
+            .and_then(|reply| Message::decode(&reply).ok())
+            .is_some_and(|reply| matches!(reply.body, Body::Response(_)));
+        if answered {
+            return Outcome::Pass(format!("{} answered", router));
+        }
+    }
+    Outcome::Fail("no bootstrap node answered — UDP may be blocked by a firewall".to_string())
+}
+
+fn check_disk(dir: &Path) -> Outcome {
+    let path = dir.join(format!(".boosttorrent-doctor-{}", rand::random::<u32>()));
+    let chunk = vec![0u8; 1024 * 1024];
+    let start = Instant::now();
+    let res = File::create(&path).and_then(|mut file| {
+        for _ in 0..DISK_TEST_SIZE / chunk.len() {
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()
+    });
+    let elapsed = start.elapsed();
+    let _ = fs::remove_file(&path);
+    if let Err(e) = res {
+        return Outcome::Fail(format!("could not write to {}: {} — check the download directory exists and \
+                                      has space", dir.display(), e));
+    }
+    let speed = DISK_TEST_SIZE as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9);
+    if speed < SLOW_DISK {
+        Outcome::Fail(format!("{} — downloads will be held back by the disk", format_speed(speed)))
+    } else {
+        Outcome::Pass(format_speed(speed))
+    }
+}
+
+/// A runtime for the checks that share code with the client, which is async
+fn runtime() -> io::Result<Runtime> {
+    runtime::Builder::new_current_thread().enable_all().build()
+}
+
+/// Send one UDP packet and wait for the reply
+fn udp_exchange(address: SocketAddr, request: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+    socket.send_to(request, address)?;
+    let mut buf = vec![0u8; 2048];
+    let (len, _from) = socket.recv_from(&mut buf)?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// The scheme and host:port of a tracker url
+fn tracker_address(url: &str) -> Option<(&str, String)> {
+    let scheme_end = url.find("://")?;
+    let scheme = &url[..scheme_end];
+    let rest = &url[scheme_end + 3..];
+    let host = rest.split(['/', '?']).next()?;
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        "udp" => return if host.contains(':') { Some((scheme, host.to_owned())) } else { None },
+        _ => return None,
+    };
+    if host.rsplit(':').next().is_some_and(|port| port.parse::<u16>().is_ok()) && host.contains(':') {
+        Some((scheme, host.to_owned()))
+    } else {
+        Some((scheme, format!("{}:{}", host, default_port)))
+    }
+}
+
+/// Find the default gateway in the kernel's routing table, as found in /proc/net/route
+fn default_gateway(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // The gateway is written as a little endian hex number
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        Some(Ipv4Addr::from(gateway.swap_bytes()))
+    })
+}
+
+fn format_speed(bytes_per_second: f64) -> String {
+    format!("{:.1} MiB/s", bytes_per_second / (1024.0 * 1024.0))
+}
@@ -0,0 +1,28 @@
+use super::*;
+
+#[test]
+fn test_tracker_address() {
+    assert_eq!(tracker_address("http://tracker.example/announce"),
+               Some(("http", "tracker.example:80".to_string())));
+    assert_eq!(tracker_address("https://tracker.example:8443/announce?x=1"),
+               Some(("https", "tracker.example:8443".to_string())));
+    assert_eq!(tracker_address("udp://tracker.example:1337/announce"),
+               Some(("udp", "tracker.example:1337".to_string())));
+    assert_eq!(tracker_address("udp://tracker.example"), None);
+    assert_eq!(tracker_address("wss://tracker.example"), None);
+}
+
+#[test]
+fn test_default_gateway() {
+    let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                 eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                 eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+    assert_eq!(default_gateway(table), Some(Ipv4Addr::new(192, 168, 1, 1)));
+    assert_eq!(default_gateway("Iface\tDestination\tGateway\n"), None);
+}
+
+#[test]
+fn test_check_display() {
+    let check = check("disk write speed", Outcome::Pass("80.0 MiB/s".to_string()));
+    assert_eq!(check.to_string(), "[PASS] disk write speed: 80.0 MiB/s");
+}
@@ -0,0 +1,123 @@
+//! events tells programs embedding the client what their torrents are doing as it happens, so a
+//! UI can follow along and tests can check what a torrent did
+use futures::channel::mpsc::{
+    unbounded,
+    UnboundedReceiver,
+    UnboundedSender,
+};
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Something that happened to a torrent, named by its info hash
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    // A piece passed its hash check and is on disk
+    PieceVerified {
+        info_hash: [u8; 20],
+        index: u32,
+    },
+    // A piece failed its hash check, and is downloaded again
+    PieceFailed {
+        info_hash: [u8; 20],
+        index: u32,
+    },
+    // Peers are numbered by the torrent.  The address is None where it isn't the peer's, like on
+    // I2P
+    PeerConnected {
+        info_hash: [u8; 20],
+        peer: usize,
+        address: Option<SocketAddr>,
+    },
+    PeerDisconnected {
+        info_hash: [u8; 20],
+        peer: usize,
+        address: Option<SocketAddr>,
+    },
+    // A tracker answered an announce with this many peers.  Tracker urls have any passkey
+    // hidden
+    TrackerAnnounce {
+        info_hash: [u8; 20],
+        tracker: String,
+        peers: usize,
+    },
+    // A tracker answered, but had something to tell the user
+    TrackerWarning {
+        info_hash: [u8; 20],
+        tracker: String,
+        message: String,
+    },
+    // A tracker refused an announce, or couldn't be reached
+    TrackerFailure {
+        info_hash: [u8; 20],
+        tracker: String,
+        message: String,
+    },
+    // Every wanted piece is on disk
+    Completed {
+        info_hash: [u8; 20],
+    },
+    // The torrent seeded as long as it was allowed to, and was paused or stopped
+    SeedLimitReached {
+        info_hash: [u8; 20],
+    },
+    Paused {
+        info_hash: [u8; 20],
+    },
+    Resumed {
+        info_hash: [u8; 20],
+    },
+    Error {
+        info_hash: [u8; 20],
+        message: String,
+    },
+}
+
+impl Event {
+    /// The torrent the event happened to
+    pub fn info_hash(&self) -> [u8; 20] {
+        match self {
+            Event::PieceVerified { info_hash, .. } |
+            Event::PieceFailed { info_hash, .. } |
+            Event::PeerConnected { info_hash, .. } |
+            Event::PeerDisconnected { info_hash, .. } |
+            Event::TrackerAnnounce { info_hash, .. } |
+            Event::TrackerWarning { info_hash, .. } |
+            Event::TrackerFailure { info_hash, .. } |
+            Event::Completed { info_hash } |
+            Event::SeedLimitReached { info_hash } |
+            Event::Paused { info_hash } |
+            Event::Resumed { info_hash } |
+            Event::Error { info_hash, .. } => *info_hash,
+        }
+    }
+}
+
+/// Sends events to everyone listening.  Clones share the listeners
+#[derive(Clone, Default)]
+pub struct Broadcast {
+    listeners: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl Broadcast {
+    /// Start listening.  Every event sent from now on comes out of the returned stream
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send an event to every listener, forgetting the ones that stopped listening
+    pub fn send(&self, event: Event) {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.is_empty() {
+            return;
+        }
+        listeners.retain(|listener| listener.unbounded_send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,30 @@
+use futures::{
+    executor::block_on,
+    StreamExt,
+};
+use super::*;
+
+#[test]
+fn test_broadcast() {
+    let broadcast = Broadcast::default();
+    let first = broadcast.subscribe();
+    let gone = broadcast.subscribe();
+    drop(gone);
+    let second = broadcast.subscribe();
+    let event = Event::PieceVerified { info_hash: [1; 20], index: 3 };
+    broadcast.send(event.clone());
+    drop(broadcast);
+    assert_eq!(block_on(first.collect::<Vec<_>>()), vec![event.clone()]);
+    assert_eq!(block_on(second.collect::<Vec<_>>()), vec![event]);
+}
+
+#[test]
+fn test_info_hash() {
+    assert_eq!(Event::Completed { info_hash: [2; 20] }.info_hash(), [2; 20]);
+    assert_eq!(Event::Error { info_hash: [3; 20], message: "disk full".to_string() }.info_hash(), [3; 20]);
+    assert_eq!(Event::TrackerFailure {
+        info_hash: [4; 20],
+        tracker: "http://tracker.example/announce".to_string(),
+        message: "unregistered torrent".to_string(),
+    }.info_hash(), [4; 20]);
+}
@@ -0,0 +1,140 @@
+//! fetch downloads .torrent files over HTTP(S), so torrents can be added straight from a url
+use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::metainfo::MetaInfo;
+use hyper::{
+    body::HttpBody,
+    Body,
+    Client,
+    client::HttpConnector,
+    header::{
+        CONTENT_LENGTH,
+        LOCATION,
+    },
+    http::uri::InvalidUri,
+    Request,
+    Uri,
+};
+use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
+
+#[cfg(test)]
+mod test;
+
+/// Biggest .torrent file we are willing to download
+pub const MAX_TORRENT_SIZE: u64 = 10 * 1024 * 1024;
+
+/// How many redirects to follow before giving up
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, derive_error::Error)]
+pub enum FetchError {
+    /// The url was somehow invalid
+    InvalidURI(InvalidUri),
+    /// Could not set up TLS for https urls
+    TlsError(native_tls::Error),
+    /// Could not download the file
+    ConnectionError(hyper::Error),
+    /// The server returned an error status code
+    #[error(non_std, no_from)]
+    ResponseError(u16),
+    /// The server redirected us too many times
+    TooManyRedirects,
+    /// The file is bigger than MAX_TORRENT_SIZE
+    TooLarge,
+    /// A header to send was not a valid "Name: value" header
+    #[error(non_std, no_from)]
+    InvalidHeader(String),
+    /// The file could not be bdecoded
+    DecodeError(DecodeError),
+    /// The file is not a valid torrent
+    #[error(non_std, no_from)]
+    InvalidTorrent(String),
+}
+
+/// Download a .torrent file, following redirects.  `headers` are sent with every request, for
+/// sites that need authentication.  Returns the file once we know it is a valid torrent
+pub async fn fetch_torrent(url: &str, headers: Vec<(String, String)>) -> Result<Vec<u8>, FetchError> {
+    let mut uri = url.parse::<Uri>().map_err(FetchError::InvalidURI)?;
+    let mut http = HttpConnector::new();
+    // https urls are let through for the TLS connector to wrap
+    http.enforce_http(false);
+    let tls = TlsConnector::new().map_err(FetchError::TlsError)?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::from((http, tls.into())));
+    let mut redirects = 0;
+    let response = loop {
+        let mut request = Request::get(uri.clone());
+        for (name, value) in &headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let request = request.body(Body::empty())
+            .map_err(|e| FetchError::InvalidHeader(e.to_string()))?;
+        let response = client.request(request).await.map_err(FetchError::ConnectionError)?;
+        let status = response.status();
+        if status.is_redirection() {
+            if redirects >= MAX_REDIRECTS {
+                return Err(FetchError::TooManyRedirects);
+            }
+            let location = response.headers().get(LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .ok_or(FetchError::ResponseError(status.as_u16()))?;
+            uri = resolve(&uri, location)?;
+            redirects += 1;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(FetchError::ResponseError(status.as_u16()));
+        }
+        let length = response.headers().get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok());
+        if length.is_some_and(|length| length > MAX_TORRENT_SIZE) {
+            return Err(FetchError::TooLarge);
+        }
+        break response;
+    };
+    // Don't trust Content-Length, it may be missing or wrong
+    let mut body = response.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.map_err(FetchError::ConnectionError)?);
+        if bytes.len() as u64 > MAX_TORRENT_SIZE {
+            return Err(FetchError::TooLarge);
+        }
+    }
+    validate(&bytes)?;
+    Ok(bytes)
+}
+
+/// Parse a "Name: value" header from the command line
+pub fn parse_header(header: &str) -> Result<(String, String), FetchError> {
+    let mut parts = header.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(FetchError::InvalidHeader(header.to_owned())),
+    }
+}
+
+/// Work out where a redirect goes.  Locations can be relative to the url that was redirected
+fn resolve(base: &Uri, location: &str) -> Result<Uri, FetchError> {
+    if location.contains("://") {
+        return location.parse().map_err(FetchError::InvalidURI);
+    }
+    let scheme = base.scheme().map_or("http", |s| s.as_str());
+    let authority = base.authority().map_or("", |a| a.as_str());
+    let path = if location.starts_with('/') {
+        location.to_owned()
+    } else {
+        let base_path = base.path();
+        let dir = &base_path[..base_path.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}{}", dir, location)
+    };
+    format!("{}://{}{}", scheme, authority, path).parse().map_err(FetchError::InvalidURI)
+}
+
+/// Make sure we downloaded a torrent and not, say, a login page
+fn validate(bytes: &[u8]) -> Result<MetaInfo, FetchError> {
+    let val = Value::decode(bytes)?;
+    MetaInfo::from_value(&val).map_err(FetchError::InvalidTorrent)
+}
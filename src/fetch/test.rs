@@ -0,0 +1,29 @@
+use super::*;
+
+#[test]
+fn test_parse_header() {
+    assert_eq!(parse_header("Authorization: Bearer abc:def").unwrap(),
+               ("Authorization".to_string(), "Bearer abc:def".to_string()));
+    assert!(parse_header("no colon").is_err());
+    assert!(parse_header(": value").is_err());
+}
+
+#[test]
+fn test_resolve_redirect() {
+    let base: Uri = "https://example.com/files/a.torrent?id=1".parse().unwrap();
+    assert_eq!(resolve(&base, "http://mirror.example/b.torrent").unwrap(),
+               "http://mirror.example/b.torrent".parse::<Uri>().unwrap());
+    assert_eq!(resolve(&base, "/download/b.torrent").unwrap(),
+               "https://example.com/download/b.torrent".parse::<Uri>().unwrap());
+    assert_eq!(resolve(&base, "b.torrent").unwrap(),
+               "https://example.com/files/b.torrent".parse::<Uri>().unwrap());
+}
+
+#[test]
+fn test_validate() {
+    assert!(validate(b"<html>Please log in</html>").is_err());
+    assert!(validate(b"d3:fooi1ee").is_err());
+    let torrent = b"d8:announce18:http://example.com4:infod6:lengthi100e4:name4:test\
+                    12:piece lengthi20e6:pieces4:\x00\x01\x02\x03ee";
+    assert!(validate(torrent).is_ok());
+}
@@ -0,0 +1,321 @@
+//! geoip reads MaxMind-format (.mmdb) databases so peers can be tagged with their country and
+//! autonomous system
+use derive_error::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[cfg(test)]
+mod test;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+#[derive(Debug, Error)]
+pub enum GeoIpError {
+    /// The database file could not be read
+    Io(io::Error),
+    /// The database file is not a valid MaxMind database
+    InvalidDatabase,
+}
+
+/// A value stored in the data section of a MaxMind database
+#[derive(Debug, PartialEq, Clone)]
+enum Data {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Map(HashMap<String, Data>),
+    Array(Vec<Data>),
+    Boolean(bool),
+    Float(f32),
+}
+
+impl Data {
+    fn get(&self, key: &str) -> Option<&Data> {
+        if let Data::Map(map) = self {
+            return map.get(key);
+        }
+
+        None
+    }
+
+    fn string(&self) -> Option<&String> {
+        if let Data::String(s) = self {
+            return Some(s);
+        }
+
+        None
+    }
+
+    fn uint(&self) -> Option<u128> {
+        if let Data::Uint(i) = self {
+            return Some(*i);
+        }
+
+        None
+    }
+}
+
+/// What we know about where a peer is on the internet
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Location {
+    // ISO 3166-1 country code
+    pub country: Option<String>,
+    // Autonomous system number
+    pub asn: Option<u32>,
+    // Organization that owns the autonomous system
+    pub as_org: Option<String>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.country.as_deref().unwrap_or("??"))?;
+        if let Some(asn) = self.asn {
+            write!(f, " AS{}", asn)?;
+        }
+        if let Some(org) = &self.as_org {
+            write!(f, " ({})", org)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single MaxMind database loaded into memory
+struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u16,
+    // offset of the data section in bytes
+    data_start: usize,
+}
+
+impl Database {
+    fn from_bytes(bytes: Vec<u8>) -> Result<Self, GeoIpError> {
+        let marker = bytes.windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(GeoIpError::InvalidDatabase)?;
+        let (metadata, _) = decode(&bytes[marker + METADATA_MARKER.len()..], 0)?;
+
+        let node_count = metadata.get("node_count").and_then(Data::uint)
+            .ok_or(GeoIpError::InvalidDatabase)? as usize;
+        let record_size = metadata.get("record_size").and_then(Data::uint)
+            .ok_or(GeoIpError::InvalidDatabase)? as usize;
+        let ip_version = metadata.get("ip_version").and_then(Data::uint)
+            .ok_or(GeoIpError::InvalidDatabase)? as u16;
+
+        if record_size != 24 && record_size != 28 && record_size != 32 {
+            return Err(GeoIpError::InvalidDatabase);
+        }
+
+        // The search tree is followed by 16 bytes of zeroes before the data section
+        let data_start = node_count * record_size * 2 / 8 + 16;
+        if data_start > marker {
+            return Err(GeoIpError::InvalidDatabase);
+        }
+
+        Ok(Database {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data_start,
+        })
+    }
+
+    /// Reads the left (0) or right (1) record of a node in the search tree
+    fn record(&self, node: usize, side: usize) -> Option<usize> {
+        let node_bytes = self.record_size * 2 / 8;
+        let start = node * node_bytes;
+        let b = self.bytes.get(start..start + node_bytes)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        Some(match (self.record_size, side) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            (28, 0) => ((b[3] as usize & 0xF0) << 20) | be(&b[0..3]),
+            (28, _) => ((b[3] as usize & 0x0F) << 24) | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        })
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Data> {
+        let (addr, mut node) = match ip {
+            IpAddr::V4(v4) if self.ip_version == 6 => {
+                // IPv4 addresses live under ::/96 in an IPv6 tree
+                let mut node = 0;
+                for _ in 0..96 {
+                    if node >= self.node_count {
+                        break;
+                    }
+                    node = self.record(node, 0)?;
+                }
+                (v4.octets().to_vec(), node)
+            }
+            IpAddr::V4(v4) => (v4.octets().to_vec(), 0),
+            IpAddr::V6(v6) if self.ip_version == 6 => (v6.octets().to_vec(), 0),
+            IpAddr::V6(_) => return None,
+        };
+
+        for i in 0..addr.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (addr[i / 8] >> (7 - (i % 8))) & 1;
+            node = self.record(node, bit as usize)?;
+        }
+
+        if node <= self.node_count {
+            // Either the address was not found, or the tree is malformed
+            return None;
+        }
+
+        let offset = node - self.node_count - 16;
+        decode(&self.bytes[self.data_start..], offset).ok().map(|(data, _)| data)
+    }
+}
+
+/// A set of MaxMind databases.  Country and ASN information are usually shipped as separate
+/// databases, so lookups merge the answers from every loaded database.
+pub struct GeoIp {
+    databases: Vec<Database>,
+}
+
+impl GeoIp {
+    /// Loads every database in `paths`
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, GeoIpError> {
+        let databases = paths.iter()
+            .map(|path| fs::read(path).map_err(GeoIpError::Io).and_then(Database::from_bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(GeoIp { databases })
+    }
+
+    /// Looks up where an address is
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let mut location = Location::default();
+        for data in self.databases.iter().filter_map(|db| db.lookup(ip)) {
+            if location.country.is_none() {
+                location.country = data.get("country")
+                    .and_then(|c| c.get("iso_code"))
+                    .and_then(Data::string)
+                    .cloned();
+            }
+            if location.asn.is_none() {
+                location.asn = data.get("autonomous_system_number").and_then(Data::uint)
+                    .map(|i| i as u32);
+            }
+            if location.as_org.is_none() {
+                location.as_org = data.get("autonomous_system_organization")
+                    .and_then(Data::string)
+                    .cloned();
+            }
+        }
+        location
+    }
+}
+
+/// Decodes the value at `offset` of a data section.  Returns the value and the offset just past
+/// it.
+fn decode(section: &[u8], offset: usize) -> Result<(Data, usize), GeoIpError> {
+    let byte = |i: usize| section.get(i).cloned().ok_or(GeoIpError::InvalidDatabase);
+    let be = |start: usize, len: usize| -> Result<u128, GeoIpError> {
+        section.get(start..start + len)
+            .map(|bytes| bytes.iter().fold(0u128, |acc, b| (acc << 8) | *b as u128))
+            .ok_or(GeoIpError::InvalidDatabase)
+    };
+
+    let ctrl = byte(offset)?;
+    let mut pos = offset + 1;
+    let mut data_type = ctrl >> 5;
+
+    if data_type == 1 {
+        // Pointer into the data section
+        let size = ((ctrl >> 3) & 0x3) as usize;
+        let value = (ctrl & 0x7) as usize;
+        let pointer = match size {
+            0 => (value << 8) | be(pos, 1)? as usize,
+            1 => ((value << 16) | be(pos, 2)? as usize) + 2048,
+            2 => ((value << 24) | be(pos, 3)? as usize) + 526_336,
+            _ => be(pos, 4)? as usize,
+        };
+        let (data, _) = decode(section, pointer)?;
+        return Ok((data, pos + size + 1));
+    }
+
+    if data_type == 0 {
+        data_type = 7 + byte(pos)?;
+        pos += 1;
+    }
+
+    let mut size = (ctrl & 0x1F) as usize;
+    match size {
+        29 => {
+            size = 29 + be(pos, 1)? as usize;
+            pos += 1;
+        }
+        30 => {
+            size = 285 + be(pos, 2)? as usize;
+            pos += 2;
+        }
+        31 => {
+            size = 65_821 + be(pos, 3)? as usize;
+            pos += 3;
+        }
+        _ => (),
+    }
+
+    match data_type {
+        2 => {
+            let bytes = section.get(pos..pos + size).ok_or(GeoIpError::InvalidDatabase)?;
+            let s = String::from_utf8(bytes.to_vec()).map_err(|_| GeoIpError::InvalidDatabase)?;
+            Ok((Data::String(s), pos + size))
+        }
+        3 => Ok((Data::Double(f64::from_bits(be(pos, 8)? as u64)), pos + 8)),
+        4 => {
+            let bytes = section.get(pos..pos + size).ok_or(GeoIpError::InvalidDatabase)?;
+            Ok((Data::Bytes(bytes.to_vec()), pos + size))
+        }
+        5 | 6 | 9 | 10 => Ok((Data::Uint(be(pos, size)?), pos + size)),
+        7 => {
+            let mut map = HashMap::new();
+            for _ in 0..size {
+                let (key, next) = decode(section, pos)?;
+                let (val, next) = decode(section, next)?;
+                pos = next;
+                match key {
+                    Data::String(key) => map.insert(key, val),
+                    _ => return Err(GeoIpError::InvalidDatabase),
+                };
+            }
+            Ok((Data::Map(map), pos))
+        }
+        8 => {
+            // int32 is stored with as few bytes as possible, so sign extend it
+            let raw = be(pos, size)? as u32;
+            let value = if size > 0 && size < 4 {
+                let shift = 32 - size * 8;
+                ((raw << shift) as i32) >> shift
+            } else {
+                raw as i32
+            };
+            Ok((Data::Int(value), pos + size))
+        }
+        11 => {
+            let mut list = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (val, next) = decode(section, pos)?;
+                pos = next;
+                list.push(val);
+            }
+            Ok((Data::Array(list), pos))
+        }
+        14 => Ok((Data::Boolean(size != 0), pos)),
+        15 => Ok((Data::Float(f32::from_bits(be(pos, 4)? as u32)), pos + 4)),
+        _ => Err(GeoIpError::InvalidDatabase),
+    }
+}
@@ -0,0 +1,70 @@
+use super::*;
+
+// A one node IPv4 database.  Everything in 0.0.0.0/1 maps to the only record in the data section,
+// everything else is unknown.
+fn test_database() -> Vec<u8> {
+    let mut db = Vec::new();
+    // search tree: left record points at data offset 0, right record is "not found"
+    db.extend_from_slice(&[0x00, 0x00, 0x11, 0x00, 0x00, 0x01]);
+    db.extend_from_slice(&[0; 16]);
+    // data section: {country: {iso_code: "US"}, autonomous_system_number: 15169}
+    db.push(0xE2);
+    db.push(0x47);
+    db.extend_from_slice(b"country");
+    db.push(0xE1);
+    db.push(0x48);
+    db.extend_from_slice(b"iso_code");
+    db.push(0x42);
+    db.extend_from_slice(b"US");
+    db.push(0x58);
+    db.extend_from_slice(b"autonomous_system_number");
+    db.extend_from_slice(&[0xC2, 0x3B, 0x41]);
+    // metadata
+    db.extend_from_slice(METADATA_MARKER);
+    db.push(0xE3);
+    db.push(0x4A);
+    db.extend_from_slice(b"node_count");
+    db.extend_from_slice(&[0xC1, 0x01]);
+    db.push(0x4B);
+    db.extend_from_slice(b"record_size");
+    db.extend_from_slice(&[0xA1, 0x18]);
+    db.push(0x4A);
+    db.extend_from_slice(b"ip_version");
+    db.extend_from_slice(&[0xA1, 0x04]);
+    db
+}
+
+#[test]
+fn test_lookup_found() {
+    let geoip = GeoIp { databases: vec![Database::from_bytes(test_database()).unwrap()] };
+    assert_eq!(geoip.lookup([1, 2, 3, 4].into()), Location {
+        country: Some("US".to_string()),
+        asn: Some(15169),
+        as_org: None,
+    });
+}
+
+#[test]
+fn test_lookup_not_found() {
+    let geoip = GeoIp { databases: vec![Database::from_bytes(test_database()).unwrap()] };
+    assert_eq!(geoip.lookup([200, 1, 1, 1].into()), Location::default());
+}
+
+#[test]
+fn test_invalid_database() {
+    assert!(Database::from_bytes(vec![0; 100]).is_err());
+}
+
+#[test]
+fn test_decode_pointer() {
+    // "hi" followed by a pointer back to it
+    let section = [0x42, b'h', b'i', 0x20, 0x00];
+    assert_eq!(decode(&section, 3).unwrap(), (Data::String("hi".to_string()), 5));
+}
+
+#[test]
+fn test_decode_negative_int() {
+    // extended type 8 (int32), one byte long
+    let section = [0x01, 0x01, 0xFF];
+    assert_eq!(decode(&section, 0).unwrap(), (Data::Int(-1), 3));
+}
@@ -0,0 +1,66 @@
+//! hasher runs the CPU heavy work of checking pieces on a pool of threads shared by every torrent,
+//! so hashing never holds up network I/O and large pieces are checked in parallel.
+use std::io;
+use std::sync::{
+    mpsc::{
+        sync_channel,
+        Receiver,
+        SyncSender,
+    },
+    Arc,
+    Mutex,
+};
+use std::thread;
+
+#[cfg(test)]
+mod test;
+
+/// How many jobs can wait for each thread before whoever adds one is held up
+const JOBS_PER_THREAD: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads taking jobs from a bounded queue.  Clones share the same threads, which
+/// stop once every clone is gone and the queue is empty
+#[derive(Clone)]
+pub struct HashPool {
+    jobs: SyncSender<Job>,
+}
+
+impl HashPool {
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let threads = threads.max(1);
+        let (jobs, receiver) = sync_channel(threads * JOBS_PER_THREAD);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("hasher-{}", i))
+                .spawn(move || run(&receiver))?;
+        }
+        Ok(HashPool { jobs })
+    }
+
+    /// Run `job` on one of the threads.  Blocks while the queue is full, which holds back whoever
+    /// is producing work faster than it can be hashed.  Must not be called from the pool's own
+    /// threads
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // The threads only stop once every sender is gone, so this can't fail
+        let _res = self.jobs.send(Box::new(job));
+    }
+}
+
+/// Take jobs until the pool is dropped
+fn run(jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        // Only wait for the job under the lock, so other threads can run theirs meanwhile
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
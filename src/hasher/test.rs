@@ -0,0 +1,16 @@
+use std::sync::mpsc::channel;
+use super::*;
+
+#[test]
+fn test_runs_every_job() {
+    let pool = HashPool::new(3).unwrap();
+    let (sender, receiver) = channel();
+    for i in 0..50 {
+        let sender = sender.clone();
+        pool.execute(move || sender.send(i).unwrap());
+    }
+    drop(sender);
+    let mut done: Vec<i32> = receiver.iter().collect();
+    done.sort();
+    assert_eq!(done, (0..50).collect::<Vec<_>>());
+}
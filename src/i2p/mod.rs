@@ -0,0 +1,284 @@
+//! i2p talks to an I2P router through its SAM bridge (SAM v3.1), so torrents can be shared over
+//! I2P instead of the open internet.  Every connection through the bridge is a plain TCP
+//! connection to the bridge, which carries our data once the bridge has set it up.
+use futures::{
+    future::BoxFuture,
+    ready,
+    FutureExt,
+    Stream,
+};
+use hyper::{
+    service::Service,
+    Uri,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{
+    SocketAddr,
+    ToSocketAddrs,
+};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{
+    Context,
+    Poll,
+};
+use tokio::{
+    io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    },
+    net::TcpStream,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Longest line we accept from the bridge.  Destinations are a few hundred bytes
+const MAX_LINE: usize = 4096;
+
+type BoxedFuture<T> = BoxFuture<'static, io::Result<T>>;
+
+/// Where the SAM bridge is
+#[derive(Debug, PartialEq, Clone)]
+pub struct SamConfig {
+    pub bridge: SocketAddr,
+}
+
+impl FromStr for SamConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bridge = s.to_socket_addrs()
+            .map_err(|e| format!("Invalid SAM bridge address {}: {}", s, e))?
+            .next()
+            .ok_or(format!("Invalid SAM bridge address: {}", s))?;
+        Ok(SamConfig { bridge })
+    }
+}
+
+/// A SAM session.  Our I2P destination lives as long as the session's control connection is
+/// open, and streams are opened through the session by its id.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SamSession {
+    bridge: SocketAddr,
+    id: String,
+}
+
+/// Create a session with a new destination.  Returns the control connection, which must be kept
+/// open for as long as the session is used, along with the session.
+pub fn create_session(config: &SamConfig, id: &str) -> impl Future<Output=io::Result<(TcpStream, SamSession)>> + Send {
+    let session = SamSession {
+        bridge: config.bridge,
+        id: id.to_owned(),
+    };
+    let request = format!("SESSION CREATE STYLE=STREAM ID={} DESTINATION=TRANSIENT SIGNATURE_TYPE=7\n", id);
+    async move {
+        let mut conn = hello(session.bridge).await?;
+        command(&mut conn, request, "SESSION").await?;
+        Ok((conn, session))
+    }
+}
+
+/// Open a stream to an I2P destination, which may be a full base64 destination, a .b32.i2p
+/// address or a name from the router's address book
+pub fn connect(session: &SamSession, destination: &str) -> impl Future<Output=io::Result<TcpStream>> + Send {
+    let session = session.clone();
+    let destination = destination.to_owned();
+    async move {
+        let destination = if destination.ends_with(".i2p") {
+            lookup(&session, &destination).await?
+        } else {
+            destination
+        };
+        let request = format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false\n", session.id, destination);
+        let mut conn = hello(session.bridge).await?;
+        command(&mut conn, request, "STREAM").await?;
+        Ok(conn)
+    }
+}
+
+/// Wait for a peer to open a stream to us.  Returns the stream and the peer's destination
+pub fn accept(session: &SamSession) -> impl Future<Output=io::Result<(TcpStream, String)>> + Send {
+    let request = format!("STREAM ACCEPT ID={} SILENT=false\n", session.id);
+    let bridge = session.bridge;
+    async move {
+        let mut conn = hello(bridge).await?;
+        command(&mut conn, request, "STREAM").await?;
+        // Once a peer connects, the bridge sends a line with their destination before their data
+        let line = read_line(&mut conn).await?;
+        let destination = line.split(' ').next().unwrap_or("").to_owned();
+        Ok((conn, destination))
+    }
+}
+
+/// Look up the full destination for an address book name or .b32.i2p address
+pub fn lookup(session: &SamSession, name: &str) -> impl Future<Output=io::Result<String>> + Send {
+    let request = format!("NAMING LOOKUP NAME={}\n", name);
+    let bridge = session.bridge;
+    async move {
+        let mut conn = hello(bridge).await?;
+        let reply = command(&mut conn, request, "NAMING").await?;
+        reply.get("VALUE").cloned().ok_or_else(|| error("SAM bridge sent no destination"))
+    }
+}
+
+/// Whether a url or host is on I2P
+pub fn is_i2p(url: &str) -> bool {
+    let host = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    let host = host.split(['/', '?', ':']).next().unwrap_or("");
+    host.ends_with(".i2p")
+}
+
+/// Lets hyper make HTTP requests to I2P hosts, for announcing to I2P trackers
+#[derive(Clone)]
+pub struct SamConnector(pub SamSession);
+
+impl Service<Uri> for SamConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = BoxedFuture<TcpStream>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        connect(&self.0, dst.host().unwrap_or("")).boxed()
+    }
+}
+
+/// A torrent's presence on I2P.  Sets up a session, then yields the streams peers open to us
+pub struct I2pTransport {
+    state: TransportState,
+}
+
+enum TransportState {
+    Creating(BoxedFuture<(TcpStream, SamSession)>),
+    Ready {
+        // Kept open so the session stays alive
+        _control: TcpStream,
+        session: SamSession,
+        accept: BoxedFuture<(TcpStream, String)>,
+    },
+}
+
+impl I2pTransport {
+    pub fn new(config: &SamConfig, id: &str) -> Self {
+        I2pTransport {
+            state: TransportState::Creating(create_session(config, id).boxed()),
+        }
+    }
+
+    /// The session, once it has been set up
+    pub fn session(&self) -> Option<&SamSession> {
+        match &self.state {
+            TransportState::Ready { session, .. } => Some(session),
+            TransportState::Creating(_) => None,
+        }
+    }
+}
+
+impl Stream for I2pTransport {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let next = match &mut self.state {
+                TransportState::Creating(creating) => match ready!(creating.as_mut().poll(cx)) {
+                    Ok((control, session)) => TransportState::Ready {
+                        _control: control,
+                        accept: accept(&session).boxed(),
+                        session,
+                    },
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                },
+                TransportState::Ready { session, accept: accepting, .. } => {
+                    let accepted = ready!(accepting.as_mut().poll(cx));
+                    // Start waiting for the next peer right away
+                    *accepting = accept(session).boxed();
+                    return Poll::Ready(Some(accepted.map(|(conn, _destination)| conn)));
+                }
+            };
+            self.state = next;
+        }
+    }
+}
+
+/// Open a connection to the bridge and agree on a protocol version
+async fn hello(bridge: SocketAddr) -> io::Result<TcpStream> {
+    let mut conn = TcpStream::connect(bridge).await?;
+    command(&mut conn, "HELLO VERSION MIN=3.1 MAX=3.1\n".to_string(), "HELLO").await?;
+    Ok(conn)
+}
+
+/// Send a command and read the reply, failing if the bridge says it didn't work
+async fn command(conn: &mut TcpStream, request: String, topic: &'static str) -> io::Result<HashMap<String, String>> {
+    conn.write_all(request.as_bytes()).await?;
+    let line = read_line(conn).await?;
+    let reply = parse_reply(&line).map_err(|e| error(&e))?;
+    if !line.starts_with(topic) {
+        return Err(error(&format!("Unexpected reply from SAM bridge: {}", line)));
+    }
+    match reply.get("RESULT").map(String::as_str) {
+        Some("OK") | None => Ok(reply),
+        Some(result) => {
+            let message = reply.get("MESSAGE").map_or("", String::as_str);
+            Err(error(&format!("SAM bridge said {} {}", result, message)))
+        }
+    }
+}
+
+/// Read one line from the bridge.  This goes a byte at a time, since anything after the line
+/// belongs to whoever gets the connection next
+async fn read_line(conn: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = conn.read_u8().await?;
+        if byte == b'\n' {
+            return Ok(String::from_utf8_lossy(&line).into_owned());
+        }
+        if line.len() >= MAX_LINE {
+            return Err(error("SAM bridge sent a line that is too long"));
+        }
+        line.push(byte);
+    }
+}
+
+/// Parse the KEY=VALUE pairs in a reply.  Values may be quoted if they contain spaces
+fn parse_reply(line: &str) -> Result<HashMap<String, String>, String> {
+    let mut res = HashMap::new();
+    let mut rest = line.trim_end_matches('\r');
+    while !rest.is_empty() {
+        rest = rest.trim_start_matches(' ');
+        let end = rest.find([' ', '=']).unwrap_or(rest.len());
+        let key = &rest[..end];
+        rest = &rest[end..];
+        if !rest.starts_with('=') {
+            // A bare word, like the topic at the start of a reply
+            continue;
+        }
+        rest = &rest[1..];
+        let value = if rest.starts_with('"') {
+            let close = rest[1..].find('"').ok_or(format!("Unterminated quote in SAM reply: {}", line))?;
+            let value = &rest[1..close + 1];
+            rest = &rest[close + 2..];
+            value
+        } else {
+            let end = rest.find(' ').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        res.insert(key.to_owned(), value.to_owned());
+    }
+    Ok(res)
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(msg)
+}
@@ -0,0 +1,29 @@
+use super::*;
+
+#[test]
+fn test_parse_reply() {
+    let reply = parse_reply("SESSION STATUS RESULT=OK DESTINATION=abc~def=").unwrap();
+    assert_eq!(reply.get("RESULT"), Some(&"OK".to_string()));
+    assert_eq!(reply.get("DESTINATION"), Some(&"abc~def=".to_string()));
+
+    let reply = parse_reply("STREAM STATUS RESULT=I2P_ERROR MESSAGE=\"Connection refused\"\r").unwrap();
+    assert_eq!(reply.get("RESULT"), Some(&"I2P_ERROR".to_string()));
+    assert_eq!(reply.get("MESSAGE"), Some(&"Connection refused".to_string()));
+
+    assert!(parse_reply("NAMING REPLY MESSAGE=\"never closed").is_err());
+}
+
+#[test]
+fn test_is_i2p() {
+    assert!(is_i2p("http://tracker2.postman.i2p/announce.php"));
+    assert!(is_i2p("http://abcdefghijklmnop.b32.i2p:80/a"));
+    assert!(!is_i2p("http://tracker.example/i2p"));
+    assert!(!is_i2p("udp://open.example:1337"));
+}
+
+#[test]
+fn test_parse_sam_config() {
+    assert_eq!("127.0.0.1:7656".parse(), Ok(SamConfig {
+        bridge: "127.0.0.1:7656".parse().unwrap(),
+    }));
+}
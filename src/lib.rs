@@ -0,0 +1,51 @@
+//! boosttorrent is a BitTorrent client as a library.  A [`Session`] runs torrents on its own
+//! runtime and hands out a [`TorrentHandle`] for each, so other programs can embed the engine.
+//! The boosttorrent2 command line program is built on it.
+pub mod ban;
+pub mod blocklist;
+pub mod boostencode;
+pub mod bufpool;
+pub mod choke;
+pub mod client;
+pub mod daemon;
+pub mod dht;
+pub mod dialer;
+pub mod doctor;
+pub mod events;
+pub mod fetch;
+pub mod geoip;
+pub mod hasher;
+pub mod i2p;
+pub mod listen;
+pub mod logging;
+pub mod magnet;
+pub mod metainfo;
+pub mod tracker;
+pub mod webhook;
+pub mod server;
+pub mod session;
+pub mod shutdown;
+pub mod ssl;
+pub mod socks;
+pub mod stats;
+pub mod superseed;
+pub mod storage;
+pub mod picker;
+pub mod piece;
+pub mod quota;
+pub mod ratelimit;
+pub mod reachability;
+pub mod resume;
+pub mod rpc;
+pub mod seedlimit;
+pub mod peer;
+pub mod piecefield;
+pub mod types;
+
+pub use crate::client::{
+    ClientError,
+    Session,
+    Settings,
+    TorrentHandle,
+};
+pub use crate::events::Event;
@@ -0,0 +1,328 @@
+//! listen takes the connections peers make to us, for every torrent of the session, on one port.
+//! Each connection's handshake is read far enough to learn which torrent it is for, and the
+//! connection is handed to that torrent.  Peers name the torrent in the plaintext handshake,
+//! prove they know its info hash in the encrypted one, or give it as the server name when they
+//! start SSL with an SSL torrent.
+//!
+//! Peers on IPv6 can usually reach us directly, so when we have a routable IPv6 address we listen
+//! on it as well and tell trackers about it.
+use crate::ban::BanList;
+use crate::blocklist::Blocklist;
+use crate::peer::mse::{
+    self,
+    Encrypted,
+    EncryptionPolicy,
+};
+use crate::reachability::Reachability;
+use crate::session::unhex;
+use futures::{
+    channel::mpsc::{
+        unbounded,
+        UnboundedReceiver,
+        UnboundedSender,
+    },
+    stream::{
+        self,
+        BoxStream,
+    },
+    StreamExt,
+};
+use log::{
+    debug,
+    error,
+    warn,
+};
+use std::collections::HashMap;
+use std::io;
+use socket2::{
+    Domain,
+    Protocol,
+    Socket,
+    Type,
+};
+use std::net::{
+    Ipv6Addr,
+    SocketAddr,
+    UdpSocket,
+};
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Duration;
+use tokio::{
+    io::AsyncReadExt,
+    net::{
+        TcpListener,
+        TcpSocket,
+        TcpStream,
+    },
+    time,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How long a peer has to send enough of its handshake for us to know the torrent
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How much of every connection is read to tell an SSL connection from the others.  Every kind of
+/// handshake sends at least this much before waiting for us
+const SNIFF_LENGTH: usize = 6;
+
+/// Content type of a TLS handshake record, and the handshake type of a ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 1;
+
+/// The TLS extension that holds the server name
+const SERVER_NAME_EXTENSION: [u8; 2] = [0, 0];
+
+/// Incoming connections, from every address we listen on
+pub type Listener = BoxStream<'static, io::Result<TcpStream>>;
+
+/// A connection a peer made to one of the session's torrents
+pub struct Incoming {
+    // Encryption has been worked out, unless the peer is starting SSL.  What was read of the
+    // handshake is read again first
+    pub conn: Encrypted<TcpStream>,
+    pub address: SocketAddr,
+    // If true, the peer is starting SSL, and the SSL handshake is still to be answered
+    pub ssl: bool,
+}
+
+/// Where a torrent takes its connections
+struct Route {
+    sender: UnboundedSender<Incoming>,
+    // Whether the torrent only talks SSL
+    ssl: bool,
+}
+
+/// The torrents taking connections, by info hash.  Clones share the same routes
+#[derive(Clone, Default)]
+pub struct Routes {
+    routes: Arc<Mutex<HashMap<[u8; 20], Route>>>,
+}
+
+impl Routes {
+    pub fn new() -> Self {
+        Routes::default()
+    }
+
+    /// Take connections for a torrent known by `info_hashes`, which only talks SSL if `ssl` is
+    /// set.  The connections come out of the receiver until the torrent is removed
+    pub fn add(&self, info_hashes: &[[u8; 20]], ssl: bool) -> UnboundedReceiver<Incoming> {
+        let (sender, receiver) = unbounded();
+        let mut routes = self.routes.lock().unwrap();
+        for info_hash in info_hashes {
+            routes.insert(*info_hash, Route {
+                sender: sender.clone(),
+                ssl,
+            });
+        }
+        receiver
+    }
+
+    /// Stop taking connections for a torrent
+    pub fn remove(&self, info_hashes: &[[u8; 20]]) {
+        let mut routes = self.routes.lock().unwrap();
+        for info_hash in info_hashes {
+            routes.remove(info_hash);
+        }
+    }
+
+    /// The info hashes of the torrents that take connections without SSL
+    fn plain(&self) -> Vec<[u8; 20]> {
+        self.routes.lock().unwrap().iter()
+            .filter(|(_, route)| !route.ssl)
+            .map(|(info_hash, _)| *info_hash)
+            .collect()
+    }
+
+    /// Hand a connection to the torrent known by `info_hash`.  False if no running torrent takes
+    /// it
+    fn route(&self, info_hash: &[u8; 20], incoming: Incoming) -> bool {
+        let mut routes = self.routes.lock().unwrap();
+        let sent = match routes.get(info_hash) {
+            Some(route) if route.ssl == incoming.ssl => route.sender.unbounded_send(incoming).is_ok(),
+            _ => return false,
+        };
+        if !sent {
+            // The torrent stopped without taking its route out
+            routes.remove(info_hash);
+        }
+        sent
+    }
+}
+
+/// Listen for peers on `port`, over IPv6 too if `ipv6` is set
+pub fn bind(port: u16, ipv6: bool) -> io::Result<Listener> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(SocketAddr::new([0, 0, 0, 0].into(), port))?;
+    let mut listener = incoming(socket.listen(1024)?);
+    if ipv6 {
+        match listen_ipv6(port) {
+            Ok(listener6) => listener = stream::select(listener, incoming(listener6)).boxed(),
+            Err(e) => warn!("Could not listen for IPv6 peers: {}", e),
+        }
+    }
+    Ok(listener)
+}
+
+/// Listen for IPv6 peers on a port.  The socket only takes IPv6 connections, so it can share the
+/// port with an IPv4 listener
+pub fn listen_ipv6(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port).into())?;
+    socket.listen(128)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Whether an address is in the global unicast range, so peers on the internet can reach it
+pub fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Our routable IPv6 address, if we have one.  Connecting a UDP socket doesn't send anything, but
+/// it makes the system pick the address it would route from
+pub fn global_ipv6() -> Option<Ipv6Addr> {
+    let socket = UdpSocket::bind("[::]:0").ok()?;
+    socket.connect("[2001:4860:4860::8888]:53").ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V6(address) if is_global_ipv6(address.ip()) => Some(*address.ip()),
+        _ => None,
+    }
+}
+
+/// The connections peers make to a listener
+fn incoming(listener: TcpListener) -> Listener {
+    stream::poll_fn(move |cx| listener.poll_accept(cx).map(|res| Some(res.map(|(conn, _)| conn)))).boxed()
+}
+
+/// Take connections from `listener` for as long as it works, and hand each to its torrent.
+/// Banned and blocked addresses are dropped before anything is read from them.  Any connection
+/// from outside shows `reachability` that the port is open
+pub async fn serve(mut listener: Listener, routes: Routes, reachability: Reachability, bans: Arc<Mutex<BanList>>,
+                   blocklist: Blocklist, policy: EncryptionPolicy) {
+    while let Some(conn) = listener.next().await {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Stopped taking peer connections, the listener failed: {}", e);
+                return;
+            }
+        };
+        let address = match conn.peer_addr() {
+            Ok(address) => address,
+            Err(_) => continue,
+        };
+        if !address.ip().is_loopback() {
+            reachability.set_open();
+        }
+        if let Some(ban) = bans.lock().unwrap().get(address.ip()) {
+            debug!("Refusing connection from banned peer {}: {}", ban.ip, ban.reason);
+            continue;
+        }
+        if blocklist.is_blocked(address.ip()) {
+            debug!("Refusing connection from blocked address {}", address);
+            continue;
+        }
+        tokio::spawn(route(conn, address, routes.clone(), policy));
+    }
+}
+
+fn other(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Read enough of a connection's handshake to know its torrent, and hand it over
+async fn route(conn: TcpStream, address: SocketAddr, routes: Routes, policy: EncryptionPolicy) {
+    let identified = time::timeout(ROUTE_TIMEOUT, identify(conn, &routes, policy)).await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+    match identified {
+        Ok((conn, info_hash, ssl)) => {
+            if !routes.route(&info_hash, Incoming { conn, address, ssl }) {
+                debug!("Dropping connection from {}, it is for a torrent we aren't running", address);
+            }
+        }
+        Err(e) => debug!("Dropping connection from {}: {}", address, e),
+    }
+}
+
+/// Work out which torrent a connection is for, and whether it is starting SSL
+async fn identify(mut conn: TcpStream, routes: &Routes, policy: EncryptionPolicy)
+                  -> io::Result<(Encrypted<TcpStream>, [u8; 20], bool)> {
+    let mut start = vec![0; SNIFF_LENGTH];
+    conn.read_exact(&mut start).await?;
+    if !is_client_hello(&start) {
+        let (conn, info_hash) = mse::accept(conn, start, routes.plain(), policy).await?;
+        return Ok((conn, info_hash, false));
+    }
+    // Read the rest of the record the ClientHello is in
+    let length = (start[3] as usize) << 8 | start[4] as usize;
+    let mut record = start;
+    record.resize(SNIFF_LENGTH + length - 1, 0);
+    conn.read_exact(&mut record[SNIFF_LENGTH..]).await?;
+    let info_hash = server_name(&record).and_then(|name| unhex(&name))
+        .ok_or_else(|| other("SSL peer didn't name a torrent"))?;
+    Ok((Encrypted::unread(conn, record), info_hash, true))
+}
+
+/// Whether a connection starts with an SSL ClientHello, rather than a BitTorrent handshake
+fn is_client_hello(start: &[u8]) -> bool {
+    // The record's version starts with 3 in every version of SSL and TLS.  A record holds at least
+    // the handshake type we already read
+    start[0] == TLS_HANDSHAKE && start[1] == 3 && start[5] == CLIENT_HELLO
+        && (start[3] != 0 || start[4] != 0)
+}
+
+/// Reads a TLS message front to back
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (front, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(front)
+    }
+
+    /// A field that starts with its length, in `bytes` bytes
+    fn field(&mut self, bytes: usize) -> Option<&'a [u8]> {
+        let len = self.take(bytes)?.iter().fold(0, |len, b| len << 8 | *b as usize);
+        self.take(len)
+    }
+}
+
+/// The server name an SSL ClientHello asks for.  `record` is the TLS record holding the
+/// ClientHello, from its header on
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut hello = Cursor(record);
+    // The record header, the handshake type and length, the version and the random
+    hello.take(5 + 4 + 2 + 32)?;
+    // Session id, cipher suites and compression methods
+    hello.field(1)?;
+    hello.field(2)?;
+    hello.field(1)?;
+    let mut extensions = Cursor(hello.field(2)?);
+    while let Some(kind) = extensions.take(2) {
+        let data = extensions.field(2)?;
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let mut names = Cursor(Cursor(data).field(2)?);
+        while let Some(kind) = names.take(1) {
+            let name = names.field(2)?;
+            // Only host names are defined
+            if kind[0] == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,103 @@
+use std::net::TcpListener as StdListener;
+use super::*;
+
+/// A ClientHello record asking for `name`, with one cipher suite and an extension before the
+/// server name
+fn client_hello(name: &str) -> Vec<u8> {
+    let mut server_name = vec![0];
+    server_name.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    server_name.extend_from_slice(name.as_bytes());
+    let mut list = (server_name.len() as u16).to_be_bytes().to_vec();
+    list.extend(server_name);
+    // An empty renegotiation_info extension, then the server name
+    let mut extensions = vec![0xff, 0x01, 0, 1, 0, 0, 0];
+    extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+    extensions.extend(list);
+
+    let mut hello = vec![3, 3];
+    hello.extend_from_slice(&[7; 32]);
+    hello.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+    hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    hello.extend(extensions);
+
+    let mut handshake = vec![CLIENT_HELLO, 0];
+    handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+    handshake.extend(hello);
+    let mut record = vec![TLS_HANDSHAKE, 3, 1];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend(handshake);
+    record
+}
+
+/// A connection to ourselves, to hand around
+fn incoming(ssl: bool) -> Incoming {
+    let listener = StdListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let conn = std::net::TcpStream::connect(address).unwrap();
+    conn.set_nonblocking(true).unwrap();
+    Incoming {
+        conn: Encrypted::plaintext(TcpStream::from_std(conn).unwrap()),
+        address,
+        ssl,
+    }
+}
+
+#[test]
+fn test_is_global_ipv6() {
+    assert!(is_global_ipv6(&"2001:db8::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"fe80::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"fd00::1".parse().unwrap()));
+    assert!(!is_global_ipv6(&"::ffff:10.0.0.1".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_ipv6_listener_shares_the_port() {
+    let listener = match listen_ipv6(0) {
+        Ok(listener) => listener,
+        // No IPv6 here
+        Err(_) => return,
+    };
+    let port = listener.local_addr().unwrap().port();
+    // It only takes IPv6 connections, so IPv4 can still have the port
+    let _listener4 = StdListener::bind(("0.0.0.0", port)).unwrap();
+    assert!(TcpStream::connect(("::1", port)).await.is_ok());
+}
+
+#[test]
+fn test_server_name() {
+    let record = client_hello("0101010101010101010101010101010101010101");
+    assert!(is_client_hello(&record[..SNIFF_LENGTH]));
+    assert_eq!(server_name(&record), Some("0101010101010101010101010101010101010101".to_string()));
+    assert_eq!(server_name(&record[..record.len() - 1]), None);
+}
+
+#[test]
+fn test_handshakes_are_not_client_hellos() {
+    assert!(!is_client_hello(b"\x13BitTorrent protocol"));
+    assert!(!is_client_hello(&[TLS_HANDSHAKE, 3, 1, 0, 0, CLIENT_HELLO]));
+}
+
+#[tokio::test]
+async fn test_routes() {
+    let routes = Routes::new();
+    let plain = routes.add(&[[1; 20], [3; 20]], false);
+    let ssl = routes.add(&[[2; 20]], true);
+    let mut hashes = routes.plain();
+    hashes.sort();
+    assert_eq!(hashes, vec![[1; 20], [3; 20]]);
+
+    // Either hash of a hybrid torrent reaches it, but only the way the torrent talks
+    assert!(routes.route(&[3; 20], incoming(false)));
+    assert!(routes.route(&[2; 20], incoming(true)));
+    assert!(!routes.route(&[2; 20], incoming(false)));
+    assert!(!routes.route(&[4; 20], incoming(false)));
+    routes.remove(&[[1; 20], [3; 20]]);
+    assert!(routes.plain().is_empty());
+    assert_eq!(plain.count().await, 1);
+
+    // A torrent that is gone takes nothing
+    drop(ssl);
+    assert!(!routes.route(&[2; 20], incoming(true)));
+    assert!(routes.routes.lock().unwrap().is_empty());
+}
@@ -0,0 +1,149 @@
+//! logging writes the client's log lines to stderr, and optionally to a file that rotates when it
+//! gets too big.  Every line names its target: peers log as `peer::<address>` and torrents as
+//! `torrent::<first 8 hex digits of the info hash>`, so one peer or torrent can be picked out of
+//! the log of a long seeding session with grep
+use chrono::Local;
+use log::{
+    Level,
+    Log,
+    Metadata,
+    Record,
+    SetLoggerError,
+};
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    Write,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Mutex;
+
+#[cfg(test)]
+mod test;
+
+/// The target torrents log under
+pub fn torrent_target(info_hash: &[u8; 20]) -> String {
+    format!("torrent::{}", crate::session::hex(&info_hash[..4]))
+}
+
+/// A log file that is moved to `<path>.1` once it grows past `max_bytes`, pushing older logs to
+/// `<path>.2` and on, and deleting what falls past `keep` of them
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    file: File,
+    written: u64,
+}
+
+impl RotatingFile {
+    /// Opens the log at `path`, appending to what is already there
+    pub fn open<P: AsRef<Path>>(path: P, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_bytes,
+            keep,
+            file,
+            written,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _res = fs::remove_file(self.rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A line longer than max_bytes still goes in, on its own file
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Formats a log line as `<time> <level> [<target>] <message>`
+pub fn format(record: &Record) -> String {
+    format!("{} {:<5} [{}] {}\n", Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), record.level(),
+            record.target(), record.args())
+}
+
+struct Logger {
+    level: Level,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format(record);
+        let _res = io::stderr().write_all(line.as_bytes());
+        if let Some(file) = &self.file {
+            // There is nowhere left to report a failed write to the log
+            let _res = file.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _res = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Logs everything at `level` or more important to stderr, and to `file` if there is one
+pub fn init(level: Level, file: Option<RotatingFile>) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(Logger {
+        level,
+        file: file.map(Mutex::new),
+    }))?;
+    log::set_max_level(level.to_level_filter());
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use super::*;
+use std::env;
+
+fn log_path(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-logging-{}-{}", name, std::process::id()));
+    let _res = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir.join("boosttorrent.log")
+}
+
+#[test]
+fn test_appends_to_existing_log() {
+    let path = log_path("append");
+    fs::write(&path, b"old\n").unwrap();
+    let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+    file.write_all(b"new\n").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"old\nnew\n");
+}
+
+#[test]
+fn test_rotates_when_full() {
+    let path = log_path("rotate");
+    let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+    for line in &[&b"first\n"[..], b"second\n", b"third\n", b"fourth\n"] {
+        file.write_all(line).unwrap();
+    }
+    let rotated = |n: usize| fs::read(format!("{}.{}", path.display(), n)).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"fourth\n");
+    assert_eq!(rotated(1), b"third\n");
+    assert_eq!(rotated(2), b"second\n");
+    // Only two old logs are kept
+    assert!(!Path::new(&format!("{}.3", path.display())).exists());
+}
+
+#[test]
+fn test_truncates_without_old_logs() {
+    let path = log_path("truncate");
+    let mut file = RotatingFile::open(&path, 10, 0).unwrap();
+    file.write_all(b"first\n").unwrap();
+    file.write_all(b"second\n").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"second\n");
+    assert!(!Path::new(&format!("{}.1", path.display())).exists());
+}
+
+#[test]
+fn test_torrent_targets_use_a_short_hash() {
+    let mut info_hash = [0u8; 20];
+    info_hash[..4].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+    assert_eq!(torrent_target(&info_hash), "torrent::deadbeef");
+}
@@ -0,0 +1,322 @@
+//! metadata downloads a torrent's info dictionary from peers with the ut_metadata extension
+//! (BEP 9), so a magnet link can be turned into a .torrent file
+use crate::boostencode::{
+    FromValue,
+    Value,
+};
+use crate::peer::{
+    extension::{
+        self,
+        ExtendedHandshake,
+        MetadataMessage,
+    },
+    message::{
+        Message,
+        MessageCodec,
+    },
+};
+use crate::tracker::{
+    Tracker,
+    TrackerResponse,
+};
+use bytes::Bytes;
+use crypto::{
+    digest::Digest,
+    sha1::Sha1,
+};
+use futures::{
+    future,
+    stream,
+    FutureExt,
+    SinkExt,
+    StreamExt,
+};
+use log::{
+    debug,
+    info,
+};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::Duration;
+use super::MagnetLink;
+use tokio::{
+    net::TcpStream,
+    time,
+};
+use tokio_util::codec::Framed;
+
+#[cfg(test)]
+mod test;
+
+/// Refuse info dictionaries bigger than this, so a peer can't make us hold gigabytes in memory
+const MAX_METADATA_SIZE: usize = 10 * 1024 * 1024;
+
+/// How many peers to ask at once
+const MAX_CONNECTIONS: usize = 8;
+
+/// How long a peer gets to send the whole info dictionary
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, derive_error::Error)]
+pub enum MetadataError {
+    /// The magnet link doesn't name any trackers to find peers with
+    NoTrackers,
+    /// The trackers didn't know of any peers
+    NoPeers,
+    /// None of the peers sent us a valid info dictionary
+    Unavailable,
+}
+
+/// Collects the pieces of an info dictionary as they arrive
+#[derive(Debug)]
+pub struct MetadataBuffer {
+    info_hash: [u8; 20],
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+}
+
+impl MetadataBuffer {
+    pub fn new(info_hash: [u8; 20], size: usize) -> Result<Self, String> {
+        if size == 0 || size > MAX_METADATA_SIZE {
+            return Err(format!("Invalid metadata size: {}", size));
+        }
+        let num_pieces = size.div_ceil(extension::METADATA_PIECE_SIZE);
+        Ok(MetadataBuffer {
+            info_hash,
+            size,
+            pieces: vec![None; num_pieces],
+        })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Store a piece.  Returns false if the piece doesn't belong to the info dictionary
+    pub fn add(&mut self, piece: u32, data: Vec<u8>) -> bool {
+        let index = piece as usize;
+        if index >= self.pieces.len() {
+            return false;
+        }
+        let expected = extension::METADATA_PIECE_SIZE.min(self.size - index * extension::METADATA_PIECE_SIZE);
+        if data.len() != expected {
+            return false;
+        }
+        self.pieces[index] = Some(data);
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// The info dictionary, if every piece arrived and it matches the info hash
+    pub fn finish(self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let info: Vec<u8> = self.pieces.into_iter().flatten().flatten().collect();
+        let mut hasher = Sha1::new();
+        hasher.input(&info);
+        let mut hash = [0; 20];
+        hasher.result(&mut hash);
+        if hash == self.info_hash {
+            Some(info)
+        } else {
+            None
+        }
+    }
+}
+
+/// Build a .torrent file around a downloaded info dictionary.  The info dictionary is copied in
+/// as is, so the info hash stays the same
+pub fn torrent_file(info: &[u8], trackers: &[String]) -> Vec<u8> {
+    let mut res = vec![b'd'];
+    if let Some(first) = trackers.first() {
+        res.extend(Value::BString(Vec::from("announce")).encode());
+        res.extend(Value::BString(Vec::from(first.as_bytes())).encode());
+        // Every tracker from the link in its own tier, in the order they were given
+        let tiers = trackers.iter()
+            .map(|url| Value::List(vec![Value::BString(Vec::from(url.as_bytes()))]))
+            .collect();
+        res.extend(Value::BString(Vec::from("announce-list")).encode());
+        res.extend(Value::List(tiers).encode());
+    }
+    res.extend(Value::BString(Vec::from("info")).encode());
+    res.extend_from_slice(info);
+    res.push(b'e');
+    res
+}
+
+/// Find peers through the link's trackers, and download the info dictionary from the first one
+/// that has it.  Resolves to the contents of a .torrent file for the link
+pub fn fetch(link: &MagnetLink, peer_id: [u8; 20], port: u16) -> impl Future<Output=Result<Vec<u8>, MetadataError>> {
+    let info_hash = link.info_hash;
+    let trackers = link.trackers.clone();
+    let announces: Vec<_> = trackers.iter().map(|url| {
+        let mut tracker = Tracker::new(peer_id, url.clone(), info_hash, port);
+        // We don't know how big the torrent is, just that we need all of it
+        tracker.start(1);
+        tracker.map(|res| match res {
+            Ok(TrackerResponse::Success(resp)) | Ok(TrackerResponse::Warning(_, resp)) => {
+                resp.peers.into_iter().map(|peer| peer.address).collect()
+            }
+            Ok(TrackerResponse::Failure(msg)) => {
+                debug!("Tracker refused to announce: {}", msg);
+                Vec::new()
+            }
+            Err(e) => {
+                debug!("Could not announce: {:?}", e);
+                Vec::new()
+            }
+        })
+    }).collect();
+
+    async move {
+        if trackers.is_empty() {
+            return Err(MetadataError::NoTrackers);
+        }
+        let mut peers: Vec<SocketAddr> = future::join_all(announces).await.into_iter().flatten().collect();
+        peers.sort();
+        peers.dedup();
+        if peers.is_empty() {
+            return Err(MetadataError::NoPeers);
+        }
+        info!("Asking {} peers for the torrent's metadata", peers.len());
+        let mut fetched = stream::iter(peers)
+            .map(|address| async move {
+                let fetch = async {
+                    let conn = TcpStream::connect(address).await?;
+                    PeerFetch::new(conn, info_hash, peer_id).await
+                };
+                let res = time::timeout(PEER_TIMEOUT, fetch).await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+                if let Err(e) = &res {
+                    debug!("Could not get metadata from {}: {:?}", address, e);
+                }
+                res.ok()
+            })
+            .buffer_unordered(MAX_CONNECTIONS)
+            .filter_map(future::ready);
+        let info = fetched.next().await.ok_or(MetadataError::Unavailable)?;
+        Ok(torrent_file(&info, &trackers))
+    }
+}
+
+/// Downloads the info dictionary from one peer
+struct PeerFetch {
+    conn: Framed<TcpStream, MessageCodec>,
+    info_hash: [u8; 20],
+    outbox: VecDeque<Message>,
+    // The id the peer wants ut_metadata messages sent under, once we know it
+    their_id: Option<u8>,
+    buffer: Option<MetadataBuffer>,
+}
+
+impl PeerFetch {
+    fn new(conn: TcpStream, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        let mut outbox = VecDeque::new();
+        outbox.push_back(Message::Handshake((info_hash, peer_id).into()));
+        PeerFetch {
+            conn: Framed::new(conn, MessageCodec::new()),
+            info_hash,
+            outbox,
+            their_id: None,
+            buffer: None,
+        }
+    }
+
+    fn send_metadata(&mut self, message: MetadataMessage) {
+        if let Some(id) = self.their_id {
+            self.outbox.push_back(Message::Extended(id, Bytes::from(message.encode())));
+        }
+    }
+
+    /// Handle a message from the peer.  Returns the info dictionary once it is complete
+    fn receive(&mut self, message: Message) -> io::Result<Option<Vec<u8>>> {
+        match message {
+            Message::Handshake(handshake) => {
+                if handshake.info_hash != self.info_hash {
+                    return Err(error("peer sent the wrong info hash"));
+                }
+                if !handshake.reserved.extended() {
+                    return Err(error("peer doesn't support the extension protocol"));
+                }
+                // The fast extension needs us to say what we have, which is nothing
+                if handshake.reserved.fast() {
+                    self.outbox.push_back(Message::HaveNone);
+                }
+                let ours = ExtendedHandshake::ours(&["ut_metadata"], None).encode();
+                self.outbox.push_back(Message::Extended(extension::HANDSHAKE_ID, Bytes::from(ours)));
+            }
+            Message::Extended(extension::HANDSHAKE_ID, payload) => {
+                let handshake = Value::decode(&payload).map_err(|e| error(&format!("{:?}", e)))
+                    .and_then(|val| ExtendedHandshake::from_value(&val).map_err(|e| error(&e)))?;
+                self.their_id = Some(handshake.id("ut_metadata")
+                    .ok_or(error("peer doesn't support ut_metadata"))?);
+                let size = handshake.metadata_size.ok_or(error("peer doesn't have the metadata"))?;
+                let buffer = MetadataBuffer::new(self.info_hash, size).map_err(|e| error(&e))?;
+                for piece in 0..buffer.num_pieces() {
+                    self.send_metadata(MetadataMessage::Request(piece as u32));
+                }
+                self.buffer = Some(buffer);
+            }
+            Message::Extended(extension::UT_METADATA_ID, payload) => {
+                match MetadataMessage::decode(&payload).map_err(|e| error(&e))? {
+                    MetadataMessage::Data { piece, data, .. } => {
+                        let buffer = self.buffer.as_mut().ok_or(error("metadata sent before handshake"))?;
+                        if !buffer.add(piece, data) {
+                            return Err(error("peer sent an invalid metadata piece"));
+                        }
+                        if buffer.is_complete() {
+                            let info = self.buffer.take().and_then(MetadataBuffer::finish)
+                                .ok_or(error("metadata doesn't match the info hash"))?;
+                            return Ok(Some(info));
+                        }
+                    }
+                    MetadataMessage::Reject(_) => return Err(error("peer refused to send the metadata")),
+                    // We don't have the metadata either
+                    MetadataMessage::Request(piece) => self.send_metadata(MetadataMessage::Reject(piece)),
+                }
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+}
+
+impl Future for PeerFetch {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        loop {
+            match this.conn.poll_next_unpin(cx)? {
+                Poll::Ready(Some(message)) => {
+                    if let Some(info) = this.receive(message)? {
+                        return Poll::Ready(Ok(info));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(Err(error("peer closed the connection"))),
+                Poll::Pending => break,
+            }
+        }
+        while !this.outbox.is_empty() && this.conn.poll_ready_unpin(cx)?.is_ready() {
+            let message = this.outbox.pop_front().unwrap();
+            this.conn.start_send_unpin(message)?;
+        }
+        let _ = this.conn.poll_flush_unpin(cx)?;
+        Poll::Pending
+    }
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(msg)
+}
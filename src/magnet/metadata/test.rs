@@ -0,0 +1,43 @@
+use crate::metainfo::MetaInfo;
+use super::*;
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+    hash
+}
+
+#[test]
+fn test_buffer_assembles_pieces() {
+    let info: Vec<u8> = (0..extension::METADATA_PIECE_SIZE + 10).map(|i| i as u8).collect();
+    let mut buffer = MetadataBuffer::new(sha1(&info), info.len()).unwrap();
+    assert_eq!(buffer.num_pieces(), 2);
+    assert!(buffer.add(1, info[extension::METADATA_PIECE_SIZE..].to_vec()));
+    assert!(!buffer.is_complete());
+    // Wrong sizes and pieces past the end are refused
+    assert!(!buffer.add(0, vec![0; 10]));
+    assert!(!buffer.add(2, vec![0; 10]));
+    assert!(buffer.add(0, info[..extension::METADATA_PIECE_SIZE].to_vec()));
+    assert_eq!(buffer.finish(), Some(info));
+}
+
+#[test]
+fn test_buffer_checks_hash() {
+    let mut buffer = MetadataBuffer::new([0; 20], 5).unwrap();
+    assert!(buffer.add(0, b"d1:ae".to_vec()));
+    assert_eq!(buffer.finish(), None);
+    assert!(MetadataBuffer::new([0; 20], MAX_METADATA_SIZE + 1).is_err());
+}
+
+#[test]
+fn test_torrent_file_keeps_info_hash() {
+    let info = b"d6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_vec();
+    let trackers = vec!["http://a.example/announce".to_string(), "http://b.example/announce".to_string()];
+    let torrent = torrent_file(&info, &trackers);
+    let metainfo = MetaInfo::from_value(&Value::decode(&torrent).unwrap()).unwrap();
+    assert_eq!(metainfo.info_hash, sha1(&info));
+    assert_eq!(metainfo.announce, "http://a.example/announce");
+    assert_eq!(metainfo.announce_list.map(|list| list.len()), Some(2));
+}
@@ -0,0 +1,127 @@
+//! magnet parses magnet URIs (BEP 9), including the select-only parameter from BEP 53 that names
+//! which files to download once we have the metadata
+use percent_encoding::percent_decode;
+use std::str::FromStr;
+
+pub mod metadata;
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    // The display name, used until the metadata arrives
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    // Inclusive ranges of file indices to download.  None means download everything
+    pub select_only: Option<Vec<(usize, usize)>>,
+}
+
+impl MagnetLink {
+    /// Whether the file at `index` should be downloaded
+    pub fn is_selected(&self, index: usize) -> bool {
+        match &self.select_only {
+            Some(ranges) => ranges.iter().any(|(start, end)| *start <= index && index <= *end),
+            None => true,
+        }
+    }
+
+    /// Which of `num_files` files to download, for once the metadata arrives.  Indices in the link
+    /// past the end of the file list are ignored.
+    pub fn selection(&self, num_files: usize) -> Vec<bool> {
+        (0..num_files).map(|i| self.is_selected(i)).collect()
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = match s.strip_prefix("magnet:?") {
+            Some(query) => query,
+            None => return Err(format!("Not a magnet link: {}", s)),
+        };
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut select_only: Option<Vec<(usize, usize)>> = None;
+        for param in query.split('&') {
+            let mut parts = param.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = percent_decode(parts.next().unwrap_or("").as_bytes())
+                .decode_utf8_lossy()
+                .into_owned();
+            match key {
+                "xt" if value.starts_with("urn:btih:") => {
+                    info_hash = Some(parse_info_hash(&value["urn:btih:".len()..])?);
+                }
+                "dn" => name = Some(value.replace('+', " ")),
+                "tr" => trackers.push(value),
+                "so" => select_only.get_or_insert_with(Vec::new).extend(parse_select_only(&value)?),
+                _ => (),
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or("Magnet link has no BitTorrent info hash".to_string())?,
+            name,
+            trackers,
+            select_only,
+        })
+    }
+}
+
+/// Parses a list like "0,2,4-6"
+pub fn parse_select_only(s: &str) -> Result<Vec<(usize, usize)>, String> {
+    s.split(',')
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let invalid = || format!("Invalid file index in select-only: {}", item);
+            let mut bounds = item.splitn(2, '-');
+            let start = bounds.next().unwrap_or("").parse().map_err(|_| invalid())?;
+            let end = match bounds.next() {
+                Some(end) => end.parse().map_err(|_| invalid())?,
+                None => start,
+            };
+            if end < start {
+                return Err(invalid());
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+/// Info hashes are either 40 hex digits or 32 base32 digits
+fn parse_info_hash(s: &str) -> Result<[u8; 20], String> {
+    let invalid = || format!("Invalid info hash: {}", s);
+    let mut res = [0; 20];
+    match s.len() {
+        40 if s.is_ascii() => {
+            for (i, byte) in res.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+            }
+        }
+        32 => {
+            let mut bits = 0u64;
+            let mut num_bits = 0;
+            let mut i = 0;
+            for c in s.chars() {
+                let digit = match c.to_ascii_uppercase() {
+                    c @ 'A'..='Z' => c as u64 - 'A' as u64,
+                    c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+                    _ => return Err(invalid()),
+                };
+                bits = (bits << 5) | digit;
+                num_bits += 5;
+                if num_bits >= 8 {
+                    num_bits -= 8;
+                    res[i] = (bits >> num_bits) as u8;
+                    i += 1;
+                }
+            }
+        }
+        _ => return Err(invalid()),
+    }
+    Ok(res)
+}
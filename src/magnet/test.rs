@@ -0,0 +1,35 @@
+use super::*;
+
+#[test]
+fn test_parse_magnet() {
+    let link: MagnetLink = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567\
+                            &dn=Some+Files&tr=http%3A%2F%2Ftracker.example%2Fannounce&so=0,2,4-6"
+        .parse().unwrap();
+    assert_eq!(link.info_hash[..4], [0x01, 0x23, 0x45, 0x67]);
+    assert_eq!(link.name, Some("Some Files".to_string()));
+    assert_eq!(link.trackers, vec!["http://tracker.example/announce".to_string()]);
+    assert_eq!(link.select_only, Some(vec![(0, 0), (2, 2), (4, 6)]));
+    assert_eq!(link.selection(8), vec![true, false, true, false, true, true, true, false]);
+}
+
+#[test]
+fn test_no_select_only_selects_everything() {
+    let link: MagnetLink = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567"
+        .parse().unwrap();
+    assert_eq!(link.select_only, None);
+    assert_eq!(link.selection(2), vec![true, true]);
+}
+
+#[test]
+fn test_invalid_select_only() {
+    let hash = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+    assert!(format!("{}&so=6-4", hash).parse::<MagnetLink>().is_err());
+    assert!(format!("{}&so=a", hash).parse::<MagnetLink>().is_err());
+}
+
+#[test]
+fn test_base32_info_hash() {
+    let hex = parse_info_hash("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let base32 = parse_info_hash("AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH").unwrap();
+    assert_eq!(hex, base32);
+}
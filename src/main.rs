@@ -1,67 +1,671 @@
-use crate::boostencode::{FromValue, Value};
+use boosttorrent::{
+    blocklist,
+    client,
+    daemon,
+    dht,
+    dialer,
+    doctor,
+    events,
+    fetch,
+    hasher,
+    logging,
+    magnet,
+    metainfo,
+    picker,
+    piece::budget::MemoryBudget,
+    quota,
+    ratelimit,
+    resume,
+    rpc,
+    seedlimit,
+    server,
+    session,
+    shutdown,
+    socks,
+    ssl,
+    storage,
+    tracker,
+    webhook,
+};
 use clap::App;
 use clap::load_yaml;
 use log::{
     debug,
     error,
+    info,
     Level,
     warn,
 };
-use rand::prelude::*;
-use simple_logger::init_with_level;
-use std::fs::File;
+use std::fs::{
+    self,
+    File,
+};
 use std::io::Read;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::time::Duration;
+use futures::future;
+use tokio::time;
+
+/// How long to wait on each tracker when scraping
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(15);
 
-mod boostencode;
-mod metainfo;
-mod tracker;
-mod server;
-mod piece;
-mod peer;
+/// How long to wait on the DHT when scraping.  It may have to join the network first
+const DHT_SCRAPE_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    match matches.occurrences_of("verbose") {
-        0 => init_with_level(Level::Error),
-        1 => init_with_level(Level::Warn),
-        2 => init_with_level(Level::Info),
-        3 => init_with_level(Level::Debug),
-        4 | _ => init_with_level(Level::Trace),
-    }.unwrap();
+    let daemon = matches.is_present("daemon") && matches.subcommand_name().is_none();
+
+    let level = match matches.occurrences_of("verbose") {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        3 => Level::Debug,
+        4 | _ => Level::Trace,
+    };
+    // A daemon has no terminal, so it always logs to a file
+    let log_path = match matches.value_of("log-file") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if daemon => Some(Path::new(matches.value_of("state-dir").unwrap()).join("boosttorrent2.log")),
+        None => None,
+    };
+    let log_file = log_path.map(|path| {
+        let size = matches.value_of("log-file-size").unwrap().parse::<u64>()
+            .expect("log-file-size must be a number");
+        let keep = matches.value_of("log-file-keep").unwrap().parse()
+            .expect("log-file-keep must be a number");
+        logging::RotatingFile::open(&path, size * 1024 * 1024, keep)
+            .unwrap_or_else(|e| panic!("Could not open the log file {}: {}", path.display(), e))
+    });
+    logging::init(level, log_file).unwrap();
 
     if matches.is_present("garbage-mode") {
         warn!("Garbage mode activated");
     }
 
-    if matches.is_present("torrent-file") {
-        let string = matches.value_of("torrent-file").unwrap();
-        let mut f = File::open(string).expect("file not found");
-        let mut contents = Vec::new();
-        f.read_to_end(&mut contents).expect("error reading file");
-        let val = Value::decode(contents.as_ref()).unwrap();
-        debug!("{}", val);
+    if let Some(ctl) = matches.subcommand_matches("ctl") {
+        let address: std::net::SocketAddr = ctl.value_of("api").unwrap().parse()
+            .expect("api must be an address like 127.0.0.1:9091");
+        let info_hash = |args: &clap::ArgMatches| session::unhex(args.value_of("info-hash").unwrap())
+            .expect("info-hash must be 40 hex digits");
+        let command = match ctl.subcommand() {
+            ("show", Some(args)) => rpc::ctl::Command::Show(info_hash(args)),
+            ("peers", Some(args)) => rpc::ctl::Command::Peers(info_hash(args)),
+            ("add", Some(args)) if args.value_of("file").is_some_and(|file| {
+                file.starts_with("http://") || file.starts_with("https://")
+            }) => {
+                rpc::ctl::Command::AddUrl {
+                    url: args.value_of("file").unwrap().to_owned(),
+                    download_dir: args.value_of("download-dir").map(str::to_owned),
+                }
+            }
+            ("add", Some(args)) => {
+                let path = args.value_of("file").unwrap();
+                rpc::ctl::Command::Add {
+                    contents: fs::read(path).unwrap_or_else(|e| panic!("Could not read {}: {}", path, e)),
+                    download_dir: args.value_of("download-dir").map(str::to_owned),
+                }
+            }
+            ("remove", Some(args)) => rpc::ctl::Command::Remove(info_hash(args)),
+            ("pause", Some(args)) => rpc::ctl::Command::Pause {
+                info_hash: info_hash(args),
+                drop_peers: args.is_present("drop-peers"),
+            },
+            ("resume", Some(args)) => rpc::ctl::Command::Resume(info_hash(args)),
+            ("verify", Some(args)) => rpc::ctl::Command::Verify(info_hash(args)),
+            ("add-tracker", Some(args)) => rpc::ctl::Command::AddTracker {
+                info_hash: info_hash(args),
+                url: args.value_of("url").unwrap().to_owned(),
+                tier: args.value_of("tier").map(|tier| tier.parse().expect("tier must be a number")),
+            },
+            ("remove-tracker", Some(args)) => rpc::ctl::Command::RemoveTracker {
+                info_hash: info_hash(args),
+                url: args.value_of("url").unwrap().to_owned(),
+            },
+            ("reannounce", Some(args)) => rpc::ctl::Command::Reannounce {
+                info_hash: info_hash(args),
+                url: args.value_of("url").map(str::to_owned),
+            },
+            ("rename", Some(args)) => rpc::ctl::Command::Rename {
+                info_hash: info_hash(args),
+                file: args.value_of("file").map(|file| file.parse().expect("file must be a number")),
+                name: args.value_of("name").unwrap().to_owned(),
+            },
+            ("file-priority", Some(args)) => rpc::ctl::Command::FilePriority {
+                info_hash: info_hash(args),
+                file: args.value_of("file").unwrap().parse().expect("file must be a number"),
+                priority: args.value_of("priority").unwrap().to_owned(),
+            },
+            ("bandwidth-priority", Some(args)) => rpc::ctl::Command::BandwidthPriority {
+                info_hash: info_hash(args),
+                priority: args.value_of("priority").unwrap().to_owned(),
+            },
+            ("queue", Some(args)) => rpc::ctl::Command::Queue {
+                info_hash: info_hash(args),
+                to: args.value_of("to").unwrap().to_owned(),
+            },
+            ("force-start", Some(args)) => rpc::ctl::Command::ForceStart {
+                info_hash: info_hash(args),
+                enabled: !args.is_present("off"),
+            },
+            ("limits", Some(args)) if args.is_present("download") || args.is_present("upload") => {
+                rpc::ctl::Command::SetLimits {
+                    download: args.value_of("download").map(str::to_owned),
+                    upload: args.value_of("upload").map(str::to_owned),
+                }
+            }
+            ("limits", _) => rpc::ctl::Command::Limits,
+            ("status", _) => rpc::ctl::Command::Status,
+            ("reload-blocklist", _) => rpc::ctl::Command::ReloadBlocklist,
+            _ => rpc::ctl::Command::List,
+        };
+        let runtime = tokio::runtime::Runtime::new().expect("error starting runtime");
+        match runtime.block_on(rpc::ctl::send(&address, &command)) {
+            Ok(json) => println!("{}", json),
+            Err(rpc::ctl::CtlError::Refused(reason)) => {
+                println!("{}", reason);
+                std::process::exit(1);
+            }
+            Err(rpc::ctl::CtlError::ConnectionError(e)) => {
+                println!("Could not reach the client on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(create) = matches.subcommand_matches("create") {
+        let path = Path::new(create.value_of("path").unwrap());
+        let options = metainfo::builder::CreateOptions {
+            path: path.to_owned(),
+            piece_length: create.value_of("piece-length").unwrap().parse::<usize>()
+                .expect("piece-length must be a number") * 1024,
+            announce: create.values_of("announce").into_iter().flatten().map(str::to_owned).collect(),
+            comment: create.value_of("comment").map(str::to_owned),
+            private: create.is_present("private"),
+            workers: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        };
+        let output = match create.value_of("output") {
+            Some(output) => output.into(),
+            None => {
+                let mut name = path.file_name().expect("PATH has no file name").to_owned();
+                name.push(".torrent");
+                PathBuf::from(name)
+            }
+        };
+        match metainfo::builder::create(&options, &output) {
+            Ok(()) => println!("Wrote {}", output.display()),
+            Err(metainfo::builder::CreateError::Invalid(reason)) => {
+                println!("Could not create the torrent: {}", reason);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                println!("Could not create the torrent: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let state_dir = Path::new(matches.value_of("state-dir").unwrap());
+    // Held until we exit, when the pid file is removed.  It is claimed before detaching, so a
+    // second copy fails while the terminal can still see it
+    let mut pid_file = if daemon {
+        let path = matches.value_of("pid-file").map(PathBuf::from)
+            .unwrap_or_else(|| state_dir.join("boosttorrent2.pid"));
+        match daemon::PidFile::create(&path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                eprintln!("Could not write the pid file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let mut session = session::Session::load(state_dir).expect("error reading session");
+
+    let port = matches.value_of("port").unwrap().parse::<u16>().expect("port must be a number");
+    let proxy: Option<socks::ProxyConfig> = matches.value_of("proxy")
+        .map(|proxy| proxy.parse().unwrap_or_else(|e| panic!("{}", e)));
+    // The DHT and incoming connections can't go through the proxy, and would give our address away
+    let proxy_only = matches.is_present("proxy-only");
+    let tracker_tls = tracker::http::TlsOptions {
+        ca: matches.value_of("tracker-ca")
+            .map(|path| tracker::http::read_certificate(Path::new(path)).unwrap_or_else(|e| panic!("{}", e))),
+        insecure: matches.is_present("tracker-insecure"),
+        no_sni: matches.is_present("tracker-no-sni"),
+    };
+
+    if matches.subcommand_matches("doctor").is_some() {
+        let mut trackers: Vec<String> = matches.values_of("tracker").into_iter().flatten()
+            .map(str::to_owned)
+            .collect();
+        for entry in session.torrents() {
+            let metainfo = fs::read(&entry.torrent_file).ok()
+                .and_then(|contents| metainfo::MetaInfo::from_bytes(&contents).ok());
+            if let Some(metainfo) = metainfo {
+                trackers.push(metainfo.announce);
+                trackers.extend(metainfo.announce_list.into_iter().flatten().map(|(_tier, url)| url));
+            }
+        }
+        trackers.sort();
+        trackers.dedup();
+        let config = doctor::DoctorConfig {
+            trackers,
+            external_ip: matches.value_of("external-ip")
+                .map(|ip| ip.parse().expect("external-ip must be an ip address")),
+            port,
+            download_dir: matches.value_of("download-dir").unwrap().into(),
+        };
+        let checks = doctor::run(&config);
+        for check in &checks {
+            println!("{}", check);
+        }
+        if checks.iter().any(|check| matches!(check.outcome, doctor::Outcome::Fail(_))) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("scrape").is_some() {
+        let runtime = tokio::runtime::Runtime::new().expect("error starting runtime");
+        let peer_id = client::gen_peer_id();
+        // Public torrents whose trackers can't be scraped are scraped from the DHT, unless it
+        // would give away our ip
+        let dht = if matches.is_present("no-dht") || proxy_only || matches.is_present("i2p") {
+            None
+        } else {
+            match dht::Dht::new(port, &[], Some(state_dir.join("dht"))) {
+                Ok((node, handle)) => {
+                    runtime.spawn(node);
+                    Some(handle)
+                }
+                Err(e) => {
+                    warn!("Could not start the DHT, only scraping trackers: {}", e);
+                    None
+                }
+            }
+        };
+        for entry in session.torrents() {
+            let metainfo = match fs::read(&entry.torrent_file).ok()
+                .and_then(|contents| metainfo::MetaInfo::from_bytes(&contents).ok()) {
+                Some(metainfo) => metainfo,
+                None => {
+                    println!("{}: could not read the torrent file", entry.name);
+                    continue;
+                }
+            };
+            let trackers = tracker::tiers::TrackerTiers::new(&metainfo.announce, &metainfo.announce_list);
+            // Trackers are tried in tier order until one answers
+            let scraped = trackers.tiers().iter().flatten().find_map(|url| {
+                let mut tracker = tracker::Tracker::new(peer_id, url.clone(), entry.info_hash, port);
+                tracker.set_proxy(proxy.clone());
+                tracker.set_tls(tracker_tls.clone());
+                runtime.block_on(async { time::timeout(SCRAPE_TIMEOUT, tracker.scrape()).await }).ok()
+                    .and_then(Result::ok)
+                    .map(|info| (url, info))
+            });
+            match scraped {
+                Some((url, info)) => println!("{}: {} seeds, {} leechers, {} downloads ({})", entry.name,
+                                              info.complete, info.incomplete, info.downloaded,
+                                              tracker::passkey::redact(url)),
+                None => {
+                    let scraped = dht.as_ref().filter(|_| !metainfo.info.private).and_then(|dht| {
+                        runtime.block_on(async { time::timeout(DHT_SCRAPE_TIMEOUT, dht.scrape(entry.info_hash)).await })
+                            .ok()
+                            .and_then(Result::ok)
+                    });
+                    match scraped {
+                        Some(info) => println!("{}: about {} seeds, {} leechers (DHT)", entry.name,
+                                               info.complete, info.incomplete),
+                        None => println!("{}: no tracker could be scraped", entry.name),
+                    }
+                }
+            }
+        }
+        return;
+    }
+
+    let hash_threads = match matches.value_of("hash-threads") {
+        Some(threads) => threads.parse().expect("hash-threads must be a number"),
+        None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    };
+    let start_hash_pool = || hasher::HashPool::new(hash_threads).expect("Failed to start the hashing threads");
 
-        let metainfo = metainfo::MetaInfo::from_value(&val).unwrap();
+    if let Some(verify) = matches.subcommand_matches("verify") {
+        let hash_pool = start_hash_pool();
+        let only = verify.value_of("info-hash")
+            .map(|hash| session::unhex(hash).expect("info-hash must be 40 hex digits"));
+        for entry in session.torrents().iter().filter(|entry| only.is_none_or(|hash| hash == entry.info_hash)) {
+            let metainfo = match fs::read(&entry.torrent_file).ok()
+                .and_then(|contents| metainfo::MetaInfo::from_bytes(&contents).ok()) {
+                Some(metainfo) => metainfo,
+                None => {
+                    println!("{}: could not read the torrent file", entry.name);
+                    continue;
+                }
+            };
+            let mut files = storage::FileMap::new(&entry.download_dir, &metainfo.info.file_info);
+            if let Some(dir) = matches.value_of("incomplete-dir") {
+                files.set_incomplete_dir(dir, matches.is_present("part-suffix"));
+            }
+            let hashes: Vec<[u8; 20]> = metainfo.info.pieces.iter()
+                .map(|hash| session::unhex(hash).unwrap_or([0; 20]))
+                .collect();
+            let report = storage::verify::verify(&files, metainfo.info.piece_length as u64, &hashes, &hash_pool);
+            let good = report.have.iter().filter(|have| *have).count();
+            println!("{}: {} of {} pieces are good, {} are corrupt", entry.name, good, hashes.len(),
+                     report.corrupt.len());
+            if !report.corrupt.is_empty() {
+                let corrupt: Vec<String> = report.corrupt.iter().map(u32::to_string).collect();
+                println!("  corrupt pieces: {}", corrupt.join(", "));
+            }
+            // The resume data now says what is really on disk.  The transfer totals are kept
+            let path = session::resume_path(state_dir, &entry.info_hash);
+            let legacy_path = resume::ResumeData::path(&entry.download_dir, metainfo.info.file_info.name());
+            let old = resume::ResumeData::load(&path).ok().flatten()
+                .or_else(|| resume::ResumeData::load(&legacy_path).ok().flatten())
+                .filter(|old| old.info_hash == entry.info_hash);
+            let resume = resume::ResumeData {
+                info_hash: entry.info_hash,
+                pieces: report.have,
+                uploaded: old.as_ref().map_or(0, |old| old.uploaded),
+                downloaded: old.as_ref().map_or(0, |old| old.downloaded),
+                tracker_id: old.as_ref().and_then(|old| old.tracker_id.clone()),
+                tracker_key: old.and_then(|old| old.tracker_key),
+                file_lengths: files.files().iter().map(|file| file.length).collect(),
+            };
+            if let Err(e) = resume.save(&path) {
+                println!("{}: could not save the resume data: {:?}", entry.name, e);
+            }
+        }
+        return;
+    }
+
+    // Only the forked process carries on, so this comes after everything that can refuse to start
+    // and before anything starts a thread
+    if let Some(pid_file) = pid_file.as_mut() {
+        daemon::detach().unwrap_or_else(|e| panic!("Could not start the daemon: {}", e));
+        if let Err(e) = pid_file.rewrite() {
+            error!("Could not write the pid file {}: {}", pid_file.path().display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    // Ctrl-C stops the torrents cleanly.  Nothing else may start a thread before this
+    let shutdown = shutdown::listen().expect("error listening for signals");
+    let hash_pool = start_hash_pool();
+
+    // A torrent given on the command line joins the session, and the command line options that
+    // only make sense for one torrent apply to it
+    let mut added = None;
+    if let Some(string) = matches.value_of("torrent-file") {
+        let link = if string.starts_with("magnet:") {
+            Some(string.parse::<magnet::MagnetLink>().unwrap_or_else(|e| panic!("{}", e)))
+        } else {
+            None
+        };
+        let contents = if let Some(link) = &link {
+            debug!("{:?}", link);
+            let runtime = tokio::runtime::Runtime::new().expect("error starting runtime");
+            match runtime.block_on(magnet::metadata::fetch(link, client::gen_peer_id(), port)) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Could not get the metadata for {}: {:?}", string, e);
+                    return;
+                }
+            }
+        } else if string.starts_with("http://") || string.starts_with("https://") {
+            let headers = matches.values_of("fetch-header").into_iter().flatten()
+                .map(|header| fetch::parse_header(header).unwrap_or_else(|e| panic!("{:?}", e)))
+                .collect();
+            let runtime = tokio::runtime::Runtime::new().expect("error starting runtime");
+            match runtime.block_on(fetch::fetch_torrent(string, headers)) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    error!("Could not download {}: {:?}", string, e);
+                    return;
+                }
+            }
+        } else {
+            let mut f = File::open(string).expect("file not found");
+            let mut contents = Vec::new();
+            f.read_to_end(&mut contents).expect("error reading file");
+            contents
+        };
+        let metainfo = metainfo::MetaInfo::from_bytes(&contents).unwrap();
         debug!("{:?}", metainfo);
 
-        let peer_id = gen_peer_id();
+        // --files picks the files like a magnet link's select-only does, and wins over it
+        let selection = match matches.value_of("files") {
+            Some(files) => Some(magnet::parse_select_only(files).unwrap_or_else(|e| panic!("{}", e))),
+            None => link.as_ref().and_then(|link| link.select_only.clone()),
+        };
+        let mut priorities: Vec<_> = (0..metainfo.info.file_info.files().len()).map(|i| {
+            let selected = selection.as_ref()
+                .is_none_or(|ranges| ranges.iter().any(|(start, end)| *start <= i && i <= *end));
+            if selected {
+                picker::Priority::Normal
+            } else {
+                picker::Priority::Skip
+            }
+        }).collect();
+        for setting in matches.values_of("file-priority").into_iter().flatten() {
+            let mut parts = setting.splitn(2, '=');
+            let index: usize = parts.next().and_then(|i| i.parse().ok())
+                .expect("file-priority must look like INDEX=PRIORITY");
+            let priority = parts.next().expect("file-priority must look like INDEX=PRIORITY")
+                .parse().unwrap_or_else(|e| panic!("{}", e));
+            *priorities.get_mut(index).unwrap_or_else(|| panic!("There is no file {}", index)) = priority;
+        }
 
-        let server = server::Server::new(peer_id, metainfo);
-        tokio::run(server);
-    } else {
+        if let Some(quota) = matches.value_of("disk-quota") {
+            let quota = quota.parse::<u64>().expect("disk-quota must be a number") * 1024 * 1024;
+            let selected = |i: usize| priorities[i] != picker::Priority::Skip;
+            if let Err(e) = quota::check(&metainfo.info.file_info, selected, quota) {
+                error!("Not downloading the torrent: {}", e);
+                return;
+            }
+        }
+
+        session.add(metainfo.info_hash, metainfo.info.file_info.name(), &contents,
+                    matches.value_of("download-dir").unwrap())
+            .expect("error adding torrent to session");
+        if priorities.iter().any(|priority| *priority != picker::Priority::Normal) {
+            session.set_file_priorities(&metainfo.info_hash, priorities)
+                .expect("error saving file priorities");
+        }
+        added = Some(metainfo.info_hash);
+    }
+
+    let api_address: Option<std::net::SocketAddr> = matches.value_of("api")
+        .map(|address| address.parse().expect("api must be an address like 127.0.0.1:9091"));
+    // With the API, torrents can be added once we are running
+    if session.torrents().is_empty() && api_address.is_none() {
         error!("No torrent file provided");
+        return;
     }
-}
 
-fn gen_peer_id() -> [u8; 20] {
-    // Generate peer id in Azures style ("-<2 letter client code><4 digit version number>-<12 random digits>")
-    let mut id = "-BO0001-".to_owned();
-    for _ in 0..12 {
-        id.push_str(&thread_rng().gen_range::<u8>(0, 9).to_string());
+    // The client takes the session over from here
+    drop(session);
+    // One DHT node serves every torrent, on the same port as peer connections
+    let (dht, dht_handle) = if matches.is_present("no-dht") || proxy_only {
+        (None, None)
+    } else {
+        match dht::Dht::new(port, &[], Some(state_dir.join("dht"))) {
+            Ok((dht, handle)) => (Some(dht), Some(handle)),
+            Err(e) => {
+                warn!("Could not start the DHT: {}", e);
+                (None, None)
+            }
+        }
+    };
+    // Rate limits are shared by every torrent
+    let rate = |name| matches.value_of(name)
+        .map(|rate| rate.parse::<u64>().unwrap_or_else(|_| panic!("{} must be a number", name)) * 1024);
+    let download_throttle = ratelimit::Throttle::new(rate("max-download-rate"));
+    let blocklist = match matches.value_of("blocklist") {
+        Some(path) => blocklist::Blocklist::load(path).unwrap_or_else(|e| panic!("Could not load the blocklist: {:?}", e)),
+        None => blocklist::Blocklist::new(),
+    };
+    if blocklist.is_loaded() {
+        info!("Blocking {} ranges of addresses", blocklist.len());
+    }
+    let upload_throttle = ratelimit::Throttle::new(rate("max-upload-rate"));
+    let schedule = matches.value_of("schedule").map(|path| {
+        ratelimit::schedule::Scheduler::new(path, download_throttle.clone(), upload_throttle.clone())
+            .unwrap_or_else(|e| panic!("Could not load the schedule: {:?}", e))
+    });
+    let write_cache = storage::cache::CacheConfig {
+        max_bytes: matches.value_of("write-cache").unwrap().parse::<usize>()
+            .expect("write-cache must be a number") * 1024 * 1024,
+        flush_interval: Duration::from_secs(matches.value_of("write-cache-flush").unwrap().parse()
+            .expect("write-cache-flush must be a number of seconds")),
+    };
+    let max_piece_memory = matches.value_of("max-piece-memory").unwrap().parse::<usize>()
+        .expect("max-piece-memory must be a number");
+    // Every torrent starts from these settings
+    let config = server::Config {
+        piece_memory: MemoryBudget::new(max_piece_memory * 1024 * 1024),
+        write_cache,
+        read_cache: matches.value_of("read-cache").unwrap().parse::<usize>()
+            .expect("read-cache must be a number") * 1024 * 1024,
+        allocation: matches.value_of("allocation").unwrap().parse()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        incomplete_dir: matches.value_of("incomplete-dir").map(PathBuf::from),
+        part_suffix: matches.is_present("part-suffix"),
+        check_md5: matches.is_present("check-md5"),
+        suppress_redundant_haves: matches.is_present("suppress-redundant-haves"),
+        external_ip: matches.value_of("external-ip")
+            .map(|ip| ip.parse().expect("external-ip must be an ip address")),
+        extra_trackers: matches.values_of("tracker").into_iter().flatten()
+            .map(str::to_owned)
+            .collect(),
+        webhook_urls: matches.values_of("webhook").into_iter().flatten()
+            .map(|url| url.parse().expect("webhook must be a url"))
+            .collect(),
+        webhook_events: match matches.values_of("webhook-events") {
+            Some(events) => events.map(|e| e.parse().unwrap_or_else(|e| panic!("{}", e))).collect(),
+            None => webhook::EventKind::all(),
+        },
+        events: events::Broadcast::default(),
+        peer_dscp: matches.value_of("peer-dscp")
+            .map(|dscp| dscp.parse().unwrap_or_else(|e| panic!("{}", e))),
+        proxy: proxy.clone(),
+        tracker_tls,
+        refuse_incoming: matches.is_present("no-incoming") || proxy_only,
+        // The session sets these up once it starts listening
+        routes: None,
+        reachability: None,
+        seed_strategy: matches.value_of("seed-strategy").unwrap().parse()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        seed_limits: seedlimit::SeedLimits {
+            ratio: matches.value_of("seed-ratio")
+                .map(|ratio| ratio.parse().expect("seed-ratio must be a number")),
+            time: matches.value_of("seed-time")
+                .map(|minutes| Duration::from_secs(minutes.parse::<u64>().expect("seed-time must be a number of minutes") * 60)),
+            action: matches.value_of("seed-limit-action").unwrap().parse()
+                .unwrap_or_else(|e| panic!("{}", e)),
+        },
+        seed_time: Duration::default(),
+        disk_locality: matches.is_present("disk-locality"),
+        sequential: matches.is_present("sequential"),
+        file_priorities: Vec::new(),
+        paused: false,
+        ssl: match (matches.value_of("ssl-cert"), matches.value_of("ssl-key")) {
+            (Some(cert), Some(key)) => Some(ssl::SslConfig {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            _ => None,
+        },
+        i2p: if matches.is_present("i2p") {
+            Some(matches.value_of("sam-bridge").unwrap().parse().unwrap_or_else(|e| panic!("{}", e)))
+        } else {
+            None
+        },
+        check_seeds: matches.is_present("check-seeds"),
+        port,
+        max_peers: matches.value_of("max-peers")
+            .map(|n| n.parse().expect("max-peers must be a number")),
+        target_peers: matches.value_of("target-peers").unwrap().parse()
+            .expect("target-peers must be a number"),
+        connection_limits: dialer::ConnectionLimits::new(
+            matches.value_of("max-connections").map(|n| n.parse().expect("max-connections must be a number")),
+            matches.value_of("max-half-open").map(|n| n.parse().expect("max-half-open must be a number"))),
+        numwant: matches.value_of("numwant").unwrap().parse()
+            .expect("numwant must be a number"),
+        unchoke_slots: matches.value_of("unchoke-slots").unwrap().parse()
+            .expect("unchoke-slots must be a number"),
+        bad_data_ban: Duration::from_secs(matches.value_of("bad-data-ban").unwrap().parse::<u64>()
+            .expect("bad-data-ban must be a number of hours") * 60 * 60),
+        handshake_timeout: Duration::from_secs(matches.value_of("handshake-timeout").unwrap().parse()
+            .expect("handshake-timeout must be a number of seconds")),
+        banned_clients: matches.values_of("ban-client").into_iter().flatten().map(str::to_owned).collect(),
+        blocklist,
+        dht: dht_handle,
+        // The session opens the databases when it starts
+        geoip: None,
+        shutdown: Some(shutdown.clone()),
+        download_throttle,
+        upload_throttle,
+        bandwidth_priority: matches.value_of("bandwidth-priority").unwrap().parse()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        encryption: matches.value_of("encryption").unwrap().parse()
+            .unwrap_or_else(|e| panic!("{}", e)),
+        hash_pool,
+    };
+    let client = client::Session::new(client::Settings {
+        state_dir: state_dir.to_owned(),
+        download_dir: matches.value_of("download-dir").unwrap().to_owned(),
+        max_active: matches.value_of("max-active")
+            .map(|n| n.parse::<usize>().expect("max-active must be a number")),
+        geoip: matches.values_of("geoip").map(|paths| paths.map(str::to_owned).collect()),
+        dht,
+        schedule,
+        torrent: config,
+    }).expect("error starting the session");
+    for passkey in matches.values_of("passkey").into_iter().flatten() {
+        let mut parts = passkey.splitn(2, '=');
+        let host = parts.next().unwrap();
+        let key = parts.next().expect("passkey must look like HOST=KEY");
+        client.set_passkey(host, key);
+    }
+    client.start(|entry, files| {
+        if added != Some(entry.info_hash) {
+            return;
+        }
+        if let Some(name) = matches.value_of("rename-root") {
+            files.rename_root(name).expect("error renaming root directory");
+        }
+        for rename in matches.values_of("rename").into_iter().flatten() {
+            let mut parts = rename.splitn(2, '=');
+            let index = parts.next().and_then(|i| i.parse().ok())
+                .expect("rename must look like INDEX=NAME");
+            let name = parts.next().expect("rename must look like INDEX=NAME");
+            files.rename_file(index, name).expect("error renaming file");
+        }
+    });
+
+    // Servers only finish once we are asked to stop
+    let torrents = if let Some(address) = api_address {
+        match client.serve_api(&address) {
+            Ok(()) => info!("Serving the API on {}", address),
+            Err(e) => error!("Could not serve the API on {}: {}", address, e),
+        }
+        // Torrents come and go, so only a signal stops the client
+        client.run_until(shutdown)
+    } else {
+        client.run_until(future::ready(()))
+    };
+    for torrent in &torrents {
+        let latest = torrent.stats();
+        info!("{}: downloaded {} bytes, uploaded {} bytes", torrent.name, latest.downloaded, latest.uploaded);
     }
-    let mut res = [0; 20];
-    res.copy_from_slice(id.as_bytes());
-    res
-}
\ No newline at end of file
+}
@@ -0,0 +1,210 @@
+//! builder makes .torrent files out of files on disk.  Pieces are hashed by a pool of worker
+//! threads, since hashing is what takes the time for large torrents.
+use crate::boostencode::Value;
+use derive_error::Error;
+use std::collections::HashMap;
+use std::fs::{
+    self,
+    File,
+};
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::sha1_hash;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, Error)]
+pub enum CreateError {
+    /// The files could not be read
+    Io(io::Error),
+    /// The files can't be made into a torrent
+    #[error(non_std, no_from)]
+    Invalid(String),
+}
+
+/// What to put in a new torrent
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    // The file or directory to share
+    pub path: PathBuf,
+    // The number of bytes in each piece.  Must be a power of two of at least 16KiB
+    pub piece_length: usize,
+    // Tracker urls.  Each one gets its own tier, in order
+    pub announce: Vec<String>,
+    // Free-form text about the torrent
+    pub comment: Option<String>,
+    // If true, peers should only be found through the trackers
+    pub private: bool,
+    // How many threads hash pieces
+    pub workers: usize,
+}
+
+/// A file going into the torrent
+#[derive(Debug, PartialEq, Clone)]
+struct Entry {
+    // Where to read the file from
+    source: PathBuf,
+    // The components of the path within the torrent.  Empty for a single file torrent
+    path: Vec<String>,
+    length: usize,
+}
+
+/// Build the bencoded metainfo for the files at `options.path`
+pub fn build(options: &CreateOptions) -> Result<Value, CreateError> {
+    if options.piece_length < 16 * 1024 || !options.piece_length.is_power_of_two() {
+        return Err(CreateError::Invalid("piece length must be a power of two of at least 16KiB".to_string()));
+    }
+    let announce = options.announce.first()
+        .ok_or_else(|| CreateError::Invalid("at least one tracker is needed".to_string()))?;
+    let name = options.path.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| CreateError::Invalid("the path has no usable name".to_string()))?
+        .to_string();
+
+    let entries = collect(&options.path)?;
+    let total: usize = entries.iter().map(|entry| entry.length).sum();
+    if total == 0 {
+        return Err(CreateError::Invalid("there is nothing to share".to_string()));
+    }
+    let pieces = hash_pieces(entries.clone(), options.piece_length, options.workers.max(1))?;
+
+    let mut info = HashMap::new();
+    info.insert(b"name".to_vec(), Value::BString(name.into_bytes()));
+    info.insert(b"piece length".to_vec(), Value::Integer(options.piece_length as i64));
+    info.insert(b"pieces".to_vec(), Value::BString(pieces.concat()));
+    if options.private {
+        info.insert(b"private".to_vec(), Value::Integer(1));
+    }
+    if options.path.is_dir() {
+        let files = entries.into_iter().map(|entry| {
+            let mut file = HashMap::new();
+            file.insert(b"length".to_vec(), Value::Integer(entry.length as i64));
+            file.insert(b"path".to_vec(), Value::List(entry.path.into_iter()
+                .map(|component| Value::BString(component.into_bytes()))
+                .collect()));
+            Value::Dict(file)
+        }).collect();
+        info.insert(b"files".to_vec(), Value::List(files));
+    } else {
+        info.insert(b"length".to_vec(), Value::Integer(total as i64));
+    }
+
+    let mut torrent = HashMap::new();
+    torrent.insert(b"announce".to_vec(), Value::BString(announce.clone().into_bytes()));
+    if options.announce.len() > 1 {
+        torrent.insert(b"announce-list".to_vec(), Value::List(options.announce.iter()
+            .map(|url| Value::List(vec![Value::BString(url.clone().into_bytes())]))
+            .collect()));
+    }
+    if let Some(comment) = &options.comment {
+        torrent.insert(b"comment".to_vec(), Value::BString(comment.clone().into_bytes()));
+    }
+    torrent.insert(b"created by".to_vec(),
+                   Value::BString(format!("boosttorrent {}", env!("CARGO_PKG_VERSION")).into_bytes()));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    torrent.insert(b"creation date".to_vec(), Value::Integer(now as i64));
+    torrent.insert(b"info".to_vec(), Value::Dict(info));
+    Ok(Value::Dict(torrent))
+}
+
+/// Build the metainfo and write it to `output`
+pub fn create(options: &CreateOptions, output: &Path) -> Result<(), CreateError> {
+    let torrent = build(options)?;
+    fs::write(output, torrent.encode())?;
+    Ok(())
+}
+
+/// Find every file under `root`, sorted by path so the same files always make the same torrent
+fn collect(root: &Path) -> Result<Vec<Entry>, CreateError> {
+    let metadata = fs::metadata(root)?;
+    if !metadata.is_dir() {
+        return Ok(vec![Entry {
+            source: root.to_owned(),
+            path: Vec::new(),
+            length: metadata.len() as usize,
+        }]);
+    }
+    let mut res = Vec::new();
+    let mut pending = vec![(root.to_owned(), Vec::new())];
+    while let Some((dir, path)) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string()
+                .map_err(|name| CreateError::Invalid(format!("{:?} is not valid UTF-8", name)))?;
+            let mut path = path.clone();
+            path.push(name);
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                pending.push((entry.path(), path));
+            } else {
+                res.push(Entry {
+                    source: entry.path(),
+                    path,
+                    length: metadata.len() as usize,
+                });
+            }
+        }
+    }
+    res.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(res)
+}
+
+/// Hash every piece.  Worker `i` hashes pieces `i`, `i + workers`, `i + 2 * workers`, ...
+fn hash_pieces(entries: Vec<Entry>, piece_length: usize, workers: usize) -> Result<Vec<[u8; 20]>, CreateError> {
+    let total: usize = entries.iter().map(|entry| entry.length).sum();
+    let count = total.div_ceil(piece_length);
+    let entries = Arc::new(entries);
+    let handles: Vec<_> = (0..workers.min(count)).map(|worker| {
+        let entries = entries.clone();
+        thread::spawn(move || -> io::Result<Vec<(usize, [u8; 20])>> {
+            let mut buf = vec![0; piece_length];
+            (worker..count).step_by(workers).map(|index| {
+                let len = read_piece(&entries, index * piece_length, &mut buf)?;
+                Ok((index, sha1_hash(&buf[..len])))
+            }).collect()
+        })
+    }).collect();
+
+    let mut pieces = vec![[0; 20]; count];
+    for handle in handles {
+        let hashed = handle.join()
+            .map_err(|_| CreateError::Invalid("a hashing thread panicked".to_string()))??;
+        for (index, hash) in hashed {
+            pieces[index] = hash;
+        }
+    }
+    Ok(pieces)
+}
+
+/// Read the piece starting `offset` bytes into the torrent, which may span several files.  Returns
+/// how many bytes were read, which is less than the buffer for the last piece
+fn read_piece(entries: &[Entry], offset: usize, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    let mut start = 0;
+    for entry in entries {
+        let end = start + entry.length;
+        if read < buf.len() && offset + read < end && offset + read >= start {
+            let mut file = File::open(&entry.source)?;
+            file.seek(SeekFrom::Start((offset + read - start) as u64))?;
+            let len = (end - offset - read).min(buf.len() - read);
+            file.read_exact(&mut buf[read..read + len])?;
+            read += len;
+        }
+        start = end;
+    }
+    Ok(read)
+}
@@ -0,0 +1,90 @@
+use crate::boostencode::FromValue;
+use crate::metainfo::{
+    FileInfo,
+    MetaInfo,
+};
+use std::env;
+use super::*;
+
+fn scratch_dir(name: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let dir = env::temp_dir().join(format!("boosttorrent-builder-{}-{}", name, now));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn options(path: PathBuf) -> CreateOptions {
+    CreateOptions {
+        path,
+        piece_length: 16 * 1024,
+        announce: vec!["http://a.example/announce".to_string(), "http://b.example/announce".to_string()],
+        comment: Some("test".to_string()),
+        private: true,
+        workers: 3,
+    }
+}
+
+#[test]
+fn test_single_file() {
+    let dir = scratch_dir("single");
+    let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+    fs::write(dir.join("data.bin"), &data).unwrap();
+
+    let meta = MetaInfo::from_value(&build(&options(dir.join("data.bin"))).unwrap()).unwrap();
+    assert_eq!(meta.info.file_info.name(), "data.bin");
+    assert_eq!(meta.info.file_info.size(), 40_000);
+    assert!(meta.info.private);
+    assert_eq!(meta.announce, "http://a.example/announce");
+    assert_eq!(meta.announce_list, Some(vec![
+        (0, "http://a.example/announce".to_string()),
+        (1, "http://b.example/announce".to_string()),
+    ]));
+    assert_eq!(meta.comment, Some("test".to_string()));
+    let expected: Vec<String> = data.chunks(16 * 1024)
+        .map(|chunk| sha1_hash(chunk).iter().map(|byte| format!("{:02x}", byte)).collect())
+        .collect();
+    assert_eq!(meta.info.pieces, expected);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_directory() {
+    let dir = scratch_dir("multi");
+    fs::create_dir_all(dir.join("share/sub")).unwrap();
+    fs::write(dir.join("share/b.txt"), vec![2; 10_000]).unwrap();
+    fs::write(dir.join("share/a.txt"), vec![1; 10_000]).unwrap();
+    fs::write(dir.join("share/sub/c.txt"), vec![3; 20_000]).unwrap();
+
+    let meta = MetaInfo::from_value(&build(&options(dir.join("share"))).unwrap()).unwrap();
+    let files = match &meta.info.file_info {
+        FileInfo::Multi(multi) => multi,
+        _ => panic!("expected a multi file torrent"),
+    };
+    assert_eq!(files.root_dir_name, "share");
+    let names: Vec<_> = files.files.iter().map(|file| file.file_name.as_str()).collect();
+    assert_eq!(names, vec!["a.txt", "b.txt", "sub/c.txt"]);
+    // Pieces run across file boundaries
+    let mut data = vec![1; 10_000];
+    data.extend(vec![2; 10_000]);
+    data.extend(vec![3; 20_000]);
+    let expected: Vec<String> = data.chunks(16 * 1024)
+        .map(|chunk| sha1_hash(chunk).iter().map(|byte| format!("{:02x}", byte)).collect())
+        .collect();
+    assert_eq!(meta.info.pieces, expected);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_invalid_options() {
+    let dir = scratch_dir("invalid");
+    fs::write(dir.join("data.bin"), vec![0; 100]).unwrap();
+    let mut bad_length = options(dir.join("data.bin"));
+    bad_length.piece_length = 20_000;
+    assert!(build(&bad_length).is_err());
+    let mut no_trackers = options(dir.join("data.bin"));
+    no_trackers.announce.clear();
+    assert!(build(&no_trackers).is_err());
+    fs::write(dir.join("empty"), vec![]).unwrap();
+    assert!(build(&options(dir.join("empty"))).is_err());
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -1,11 +1,14 @@
-//! metainfo contains functions and types to parse the .torrent file
+//! metainfo contains functions and types to parse the .torrent file.  Both v1 torrents and the
+//! v2 torrents of BEP 52 are understood, as are hybrids that carry both.
 use crate::boostencode::{FromValue, Value};
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
 use std::collections::HashMap;
 
 #[cfg(test)]
 mod test;
+pub mod builder;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct SingleFile {
@@ -31,22 +34,43 @@ pub enum FileInfo {
     Multi(MultiFile),
 }
 
+/// A file from a v2 file tree
+#[derive(Debug, PartialEq, Clone)]
+pub struct TreeFile {
+    // The components of the path from the root of the tree
+    pub path: Vec<String>,
+    pub length: usize,
+    // The root of the merkle tree of the file's blocks.  Empty files don't have one
+    pub pieces_root: Option<[u8; 32]>,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct InfoDict {
     // The number of bytes in each piece
     pub piece_length: usize,
-    // The SHA1 hashes of each piece
+    // The SHA1 hashes of each piece.  Empty for v2-only torrents
     pub pieces: Vec<String>,
     // If true, only publish presence via trackers and not directly to peers
     pub private: bool,
     // Information about the file(s) to download
     pub file_info: FileInfo,
+    // The PEM CA certificate of an SSL torrent.  Peers must have certificates signed by it
+    pub ssl_cert: Option<Vec<u8>>,
+    // 1 for v1 torrents, 2 for v2 and hybrid torrents
+    pub meta_version: u32,
+    // The v2 files, sorted by path.  None for v1-only torrents
+    pub file_tree: Option<Vec<TreeFile>>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct MetaInfo {
-    // The SHA1 hash of the value of the info key in the torrent file
+    // The hash trackers, the DHT and peers know the torrent by.  The SHA1 hash of the value of the
+    // info key for v1 and hybrid torrents, and the SHA-256 hash cut to 20 bytes for v2 torrents
     pub info_hash: [u8; 20],
+    // The SHA-256 hash of the value of the info key, for v2 and hybrid torrents
+    pub info_hash_v2: Option<[u8; 32]>,
+    // For each file's pieces root, the SHA-256 hashes of its pieces
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
     // Information about the file to be downloaded
     pub info: InfoDict,
     // The url for the tracker
@@ -89,8 +113,42 @@ impl FromValue for SingleFile {
 impl FromValue for MultiFile {
     type Error = String;
 
-    fn from_value(_val: &Value) -> Result<Self, Self::Error> where Self: Sized {
-        unimplemented!()
+    fn from_value(val: &Value) -> Result<Self, Self::Error> where Self: Sized {
+        let map = val.dict().ok_or("Multi file not a dictionary".to_string())?;
+
+        let root_dir_name = map.get("name".as_bytes()).and_then(Value::bstring_utf8)
+            .ok_or("Missing key: name".to_string())?;
+
+        let files = map.get("files".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: files".to_string())?
+            .iter()
+            .map(|file| {
+                let file_map = file.dict().ok_or("File not a dictionary".to_string())?;
+
+                let file_name = file_map.get("path".as_bytes()).and_then(Value::list)
+                    .ok_or("Missing key: path".to_string())?
+                    .iter()
+                    .map(|component| component.bstring_utf8().ok_or("Invalid path".to_string()))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("/");
+
+                let length = file_map.get("length".as_bytes()).and_then(Value::integer)
+                    .map(|i| *i as usize).ok_or("Missing key: length".to_string())?;
+
+                let md5sum = file_map.get("md5".as_bytes()).and_then(Value::bstring_utf8);
+
+                Ok(SingleFile {
+                    file_name,
+                    length,
+                    md5sum,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(MultiFile {
+            root_dir_name,
+            files,
+        })
     }
 }
 
@@ -108,6 +166,28 @@ impl FromValue for FileInfo {
 }
 
 impl FileInfo {
+    /// Build the file info of a v2-only torrent from its file tree.  A torrent of one file is a
+    /// tree holding only that file, under the torrent's name
+    fn from_tree(map: &HashMap<Vec<u8>, Value>, tree: &[TreeFile]) -> Result<Self, String> {
+        let name = map.get("name".as_bytes()).and_then(Value::bstring_utf8)
+            .ok_or("Missing key: name".to_string())?;
+        match tree {
+            [file] if file.path == [name.clone()] => Ok(FileInfo::Single(SingleFile {
+                file_name: name,
+                length: file.length,
+                md5sum: None,
+            })),
+            _ => Ok(FileInfo::Multi(MultiFile {
+                root_dir_name: name,
+                files: tree.iter().map(|file| SingleFile {
+                    file_name: file.path.join("/"),
+                    length: file.length,
+                    md5sum: None,
+                }).collect(),
+            })),
+        }
+    }
+
     /// Gets the total size requirements of the torrent in bytes
     pub fn size(&self) -> usize {
         match self {
@@ -115,6 +195,22 @@ impl FileInfo {
             FileInfo::Multi(m) => m.files.iter().fold(0, |a, h| a + h.length)
         }
     }
+
+    /// Gets the name of the torrent, which is the file name or the root directory name
+    pub fn name(&self) -> &str {
+        match self {
+            FileInfo::Single(s) => &s.file_name,
+            FileInfo::Multi(m) => &m.root_dir_name,
+        }
+    }
+
+    /// Gets every file in the torrent, in the order their data appears in the pieces
+    pub fn files(&self) -> Vec<&SingleFile> {
+        match self {
+            FileInfo::Single(s) => vec![s],
+            FileInfo::Multi(m) => m.files.iter().collect(),
+        }
+    }
 }
 
 impl FromValue for InfoDict {
@@ -127,37 +223,149 @@ impl FromValue for InfoDict {
             .map(|i| *i as usize)
             .ok_or("Missing key: piece length".to_string())?;
 
-        let pieces = map.get("pieces".as_bytes()).and_then(Value::bstring)
-            .map(|bytes| bytes.chunks(20).map(|chunk| {
-                chunk.iter()
-                    .map(|byte| format!("{:02x?}", byte))
-                    .collect::<Vec<_>>()
-                    .join("")
-            }).collect::<Vec<_>>()).ok_or("Missking key: pieces".to_string())?;
+        let meta_version = map.get("meta version".as_bytes()).and_then(Value::integer)
+            .map_or(1, |i| *i as u32);
+
+        let file_tree = if meta_version >= 2 {
+            let tree = map.get("file tree".as_bytes()).ok_or("Missing key: file tree".to_string())?;
+            let mut files = Vec::new();
+            read_file_tree(tree, &mut Vec::new(), &mut files)?;
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+            Some(files)
+        } else {
+            None
+        };
+
+        // v2-only torrents have no v1 pieces
+        let pieces = match (map.get("pieces".as_bytes()), &file_tree) {
+            (None, Some(_)) => Vec::new(),
+            (pieces, _) => pieces.and_then(Value::bstring)
+                .map(|bytes| bytes.chunks(20).map(|chunk| {
+                    chunk.iter()
+                        .map(|byte| format!("{:02x?}", byte))
+                        .collect::<Vec<_>>()
+                        .join("")
+                }).collect::<Vec<_>>()).ok_or("Missking key: pieces".to_string())?,
+        };
 
         let private = map.get("private".as_bytes()).and_then(Value::integer)
             .map_or(false, |i| *i == 1);
 
-        let file_info = FileInfo::from_value(val)?;
+        let has_v1_files = map.contains_key("length".as_bytes()) || map.contains_key("files".as_bytes());
+        let file_info = match &file_tree {
+            Some(tree) if !has_v1_files => FileInfo::from_tree(map, tree)?,
+            _ => FileInfo::from_value(val)?,
+        };
+
+        let ssl_cert = map.get("ssl-cert".as_bytes()).and_then(Value::bstring)
+            .cloned();
 
         Ok(InfoDict {
             piece_length,
             pieces,
             private,
             file_info,
+            ssl_cert,
+            meta_version,
+            file_tree,
         })
     }
 }
 
+impl InfoDict {
+    /// Whether v1 peers can download the torrent
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    /// Whether v2 peers can download the torrent
+    pub fn is_v2(&self) -> bool {
+        self.file_tree.is_some()
+    }
+
+    /// Whether the torrent can be downloaded from both v1 and v2 peers
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+}
+
+/// Walk a v2 file tree, adding the files under it to `files`.  A file is a dictionary with an
+/// empty key, holding its length and pieces root
+fn read_file_tree(tree: &Value, path: &mut Vec<String>, files: &mut Vec<TreeFile>) -> Result<(), String> {
+    let map = tree.dict().ok_or("File tree not a dictionary".to_string())?;
+    for (name, node) in map {
+        if name.is_empty() {
+            let file = node.dict().ok_or("File not a dictionary".to_string())?;
+            let length = file.get("length".as_bytes()).and_then(Value::integer)
+                .map(|i| *i as usize).ok_or("Missing key: length".to_string())?;
+            let pieces_root = match file.get("pieces root".as_bytes()).and_then(Value::bstring) {
+                Some(root) if root.len() == 32 => {
+                    let mut res = [0; 32];
+                    res.copy_from_slice(root);
+                    Some(res)
+                }
+                Some(_) => return Err("Invalid pieces root".to_string()),
+                None if length > 0 => return Err("Missing key: pieces root".to_string()),
+                None => None,
+            };
+            if path.is_empty() {
+                return Err("File tree has a file without a name".to_string());
+            }
+            files.push(TreeFile {
+                path: path.clone(),
+                length,
+                pieces_root,
+            });
+        } else {
+            let name = String::from_utf8(name.clone()).map_err(|_| "Invalid path".to_string())?;
+            path.push(name);
+            read_file_tree(node, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
 impl FromValue for MetaInfo {
     type Error = String;
 
     fn from_value(val: &Value) -> Result<Self, Self::Error> where Self: Sized {
+        let info_val = val.dict().and_then(|map| map.get("info".as_bytes()))
+            .ok_or("Missing key: info".to_string())?;
+        // Only the same as the file's bytes if it was encoded canonically.  from_bytes hashes what
+        // is in the file
+        MetaInfo::parse(val, &info_val.encode())
+    }
+}
+
+impl MetaInfo {
+    /// Parse a .torrent file, hashing the info dictionary exactly as it appears in the file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let (val, spans) = Value::decode_with_spans(bytes).map_err(|e| format!("{:?}", e))?;
+        let info = spans.get("info".as_bytes()).ok_or("Missing key: info".to_string())?.range();
+        MetaInfo::parse(&val, &bytes[info])
+    }
+
+    /// Parse the metainfo, given the encoded info dictionary to hash
+    fn parse(val: &Value, encoded_info: &[u8]) -> Result<Self, String> {
         let map = val.dict().ok_or("Not a dictionary".to_string())?;
 
         let info_val = map.get("info".as_bytes()).ok_or("Missing key: info".to_string())?;
-        let info_hash = sha1_hash(&info_val.clone().encode());
         let info = InfoDict::from_value(info_val)?;
+        let info_hash_v2 = if info.is_v2() {
+            Some(sha256_hash(encoded_info))
+        } else {
+            None
+        };
+        let info_hash = match info_hash_v2 {
+            Some(hash) if !info.is_v1() => truncate_hash(&hash),
+            _ => sha1_hash(encoded_info),
+        };
+
+        let piece_layers = match map.get("piece layers".as_bytes()).and_then(Value::dict) {
+            Some(layers) => MetaInfo::interpret_piece_layers(layers)?,
+            None => HashMap::new(),
+        };
 
         let announce = map.get("announce".as_bytes()).and_then(Value::bstring_utf8).ok_or("Missing key: announce".to_string())?;
 
@@ -175,6 +383,8 @@ impl FromValue for MetaInfo {
 
         Ok(MetaInfo {
             info_hash,
+            info_hash_v2,
+            piece_layers,
             info,
             announce,
             announce_list,
@@ -184,9 +394,34 @@ impl FromValue for MetaInfo {
             encoding,
         })
     }
-}
 
-impl MetaInfo {
+    /// The other hash a hybrid torrent is known by.  v2 peers handshake with the truncated
+    /// SHA-256 hash, rather than the SHA1 hash in `info_hash`
+    pub fn alt_info_hash(&self) -> Option<[u8; 20]> {
+        match self.info_hash_v2 {
+            Some(hash) if self.info.is_hybrid() => Some(truncate_hash(&hash)),
+            _ => None,
+        }
+    }
+
+    fn interpret_piece_layers(layers: &HashMap<Vec<u8>, Value>) -> Result<HashMap<[u8; 32], Vec<[u8; 32]>>, String> {
+        let mut res = HashMap::new();
+        for (root, hashes) in layers {
+            let hashes = hashes.bstring().ok_or("Piece layer not a string".to_string())?;
+            if root.len() != 32 || hashes.len() % 32 != 0 {
+                return Err("Invalid piece layer".to_string());
+            }
+            let mut key = [0; 32];
+            key.copy_from_slice(root);
+            res.insert(key, hashes.chunks(32).map(|chunk| {
+                let mut hash = [0; 32];
+                hash.copy_from_slice(chunk);
+                hash
+            }).collect());
+        }
+        Ok(res)
+    }
+
     fn interpret_announce_list(tiers: &Vec<Value>) -> Option<Vec<(usize, String)>> {
         let mut res = Vec::new();
 
@@ -209,10 +444,25 @@ impl MetaInfo {
 }
 
 
-fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
+pub fn sha1_hash(bytes: &[u8]) -> [u8; 20] {
     let mut res = [0u8; 20];
     let mut hasher = Sha1::new();
     hasher.input(bytes);
     hasher.result(&mut res);
     res
+}
+
+fn sha256_hash(bytes: &[u8]) -> [u8; 32] {
+    let mut res = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+    hasher.result(&mut res);
+    res
+}
+
+/// v2 hashes are cut to 20 bytes wherever a v1 hash would go
+fn truncate_hash(hash: &[u8; 32]) -> [u8; 20] {
+    let mut res = [0u8; 20];
+    res.copy_from_slice(&hash[..20]);
+    res
 }
\ No newline at end of file
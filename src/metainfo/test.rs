@@ -32,6 +32,8 @@ fn test_metainfo_from_value_valid() {
 
     assert_eq!(MetaInfo::from_value(&val), Ok(MetaInfo {
         info_hash: sha1_hash(info.encode().as_ref()),
+        info_hash_v2: None,
+        piece_layers: HashMap::new(),
         info: InfoDict {
             piece_length: 20,
             pieces: vec!["00010203".to_string()],
@@ -41,6 +43,9 @@ fn test_metainfo_from_value_valid() {
                 length: 100,
                 md5sum: None,
             }),
+            ssl_cert: None,
+            meta_version: 1,
+            file_tree: None,
         },
         announce: "http://example.com".to_string(),
         announce_list: Some(vec![(0, "site1a".to_string()), (0, "site2a".to_string()), (1, "site1b".to_string()), (1, "site2b".to_string())]),
@@ -49,4 +54,145 @@ fn test_metainfo_from_value_valid() {
         created_by: None,
         encoding: None,
     }));
-}
\ No newline at end of file
+}
+#[test]
+fn test_multi_file_from_value() {
+    let info = Value::Dict(hashmap! {
+        bytes("name") => Value::BString(bytes("album")),
+        bytes("files") => Value::List(vec![
+            Value::Dict(hashmap! {
+                bytes("length") => Value::Integer(10),
+                bytes("path") => Value::List(vec![Value::BString(bytes("disc1")), Value::BString(bytes("track1.mp3"))]),
+            }),
+            Value::Dict(hashmap! {
+                bytes("length") => Value::Integer(20),
+                bytes("path") => Value::List(vec![Value::BString(bytes("cover.jpg"))]),
+            }),
+        ]),
+    });
+
+    assert_eq!(FileInfo::from_value(&info), Ok(FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![
+            SingleFile {
+                file_name: "disc1/track1.mp3".to_string(),
+                length: 10,
+                md5sum: None,
+            },
+            SingleFile {
+                file_name: "cover.jpg".to_string(),
+                length: 20,
+                md5sum: None,
+            },
+        ],
+    })));
+}
+
+/// The info dictionary of a v2 torrent holding `files`, each a path and a length
+fn v2_info(name: &str, files: &[(&[&str], i64)]) -> HashMap<Vec<u8>, Value> {
+    let mut tree = Value::Dict(HashMap::new());
+    for (path, length) in files {
+        let mut node = &mut tree;
+        for component in path.iter() {
+            node = match node {
+                Value::Dict(map) => map.entry(bytes(component)).or_insert_with(|| Value::Dict(HashMap::new())),
+                _ => unreachable!(),
+            };
+        }
+        if let Value::Dict(map) = node {
+            map.insert(Vec::new(), Value::Dict(hashmap! {
+                bytes("length") => Value::Integer(*length),
+                bytes("pieces root") => Value::BString(vec![*length as u8; 32]),
+            }));
+        }
+    }
+    hashmap! {
+        bytes("name") => Value::BString(bytes(name)),
+        bytes("piece length") => Value::Integer(16384),
+        bytes("meta version") => Value::Integer(2),
+        bytes("file tree") => tree,
+    }
+}
+
+#[test]
+fn test_v2_metainfo() {
+    let info = Value::Dict(v2_info("album", &[(&["b.mp3"], 20), (&["a", "c.mp3"], 10)]));
+    let val = Value::Dict(hashmap! {
+        bytes("announce") => Value::BString(bytes("http://example.com")),
+        bytes("info") => info.clone(),
+        bytes("piece layers") => Value::Dict(hashmap! {
+            vec![20; 32] => Value::BString(vec![7; 64]),
+        }),
+    });
+
+    let meta = MetaInfo::from_value(&val).unwrap();
+    let hash = sha256_hash(&info.encode());
+    assert_eq!(meta.info_hash_v2, Some(hash));
+    assert_eq!(&meta.info_hash[..], &hash[..20]);
+    assert_eq!(meta.alt_info_hash(), None);
+    assert!(meta.info.is_v2() && !meta.info.is_v1());
+    assert_eq!(meta.info.file_tree, Some(vec![
+        TreeFile { path: vec!["a".to_string(), "c.mp3".to_string()], length: 10, pieces_root: Some([10; 32]) },
+        TreeFile { path: vec!["b.mp3".to_string()], length: 20, pieces_root: Some([20; 32]) },
+    ]));
+    let names: Vec<_> = meta.info.file_info.files().iter().map(|file| file.file_name.clone()).collect();
+    assert_eq!(names, vec!["a/c.mp3", "b.mp3"]);
+    assert_eq!(meta.piece_layers.get(&[20; 32]), Some(&vec![[7; 32], [7; 32]]));
+}
+
+#[test]
+fn test_v2_single_file() {
+    let info = Value::Dict(v2_info("song.mp3", &[(&["song.mp3"], 100)]));
+    let info = InfoDict::from_value(&info).unwrap();
+    assert_eq!(info.file_info, FileInfo::Single(SingleFile {
+        file_name: "song.mp3".to_string(),
+        length: 100,
+        md5sum: None,
+    }));
+}
+
+#[test]
+fn test_hybrid_metainfo() {
+    let mut info = v2_info("song.mp3", &[(&["song.mp3"], 100)]);
+    info.insert(bytes("pieces"), Value::BString(vec![1; 20]));
+    info.insert(bytes("length"), Value::Integer(100));
+    let info = Value::Dict(info);
+    let val = Value::Dict(hashmap! {
+        bytes("announce") => Value::BString(bytes("http://example.com")),
+        bytes("info") => info.clone(),
+    });
+
+    let meta = MetaInfo::from_value(&val).unwrap();
+    assert!(meta.info.is_hybrid());
+    // v1 peers are the most common, so the SHA1 hash is the main one
+    assert_eq!(meta.info_hash, sha1_hash(&info.encode()));
+    assert_eq!(meta.alt_info_hash(), Some(truncate_hash(&sha256_hash(&info.encode()))));
+}
+
+#[test]
+fn test_v2_file_without_pieces_root() {
+    let mut info = v2_info("album", &[(&["a.mp3"], 10)]);
+    info.insert(bytes("file tree"), Value::Dict(hashmap! {
+        bytes("a.mp3") => Value::Dict(hashmap! {
+            Vec::new() => Value::Dict(hashmap! { bytes("length") => Value::Integer(10) }),
+        }),
+    }));
+    assert!(InfoDict::from_value(&Value::Dict(info)).is_err());
+}
+
+#[test]
+fn test_from_bytes_hashes_original_info() {
+    let bytes = b"d8:announce18:http://example.com4:infod6:lengthi100e4:name4:file12:piece lengthi20e6:pieces4:\x00\x01\x02\x03ee";
+    let meta = MetaInfo::from_bytes(bytes).unwrap();
+    assert_eq!(meta.info_hash, sha1_hash(&bytes[38..bytes.len() - 1]));
+    assert_eq!(meta, MetaInfo::from_value(&Value::decode(bytes).unwrap()).unwrap());
+}
+
+#[test]
+fn test_from_bytes_non_canonical() {
+    // Some torrent makers write integers with leading zeroes
+    let bytes = b"d8:announce18:http://example.com4:infod6:lengthi0100e4:name4:file12:piece lengthi20e6:pieces4:\x00\x01\x02\x03ee";
+    let meta = MetaInfo::from_bytes(bytes).unwrap();
+    assert_eq!(meta.info.file_info.size(), 100);
+    assert_eq!(meta.info_hash, sha1_hash(&bytes[38..bytes.len() - 1]));
+}
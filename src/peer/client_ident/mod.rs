@@ -0,0 +1,144 @@
+//! Works out which client a peer runs from its peer id.  Most clients follow one of two
+//! conventions: Azureus-style ids, `-XX1234-` then random bytes, with a two letter client code and
+//! four version characters, and Shadow-style ids, a one letter client code, up to five version
+//! characters and then dashes.
+use std::fmt;
+
+#[cfg(test)]
+mod test;
+
+/// Two letter codes of Azureus-style peer ids
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BN", "Baidu Netdisk"),
+    ("BO", "boosttorrent"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("CD", "Enhanced CTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("HL", "Halite"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("ML", "MLDonkey"),
+    ("PI", "PicoTorrent"),
+    ("QD", "QQDownload"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("TT", "TuoTu"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XF", "Xfplay"),
+    ("XL", "Xunlei"),
+];
+
+/// One letter codes of Shadow-style peer ids
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// The client a peer runs, and its version if the peer id has one
+#[derive(Debug, PartialEq, Clone)]
+pub struct ClientIdent {
+    pub name: String,
+    pub version: String,
+}
+
+impl ClientIdent {
+    /// Whether this is the client `pattern` names, ignoring case.  A pattern with a version only
+    /// matches that version and the versions under it, so "qBittorrent 4.1" matches 4.1.5 but not
+    /// 4.10
+    pub fn matches(&self, pattern: &str) -> bool {
+        let ident = self.to_string().to_lowercase();
+        let pattern = pattern.trim().to_lowercase();
+        ident == pattern
+            || ident.starts_with(&format!("{} ", pattern))
+            || ident.starts_with(&format!("{}.", pattern))
+    }
+}
+
+impl fmt::Display for ClientIdent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+/// The client behind a peer id, or None if the id follows no convention we know
+pub fn identify(peer_id: &[u8; 20]) -> Option<ClientIdent> {
+    azureus(peer_id).or_else(|| shadow(peer_id))
+}
+
+/// `-XX1234-`: a two letter client code and four version characters
+fn azureus(peer_id: &[u8; 20]) -> Option<ClientIdent> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !peer_id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = String::from_utf8_lossy(&peer_id[1..3]).into_owned();
+    let name = AZUREUS_CLIENTS.iter()
+        .find(|(known, _)| *known == code)
+        .map_or(code, |(_, name)| name.to_string());
+    let digits: Vec<u8> = peer_id[3..7].iter()
+        .take_while(|c| c.is_ascii_digit())
+        .map(|c| c - b'0')
+        .collect();
+    let version = match (&peer_id[1..3], digits.as_slice()) {
+        // Transmission puts the minor version in two digits: 294 is 2.94
+        (b"TR", [major, tens, ones, ..]) => format!("{}.{}{}", major, tens, ones),
+        _ => dotted(&digits),
+    };
+    Some(ClientIdent { name, version })
+}
+
+/// `T03I--`: a one letter client code and up to five version characters, then dashes
+fn shadow(peer_id: &[u8; 20]) -> Option<ClientIdent> {
+    let name = SHADOW_CLIENTS.iter().find(|(code, _)| *code == peer_id[0])?.1;
+    let end = 1 + peer_id[1..6].iter().take_while(|c| **c != b'-').count();
+    if end == 1 || peer_id[end..].len() < 2 || &peer_id[end..end + 2] != b"--" {
+        return None;
+    }
+    let parts = peer_id[1..end].iter()
+        .map(|c| shadow_digit(*c))
+        .collect::<Option<Vec<_>>>()?;
+    Some(ClientIdent {
+        name: name.to_string(),
+        version: parts.iter().map(u8::to_string).collect::<Vec<_>>().join("."),
+    })
+}
+
+/// The value of a Shadow-style version character
+fn shadow_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'Z' => Some(c - b'A' + 10),
+        b'a'..=b'z' => Some(c - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+/// Version digits joined with dots, without trailing zeros past the minor version
+fn dotted(digits: &[u8]) -> String {
+    let mut len = digits.len();
+    while len > 2 && digits[len - 1] == 0 {
+        len -= 1;
+    }
+    digits[..len].iter().map(u8::to_string).collect::<Vec<_>>().join(".")
+}
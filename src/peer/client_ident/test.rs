@@ -0,0 +1,61 @@
+use super::*;
+
+fn id(prefix: &[u8]) -> [u8; 20] {
+    let mut id = [b'x'; 20];
+    id[..prefix.len()].copy_from_slice(prefix);
+    id
+}
+
+fn ident(name: &str, version: &str) -> Option<ClientIdent> {
+    Some(ClientIdent {
+        name: name.to_string(),
+        version: version.to_string(),
+    })
+}
+
+#[test]
+fn test_azureus() {
+    assert_eq!(identify(&id(b"-qB4500-")), ident("qBittorrent", "4.5"));
+    assert_eq!(identify(&id(b"-qB4520-")), ident("qBittorrent", "4.5.2"));
+    assert_eq!(identify(&id(b"-UT355S-")), ident("µTorrent", "3.5.5"));
+    assert_eq!(identify(&id(b"-TR2940-")), ident("Transmission", "2.94"));
+    assert_eq!(identify(&id(b"-TR300Z-")), ident("Transmission", "3.00"));
+    assert_eq!(identify(&id(b"-LT1000-")), ident("libtorrent", "1.0"));
+    // Clients we don't know are named by their code
+    assert_eq!(identify(&id(b"-ZZ1200-")), ident("ZZ", "1.2"));
+}
+
+#[test]
+fn test_shadow() {
+    assert_eq!(identify(&id(b"T03I-----")), ident("BitTornado", "0.3.18"));
+    assert_eq!(identify(&id(b"S58B-----")), ident("Shadow's client", "5.8.11"));
+    // No dashes after the version
+    assert_eq!(identify(&id(b"T03I")), None);
+    // No version
+    assert_eq!(identify(&id(b"T-----")), None);
+}
+
+#[test]
+fn test_unknown() {
+    assert_eq!(identify(&[0; 20]), None);
+    assert_eq!(identify(&id(b"M4-3-6--")), None);
+    // The dashes of an Azureus id have to be where they belong
+    assert_eq!(identify(&id(b"-qB4500x")), None);
+}
+
+#[test]
+fn test_display() {
+    assert_eq!(identify(&id(b"-qB4500-")).unwrap().to_string(), "qBittorrent 4.5");
+    assert_eq!(identify(&id(b"-XLabcd-")).unwrap().to_string(), "Xunlei");
+}
+
+#[test]
+fn test_matches() {
+    let client = identify(&id(b"-qB4152-")).unwrap();
+    assert!(client.matches("qBittorrent"));
+    assert!(client.matches("qbittorrent 4.1"));
+    assert!(client.matches("qBittorrent 4.1.5.2"));
+    assert!(!client.matches("qBittorrent 4.10"));
+    assert!(!client.matches("qBit"));
+    assert!(!client.matches("Transmission"));
+}
@@ -0,0 +1,103 @@
+//! Marking peer traffic with a DSCP value, so routers doing QoS can put torrent traffic behind
+//! interactive use
+use std::io;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+#[cfg(test)]
+mod test;
+
+/// A differentiated services code point
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// The value of the TOS / traffic class byte for this code point.  The low two bits are used
+    /// for congestion notification, so the code point sits above them.
+    pub fn tos(self) -> u8 {
+        self.0 << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    /// Parses a code point name, like "le" or "cs1", or a number from 0 to 63
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = match s.to_lowercase().as_str() {
+            // Lower effort, RFC 8622
+            "le" => 1,
+            "cs0" | "default" => 0,
+            "cs1" => 8,
+            "af11" => 10,
+            "af12" => 12,
+            "af13" => 14,
+            "cs2" => 16,
+            "af21" => 18,
+            "af22" => 20,
+            "af23" => 22,
+            "cs3" => 24,
+            "af31" => 26,
+            "af32" => 28,
+            "af33" => 30,
+            "cs4" => 32,
+            "af41" => 34,
+            "af42" => 36,
+            "af43" => 38,
+            "cs5" => 40,
+            "ef" => 46,
+            "cs6" => 48,
+            "cs7" => 56,
+            other => other.parse::<u8>().ok()
+                .filter(|v| *v < 64)
+                .ok_or(format!("Invalid DSCP value: {}", s))?,
+        };
+        Ok(Dscp(value))
+    }
+}
+
+// Socket options for the TOS / traffic class byte.  Older versions of libc don't export these
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IP_TOS: libc::c_int = 1;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const IPV6_TCLASS: libc::c_int = 67;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IP_TOS: libc::c_int = 3;
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const IPV6_TCLASS: libc::c_int = 36;
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+const IP_TOS: libc::c_int = 3;
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+const IPV6_TCLASS: libc::c_int = 61;
+
+/// Mark all traffic sent on a connection with the code point
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+          target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd"))]
+pub fn set_dscp(conn: &TcpStream, dscp: Dscp) -> io::Result<()> {
+    use std::mem;
+    use std::os::unix::io::AsRawFd;
+
+    let (level, option) = match conn.local_addr()? {
+        std::net::SocketAddr::V4(_) => (libc::IPPROTO_IP, IP_TOS),
+        std::net::SocketAddr::V6(_) => (libc::IPPROTO_IPV6, IPV6_TCLASS),
+    };
+    let value = dscp.tos() as libc::c_int;
+    let res = unsafe {
+        libc::setsockopt(conn.as_raw_fd(),
+                         level,
+                         option,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if res == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "dragonfly", target_os = "openbsd", target_os = "netbsd")))]
+pub fn set_dscp(_conn: &TcpStream, _dscp: Dscp) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "DSCP marking is not supported on this platform"))
+}
@@ -0,0 +1,21 @@
+use super::*;
+
+#[test]
+fn test_parse_names() {
+    assert_eq!("le".parse(), Ok(Dscp(1)));
+    assert_eq!("CS1".parse(), Ok(Dscp(8)));
+    assert_eq!("ef".parse(), Ok(Dscp(46)));
+}
+
+#[test]
+fn test_parse_numbers() {
+    assert_eq!("63".parse(), Ok(Dscp(63)));
+    assert!("64".parse::<Dscp>().is_err());
+    assert!("fast".parse::<Dscp>().is_err());
+}
+
+#[test]
+fn test_tos() {
+    assert_eq!(Dscp(1).tos(), 0x04);
+    assert_eq!(Dscp(8).tos(), 0x20);
+}
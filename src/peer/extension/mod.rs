@@ -0,0 +1,368 @@
+//! extension holds the messages of the extension protocol (BEP 10) and the extensions built on
+//! it.  Each side names the extensions it supports in its extended handshake, along with the id
+//! it wants to receive that extension's messages under.
+use crate::boostencode::{
+    FromValue,
+    Value,
+};
+use crate::types::{
+    decode_compact,
+    encode_compact,
+};
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+#[cfg(test)]
+mod test;
+
+/// The extended message id of the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+/// The id peers should send us ut_metadata messages under
+pub const UT_METADATA_ID: u8 = 1;
+
+/// The id peers should send us ut_pex messages under
+pub const UT_PEX_ID: u8 = 2;
+
+/// The id peers should send us ut_holepunch messages under
+pub const UT_HOLEPUNCH_ID: u8 = 3;
+
+/// The id peers should send us lt_tex messages under
+pub const LT_TEX_ID: u8 = 4;
+
+/// Most peers to put in the added list of one ut_pex message (BEP 11)
+pub const MAX_PEX_ADDED: usize = 50;
+
+/// Most trackers to put in the added list of one lt_tex message (BEP 28)
+pub const MAX_TEX_ADDED: usize = 20;
+
+/// Longest tracker url we take from an lt_tex message
+const MAX_TEX_URL_LEN: usize = 512;
+
+/// Metadata is sent in pieces of this size.  Only the last piece may be smaller
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// The first message sent over the extension protocol
+#[derive(Debug, PartialEq, Default)]
+pub struct ExtendedHandshake {
+    // The extensions the sender supports, and the ids to send their messages under
+    pub extensions: HashMap<String, u8>,
+    // Size of the info dictionary, if the sender has it
+    pub metadata_size: Option<usize>,
+    // The sender's client name and version
+    pub client: Option<String>,
+}
+
+impl ExtendedHandshake {
+    /// Our handshake, offering `extensions`.  `metadata_size` is set if we have the info
+    /// dictionary to share
+    pub fn ours(extensions: &[&str], metadata_size: Option<usize>) -> Self {
+        ExtendedHandshake {
+            extensions: extensions.iter()
+                .filter_map(|name| {
+                    let id = match *name {
+                        "ut_metadata" => UT_METADATA_ID,
+                        "ut_pex" => UT_PEX_ID,
+                        "ut_holepunch" => UT_HOLEPUNCH_ID,
+                        "lt_tex" => LT_TEX_ID,
+                        _ => return None,
+                    };
+                    Some((name.to_string(), id))
+                })
+                .collect(),
+            metadata_size,
+            client: Some(format!("boosttorrent {}", env!("CARGO_PKG_VERSION"))),
+        }
+    }
+
+    /// The id to send an extension's messages to the peer under, if it supports it
+    pub fn id(&self, extension: &str) -> Option<u8> {
+        self.extensions.get(extension).cloned().filter(|id| *id != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let m = self.extensions.iter()
+            .map(|(name, id)| (Vec::from(name.as_bytes()), Value::Integer(*id as i64)))
+            .collect();
+        let mut map = hashmap! {
+            Vec::from("m") => Value::Dict(m),
+        };
+        if let Some(size) = self.metadata_size {
+            map.insert(Vec::from("metadata_size"), Value::Integer(size as i64));
+        }
+        if let Some(client) = &self.client {
+            map.insert(Vec::from("v"), Value::BString(Vec::from(client.as_bytes())));
+        }
+        Value::Dict(map).encode()
+    }
+}
+
+impl FromValue for ExtendedHandshake {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Extended handshake not a dictionary".to_string())?;
+
+        let extensions = map.get("m".as_bytes()).and_then(Value::dict)
+            .ok_or("Missing key: m".to_string())?
+            .iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name.clone()).ok()?;
+                let id = id.integer().filter(|id| **id >= 0 && **id <= 255)?;
+                Some((name, *id as u8))
+            })
+            .collect();
+
+        let metadata_size = map.get("metadata_size".as_bytes()).and_then(Value::integer)
+            .filter(|size| **size > 0)
+            .map(|size| *size as usize);
+
+        let client = map.get("v".as_bytes()).and_then(Value::bstring_utf8);
+
+        Ok(ExtendedHandshake {
+            extensions,
+            metadata_size,
+            client,
+        })
+    }
+}
+
+/// A ut_metadata message (BEP 9), used to download the info dictionary from peers
+#[derive(Debug, PartialEq)]
+pub enum MetadataMessage {
+    Request(u32),
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    // The peer won't send us this piece
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request(piece) => (0, *piece),
+            MetadataMessage::Data { piece, .. } => (1, *piece),
+            MetadataMessage::Reject(piece) => (2, *piece),
+        };
+        let mut map = hashmap! {
+            Vec::from("msg_type") => Value::Integer(msg_type),
+            Vec::from("piece") => Value::Integer(piece as i64),
+        };
+        if let MetadataMessage::Data { total_size, .. } = self {
+            map.insert(Vec::from("total_size"), Value::Integer(*total_size as i64));
+        }
+        let mut res = Value::Dict(map).encode();
+        // The piece's data comes after the dictionary
+        if let MetadataMessage::Data { data, .. } = self {
+            res.extend_from_slice(data);
+        }
+        res
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let (val, len) = Value::decode_prefix(bytes).map_err(|e| format!("{:?}", e))?;
+        let map = val.dict().ok_or("ut_metadata message not a dictionary".to_string())?;
+
+        let piece = map.get("piece".as_bytes()).and_then(Value::integer)
+            .filter(|piece| **piece >= 0)
+            .map(|piece| *piece as u32)
+            .ok_or("Missing key: piece".to_string())?;
+
+        match map.get("msg_type".as_bytes()).and_then(Value::integer) {
+            Some(0) => Ok(MetadataMessage::Request(piece)),
+            Some(1) => {
+                let total_size = map.get("total_size".as_bytes()).and_then(Value::integer)
+                    .filter(|size| **size > 0)
+                    .map(|size| *size as usize)
+                    .ok_or("Missing key: total_size".to_string())?;
+                Ok(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data: bytes[len..].to_vec(),
+                })
+            }
+            Some(2) => Ok(MetadataMessage::Reject(piece)),
+            _ => Err("Invalid msg_type".to_string()),
+        }
+    }
+}
+
+/// A ut_pex message (BEP 11): the peers the sender connected to and dropped since its last one
+#[derive(Debug, PartialEq, Default)]
+pub struct PexMessage {
+    pub added: Vec<SocketAddr>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (added, added6) = encode_peers(&self.added);
+        let (dropped, dropped6) = encode_peers(&self.dropped);
+        // One flags byte per added peer.  We don't know anything about them worth flagging
+        let flags = vec![0; added.len() / 6];
+        Value::Dict(hashmap! {
+            Vec::from("added") => Value::BString(added),
+            Vec::from("added.f") => Value::BString(flags),
+            Vec::from("added6") => Value::BString(added6),
+            Vec::from("dropped") => Value::BString(dropped),
+            Vec::from("dropped6") => Value::BString(dropped6),
+        }).encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("{:?}", e))?;
+        let map = val.dict().ok_or("ut_pex message not a dictionary".to_string())?;
+        let peers = |v4: &str, v6: &str| {
+            let mut res = map.get(v4.as_bytes()).and_then(Value::bstring)
+                .map_or(Vec::new(), |bytes| decode_compact(bytes, 4));
+            res.extend(map.get(v6.as_bytes()).and_then(Value::bstring)
+                .map_or(Vec::new(), |bytes| decode_compact(bytes, 16)));
+            res
+        };
+        Ok(PexMessage {
+            added: peers("added", "added6"),
+            dropped: peers("dropped", "dropped6"),
+        })
+    }
+}
+
+/// An lt_tex message (BEP 28): trackers the sender uses that it hasn't told us about yet
+#[derive(Debug, PartialEq, Default)]
+pub struct TexMessage {
+    pub added: Vec<String>,
+}
+
+impl TexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let added = self.added.iter()
+            .map(|url| Value::BString(Vec::from(url.as_bytes())))
+            .collect();
+        Value::Dict(hashmap! {
+            Vec::from("added") => Value::List(added),
+        }).encode()
+    }
+
+    /// Decode a message, keeping only the http, https and udp tracker urls in it
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let val = Value::decode(bytes).map_err(|e| format!("{:?}", e))?;
+        let map = val.dict().ok_or("lt_tex message not a dictionary".to_string())?;
+        let added = map.get("added".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: added".to_string())?
+            .iter()
+            .filter_map(Value::bstring_utf8)
+            .filter(|url| url.len() <= MAX_TEX_URL_LEN && is_tracker_url(url))
+            .take(MAX_TEX_ADDED)
+            .collect();
+        Ok(TexMessage { added })
+    }
+}
+
+/// Whether `url` is a tracker we know how to announce to
+fn is_tracker_url(url: &str) -> bool {
+    ["http://", "https://", "udp://"].iter()
+        .any(|scheme| url.starts_with(scheme) && url.len() > scheme.len())
+}
+
+/// Why a relay couldn't pass on a ut_holepunch rendezvous
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HolepunchError {
+    /// The relay isn't connected to the target
+    NoSuchPeer,
+    /// The relay was connected to the target, but the connection is closing
+    NotConnected,
+    /// The target doesn't support ut_holepunch
+    NoSupport,
+    /// The target is the peer that asked
+    NoSelf,
+}
+
+impl HolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            HolepunchError::NoSuchPeer => 1,
+            HolepunchError::NotConnected => 2,
+            HolepunchError::NoSupport => 3,
+            HolepunchError::NoSelf => 4,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(HolepunchError::NoSuchPeer),
+            2 => Some(HolepunchError::NotConnected),
+            3 => Some(HolepunchError::NoSupport),
+            4 => Some(HolepunchError::NoSelf),
+            _ => None,
+        }
+    }
+}
+
+/// A ut_holepunch message (BEP 55), which lets two peers behind NATs connect to each other with
+/// the help of a relay peer connected to both.  The relay tells each of them to connect to the
+/// other at the same time, so each one's outgoing attempt opens its NAT to the other's
+#[derive(Debug, PartialEq, Clone)]
+pub enum HolepunchMessage {
+    /// Sent to the relay, asking it to put us in touch with a peer
+    Rendezvous(SocketAddr),
+    /// Sent by the relay, telling us to connect to a peer
+    Connect(SocketAddr),
+    /// Sent by the relay when it can't put us in touch with a peer
+    Error(SocketAddr, HolepunchError),
+}
+
+impl HolepunchMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, address, err_code) = match self {
+            HolepunchMessage::Rendezvous(address) => (0, address, 0),
+            HolepunchMessage::Connect(address) => (1, address, 0),
+            HolepunchMessage::Error(address, error) => (2, address, error.code()),
+        };
+        let addr_type = match address {
+            SocketAddr::V4(_) => 0,
+            SocketAddr::V6(_) => 1,
+        };
+        let mut res = vec![msg_type, addr_type];
+        res.extend(encode_compact(*address));
+        res.extend_from_slice(&err_code.to_be_bytes());
+        res
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let ip_len = match bytes.get(1) {
+            Some(0) => 4,
+            Some(1) => 16,
+            _ => return Err("Invalid addr_type".to_string()),
+        };
+        if bytes.len() != 2 + ip_len + 2 + 4 {
+            return Err("ut_holepunch message has the wrong length".to_string());
+        }
+        let address = decode_compact(&bytes[2..2 + ip_len + 2], ip_len)[0];
+        let mut err_code = [0; 4];
+        err_code.copy_from_slice(&bytes[2 + ip_len + 2..]);
+        match bytes[0] {
+            0 => Ok(HolepunchMessage::Rendezvous(address)),
+            1 => Ok(HolepunchMessage::Connect(address)),
+            2 => HolepunchError::from_code(u32::from_be_bytes(err_code))
+                .map(|error| HolepunchMessage::Error(address, error))
+                .ok_or("Invalid err_code".to_string()),
+            _ => Err("Invalid msg_type".to_string()),
+        }
+    }
+}
+
+/// Encode addresses in the compact format, as (IPv4 peers, IPv6 peers)
+fn encode_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for peer in peers {
+        match peer {
+            SocketAddr::V4(_) => v4.extend(encode_compact(*peer)),
+            SocketAddr::V6(_) => v6.extend(encode_compact(*peer)),
+        }
+    }
+    (v4, v6)
+}
@@ -0,0 +1,96 @@
+use super::*;
+
+#[test]
+fn test_handshake_round_trip() {
+    let ours = ExtendedHandshake::ours(&["ut_metadata", "ut_pex", "unknown"], Some(1234));
+    assert_eq!(ours.extensions.len(), 2);
+    let decoded = ExtendedHandshake::from_value(&Value::decode(&ours.encode()).unwrap()).unwrap();
+    assert_eq!(decoded, ours);
+    assert_eq!(decoded.id("ut_metadata"), Some(UT_METADATA_ID));
+    assert_eq!(decoded.id("ut_pex"), Some(UT_PEX_ID));
+    assert_eq!(decoded.id("lt_donthave"), None);
+}
+
+#[test]
+fn test_handshake_disabled_extension() {
+    // An id of 0 means the peer turned the extension off
+    let val = Value::decode(b"d1:md11:ut_metadatai0eee").unwrap();
+    let handshake = ExtendedHandshake::from_value(&val).unwrap();
+    assert_eq!(handshake.id("ut_metadata"), None);
+    assert_eq!(handshake.metadata_size, None);
+}
+
+#[test]
+fn test_metadata_messages() {
+    assert_eq!(MetadataMessage::Request(3).encode(), b"d8:msg_typei0e5:piecei3ee".to_vec());
+    assert_eq!(MetadataMessage::decode(b"d8:msg_typei2e5:piecei1ee"), Ok(MetadataMessage::Reject(1)));
+
+    let data = MetadataMessage::Data {
+        piece: 0,
+        total_size: 5,
+        data: b"d1:ae".to_vec(),
+    };
+    let encoded = data.encode();
+    assert_eq!(encoded, b"d8:msg_typei1e5:piecei0e10:total_sizei5eed1:ae".to_vec());
+    assert_eq!(MetadataMessage::decode(&encoded), Ok(data));
+    assert!(MetadataMessage::decode(b"d8:msg_typei7e5:piecei0ee").is_err());
+}
+
+#[test]
+fn test_pex_round_trip() {
+    let pex = PexMessage {
+        added: vec!["1.2.3.4:6881".parse().unwrap(), "[::1]:80".parse().unwrap()],
+        dropped: vec!["5.6.7.8:1".parse().unwrap()],
+    };
+    let encoded = pex.encode();
+    let val = Value::decode(&encoded).unwrap();
+    let map = val.dict().unwrap();
+    assert_eq!(map.get("added".as_bytes()), Some(&Value::BString(vec![1, 2, 3, 4, 0x1a, 0xe1])));
+    assert_eq!(map.get("added.f".as_bytes()), Some(&Value::BString(vec![0])));
+    assert_eq!(PexMessage::decode(&encoded), Ok(pex));
+}
+
+#[test]
+fn test_pex_ignores_partial_entries() {
+    let pex = PexMessage::decode(b"d5:added8:\x01\x02\x03\x04\x00\x50\x09\x09e").unwrap();
+    assert_eq!(pex.added, vec!["1.2.3.4:80".parse().unwrap()]);
+    assert!(pex.dropped.is_empty());
+}
+
+#[test]
+fn test_holepunch_round_trip() {
+    let v4 = HolepunchMessage::Connect("1.2.3.4:6881".parse().unwrap());
+    assert_eq!(v4.encode(), b"\x01\x00\x01\x02\x03\x04\x1a\xe1\x00\x00\x00\x00");
+    assert_eq!(HolepunchMessage::decode(&v4.encode()), Ok(v4));
+    let v6 = HolepunchMessage::Error("[2001:db8::1]:51413".parse().unwrap(), HolepunchError::NoSupport);
+    assert_eq!(v6.encode().len(), 24);
+    assert_eq!(HolepunchMessage::decode(&v6.encode()), Ok(v6));
+
+    let mut bad = HolepunchMessage::Rendezvous("1.2.3.4:6881".parse().unwrap()).encode();
+    bad.pop();
+    assert!(HolepunchMessage::decode(&bad).is_err());
+    bad.push(9);
+    bad[0] = 2;
+    assert!(HolepunchMessage::decode(&bad).is_err());
+}
+
+#[test]
+fn test_tex_round_trip() {
+    let tex = TexMessage {
+        added: vec!["http://tracker.example/announce".to_string(), "udp://tracker.example:6969".to_string()],
+    };
+    let encoded = tex.encode();
+    assert_eq!(encoded, b"d5:addedl31:http://tracker.example/announce26:udp://tracker.example:6969ee".to_vec());
+    assert_eq!(TexMessage::decode(&encoded), Ok(tex));
+    assert!(TexMessage::decode(b"d5:added3:abce").is_err());
+}
+
+#[test]
+fn test_tex_ignores_unusable_urls() {
+    let tex = TexMessage::decode(b"d5:addedl9:ws://a/b/7:http://i42e2:\xff\xfe9:https://xee").unwrap();
+    assert_eq!(tex.added, vec!["https://x".to_string()]);
+
+    let urls: Vec<_> = (0..MAX_TEX_ADDED + 5).map(|i| format!("http://t{}/announce", i)).collect();
+    let many = TexMessage { added: urls };
+    assert_eq!(TexMessage::decode(&many.encode()).unwrap().added.len(), MAX_TEX_ADDED);
+}
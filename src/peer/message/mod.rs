@@ -0,0 +1,388 @@
+use byteorder::{ByteOrder, NetworkEndian};
+use crate::bufpool::BufferPool;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+#[cfg(test)]
+mod test;
+
+const HANDSHAKE_LENGTH: usize = 1 + 19 + 8 + 20 + 20;
+
+/// Where in the handshake's reserved bytes support for the extension protocol is flagged
+const EXTENDED_BYTE: usize = 5;
+const EXTENDED_BIT: u8 = 0x10;
+
+/// Where support for the fast extension (BEP 6) and the DHT (BEP 5) are flagged
+const FAST_BYTE: usize = 7;
+const FAST_BIT: u8 = 0x04;
+const DHT_BYTE: usize = 7;
+const DHT_BIT: u8 = 0x01;
+
+/// The largest message we will accept.  Blocks are 16KiB, so this is only reached by the
+/// bitfields of enormous torrents
+const MAX_MESSAGE_LENGTH: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Request {
+    /// Whether the block is inside a piece of `piece_size` bytes, and isn't empty
+    pub fn fits(&self, piece_size: u64) -> bool {
+        self.length > 0 && self.begin as u64 + self.length as u64 <= piece_size
+    }
+}
+
+impl From<(u32, u32, u32)> for Request {
+    fn from((index, begin, length): (u32, u32, u32)) -> Self {
+        Request { index, begin, length }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Piece {
+    pub index: u32,
+    pub begin: u32,
+    pub block: Bytes,
+}
+
+impl Piece {
+    pub fn new(index: u32, begin: u32, block: Bytes) -> Piece {
+        Piece { index, begin, block }
+    }
+
+    /// Whether this block is the one asked for by `request`
+    pub fn answers(&self, request: &Request) -> bool {
+        self.index == request.index
+            && self.begin == request.begin
+            && self.block.len() == request.length as usize
+    }
+}
+
+/// The reserved bytes of a handshake, where clients flag the extensions they support
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Reserved(pub [u8; 8]);
+
+impl Reserved {
+    /// What we support: the extension protocol and the fast extension
+    pub fn ours() -> Self {
+        let mut reserved = [0u8; 8];
+        reserved[EXTENDED_BYTE] |= EXTENDED_BIT;
+        reserved[FAST_BYTE] |= FAST_BIT;
+        Reserved(reserved)
+    }
+
+    /// Whether the extension protocol (BEP 10) is supported
+    pub fn extended(&self) -> bool {
+        self.0[EXTENDED_BYTE] & EXTENDED_BIT != 0
+    }
+
+    /// Whether the fast extension (BEP 6) is supported
+    pub fn fast(&self) -> bool {
+        self.0[FAST_BYTE] & FAST_BIT != 0
+    }
+
+    /// Whether the peer runs a DHT node, and will send its port
+    pub fn dht(&self) -> bool {
+        self.0[DHT_BYTE] & DHT_BIT != 0
+    }
+
+    /// The same bits with DHT support flagged, for when we run a DHT node
+    pub fn with_dht(mut self) -> Self {
+        self.0[DHT_BYTE] |= DHT_BIT;
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Handshake {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub reserved: Reserved,
+}
+
+/// Our handshake
+impl From<([u8; 20], [u8; 20])> for Handshake {
+    fn from(pair: ([u8; 20], [u8; 20])) -> Self {
+        Handshake {
+            info_hash: pair.0,
+            peer_id: pair.1,
+            reserved: Reserved::ours(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Handshake(Handshake),
+    /// Sent when there is nothing else to say, so the connection isn't dropped
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(bit_vec::BitVec),
+    Request(Request),
+    Piece(Piece),
+    Cancel(Request),
+    /// The port our DHT node listens on
+    Port(u16),
+    /// The fast extension (BEP 6) adds these.  A piece that is quick for the sender to upload
+    SuggestPiece(u32),
+    /// Stand-ins for a bitfield with every piece or none
+    HaveAll,
+    HaveNone,
+    /// A request that won't be answered
+    Reject(Request),
+    /// A piece that may be requested even while choked
+    AllowedFast(u32),
+    /// A message of the extension protocol (BEP 10), with its extended message id
+    Extended(u8, Bytes),
+    /// A message that has already been encoded, so the same bytes can be sent to many peers
+    Encoded(Bytes),
+}
+
+impl Message {
+    /// Everything but piece data is a small control message, which should never have to wait
+    /// behind piece data to go out
+    pub fn is_control(&self) -> bool {
+        !matches!(self, Message::Piece(_))
+    }
+}
+
+pub struct MessageCodec {
+    // Where blocks go back to once they are encoded
+    pool: Option<BufferPool>,
+}
+
+
+impl MessageCodec {
+    pub fn new() -> MessageCodec {
+        MessageCodec { pool: None }
+    }
+
+    /// A codec that gives the blocks it sends back to `pool`
+    pub fn with_pool(pool: BufferPool) -> MessageCodec {
+        MessageCodec { pool: Some(pool) }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        // No message can be this long, so a leading 19 means the handshake
+        if !src.is_empty() && src[0] == 19 {
+            if src.len() < HANDSHAKE_LENGTH {
+                return Ok(None);
+            }
+            let mut buf = src.split_to(HANDSHAKE_LENGTH);
+            buf.advance(1);
+
+            let mut name: [u8; 19] = [0; 19];
+            buf.copy_to_slice(&mut name);
+            if name != b"BitTorrent protocol".as_ref() {
+                return Err(error("invalid protocol name"));
+            }
+
+            let mut reserved: [u8; 8] = [0; 8];
+            buf.copy_to_slice(&mut reserved);
+
+            let mut info_hash: [u8; 20] = [0; 20];
+            buf.copy_to_slice(&mut info_hash);
+
+            let mut peer_id: [u8; 20] = [0; 20];
+            buf.copy_to_slice(&mut peer_id);
+
+            return Ok(Some(Message::Handshake(Handshake {
+                info_hash,
+                peer_id,
+                reserved: Reserved(reserved),
+            })));
+        }
+
+        loop {
+            if src.len() < 4 {
+                return Ok(None);
+            }
+            let length = NetworkEndian::read_u32(&src[..4]) as usize;
+            if length > MAX_MESSAGE_LENGTH {
+                return Err(error("message too long"));
+            }
+            if src.len() < 4 + length {
+                src.reserve(4 + length - src.len());
+                return Ok(None);
+            }
+            src.advance(4);
+            if length == 0 {
+                return Ok(Some(Message::KeepAlive));
+            }
+            // Payloads are slices of the frame rather than copies of it
+            let frame = src.split_to(length).freeze();
+            let mut buf = frame.clone();
+            let type_id = buf.get_u8();
+            let payload_length = length - 1;
+
+            let expected_length = match type_id {
+                0..=3 | 14 | 15 => Some(0),
+                4 | 13 | 17 => Some(4),
+                6 | 8 | 16 => Some(12),
+                9 => Some(2),
+                7 if payload_length < 8 => return Err(error("piece message too short")),
+                20 if payload_length < 1 => return Err(error("extended message too short")),
+                _ => None,
+            };
+            if expected_length.is_some_and(|expected| expected != payload_length) {
+                return Err(error("message has the wrong length"));
+            }
+
+            let message = match type_id {
+                0 => Message::Choke,
+                1 => Message::Unchoke,
+                2 => Message::Interested,
+                3 => Message::NotInterested,
+                4 => Message::Have(buf.get_u32()),
+                5 => Message::Bitfield(bit_vec::BitVec::from_bytes(buf.chunk())),
+                6 => {
+                    let index = buf.get_u32();
+                    let begin = buf.get_u32();
+                    let length = buf.get_u32();
+                    Message::Request((index, begin, length).into())
+                }
+                7 => {
+                    let index = buf.get_u32();
+                    let begin = buf.get_u32();
+                    Message::Piece(Piece::new(index, begin, frame.slice(9..)))
+                }
+                8 => {
+                    let index = buf.get_u32();
+                    let begin = buf.get_u32();
+                    let length = buf.get_u32();
+                    Message::Cancel((index, begin, length).into())
+                }
+                9 => Message::Port(buf.get_u16()),
+                13 => Message::SuggestPiece(buf.get_u32()),
+                14 => Message::HaveAll,
+                15 => Message::HaveNone,
+                16 => {
+                    let index = buf.get_u32();
+                    let begin = buf.get_u32();
+                    let length = buf.get_u32();
+                    Message::Reject((index, begin, length).into())
+                }
+                17 => Message::AllowedFast(buf.get_u32()),
+                20 => {
+                    let id = buf.get_u8();
+                    Message::Extended(id, frame.slice(2..))
+                }
+                // Messages of extensions we don't speak are skipped, so the next one can be read
+                _ => continue,
+            };
+            return Ok(Some(message));
+        }
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Message::Handshake(item) => {
+                dst.reserve(HANDSHAKE_LENGTH);
+
+                dst.put_u8(19);
+                dst.put_slice(b"BitTorrent protocol".as_ref());
+                dst.put_slice(item.reserved.0.as_ref());
+                dst.put_slice(item.info_hash.as_ref());
+                dst.put_slice(item.peer_id.as_ref());
+            },
+            Message::KeepAlive => {
+                dst.reserve(4);
+                dst.put_u32(0);
+            }
+            Message::Choke => length_and_id(dst, 1, 0),
+            Message::Unchoke => length_and_id(dst, 1, 1),
+            Message::Interested => length_and_id(dst, 1, 2),
+            Message::NotInterested => length_and_id(dst, 1, 3),
+            Message::Have(piece_index) => {
+                length_and_id(dst, 5, 4);
+                dst.put_u32(piece_index);
+            }
+            Message::Bitfield(bit_vec) => {
+                let bytes = bit_vec.to_bytes();
+                length_and_id(dst, 1 + bytes.len() as u32, 5);
+                dst.put_slice(&bytes);
+            }
+            Message::Request(request) => {
+                length_and_id(dst, 13, 6);
+                dst.put_u32(request.index);
+                dst.put_u32(request.begin);
+                dst.put_u32(request.length);
+            }
+            Message::Piece(piece) => {
+                length_and_id(dst, 9 + piece.block.len() as u32, 7);
+                dst.put_u32(piece.index);
+                dst.put_u32(piece.begin);
+                dst.put_slice(&piece.block);
+                if let Some(pool) = &self.pool {
+                    pool.put(piece.block);
+                }
+            }
+            Message::Cancel(request) => {
+                length_and_id(dst, 13, 8);
+                dst.put_u32(request.index);
+                dst.put_u32(request.begin);
+                dst.put_u32(request.length);
+            }
+            Message::Port(port) => {
+                length_and_id(dst, 3, 9);
+                dst.put_u16(port);
+            }
+            Message::SuggestPiece(index) => {
+                length_and_id(dst, 5, 13);
+                dst.put_u32(index);
+            }
+            Message::HaveAll => length_and_id(dst, 1, 14),
+            Message::HaveNone => length_and_id(dst, 1, 15),
+            Message::AllowedFast(index) => {
+                length_and_id(dst, 5, 17);
+                dst.put_u32(index);
+            }
+            Message::Reject(request) => {
+                length_and_id(dst, 13, 16);
+                dst.put_u32(request.index);
+                dst.put_u32(request.begin);
+                dst.put_u32(request.length);
+            }
+            Message::Extended(id, payload) => {
+                length_and_id(dst, 2 + payload.len() as u32, 20);
+                dst.put_u8(id);
+                dst.put_slice(&payload);
+            }
+            Message::Encoded(bytes) => dst.extend_from_slice(&bytes),
+        }
+        Ok(())
+    }
+}
+
+fn length_and_id(dst: &mut BytesMut, length: u32, id: u8) {
+    dst.reserve((length + 4) as usize);
+    dst.put_u32(length);
+    dst.put_u8(id);
+}
+
+fn error(msg: &str) -> io::Error {
+    io::Error::other(msg)
+}
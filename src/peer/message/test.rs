@@ -0,0 +1,135 @@
+use bit_vec::BitVec;
+use super::*;
+
+fn encode(message: Message) -> Vec<u8> {
+    let mut dst = BytesMut::new();
+    MessageCodec::new().encode(message, &mut dst).unwrap();
+    dst.to_vec()
+}
+
+fn decode(bytes: &[u8]) -> Option<Message> {
+    MessageCodec::new().decode(&mut BytesMut::from(bytes)).unwrap()
+}
+
+#[test]
+fn test_round_trip() {
+    let cases: Vec<(Message, &[u8])> = vec![
+        (Message::KeepAlive, b"\x00\x00\x00\x00"),
+        (Message::Choke, b"\x00\x00\x00\x01\x00"),
+        (Message::NotInterested, b"\x00\x00\x00\x01\x03"),
+        (Message::Have(0x1234), b"\x00\x00\x00\x05\x04\x00\x00\x12\x34"),
+        (Message::Bitfield(BitVec::from_bytes(&[0b1010_0000, 0b0000_0001])), b"\x00\x00\x00\x03\x05\xa0\x01"),
+        (Message::Request((1, 0x4000, 0x4000).into()),
+         b"\x00\x00\x00\x0d\x06\x00\x00\x00\x01\x00\x00\x40\x00\x00\x00\x40\x00"),
+        (Message::Piece(Piece::new(2, 0, Bytes::from_static(b"abc"))),
+         b"\x00\x00\x00\x0c\x07\x00\x00\x00\x02\x00\x00\x00\x00abc"),
+        (Message::Cancel((1, 0x4000, 0x4000).into()),
+         b"\x00\x00\x00\x0d\x08\x00\x00\x00\x01\x00\x00\x40\x00\x00\x00\x40\x00"),
+        (Message::Port(6881), b"\x00\x00\x00\x03\x09\x1a\xe1"),
+        (Message::Reject((1, 0x4000, 0x4000).into()),
+         b"\x00\x00\x00\x0d\x10\x00\x00\x00\x01\x00\x00\x40\x00\x00\x00\x40\x00"),
+        (Message::SuggestPiece(3), b"\x00\x00\x00\x05\x0d\x00\x00\x00\x03"),
+        (Message::HaveAll, b"\x00\x00\x00\x01\x0e"),
+        (Message::HaveNone, b"\x00\x00\x00\x01\x0f"),
+        (Message::AllowedFast(3), b"\x00\x00\x00\x05\x11\x00\x00\x00\x03"),
+        (Message::Extended(1, Bytes::from_static(b"de")), b"\x00\x00\x00\x04\x14\x01de"),
+    ];
+    for (message, bytes) in cases {
+        assert_eq!(decode(bytes).as_ref(), Some(&message));
+        assert_eq!(encode(message), bytes.to_vec());
+    }
+}
+
+#[test]
+fn test_handshake() {
+    let mut bytes = b"\x13BitTorrent protocol".to_vec();
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+    bytes.extend_from_slice(&[1; 20]);
+    bytes.extend_from_slice(&[2; 20]);
+    assert_eq!(encode(Message::Handshake(([1; 20], [2; 20]).into())), bytes);
+    assert_eq!(decode(&bytes), Some(Message::Handshake(([1; 20], [2; 20]).into())));
+    assert_eq!(decode(&bytes[..40]), None);
+
+    // A peer without the extension protocol or the fast extension
+    bytes[25] = 0;
+    bytes[27] = 0;
+    assert_eq!(decode(&bytes), Some(Message::Handshake(Handshake {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        reserved: Reserved([0; 8]),
+    })));
+}
+
+#[test]
+fn test_reserved() {
+    let ours = Reserved::ours();
+    assert!(ours.extended());
+    assert!(ours.fast());
+    assert!(!ours.dht());
+
+    let reserved = Reserved([0, 0, 0, 0, 0, 0, 0, 0x05]);
+    assert!(!reserved.extended());
+    assert!(reserved.fast());
+    assert!(reserved.dht());
+    assert!(!Reserved::default().dht());
+    assert!(ours.with_dht().dht());
+    assert!(ours.with_dht().extended());
+
+    // Bits we don't know are kept as the peer sent them
+    let mut bytes = b"\x13BitTorrent protocol".to_vec();
+    bytes.extend_from_slice(&[0x80, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    bytes.extend_from_slice(&[1; 20]);
+    bytes.extend_from_slice(&[2; 20]);
+    let handshake = Handshake {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        reserved: Reserved([0x80, 0, 0, 0, 0, 0x10, 0, 0x05]),
+    };
+    assert_eq!(decode(&bytes), Some(Message::Handshake(handshake)));
+}
+
+#[test]
+fn test_request_fits() {
+    assert!(Request::from((0, 0, 0x4000)).fits(0x8000));
+    assert!(Request::from((0, 0x4000, 0x4000)).fits(0x8000));
+    assert!(!Request::from((0, 0x4000, 0x4001)).fits(0x8000));
+    assert!(!Request::from((0, 0x8000, 1)).fits(0x8000));
+    assert!(!Request::from((0, 0, 0)).fits(0x8000));
+    // Offsets near the end of u32 can't wrap around
+    assert!(!Request::from((0, u32::MAX, 2)).fits(0x8000));
+}
+
+#[test]
+fn test_partial_frames() {
+    let mut codec = MessageCodec::new();
+    let mut src = BytesMut::from(&b"\x00\x00"[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    src.extend_from_slice(b"\x00\x05\x04\x00\x00");
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    // Two messages arriving together come out one at a time
+    src.extend_from_slice(b"\x00\x07\x00\x00\x00\x01\x01");
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Have(7)));
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+    assert!(src.is_empty());
+}
+
+#[test]
+fn test_invalid_messages() {
+    let mut codec = MessageCodec::new();
+    // A have without its index
+    assert!(codec.decode(&mut BytesMut::from(&b"\x00\x00\x00\x02\x04\x00"[..])).is_err());
+    // A HaveAll with a payload
+    assert!(codec.decode(&mut BytesMut::from(&b"\x00\x00\x00\x02\x0e\x00"[..])).is_err());
+    assert!(codec.decode(&mut BytesMut::from(&b"\x7f\x00\x00\x00"[..])).is_err());
+}
+
+#[test]
+fn test_unknown_messages_are_skipped() {
+    let mut codec = MessageCodec::new();
+    let mut src = BytesMut::from(&b"\x00\x00\x00\x01\x63\x00\x00\x00\x03\x15ab\x00\x00\x00\x01\x01"[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), Some(Message::Unchoke));
+    assert!(src.is_empty());
+    let mut src = BytesMut::from(&b"\x00\x00\x00\x01\x63"[..]);
+    assert_eq!(codec.decode(&mut src).unwrap(), None);
+    assert!(src.is_empty());
+}
@@ -1,108 +1,1187 @@
+use crate::boostencode::{
+    FromValue,
+    Value,
+};
+use crate::bufpool::BufferPool;
 use crate::piece::Piece;
-use futures::sync::mpsc::{
-    Receiver,
-    Sender,
+use crate::piecefield::PieceField;
+use crate::storage::reader::ReadRequest;
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use futures::{
+    channel::{
+        mpsc::{
+            channel,
+            Receiver,
+            Sender,
+        },
+        oneshot,
+    },
+    FutureExt,
+    SinkExt,
+    StreamExt,
 };
 use tokio::{
-    net::TcpStream,
-    prelude::{
-        Async,
-        Future,
-        Stream,
-        Sink,
-        AsyncSink,
+    io::{
+        AsyncRead,
+        AsyncWrite,
+    },
+    time::{
+        self,
+        Interval,
+        Sleep,
     },
-    codec::Framed,
 };
-use bit_vec::BitVec;
-use log::error;
+use tokio_util::codec::{
+    Encoder,
+    Framed,
+};
+use log::{
+    debug,
+    error,
+};
+use self::extension::{
+    ExtendedHandshake,
+    HolepunchMessage,
+    PexMessage,
+    TexMessage,
+};
+use self::pipeline::{
+    Pipeline,
+    SNUB_TIMEOUT,
+};
+use std::collections::{
+    HashSet,
+    VecDeque,
+};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+pub mod client_ident;
+pub mod dscp;
+pub mod extension;
+pub mod message;
+pub mod mse;
+mod pipeline;
+pub mod priority;
+
+/// Send a keep-alive after this long without sending anything, so the peer doesn't drop us
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(100);
+
+/// Drop the connection after this long without hearing from the peer
+const IDLE_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// How often to check whether a keep-alive is due or the peer went quiet
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Most block requests from a peer we work on at once.  Requests past this are dropped, and the
+/// peer has to ask again
+const MAX_PEER_REQUESTS: usize = 64;
 
-mod message;
+/// Largest block a peer can ask for.  Clients drop connections that ask for more than this
+const MAX_BLOCK_LENGTH: u32 = 1 << 17;
+
+/// How many requests for blocks that can't exist a peer may send before we drop it
+const MAX_BAD_REQUESTS: u32 = 10;
+
+/// Anything we can talk to a peer over, such as a TCP connection or an SSL stream
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+/// Sent to every peer when we finish a piece.  The Have message is only encoded once, and the
+/// encoded bytes are shared between all of the peers.
+#[derive(Clone)]
+pub struct HaveBroadcast {
+    index: u32,
+    frame: Bytes,
+}
+
+impl HaveBroadcast {
+    pub fn new(index: u32) -> Self {
+        let mut frame = BytesMut::new();
+        // Encoding a Have into a BytesMut can't fail
+        let _res = message::MessageCodec::new().encode(message::Message::Have(index), &mut frame);
+        HaveBroadcast {
+            index,
+            frame: frame.freeze(),
+        }
+    }
+}
+
+/// Every peer the server is connected to, sent out periodically so peers can tell each other
+/// about them with ut_pex
+pub type PexSnapshot = Arc<Vec<SocketAddr>>;
+
+/// The trackers the server is willing to share, sent out periodically for lt_tex
+pub type TexSnapshot = Arc<Vec<String>>;
+
+/// What a peer tells the server about the pieces it downloads and the peers it hears about
+pub enum PeerEvent {
+    /// The peer wants a piece to download.  `finished` is the piece it just completed, if any.
+    /// The new piece is sent back through `reply`, and dropping `reply` means there is nothing
+    /// this peer can help with
+    WantPiece {
+        peer: usize,
+        finished: Option<Piece>,
+        pieces: PieceField,
+        reply: Sender<Piece>,
+    },
+    /// The connection closed, so the piece it was given has to go to someone else.  Always the
+    /// last event from a peer
+    Gone {
+        peer: usize,
+        pieces: PieceField,
+    },
+    /// The peer has sent nothing for a long time while we waited on it.  `piece` is the piece it
+    /// was downloading, handed back with the blocks it did send so another peer can finish it.
+    /// New work is sent through `reply`, like for WantPiece
+    Snubbed {
+        peer: usize,
+        piece: Option<Piece>,
+        reply: Sender<Piece>,
+    },
+    /// A snubbed peer sent us a block again
+    Unsnubbed(usize),
+    /// The peer said in its extended handshake that it supports ut_holepunch
+    SupportsHolepunch(usize),
+    /// The peer sent a ut_holepunch message, either asking us to relay or relaying for someone
+    Holepunch {
+        peer: usize,
+        message: HolepunchMessage,
+    },
+    /// The peer told us about other peers in the swarm
+    Discovered {
+        peer: usize,
+        peers: Vec<SocketAddr>,
+    },
+    /// The peer told us about trackers for the torrent
+    Trackers {
+        peer: usize,
+        urls: Vec<String>,
+    },
+    /// The peer runs a DHT node at this address
+    DhtNode {
+        peer: usize,
+        node: SocketAddr,
+    },
+    /// The connection we opened reached ourselves, so its address should never be dialed again
+    Ourselves(usize),
+    /// The peer's handshake arrived, with the peer id it goes by
+    Handshake {
+        peer: usize,
+        peer_id: [u8; 20],
+    },
+    /// The peer sent a block we had no use for, because we cancelled it or already had it
+    Wasted {
+        peer: usize,
+        bytes: u32,
+    },
+    /// The peer started or stopped wanting data from us
+    Interest {
+        peer: usize,
+        interested: bool,
+    },
+    /// What the peer has, sent when it changes while super-seeding so the server knows when to
+    /// offer the peer another piece
+    Has {
+        peer: usize,
+        pieces: PieceField,
+    },
+}
+
+/// The target a peer logs as: `peer::<address>`, or `peer::<torrent>#<id>` for a peer without an
+/// address, like one reached over I2P
+pub fn log_target(info_hash: &[u8; 20], id: usize, address: Option<SocketAddr>) -> String {
+    match address {
+        Some(address) => format!("peer::{}", address),
+        None => format!("peer::{}#{}", crate::session::hex(&info_hash[..4]), id),
+    }
+}
+
+/// Everything a peer connection gets from the server: the channels it talks to the server
+/// through, and what it needs to know about the torrent
+pub struct PeerConfig {
+    // Bytes moved over the connection are counted through these
+    pub uploaded_sender: Sender<u32>,
+    pub downloaded_sender: Sender<u32>,
+    // Identifies this peer to the server
+    pub id: usize,
+    pub event_sender: Sender<PeerEvent>,
+    // Takes what the peer had when the connection closes.  The server hears about it after every
+    // other event, even if event_sender's channel is full
+    pub gone_sender: oneshot::Sender<PieceField>,
+    pub have_receiver: Receiver<HaveBroadcast>,
+    // If true, don't tell the peer about pieces it already has
+    pub suppress_redundant_haves: bool,
+    pub info_hash: [u8; 20],
+    // The other hash a hybrid torrent is known by
+    pub alt_info_hash: Option<[u8; 20]>,
+    pub peer_id: [u8; 20],
+    // The peer id the tracker gave for the address we dialed, if it gave one
+    pub expected_peer_id: Option<[u8; 20]>,
+    // True if we opened the connection, and send our handshake first
+    pub initiates: bool,
+    // The peer's address, if it is on the internet
+    pub address: Option<SocketAddr>,
+    // None if peer exchange is off for the torrent
+    pub pex_receiver: Option<Receiver<PexSnapshot>>,
+    pub choke_receiver: Receiver<bool>,
+    // The pieces we have, or only the ones offered while super-seeding
+    pub our_pieces: PieceField,
+    pub piece_length: u64,
+    pub download_size: u64,
+    pub reader: Sender<ReadRequest>,
+    // Where blocks we upload go back to once they are sent
+    pub buffers: BufferPool,
+    // None if we don't offer ut_holepunch
+    pub holepunch_receiver: Option<Receiver<HolepunchMessage>>,
+    // None if tracker exchange is off
+    pub tex_receiver: Option<Receiver<TexSnapshot>>,
+    // None if the torrent doesn't use the DHT
+    pub dht_port: Option<u16>,
+    pub super_seeding: bool,
+    // How long the peer has to send its handshake
+    pub handshake_timeout: Duration,
+}
 
 /// A connection to a peer.  Can download pieces from this connection
 pub struct Peer {
-    conn: Framed<TcpStream, message::MessageCodec>,
+    conn: Framed<Box<dyn Connection>, message::MessageCodec>,
     uploaded_sender: Sender<u32>,
     downloaded_sender: Sender<u32>,
-    // When a piece is done, the peer will send the piece to the receiver, along with what pieces
+    // Identifies this peer to the server
+    id: usize,
+    // When a piece is done, the peer will send the piece to the server, along with what pieces
     // this peer has, and a way to send a new piece back
-    finished_piece_sender: Sender<(Piece, Sender<Piece>, BitVec)>,
-    peers_pieces: BitVec,
+    event_sender: Sender<PeerEvent>,
+    // Tells the server we are gone.  Taken when the peer is dropped
+    gone_sender: Option<oneshot::Sender<PieceField>>,
+    // The piece being downloaded from this peer
+    piece: Option<Piece>,
+    // Set while we wait for the server to give us a piece
+    piece_receiver: Option<Receiver<Piece>>,
+    // True when the server had nothing for this peer.  We ask again when the peer gets new pieces
+    idle: bool,
+    // Blocks requested from the peer that haven't arrived, and how many there should be
+    pipeline: Pipeline,
+    // Whether the peer refuses to send us blocks
+    choked: bool,
+    // Whether we told the peer we want blocks from it
+    interested: bool,
+    // Whether we refuse to send the peer blocks.  The server decides, and tells us through
+    // choke_receiver: true to choke, false to unchoke
+    choking: bool,
+    choke_receiver: Receiver<bool>,
+    // Whether the peer wants blocks from us
+    peer_interested: bool,
+    // When we last heard from the peer and last sent it something
+    last_received: Instant,
+    last_sent: Instant,
+    idle_check: Interval,
+    // The pieces we have, so we only answer requests we can
+    our_pieces: PieceField,
+    // The size of every piece but the last, and of the whole torrent, to check requests against
+    piece_length: u64,
+    download_size: u64,
+    // How many requests for blocks that can't exist the peer has sent
+    bad_requests: u32,
+    // Reads blocks the peer asked for from disk
+    reader: Sender<ReadRequest>,
+    // Blocks being read for the peer, in the order it asked for them
+    reads: VecDeque<(message::Request, oneshot::Receiver<io::Result<Bytes>>)>,
+    // Pieces we finished that this peer should be told about
+    have_receiver: Receiver<HaveBroadcast>,
+    // If true, don't tell the peer about pieces it already has
+    suppress_redundant_haves: bool,
+    // Messages waiting to go out.  Control messages always go before piece data, so chokes and
+    // cancels aren't stuck behind megabytes of blocks on a slow uplink
+    control_queue: VecDeque<message::Message>,
+    payload_queue: VecDeque<message::Message>,
+    // The pieces the peer has, from its bitfield and Haves
+    peers_pieces: PieceField,
     info_hash: [u8; 20],
+    // The other hash a hybrid torrent is known by.  v2 peers handshake with it
+    alt_info_hash: Option<[u8; 20]>,
     peer_id: [u8; 20],
+    // The peer id the tracker gave for the address we dialed, if it gave one
+    expected_peer_id: Option<[u8; 20]>,
     initiates: bool,
+    // The peer's address, if it is on the internet
+    address: Option<SocketAddr>,
+    // What the peer logs as: its address, or the torrent and its id when it has none
+    log_target: String,
+    // The reserved bits of the peer's handshake, once it has sent it
+    reserved: Option<message::Reserved>,
+    // The peer is dropped if its handshake hasn't arrived by then
+    handshake_deadline: Option<Pin<Box<Sleep>>>,
+    // The extensions the peer supports, once it has told us
+    extensions: Option<ExtendedHandshake>,
+    // Snapshots of the server's peers, for ut_pex.  None if peer exchange is off for the torrent
+    pex_receiver: Option<Receiver<PexSnapshot>>,
+    // The peers we have told this peer about
+    pex_sent: HashSet<SocketAddr>,
+    // ut_holepunch messages the server wants sent to the peer.  None if we don't offer ut_holepunch
+    holepunch_receiver: Option<Receiver<HolepunchMessage>>,
+    // The port of our DHT node, sent to peers that run one too.  None if the torrent doesn't use
+    // the DHT
+    dht_port: Option<u16>,
+    // Snapshots of the trackers the server shares, for lt_tex.  None if tracker exchange is off
+    tex_receiver: Option<Receiver<TexSnapshot>>,
+    // The trackers we have told this peer about
+    tex_sent: HashSet<String>,
+    // If true, our_pieces only holds the pieces the server offered the peer, and the server hears
+    // about every change to the peer's pieces
+    super_seeding: bool,
 }
 
 impl Peer {
-    pub fn new(conn: TcpStream,
-               uploaded_sender: Sender<u32>,
-               downloaded_sender: Sender<u32>,
-               finished_piece_sender: Sender<(Piece, Sender<Piece>, BitVec)>,
-               info_hash: [u8; 20],
-               peer_id: [u8; 20],
-               initiates: bool) -> Self {
-        let mut conn = Framed::new(conn, message::MessageCodec::new());
-        if initiates {
-            let _res = conn.start_send(message::Message::Handshake((info_hash.clone(), peer_id.clone()).into()));
-        }
-        Peer {
+    pub fn new(conn: Box<dyn Connection>, config: PeerConfig) -> Self {
+        let PeerConfig {
+            uploaded_sender,
+            downloaded_sender,
+            id,
+            event_sender,
+            gone_sender,
+            have_receiver,
+            suppress_redundant_haves,
+            info_hash,
+            alt_info_hash,
+            peer_id,
+            expected_peer_id,
+            initiates,
+            address,
+            pex_receiver,
+            choke_receiver,
+            our_pieces,
+            piece_length,
+            download_size,
+            reader,
+            buffers,
+            holepunch_receiver,
+            tex_receiver,
+            dht_port,
+            super_seeding,
+            handshake_timeout,
+        } = config;
+        let conn = Framed::new(conn, message::MessageCodec::with_pool(buffers));
+        let peers_pieces = PieceField::new(our_pieces.len());
+        let log_target = log_target(&info_hash, id, address);
+        let mut peer = Peer {
             conn,
             uploaded_sender,
             downloaded_sender,
-            finished_piece_sender,
-            peers_pieces: BitVec::new(),
+            id,
+            event_sender,
+            gone_sender: Some(gone_sender),
+            piece: None,
+            piece_receiver: None,
+            idle: false,
+            pipeline: Pipeline::new(Instant::now()),
+            choked: true,
+            interested: false,
+            choking: true,
+            choke_receiver,
+            peer_interested: false,
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            idle_check: time::interval_at(time::Instant::now() + IDLE_CHECK_INTERVAL, IDLE_CHECK_INTERVAL),
+            our_pieces,
+            piece_length,
+            download_size,
+            bad_requests: 0,
+            reader,
+            reads: VecDeque::new(),
+            have_receiver,
+            suppress_redundant_haves,
+            control_queue: VecDeque::new(),
+            payload_queue: VecDeque::new(),
+            peers_pieces,
             info_hash,
+            alt_info_hash,
             peer_id,
+            expected_peer_id,
             initiates,
+            address,
+            log_target,
+            reserved: None,
+            handshake_deadline: Some(Box::pin(time::sleep(handshake_timeout))),
+            extensions: None,
+            pex_receiver,
+            pex_sent: HashSet::new(),
+            holepunch_receiver,
+            dht_port,
+            tex_receiver,
+            tex_sent: HashSet::new(),
+            super_seeding,
+        };
+        if initiates {
+            let handshake = peer.our_handshake();
+            let _res = peer.conn.start_send_unpin(message::Message::Handshake(handshake));
+        }
+        peer
+    }
+
+    /// Our handshake, flagging the DHT if we run a node for this torrent
+    fn our_handshake(&self) -> message::Handshake {
+        let mut handshake: message::Handshake = (self.info_hash, self.peer_id).into();
+        if self.dht_port.is_some() {
+            handshake.reserved = handshake.reserved.with_dht();
+        }
+        handshake
+    }
+
+    /// The reserved bits of the peer's handshake, or None if it hasn't arrived yet
+    pub fn reserved(&self) -> Option<message::Reserved> {
+        self.reserved
+    }
+
+    /// Whether the peer supports the extension protocol (BEP 10)
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved.is_some_and(|reserved| reserved.extended())
+    }
+
+    /// Whether the peer supports the fast extension (BEP 6).  We always do, so this is whether
+    /// the connection uses it
+    pub fn supports_fast(&self) -> bool {
+        self.reserved.is_some_and(|reserved| reserved.fast())
+    }
+
+    /// Whether the peer runs a DHT node (BEP 5)
+    pub fn supports_dht(&self) -> bool {
+        self.reserved.is_some_and(|reserved| reserved.dht())
+    }
+
+    /// Record that the peer has a piece
+    fn peer_has(&mut self, index: u32) -> Result<(), ()> {
+        if let Err(e) = self.peers_pieces.set(index) {
+            debug!(target: &self.log_target, "Peer sent a bad Have: {}", e);
+            return Err(());
+        }
+        self.idle = false;
+        self.report_pieces();
+        Ok(())
+    }
+
+    /// Tell the server what the peer has, if it needs to know
+    fn report_pieces(&mut self) {
+        if self.super_seeding {
+            let _res = self.event_sender.try_send(PeerEvent::Has {
+                peer: self.id,
+                pieces: self.peers_pieces.clone(),
+            });
+        }
+    }
+
+    /// Ask the server for a piece to download, handing back the one we finished
+    fn want_piece(&mut self, finished: Option<Piece>) {
+        let (reply, receiver) = channel(1);
+        self.piece_receiver = Some(receiver);
+        // If the channel is full the reply is dropped, and we wait idle for new pieces as if the
+        // server had nothing for us
+        let _res = self.event_sender.try_send(PeerEvent::WantPiece {
+            peer: self.id,
+            finished,
+            pieces: self.peers_pieces.clone(),
+            reply,
+        });
+    }
+
+    /// A block of the piece we are downloading arrived
+    fn receive_block(&mut self, block: message::Piece, cx: &mut Context<'_>) {
+        let length = block.block.len() as u32;
+        let _res = self.downloaded_sender.try_send(length);
+        let id = self.id;
+        let added = match self.piece.as_mut() {
+            Some(piece) if piece.index() == block.index => piece.add_block_from(id, block.begin, block.block),
+            _ => false,
+        };
+        if !added {
+            debug!(target: &self.log_target, "Peer sent a block we didn't ask for");
+            let _res = self.event_sender.try_send(PeerEvent::Wasted {
+                peer: self.id,
+                bytes: length,
+            });
+            return;
+        }
+        self.pipeline.received(block.index, block.begin, length, Instant::now());
+        if self.pipeline.is_snubbed() {
+            debug!(target: &self.log_target, "Peer stopped snubbing us");
+            self.pipeline.set_snubbed(false);
+            let _res = self.event_sender.try_send(PeerEvent::Unsnubbed(self.id));
+        }
+        // In endgame this may have been the last block other peers were missing too
+        self.cancel_arrived(cx);
+        if self.piece.as_ref().is_some_and(Piece::is_complete) {
+            let finished = self.piece.take();
+            self.want_piece(finished);
+        }
+    }
+
+    /// Take in the blocks of our piece that other peers sent first, which happens in endgame, and
+    /// cancel our requests for them
+    fn cancel_arrived(&mut self, cx: &mut Context<'_>) {
+        let piece = match self.piece.as_mut() {
+            Some(piece) => piece,
+            None => return,
+        };
+        let index = piece.index();
+        for (begin, length) in piece.catch_up(cx) {
+            self.pipeline.rejected(index, begin);
+            self.send(message::Message::Cancel((index, begin, length).into()));
+        }
+    }
+
+    /// Other peers may have sent blocks of our piece.  If they sent the rest of it, the peer that
+    /// sent the last block hands the piece in and we ask for something else
+    fn follow_endgame(&mut self, cx: &mut Context<'_>) {
+        self.cancel_arrived(cx);
+        if self.piece.as_ref().is_some_and(Piece::is_complete) {
+            self.piece = None;
+            self.pipeline.clear();
+            self.want_piece(None);
+        }
+    }
+
+    /// Someone else finished the piece we are downloading, which happens in endgame.  The blocks
+    /// still on their way are cancelled and we ask for something else
+    fn abandon_piece(&mut self) {
+        if let Some(piece) = self.piece.take() {
+            for (begin, length) in piece.outstanding_requests() {
+                self.send(message::Message::Cancel((piece.index(), begin, length).into()));
+            }
+        }
+        self.pipeline.clear();
+        self.want_piece(None);
+    }
+
+    /// The peer sat on our requests for too long.  Its piece goes back to the server for a faster
+    /// peer to finish, and from now on it only gets one request at a time
+    fn snub(&mut self) {
+        debug!(target: &self.log_target, "Peer sent none of {} blocks for {} seconds, it is snubbing us", self.pipeline.in_flight(),
+               SNUB_TIMEOUT.as_secs());
+        self.pipeline.set_snubbed(true);
+        let piece = self.piece.take().map(|mut piece| {
+            for (begin, length) in piece.outstanding_requests() {
+                self.send(message::Message::Cancel((piece.index(), begin, length).into()));
+            }
+            piece.reset_requests();
+            piece
+        });
+        self.pipeline.clear();
+        let (reply, receiver) = channel(1);
+        self.piece_receiver = Some(receiver);
+        let _res = self.event_sender.try_send(PeerEvent::Snubbed {
+            peer: self.id,
+            piece,
+            reply,
+        });
+    }
+
+    /// Get pieces from the server and keep the peer's request pipeline full
+    fn schedule(&mut self, cx: &mut Context<'_>) {
+        let poll = self.piece_receiver.as_mut().map(|receiver| receiver.poll_next_unpin(cx));
+        match poll {
+            Some(Poll::Ready(Some(piece))) => {
+                self.piece = Some(piece);
+                self.piece_receiver = None;
+                self.pipeline.clear();
+                if !self.interested {
+                    self.interested = true;
+                    self.send(message::Message::Interested);
+                }
+            }
+            Some(Poll::Ready(None)) => {
+                self.piece_receiver = None;
+                self.idle = true;
+                if self.interested {
+                    self.interested = false;
+                    self.send(message::Message::NotInterested);
+                }
+            }
+            _ => (),
+        }
+        if self.piece.is_none() && self.piece_receiver.is_none() && !self.idle
+            && self.peers_pieces.any() {
+            self.want_piece(None);
+        }
+        if self.choked {
+            return;
+        }
+        while self.pipeline.wants_more() {
+            let (index, (begin, length)) = match self.piece.as_mut() {
+                Some(piece) => match piece.next_request() {
+                    Some(request) => (piece.index(), request),
+                    None => return,
+                },
+                None => return,
+            };
+            self.pipeline.requested(index, begin, Instant::now());
+            self.send(message::Message::Request((index, begin, length).into()));
+        }
+    }
+
+    /// Choke or unchoke the peer, as the server decided.  Ready once the server is gone, which
+    /// means the torrent is stopping
+    fn apply_chokes(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut latest = None;
+        loop {
+            match self.choke_receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(choke)) => latest = Some(choke),
+                Poll::Pending => break,
+                Poll::Ready(None) => return Poll::Ready(()),
+            }
+        }
+        match latest {
+            Some(true) if !self.choking => {
+                self.choking = true;
+                // Choking throws away the peer's requests, including blocks that haven't gone out.
+                // Under the fast extension each one has to be rejected
+                let mut dropped: Vec<message::Request> = self.reads.drain(..).map(|(request, _)| request).collect();
+                let payload = std::mem::take(&mut self.payload_queue);
+                for message in payload {
+                    match message {
+                        message::Message::Piece(piece) => {
+                            dropped.push((piece.index, piece.begin, piece.block.len() as u32).into());
+                        }
+                        message => self.payload_queue.push_back(message),
+                    }
+                }
+                self.send(message::Message::Choke);
+                for request in dropped {
+                    self.reject(request);
+                }
+            }
+            Some(false) if self.choking => {
+                self.choking = false;
+                self.send(message::Message::Unchoke);
+            }
+            _ => (),
+        }
+        Poll::Pending
+    }
+
+    /// The peer told us whether it wants blocks from us
+    fn set_peer_interested(&mut self, interested: bool) {
+        if self.peer_interested != interested {
+            self.peer_interested = interested;
+            let _res = self.event_sender.try_send(PeerEvent::Interest {
+                peer: self.id,
+                interested,
+            });
+        }
+    }
+
+    /// The peer asked us for a block
+    fn receive_request(&mut self, request: message::Request) -> Result<(), ()> {
+        if self.choking {
+            // Requests from choked peers are refused but not held against them, since it may not
+            // know it's choked yet
+            self.reject(request);
+            return Ok(());
+        }
+        if !self.our_pieces.has(request.index) || request.length > MAX_BLOCK_LENGTH
+            || !request.fits(self.piece_size(request.index)) {
+            debug!(target: &self.log_target, "Peer asked for a block we can't send: {:?}", request);
+            self.reject(request);
+            self.bad_requests += 1;
+            if self.bad_requests >= MAX_BAD_REQUESTS {
+                debug!(target: &self.log_target, "Peer sent {} requests for blocks that can't exist, dropping it",
+                       self.bad_requests);
+                return Err(());
+            }
+            return Ok(());
+        }
+        if self.reads.len() >= MAX_PEER_REQUESTS {
+            debug!(target: &self.log_target, "Peer has too many requests outstanding");
+            self.reject(request);
+            return Ok(());
+        }
+        let (reply, receiver) = oneshot::channel();
+        let read = ReadRequest {
+            index: request.index,
+            begin: request.begin,
+            length: request.length,
+            reply,
+        };
+        if self.reader.try_send(read).is_ok() {
+            self.reads.push_back((request, receiver));
+        }
+        Ok(())
+    }
+
+    /// Tell the peer a request won't be answered, if it understands Reject.  Otherwise it finds
+    /// out when the request times out
+    fn reject(&mut self, request: message::Request) {
+        if self.supports_fast() {
+            self.send(message::Message::Reject(request));
+        }
+    }
+
+    /// The peer won't send a block we asked for, so it goes back to be asked for again
+    fn rejected(&mut self, request: &message::Request) {
+        self.pipeline.rejected(request.index, request.begin);
+        if let Some(piece) = self.piece.as_mut().filter(|piece| piece.index() == request.index) {
+            piece.reject_request(request.begin);
+        }
+    }
+
+    /// The size of a piece, which is less than the piece length for the last one
+    fn piece_size(&self, index: u32) -> u64 {
+        self.piece_length.min(self.download_size.saturating_sub(index as u64 * self.piece_length))
+    }
+
+    /// Queue blocks that have been read, in the order they were asked for
+    fn queue_reads(&mut self, cx: &mut Context<'_>) -> Result<(), ()> {
+        while let Some((request, mut receiver)) = self.reads.pop_front() {
+            match receiver.poll_unpin(cx) {
+                Poll::Ready(Ok(Ok(block))) => {
+                    self.send(message::Message::Piece(message::Piece {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    }));
+                }
+                Poll::Ready(Ok(Err(e))) => {
+                    error!(target: &self.log_target, "Could not read a block for a peer: {}", e);
+                    return Err(());
+                }
+                Poll::Pending => {
+                    self.reads.push_front((request, receiver));
+                    return Ok(());
+                }
+                // The reader thread is gone, so nothing can be uploaded
+                Poll::Ready(Err(oneshot::Canceled)) => return Err(()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue up every pending Have
+    fn queue_haves(&mut self, cx: &mut Context<'_>) {
+        loop {
+            match self.have_receiver.poll_next_unpin(cx) {
+                Poll::Ready(Some(have)) => {
+                    let _res = self.our_pieces.set(have.index);
+                    if self.piece.as_ref().is_some_and(|piece| piece.index() == have.index) {
+                        self.abandon_piece();
+                    }
+                    if self.suppress_redundant_haves
+                        && self.peers_pieces.has(have.index) {
+                        continue;
+                    }
+                    self.send(message::Message::Encoded(have.frame));
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Handle a message of the extension protocol
+    fn receive_extended(&mut self, id: u8, payload: Bytes) {
+        match id {
+            extension::HANDSHAKE_ID => {
+                match Value::decode(&payload).map_err(|e| format!("{:?}", e))
+                    .and_then(|val| ExtendedHandshake::from_value(&val)) {
+                    Ok(handshake) => {
+                        if self.holepunch_receiver.is_some() && handshake.id("ut_holepunch").is_some() {
+                            let _res = self.event_sender.try_send(PeerEvent::SupportsHolepunch(self.id));
+                        }
+                        self.extensions = Some(handshake);
+                    }
+                    Err(e) => debug!(target: &self.log_target, "Peer sent an invalid extended handshake: {}", e),
+                }
+            }
+            extension::UT_PEX_ID if self.pex_receiver.is_some() => {
+                match PexMessage::decode(&payload) {
+                    // If the channel is full, these peers are dropped.  Peers send ut_pex at most
+                    // once a minute, so that only happens when the server is far behind
+                    Ok(pex) => {
+                        let _res = self.event_sender.try_send(PeerEvent::Discovered {
+                            peer: self.id,
+                            peers: pex.added,
+                        });
+                    }
+                    Err(e) => debug!(target: &self.log_target, "Peer sent an invalid ut_pex message: {}", e),
+                }
+            }
+            extension::UT_HOLEPUNCH_ID if self.holepunch_receiver.is_some() => {
+                match HolepunchMessage::decode(&payload) {
+                    Ok(message) => {
+                        let _res = self.event_sender.try_send(PeerEvent::Holepunch {
+                            peer: self.id,
+                            message,
+                        });
+                    }
+                    Err(e) => debug!(target: &self.log_target, "Peer sent an invalid ut_holepunch message: {}", e),
+                }
+            }
+            extension::LT_TEX_ID if self.tex_receiver.is_some() => {
+                match TexMessage::decode(&payload) {
+                    Ok(tex) => {
+                        if !tex.added.is_empty() {
+                            let _res = self.event_sender.try_send(PeerEvent::Trackers {
+                                peer: self.id,
+                                urls: tex.added,
+                            });
+                        }
+                    }
+                    Err(e) => debug!(target: &self.log_target, "Peer sent an invalid lt_tex message: {}", e),
+                }
+            }
+            _ => debug!(target: &self.log_target, "Peer sent a message for an extension we didn't offer: {}", id),
+        }
+    }
+
+    /// Tell the peer which peers we connected to and dropped since last time, if it supports ut_pex
+    fn queue_pex(&mut self, cx: &mut Context<'_>) {
+        let mut latest = None;
+        while let Some(Poll::Ready(Some(snapshot))) = self.pex_receiver.as_mut().map(|r| r.poll_next_unpin(cx)) {
+            latest = Some(snapshot);
+        }
+        let (snapshot, id) = match (latest, self.extensions.as_ref().and_then(|e| e.id("ut_pex"))) {
+            (Some(snapshot), Some(id)) => (snapshot, id),
+            _ => return,
+        };
+        let current: HashSet<SocketAddr> = snapshot.iter()
+            .filter(|address| Some(**address) != self.address)
+            .cloned()
+            .collect();
+        let added: Vec<_> = current.difference(&self.pex_sent)
+            .take(extension::MAX_PEX_ADDED)
+            .cloned()
+            .collect();
+        let dropped: Vec<_> = self.pex_sent.difference(&current).cloned().collect();
+        if added.is_empty() && dropped.is_empty() {
+            return;
+        }
+        for address in &dropped {
+            self.pex_sent.remove(address);
+        }
+        self.pex_sent.extend(added.iter().cloned());
+        let pex = PexMessage { added, dropped };
+        self.send(message::Message::Extended(id, Bytes::from(pex.encode())));
+    }
+
+    /// Tell the peer about the trackers it hasn't heard about from us, if it supports lt_tex
+    fn queue_tex(&mut self, cx: &mut Context<'_>) {
+        let mut latest = None;
+        while let Some(Poll::Ready(Some(snapshot))) = self.tex_receiver.as_mut().map(|r| r.poll_next_unpin(cx)) {
+            latest = Some(snapshot);
+        }
+        let (snapshot, id) = match (latest, self.extensions.as_ref().and_then(|e| e.id("lt_tex"))) {
+            (Some(snapshot), Some(id)) => (snapshot, id),
+            _ => return,
+        };
+        // Trackers only ever get added, so there is nothing like ut_pex's dropped list
+        let added: Vec<_> = snapshot.iter()
+            .filter(|url| !self.tex_sent.contains(*url))
+            .take(extension::MAX_TEX_ADDED)
+            .cloned()
+            .collect();
+        if added.is_empty() {
+            return;
+        }
+        self.tex_sent.extend(added.iter().cloned());
+        let tex = TexMessage { added };
+        self.send(message::Message::Extended(id, Bytes::from(tex.encode())));
+    }
+
+    /// Pass on the ut_holepunch messages the server has for the peer
+    fn queue_holepunch(&mut self, cx: &mut Context<'_>) {
+        while let Some(Poll::Ready(Some(holepunch))) = self.holepunch_receiver.as_mut().map(|r| r.poll_next_unpin(cx)) {
+            // The server only relays to peers that said they support it
+            if let Some(id) = self.extensions.as_ref().and_then(|e| e.id("ut_holepunch")) {
+                self.send(message::Message::Extended(id, Bytes::from(holepunch.encode())));
+            }
+        }
+    }
+
+    /// Drop peers that went quiet, and keep the connection alive when we have nothing to say.
+    /// Returns Err if the peer timed out
+    fn check_idle(&mut self, cx: &mut Context<'_>) -> Result<(), ()> {
+        let mut due = false;
+        while self.idle_check.poll_tick(cx).is_ready() {
+            due = true;
+        }
+        if !due {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now.duration_since(self.last_received) >= IDLE_TIMEOUT {
+            debug!(target: &self.log_target, "Peer sent nothing for {} seconds, dropping it", IDLE_TIMEOUT.as_secs());
+            return Err(());
+        }
+        if !self.pipeline.is_snubbed() && self.pipeline.stalled_for(now) >= SNUB_TIMEOUT {
+            self.snub();
+        }
+        if now.duration_since(self.last_sent) >= KEEP_ALIVE_INTERVAL
+            && self.control_queue.is_empty() && self.payload_queue.is_empty() {
+            self.send(message::Message::KeepAlive);
+        }
+        Ok(())
+    }
+
+    /// Queue a message to send to the peer
+    fn send(&mut self, message: message::Message) {
+        if message.is_control() {
+            self.control_queue.push_back(message);
+        } else {
+            self.payload_queue.push_back(message);
+        }
+    }
+
+    /// The peer no longer wants a block, so don't send it if it hasn't gone out yet.  Under the
+    /// fast extension a cancelled request is answered with a Reject, unless the block already went
+    fn cancel(&mut self, request: message::Request) {
+        let queued = self.reads.len() + self.payload_queue.len();
+        self.reads.retain(|(read, _)| read != &request);
+        self.payload_queue.retain(|message| match message {
+            message::Message::Piece(piece) => !piece.answers(&request),
+            _ => true,
+        });
+        if self.reads.len() + self.payload_queue.len() < queued {
+            self.reject(request);
+        }
+    }
+
+    /// Hand queued messages to the connection, control messages first.  Piece data is only
+    /// handed over once everything before it has been written, so a control message queued while
+    /// a block is going out only waits for that one block.
+    fn write_queued(&mut self, cx: &mut Context<'_>) -> Result<(), ()> {
+        loop {
+            if !self.control_queue.is_empty() {
+                match self.conn.poll_ready_unpin(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    Poll::Pending => return Ok(()),
+                    Poll::Ready(Err(e)) => {
+                        error!(target: &self.log_target, "Connection to peer closed with error '{}'", e);
+                        return Err(());
+                    }
+                }
+                let message = self.control_queue.pop_front().unwrap();
+                if let Err(e) = self.conn.start_send_unpin(message) {
+                    error!(target: &self.log_target, "Connection to peer closed with error '{}'", e);
+                    return Err(());
+                }
+                self.last_sent = Instant::now();
+                continue;
+            }
+            if self.payload_queue.is_empty() {
+                return Ok(());
+            }
+            match self.conn.poll_flush_unpin(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Pending => return Ok(()),
+                Poll::Ready(Err(e)) => {
+                    error!(target: &self.log_target, "Connection to peer closed with error '{}'", e);
+                    return Err(());
+                }
+            }
+            if let Some(message) = self.payload_queue.pop_front() {
+                let uploaded = match &message {
+                    message::Message::Piece(piece) => piece.block.len() as u32,
+                    _ => 0,
+                };
+                if let Err(e) = self.conn.start_send_unpin(message) {
+                    error!(target: &self.log_target, "Connection to peer closed with error '{}'", e);
+                    return Err(());
+                }
+                self.last_sent = Instant::now();
+                if uploaded > 0 {
+                    let _res = self.uploaded_sender.try_send(uploaded);
+                }
+            }
         }
     }
 }
 
 // Peer can be spun into tasks
 impl Future for Peer {
-    type Item = ();
-    type Error = ();
+    type Output = Result<(), ()>;
 
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
         loop {
-            match self.conn.poll() {
-                Ok(Async::NotReady) => break, // No more messages right now
-                Ok(Async::Ready(None)) => return Ok(Async::Ready(())), // connection closed, end the task
-                Ok(Async::Ready(Some(message))) => {
+            match this.conn.poll_next_unpin(cx) {
+                Poll::Pending => break, // No more messages right now
+                Poll::Ready(None) => return Poll::Ready(Ok(())), // connection closed, end the task
+                Poll::Ready(Some(Ok(message))) => {
+                    this.last_received = Instant::now();
                     match message {
                         message::Message::Handshake(item) => {
-                            if self.info_hash != item.info_hash {
-                                error!("The info hash sent by a peer does not match ours");
-                                return Err(())
+                            if this.alt_info_hash == Some(item.info_hash) && !this.initiates {
+                                // Answer with the hash the peer asked for
+                                this.info_hash = item.info_hash;
+                            }
+                            if this.info_hash != item.info_hash {
+                                // Peers asking for torrents we don't have are refused
+                                debug!(target: &this.log_target, "The info hash sent by a peer does not match ours");
+                                return Poll::Ready(Err(()))
+                            }
+                            if item.peer_id == this.peer_id && this.initiates {
+                                debug!(target: &this.log_target, "Connected to ourselves, dropping the connection");
+                                let _res = this.event_sender.try_send(PeerEvent::Ourselves(this.id));
+                                return Poll::Ready(Err(()));
+                            }
+                            if this.expected_peer_id.is_some_and(|expected| expected != item.peer_id) {
+                                debug!(target: &this.log_target, "The peer id sent by a peer does not match the tracker's");
+                                return Poll::Ready(Err(()))
+                            }
+                            this.reserved = Some(item.reserved);
+                            this.handshake_deadline = None;
+                            let _res = this.event_sender.try_send(PeerEvent::Handshake {
+                                peer: this.id,
+                                peer_id: item.peer_id,
+                            });
+                            if !this.initiates {
+                                let handshake = this.our_handshake();
+                                this.send(message::Message::Handshake(handshake));
+                            }
+                            if item.peer_id == this.peer_id {
+                                // Only answer, so the end that dialed sees it reached itself and
+                                // closes the connection
+                                continue;
+                            }
+                            // Under the fast extension the peer is always told what we have, and
+                            // HaveAll and HaveNone say it in a byte
+                            if this.supports_fast() && !this.our_pieces.any() {
+                                this.send(message::Message::HaveNone);
+                            } else if this.supports_fast() && this.our_pieces.all() {
+                                this.send(message::Message::HaveAll);
+                            } else if this.our_pieces.any() {
+                                this.send(message::Message::Bitfield(this.our_pieces.to_bitfield()));
+                            }
+                            // Time for the first piece on offer
+                            this.report_pieces();
+                            if this.supports_extensions() {
+                                let mut extensions = Vec::new();
+                                if this.pex_receiver.is_some() {
+                                    extensions.push("ut_pex");
+                                }
+                                if this.holepunch_receiver.is_some() {
+                                    extensions.push("ut_holepunch");
+                                }
+                                if this.tex_receiver.is_some() {
+                                    extensions.push("lt_tex");
+                                }
+                                let ours = ExtendedHandshake::ours(&extensions, None).encode();
+                                this.send(message::Message::Extended(extension::HANDSHAKE_ID, Bytes::from(ours)));
+                            }
+                            // Peers that run a DHT node can add ours to their routing table (BEP 5)
+                            if let (Some(port), true) = (this.dht_port, this.supports_dht()) {
+                                this.send(message::Message::Port(port));
+                            }
+                        }
+                        message::Message::Have(index) => this.peer_has(index)?,
+                        message::Message::Bitfield(bitfield) => {
+                            match PieceField::from_bitfield(bitfield, this.our_pieces.len()) {
+                                Ok(pieces) => this.peers_pieces = pieces,
+                                Err(e) => {
+                                    debug!(target: &this.log_target, "Peer sent a bad bitfield: {}", e);
+                                    return Poll::Ready(Err(()));
+                                }
+                            }
+                            this.idle = false;
+                            this.report_pieces();
+                        }
+                        message::Message::HaveAll | message::Message::HaveNone
+                        | message::Message::Reject(_) if !this.supports_fast() => {
+                            debug!(target: &this.log_target, "Peer sent a fast extension message without the extension");
+                            return Poll::Ready(Err(()));
+                        }
+                        message::Message::HaveAll => {
+                            this.peers_pieces = PieceField::full(this.our_pieces.len());
+                            this.idle = false;
+                            this.report_pieces();
+                        }
+                        message::Message::HaveNone => {
+                            this.peers_pieces = PieceField::new(this.our_pieces.len());
+                            this.report_pieces();
+                        }
+                        message::Message::Reject(request) => this.rejected(&request),
+                        // Only hints, which we don't take
+                        message::Message::SuggestPiece(_) | message::Message::AllowedFast(_) => (),
+                        message::Message::Request(request) => this.receive_request(request)?,
+                        message::Message::Cancel(request) => this.cancel(request),
+                        message::Message::Choke => {
+                            this.choked = true;
+                            // Without the fast extension the peer throws away our requests when
+                            // it chokes us.  With it, the peer rejects the ones it won't answer
+                            if !this.supports_fast() {
+                                this.pipeline.clear();
+                                if let Some(piece) = this.piece.as_mut() {
+                                    piece.reset_requests();
+                                }
                             }
-                            if !self.initiates {
-                                let _res = self.conn.start_send(
-                                    message::Message::Handshake(
-                                        (self.info_hash.clone(), self.peer_id.clone()).into()
-                                    ));
+                        }
+                        message::Message::Unchoke => this.choked = false,
+                        message::Message::Interested => this.set_peer_interested(true),
+                        message::Message::NotInterested => this.set_peer_interested(false),
+                        message::Message::Piece(block) => this.receive_block(block, cx),
+                        message::Message::Extended(id, payload) => this.receive_extended(id, payload),
+                        message::Message::Port(port) => {
+                            if let Some(address) = this.address {
+                                let node = SocketAddr::new(address.ip(), port);
+                                let _res = this.event_sender.try_send(PeerEvent::DhtNode {
+                                    peer: this.id,
+                                    node,
+                                });
                             }
                         }
                         // TODO Process Message
                         _ => {}
                     }
                 }
-                Err(e) => {
-                    error!("Connection to peer closed with error '{}'", e);
-                    return Err(());
+                Poll::Ready(Some(Err(e))) => {
+                    error!(target: &this.log_target, "Connection to peer closed with error '{}'", e);
+                    return Poll::Ready(Err(()));
                 }
             }
         };
-        // TODO maybe send a message using self.conn.start_send here
-        loop {
-            match self.conn.poll_complete() {
-                Ok(Async::NotReady) => break, // Can't make anymore progress
-                Ok(Async::Ready(())) => (), // All sends complete
-                Err(e) => {
-                    error!("Connection to peer closed with error '{}'", e);
-                    return Err(());
-                }
-            }
+        if let Some(Poll::Ready(())) = this.handshake_deadline.as_mut().map(|deadline| deadline.as_mut().poll(cx)) {
+            debug!(target: &this.log_target, "Peer didn't send its handshake in time, dropping it");
+            return Poll::Ready(Err(()));
         }
-        Ok(Async::NotReady)
+        this.check_idle(cx)?;
+        if this.apply_chokes(cx).is_ready() {
+            debug!(target: &this.log_target, "Closing the connection, the torrent is stopping");
+            return Poll::Ready(Ok(()));
+        }
+        this.follow_endgame(cx);
+        this.schedule(cx);
+        this.queue_reads(cx)?;
+        this.queue_haves(cx);
+        this.queue_pex(cx);
+        this.queue_holepunch(cx);
+        this.queue_tex(cx);
+        this.write_queued(cx)?;
+        if let Poll::Ready(Err(e)) = this.conn.poll_flush_unpin(cx) {
+            error!(target: &this.log_target, "Connection to peer closed with error '{}'", e);
+            return Poll::Ready(Err(()));
+        }
+        Poll::Pending
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Peer {
+    /// Let the server know we are gone, so our piece can go to another peer
+    fn drop(&mut self) {
+        if let Some(gone_sender) = self.gone_sender.take() {
+            let _res = gone_sender.send(self.peers_pieces.clone());
+        }
+    }
+}
@@ -0,0 +1,674 @@
+//! mse implements Message Stream Encryption, the obfuscated handshake most clients use so that
+//! BitTorrent traffic can't be picked out by its plaintext handshake.  The two sides agree on a
+//! secret with Diffie-Hellman, prove they know the torrent's info hash, and then pick either RC4
+//! or plaintext for the rest of the connection.
+use crate::socks::{
+    self,
+    ProxyConfig,
+};
+use crypto::{
+    digest::Digest,
+    rc4::Rc4,
+    sha1::Sha1,
+    symmetriccipher::SynchronousStreamCipher,
+};
+use futures::ready;
+use openssl::bn::{
+    BigNum,
+    BigNumContext,
+};
+use openssl::error::ErrorStack;
+use rand::{
+    Rng,
+    thread_rng,
+};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::Duration;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        ReadBuf,
+    },
+    net::TcpStream,
+    time,
+};
+
+#[cfg(test)]
+mod test;
+
+/// The 768 bit safe prime the key exchange is done in, with generator 2
+const PRIME: [u8; 96] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2, 0x21, 0x68, 0xc2, 0x34,
+    0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1, 0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74,
+    0x02, 0x0b, 0xbe, 0xa6, 0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d, 0xf2, 0x5f, 0x14, 0x37,
+    0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45, 0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6,
+    0xf4, 0x4c, 0x42, 0xe9, 0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+/// Length of a public key, and of the shared secret
+const KEY_LENGTH: usize = 96;
+
+/// Most random padding either side may send after its public key or in the crypto negotiation
+const MAX_PAD: usize = 512;
+
+/// Sent encrypted so each side can tell where the other's encrypted stream starts
+const VERIFICATION: [u8; 8] = [0; 8];
+
+/// Bits of crypto_provide and crypto_select
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+/// How long the whole handshake may take before the connection is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The first bytes of a plaintext BitTorrent handshake
+const PLAINTEXT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+
+/// Where the info hash is in a plaintext handshake, after the protocol name and reserved bytes
+const PLAINTEXT_INFO_HASH: usize = 28;
+
+/// Which connections we are willing to make and accept
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum EncryptionPolicy {
+    /// Encrypt when the peer supports it, and fall back to plaintext when it doesn't
+    #[default]
+    PreferEncrypted,
+    /// Only talk to peers over RC4
+    RequireEncrypted,
+    /// Never use the encrypted handshake
+    PlaintextOnly,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer-encrypted" => Ok(EncryptionPolicy::PreferEncrypted),
+            "require-encrypted" => Ok(EncryptionPolicy::RequireEncrypted),
+            "plaintext-only" => Ok(EncryptionPolicy::PlaintextOnly),
+            _ => Err(format!("Invalid encryption policy: {}", s)),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    /// The crypto_provide or allowed crypto_select bits
+    fn methods(self) -> u32 {
+        match self {
+            EncryptionPolicy::PreferEncrypted => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+            EncryptionPolicy::PlaintextOnly => CRYPTO_PLAINTEXT,
+        }
+    }
+}
+
+fn other(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.input(part);
+    }
+    let mut res = [0; 20];
+    hasher.result(&mut res);
+    res
+}
+
+/// An RC4 stream for one direction.  The first 1024 bytes of keystream are thrown away, since they
+/// leak information about the key
+fn cipher(label: &[u8], secret: &[u8], skey: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[label, secret, skey]));
+    let discard = [0; 1024];
+    let mut out = [0; 1024];
+    rc4.process(&discard, &mut out);
+    rc4
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut pad = vec![0; thread_rng().gen_range(0, MAX_PAD as u32 + 1) as usize];
+    thread_rng().fill(&mut pad[..]);
+    pad
+}
+
+/// Our half of a Diffie-Hellman key exchange
+struct KeyPair {
+    private: BigNum,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    fn generate() -> io::Result<Self> {
+        let openssl = io::Error::other::<ErrorStack>;
+        let mut private = [0; 20];
+        thread_rng().fill(&mut private);
+        let private = BigNum::from_slice(&private).map_err(openssl)?;
+        let mut public = BigNum::new().map_err(openssl)?;
+        let mut ctx = BigNumContext::new().map_err(openssl)?;
+        let generator = BigNum::from_u32(2).map_err(openssl)?;
+        let prime = BigNum::from_slice(&PRIME).map_err(openssl)?;
+        public.mod_exp(&generator, &private, &prime, &mut ctx).map_err(openssl)?;
+        Ok(KeyPair {
+            private,
+            public: public.to_vec_padded(KEY_LENGTH as i32).map_err(openssl)?,
+        })
+    }
+
+    /// The secret shared with whoever sent `theirs`
+    fn secret(&self, theirs: &[u8]) -> io::Result<Vec<u8>> {
+        let openssl = io::Error::other::<ErrorStack>;
+        let mut secret = BigNum::new().map_err(openssl)?;
+        let mut ctx = BigNumContext::new().map_err(openssl)?;
+        let theirs = BigNum::from_slice(theirs).map_err(openssl)?;
+        let prime = BigNum::from_slice(&PRIME).map_err(openssl)?;
+        secret.mod_exp(&theirs, &self.private, &prime, &mut ctx).map_err(openssl)?;
+        secret.to_vec_padded(KEY_LENGTH as i32).map_err(openssl)
+    }
+}
+
+/// A connection after the handshake.  Bytes are run through RC4 if that was picked, and passed
+/// through untouched otherwise
+pub struct Encrypted<C> {
+    conn: C,
+    // Bytes of the stream that arrived during the handshake, ready to be read
+    pending: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    // Encrypted bytes the connection hasn't taken yet.  The keystream has moved past them, so
+    // they have to go out as they are
+    unsent: Vec<u8>,
+}
+
+impl<C> Encrypted<C> {
+    /// A connection that doesn't use encryption at all
+    pub fn plaintext(conn: C) -> Self {
+        Encrypted::unread(conn, Vec::new())
+    }
+
+    /// A connection that doesn't use encryption, where `pending` was read from it already and is
+    /// read again first
+    pub fn unread(conn: C, pending: Vec<u8>) -> Self {
+        Encrypted {
+            conn,
+            pending,
+            decrypt: None,
+            encrypt: None,
+            unsent: Vec::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.conn
+    }
+
+    /// Whether the connection is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+}
+
+impl<C: AsyncWrite + Unpin> Encrypted<C> {
+    fn poll_write_unsent(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let written = ready!(Pin::new(&mut self.conn).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Encrypted<C> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.pending.is_empty() {
+            let len = buf.remaining().min(this.pending.len());
+            buf.put_slice(&this.pending[..len]);
+            this.pending.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.conn).poll_read(cx, buf))?;
+        if let Some(decrypt) = this.decrypt.as_mut() {
+            let received = &mut buf.filled_mut()[start..];
+            let ciphertext = received.to_vec();
+            decrypt.process(&ciphertext, received);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Encrypted<C> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.encrypt.is_none() {
+            return Pin::new(&mut this.conn).poll_write(cx, buf);
+        }
+        // Only take more once the last write has gone out, so unsent stays small
+        ready!(this.poll_write_unsent(cx))?;
+        let mut ciphertext = vec![0; buf.len()];
+        if let Some(encrypt) = this.encrypt.as_mut() {
+            encrypt.process(buf, &mut ciphertext);
+        }
+        this.unsent = ciphertext;
+        // The keystream has moved past buf, so it counts as written even if the connection
+        // doesn't take it yet
+        if let Poll::Ready(Err(e)) = this.poll_write_unsent(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_unsent(cx))?;
+        Pin::new(&mut this.conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.get_mut().conn).poll_shutdown(cx)
+    }
+}
+
+/// Where a handshake is up to
+enum Stage {
+    // Incoming: waiting to see whether the peer sent a plaintext handshake or a public key
+    Detect,
+    // Incoming: looking for HASH('req1', S) past the peer's padding
+    FindRequest,
+    // Incoming: checking the info hash and reading crypto_provide
+    ReadProvide,
+    // Incoming: reading the padding and the length of the initial payload
+    ReadPadC(usize),
+    // Incoming: reading the initial payload
+    ReadPayload(usize),
+    // Outgoing: waiting for the peer's public key
+    ReadKey,
+    // Outgoing: looking for the encrypted verification constant past the peer's padding
+    FindVerification([u8; 8]),
+    // Outgoing: reading crypto_select
+    ReadSelect,
+    // Outgoing: reading the padding after crypto_select
+    ReadPadD(usize),
+    // Waiting for the last of our handshake to go out
+    Flush,
+}
+
+/// Either side of the encrypted handshake
+struct Handshake<C> {
+    conn: Option<C>,
+    // The torrent the connection is for.  Incoming peers pick it from the candidates
+    info_hash: [u8; 20],
+    // The info hashes incoming peers may ask for, counting both hashes of hybrid torrents
+    candidates: Vec<[u8; 20]>,
+    policy: EncryptionPolicy,
+    keys: KeyPair,
+    secret: Vec<u8>,
+    stage: Stage,
+    // Everything read from the peer so far
+    received: Vec<u8>,
+    // How much of received has been handled
+    position: usize,
+    unsent: Vec<u8>,
+    decrypt: Option<Rc4>,
+    encrypt: Option<Rc4>,
+    // What was picked, once it is known
+    selected: u32,
+    // Decrypted bytes the peer sent during the handshake that belong to the stream
+    payload: Vec<u8>,
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> Handshake<C> {
+    fn new(conn: C, info_hash: [u8; 20], policy: EncryptionPolicy, stage: Stage) -> io::Result<Self> {
+        Ok(Handshake {
+            conn: Some(conn),
+            info_hash,
+            candidates: Vec::new(),
+            policy,
+            keys: KeyPair::generate()?,
+            secret: Vec::new(),
+            stage,
+            received: Vec::new(),
+            position: 0,
+            unsent: Vec::new(),
+            decrypt: None,
+            encrypt: None,
+            selected: 0,
+            payload: Vec::new(),
+        })
+    }
+
+    /// Start our side of an outgoing handshake
+    fn outgoing(conn: C, info_hash: [u8; 20], policy: EncryptionPolicy) -> io::Result<Self> {
+        let mut handshake = Handshake::new(conn, info_hash, policy, Stage::ReadKey)?;
+        handshake.unsent = handshake.keys.public.clone();
+        handshake.unsent.extend(random_pad());
+        Ok(handshake)
+    }
+
+    /// Start reading a handshake a peer sent us.  `received` is what was already read from the
+    /// connection
+    fn incoming(conn: C, received: Vec<u8>, candidates: Vec<[u8; 20]>, policy: EncryptionPolicy)
+                -> io::Result<Self> {
+        let mut handshake = Handshake::new(conn, [0; 20], policy, Stage::Detect)?;
+        handshake.received = received;
+        handshake.candidates = candidates;
+        Ok(handshake)
+    }
+
+    /// The candidate the peer asked for, by checking `matches` against each
+    fn pick<F: Fn(&[u8; 20]) -> bool>(&self, matches: F) -> io::Result<[u8; 20]> {
+        self.candidates.iter().cloned().find(|info_hash| matches(info_hash))
+            .ok_or_else(|| other("Peer asked for a torrent we don't have"))
+    }
+
+    fn conn(&mut self) -> &mut C {
+        self.conn.as_mut().expect("handshake polled after it finished")
+    }
+
+    /// Read whatever has arrived
+    fn receive(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut buf = [0; 1024];
+        let mut read = ReadBuf::new(&mut buf);
+        ready!(Pin::new(self.conn()).poll_read(cx, &mut read))?;
+        if read.filled().is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+        self.received.extend_from_slice(read.filled());
+        Poll::Ready(Ok(()))
+    }
+
+    /// Read until at least `len` bytes have arrived
+    fn fill(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<()>> {
+        while self.received.len() < len {
+            ready!(self.receive(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Look for `needle` past the peer's public key, giving up once more padding arrived than
+    /// the peer may send.  Returns where it starts
+    fn find(&mut self, cx: &mut Context<'_>, needle: &[u8]) -> Poll<io::Result<usize>> {
+        loop {
+            let found = self.received.get(KEY_LENGTH..).and_then(|rest| {
+                rest.windows(needle.len()).position(|window| window == needle)
+            });
+            if let Some(i) = found {
+                return Poll::Ready(Ok(KEY_LENGTH + i));
+            }
+            if self.received.len() >= KEY_LENGTH + MAX_PAD + needle.len() {
+                return Poll::Ready(Err(other("Peer didn't send a valid encrypted handshake")));
+            }
+            ready!(self.receive(cx))?;
+        }
+    }
+
+    /// Send everything we queued
+    fn send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.unsent.is_empty() {
+            let conn = self.conn.as_mut().expect("handshake polled after it finished");
+            let written = ready!(Pin::new(conn).poll_write(cx, &self.unsent))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.unsent.drain(..written);
+        }
+        Pin::new(self.conn()).poll_flush(cx)
+    }
+
+    /// Decrypt the next `len` received bytes, and return them
+    fn take_decrypted(&mut self, len: usize) -> Vec<u8> {
+        let ciphertext = &self.received[self.position..self.position + len];
+        let mut plaintext = vec![0; len];
+        if let Some(decrypt) = self.decrypt.as_mut() {
+            decrypt.process(ciphertext, &mut plaintext);
+        }
+        self.position += len;
+        plaintext
+    }
+
+    fn queue_encrypted(&mut self, plaintext: &[u8]) {
+        let mut ciphertext = vec![0; plaintext.len()];
+        if let Some(encrypt) = self.encrypt.as_mut() {
+            encrypt.process(plaintext, &mut ciphertext);
+        }
+        self.unsent.extend(ciphertext);
+    }
+
+    /// Work out the secret from the peer's public key
+    fn read_key(&mut self) -> io::Result<()> {
+        self.secret = self.keys.secret(&self.received[..KEY_LENGTH])?;
+        Ok(())
+    }
+
+    /// Move the handshake along as far as it will go.  Ready once the handshake is over
+    fn advance(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            // Our side goes out whenever the connection takes it
+            if self.send(cx)?.is_pending() {
+                if let Stage::Flush = self.stage {
+                    return Poll::Pending;
+                }
+            }
+            match self.stage {
+                Stage::Detect => {
+                    ready!(self.fill(cx, PLAINTEXT_HANDSHAKE.len()))?;
+                    if self.received.starts_with(PLAINTEXT_HANDSHAKE) {
+                        if self.policy == EncryptionPolicy::RequireEncrypted {
+                            return Poll::Ready(Err(other("Peer sent a plaintext handshake, but encryption is required")));
+                        }
+                        ready!(self.fill(cx, PLAINTEXT_INFO_HASH + 20))?;
+                        let asked = &self.received[PLAINTEXT_INFO_HASH..PLAINTEXT_INFO_HASH + 20];
+                        self.info_hash = self.pick(|info_hash| info_hash[..] == *asked)?;
+                        self.selected = CRYPTO_PLAINTEXT;
+                        self.payload = self.received.split_off(0);
+                        self.stage = Stage::Flush;
+                        continue;
+                    }
+                    if self.policy == EncryptionPolicy::PlaintextOnly {
+                        return Poll::Ready(Err(other("Peer didn't send a plaintext handshake, and encryption is off")));
+                    }
+                    ready!(self.fill(cx, KEY_LENGTH))?;
+                    self.read_key()?;
+                    self.unsent = self.keys.public.clone();
+                    self.unsent.extend(random_pad());
+                    self.stage = Stage::FindRequest;
+                }
+                Stage::FindRequest => {
+                    let request = hash(&[b"req1", &self.secret]);
+                    self.position = ready!(self.find(cx, &request))? + request.len();
+                    self.stage = Stage::ReadProvide;
+                }
+                Stage::ReadProvide => {
+                    // HASH('req2', SKEY) xor HASH('req3', S), then VC, crypto_provide and len(PadC)
+                    ready!(self.fill(cx, self.position + 20 + 14))?;
+                    let req3 = hash(&[b"req3", &self.secret]);
+                    let provided = &self.received[self.position..self.position + 20];
+                    self.info_hash = self.pick(|info_hash| {
+                        let req2 = hash(&[b"req2", info_hash]);
+                        req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b).eq(provided.iter().cloned())
+                    })?;
+                    self.position += 20;
+                    self.decrypt = Some(cipher(b"keyA", &self.secret, &self.info_hash));
+                    self.encrypt = Some(cipher(b"keyB", &self.secret, &self.info_hash));
+                    let negotiation = self.take_decrypted(14);
+                    if negotiation[..8] != VERIFICATION {
+                        return Poll::Ready(Err(other("Peer sent a bad verification constant")));
+                    }
+                    let provided = u32::from(negotiation[8]) << 24 | u32::from(negotiation[9]) << 16
+                        | u32::from(negotiation[10]) << 8 | u32::from(negotiation[11]);
+                    let methods = provided & self.policy.methods();
+                    self.selected = if methods & CRYPTO_RC4 != 0 {
+                        CRYPTO_RC4
+                    } else if methods & CRYPTO_PLAINTEXT != 0 {
+                        CRYPTO_PLAINTEXT
+                    } else {
+                        return Poll::Ready(Err(other("Peer doesn't support an encryption method we allow")));
+                    };
+                    let pad = (negotiation[12] as usize) << 8 | negotiation[13] as usize;
+                    if pad > MAX_PAD {
+                        return Poll::Ready(Err(other("Peer sent too much padding")));
+                    }
+                    self.stage = Stage::ReadPadC(pad);
+                }
+                Stage::ReadPadC(pad) => {
+                    ready!(self.fill(cx, self.position + pad + 2))?;
+                    let length = self.take_decrypted(pad + 2);
+                    let length = (length[pad] as usize) << 8 | length[pad + 1] as usize;
+                    self.stage = Stage::ReadPayload(length);
+                }
+                Stage::ReadPayload(length) => {
+                    ready!(self.fill(cx, self.position + length))?;
+                    self.payload = self.take_decrypted(length);
+                    let mut answer = VERIFICATION.to_vec();
+                    answer.extend_from_slice(&self.selected.to_be_bytes());
+                    // No padding
+                    answer.extend_from_slice(&[0, 0]);
+                    self.queue_encrypted(&answer);
+                    self.stage = Stage::Flush;
+                }
+                Stage::ReadKey => {
+                    ready!(self.fill(cx, KEY_LENGTH))?;
+                    self.read_key()?;
+                    self.unsent.extend_from_slice(&hash(&[b"req1", &self.secret]));
+                    let req2 = hash(&[b"req2", &self.info_hash]);
+                    let req3 = hash(&[b"req3", &self.secret]);
+                    self.unsent.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+                    self.encrypt = Some(cipher(b"keyA", &self.secret, &self.info_hash));
+                    self.decrypt = Some(cipher(b"keyB", &self.secret, &self.info_hash));
+                    let mut negotiation = VERIFICATION.to_vec();
+                    negotiation.extend_from_slice(&self.policy.methods().to_be_bytes());
+                    // No padding, and no initial payload.  Our BitTorrent handshake goes out
+                    // once the method is picked
+                    negotiation.extend_from_slice(&[0, 0, 0, 0]);
+                    self.queue_encrypted(&negotiation);
+                    let mut marker = [0; 8];
+                    cipher(b"keyB", &self.secret, &self.info_hash).process(&VERIFICATION, &mut marker);
+                    self.stage = Stage::FindVerification(marker);
+                }
+                Stage::FindVerification(marker) => {
+                    self.position = ready!(self.find(cx, &marker))?;
+                    self.take_decrypted(marker.len());
+                    self.stage = Stage::ReadSelect;
+                }
+                Stage::ReadSelect => {
+                    ready!(self.fill(cx, self.position + 6))?;
+                    let select = self.take_decrypted(6);
+                    self.selected = u32::from(select[0]) << 24 | u32::from(select[1]) << 16
+                        | u32::from(select[2]) << 8 | u32::from(select[3]);
+                    if self.selected != CRYPTO_RC4 && self.selected != CRYPTO_PLAINTEXT
+                        || self.selected & self.policy.methods() == 0 {
+                        return Poll::Ready(Err(other("Peer picked an encryption method we didn't offer")));
+                    }
+                    let pad = (select[4] as usize) << 8 | select[5] as usize;
+                    if pad > MAX_PAD {
+                        return Poll::Ready(Err(other("Peer sent too much padding")));
+                    }
+                    self.stage = Stage::ReadPadD(pad);
+                }
+                Stage::ReadPadD(pad) => {
+                    ready!(self.fill(cx, self.position + pad))?;
+                    self.take_decrypted(pad);
+                    self.stage = Stage::Flush;
+                }
+                Stage::Flush => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> Future for Handshake<C> {
+    // The connection, and the info hash of the torrent it is for
+    type Output = io::Result<(Encrypted<C>, [u8; 20])>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        ready!(this.advance(cx))?;
+        // Whatever came after the handshake is the start of the stream
+        let mut pending = std::mem::take(&mut this.payload);
+        let rest = this.received.split_off(this.position.min(this.received.len()));
+        let mut encrypted = Encrypted::plaintext(this.conn.take().expect("handshake polled after it finished"));
+        match (this.selected, this.decrypt.take()) {
+            (CRYPTO_RC4, Some(mut decrypt)) => {
+                let mut plaintext = vec![0; rest.len()];
+                decrypt.process(&rest, &mut plaintext);
+                pending.extend(plaintext);
+                encrypted.decrypt = Some(decrypt);
+                encrypted.encrypt = this.encrypt.take();
+            }
+            _ => pending.extend(rest),
+        }
+        encrypted.pending = pending;
+        Poll::Ready(Ok((encrypted, this.info_hash)))
+    }
+}
+
+/// Fail with TimedOut if a handshake takes too long
+async fn limit<F, T>(handshake: F) -> io::Result<T>
+    where F: Future<Output=io::Result<T>> {
+    time::timeout(HANDSHAKE_TIMEOUT, handshake).await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+/// Answer a connection a peer made to us, and find out which of `info_hashes` it is for.
+/// `received` is what was already read from the connection.  Peers that start with a plaintext
+/// handshake are accepted as they are, unless encryption is required, and their handshake is
+/// read again by the peer
+pub async fn accept<C>(conn: C, received: Vec<u8>, info_hashes: Vec<[u8; 20]>, policy: EncryptionPolicy)
+                       -> io::Result<(Encrypted<C>, [u8; 20])>
+    where C: AsyncRead + AsyncWrite + Unpin {
+    limit(Handshake::incoming(conn, received, info_hashes, policy)?).await
+}
+
+/// Start the encrypted handshake on a connection we made, unless encryption is off
+pub async fn connect<C>(conn: C, info_hash: [u8; 20], policy: EncryptionPolicy) -> io::Result<Encrypted<C>>
+    where C: AsyncRead + AsyncWrite + Unpin {
+    if policy == EncryptionPolicy::PlaintextOnly {
+        return Ok(Encrypted::plaintext(conn));
+    }
+    let (conn, _) = limit(Handshake::outgoing(conn, info_hash, policy)?).await?;
+    Ok(conn)
+}
+
+/// One try at connecting to a peer with the given policy
+async fn attempt(address: SocketAddr, info_hash: [u8; 20], policy: EncryptionPolicy, proxy: Option<&ProxyConfig>)
+                 -> io::Result<Encrypted<TcpStream>> {
+    let conn = match proxy {
+        Some(proxy) => socks::connect(proxy, address).await?,
+        None => TcpStream::connect(address).await?,
+    };
+    connect(conn, info_hash, policy).await
+}
+
+/// Connect to a peer, through the proxy if there is one.  When the policy prefers encryption, a
+/// peer that hangs up on the encrypted handshake is tried again in plaintext, since older clients don't support it
+pub async fn dial(address: SocketAddr, info_hash: [u8; 20], policy: EncryptionPolicy, proxy: Option<ProxyConfig>)
+                  -> io::Result<Encrypted<TcpStream>> {
+    let first = match policy {
+        EncryptionPolicy::PreferEncrypted => EncryptionPolicy::RequireEncrypted,
+        policy => policy,
+    };
+    match attempt(address, info_hash, first, proxy.as_ref()).await {
+        // Nothing listens there, so another try won't help
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Err(e),
+        Err(_) if policy == EncryptionPolicy::PreferEncrypted => {
+            attempt(address, info_hash, EncryptionPolicy::PlaintextOnly, proxy.as_ref()).await
+        }
+        res => res,
+    }
+}
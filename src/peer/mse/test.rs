@@ -0,0 +1,144 @@
+use futures::executor::block_on;
+use std::io::{
+    Read,
+    Write,
+};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use super::*;
+use tokio::io::{
+    AsyncReadExt,
+    AsyncWriteExt,
+};
+
+/// A blocking socket, so handshakes can run with block_on() instead of a runtime
+struct Blocking(UnixStream);
+
+impl AsyncRead for Blocking {
+    fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let read = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Blocking {
+    fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.write(buf))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.0.flush())
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run a handshake between both sides, then send a message each way.  Returns whether each side
+/// ended up encrypted
+fn exchange(accepting: EncryptionPolicy, connecting: EncryptionPolicy, info_hash: [u8; 20])
+            -> io::Result<(bool, bool)> {
+    let (server_sock, client_sock) = UnixStream::pair().unwrap();
+    let server = thread::spawn(move || block_on(async {
+        let (mut conn, _) = Handshake::incoming(Blocking(server_sock), Vec::new(), vec![[1; 20], [3; 20]], accepting)?
+            .await?;
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hello");
+        conn.write_all(b"world").await?;
+        conn.flush().await?;
+        Ok::<_, io::Error>(conn.is_encrypted())
+    }));
+    let client = block_on(async {
+        let (mut conn, _) = Handshake::outgoing(Blocking(client_sock), info_hash, connecting)?.await?;
+        conn.write_all(b"hello").await?;
+        conn.flush().await?;
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"world");
+        Ok::<_, io::Error>(conn.is_encrypted())
+    });
+    Ok((server.join().unwrap()?, client?))
+}
+
+#[test]
+fn test_key_exchange() {
+    let ours = KeyPair::generate().unwrap();
+    let theirs = KeyPair::generate().unwrap();
+    assert_eq!(ours.public.len(), KEY_LENGTH);
+    assert_eq!(ours.secret(&theirs.public).unwrap(), theirs.secret(&ours.public).unwrap());
+}
+
+#[test]
+fn test_encrypted_handshake() {
+    let res = exchange(EncryptionPolicy::PreferEncrypted, EncryptionPolicy::RequireEncrypted, [1; 20]);
+    assert_eq!(res.unwrap(), (true, true));
+}
+
+#[test]
+fn test_rc4_picked_when_both_offered() {
+    let res = exchange(EncryptionPolicy::PreferEncrypted, EncryptionPolicy::PreferEncrypted, [1; 20]);
+    assert_eq!(res.unwrap(), (true, true));
+}
+
+#[test]
+fn test_alt_info_hash_accepted() {
+    let res = exchange(EncryptionPolicy::PreferEncrypted, EncryptionPolicy::RequireEncrypted, [3; 20]);
+    assert_eq!(res.unwrap(), (true, true));
+}
+
+#[test]
+fn test_wrong_info_hash_refused() {
+    assert!(exchange(EncryptionPolicy::PreferEncrypted, EncryptionPolicy::RequireEncrypted, [2; 20]).is_err());
+}
+
+/// The start of a plaintext BitTorrent handshake for `info_hash`
+fn plaintext_handshake(info_hash: [u8; 20]) -> Vec<u8> {
+    let mut handshake = PLAINTEXT_HANDSHAKE.to_vec();
+    handshake.extend_from_slice(&[0; 8]);
+    handshake.extend_from_slice(&info_hash);
+    handshake
+}
+
+#[test]
+fn test_plaintext_peer_accepted() {
+    // A plaintext connection starts with the BitTorrent handshake, which has to be handed on
+    let (server_sock, mut client_sock) = UnixStream::pair().unwrap();
+    // Part of it was already read, to see what kind of connection it is
+    let sent = plaintext_handshake([3; 20]);
+    client_sock.write_all(&sent[6..]).unwrap();
+    let handshake = Handshake::incoming(Blocking(server_sock), sent[..6].to_vec(), vec![[1; 20], [3; 20]],
+                                        EncryptionPolicy::PreferEncrypted);
+    let (mut conn, info_hash) = block_on(handshake.unwrap()).unwrap();
+    assert!(!conn.is_encrypted());
+    assert_eq!(info_hash, [3; 20]);
+    let mut buf = vec![0; sent.len()];
+    block_on(conn.read_exact(&mut buf)).unwrap();
+    assert_eq!(buf, sent);
+}
+
+#[test]
+fn test_plaintext_peer_refused_when_encryption_required() {
+    let (server_sock, mut client_sock) = UnixStream::pair().unwrap();
+    client_sock.write_all(&plaintext_handshake([1; 20])).unwrap();
+    let handshake = Handshake::incoming(Blocking(server_sock), Vec::new(), vec![[1; 20]],
+                                        EncryptionPolicy::RequireEncrypted);
+    assert!(block_on(handshake.unwrap()).is_err());
+}
+
+#[test]
+fn test_plaintext_peer_for_unknown_torrent_refused() {
+    let (server_sock, mut client_sock) = UnixStream::pair().unwrap();
+    client_sock.write_all(&plaintext_handshake([2; 20])).unwrap();
+    let handshake = Handshake::incoming(Blocking(server_sock), Vec::new(), vec![[1; 20]],
+                                        EncryptionPolicy::PlaintextOnly);
+    assert!(block_on(handshake.unwrap()).is_err());
+}
+
+#[test]
+fn test_parse_policy() {
+    assert_eq!("require-encrypted".parse(), Ok(EncryptionPolicy::RequireEncrypted));
+    assert!("rot13".parse::<EncryptionPolicy>().is_err());
+}
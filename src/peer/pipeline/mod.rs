@@ -0,0 +1,144 @@
+//! pipeline decides how many block requests to keep in flight to a peer.  Enough requests have to
+//! be queued to cover the round trip and keep the peer busy, but every extra request is a block
+//! that waits on this peer instead of a faster one.  The depth follows the peer's measured rate and
+//! latency, so fast peers are kept saturated and slow ones only hold a few blocks.  A peer that
+//! sits on our requests for too long is snubbing us, and only gets one request at a time.
+use crate::piece::BLOCK_SIZE;
+use std::collections::HashMap;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Requests to keep in flight before we know anything about the peer
+const INITIAL_DEPTH: usize = 5;
+
+/// Fewest requests to keep in flight, so there is always another block on its way
+const MIN_DEPTH: usize = 2;
+
+/// Most requests to keep in flight.  This is what we accept from peers ourselves, and less than
+/// other clients accept
+const MAX_DEPTH: usize = 64;
+
+/// How long the measured rate is averaged over before the depth changes
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// On top of the round trip, how many seconds of downloading to keep queued at the peer
+const QUEUE_TIME: f64 = 1.0;
+
+/// How much a new rate measurement counts against the old estimate
+const RATE_WEIGHT: f64 = 0.5;
+
+/// A peer that sends nothing for this long while we wait on it is snubbing us
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct Pipeline {
+    depth: usize,
+    // When each request in flight was sent, by piece and offset
+    in_flight: HashMap<(u32, u32), Instant>,
+    // The quickest a block has come back, which is as close as we get to the round trip time
+    // without the peer's queue in the way
+    min_latency: Option<Duration>,
+    // Smoothed download rate from the peer, in bytes per second
+    rate: Option<f64>,
+    window_start: Instant,
+    window_bytes: u64,
+    // When a block last arrived, or when we started waiting if that was later
+    waiting_since: Instant,
+    snubbed: bool,
+}
+
+impl Pipeline {
+    pub fn new(now: Instant) -> Self {
+        Pipeline {
+            depth: INITIAL_DEPTH,
+            in_flight: HashMap::new(),
+            min_latency: None,
+            rate: None,
+            window_start: now,
+            window_bytes: 0,
+            waiting_since: now,
+            snubbed: false,
+        }
+    }
+
+    /// How many requests are in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// True if more requests should be sent
+    pub fn wants_more(&self) -> bool {
+        let depth = if self.snubbed { 1 } else { self.depth };
+        self.in_flight.len() < depth
+    }
+
+    /// How long we have been waiting on the peer without getting a block.  Zero if we aren't
+    /// waiting for anything
+    pub fn stalled_for(&self, now: Instant) -> Duration {
+        if self.in_flight.is_empty() {
+            return Duration::from_secs(0);
+        }
+        now.saturating_duration_since(self.waiting_since)
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    pub fn set_snubbed(&mut self, snubbed: bool) {
+        self.snubbed = snubbed;
+    }
+
+    pub fn requested(&mut self, index: u32, begin: u32, now: Instant) {
+        if self.in_flight.is_empty() {
+            // Time spent with nothing to download doesn't count against the peer's rate
+            self.window_start = now;
+            self.window_bytes = 0;
+            self.waiting_since = now;
+        }
+        self.in_flight.insert((index, begin), now);
+    }
+
+    /// A block we asked for arrived
+    pub fn received(&mut self, index: u32, begin: u32, length: u32, now: Instant) {
+        let sent = match self.in_flight.remove(&(index, begin)) {
+            Some(sent) => sent,
+            None => return,
+        };
+        self.waiting_since = now;
+        let latency = now.duration_since(sent);
+        self.min_latency = Some(self.min_latency.map_or(latency, |min| min.min(latency)));
+        self.window_bytes += length as u64;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= RATE_WINDOW {
+            let measured = self.window_bytes as f64 / elapsed.as_secs_f64();
+            let rate = self.rate.map_or(measured, |rate| rate + RATE_WEIGHT * (measured - rate));
+            self.rate = Some(rate);
+            self.window_start = now;
+            self.window_bytes = 0;
+            self.adapt(rate);
+        }
+    }
+
+    /// The peer refused a request, or we cancelled it, so it is no longer waited on
+    pub fn rejected(&mut self, index: u32, begin: u32) {
+        self.in_flight.remove(&(index, begin));
+    }
+
+    /// Every request in flight was thrown away, as when the peer chokes us or we move on from
+    /// the piece
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+
+    /// Queue enough blocks to cover the round trip plus a little more at the current rate
+    fn adapt(&mut self, rate: f64) {
+        let latency = self.min_latency.map_or(0.0, |latency| latency.as_secs_f64());
+        let wanted = (rate * (latency + QUEUE_TIME) / BLOCK_SIZE as f64).ceil() as usize;
+        self.depth = wanted.clamp(MIN_DEPTH, MAX_DEPTH);
+    }
+}
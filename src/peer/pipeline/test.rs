@@ -0,0 +1,85 @@
+use super::*;
+
+/// Download `blocks` blocks, keeping the pipeline full, from a peer that sends a block every
+/// `per_block` and takes `round_trip` to answer a request
+fn download(pipeline: &mut Pipeline, start: Instant, blocks: u32, per_block: Duration,
+            round_trip: Duration) -> Instant {
+    let mut now = start;
+    // When the peer will be done sending each block we asked for
+    let mut sending: Vec<(u32, Instant)> = Vec::new();
+    let mut next = 0;
+    let mut free_at = start;
+    for _ in 0..blocks {
+        while pipeline.wants_more() {
+            pipeline.requested(0, next * BLOCK_SIZE, now);
+            let arrives = (now + round_trip).max(free_at + per_block);
+            free_at = arrives;
+            sending.push((next, arrives));
+            next += 1;
+        }
+        let (block, arrives) = sending.remove(0);
+        now = arrives;
+        pipeline.received(0, block * BLOCK_SIZE, BLOCK_SIZE, now);
+    }
+    now
+}
+
+#[test]
+fn test_fast_peer_gets_deeper_pipeline() {
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new(start);
+    assert_eq!(pipeline.depth, INITIAL_DEPTH);
+    // 4MiB/s with a 100ms round trip needs about 1.1s worth of blocks in flight
+    download(&mut pipeline, start, 2000, Duration::from_micros(3906), Duration::from_millis(100));
+    assert!(pipeline.depth > 40, "depth {}", pipeline.depth);
+}
+
+#[test]
+fn test_slow_peer_gets_shallow_pipeline() {
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new(start);
+    // 16KiB/s is a block a second
+    download(&mut pipeline, start, 20, Duration::from_secs(1), Duration::from_millis(50));
+    assert_eq!(pipeline.depth, MIN_DEPTH);
+}
+
+#[test]
+fn test_unrequested_blocks_are_ignored() {
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new(start);
+    pipeline.requested(3, 0, start);
+    pipeline.received(3, BLOCK_SIZE, BLOCK_SIZE, start + Duration::from_secs(2));
+    assert_eq!(pipeline.in_flight(), 1);
+    pipeline.clear();
+    assert_eq!(pipeline.in_flight(), 0);
+    pipeline.received(3, 0, BLOCK_SIZE, start + Duration::from_secs(2));
+    assert_eq!(pipeline.depth, INITIAL_DEPTH);
+}
+
+#[test]
+fn test_rejected_requests_are_not_waited_on() {
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new(start);
+    pipeline.requested(3, 0, start);
+    pipeline.requested(3, BLOCK_SIZE, start);
+    pipeline.rejected(3, 0);
+    assert_eq!(pipeline.in_flight(), 1);
+}
+
+#[test]
+fn test_stalled() {
+    let start = Instant::now();
+    let mut pipeline = Pipeline::new(start);
+    let later = start + Duration::from_secs(90);
+    assert_eq!(pipeline.stalled_for(later), Duration::from_secs(0));
+    pipeline.requested(0, 0, start + Duration::from_secs(30));
+    pipeline.requested(0, BLOCK_SIZE, start + Duration::from_secs(40));
+    assert_eq!(pipeline.stalled_for(later), Duration::from_secs(60));
+    pipeline.received(0, 0, BLOCK_SIZE, start + Duration::from_secs(80));
+    assert_eq!(pipeline.stalled_for(later), Duration::from_secs(10));
+
+    pipeline.set_snubbed(true);
+    assert!(!pipeline.wants_more());
+    pipeline.received(0, BLOCK_SIZE, BLOCK_SIZE, later);
+    assert!(pipeline.wants_more());
+}
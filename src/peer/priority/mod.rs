@@ -0,0 +1,89 @@
+//! Canonical peer priority as described in BEP 40.  When there are more candidate peers than
+//! connection slots, every client in the swarm should prefer the same pairs so the swarm stays
+//! well connected instead of forming cliques.
+use std::cmp::{
+    Ordering,
+    Reverse,
+};
+use std::net::{
+    IpAddr,
+    SocketAddr,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Computes the canonical priority of a connection between our address and a peer's address.
+/// Higher values should be preferred.
+pub fn priority(own: SocketAddr, peer: SocketAddr) -> u32 {
+    if own.ip() == peer.ip() {
+        // Same host, so the ports are the only thing that can tell the two apart
+        let (low, high) = sorted(own.port().to_be_bytes(), peer.port().to_be_bytes());
+        let mut bytes = Vec::with_capacity(4);
+        bytes.extend_from_slice(&low);
+        bytes.extend_from_slice(&high);
+        return crc32c(&bytes);
+    }
+
+    let (own_bytes, peer_bytes, base) = match (own.ip(), peer.ip()) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => (a.octets().to_vec(), b.octets().to_vec(), 2),
+        (IpAddr::V6(a), IpAddr::V6(b)) => (a.octets().to_vec(), b.octets().to_vec(), 6),
+        // Mixed families, compare them both as IPv6
+        (a, b) => (to_v6_octets(a), to_v6_octets(b), 6),
+    };
+
+    let mask = mask(&own_bytes, &peer_bytes, base);
+    let own_masked: Vec<u8> = own_bytes.iter().zip(mask.iter()).map(|(b, m)| b & m).collect();
+    let peer_masked: Vec<u8> = peer_bytes.iter().zip(mask.iter()).map(|(b, m)| b & m).collect();
+
+    let (low, high) = sorted(own_masked, peer_masked);
+    let mut bytes = low;
+    bytes.extend_from_slice(&high);
+    crc32c(&bytes)
+}
+
+/// Sorts the candidate peers so that the highest priority connections come first.  Peers of
+/// equal priority keep their order
+pub fn rank(own: SocketAddr, peers: &mut [SocketAddr]) {
+    peers.sort_by_cached_key(|peer| Reverse(priority(own, *peer)));
+}
+
+/// Builds the mask for two addresses.  `base` is the number of leading bytes that are always kept
+/// in full.  Each further byte is kept in full if the addresses share every byte before it, up to
+/// two extra bytes.  Anything else is masked with 0x55.
+fn mask(a: &[u8], b: &[u8], base: usize) -> Vec<u8> {
+    let common = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let full = if common >= base {
+        (common + 1).min(base + 2).min(a.len())
+    } else {
+        base
+    };
+
+    (0..a.len()).map(|i| if i < full { 0xFF } else { 0x55 }).collect()
+}
+
+fn to_v6_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+fn sorted<T: Ord>(a: T, b: T) -> (T, T) {
+    match a.cmp(&b) {
+        Ordering::Greater => (b, a),
+        _ => (a, b),
+    }
+}
+
+/// CRC32-C (Castagnoli), the checksum BEP 40 specifies for the priority
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,41 @@
+use super::*;
+
+#[test]
+fn test_crc32c() {
+    assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+}
+
+#[test]
+fn test_priority_different_networks() {
+    let own: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let peer: SocketAddr = "98.76.54.32:6881".parse().unwrap();
+    assert_eq!(priority(own, peer), 0xec2d_7224);
+    // The priority must be the same from both ends of the connection
+    assert_eq!(priority(peer, own), 0xec2d_7224);
+}
+
+#[test]
+fn test_priority_same_slash_24() {
+    let own: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let peer: SocketAddr = "123.213.32.234:6881".parse().unwrap();
+    assert_eq!(priority(own, peer), 0x9956_8189);
+}
+
+#[test]
+fn test_priority_same_ip() {
+    let own: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let peer: SocketAddr = "123.213.32.10:6882".parse().unwrap();
+    assert_eq!(priority(own, peer), crc32c(&[0x1a, 0xe1, 0x1a, 0xe2]));
+    assert_eq!(priority(own, peer), priority(peer, own));
+}
+
+#[test]
+fn test_rank() {
+    let own: SocketAddr = "123.213.32.10:6881".parse().unwrap();
+    let mut peers: Vec<SocketAddr> = vec![
+        "123.213.32.234:6881".parse().unwrap(),
+        "98.76.54.32:6881".parse().unwrap(),
+    ];
+    rank(own, &mut peers);
+    assert_eq!(peers[0], "98.76.54.32:6881".parse().unwrap());
+}
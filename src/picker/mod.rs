@@ -0,0 +1,252 @@
+//! picker decides which piece to download next.  Pieces of higher priority files go first, and
+//! within a priority pieces are picked rarest first, so that pieces few peers have get spread
+//! around before those peers leave.  In sequential mode pieces come in order instead, so media
+//! can be played while it downloads.
+use crate::storage::FileEntry;
+use bit_vec::BitVec;
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+#[cfg(test)]
+mod test;
+
+/// With disk locality on, a peer keeps getting the piece after its last one as long as that piece
+/// is at most this many peers more common than the rarest piece it could get instead
+const LOCALITY_SLACK: u32 = 1;
+
+/// In sequential mode, how many of the next missing pieces are picked from rarest first.  A little
+/// slack keeps every peer busy without getting far ahead of playback
+const SEQUENTIAL_WINDOW: usize = 8;
+
+/// How much the user wants a file.  Skipped files aren't downloaded at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Skip => "skip",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("Invalid priority: {}", s)),
+        }
+    }
+}
+
+/// The priority of each piece, given the priority of each file.  A piece gets the highest
+/// priority of the files it holds data for, so a piece is only skipped if every file in it is.
+/// Padding files don't count, since they hold no data.  Files without a priority are normal
+pub fn piece_priorities(files: &[FileEntry], piece_length: u64, num_pieces: usize,
+                        file_priorities: &[Priority]) -> Vec<Priority> {
+    let mut res = vec![Priority::Skip; num_pieces];
+    for (i, file) in files.iter().enumerate() {
+        if file.is_padding() || file.length == 0 {
+            continue;
+        }
+        let priority = file_priorities.get(i).cloned().unwrap_or_default();
+        let first = (file.offset / piece_length) as usize;
+        let last = ((file.offset + file.length - 1) / piece_length) as usize;
+        for piece in res.iter_mut().take(last + 1).skip(first) {
+            *piece = (*piece).max(priority);
+        }
+    }
+    res
+}
+
+pub struct Picker {
+    // How many connected peers have each piece
+    availability: Vec<u32>,
+    // Pieces we have verified
+    have: BitVec,
+    // Pieces someone is downloading
+    in_progress: BitVec,
+    // How much each piece is wanted
+    priorities: Vec<Priority>,
+    // If true, prefer giving each peer contiguous runs of pieces so writes are more sequential
+    disk_locality: bool,
+    // If true, download pieces in order for streaming
+    sequential: bool,
+}
+
+impl Picker {
+    pub fn new(num_pieces: usize, disk_locality: bool) -> Self {
+        Picker {
+            availability: vec![0; num_pieces],
+            have: BitVec::from_elem(num_pieces, false),
+            in_progress: BitVec::from_elem(num_pieces, false),
+            priorities: vec![Priority::Normal; num_pieces],
+            disk_locality,
+            sequential: false,
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    /// Set how much each piece is wanted, as worked out by `piece_priorities`
+    pub fn set_priorities(&mut self, priorities: Vec<Priority>) {
+        self.priorities = priorities;
+    }
+
+    /// Download pieces in order, picking rarest first only among the next few missing pieces
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Whether we want to download a piece at all
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.priorities.get(index).is_some_and(|priority| *priority != Priority::Skip)
+    }
+
+    /// A peer told us every piece it has
+    pub fn add_peer(&mut self, pieces: &BitVec) {
+        for (i, has) in pieces.iter().enumerate().take(self.availability.len()) {
+            if has {
+                self.availability[i] += 1;
+            }
+        }
+    }
+
+    /// A peer with these pieces went away
+    pub fn remove_peer(&mut self, pieces: &BitVec) {
+        for (i, has) in pieces.iter().enumerate().take(self.availability.len()) {
+            if has {
+                self.availability[i] = self.availability[i].saturating_sub(1);
+            }
+        }
+    }
+
+    /// A peer got a new piece
+    pub fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Pick the next piece to download from a peer that has `peer_pieces`.  `last_piece` is the
+    /// last piece handed to this peer, used to keep its pieces together when disk locality is on.
+    pub fn pick(&self, peer_pieces: &BitVec, last_piece: Option<u32>) -> Option<u32> {
+        let wanted = |i: usize| {
+            peer_pieces.get(i).unwrap_or(false)
+                && self.is_wanted(i)
+                && !self.have.get(i).unwrap_or(true)
+                && !self.in_progress.get(i).unwrap_or(true)
+        };
+
+        if self.sequential {
+            return self.pick_sequential(wanted);
+        }
+
+        let rarest = (0..self.availability.len())
+            .filter(|i| wanted(*i))
+            .min_by_key(|i| (Reverse(self.priorities[*i]), self.availability[*i]))?;
+
+        if self.disk_locality {
+            if let Some(next) = last_piece.map(|l| l as usize + 1) {
+                if next < self.availability.len() && wanted(next)
+                    && self.priorities[next] >= self.priorities[rarest]
+                    && self.availability[next] <= self.availability[rarest] + LOCALITY_SLACK {
+                    return Some(next as u32);
+                }
+            }
+        }
+
+        Some(rarest as u32)
+    }
+
+    /// Pick the rarest piece in the window of the next missing pieces, or the first piece the peer
+    /// can give us past the window.  Pieces being downloaded stay in the window until they finish,
+    /// so a slow peer holds the window back instead of letting it run ahead
+    fn pick_sequential<F: Fn(usize) -> bool>(&self, wanted: F) -> Option<u32> {
+        let mut missing = (0..self.availability.len())
+            .filter(|i| self.is_wanted(*i) && !self.have.get(*i).unwrap_or(true));
+        let window: Vec<usize> = missing.by_ref().take(SEQUENTIAL_WINDOW).collect();
+        window.into_iter()
+            .filter(|i| wanted(*i))
+            .min_by_key(|i| (self.availability[*i], *i))
+            .or_else(|| missing.find(|i| wanted(*i)))
+            .map(|i| i as u32)
+    }
+
+    /// Whether a peer with `peer_pieces` has anything we still need, even if someone else is
+    /// downloading it right now
+    pub fn interesting(&self, peer_pieces: &BitVec) -> bool {
+        peer_pieces.iter().zip(self.have.iter()).enumerate()
+            .any(|(i, (theirs, ours))| theirs && !ours && self.is_wanted(i))
+    }
+
+    /// Pieces a peer with `peer_pieces` has that someone is already downloading.  In endgame these
+    /// are downloaded from more than one peer at once
+    pub fn in_progress(&self, peer_pieces: &BitVec) -> Vec<u32> {
+        self.in_progress.iter().enumerate()
+            .filter(|(i, started)| *started && peer_pieces.get(*i).unwrap_or(false))
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Whether every piece we are missing is being downloaded by someone
+    pub fn all_started(&self) -> bool {
+        self.have.iter().zip(self.in_progress.iter()).enumerate()
+            .all(|(i, (have, started))| have || started || !self.is_wanted(i))
+    }
+
+    /// Someone started downloading a piece
+    pub fn start(&mut self, index: u32) {
+        self.in_progress.set(index as usize, true);
+    }
+
+    /// A download was given up on, so the piece can be picked again
+    pub fn abandon(&mut self, index: u32) {
+        self.in_progress.set(index as usize, false);
+    }
+
+    /// A piece was downloaded and verified
+    pub fn finish(&mut self, index: u32) {
+        self.in_progress.set(index as usize, false);
+        self.have.set(index as usize, true);
+    }
+
+    /// The pieces we have
+    pub fn have(&self) -> &BitVec {
+        &self.have
+    }
+
+    /// Replace the pieces we have, after a recheck of the data on disk
+    pub fn set_have(&mut self, have: BitVec) {
+        self.have = have;
+    }
+
+    /// Whether every piece has been downloaded
+    pub fn is_complete(&self) -> bool {
+        self.have.all()
+    }
+
+    /// The wanted pieces we don't have yet
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.have.len())
+            .filter(|i| self.is_wanted(*i) && !self.have.get(*i).unwrap_or(true))
+            .map(|i| i as u32)
+            .collect()
+    }
+}
@@ -0,0 +1,140 @@
+use super::*;
+
+fn bits(bits: &[bool]) -> BitVec {
+    let mut res = BitVec::from_elem(bits.len(), false);
+    for (i, bit) in bits.iter().enumerate() {
+        res.set(i, *bit);
+    }
+    res
+}
+
+#[test]
+fn test_rarest_first() {
+    let mut picker = Picker::new(3, false);
+    picker.add_peer(&bits(&[true, true, true]));
+    picker.add_peer(&bits(&[true, false, true]));
+    picker.add_peer(&bits(&[true, false, false]));
+    assert_eq!(picker.pick(&bits(&[true, true, true]), None), Some(1));
+}
+
+#[test]
+fn test_skips_in_progress_and_finished() {
+    let mut picker = Picker::new(3, false);
+    picker.add_peer(&bits(&[true, true, true]));
+    picker.start(0);
+    picker.finish(1);
+    assert_eq!(picker.pick(&bits(&[true, true, true]), None), Some(2));
+    picker.start(2);
+    assert_eq!(picker.pick(&bits(&[true, true, true]), None), None);
+    picker.abandon(0);
+    assert_eq!(picker.pick(&bits(&[true, true, true]), None), Some(0));
+}
+
+#[test]
+fn test_disk_locality_continues_run() {
+    let all = bits(&[true, true, true, true]);
+    for locality in &[false, true] {
+        let mut picker = Picker::new(4, *locality);
+        picker.add_peer(&all);
+        picker.add_peer(&bits(&[true, true, true, false]));
+        picker.add_peer(&bits(&[true, false, false, false]));
+        // availability is [3, 2, 2, 1], so piece 3 is the rarest but piece 2 is close
+        picker.start(1);
+        let expected = if *locality { Some(2) } else { Some(3) };
+        assert_eq!(picker.pick(&all, Some(1)), expected);
+    }
+}
+
+#[test]
+fn test_disk_locality_stays_rarest_first() {
+    let mut picker = Picker::new(3, true);
+    let all = bits(&[true, true, true]);
+    picker.add_peer(&all);
+    picker.add_peer(&all);
+    picker.add_peer(&all);
+    picker.add_peer(&bits(&[true, true, false]));
+    picker.remove_peer(&bits(&[false, false, true]));
+    picker.remove_peer(&bits(&[false, false, true]));
+    // availability is [4, 4, 1], so piece 1 is too common to continue the run from piece 0
+    picker.start(0);
+    assert_eq!(picker.pick(&all, Some(0)), Some(2));
+}
+
+#[test]
+fn test_interesting() {
+    let mut picker = Picker::new(2, false);
+    picker.finish(0);
+    picker.start(1);
+    // Piece 1 is taken, but it's still something the peer could give us
+    assert!(picker.interesting(&bits(&[true, true])));
+    assert!(!picker.interesting(&bits(&[true, false])));
+}
+
+#[test]
+fn test_endgame_candidates() {
+    let mut picker = Picker::new(3, false);
+    picker.start(0);
+    picker.finish(1);
+    assert!(!picker.all_started());
+    picker.start(2);
+    assert!(picker.all_started());
+    assert_eq!(picker.in_progress(&bits(&[true, true, true])), vec![0, 2]);
+    assert_eq!(picker.in_progress(&bits(&[false, true, true])), vec![2]);
+}
+
+#[test]
+fn test_priorities() {
+    let all = bits(&[true, true, true]);
+    let mut picker = Picker::new(3, false);
+    picker.add_peer(&all);
+    picker.add_peer(&bits(&[true, false, false]));
+    picker.set_priorities(vec![Priority::High, Priority::Normal, Priority::Skip]);
+    // Piece 0 is the most common, but it is also the most wanted
+    assert_eq!(picker.pick(&all, None), Some(0));
+    picker.finish(0);
+    assert_eq!(picker.pick(&all, None), Some(1));
+    picker.finish(1);
+    assert_eq!(picker.pick(&all, None), None);
+    assert!(!picker.interesting(&all));
+    assert!(picker.all_started());
+    assert!(picker.missing().is_empty());
+}
+
+#[test]
+fn test_piece_priorities() {
+    let file = |torrent_path: &str, length, offset| FileEntry {
+        torrent_path: torrent_path.to_string(),
+        length,
+        offset,
+    };
+    let files = vec![
+        file("a", 10, 0),
+        file(".pad/6", 6, 10),
+        file("b", 20, 16),
+        file("c", 12, 36),
+    ];
+    let priorities = piece_priorities(&files, 16, 3, &[Priority::Low, Priority::Skip, Priority::Skip]);
+    // Padding doesn't make piece 0 skipped, and c shares piece 2 with b
+    assert_eq!(priorities, vec![Priority::Low, Priority::Skip, Priority::Normal]);
+}
+
+#[test]
+fn test_sequential() {
+    let all = bits(&[true; 12]);
+    let mut picker = Picker::new(12, false);
+    picker.set_sequential(true);
+    picker.add_peer(&all);
+    picker.add_peer(&bits(&[true, true, true, false, true, true, true, true, true, true, true, false]));
+    // Piece 3 is the rarest in the window
+    assert_eq!(picker.pick(&all, None), Some(3));
+    picker.start(3);
+    assert_eq!(picker.pick(&all, None), Some(0));
+    // Piece 11 is rarer, but it is past the window
+    for i in 0..8 {
+        picker.start(i);
+    }
+    assert_eq!(picker.pick(&all, None), Some(8));
+    // A peer with nothing in the window gets the first piece it has after it
+    let late = bits(&[false, false, false, false, false, false, false, false, false, false, true, true]);
+    assert_eq!(picker.pick(&late, None), Some(10));
+}
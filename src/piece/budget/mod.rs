@@ -0,0 +1,79 @@
+//! Accounting for the memory held by pieces that are still being downloaded or are waiting to be
+//! written to disk.  When the budget is used up no new pieces should be started, which stops us
+//! from buffering the whole torrent in memory when the disk is slower than the network.
+use std::sync::{
+    Arc,
+    atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+
+#[cfg(test)]
+mod test;
+
+/// A shared limit on in-flight piece data.  Clones share the same budget
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    used: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+/// Memory taken out of a budget.  It is given back when this is dropped
+#[derive(Debug)]
+pub struct Reservation {
+    used: Arc<AtomicUsize>,
+    size: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            used: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    /// Reserve `size` bytes, or None if that would go over the limit
+    pub fn try_reserve(&self, size: usize) -> Option<Reservation> {
+        let mut used = self.used.load(Ordering::SeqCst);
+        loop {
+            if used + size > self.limit {
+                return None;
+            }
+            match self.used.compare_exchange(used, used + size, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Reservation {
+                    used: self.used.clone(),
+                    size,
+                }),
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// Bytes currently reserved
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Whether a new piece of `size` bytes would fit right now
+    pub fn has_room(&self, size: usize) -> bool {
+        self.used() + size <= self.limit
+    }
+}
+
+impl Reservation {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.used.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
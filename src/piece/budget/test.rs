@@ -0,0 +1,21 @@
+use super::*;
+
+#[test]
+fn test_reserve_and_release() {
+    let budget = MemoryBudget::new(100);
+    let first = budget.try_reserve(60).expect("should fit");
+    assert!(budget.try_reserve(60).is_none());
+    assert!(budget.has_room(40));
+
+    drop(first);
+    assert_eq!(budget.used(), 0);
+    assert!(budget.try_reserve(100).is_some());
+}
+
+#[test]
+fn test_clones_share_budget() {
+    let budget = MemoryBudget::new(100);
+    let other = budget.clone();
+    let _reservation = other.try_reserve(100).unwrap();
+    assert!(!budget.has_room(1));
+}
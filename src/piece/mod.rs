@@ -3,35 +3,282 @@ use crypto::{
     sha1::Sha1
 };
 use bit_vec::BitVec;
+use bytes::Bytes;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+use std::task::{
+    Context,
+    Waker,
+};
+
+pub mod budget;
+#[cfg(test)]
+mod test;
+
+use self::budget::{
+    MemoryBudget,
+    Reservation,
+};
+
+/// Peers won't answer requests for more than this many bytes
+pub const BLOCK_SIZE: u32 = 1 << 14;
 
-/// Holds the data of a downloaded piece
+/// The blocks of a piece that have arrived, shared by every copy of the piece.  In endgame more
+/// than one peer downloads the same piece, and this lets each copy skip the blocks another copy
+/// already has, and cancel its own requests for them
+#[derive(Clone)]
+pub struct Arrivals(Arc<Mutex<SharedBlocks>>);
+
+struct SharedBlocks {
+    // Each block that has arrived, with the peer that sent it
+    blocks: Vec<Option<(Bytes, Option<usize>)>>,
+    // How many of them have arrived
+    arrived: usize,
+    // How many copies there are.  Each copy is numbered by the order it was made in
+    copies: usize,
+    // Tasks of the copies that want to hear when a block arrives, by copy
+    waiting: Vec<(usize, Waker)>,
+}
+
+impl Arrivals {
+    fn new(num_blocks: usize) -> Self {
+        Arrivals(Arc::new(Mutex::new(SharedBlocks {
+            blocks: vec![None; num_blocks],
+            arrived: 0,
+            copies: 1,
+            waiting: Vec::new(),
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SharedBlocks> {
+        // Nothing can panic while the lock is held, but if something did the blocks are still fine
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Holds the data of a downloaded piece.  Blocks are kept as they came off the wire, so they are
+/// never copied on their way to disk
 pub struct Piece {
-    data: Vec<u8>,
+    index: u32,
+    size: u32,
+    blocks: Vec<Option<Bytes>>,
+    // The memory this piece holds, returned to the budget when the piece is dropped
+    reservation: Option<Reservation>,
     hasher: Sha1,
     hash: [u8; 20],
     // Pieces can be arbitrarily sized, but requests can be no larger than 16k.  This keeps track
     // of which pieces of the larger piece we have collected
-    sub_pieces: BitVec
+    sub_pieces: BitVec,
+    // Which blocks have been asked for but not received
+    requested: BitVec,
+    // The peer that sent each block, so bad data can be blamed on someone
+    sources: Vec<Option<usize>>,
+    // Blocks that arrived for any copy of this piece, and how many of them this copy has seen
+    arrivals: Arrivals,
+    seen: usize,
+    // Which copy of the piece this is
+    copy: usize,
 }
 
 impl Piece {
-    pub fn new(piece_size: u32, piece_hash: [u8;20]) -> Self {
-        let mut num_subpieces = piece_size / (1 << 14);
-        num_subpieces += if piece_size % (1 << 14) == 0 { 0 } else { 1 };
+    pub fn new(index: u32, piece_size: u32, piece_hash: [u8;20]) -> Self {
+        let num_subpieces = piece_size.div_ceil(BLOCK_SIZE);
         Piece {
-            data: Vec::with_capacity(piece_size as usize),
+            index,
+            size: piece_size,
+            blocks: vec![None; num_subpieces as usize],
+            reservation: None,
             hasher: Sha1::new(),
             hash: piece_hash,
-            sub_pieces: BitVec::from_elem(num_subpieces as usize, false)
+            sub_pieces: BitVec::from_elem(num_subpieces as usize, false),
+            requested: BitVec::from_elem(num_subpieces as usize, false),
+            sources: vec![None; num_subpieces as usize],
+            arrivals: Arrivals::new(num_subpieces as usize),
+            seen: 0,
+            copy: 0,
+        }
+    }
+
+    /// Create a new piece whose memory counts against `budget`.  Returns None if the budget is
+    /// full, in which case no more blocks should be requested until some pieces are written out.
+    pub fn with_budget(index: u32, piece_size: u32, piece_hash: [u8; 20], budget: &MemoryBudget) -> Option<Self> {
+        let reservation = budget.try_reserve(piece_size as usize)?;
+        let mut piece = Piece::new(index, piece_size, piece_hash);
+        piece.reservation = Some(reservation);
+        Some(piece)
+    }
+
+    /// Make this piece a copy of one another peer is downloading, which happens in endgame.  The
+    /// blocks that already arrived are taken in, so only the missing ones are asked for
+    pub fn share(mut self, arrivals: Arrivals) -> Self {
+        let mut shared = arrivals.lock();
+        if shared.blocks.len() == self.blocks.len() {
+            self.take_in_arrivals(&mut shared);
+            self.copy = shared.copies;
+            shared.copies += 1;
+            drop(shared);
+            self.arrivals = arrivals;
         }
+        self
+    }
+
+    /// What every copy of this piece shares, for making more copies
+    pub fn arrivals(&self) -> Arrivals {
+        self.arrivals.clone()
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// How many bytes the piece holds once it is complete
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The blocks that have arrived, in order.  Once the piece is complete they are its data
+    pub fn blocks(&self) -> Vec<&[u8]> {
+        self.blocks.iter().flatten().map(|block| block.as_ref()).collect()
+    }
+
+    /// The next block to ask for, as (begin, length).  The block is marked as requested
+    pub fn next_request(&mut self) -> Option<(u32, u32)> {
+        let shared = self.arrivals.lock();
+        let block = (0..self.sub_pieces.len())
+            .find(|&i| !self.sub_pieces[i] && !self.requested[i] && shared.blocks[i].is_none())?;
+        drop(shared);
+        self.requested.set(block, true);
+        Some(self.block_span(block))
+    }
+
+    /// Requests we have sent are lost, for example because the peer choked us, so every missing
+    /// block has to be asked for again
+    pub fn reset_requests(&mut self) {
+        self.requested.clear();
+    }
+
+    /// The peer refused to send a block, so it has to be asked for again
+    pub fn reject_request(&mut self, begin: u32) {
+        let index = (begin / BLOCK_SIZE) as usize;
+        if begin.is_multiple_of(BLOCK_SIZE) && index < self.requested.len() {
+            self.requested.set(index, false);
+        }
+    }
+
+    /// Store a block the peer sent.  Returns false if it isn't a block of this piece
+    pub fn add_block(&mut self, begin: u32, block: Bytes) -> bool {
+        self.store(begin, block, None)
+    }
+
+    /// Store a block sent by the peer with the server's id `peer`
+    pub fn add_block_from(&mut self, peer: usize, begin: u32, block: Bytes) -> bool {
+        self.store(begin, block, Some(peer))
+    }
+
+    /// Take in the blocks other copies of this piece got first.  Returns the ones this copy asked
+    /// for, as (begin, length), so the requests can be cancelled.  While blocks are missing, the
+    /// task is woken whenever another copy gets one
+    pub fn catch_up(&mut self, cx: &mut Context<'_>) -> Vec<(u32, u32)> {
+        let arrivals = self.arrivals.clone();
+        let mut shared = arrivals.lock();
+        let cancelled = self.take_in_arrivals(&mut shared);
+        // Only copies handed out in endgame share their arrivals with another copy
+        if !self.sub_pieces.all() && Arc::strong_count(&self.arrivals.0) > 1 {
+            let copy = self.copy;
+            shared.waiting.retain(|(waiting, _)| *waiting != copy);
+            shared.waiting.push((copy, cx.waker().clone()));
+        }
+        drop(shared);
+        cancelled.into_iter().map(|i| self.block_span(i)).collect()
+    }
+
+    /// Every peer that sent blocks of this piece, with how many blocks it sent
+    pub fn sources(&self) -> Vec<(usize, u32)> {
+        let mut sources: Vec<(usize, u32)> = Vec::new();
+        for peer in self.sources.iter().flatten() {
+            match sources.iter_mut().find(|(source, _)| source == peer) {
+                Some((_, blocks)) => *blocks += 1,
+                None => sources.push((*peer, 1)),
+            }
+        }
+        sources
+    }
+
+    /// Blocks that were asked for and haven't arrived, as (begin, length)
+    pub fn outstanding_requests(&self) -> Vec<(u32, u32)> {
+        (0..self.sub_pieces.len())
+            .filter(|&i| self.requested[i] && !self.sub_pieces[i])
+            .map(|i| self.block_span(i))
+            .collect()
+    }
+
+    /// Whether every block has arrived
+    pub fn is_complete(&self) -> bool {
+        self.sub_pieces.all()
     }
 
     pub fn verify(&mut self) -> bool {
         self.hasher.reset();
-        self.hasher.input(&self.data);
+        for block in self.blocks.iter().flatten() {
+            self.hasher.input(block);
+        }
         let mut data_hash: [u8;20] = [0;20];
         self.hasher.result(&mut data_hash);
         return data_hash == self.hash;
     }
 
-}
\ No newline at end of file
+    /// Copy in the blocks that arrived for other copies.  Returns the ones this copy asked for
+    fn take_in_arrivals(&mut self, shared: &mut SharedBlocks) -> Vec<usize> {
+        let mut cancelled = Vec::new();
+        let news = if shared.arrived > self.seen { shared.blocks.as_slice() } else { &[] };
+        for (i, arrived) in news.iter().enumerate() {
+            if let (Some((block, source)), false) = (arrived, self.sub_pieces[i]) {
+                if self.requested[i] {
+                    cancelled.push(i);
+                }
+                self.blocks[i] = Some(block.clone());
+                self.sources[i] = *source;
+                self.sub_pieces.set(i, true);
+                self.requested.set(i, false);
+            }
+        }
+        self.seen = shared.arrived;
+        cancelled
+    }
+
+    fn store(&mut self, begin: u32, block: Bytes, source: Option<usize>) -> bool {
+        if !begin.is_multiple_of(BLOCK_SIZE) {
+            return false;
+        }
+        let index = (begin / BLOCK_SIZE) as usize;
+        if index >= self.sub_pieces.len() || self.block_span(index).1 as usize != block.len() {
+            return false;
+        }
+        let mut shared = self.arrivals.lock();
+        if shared.blocks[index].is_none() {
+            shared.blocks[index] = Some((block.clone(), source));
+            shared.arrived += 1;
+            // Let the other copies cancel their requests for the block
+            let copy = self.copy;
+            shared.waiting.retain(|(waiting, waker)| *waiting == copy || {
+                waker.wake_by_ref();
+                false
+            });
+        }
+        drop(shared);
+        self.blocks[index] = Some(block);
+        self.sources[index] = source;
+        self.sub_pieces.set(index, true);
+        self.requested.set(index, false);
+        true
+    }
+
+    /// Where a block starts and how long it is.  The last block may be short
+    fn block_span(&self, block: usize) -> (u32, u32) {
+        let begin = block as u32 * BLOCK_SIZE;
+        (begin, BLOCK_SIZE.min(self.size - begin))
+    }
+}
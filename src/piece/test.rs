@@ -0,0 +1,107 @@
+use futures::task::noop_waker;
+use super::*;
+
+#[test]
+fn test_requests_cover_piece() {
+    let mut piece = Piece::new(0, BLOCK_SIZE * 2 + 100, [0; 20]);
+    assert_eq!(piece.next_request(), Some((0, BLOCK_SIZE)));
+    assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+    assert_eq!(piece.next_request(), Some((BLOCK_SIZE * 2, 100)));
+    assert_eq!(piece.next_request(), None);
+}
+
+#[test]
+fn test_reset_requests() {
+    let mut piece = Piece::new(0, BLOCK_SIZE * 2, [0; 20]);
+    piece.next_request();
+    piece.next_request();
+    assert!(piece.add_block(0, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+    piece.reset_requests();
+    // Only the block that never arrived is asked for again
+    assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+    assert_eq!(piece.next_request(), None);
+}
+
+#[test]
+fn test_reject_request() {
+    let mut piece = Piece::new(0, BLOCK_SIZE * 2, [0; 20]);
+    piece.next_request();
+    piece.next_request();
+    piece.reject_request(BLOCK_SIZE);
+    piece.reject_request(1);
+    piece.reject_request(BLOCK_SIZE * 2);
+    assert_eq!(piece.outstanding_requests(), vec![(0, BLOCK_SIZE)]);
+    assert_eq!(piece.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+}
+
+#[test]
+fn test_reassemble_out_of_order() {
+    let data: Vec<u8> = (0..BLOCK_SIZE + 10).map(|i| i as u8).collect();
+    let mut hasher = Sha1::new();
+    hasher.input(&data);
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+
+    let mut piece = Piece::new(3, data.len() as u32, hash);
+    assert!(piece.add_block(BLOCK_SIZE, Bytes::copy_from_slice(&data[BLOCK_SIZE as usize..])));
+    assert!(!piece.is_complete());
+    assert!(piece.add_block(0, Bytes::copy_from_slice(&data[..BLOCK_SIZE as usize])));
+    assert!(piece.is_complete());
+    assert!(piece.verify());
+    assert_eq!(piece.blocks().concat(), data);
+}
+
+#[test]
+fn test_reject_bad_blocks() {
+    let mut piece = Piece::new(0, BLOCK_SIZE + 10, [0; 20]);
+    assert!(!piece.add_block(1, Bytes::from(vec![0; 10])));
+    assert!(!piece.add_block(BLOCK_SIZE, Bytes::from(vec![0; 11])));
+    assert!(!piece.add_block(BLOCK_SIZE * 2, Bytes::from(vec![0; 10])));
+    assert!(!piece.is_complete());
+}
+
+#[test]
+fn test_outstanding_requests() {
+    let mut piece = Piece::new(0, BLOCK_SIZE * 3, [0; 20]);
+    piece.next_request();
+    piece.next_request();
+    assert!(piece.add_block(0, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+    assert_eq!(piece.outstanding_requests(), vec![(BLOCK_SIZE, BLOCK_SIZE)]);
+}
+
+#[test]
+fn test_sources() {
+    let mut piece = Piece::new(0, BLOCK_SIZE * 3, [0; 20]);
+    assert!(piece.add_block_from(7, 0, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+    assert!(piece.add_block_from(2, BLOCK_SIZE, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+    assert!(piece.add_block_from(7, BLOCK_SIZE * 2, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+    assert!(!piece.add_block_from(3, 1, Bytes::from(vec![1; 10])));
+    assert_eq!(piece.sources(), vec![(7, 2), (2, 1)]);
+}
+
+#[test]
+fn test_copies_only_ask_for_missing_blocks() {
+    let waker = noop_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut first = Piece::new(0, BLOCK_SIZE * 3, [0; 20]);
+    first.next_request();
+    first.next_request();
+    assert!(first.add_block_from(1, 0, Bytes::from(vec![1; BLOCK_SIZE as usize])));
+
+    // The copy skips the block that already arrived, and asks for the one still on its way
+    let mut second = Piece::new(0, BLOCK_SIZE * 3, [0; 20]).share(first.arrivals());
+    assert_eq!(second.next_request(), Some((BLOCK_SIZE, BLOCK_SIZE)));
+    assert_eq!(second.sources(), vec![(1, 1)]);
+    assert!(second.add_block_from(2, BLOCK_SIZE, Bytes::from(vec![2; BLOCK_SIZE as usize])));
+
+    // The first copy takes the block in and cancels its own request for it
+    assert_eq!(first.catch_up(&mut cx), vec![(BLOCK_SIZE, BLOCK_SIZE)]);
+    assert_eq!(first.outstanding_requests(), vec![]);
+    assert_eq!(first.catch_up(&mut cx), vec![]);
+    assert_eq!(first.next_request(), Some((BLOCK_SIZE * 2, BLOCK_SIZE)));
+    assert!(second.add_block(BLOCK_SIZE * 2, Bytes::from(vec![3; BLOCK_SIZE as usize])));
+    assert!(second.is_complete());
+    assert_eq!(first.catch_up(&mut cx), vec![(BLOCK_SIZE * 2, BLOCK_SIZE)]);
+    assert!(first.is_complete());
+    assert_eq!(first.blocks().concat(), second.blocks().concat());
+}
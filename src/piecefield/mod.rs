@@ -0,0 +1,94 @@
+//! piecefield tracks which pieces of a torrent someone has.  It is sized to the torrent, so peers
+//! that send a bitfield of the wrong length or say they have pieces that don't exist are caught
+//! as soon as they do.
+use bit_vec::BitVec;
+
+#[cfg(test)]
+mod test;
+
+/// One bit per piece of the torrent, set if the piece is had
+#[derive(Debug, Clone, PartialEq)]
+pub struct PieceField {
+    pieces: BitVec,
+}
+
+impl PieceField {
+    /// A field with none of `num_pieces` pieces
+    pub fn new(num_pieces: usize) -> Self {
+        PieceField {
+            pieces: BitVec::from_elem(num_pieces, false),
+        }
+    }
+
+    /// A field with every one of `num_pieces` pieces, as when a peer sends HaveAll
+    pub fn full(num_pieces: usize) -> Self {
+        PieceField {
+            pieces: BitVec::from_elem(num_pieces, true),
+        }
+    }
+
+    /// Check a bitfield a peer sent for a torrent of `num_pieces` pieces.  It must be exactly the
+    /// whole number of bytes needed, with the spare bits at the end cleared
+    pub fn from_bitfield(bitfield: BitVec, num_pieces: usize) -> Result<Self, String> {
+        let expected = num_pieces.div_ceil(8) * 8;
+        if bitfield.len() != expected {
+            return Err(format!("Bitfield has {} bits, expected {}", bitfield.len(), expected));
+        }
+        if bitfield.iter().skip(num_pieces).any(|has| has) {
+            return Err("Bitfield has spare bits set".to_string());
+        }
+        let mut pieces = bitfield;
+        pieces.truncate(num_pieces);
+        Ok(PieceField { pieces })
+    }
+
+    /// The field as it is sent in a Bitfield message
+    pub fn to_bitfield(&self) -> BitVec {
+        self.pieces.clone()
+    }
+
+    /// Mark a piece as had, as when a peer sends Have.  Pieces past the end are an error
+    pub fn set(&mut self, index: u32) -> Result<(), String> {
+        if index as usize >= self.pieces.len() {
+            return Err(format!("Piece {} is out of range, there are {}", index, self.pieces.len()));
+        }
+        self.pieces.set(index as usize, true);
+        Ok(())
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.pieces.get(index as usize).unwrap_or(false)
+    }
+
+    pub fn any(&self) -> bool {
+        self.pieces.any()
+    }
+
+    pub fn all(&self) -> bool {
+        self.pieces.all()
+    }
+
+    /// How many pieces are had
+    pub fn count(&self) -> usize {
+        self.pieces.iter().filter(|has| *has).count()
+    }
+
+    /// How many pieces the torrent has
+    pub fn len(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pieces.is_empty()
+    }
+
+    pub fn bits(&self) -> &BitVec {
+        &self.pieces
+    }
+}
+
+impl From<BitVec> for PieceField {
+    fn from(pieces: BitVec) -> Self {
+        PieceField { pieces }
+    }
+}
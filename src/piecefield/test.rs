@@ -0,0 +1,30 @@
+use super::*;
+
+#[test]
+fn test_from_bitfield() {
+    let field = PieceField::from_bitfield(BitVec::from_bytes(&[0b1010_0000, 0b1000_0000]), 9).unwrap();
+    assert_eq!(field.len(), 9);
+    assert_eq!(field.count(), 3);
+    assert!(field.has(0) && field.has(2) && field.has(8));
+    assert!(!field.has(1) && !field.has(9));
+    assert_eq!(field.to_bitfield().to_bytes(), vec![0b1010_0000, 0b1000_0000]);
+
+    // A spare bit is set
+    assert!(PieceField::from_bitfield(BitVec::from_bytes(&[0, 0b0100_0000]), 9).is_err());
+    // Too short and too long
+    assert!(PieceField::from_bitfield(BitVec::from_bytes(&[0]), 9).is_err());
+    assert!(PieceField::from_bitfield(BitVec::from_bytes(&[0, 0, 0]), 9).is_err());
+}
+
+#[test]
+fn test_set() {
+    let mut field = PieceField::new(9);
+    assert!(!field.any());
+    assert_eq!(field.set(8), Ok(()));
+    assert!(field.has(8));
+    assert!(field.set(9).is_err());
+    assert_eq!(field.count(), 1);
+    assert!(!field.all());
+    assert!(PieceField::full(9).all());
+    assert_eq!(PieceField::full(9).count(), 9);
+}
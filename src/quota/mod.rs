@@ -0,0 +1,56 @@
+//! quota keeps a torrent from using more disk space than the user allows
+use crate::metainfo::FileInfo;
+use std::fmt;
+
+#[cfg(test)]
+mod test;
+
+/// The files selected for download need more space than the quota allows
+#[derive(Debug, PartialEq)]
+pub struct QuotaExceeded {
+    // The most bytes the torrent may use
+    pub quota: u64,
+    // The bytes the selected files need
+    pub required: u64,
+    // The selected files that don't fit in the quota, with their sizes
+    pub overflow: Vec<(String, u64)>,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "selected files need {} bytes but the disk quota is {} bytes.  Files over the quota:",
+               self.required, self.quota)?;
+        for (name, length) in &self.overflow {
+            write!(f, "\n  {} ({} bytes)", name, length)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the selected files fit in `quota` bytes.  `selected` is given the index of each
+/// file and says whether it will be downloaded.  Files are counted in torrent order, so the
+/// overflow is the files that first pushed the total over the quota.
+pub fn check<F>(file_info: &FileInfo, selected: F, quota: u64) -> Result<(), QuotaExceeded>
+    where F: Fn(usize) -> bool {
+    let mut required = 0;
+    let mut overflow = Vec::new();
+    for (i, file) in file_info.files().into_iter().enumerate() {
+        if !selected(i) {
+            continue;
+        }
+        required += file.length as u64;
+        if required > quota {
+            overflow.push((file.file_name.clone(), file.length as u64));
+        }
+    }
+
+    if overflow.is_empty() {
+        Ok(())
+    } else {
+        Err(QuotaExceeded {
+            quota,
+            required,
+            overflow,
+        })
+    }
+}
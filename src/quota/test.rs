@@ -0,0 +1,36 @@
+use crate::metainfo::{
+    MultiFile,
+    SingleFile,
+};
+use super::*;
+
+fn file_info() -> FileInfo {
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    FileInfo::Multi(MultiFile {
+        root_dir_name: "root".to_string(),
+        files: vec![file("a", 100), file("b", 200), file("c", 300)],
+    })
+}
+
+#[test]
+fn test_within_quota() {
+    assert_eq!(check(&file_info(), |_| true, 600), Ok(()));
+}
+
+#[test]
+fn test_over_quota() {
+    assert_eq!(check(&file_info(), |_| true, 250), Err(QuotaExceeded {
+        quota: 250,
+        required: 600,
+        overflow: vec![("b".to_string(), 200), ("c".to_string(), 300)],
+    }));
+}
+
+#[test]
+fn test_unselected_files_dont_count() {
+    assert_eq!(check(&file_info(), |i| i != 2, 300), Ok(()));
+}
@@ -0,0 +1,396 @@
+//! ratelimit hands out a global bandwidth allowance to torrents or connections.  Each refill of
+//! the global bucket is split between those that recently asked for bandwidth, weighted by their priority
+//! class, so a high priority download isn't starved by a pile of low priority seeds.
+//!
+//! Throttled applies a limiter to a peer connection.  Every connection gets its own share of the
+//! bucket, so one fast peer can't take the whole allowance from the others.
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+    atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+};
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        ReadBuf,
+    },
+    time::{
+        self,
+        Sleep,
+    },
+};
+
+pub mod schedule;
+
+#[cfg(test)]
+mod test;
+
+/// How much of the global bandwidth a torrent should get relative to the others
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    pub fn name(self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    fn weight(self) -> u64 {
+        match self {
+            Priority::High => 4,
+            Priority::Normal => 2,
+            Priority::Low => 1,
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "low" => Ok(Priority::Low),
+            _ => Err(format!("Invalid priority: {}", s)),
+        }
+    }
+}
+
+/// A torrent's priority, shared with its connections so a change reaches the ones already open
+#[derive(Clone, Default)]
+pub struct PriorityClass(Arc<Mutex<Priority>>);
+
+impl PriorityClass {
+    pub fn new(priority: Priority) -> Self {
+        PriorityClass(Arc::new(Mutex::new(priority)))
+    }
+
+    pub fn get(&self) -> Priority {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set(&self, priority: Priority) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = priority;
+    }
+}
+
+struct Allocation {
+    priority: Priority,
+    // Bytes this torrent may still transfer
+    tokens: u64,
+    // Its share of the refills that didn't add up to a whole byte yet, in byte-nanoseconds
+    credit: u128,
+    // When this torrent last asked for bandwidth
+    last_wanted: Option<Instant>,
+}
+
+impl Allocation {
+    fn wants(&self, now: Instant) -> bool {
+        self.last_wanted.is_some_and(|wanted| now.saturating_duration_since(wanted) <= DEMAND_WINDOW)
+    }
+}
+
+/// How long a throttled connection waits before asking for bandwidth again
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A torrent that asked for bandwidth this recently still gets a share of every refill
+const DEMAND_WINDOW: Duration = Duration::from_secs(1);
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Shares bandwidth between torrents, keyed by info hash, or between anything else with a key
+pub struct RateLimiter<K = [u8; 20]> {
+    // Bytes per second shared between everyone.  None means unlimited
+    rate: Option<u64>,
+    last_refill: Instant,
+    torrents: HashMap<K, Allocation>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate: Option<u64>) -> Self {
+        RateLimiter {
+            rate,
+            last_refill: Instant::now(),
+            torrents: HashMap::new(),
+        }
+    }
+
+    /// Change the global rate.  Takes effect on the next refill
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate
+    }
+
+    pub fn add(&mut self, key: K, priority: Priority) {
+        self.torrents.insert(key, Allocation {
+            priority,
+            tokens: 0,
+            credit: 0,
+            last_wanted: None,
+        });
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        self.torrents.remove(key);
+    }
+
+    pub fn set_priority(&mut self, key: &K, priority: Priority) {
+        if let Some(allocation) = self.torrents.get_mut(key) {
+            allocation.priority = priority;
+        }
+    }
+
+    /// Ask to transfer `amount` bytes for a torrent.  Returns how many bytes may be transferred
+    /// right now, which may be less than asked for.
+    pub fn request(&mut self, key: &K, amount: u64) -> u64 {
+        self.request_at(key, amount, Instant::now())
+    }
+
+    fn request_at(&mut self, key: &K, amount: u64, now: Instant) -> u64 {
+        if self.rate.is_none() {
+            return amount;
+        }
+        self.refill(now);
+        match self.torrents.get_mut(key) {
+            Some(allocation) => {
+                let granted = allocation.tokens.min(amount);
+                allocation.tokens -= granted;
+                allocation.last_wanted = Some(now);
+                granted
+            }
+            None => 0,
+        }
+    }
+
+    /// Hand back bandwidth that was granted but not used
+    pub fn refund(&mut self, key: &K, amount: u64) {
+        if let (Some(rate), Some(allocation)) = (self.rate, self.torrents.get_mut(key)) {
+            allocation.tokens = (allocation.tokens + amount).min(rate);
+        }
+    }
+
+    /// Split the bandwidth accrued since the last refill between the torrents that asked for some
+    /// within the demand window
+    fn refill(&mut self, now: Instant) {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        if elapsed.is_zero() {
+            return;
+        }
+        self.last_refill = now;
+        // Count in byte-nanoseconds so frequent refills don't each round their bytes away
+        let accrued = rate as u128 * elapsed.as_nanos();
+
+        // If nobody has asked yet, share between everyone so the first requests aren't refused
+        let any_wants = self.torrents.values().any(|a| a.wants(now));
+        let total_weight: u64 = self.torrents.values()
+            .filter(|a| !any_wants || a.wants(now))
+            .map(|a| a.priority.weight())
+            .sum();
+        if total_weight == 0 {
+            return;
+        }
+
+        for allocation in self.torrents.values_mut() {
+            if any_wants && !allocation.wants(now) {
+                continue;
+            }
+            allocation.credit += accrued * allocation.priority.weight() as u128 / total_weight as u128;
+            let share = (allocation.credit / NANOS_PER_SEC).min(rate as u128) as u64;
+            allocation.credit %= NANOS_PER_SEC;
+            // Don't let anyone save up more than a second of bandwidth
+            allocation.tokens = (allocation.tokens + share).min(rate);
+        }
+    }
+}
+
+/// Parse a rate limit in KiB/s, like on the command line, into bytes per second.  Some(None) is
+/// "unlimited"
+pub fn parse_limit(value: &str) -> Option<Option<u64>> {
+    if value == "unlimited" {
+        Some(None)
+    } else {
+        value.parse::<u64>().ok().map(|rate| Some(rate * 1024))
+    }
+}
+
+/// A rate limit shared by every peer connection, for one direction.  Cloning it shares the limit
+#[derive(Clone)]
+pub struct Throttle {
+    limiter: Arc<Mutex<RateLimiter<usize>>>,
+    next_key: Arc<AtomicUsize>,
+}
+
+impl Throttle {
+    /// `rate` is in bytes per second.  None means unlimited
+    pub fn new(rate: Option<u64>) -> Self {
+        Throttle {
+            limiter: Arc::new(Mutex::new(RateLimiter::new(rate))),
+            next_key: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Change the limit for every connection sharing it.  None means unlimited
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.lock().set_rate(rate);
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.lock().rate()
+    }
+
+    /// Give a connection its own share of the limit, weighted by its torrent's priority
+    fn share(&self, priority: &PriorityClass) -> Share {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        self.lock().add(key, priority.get());
+        Share {
+            throttle: self.clone(),
+            key,
+            priority: priority.clone(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RateLimiter<usize>> {
+        // Nothing can panic while the lock is held, but if something did the limiter is still fine
+        self.limiter.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// One connection's share of a Throttle.  The share is given up when the connection closes
+struct Share {
+    throttle: Throttle,
+    key: usize,
+    priority: PriorityClass,
+}
+
+impl Share {
+    fn request(&self, amount: usize) -> usize {
+        let mut limiter = self.throttle.lock();
+        // The torrent's priority may have changed since the last request
+        limiter.set_priority(&self.key, self.priority.get());
+        limiter.request(&self.key, amount as u64) as usize
+    }
+
+    fn refund(&self, amount: usize) {
+        if amount > 0 {
+            self.throttle.lock().refund(&self.key, amount as u64);
+        }
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.throttle.lock().remove(&self.key);
+    }
+}
+
+/// A connection that reads and writes no faster than its throttles allow.  When it is out of
+/// bandwidth, reads and writes wait and the task is woken once more may be available
+pub struct Throttled<C> {
+    conn: C,
+    download: Share,
+    upload: Share,
+    retry: Option<Pin<Box<Sleep>>>,
+}
+
+impl<C> Throttled<C> {
+    pub fn new(conn: C, download: &Throttle, upload: &Throttle, priority: &PriorityClass) -> Self {
+        Throttled {
+            conn,
+            download: download.share(priority),
+            upload: upload.share(priority),
+            retry: None,
+        }
+    }
+
+    /// Make sure the task is woken again after running out of bandwidth
+    fn wait<T>(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        loop {
+            let retry = self.retry.get_or_insert_with(|| Box::pin(time::sleep(RETRY_INTERVAL)));
+            match retry.as_mut().poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(()) => self.retry = None,
+            }
+        }
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Throttled<C> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.conn).poll_read(cx, buf);
+        }
+        let granted = this.download.request(buf.remaining());
+        if granted == 0 {
+            return this.wait(cx);
+        }
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(granted));
+        let res = Pin::new(&mut this.conn).poll_read(cx, &mut limited);
+        let read = limited.filled().len();
+        this.download.refund(granted - read);
+        buf.advance(read);
+        res
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Throttled<C> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if buf.is_empty() {
+            return Pin::new(&mut this.conn).poll_write(cx, buf);
+        }
+        let granted = this.upload.request(buf.len());
+        if granted == 0 {
+            return this.wait(cx);
+        }
+        let res = Pin::new(&mut this.conn).poll_write(cx, &buf[..granted]);
+        let written = match &res {
+            Poll::Ready(Ok(written)) => *written,
+            _ => 0,
+        };
+        this.upload.refund(granted - written);
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.conn).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,256 @@
+//! schedule changes the rate limits by the time of day, like leaving them off at night and
+//! capping them while people are at home.  The schedule is a file of rules, one per line:
+//!
+//!   # times are local, and a rule may run past midnight
+//!   23:00-07:00 download=unlimited upload=unlimited
+//!   17:00-23:00 download=500 upload=50
+//!
+//! Limits are in KiB/s or "unlimited", and a rule may leave out either direction to keep its
+//! limit.  The first rule covering the time wins.  Outside every rule the limits go back to what
+//! they were, which may have been changed through the API meanwhile.  The file is read again when
+//! it changes, so the schedule can be edited without a restart
+use chrono::{
+    Local,
+    Timelike,
+};
+use derive_error::Error;
+use log::{
+    info,
+    warn,
+};
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::{
+    Duration,
+    SystemTime,
+};
+use super::{
+    parse_limit,
+    Throttle,
+};
+use tokio::time::{
+    self,
+    Interval,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How often to check whether the time or the schedule file changed
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    /// The schedule could not be read
+    Io(io::Error),
+    /// A line of the schedule could not be understood
+    #[error(non_std, no_from)]
+    InvalidSchedule(String),
+}
+
+/// Limits for part of the day.  Times are minutes past midnight, and the end isn't included.  A
+/// limit of None leaves that direction alone, Some(None) lifts it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rule {
+    pub start: u32,
+    pub end: u32,
+    pub download: Option<Option<u64>>,
+    pub upload: Option<Option<u64>>,
+}
+
+impl Rule {
+    /// Whether the rule covers `minute` past midnight.  A rule that starts when it ends covers the
+    /// whole day
+    pub fn covers(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Parse HH:MM into minutes past midnight
+fn parse_time(s: &str) -> Option<u32> {
+    let mut parts = s.splitn(2, ':');
+    let hours: u32 = parts.next()?.parse().ok()?;
+    let minutes: u32 = parts.next()?.parse().ok()?;
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let times = words.next().ok_or_else(|| "Empty rule".to_string())?;
+        let mut times = times.splitn(2, '-');
+        let start = times.next().and_then(parse_time);
+        let end = times.next().and_then(parse_time);
+        let (start, end) = match (start, end) {
+            (Some(start), Some(end)) => (start, end),
+            _ => return Err(format!("Invalid times, expected HH:MM-HH:MM: {}", s)),
+        };
+        let mut rule = Rule {
+            start,
+            end,
+            download: None,
+            upload: None,
+        };
+        for word in words {
+            let mut parts = word.splitn(2, '=');
+            match (parts.next(), parts.next().and_then(parse_limit)) {
+                (Some("download"), Some(limit)) => rule.download = Some(limit),
+                (Some("upload"), Some(limit)) => rule.upload = Some(limit),
+                _ => return Err(format!("Invalid limit, expected download=N or upload=N: {}", word)),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// Every rule, in the order they were given
+#[derive(Debug, PartialEq, Default)]
+pub struct Schedule {
+    rules: Vec<Rule>,
+}
+
+impl Schedule {
+    /// Read the schedule file at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ScheduleError> {
+        fs::read_to_string(path)?.parse().map_err(ScheduleError::InvalidSchedule)
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The index of the rule in effect at `minute` past midnight, if any
+    pub fn active(&self, minute: u32) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.covers(minute))
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// Blank lines and lines starting with # are skipped
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rules = s.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Schedule { rules })
+    }
+}
+
+/// Applies a schedule to the rate limits for as long as it runs on the runtime
+pub struct Scheduler {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    schedule: Schedule,
+    download: Throttle,
+    upload: Throttle,
+    // The rule in effect, and the limits from before it took effect
+    active: Option<usize>,
+    saved: (Option<u64>, Option<u64>),
+    // Made on the first poll, as timers need the runtime
+    interval: Option<Interval>,
+}
+
+impl Scheduler {
+    /// Load the schedule at `path`, to apply to the throttles
+    pub fn new<P: AsRef<Path>>(path: P, download: Throttle, upload: Throttle) -> Result<Self, ScheduleError> {
+        let path = path.as_ref().to_path_buf();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let schedule = Schedule::load(&path)?;
+        Ok(Scheduler {
+            path,
+            modified,
+            schedule,
+            download,
+            upload,
+            active: None,
+            saved: (None, None),
+            interval: None,
+        })
+    }
+
+    /// Put the limits of the rule in effect at `minute` past midnight in place, or put the old
+    /// limits back if there no longer is one
+    pub fn apply(&mut self, minute: u32) {
+        let active = self.schedule.active(minute % MINUTES_PER_DAY);
+        if active == self.active {
+            return;
+        }
+        if self.active.is_none() {
+            self.saved = (self.download.rate(), self.upload.rate());
+        }
+        let (download, upload) = match active {
+            Some(i) => {
+                let rule = &self.schedule.rules()[i];
+                info!("Scheduled rate limits from {:02}:{:02} apply", rule.start / 60, rule.start % 60);
+                (rule.download.unwrap_or(self.saved.0), rule.upload.unwrap_or(self.saved.1))
+            }
+            None => {
+                info!("Scheduled rate limits ended");
+                self.saved
+            }
+        };
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+        self.active = active;
+    }
+
+    /// Read the schedule again if the file changed.  A schedule that can't be read is kept
+    fn reload(&mut self) {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match Schedule::load(&self.path) {
+            Ok(schedule) => {
+                info!("Loaded the new schedule from {}", self.path.display());
+                if self.active.take().is_some() {
+                    self.download.set_rate(self.saved.0);
+                    self.upload.set_rate(self.saved.1);
+                }
+                self.schedule = schedule;
+            }
+            Err(e) => warn!("Could not load the new schedule from {}: {:?}", self.path.display(), e),
+        }
+    }
+}
+
+impl Future for Scheduler {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        while this.interval.get_or_insert_with(|| time::interval(CHECK_INTERVAL)).poll_tick(cx).is_ready() {
+            this.reload();
+            let now = Local::now();
+            this.apply(now.hour() * 60 + now.minute());
+        }
+        Poll::Pending
+    }
+}
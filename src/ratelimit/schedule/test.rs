@@ -0,0 +1,82 @@
+use super::*;
+use std::env;
+
+const SCHEDULE: &str = "
+# nights are free
+23:00-07:00 download=unlimited upload=unlimited
+17:00-23:00 download=500
+";
+
+fn scheduler(name: &str, contents: &str) -> Scheduler {
+    let path = env::temp_dir().join(format!("boosttorrent2-test-schedule-{}-{}", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    let scheduler = Scheduler::new(&path, Throttle::new(Some(100 * 1024)), Throttle::new(Some(10 * 1024))).unwrap();
+    fs::remove_file(&path).unwrap();
+    scheduler
+}
+
+fn limits(scheduler: &Scheduler) -> (Option<u64>, Option<u64>) {
+    (scheduler.download.rate(), scheduler.upload.rate())
+}
+
+#[test]
+fn test_parses_rules() {
+    let schedule: Schedule = SCHEDULE.parse().unwrap();
+    assert_eq!(schedule.rules(), &[
+        Rule {
+            start: 23 * 60,
+            end: 7 * 60,
+            download: Some(None),
+            upload: Some(None),
+        },
+        Rule {
+            start: 17 * 60,
+            end: 23 * 60,
+            download: Some(Some(500 * 1024)),
+            upload: None,
+        },
+    ]);
+}
+
+#[test]
+fn test_rejects_bad_rules() {
+    assert!("25:00-07:00 download=1".parse::<Schedule>().is_err());
+    assert!("23:00 download=1".parse::<Schedule>().is_err());
+    assert!("23:00-07:00 download=fast".parse::<Schedule>().is_err());
+    assert!("23:00-07:00 sideways=1".parse::<Schedule>().is_err());
+}
+
+#[test]
+fn test_rules_run_past_midnight() {
+    let schedule: Schedule = SCHEDULE.parse().unwrap();
+    assert_eq!(schedule.active(23 * 60 + 30), Some(0));
+    assert_eq!(schedule.active(3 * 60), Some(0));
+    assert_eq!(schedule.active(7 * 60), None);
+    assert_eq!(schedule.active(17 * 60), Some(1));
+    let all_day: Rule = "00:00-00:00 upload=1".parse().unwrap();
+    assert!(all_day.covers(12 * 60));
+}
+
+#[test]
+fn test_limits_come_back_after_a_rule() {
+    let mut scheduler = scheduler("restore", SCHEDULE);
+    scheduler.apply(12 * 60);
+    assert_eq!(limits(&scheduler), (Some(100 * 1024), Some(10 * 1024)));
+    scheduler.apply(18 * 60);
+    // The evening rule leaves the upload limit alone
+    assert_eq!(limits(&scheduler), (Some(500 * 1024), Some(10 * 1024)));
+    scheduler.apply(23 * 60);
+    assert_eq!(limits(&scheduler), (None, None));
+    scheduler.apply(8 * 60);
+    assert_eq!(limits(&scheduler), (Some(100 * 1024), Some(10 * 1024)));
+}
+
+#[test]
+fn test_limits_set_between_rules_are_kept() {
+    let mut scheduler = scheduler("changed", SCHEDULE);
+    scheduler.apply(12 * 60);
+    scheduler.download.set_rate(Some(200 * 1024));
+    scheduler.apply(23 * 60);
+    scheduler.apply(8 * 60);
+    assert_eq!(limits(&scheduler), (Some(200 * 1024), Some(10 * 1024)));
+}
@@ -0,0 +1,154 @@
+use futures::task::noop_waker;
+use std::time::Duration;
+use super::*;
+
+/// Read once, without waiting for bandwidth
+fn try_read(conn: &mut Throttled<io::Cursor<Vec<u8>>>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+    let waker = noop_waker();
+    let mut buf = ReadBuf::new(buf);
+    Pin::new(conn).poll_read(&mut Context::from_waker(&waker), &mut buf).map_ok(|()| buf.filled().len())
+}
+
+/// Write once, without waiting for bandwidth
+fn try_write(conn: &mut Throttled<io::Cursor<Vec<u8>>>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    let waker = noop_waker();
+    Pin::new(conn).poll_write(&mut Context::from_waker(&waker), buf)
+}
+
+#[test]
+fn test_unlimited() {
+    let mut limiter = RateLimiter::new(None);
+    assert_eq!(limiter.request(&[0; 20], 1000), 1000);
+}
+
+#[test]
+fn test_priority_weighting() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Some(700));
+    limiter.last_refill = start;
+    limiter.add([1; 20], Priority::High);
+    limiter.add([2; 20], Priority::Normal);
+    limiter.add([3; 20], Priority::Low);
+
+    let later = start + Duration::from_secs(1);
+    assert_eq!(limiter.request_at(&[1; 20], 1000, later), 400);
+    assert_eq!(limiter.request_at(&[2; 20], 1000, later), 200);
+    assert_eq!(limiter.request_at(&[3; 20], 1000, later), 100);
+}
+
+#[test]
+fn test_idle_torrents_dont_get_bandwidth() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Some(600));
+    limiter.last_refill = start;
+    limiter.add([1; 20], Priority::Low);
+    limiter.add([2; 20], Priority::High);
+
+    // Only the low priority torrent asks, so it gets everything on the next refill
+    limiter.request_at(&[1; 20], 1000, start + Duration::from_secs(1));
+    assert_eq!(limiter.request_at(&[1; 20], 1000, start + Duration::from_secs(2)), 600);
+}
+
+#[test]
+fn test_interleaved_requests_follow_priority() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Some(1000));
+    limiter.last_refill = start;
+    limiter.add([1; 20], Priority::High);
+    limiter.add([2; 20], Priority::Low);
+
+    // Both torrents ask every millisecond, taking turns, so every refill is a byte or less
+    let (mut high, mut low) = (0, 0);
+    for tick in 1..=5000 {
+        let now = start + Duration::from_millis(tick);
+        high += limiter.request_at(&[1; 20], 16384, now);
+        low += limiter.request_at(&[2; 20], 16384, now);
+    }
+    assert_eq!(high + low, 5000);
+    assert!((3990..=4010).contains(&high), "high got {}", high);
+}
+
+#[test]
+fn test_demand_outlasts_a_refill() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Some(600));
+    limiter.last_refill = start;
+    limiter.add([1; 20], Priority::High);
+    limiter.add([2; 20], Priority::Low);
+    limiter.request_at(&[1; 20], 1000, start);
+    limiter.request_at(&[2; 20], 1000, start);
+
+    // The low priority torrent's refill doesn't stop the high priority one from getting its share
+    let later = start + Duration::from_millis(500);
+    assert_eq!(limiter.request_at(&[2; 20], 1000, later), 60);
+    assert_eq!(limiter.request_at(&[1; 20], 1000, later), 240);
+    // Once a torrent stops asking, the others get its share
+    let idle = later + DEMAND_WINDOW + Duration::from_millis(500);
+    assert_eq!(limiter.request_at(&[1; 20], 1000, idle), 600);
+}
+
+#[test]
+fn test_parse_priority() {
+    assert_eq!("high".parse(), Ok(Priority::High));
+    assert!("urgent".parse::<Priority>().is_err());
+}
+
+#[test]
+fn test_refund() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Some(100));
+    limiter.last_refill = start;
+    limiter.add(1usize, Priority::Normal);
+
+    let later = start + Duration::from_secs(1);
+    assert_eq!(limiter.request_at(&1, 100, later), 100);
+    limiter.refund(&1, 40);
+    assert_eq!(limiter.request_at(&1, 100, later), 40);
+}
+
+#[tokio::test]
+async fn test_throttled_connections_share() {
+    let download = Throttle::new(Some(1000));
+    let upload = Throttle::new(None);
+    let priority = PriorityClass::default();
+    let mut first = Throttled::new(io::Cursor::new(vec![0; 2000]), &download, &upload, &priority);
+    let mut second = Throttled::new(io::Cursor::new(vec![0; 2000]), &download, &upload, &priority);
+    download.lock().last_refill = Instant::now() - Duration::from_secs(1);
+
+    // A second has passed, so each connection gets half of the bucket
+    let mut buf = [0; 2000];
+    let read = try_read(&mut first, &mut buf).map(Result::unwrap);
+    assert!(matches!(read, Poll::Ready(500..=549)));
+    let read = try_read(&mut second, &mut buf).map(Result::unwrap);
+    assert!(matches!(read, Poll::Ready(500..=549)));
+    // Unlimited directions pass everything through
+    assert!(matches!(try_write(&mut first, &buf), Poll::Ready(Ok(2000))));
+}
+
+#[tokio::test]
+async fn test_throttled_connections_follow_their_torrents_priority() {
+    let download = Throttle::new(Some(1000));
+    let upload = Throttle::new(None);
+    let (high, low) = (PriorityClass::new(Priority::High), PriorityClass::new(Priority::Low));
+    let mut first = Throttled::new(io::Cursor::new(vec![0; 2000]), &download, &upload, &high);
+    let mut second = Throttled::new(io::Cursor::new(vec![0; 2000]), &download, &upload, &low);
+    // Changing the class reaches connections that are already open, on their next request
+    low.set(Priority::Normal);
+    let mut buf = [0; 2000];
+    let _res = try_read(&mut second, &mut buf);
+    let _res = try_read(&mut first, &mut buf);
+    {
+        let mut limiter = download.lock();
+        limiter.last_refill = Instant::now() - Duration::from_secs(1);
+        limiter.torrents.values_mut().for_each(|a| {
+            a.tokens = 0;
+            a.credit = 0;
+        });
+    }
+
+    // High gets twice what normal does
+    let read = try_read(&mut first, &mut buf).map(Result::unwrap);
+    assert!(matches!(read, Poll::Ready(666..=699)));
+    let read = try_read(&mut second, &mut buf).map(Result::unwrap);
+    assert!(matches!(read, Poll::Ready(333..=366)));
+}
@@ -0,0 +1,122 @@
+//! reachability works out whether other peers can connect to the port we announce.  A client
+//! behind a closed port can still download, but only from peers that accept connections, so it's
+//! worth telling the user about.
+//!
+//! The session's listener is shared by every torrent, so the test runs once for the listener and
+//! every torrent reads its result.
+use log::{
+    info,
+    warn,
+};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::Duration;
+use tokio::{
+    net::TcpStream,
+    time,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How long to wait for the self test connection before giving up
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum PortStatus {
+    /// We have not been able to tell yet
+    #[default]
+    Unknown,
+    /// Someone outside has connected to us
+    Open,
+    /// Connecting to our own external address failed
+    Closed,
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            PortStatus::Unknown => "port status unknown",
+            PortStatus::Open => "port open",
+            PortStatus::Closed => "port closed — check your firewall/NAT",
+        })
+    }
+}
+
+/// Try to connect to our own listener through our external address.  Some routers can't loop
+/// connections back to the local network, so a Closed result should be overridden by any
+/// incoming connection we see later.
+pub async fn self_test(external: SocketAddr) -> PortStatus {
+    match time::timeout(SELF_TEST_TIMEOUT, TcpStream::connect(external)).await {
+        Ok(Ok(_)) => PortStatus::Open,
+        _ => PortStatus::Closed,
+    }
+}
+
+/// Whether the session's listener can be reached from outside.  Cloning it shares the status
+#[derive(Clone, Default)]
+pub struct Reachability(Arc<Mutex<Probe>>);
+
+#[derive(Default)]
+struct Probe {
+    status: PortStatus,
+    // Whether a self test is running
+    testing: bool,
+}
+
+impl Reachability {
+    pub fn new() -> Self {
+        Reachability::default()
+    }
+
+    pub fn status(&self) -> PortStatus {
+        self.lock().status
+    }
+
+    /// Someone outside connected to us, which is better evidence than the self test
+    pub fn set_open(&self) {
+        self.lock().set(PortStatus::Open);
+    }
+
+    /// Test `external` in the background, unless the status is already known or a test is
+    /// running.  Must be called on the runtime
+    pub fn test(&self, external: SocketAddr) {
+        {
+            let mut probe = self.lock();
+            if probe.testing || probe.status != PortStatus::Unknown {
+                return;
+            }
+            probe.testing = true;
+        }
+        let reachability = self.clone();
+        tokio::spawn(async move {
+            let status = self_test(external).await;
+            let mut probe = reachability.lock();
+            probe.testing = false;
+            // A connection may have come in while we waited
+            if probe.status != PortStatus::Open {
+                probe.set(status);
+            }
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Probe> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Probe {
+    fn set(&mut self, status: PortStatus) {
+        if status != self.status {
+            match status {
+                PortStatus::Closed => warn!("{}", status),
+                _ => info!("{}", status),
+            }
+            self.status = status;
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::net::TcpListener;
+use super::*;
+
+#[tokio::test]
+async fn test_self_test_open() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    assert_eq!(self_test(address).await, PortStatus::Open);
+}
+
+#[tokio::test]
+async fn test_self_test_closed() {
+    // Bind and drop to find a port nobody is listening on
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert_eq!(self_test(address).await, PortStatus::Closed);
+}
+
+/// Wait for a self test started on `reachability` to finish
+async fn tested(reachability: &Reachability) -> PortStatus {
+    while reachability.status() == PortStatus::Unknown {
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    reachability.status()
+}
+
+#[tokio::test]
+async fn test_reachability_tests_once() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let open = listener.local_addr().unwrap();
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let reachability = Reachability::new();
+    let torrent = reachability.clone();
+    reachability.test(open);
+    // Another torrent learning our address while the test runs doesn't start a second one
+    torrent.test(closed);
+    assert_eq!(tested(&torrent).await, PortStatus::Open);
+}
+
+#[tokio::test]
+async fn test_incoming_connection_beats_the_self_test() {
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let reachability = Reachability::new();
+    reachability.test(closed);
+    assert_eq!(tested(&reachability).await, PortStatus::Closed);
+    reachability.set_open();
+    assert_eq!(reachability.status(), PortStatus::Open);
+}
@@ -0,0 +1,155 @@
+//! resume remembers how far a torrent got, so a restarted client picks up where it left off
+//! instead of downloading everything again.  The resume file sits in the state directory, or next
+//! to the download without one, and holds the pieces we verified, our transfer totals and the
+//! tracker id.
+use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::session::{counter, hex, unhex};
+use crate::storage::FileMap;
+use bit_vec::BitVec;
+use derive_error::Error;
+use maplit::hashmap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, Error)]
+pub enum ResumeError {
+    /// The resume file could not be read or written
+    Io(io::Error),
+    /// The resume file could not be bdecoded
+    DecodeError(DecodeError),
+    /// The contents of the resume file are not correct
+    #[error(non_std, no_from)]
+    InvalidResume(String),
+}
+
+/// A torrent's progress
+#[derive(Debug, PartialEq, Clone)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    // The pieces we have verified and written
+    pub pieces: BitVec,
+    // Bytes transferred, as reported to the tracker
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub tracker_id: Option<String>,
+    // The key we announce with, so trackers know us after a restart
+    pub tracker_key: Option<u32>,
+    // The length of each file when the resume data was saved
+    pub file_lengths: Vec<u64>,
+}
+
+impl ResumeData {
+    /// Where the resume file of a torrent named `name` is kept when there is no state directory
+    pub fn path<P: AsRef<Path>>(download_dir: P, name: &str) -> PathBuf {
+        download_dir.as_ref().join(format!("{}.resume", name))
+    }
+
+    /// Load the resume file at `path`.  A missing file means the torrent hasn't started yet
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Self>, ResumeError> {
+        match fs::read(path) {
+            Ok(bytes) => {
+                let val = Value::decode(&bytes)?;
+                Ok(Some(ResumeData::from_value(&val).map_err(ResumeError::InvalidResume)?))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(ResumeError::Io(e)),
+        }
+    }
+
+    /// Write the resume file to `path`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ResumeError> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write then rename, so a crash part way through doesn't lose the progress
+        let tmp = path.with_extension("resume.tmp");
+        fs::write(&tmp, self.to_value().encode())?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Whether the files on disk still look like they did when the resume data was saved.  If a
+    /// file was deleted or truncated the pieces in it are gone, so the resume data can't be
+    /// trusted.  Skipped and padding files are never created, so they may be missing
+    pub fn matches(&self, files: &FileMap) -> bool {
+        self.file_lengths.len() == files.files().len()
+            && self.file_lengths.iter().enumerate().all(|(i, length)| {
+                match files.disk_path(i).and_then(|path| fs::metadata(path).ok()) {
+                    Some(metadata) => metadata.len() == *length,
+                    None => files.is_skipped(i) || files.files()[i].is_padding(),
+                }
+            })
+    }
+
+    fn to_value(&self) -> Value {
+        let mut map = hashmap! {
+            Vec::from("info_hash") => Value::BString(Vec::from(hex(&self.info_hash))),
+            Vec::from("pieces") => Value::BString(self.pieces.to_bytes()),
+            Vec::from("num_pieces") => Value::Integer(self.pieces.len() as i64),
+            Vec::from("uploaded") => Value::Integer(self.uploaded as i64),
+            Vec::from("downloaded") => Value::Integer(self.downloaded as i64),
+            Vec::from("file_lengths") => Value::List(self.file_lengths.iter()
+                .map(|length| Value::Integer(*length as i64))
+                .collect()),
+        };
+        if let Some(id) = &self.tracker_id {
+            map.insert(Vec::from("tracker id"), Value::BString(Vec::from(id.as_bytes())));
+        }
+        if let Some(key) = self.tracker_key {
+            map.insert(Vec::from("tracker key"), Value::Integer(i64::from(key)));
+        }
+        Value::Dict(map)
+    }
+}
+
+impl FromValue for ResumeData {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Resume data not a dictionary".to_string())?;
+
+        let info_hash = map.get("info_hash".as_bytes()).and_then(Value::bstring_utf8)
+            .and_then(|s| unhex(&s))
+            .ok_or("Missing key: info_hash".to_string())?;
+
+        let num_pieces = map.get("num_pieces".as_bytes()).and_then(Value::integer)
+            .ok_or("Missing key: num_pieces".to_string())?;
+
+        let mut pieces = map.get("pieces".as_bytes()).and_then(Value::bstring)
+            .map(|bytes| BitVec::from_bytes(bytes))
+            .ok_or("Missing key: pieces".to_string())?;
+        if *num_pieces < 0 || pieces.len() < *num_pieces as usize {
+            return Err("Piece bitfield is too short".to_string());
+        }
+        pieces.truncate(*num_pieces as usize);
+
+        let stat = |key: &str| map.get(key.as_bytes()).and_then(counter);
+
+        let file_lengths = map.get("file_lengths".as_bytes()).and_then(Value::list)
+            .ok_or("Missing key: file_lengths".to_string())?
+            .iter()
+            .map(counter)
+            .collect::<Option<Vec<u64>>>()
+            .ok_or("Invalid file length".to_string())?;
+
+        Ok(ResumeData {
+            info_hash,
+            pieces,
+            uploaded: stat("uploaded").unwrap_or(0),
+            downloaded: stat("downloaded").unwrap_or(0),
+            tracker_id: map.get("tracker id".as_bytes()).and_then(Value::bstring_utf8),
+            tracker_key: map.get("tracker key".as_bytes()).and_then(counter)
+                .and_then(|key| u32::try_from(key).ok()),
+            file_lengths,
+        })
+    }
+}
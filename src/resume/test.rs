@@ -0,0 +1,89 @@
+use crate::metainfo::{
+    FileInfo,
+    MultiFile,
+    SingleFile,
+};
+use std::env;
+use super::*;
+
+fn resume_data() -> ResumeData {
+    let mut pieces = BitVec::from_elem(10, false);
+    pieces.set(0, true);
+    pieces.set(9, true);
+    ResumeData {
+        info_hash: [7; 20],
+        pieces,
+        uploaded: 5_000_000_000,
+        downloaded: 12,
+        tracker_id: Some("abc".to_string()),
+        tracker_key: Some(0xdeadbeef),
+        file_lengths: vec![6, 4],
+    }
+}
+
+#[test]
+fn test_resume_round_trip() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-resume-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = ResumeData::path(&dir, "album");
+    assert!(ResumeData::load(&path).unwrap().is_none());
+    resume_data().save(&path).unwrap();
+    let loaded = ResumeData::load(&path).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    // The bitfield is padded to whole bytes on disk, but comes back the right length
+    assert_eq!(loaded, Some(resume_data()));
+}
+
+#[test]
+fn test_resume_matches_files() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-resume-files-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let files = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a", 6), file("b", 4)],
+    }));
+    let data = resume_data();
+    assert!(!data.matches(&files));
+    files.allocate().unwrap();
+    assert!(data.matches(&files));
+    fs::write(files.disk_path(1).unwrap(), b"x").unwrap();
+    let truncated = data.matches(&files);
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(!truncated);
+}
+
+#[test]
+fn test_resume_rejects_short_bitfield() {
+    let mut val = resume_data().to_value();
+    if let Value::Dict(map) = &mut val {
+        map.insert(Vec::from("num_pieces"), Value::Integer(100));
+    }
+    assert!(ResumeData::from_value(&val).is_err());
+}
+
+#[test]
+fn test_resume_counters_are_integers() {
+    let val = resume_data().to_value();
+    let map = val.dict().unwrap();
+    assert_eq!(map.get("uploaded".as_bytes()), Some(&Value::Integer(5_000_000_000)));
+    assert_eq!(map.get("tracker key".as_bytes()), Some(&Value::Integer(0xdeadbeef)));
+}
+
+#[test]
+fn test_resume_reads_string_counters() {
+    // Resume files used to hold the counters as strings
+    let mut val = resume_data().to_value();
+    if let Value::Dict(map) = &mut val {
+        map.insert(Vec::from("uploaded"), Value::BString(Vec::from("5000000000")));
+        map.insert(Vec::from("file_lengths"), Value::List(vec![
+            Value::BString(Vec::from("6")),
+            Value::BString(Vec::from("4")),
+        ]));
+        map.insert(Vec::from("tracker key"), Value::BString(Vec::from("3735928559")));
+    }
+    assert_eq!(ResumeData::from_value(&val).unwrap(), resume_data());
+}
@@ -0,0 +1,221 @@
+//! ctl is the other end of the API: it sends one command to a running client and hands back the
+//! JSON answer, for `boosttorrent2 ctl`
+use crate::session::hex;
+use hyper::{
+    Body,
+    Client,
+    Method,
+    Request,
+};
+use percent_encoding::{
+    define_encode_set,
+    utf8_percent_encode,
+    QUERY_ENCODE_SET,
+};
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+
+#[cfg(test)]
+mod test;
+
+define_encode_set! {
+    /// Query values can't hold the characters that split up the query
+    pub QUERY_VALUE_ENCODE_SET = [QUERY_ENCODE_SET] | {'&', '=', '+'}
+}
+
+#[derive(Debug, derive_error::Error)]
+pub enum CtlError {
+    /// The client could not be reached
+    ConnectionError(hyper::Error),
+    /// The client refused the command.  Holds the reason it gave
+    #[error(non_std, no_from)]
+    Refused(String),
+}
+
+/// Something to ask a running client to do
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    List,
+    Show([u8; 20]),
+    Peers([u8; 20]),
+    // Add a .torrent file, downloading into `download_dir` or the client's download directory
+    Add {
+        contents: Vec<u8>,
+        download_dir: Option<String>,
+    },
+    // Have the client download a .torrent file from `url` and add it
+    AddUrl {
+        url: String,
+        download_dir: Option<String>,
+    },
+    Remove([u8; 20]),
+    Pause {
+        info_hash: [u8; 20],
+        drop_peers: bool,
+    },
+    Resume([u8; 20]),
+    Verify([u8; 20]),
+    // Start announcing to a tracker, in `tier` or a new last tier
+    AddTracker {
+        info_hash: [u8; 20],
+        url: String,
+        tier: Option<usize>,
+    },
+    RemoveTracker {
+        info_hash: [u8; 20],
+        url: String,
+    },
+    // Announce now, to `url` or the tracker in use
+    Reannounce {
+        info_hash: [u8; 20],
+        url: Option<String>,
+    },
+    // Rename the root directory, or file `file` if it is given
+    Rename {
+        info_hash: [u8; 20],
+        file: Option<usize>,
+        name: String,
+    },
+    // How much file `file` is wanted: skip, low, normal or high
+    FilePriority {
+        info_hash: [u8; 20],
+        file: usize,
+        priority: String,
+    },
+    // A torrent's share of the rate limits: high, normal or low
+    BandwidthPriority {
+        info_hash: [u8; 20],
+        priority: String,
+    },
+    // Move a torrent up, down, to the top or bottom of the queue, or to a position
+    Queue {
+        info_hash: [u8; 20],
+        to: String,
+    },
+    ForceStart {
+        info_hash: [u8; 20],
+        enabled: bool,
+    },
+    Status,
+    Limits,
+    // Change the limits that are given, in KiB/s or "unlimited"
+    SetLimits {
+        download: Option<String>,
+        upload: Option<String>,
+    },
+    ReloadBlocklist,
+}
+
+impl Command {
+    /// The API request that carries out the command
+    pub fn request(&self, address: &SocketAddr) -> Request<Body> {
+        let torrent = |info_hash: &[u8; 20]| format!("/torrents/{}", hex(info_hash));
+        let (method, path, body) = match self {
+            Command::List => (Method::GET, "/torrents".to_string(), Body::empty()),
+            Command::Show(info_hash) => (Method::GET, torrent(info_hash), Body::empty()),
+            Command::Peers(info_hash) => (Method::GET, torrent(info_hash) + "/peers", Body::empty()),
+            Command::Add { contents, download_dir } => {
+                let path = match download_dir {
+                    Some(dir) => format!("/torrents?download_dir={}", utf8_percent_encode(dir, QUERY_VALUE_ENCODE_SET)),
+                    None => "/torrents".to_string(),
+                };
+                (Method::POST, path, Body::from(contents.clone()))
+            }
+            Command::AddUrl { url, download_dir } => {
+                let mut path = format!("/torrents?url={}", utf8_percent_encode(url, QUERY_VALUE_ENCODE_SET));
+                if let Some(dir) = download_dir {
+                    let _ = write!(path, "&download_dir={}", utf8_percent_encode(dir, QUERY_VALUE_ENCODE_SET));
+                }
+                (Method::POST, path, Body::empty())
+            }
+            Command::Remove(info_hash) => (Method::DELETE, torrent(info_hash), Body::empty()),
+            Command::Pause { info_hash, drop_peers } => {
+                let mut path = torrent(info_hash) + "/pause";
+                if *drop_peers {
+                    path.push_str("?drop_peers=true");
+                }
+                (Method::POST, path, Body::empty())
+            }
+            Command::Resume(info_hash) => (Method::POST, torrent(info_hash) + "/resume", Body::empty()),
+            Command::Verify(info_hash) => (Method::POST, torrent(info_hash) + "/verify", Body::empty()),
+            Command::AddTracker { info_hash, url, tier } => {
+                let url = utf8_percent_encode(url, QUERY_VALUE_ENCODE_SET);
+                let mut path = format!("{}/trackers?url={}", torrent(info_hash), url);
+                if let Some(tier) = tier {
+                    let _ = write!(path, "&tier={}", tier);
+                }
+                (Method::POST, path, Body::empty())
+            }
+            Command::RemoveTracker { info_hash, url } => {
+                let url = utf8_percent_encode(url, QUERY_VALUE_ENCODE_SET);
+                let path = format!("{}/trackers?url={}", torrent(info_hash), url);
+                (Method::DELETE, path, Body::empty())
+            }
+            Command::Reannounce { info_hash, url } => {
+                let mut path = torrent(info_hash) + "/reannounce";
+                if let Some(url) = url {
+                    let _ = write!(path, "?url={}", utf8_percent_encode(url, QUERY_VALUE_ENCODE_SET));
+                }
+                (Method::POST, path, Body::empty())
+            }
+            Command::Rename { info_hash, file, name } => {
+                let name = utf8_percent_encode(name, QUERY_VALUE_ENCODE_SET);
+                let path = match file {
+                    Some(index) => format!("{}/files/{}/rename?name={}", torrent(info_hash), index, name),
+                    None => format!("{}/rename?name={}", torrent(info_hash), name),
+                };
+                (Method::POST, path, Body::empty())
+            }
+            Command::FilePriority { info_hash, file, priority } => {
+                let path = format!("{}/files/{}/priority?priority={}", torrent(info_hash), file,
+                                   utf8_percent_encode(priority, QUERY_VALUE_ENCODE_SET));
+                (Method::POST, path, Body::empty())
+            }
+            Command::BandwidthPriority { info_hash, priority } => {
+                let path = format!("{}/bandwidth_priority?priority={}", torrent(info_hash),
+                                   utf8_percent_encode(priority, QUERY_VALUE_ENCODE_SET));
+                (Method::POST, path, Body::empty())
+            }
+            Command::Queue { info_hash, to } => {
+                let path = format!("{}/queue?to={}", torrent(info_hash), utf8_percent_encode(to, QUERY_VALUE_ENCODE_SET));
+                (Method::POST, path, Body::empty())
+            }
+            Command::ForceStart { info_hash, enabled } => {
+                (Method::POST, format!("{}/force_start?enabled={}", torrent(info_hash), enabled), Body::empty())
+            }
+            Command::Status => (Method::GET, "/status".to_string(), Body::empty()),
+            Command::Limits => (Method::GET, "/limits".to_string(), Body::empty()),
+            Command::SetLimits { download, upload } => {
+                let params: Vec<String> = [("download", download), ("upload", upload)].iter()
+                    .filter_map(|(name, value)| value.as_ref().map(|value| {
+                        format!("{}={}", name, utf8_percent_encode(value, QUERY_VALUE_ENCODE_SET))
+                    }))
+                    .collect();
+                (Method::POST, format!("/limits?{}", params.join("&")), Body::empty())
+            }
+            Command::ReloadBlocklist => (Method::POST, "/blocklist/reload".to_string(), Body::empty()),
+        };
+        Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", address, path))
+            .body(body)
+            .unwrap()
+    }
+}
+
+/// Send `command` to the client serving the API on `address`.  Resolves to the JSON answer
+pub fn send(address: &SocketAddr, command: &Command) -> impl Future<Output=Result<String, CtlError>> + Send {
+    let request = Client::new().request(command.request(address));
+    async move {
+        let response = request.await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let body = String::from_utf8_lossy(&body).into_owned();
+        if status.is_success() {
+            Ok(body)
+        } else {
+            Err(CtlError::Refused(format!("{}: {}", status, body)))
+        }
+    }
+}
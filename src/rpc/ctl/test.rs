@@ -0,0 +1,60 @@
+use super::*;
+
+fn sent(command: Command) -> (Method, String) {
+    let request = command.request(&"127.0.0.1:9091".parse().unwrap());
+    assert_eq!(request.uri().authority().map(|a| a.as_str()), Some("127.0.0.1:9091"));
+    let path = request.uri().path_and_query().unwrap().as_str().to_owned();
+    (request.method().clone(), path)
+}
+
+#[test]
+fn test_commands_reach_their_endpoints() {
+    let hash = hex(&[1; 20]);
+    assert_eq!(sent(Command::List), (Method::GET, "/torrents".to_string()));
+    assert_eq!(sent(Command::Show([1; 20])), (Method::GET, format!("/torrents/{}", hash)));
+    assert_eq!(sent(Command::Remove([1; 20])), (Method::DELETE, format!("/torrents/{}", hash)));
+    assert_eq!(sent(Command::Pause { info_hash: [1; 20], drop_peers: true }),
+               (Method::POST, format!("/torrents/{}/pause?drop_peers=true", hash)));
+    assert_eq!(sent(Command::Verify([1; 20])), (Method::POST, format!("/torrents/{}/verify", hash)));
+    assert_eq!(sent(Command::Reannounce { info_hash: [1; 20], url: None }),
+               (Method::POST, format!("/torrents/{}/reannounce", hash)));
+    assert_eq!(sent(Command::Rename { info_hash: [1; 20], file: Some(2), name: "b/c.txt".to_string() }),
+               (Method::POST, format!("/torrents/{}/files/2/rename?name=b/c.txt", hash)));
+    assert_eq!(sent(Command::FilePriority { info_hash: [1; 20], file: 0, priority: "skip".to_string() }),
+               (Method::POST, format!("/torrents/{}/files/0/priority?priority=skip", hash)));
+    assert_eq!(sent(Command::BandwidthPriority { info_hash: [1; 20], priority: "low".to_string() }),
+               (Method::POST, format!("/torrents/{}/bandwidth_priority?priority=low", hash)));
+    assert_eq!(sent(Command::Queue { info_hash: [1; 20], to: "top".to_string() }),
+               (Method::POST, format!("/torrents/{}/queue?to=top", hash)));
+    assert_eq!(sent(Command::ForceStart { info_hash: [1; 20], enabled: false }),
+               (Method::POST, format!("/torrents/{}/force_start?enabled=false", hash)));
+    assert_eq!(sent(Command::Status), (Method::GET, "/status".to_string()));
+    assert_eq!(sent(Command::ReloadBlocklist), (Method::POST, "/blocklist/reload".to_string()));
+}
+
+#[test]
+fn test_query_values_are_encoded() {
+    let add = Command::Add {
+        contents: Vec::new(),
+        download_dir: Some("/data/a&b=c".to_string()),
+    };
+    assert_eq!(sent(add), (Method::POST, "/torrents?download_dir=/data/a%26b%3Dc".to_string()));
+    let add_url = Command::AddUrl {
+        url: "https://example.com/a.torrent?id=1&key=2".to_string(),
+        download_dir: Some("/data".to_string()),
+    };
+    let path = "/torrents?url=https://example.com/a.torrent?id%3D1%26key%3D2&download_dir=/data";
+    assert_eq!(sent(add_url), (Method::POST, path.to_string()));
+    let limits = Command::SetLimits {
+        download: None,
+        upload: Some("unlimited".to_string()),
+    };
+    assert_eq!(sent(limits), (Method::POST, "/limits?upload=unlimited".to_string()));
+    let add_tracker = Command::AddTracker {
+        info_hash: [1; 20],
+        url: "http://t.example/announce?key=a&b".to_string(),
+        tier: Some(1),
+    };
+    let path = format!("/torrents/{}/trackers?url=http://t.example/announce?key%3Da%26b&tier=1", hex(&[1; 20]));
+    assert_eq!(sent(add_tracker), (Method::POST, path));
+}
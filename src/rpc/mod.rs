@@ -0,0 +1,573 @@
+//! rpc is an HTTP API for driving the client from other programs, such as a web UI.  Answers are
+//! JSON, torrents are named by their info hash in hex, and rate limits are in KiB/s like on the
+//! command line.
+//!
+//!   GET    /torrents                   every running torrent and its progress
+//!   POST   /torrents?download_dir=DIR  add the .torrent file in the body, and start it
+//!   POST   /torrents?url=URL&download_dir=DIR  download the .torrent file at URL, add it, and
+//!                                      start it
+//!   GET    /torrents/HASH              one torrent, with its peers
+//!   DELETE /torrents/HASH              stop a torrent and take it out of the session
+//!   GET    /torrents/HASH/peers        the peers of a torrent
+//!   POST   /torrents/HASH/pause        pause a torrent.  ?drop_peers=true disconnects its peers
+//!   POST   /torrents/HASH/resume       resume a paused torrent
+//!   POST   /torrents/HASH/verify       hash the data on disk again
+//!   POST   /torrents/HASH/trackers?url=URL&tier=N  start announcing to a tracker, in tier N or
+//!                                      a new last tier
+//!   DELETE /torrents/HASH/trackers?url=URL  stop announcing to a tracker
+//!   POST   /torrents/HASH/reannounce   announce now.  ?url=URL picks the tracker, if it is the
+//!                                      one in use
+//!   POST   /torrents/HASH/rename?name=NAME  rename the torrent's root directory
+//!   POST   /torrents/HASH/files/N/rename?name=NAME  rename file N, counting from 0
+//!   POST   /torrents/HASH/files/N/priority?priority=P  how much file N is wanted: skip, low,
+//!                                      normal or high
+//!   POST   /torrents/HASH/bandwidth_priority?priority=P  the torrent's share of the rate limits:
+//!                                      high, normal or low
+//!   POST   /torrents/HASH/queue?to=TO  move a torrent in the queue.  TO is up, down, top, bottom
+//!                                      or a position, counting from 0
+//!   POST   /torrents/HASH/force_start  run a torrent whatever the queue says.  ?enabled=false
+//!                                      puts it back in the queue
+//!   GET    /status                     whether peers can reach our port, and how many torrents run
+//!   GET    /limits                     the download and upload limits
+//!   POST   /limits?download=N&upload=N change the limits.  N may be "unlimited"
+//!   POST   /blocklist/reload           load the blocklist file again
+//!
+//! `boosttorrent2 ctl` sends these requests from the command line, see ctl.
+use crate::blocklist::Blocklist;
+use crate::client::TorrentHandle;
+use crate::fetch::{
+    fetch_torrent,
+    FetchError,
+};
+use crate::metainfo::MetaInfo;
+use crate::picker::Priority;
+use crate::server::control::ServerHandle;
+use crate::ratelimit::{
+    self,
+    parse_limit,
+    Throttle,
+};
+use crate::reachability::PortStatus;
+use crate::session::{
+    hex,
+    unhex,
+    QueueMove,
+    Session,
+    SessionError,
+};
+use crate::stats::{
+    PeerSnapshot,
+    Snapshot,
+};
+use crate::webhook::json_string;
+use futures::{
+    channel::mpsc::UnboundedSender,
+    future::{
+        self,
+        BoxFuture,
+    },
+    FutureExt,
+};
+use hyper::{
+    body::HttpBody,
+    service::{
+        make_service_fn,
+        service_fn,
+    },
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::error;
+use percent_encoding::percent_decode;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+
+pub mod ctl;
+
+#[cfg(test)]
+mod test;
+
+/// The largest .torrent file that can be added
+const MAX_TORRENT_SIZE: usize = 16 * 1024 * 1024;
+
+type ResponseFuture = BoxFuture<'static, hyper::Result<Response<Body>>>;
+
+/// Everything the API can reach.  Clones share all of it
+#[derive(Clone)]
+pub struct Api {
+    // The running torrents.  The client adds to this as it starts torrents
+    pub torrents: Arc<Mutex<Vec<TorrentHandle>>>,
+    pub session: Arc<Mutex<Session>>,
+    pub download_throttle: Throttle,
+    pub upload_throttle: Throttle,
+    // Where added torrents go, unless the request says otherwise
+    pub download_dir: String,
+    // Info hashes of torrents added to the session, for the client to start
+    pub starts: UnboundedSender<[u8; 20]>,
+    // Asks the client to start whatever the queue lets run now
+    pub queue: UnboundedSender<()>,
+    pub blocklist: Blocklist,
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    List,
+    Add,
+    Get([u8; 20]),
+    Remove([u8; 20]),
+    Peers([u8; 20]),
+    Pause([u8; 20]),
+    Resume([u8; 20]),
+    Verify([u8; 20]),
+    AddTracker([u8; 20]),
+    RemoveTracker([u8; 20]),
+    Reannounce([u8; 20]),
+    RenameRoot([u8; 20]),
+    RenameFile([u8; 20], usize),
+    FilePriority([u8; 20], usize),
+    BandwidthPriority([u8; 20]),
+    Queue([u8; 20]),
+    ForceStart([u8; 20]),
+    Status,
+    Limits,
+    SetLimits,
+    ReloadBlocklist,
+}
+
+/// Work out which endpoint a request is for
+fn route(method: &Method, path: &str) -> Option<Route> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        (&Method::GET, ["torrents"]) => Some(Route::List),
+        (&Method::POST, ["torrents"]) => Some(Route::Add),
+        (&Method::GET, ["torrents", hash]) => unhex(hash).map(Route::Get),
+        (&Method::DELETE, ["torrents", hash]) => unhex(hash).map(Route::Remove),
+        (&Method::GET, ["torrents", hash, "peers"]) => unhex(hash).map(Route::Peers),
+        (&Method::POST, ["torrents", hash, "pause"]) => unhex(hash).map(Route::Pause),
+        (&Method::POST, ["torrents", hash, "resume"]) => unhex(hash).map(Route::Resume),
+        (&Method::POST, ["torrents", hash, "verify"]) => unhex(hash).map(Route::Verify),
+        (&Method::POST, ["torrents", hash, "trackers"]) => unhex(hash).map(Route::AddTracker),
+        (&Method::DELETE, ["torrents", hash, "trackers"]) => unhex(hash).map(Route::RemoveTracker),
+        (&Method::POST, ["torrents", hash, "reannounce"]) => unhex(hash).map(Route::Reannounce),
+        (&Method::POST, ["torrents", hash, "rename"]) => unhex(hash).map(Route::RenameRoot),
+        (&Method::POST, ["torrents", hash, "files", index, "rename"]) => {
+            Some(Route::RenameFile(unhex(hash)?, index.parse().ok()?))
+        }
+        (&Method::POST, ["torrents", hash, "files", index, "priority"]) => {
+            Some(Route::FilePriority(unhex(hash)?, index.parse().ok()?))
+        }
+        (&Method::POST, ["torrents", hash, "bandwidth_priority"]) => unhex(hash).map(Route::BandwidthPriority),
+        (&Method::POST, ["torrents", hash, "queue"]) => unhex(hash).map(Route::Queue),
+        (&Method::POST, ["torrents", hash, "force_start"]) => unhex(hash).map(Route::ForceStart),
+        (&Method::GET, ["status"]) => Some(Route::Status),
+        (&Method::GET, ["limits"]) => Some(Route::Limits),
+        (&Method::POST, ["limits"]) => Some(Route::SetLimits),
+        (&Method::POST, ["blocklist", "reload"]) => Some(Route::ReloadBlocklist),
+        _ => None,
+    }
+}
+
+/// The value of a query parameter, percent decoded
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&')
+        .filter_map(|param| {
+            let mut parts = param.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+        .map(|value| percent_decode(value.as_bytes()).decode_utf8_lossy().into_owned())
+}
+
+fn respond(status: StatusCode, json: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    respond(status, format!("{{\"error\":{}}}", json_string(message)))
+}
+
+fn bad_request(message: &str) -> ResponseFuture {
+    future::ok(error_response(StatusCode::BAD_REQUEST, message)).boxed()
+}
+
+fn not_running() -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, "That torrent is not running")
+}
+
+fn peer_json(peer: &PeerSnapshot) -> String {
+    let address = match peer.address {
+        Some(address) => json_string(&address.to_string()),
+        None => "null".to_string(),
+    };
+    let client = match &peer.client {
+        Some(client) => json_string(client),
+        None => "null".to_string(),
+    };
+    let country = match &peer.country {
+        Some(country) => json_string(country),
+        None => "null".to_string(),
+    };
+    let asn = match peer.asn {
+        Some(asn) => asn.to_string(),
+        None => "null".to_string(),
+    };
+    format!("{{\"address\":{},\"client\":{},\"country\":{},\"asn\":{},\"uploaded\":{},\"downloaded\":{},\
+             \"upload_rate\":{},\"download_rate\":{}}}",
+            address, client, country, asn, peer.uploaded, peer.downloaded, peer.upload_rate, peer.download_rate)
+}
+
+fn peers_json(peers: &[PeerSnapshot]) -> String {
+    let peers: Vec<String> = peers.iter().map(peer_json).collect();
+    format!("[{}]", peers.join(","))
+}
+
+/// A torrent's progress and transfer stats.  The peers are listed if `with_peers` is set, and
+/// only counted otherwise
+fn torrent_json(torrent: &TorrentHandle, snapshot: &Snapshot, with_peers: bool) -> String {
+    let progress = if snapshot.size == 0 {
+        0.0
+    } else {
+        (snapshot.size - snapshot.left.min(snapshot.size)) as f64 / snapshot.size as f64
+    };
+    let mut json = format!("{{\"info_hash\":\"{}\",\"name\":{},\"size\":{},\"left\":{},\"progress\":{:.4},\
+                            \"paused\":{},\"uploaded\":{},\"downloaded\":{},\"upload_rate\":{},\"download_rate\":{},\
+                            \"wasted\":{},\"ratio\":{:.3},\"seed_time\":{},\"read_cache_hits\":{},\"read_cache_misses\":{},\
+                            \"buffers_reused\":{},\"buffers_allocated\":{},\"md5_mismatches\":[{}]",
+                           hex(&torrent.info_hash), json_string(&torrent.name), snapshot.size, snapshot.left,
+                           progress, snapshot.paused, snapshot.uploaded, snapshot.downloaded,
+                           snapshot.upload_rate, snapshot.download_rate, snapshot.wasted, snapshot.ratio(),
+                           snapshot.seed_time.as_secs(), snapshot.read_cache_hits, snapshot.read_cache_misses,
+                           snapshot.buffers_reused, snapshot.buffers_allocated,
+                           snapshot.md5_mismatches.iter().map(|name| json_string(name)).collect::<Vec<_>>().join(","));
+    if with_peers {
+        let _ = write!(json, ",\"peers\":{}", peers_json(&snapshot.peers));
+    } else {
+        let _ = write!(json, ",\"peers\":{}", snapshot.peers.len());
+    }
+    json.push('}');
+    json
+}
+
+fn limit_json(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => (rate / 1024).to_string(),
+        None => "null".to_string(),
+    }
+}
+
+/// Read a request body, giving up on bodies over `limit` bytes.  None if the body was too big
+async fn read_body(mut body: Body, limit: usize) -> hyper::Result<Option<Vec<u8>>> {
+    let mut acc = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if acc.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        acc.extend_from_slice(&chunk);
+    }
+    Ok(Some(acc))
+}
+
+impl Api {
+    /// Answer one request
+    fn handle(&self, req: Request<Body>) -> ResponseFuture {
+        let route = match route(req.method(), req.uri().path()) {
+            Some(route) => route,
+            None => return future::ok(error_response(StatusCode::NOT_FOUND, "No such endpoint")).boxed(),
+        };
+        let query = req.uri().query().map(str::to_owned);
+        match route {
+            Route::Add if query_param(query.as_deref(), "url").is_some() => {
+                let url = query_param(query.as_deref(), "url").unwrap_or_default();
+                let api = self.clone();
+                async move {
+                    Ok(match fetch_torrent(&url, Vec::new()).await {
+                        Ok(contents) => api.add(&contents, query.as_deref()),
+                        Err(FetchError::InvalidURI(e)) => {
+                            error_response(StatusCode::BAD_REQUEST, &format!("Not a valid url: {}", e))
+                        }
+                        Err(e) => {
+                            error_response(StatusCode::BAD_GATEWAY, &format!("Could not download the torrent: {:?}", e))
+                        }
+                    })
+                }.boxed()
+            }
+            Route::Add => {
+                let api = self.clone();
+                async move {
+                    Ok(match read_body(req.into_body(), MAX_TORRENT_SIZE).await? {
+                        Some(body) => api.add(&body, query.as_deref()),
+                        None => error_response(StatusCode::PAYLOAD_TOO_LARGE, "The torrent file is too big"),
+                    })
+                }.boxed()
+            }
+            Route::AddTracker(info_hash) => {
+                let url = match query_param(query.as_deref(), "url") {
+                    Some(url) => url,
+                    None => return bad_request("A url is needed"),
+                };
+                let tier = match query_param(query.as_deref(), "tier").map(|tier| tier.parse::<usize>()) {
+                    Some(Ok(tier)) => Some(tier),
+                    Some(Err(_)) => return bad_request("tier must be a number"),
+                    None => None,
+                };
+                self.command(&info_hash, |handle| handle.add_tracker(tier, &url))
+            }
+            Route::RemoveTracker(info_hash) => match query_param(query.as_deref(), "url") {
+                Some(url) => self.command(&info_hash, |handle| handle.remove_tracker(&url)),
+                None => bad_request("A url is needed"),
+            },
+            Route::Reannounce(info_hash) => {
+                let url = query_param(query.as_deref(), "url");
+                self.command(&info_hash, |handle| handle.reannounce(url.as_deref()))
+            }
+            Route::RenameRoot(info_hash) => match query_param(query.as_deref(), "name") {
+                Some(name) => self.command(&info_hash, |handle| handle.rename_root(&name)),
+                None => bad_request("A name is needed"),
+            },
+            Route::RenameFile(info_hash, index) => match query_param(query.as_deref(), "name") {
+                Some(name) => self.command(&info_hash, |handle| handle.rename_file(index, &name)),
+                None => bad_request("A name is needed"),
+            },
+            Route::FilePriority(info_hash, index) => {
+                match query_param(query.as_deref(), "priority").map(|priority| priority.parse::<Priority>()) {
+                    Some(Ok(priority)) => self.command(&info_hash, |handle| handle.set_file_priority(index, priority)),
+                    _ => bad_request("priority must be skip, low, normal or high"),
+                }
+            }
+            Route::BandwidthPriority(info_hash) => {
+                match query_param(query.as_deref(), "priority").map(|p| p.parse::<ratelimit::Priority>()) {
+                    Some(Ok(priority)) => self.command(&info_hash, |handle| handle.set_bandwidth_priority(priority)),
+                    _ => bad_request("priority must be high, normal or low"),
+                }
+            }
+            route => future::ok(self.answer(route, query.as_deref())).boxed(),
+        }
+    }
+
+    fn find(&self, info_hash: &[u8; 20]) -> Option<TorrentHandle> {
+        self.torrents.lock().unwrap().iter().find(|torrent| &torrent.info_hash == info_hash).cloned()
+    }
+
+    /// Send a command to a running torrent, and answer once the torrent has carried it out
+    fn command<F, C>(&self, info_hash: &[u8; 20], send: F) -> ResponseFuture
+        where F: FnOnce(&ServerHandle) -> C,
+              C: Future<Output=Result<(), String>> + Send + 'static {
+        let torrent = match self.find(info_hash) {
+            Some(torrent) => torrent,
+            None => return future::ok(not_running()).boxed(),
+        };
+        send(&torrent.handle).map(|result| Ok(match result {
+            Ok(()) => respond(StatusCode::OK, "{}".to_string()),
+            Err(reason) => error_response(StatusCode::CONFLICT, &reason),
+        })).boxed()
+    }
+
+    /// Answer a request that doesn't need its body
+    fn answer(&self, route: Route, query: Option<&str>) -> Response<Body> {
+        match route {
+            Route::List => {
+                let torrents: Vec<String> = self.torrents.lock().unwrap().iter()
+                    .map(|torrent| torrent_json(torrent, &torrent.stats.latest(), false))
+                    .collect();
+                respond(StatusCode::OK, format!("[{}]", torrents.join(",")))
+            }
+            Route::Get(info_hash) => match self.find(&info_hash) {
+                Some(torrent) => respond(StatusCode::OK, torrent_json(&torrent, &torrent.stats.latest(), true)),
+                None => not_running(),
+            },
+            Route::Peers(info_hash) => match self.find(&info_hash) {
+                Some(torrent) => respond(StatusCode::OK, peers_json(&torrent.stats.latest().peers)),
+                None => not_running(),
+            },
+            Route::Pause(info_hash) => match self.find(&info_hash) {
+                Some(torrent) => {
+                    let drop_peers = query_param(query, "drop_peers")
+                        .is_some_and(|value| value == "true" || value == "1");
+                    torrent.handle.pause(drop_peers);
+                    respond(StatusCode::ACCEPTED, "{}".to_string())
+                }
+                None => not_running(),
+            },
+            Route::Resume(info_hash) => match self.find(&info_hash) {
+                Some(torrent) => {
+                    torrent.handle.resume();
+                    respond(StatusCode::ACCEPTED, "{}".to_string())
+                }
+                None => not_running(),
+            },
+            Route::Verify(info_hash) => match self.find(&info_hash) {
+                Some(torrent) => {
+                    torrent.handle.verify();
+                    respond(StatusCode::ACCEPTED, "{}".to_string())
+                }
+                None => not_running(),
+            },
+            Route::Remove(info_hash) => self.remove(&info_hash),
+            Route::Queue(info_hash) => {
+                let to = query_param(query, "to").unwrap_or_default();
+                match (to.parse::<usize>(), to.parse::<QueueMove>()) {
+                    (Ok(position), _) => self.requeue(&info_hash, |session| session.move_to(&info_hash, position)),
+                    (_, Ok(direction)) => {
+                        self.requeue(&info_hash, |session| session.move_torrent(&info_hash, direction))
+                    }
+                    _ => error_response(StatusCode::BAD_REQUEST, "to must be up, down, top, bottom or a position"),
+                }
+            }
+            Route::ForceStart(info_hash) => {
+                let enabled = query_param(query, "enabled")
+                    .is_none_or(|value| value == "true" || value == "1");
+                self.requeue(&info_hash, |session| session.set_force_start(&info_hash, enabled))
+            }
+            Route::Status => self.status(),
+            Route::Limits => self.limits(),
+            Route::SetLimits => {
+                let download = query_param(query, "download").map(|value| parse_limit(&value));
+                let upload = query_param(query, "upload").map(|value| parse_limit(&value));
+                if download == Some(None) || upload == Some(None) {
+                    return error_response(StatusCode::BAD_REQUEST,
+                                          "Limits must be a number of KiB/s or \"unlimited\"");
+                }
+                if let Some(Some(rate)) = download {
+                    self.download_throttle.set_rate(rate);
+                }
+                if let Some(Some(rate)) = upload {
+                    self.upload_throttle.set_rate(rate);
+                }
+                let saved = self.session.lock().unwrap()
+                    .set_limits(self.download_throttle.rate(), self.upload_throttle.rate());
+                if let Err(e) = saved {
+                    return error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                          &format!("The limits are set, but could not be saved: {:?}", e));
+                }
+                self.limits()
+            }
+            Route::ReloadBlocklist => {
+                if !self.blocklist.is_loaded() {
+                    return error_response(StatusCode::CONFLICT, "No blocklist was given on the command line");
+                }
+                match self.blocklist.reload() {
+                    Ok(ranges) => respond(StatusCode::OK, format!("{{\"ranges\":{}}}", ranges)),
+                    Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                             &format!("Could not load the blocklist: {:?}", e)),
+                }
+            }
+            Route::Add => error_response(StatusCode::BAD_REQUEST, "A torrent file is needed"),
+            // These wait for the torrent to answer, see handle
+            Route::AddTracker(_) | Route::RemoveTracker(_) | Route::Reannounce(_) | Route::RenameRoot(_) |
+            Route::RenameFile(..) | Route::FilePriority(..) | Route::BandwidthPriority(_) => {
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, "That endpoint waits for the torrent")
+            }
+        }
+    }
+
+    /// The port is open once any torrent has had a peer reach it.  Only when a torrent found it
+    /// closed and none found it open is it closed
+    fn status(&self) -> Response<Body> {
+        let torrents = self.torrents.lock().unwrap();
+        let statuses: Vec<PortStatus> = torrents.iter().map(|torrent| torrent.stats.latest().port_status).collect();
+        let port = if statuses.contains(&PortStatus::Open) {
+            "open"
+        } else if statuses.contains(&PortStatus::Closed) {
+            "closed"
+        } else {
+            "unknown"
+        };
+        respond(StatusCode::OK, format!("{{\"port\":\"{}\",\"torrents\":{}}}", port, torrents.len()))
+    }
+
+    fn limits(&self) -> Response<Body> {
+        respond(StatusCode::OK, format!("{{\"download\":{},\"upload\":{}}}",
+                                        limit_json(self.download_throttle.rate()),
+                                        limit_json(self.upload_throttle.rate())))
+    }
+
+    /// Put a torrent in the session and ask the client to start it
+    fn add(&self, contents: &[u8], query: Option<&str>) -> Response<Body> {
+        let metainfo = match MetaInfo::from_bytes(contents) {
+            Ok(metainfo) => metainfo,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Not a torrent file: {}", e)),
+        };
+        if !metainfo.info.is_v1() {
+            return error_response(StatusCode::BAD_REQUEST, "v2-only torrents can't be downloaded yet");
+        }
+        let download_dir = query_param(query, "download_dir").unwrap_or_else(|| self.download_dir.clone());
+        let added = self.session.lock().unwrap()
+            .add(metainfo.info_hash, metainfo.info.file_info.name(), contents, &download_dir);
+        match added {
+            Ok(true) => (),
+            Ok(false) => return error_response(StatusCode::CONFLICT, "That torrent is already in the session"),
+            Err(e) => return error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                            &format!("Could not add the torrent: {:?}", e)),
+        }
+        let _res = self.starts.unbounded_send(metainfo.info_hash);
+        respond(StatusCode::CREATED, format!("{{\"info_hash\":\"{}\"}}", hex(&metainfo.info_hash)))
+    }
+
+    /// Stop a torrent if it is running, and forget it.  Its files stay on disk
+    fn remove(&self, info_hash: &[u8; 20]) -> Response<Body> {
+        let mut session = self.session.lock().unwrap();
+        if session.get(info_hash).is_none() {
+            return error_response(StatusCode::NOT_FOUND, "That torrent is not in the session");
+        }
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(i) = torrents.iter().position(|torrent| &torrent.info_hash == info_hash) {
+            torrents.remove(i).handle.stop();
+        }
+        match session.remove(info_hash) {
+            Ok(()) => {
+                // The next torrent in the queue can have its place
+                let _res = self.queue.unbounded_send(());
+                respond(StatusCode::OK, "{}".to_string())
+            }
+            Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                     &format!("Could not remove the torrent: {:?}", e)),
+        }
+    }
+
+    /// Change where a torrent stands in the queue, and have the client start what can run now
+    fn requeue<F>(&self, info_hash: &[u8; 20], change: F) -> Response<Body>
+        where F: FnOnce(&mut Session) -> Result<(), SessionError> {
+        let mut session = self.session.lock().unwrap();
+        if session.get(info_hash).is_none() {
+            return error_response(StatusCode::NOT_FOUND, "That torrent is not in the session");
+        }
+        if let Err(e) = change(&mut session) {
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                  &format!("Could not change the queue: {:?}", e));
+        }
+        let _res = self.queue.unbounded_send(());
+        respond(StatusCode::OK, "{}".to_string())
+    }
+}
+
+/// Serve the API on `address`.  The future runs until the runtime is shut down
+pub fn serve(api: Api, address: &SocketAddr) -> hyper::Result<impl Future<Output=()> + Send> {
+    let server = Server::try_bind(address)?
+        .serve(make_service_fn(move |_| {
+            let api = api.clone();
+            future::ok::<_, Infallible>(service_fn(move |req| api.handle(req)))
+        }));
+    Ok(async move {
+        if let Err(e) = server.await {
+            error!("The API server failed: {}", e);
+        }
+    })
+}
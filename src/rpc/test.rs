@@ -0,0 +1,340 @@
+use crate::server::control::{
+    Command,
+    ServerHandle,
+};
+use crate::stats::StatsHandle;
+use futures::channel::mpsc::{
+    unbounded,
+    UnboundedReceiver,
+};
+use futures::executor::{
+    block_on,
+    block_on_stream,
+};
+use std::time::Duration;
+use super::*;
+
+fn api() -> (Api, UnboundedReceiver<Command>, UnboundedReceiver<[u8; 20]>) {
+    let (handle, commands) = ServerHandle::new();
+    let (starts, start_requests) = unbounded();
+    let api = Api {
+        torrents: Arc::new(Mutex::new(vec![TorrentHandle {
+            info_hash: [1; 20],
+            name: "ubuntu.iso".to_string(),
+            stats: StatsHandle::default(),
+            handle,
+        }])),
+        session: Arc::new(Mutex::new(Session::new())),
+        download_throttle: Throttle::new(None),
+        upload_throttle: Throttle::new(Some(50 * 1024)),
+        download_dir: "downloads".to_string(),
+        starts,
+        queue: unbounded().0,
+        blocklist: Blocklist::new(),
+    };
+    (api, commands, start_requests)
+}
+
+fn body(response: Response<Body>) -> String {
+    String::from_utf8(block_on(hyper::body::to_bytes(response.into_body())).unwrap().to_vec()).unwrap()
+}
+
+#[test]
+fn test_route() {
+    let hash = "0101010101010101010101010101010101010101";
+    assert_eq!(route(&Method::GET, "/torrents"), Some(Route::List));
+    assert_eq!(route(&Method::POST, "/torrents/"), Some(Route::Add));
+    assert_eq!(route(&Method::GET, &format!("/torrents/{}", hash)), Some(Route::Get([1; 20])));
+    assert_eq!(route(&Method::POST, &format!("/torrents/{}/pause", hash)), Some(Route::Pause([1; 20])));
+    assert_eq!(route(&Method::DELETE, &format!("/torrents/{}", hash)), Some(Route::Remove([1; 20])));
+    assert_eq!(route(&Method::POST, &format!("/torrents/{}/verify", hash)), Some(Route::Verify([1; 20])));
+    assert_eq!(route(&Method::DELETE, &format!("/torrents/{}/trackers", hash)), Some(Route::RemoveTracker([1; 20])));
+    assert_eq!(route(&Method::POST, &format!("/torrents/{}/files/3/rename", hash)), Some(Route::RenameFile([1; 20], 3)));
+    assert_eq!(route(&Method::POST, &format!("/torrents/{}/files/x/rename", hash)), None);
+    assert_eq!(route(&Method::POST, &format!("/torrents/{}/queue", hash)), Some(Route::Queue([1; 20])));
+    assert_eq!(route(&Method::GET, "/torrents/nothex"), None);
+    assert_eq!(route(&Method::POST, "/blocklist/reload"), Some(Route::ReloadBlocklist));
+    assert_eq!(route(&Method::PUT, "/limits"), None);
+}
+
+#[test]
+fn test_query_param() {
+    let query = Some("drop_peers=true&download_dir=%2Fmnt%2Fmedia");
+    assert_eq!(query_param(query, "download_dir"), Some("/mnt/media".to_string()));
+    assert_eq!(query_param(query, "drop_peers"), Some("true".to_string()));
+    assert_eq!(query_param(query, "upload"), None);
+    assert_eq!(query_param(None, "upload"), None);
+}
+
+#[test]
+fn test_torrent_json() {
+    let (api, _commands, _starts) = api();
+    let torrent = api.torrents.lock().unwrap()[0].clone();
+    let snapshot = Snapshot {
+        uploaded: 10,
+        downloaded: 30,
+        upload_rate: 1,
+        download_rate: 3,
+        wasted: 16384,
+        peers: vec![PeerSnapshot {
+            id: 0,
+            address: Some("10.0.0.1:6881".parse().unwrap()),
+            client: Some("qBittorrent 4.5".to_string()),
+            country: Some("NL".to_string()),
+            asn: None,
+            uploaded: 10,
+            downloaded: 30,
+            upload_rate: 1,
+            download_rate: 3,
+        }],
+        size: 40,
+        left: 10,
+        paused: false,
+        read_cache_hits: 5,
+        read_cache_misses: 2,
+        buffers_reused: 40,
+        buffers_allocated: 8,
+        md5_mismatches: vec!["disc 1/track \"1\".mp3".to_string()],
+        seed_time: Duration::from_secs(600),
+        port_status: PortStatus::Open,
+    };
+    assert_eq!(torrent_json(&torrent, &snapshot, false),
+               "{\"info_hash\":\"0101010101010101010101010101010101010101\",\"name\":\"ubuntu.iso\",\
+                \"size\":40,\"left\":10,\"progress\":0.7500,\"paused\":false,\"uploaded\":10,\
+                \"downloaded\":30,\"upload_rate\":1,\"download_rate\":3,\"wasted\":16384,\"ratio\":0.333,\"seed_time\":600,\
+                \"read_cache_hits\":5,\
+                \"read_cache_misses\":2,\"buffers_reused\":40,\"buffers_allocated\":8,\"md5_mismatches\":[\"disc 1/track \\\"1\\\".mp3\"],\"peers\":1}");
+    assert!(torrent_json(&torrent, &snapshot, true).ends_with(
+        "\"peers\":[{\"address\":\"10.0.0.1:6881\",\"client\":\"qBittorrent 4.5\",\"country\":\"NL\",\"asn\":null,\"uploaded\":10,\"downloaded\":30,\
+         \"upload_rate\":1,\"download_rate\":3}]}"));
+}
+
+#[test]
+fn test_pause_and_resume() {
+    let (api, commands, _starts) = api();
+    let response = api.answer(Route::Pause([1; 20]), Some("drop_peers=1"));
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    api.answer(Route::Resume([1; 20]), None);
+    let mut commands = block_on_stream(commands);
+    match commands.next() {
+        Some(Command::Pause { drop_peers }) => assert!(drop_peers),
+        _ => panic!("expected a pause"),
+    }
+    match commands.next() {
+        Some(Command::Resume) => (),
+        _ => panic!("expected a resume"),
+    }
+    assert_eq!(api.answer(Route::Pause([2; 20]), None).status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_status() {
+    let (api, _commands, _starts) = api();
+    assert_eq!(body(api.answer(Route::Status, None)), "{\"port\":\"unknown\",\"torrents\":1}");
+    let torrent = api.torrents.lock().unwrap()[0].clone();
+    torrent.stats.publish(Snapshot { port_status: PortStatus::Closed, ..Snapshot::default() });
+    assert_eq!(body(api.answer(Route::Status, None)), "{\"port\":\"closed\",\"torrents\":1}");
+}
+
+#[test]
+fn test_limits() {
+    let (api, _commands, _starts) = api();
+    assert_eq!(body(api.answer(Route::Limits, None)), "{\"download\":null,\"upload\":50}");
+    let response = api.answer(Route::SetLimits, Some("download=100&upload=unlimited"));
+    assert_eq!(body(response), "{\"download\":100,\"upload\":null}");
+    assert_eq!(api.download_throttle.rate(), Some(100 * 1024));
+    let response = api.answer(Route::SetLimits, Some("download=fast"));
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(api.download_throttle.rate(), Some(100 * 1024));
+}
+
+#[test]
+fn test_reload_blocklist_without_one() {
+    let (api, _commands, _starts) = api();
+    assert_eq!(api.answer(Route::ReloadBlocklist, None).status(), StatusCode::CONFLICT);
+}
+
+#[test]
+fn test_add_and_remove() {
+    let (api, _commands, starts) = api();
+    let torrent = b"d8:announce19:http://a.example/an4:infod6:lengthi5e4:name4:test12:piece lengthi16384e\
+                    6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+    let response = api.add(torrent, Some("download_dir=elsewhere"));
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let info_hash = MetaInfo::from_bytes(torrent).unwrap().info_hash;
+    assert_eq!(api.session.lock().unwrap().get(&info_hash).unwrap().download_dir, "elsewhere");
+    assert_eq!(block_on_stream(starts).next(), Some(info_hash));
+    assert_eq!(api.add(torrent, None).status(), StatusCode::CONFLICT);
+    assert_eq!(api.add(b"not a torrent", None).status(), StatusCode::BAD_REQUEST);
+
+    assert_eq!(api.answer(Route::Remove(info_hash), None).status(), StatusCode::OK);
+    assert!(api.session.lock().unwrap().get(&info_hash).is_none());
+    assert_eq!(api.answer(Route::Remove(info_hash), None).status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_add_from_a_bad_url() {
+    let (api, _commands, _starts) = api();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/torrents?url=not%20a%20url")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(block_on(api.handle(request)).unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_queue_and_force_start() {
+    let (api, _commands, _starts) = api();
+    let (queue, changes) = unbounded();
+    let api = Api { queue, ..api };
+    for name in &["a", "b"] {
+        let added = api.session.lock().unwrap().add([name.as_bytes()[0]; 20], name, b"", "downloads");
+        assert!(added.unwrap());
+    }
+    let b = [b'b'; 20];
+    assert_eq!(api.answer(Route::Queue(b), Some("to=top")).status(), StatusCode::OK);
+    assert_eq!(api.session.lock().unwrap().torrents()[0].info_hash, b);
+    assert_eq!(api.answer(Route::Queue(b), Some("to=1")).status(), StatusCode::OK);
+    assert_eq!(api.session.lock().unwrap().torrents()[1].info_hash, b);
+    assert_eq!(api.answer(Route::Queue(b), Some("to=sideways")).status(), StatusCode::BAD_REQUEST);
+    assert_eq!(api.answer(Route::ForceStart(b), None).status(), StatusCode::OK);
+    assert!(api.session.lock().unwrap().get(&b).unwrap().force_start);
+    assert_eq!(api.answer(Route::ForceStart(b), Some("enabled=false")).status(), StatusCode::OK);
+    assert!(!api.session.lock().unwrap().get(&b).unwrap().force_start);
+    assert_eq!(api.answer(Route::ForceStart([2; 20]), None).status(), StatusCode::NOT_FOUND);
+    // The client is asked to look at the queue again after each change
+    drop(api);
+    assert_eq!(block_on_stream(changes).count(), 4);
+}
+
+#[test]
+fn test_trackers_wait_for_the_torrent() {
+    let (api, commands, _starts) = api();
+    let hash = "0101010101010101010101010101010101010101";
+    let request = |method, query: &str| {
+        Request::builder()
+            .method(method)
+            .uri(format!("/torrents/{}/trackers?{}", hash, query))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let added = api.handle(request(Method::POST, "url=http%3A%2F%2Ft.example%2Fannounce&tier=1"));
+    let removed = api.handle(request(Method::DELETE, "url=http%3A%2F%2Ft.example%2Fannounce"));
+    let mut commands = block_on_stream(commands);
+    match commands.next() {
+        Some(Command::AddTracker { tier, url, reply }) => {
+            assert_eq!((tier, url.as_str()), (Some(1), "http://t.example/announce"));
+            reply.send(Ok(())).unwrap();
+        }
+        _ => panic!("expected a tracker to be added"),
+    }
+    match commands.next() {
+        Some(Command::RemoveTracker { reply, .. }) => {
+            reply.send(Err("Can't remove the last tracker".to_string())).unwrap();
+        }
+        _ => panic!("expected a tracker to be removed"),
+    }
+    assert_eq!(block_on(added).unwrap().status(), StatusCode::OK);
+    let removed = block_on(removed).unwrap();
+    assert_eq!(removed.status(), StatusCode::CONFLICT);
+    assert_eq!(body(removed), "{\"error\":\"Can't remove the last tracker\"}");
+    assert_eq!(block_on(api.handle(request(Method::DELETE, ""))).unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_reannounce() {
+    let (api, commands, _starts) = api();
+    let request = |hash: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/torrents/{}/reannounce", hash))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let reannounced = api.handle(request("0101010101010101010101010101010101010101"));
+    match block_on_stream(commands).next() {
+        Some(Command::Reannounce { url: None, reply }) => reply.send(Ok(())).unwrap(),
+        _ => panic!("expected a reannounce"),
+    }
+    assert_eq!(block_on(reannounced).unwrap().status(), StatusCode::OK);
+    let response = block_on(api.handle(request("0202020202020202020202020202020202020202"))).unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn test_rename() {
+    let (api, commands, _starts) = api();
+    let hash = "0101010101010101010101010101010101010101";
+    let request = |path: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/torrents/{}/{}", hash, path))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let root = api.handle(request("rename?name=Ubuntu"));
+    let file = api.handle(request("files/1/rename?name=disc%201.iso"));
+    let mut commands = block_on_stream(commands);
+    match commands.next() {
+        Some(Command::RenameRoot { name, reply }) => {
+            assert_eq!(name, "Ubuntu");
+            reply.send(Ok(())).unwrap();
+        }
+        _ => panic!("expected the root to be renamed"),
+    }
+    match commands.next() {
+        Some(Command::RenameFile { index, name, reply }) => {
+            assert_eq!((index, name.as_str()), (1, "disc 1.iso"));
+            reply.send(Err("There is no file 1".to_string())).unwrap();
+        }
+        _ => panic!("expected a file to be renamed"),
+    }
+    assert_eq!(block_on(root).unwrap().status(), StatusCode::OK);
+    assert_eq!(block_on(file).unwrap().status(), StatusCode::CONFLICT);
+    assert_eq!(block_on(api.handle(request("rename"))).unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_file_priority() {
+    let (api, commands, _starts) = api();
+    let request = |query: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/torrents/0101010101010101010101010101010101010101/files/2/priority?{}", query))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let changed = api.handle(request("priority=skip"));
+    match block_on_stream(commands).next() {
+        Some(Command::SetFilePriority { index, priority, reply }) => {
+            assert_eq!((index, priority), (2, Priority::Skip));
+            reply.send(Ok(())).unwrap();
+        }
+        _ => panic!("expected a file priority"),
+    }
+    assert_eq!(block_on(changed).unwrap().status(), StatusCode::OK);
+    assert_eq!(block_on(api.handle(request("priority=urgent"))).unwrap().status(), StatusCode::BAD_REQUEST);
+}
+
+#[test]
+fn test_bandwidth_priority() {
+    let (api, commands, _starts) = api();
+    let request = |query: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("/torrents/0101010101010101010101010101010101010101/bandwidth_priority?{}", query))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let changed = api.handle(request("priority=high"));
+    match block_on_stream(commands).next() {
+        Some(Command::SetBandwidthPriority { priority, reply }) => {
+            assert_eq!(priority, ratelimit::Priority::High);
+            reply.send(Ok(())).unwrap();
+        }
+        _ => panic!("expected a bandwidth priority"),
+    }
+    assert_eq!(block_on(changed).unwrap().status(), StatusCode::OK);
+    assert_eq!(block_on(api.handle(request("priority=skip"))).unwrap().status(), StatusCode::BAD_REQUEST);
+}
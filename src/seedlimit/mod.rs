@@ -0,0 +1,54 @@
+//! seedlimit decides when a torrent has seeded enough: once it uploaded some multiple of what it
+//! downloaded, or seeded for long enough, it stops seeding so the upload goes to other torrents.
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(test)]
+mod test;
+
+/// What to do with a torrent that reached its seed limit
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SeedLimitAction {
+    /// Pause it, so it can be resumed through the API
+    #[default]
+    Pause,
+    /// Stop it for good, until the client is started again
+    Stop,
+}
+
+impl FromStr for SeedLimitAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(SeedLimitAction::Pause),
+            "stop" => Ok(SeedLimitAction::Stop),
+            _ => Err(format!("Invalid seed limit action: {}", s)),
+        }
+    }
+}
+
+/// When to stop seeding.  Either limit is enough, and with neither we seed until stopped
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SeedLimits {
+    pub ratio: Option<f64>,
+    // Time spent seeding, over every run
+    pub time: Option<Duration>,
+    pub action: SeedLimitAction,
+}
+
+impl SeedLimits {
+    pub fn reached(&self, ratio: f64, seed_time: Duration) -> bool {
+        self.ratio.is_some_and(|limit| ratio >= limit) || self.time.is_some_and(|limit| seed_time >= limit)
+    }
+}
+
+/// How many times over we uploaded what we downloaded.  A torrent we never downloaded, like one
+/// we made, is measured against its size
+pub fn share_ratio(uploaded: u64, downloaded: u64, size: u64) -> f64 {
+    match (downloaded, size) {
+        (0, 0) => 0.0,
+        (0, size) => uploaded as f64 / size as f64,
+        (downloaded, _) => uploaded as f64 / downloaded as f64,
+    }
+}
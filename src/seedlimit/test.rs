@@ -0,0 +1,28 @@
+use super::*;
+
+#[test]
+fn test_either_limit_is_enough() {
+    let limits = SeedLimits {
+        ratio: Some(2.0),
+        time: Some(Duration::from_secs(3600)),
+        action: SeedLimitAction::Pause,
+    };
+    assert!(!limits.reached(1.5, Duration::from_secs(60)));
+    assert!(limits.reached(2.0, Duration::from_secs(60)));
+    assert!(limits.reached(0.1, Duration::from_secs(3600)));
+    assert!(!SeedLimits::default().reached(100.0, Duration::from_secs(1_000_000)));
+}
+
+#[test]
+fn test_ratio_of_a_torrent_we_made_uses_its_size() {
+    assert_eq!(share_ratio(300, 100, 100), 3.0);
+    assert_eq!(share_ratio(50, 0, 100), 0.5);
+    assert_eq!(share_ratio(50, 0, 0), 0.0);
+}
+
+#[test]
+fn test_parse_actions() {
+    assert_eq!("pause".parse(), Ok(SeedLimitAction::Pause));
+    assert_eq!("stop".parse(), Ok(SeedLimitAction::Stop));
+    assert!("delete".parse::<SeedLimitAction>().is_err());
+}
@@ -0,0 +1,154 @@
+//! control lets other parts of the client steer a running torrent.  Commands go to the server
+//! over a channel, so they are handled on the server's own task.  Commands that can fail carry a
+//! reply, which says whether the server carried them out.
+use crate::picker::Priority;
+use crate::ratelimit;
+use futures::{
+    channel::{
+        mpsc::{
+            unbounded,
+            UnboundedReceiver,
+            UnboundedSender,
+        },
+        oneshot,
+    },
+    Future,
+};
+
+/// Where the server says whether a command worked, or why not
+pub type Reply = oneshot::Sender<Result<(), String>>;
+
+pub enum Command {
+    // Stop downloading and uploading.  If drop_peers is set, peers are disconnected too
+    Pause {
+        drop_peers: bool,
+    },
+    Resume,
+    // Hash the data on disk again, and download whatever is missing or corrupt
+    Verify,
+    // Stop the torrent for good, as if the process was stopping
+    Stop,
+    // Start using a tracker.  A tier of None puts it in a new tier after the others
+    AddTracker {
+        tier: Option<usize>,
+        url: String,
+        reply: Reply,
+    },
+    RemoveTracker {
+        url: String,
+        reply: Reply,
+    },
+    // Announce now.  `url` says which tracker, or the one in use if None
+    Reannounce {
+        url: Option<String>,
+        reply: Reply,
+    },
+    RenameFile {
+        index: usize,
+        name: String,
+        reply: Reply,
+    },
+    RenameRoot {
+        name: String,
+        reply: Reply,
+    },
+    SetFilePriority {
+        index: usize,
+        priority: Priority,
+        reply: Reply,
+    },
+    // Change how much of the global rate limits the torrent gets next to the others
+    SetBandwidthPriority {
+        priority: ratelimit::Priority,
+        reply: Reply,
+    },
+}
+
+/// Sends commands to one torrent.  Clones talk to the same torrent, and commands sent after it
+/// stops are ignored
+#[derive(Clone)]
+pub struct ServerHandle {
+    commands: UnboundedSender<Command>,
+}
+
+impl ServerHandle {
+    /// Make a handle, and the receiver the server takes its commands from
+    pub fn new() -> (Self, UnboundedReceiver<Command>) {
+        let (commands, receiver) = unbounded();
+        (ServerHandle { commands }, receiver)
+    }
+
+    /// Stop requesting pieces, choke every peer and tell the tracker we stopped.  Connections are
+    /// kept open, so resuming is quick, unless `drop_peers` is set
+    pub fn pause(&self, drop_peers: bool) {
+        let _res = self.commands.unbounded_send(Command::Pause { drop_peers });
+    }
+
+    /// Pick up where a pause left off
+    pub fn resume(&self) {
+        let _res = self.commands.unbounded_send(Command::Resume);
+    }
+
+    /// Recheck the data on disk.  Downloading waits until the recheck is done
+    pub fn verify(&self) {
+        let _res = self.commands.unbounded_send(Command::Verify);
+    }
+
+    /// Stop the torrent, telling the tracker we left.  The server's future finishes once it has
+    pub fn stop(&self) {
+        let _res = self.commands.unbounded_send(Command::Stop);
+    }
+
+    /// Start announcing to another tracker.  Resolves once the torrent has it
+    pub fn add_tracker(&self, tier: Option<usize>, url: &str) -> impl Future<Output = Result<(), String>> {
+        let url = url.to_owned();
+        self.ask(move |reply| Command::AddTracker { tier, url, reply })
+    }
+
+    /// Stop announcing to a tracker.  The last tracker can't be removed
+    pub fn remove_tracker(&self, url: &str) -> impl Future<Output = Result<(), String>> {
+        let url = url.to_owned();
+        self.ask(move |reply| Command::RemoveTracker { url, reply })
+    }
+
+    /// Announce right away instead of waiting for the tracker's interval.  Fails if the tracker
+    /// asked us to wait longer between announces
+    pub fn reannounce(&self, url: Option<&str>) -> impl Future<Output = Result<(), String>> {
+        let url = url.map(str::to_owned);
+        self.ask(move |reply| Command::Reannounce { url, reply })
+    }
+
+    /// Give file `index` a new name, moving it on disk if it is there
+    pub fn rename_file(&self, index: usize, name: &str) -> impl Future<Output = Result<(), String>> {
+        let name = name.to_owned();
+        self.ask(move |reply| Command::RenameFile { index, name, reply })
+    }
+
+    /// Give the torrent's root directory a new name
+    pub fn rename_root(&self, name: &str) -> impl Future<Output = Result<(), String>> {
+        let name = name.to_owned();
+        self.ask(move |reply| Command::RenameRoot { name, reply })
+    }
+
+    /// Change how much file `index` is wanted.  Skipped files stop being downloaded
+    pub fn set_file_priority(&self, index: usize, priority: Priority) -> impl Future<Output = Result<(), String>> {
+        self.ask(move |reply| Command::SetFilePriority { index, priority, reply })
+    }
+
+    /// Change the torrent's share of the global rate limits.  Open connections follow the change
+    pub fn set_bandwidth_priority(&self, priority: ratelimit::Priority) -> impl Future<Output = Result<(), String>> {
+        self.ask(move |reply| Command::SetBandwidthPriority { priority, reply })
+    }
+
+    /// Send a command that replies, and wait for the reply
+    fn ask<F: FnOnce(Reply) -> Command>(&self, command: F) -> impl Future<Output = Result<(), String>> {
+        let (reply, receiver) = oneshot::channel();
+        let _res = self.commands.unbounded_send(command(reply));
+        async move {
+            match receiver.await {
+                Ok(result) => result,
+                Err(oneshot::Canceled) => Err("The torrent stopped".to_string()),
+            }
+        }
+    }
+}
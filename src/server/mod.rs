@@ -1,176 +1,2687 @@
-use bit_vec::BitVec;
-use futures::sync::mpsc::{channel, Receiver, Sender};
+use futures::channel::mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures::channel::oneshot;
+use futures::{
+    future::{
+        self,
+        BoxFuture,
+    },
+    stream::{
+        BoxStream,
+        SelectAll,
+    },
+    FutureExt,
+    StreamExt,
+    TryFutureExt,
+};
 use log::{
+    debug,
     error,
+    info,
     trace,
     warn,
 };
+use crate::ban::{
+    BadData,
+    BanList,
+};
+use crate::blocklist::Blocklist;
+use crate::bufpool::BufferPool;
+use crate::choke::{
+    Choker,
+    SeedStrategy,
+    UNCHOKE_INTERVAL,
+};
+use crate::dht::DhtHandle;
+use crate::superseed::SuperSeed;
+use crate::seedlimit::{
+    share_ratio,
+    SeedLimitAction,
+    SeedLimits,
+};
+use crate::dialer::{
+    ConnectionLimits,
+    DIAL_TIMEOUT,
+    Dialer,
+    Permit,
+};
+use crate::geoip::GeoIp;
+use crate::hasher::HashPool;
+use crate::logging;
+use crate::i2p::{
+    self,
+    I2pTransport,
+    SamConfig,
+};
+use crate::listen::{
+    self,
+    Incoming,
+    Routes,
+};
 use crate::metainfo::MetaInfo;
-use crate::peer::Peer;
-use crate::piece::Piece;
-use replace_with::replace_with;
+use crate::peer::{
+    self,
+    client_ident::{
+        self,
+        ClientIdent,
+    },
+    dscp::{
+        self,
+        Dscp,
+    },
+    extension::{
+        HolepunchError,
+        HolepunchMessage,
+    },
+    mse::{
+        self,
+        Encrypted,
+        EncryptionPolicy,
+    },
+    priority,
+    Connection,
+    HaveBroadcast,
+    Peer,
+    PeerConfig,
+    PeerEvent,
+    PexSnapshot,
+    TexSnapshot,
+};
+use crate::picker::{
+    self,
+    Picker,
+    Priority,
+};
+use crate::piece::{
+    Arrivals,
+    budget::MemoryBudget,
+    Piece,
+    BLOCK_SIZE,
+};
+use crate::piecefield::PieceField;
+use crate::ratelimit::{
+    self,
+    PriorityClass,
+    Throttle,
+    Throttled,
+};
+use crate::resume::ResumeData;
+use crate::shutdown::Shutdown;
+use crate::stats::{
+    Stats,
+    StatsHandle,
+    SNAPSHOT_INTERVAL,
+};
+use crate::reachability::{
+    PortStatus,
+    Reachability,
+};
+use crate::session::{
+    self,
+    Session,
+};
+use crate::socks::{
+    self,
+    ProxyConfig,
+};
+use crate::ssl::{
+    SslConfig,
+    SwarmTls,
+};
+use crate::storage::{
+    Allocation,
+    cache::{
+        CacheConfig,
+        ReadStats,
+    },
+    reader::{
+        self,
+        ReadRequest,
+    },
+    FileMap,
+    verify::{
+        self,
+        Report,
+    },
+    writer::{
+        self,
+        StorageError,
+        Written,
+    },
+};
+use crate::events::{
+    Broadcast,
+    Event,
+};
+use crate::webhook::{
+    EventKind,
+    Notification,
+    Webhooks,
+};
+use hyper::Uri;
+use std::collections::{
+    BTreeSet,
+    HashMap,
+    HashSet,
+    VecDeque,
+};
 use std::default::Default;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::io;
+use std::net::{
+    IpAddr,
+    Ipv6Addr,
+    SocketAddr,
+};
 use std::ops::Deref;
-use std::str::FromStr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::task::{
+    Context,
+    Poll,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
 use tokio::{
-    io::Error,
-    net::{
-        tcp::Incoming,
-        TcpListener,
-    },
-    prelude::{
-        Async,
-        Future,
-        future,
-        Stream,
-        stream,
+    io::{
+        Error,
+        ErrorKind,
     },
+    net::TcpStream,
     spawn,
+    time::{
+        self,
+        Interval,
+        Sleep,
+    },
+};
+use self::control::{
+    Command,
+    ServerHandle,
 };
+use crate::types::PeerInfo;
 use crate::tracker::{
+    ScrapeInfo,
+    TrackerError,
+    backoff::{
+        self,
+        Backoff,
+        Retry,
+    },
+    cache::ResponseCache,
+    http::TlsOptions,
+    passkey::{
+        self,
+        Passkeys,
+    },
+    tiers::TrackerTiers,
     Tracker,
     TrackerResponse,
+    TrackerSuccessResponse,
 };
 
+pub mod control;
+
 /// Type alias for a heap allocated Stream trait object
-type BoxedStream<T> = Box<dyn Stream<Item=T, Error=()> + Send>;
+type BoxedStream<T> = BoxStream<'static, T>;
+
+/// A new peer connection, and how far its handshake got
+enum NewConnection {
+    // A connection we opened to the peer at this address
+    Dialed(TcpStream, SocketAddr),
+    // A connection opened to us through I2P.  None of its handshake has been read
+    Accepted(TcpStream),
+    // A connection the session's listener found is for this torrent
+    Routed(Box<Incoming>),
+}
+
+impl NewConnection {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            NewConnection::Dialed(conn, _) | NewConnection::Accepted(conn) => conn,
+            NewConnection::Routed(incoming) => incoming.conn.get_ref(),
+        }
+    }
+}
+
+/// An outgoing connection being opened, the address it is to, and its place under the half-open
+/// limit
+type Dial = (SocketAddr, Permit, BoxFuture<'static, io::Result<TcpStream>>);
+
+/// How often to save our transfer stats to the session
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often to tell peers about the peers we connected to and dropped (BEP 11)
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most peers to remember for connecting to later
+const MAX_KNOWN_PEERS: usize = 2000;
+
+/// Most trackers to take from peers over lt_tex (BEP 28), so peers can't flood the tracker list
+const MAX_TEX_TRACKERS: usize = 10;
+
+/// Give up on a dial after DIAL_TIMEOUT, with an io error like any other failure to connect
+async fn dial_timeout<F: Future<Output=io::Result<TcpStream>>>(dial: F) -> io::Result<TcpStream> {
+    time::timeout(DIAL_TIMEOUT, dial).await
+        .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "timed out")))
+}
+
+/// Endgame starts once this few blocks are left to download, or once every missing piece is
+/// being downloaded.  In endgame, idle peers also ask for the blocks still missing from pieces
+/// others are working on, so one slow peer can't hold up the end of the download
+const ENDGAME_BLOCKS: u64 = 64;
+
+/// How long to wait before starting over when every tracker is down
+const TRACKER_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait for the tracker to answer our Stopped announce before giving up on it
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for finished pieces to be written when stopping
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often to search the DHT for peers
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Most block buffers to keep around for uploading.  The reader's queue plus a few blocks queued
+/// for each peer we upload to
+const UPLOAD_BUFFERS: usize = 256;
+
+/// How long to wait before checking a swarm with no seeds again
+const SEED_RECHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Where we are in checking that a swarm has a seed before joining it
+enum SeedCheck {
+    // Scrape as soon as we can
+    Due,
+    Scraping(BoxFuture<'static, Result<ScrapeInfo, TrackerError>>),
+    // The tracker couldn't be scraped, so ask the DHT instead (BEP 33)
+    ScrapingDht(oneshot::Receiver<ScrapeInfo>),
+    // The swarm had no seeds, so look again later
+    Waiting(Pin<Box<Sleep>>),
+}
+
+/// Where we are in stopping the torrent
+enum Stopping {
+    // Waiting for finished pieces to be written, so they aren't downloaded again
+    Flushing(Pin<Box<Sleep>>),
+    // Waiting for the tracker to answer our Stopped announce
+    Announcing(Pin<Box<Sleep>>),
+}
+
+/// What the server knows about a connected peer's downloads
+struct PeerPieces {
+    // The pieces the peer said it has, as of its last request
+    pieces: PieceField,
+    // The piece the peer is downloading
+    current: Option<u32>,
+    // The blocks of that piece that have arrived, shared with any peer given it in endgame
+    arrivals: Option<Arrivals>,
+    // The last piece given to the peer
+    last_piece: Option<u32>,
+    // Whether the peer stopped sending us the blocks we asked for
+    snubbed: bool,
+}
+
+/// The Have messages on their way to one peer.  When the peer's channel is full they wait here
+/// instead of being dropped, so a slow peer still hears about every piece
+struct HaveOutbox {
+    sender: Sender<HaveBroadcast>,
+    queued: VecDeque<HaveBroadcast>,
+}
+
+impl HaveOutbox {
+    fn new(sender: Sender<HaveBroadcast>) -> Self {
+        HaveOutbox {
+            sender,
+            queued: VecDeque::new(),
+        }
+    }
+
+    /// Queue a Have and pass on as many as the channel takes.  Returns false once the peer is gone
+    fn send(&mut self, have: HaveBroadcast) -> bool {
+        self.queued.push_back(have);
+        self.pass_on()
+    }
 
+    /// Pass on queued Haves until the channel is full, in which case the task is woken once it has
+    /// room.  Returns false once the peer is gone
+    fn flush(&mut self, cx: &mut Context<'_>) -> bool {
+        while !self.queued.is_empty() {
+            match self.sender.poll_ready(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Pending => return true,
+                Poll::Ready(Err(_)) => return false,
+            }
+            if !self.pass_on() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Pass on queued Haves until the channel is full.  Returns false once the peer is gone
+    fn pass_on(&mut self) -> bool {
+        while let Some(have) = self.queued.pop_front() {
+            if let Err(e) = self.sender.try_send(have) {
+                if e.is_disconnected() {
+                    return false;
+                }
+                self.queued.push_front(e.into_inner());
+                return true;
+            }
+        }
+        true
+    }
+}
+
+/// Settings for the server that come from the command line
+#[derive(Clone)]
+pub struct Config {
+    // Limits the piece data held in memory at once.  Every torrent of the session shares it
+    pub piece_memory: MemoryBudget,
+    // How long checked pieces can wait in memory to be written together
+    pub write_cache: CacheConfig,
+    // Most bytes of pieces read for uploading to keep in memory.  0 reads every block from disk
+    pub read_cache: usize,
+    // How the torrent's files take up space before their data arrives
+    pub allocation: Allocation,
+    // Where files are downloaded until they are complete, and whether they get a .part suffix
+    // there.  None downloads straight into the download directory
+    pub incomplete_dir: Option<PathBuf>,
+    pub part_suffix: bool,
+    // If true, files that come with an MD5 sum are checked against it once they are complete
+    pub check_md5: bool,
+    // If true, don't send Have messages to peers that already have the piece
+    pub suppress_redundant_haves: bool,
+    // Our address as seen from the internet, if the user told us
+    pub external_ip: Option<IpAddr>,
+    // Trackers to use on top of the ones in the torrent file
+    pub extra_trackers: Vec<String>,
+    // Urls to POST notifications to
+    pub webhook_urls: Vec<Uri>,
+    // Which events the webhooks are told about
+    pub webhook_events: Vec<EventKind>,
+    // Told what the torrent is doing, for programs embedding the client
+    pub events: Broadcast,
+    // Code point to mark peer traffic with
+    pub peer_dscp: Option<Dscp>,
+    // Proxy to make tracker requests and outgoing peer connections through
+    pub proxy: Option<ProxyConfig>,
+    // How to check the certificates of https trackers
+    pub tracker_tls: TlsOptions,
+    // If true, the session doesn't listen for incoming peer connections
+    pub refuse_incoming: bool,
+    // Where the session's listener hands out incoming peer connections.  None if it doesn't
+    pub routes: Option<Routes>,
+    // Whether the session's listener can be reached, shared by every torrent
+    pub reachability: Option<Reachability>,
+    // How to pick peers to upload to while seeding
+    pub seed_strategy: SeedStrategy,
+    // When to stop seeding, and how
+    pub seed_limits: SeedLimits,
+    // Time the torrent spent seeding in earlier runs
+    pub seed_time: Duration,
+    // If true, give peers runs of neighbouring pieces
+    pub disk_locality: bool,
+    // If true, download pieces in order so media can be played while it downloads
+    pub sequential: bool,
+    // How much each file is wanted, by file index.  Files past the end are normal
+    pub file_priorities: Vec<Priority>,
+    // If true, the torrent starts paused, like it was when the client last stopped
+    pub paused: bool,
+    // Our certificate for SSL torrents
+    pub ssl: Option<SslConfig>,
+    // If set, the torrent only uses I2P, through this SAM bridge
+    pub i2p: Option<SamConfig>,
+    // If true, don't join a swarm until a scrape shows it has a seed
+    pub check_seeds: bool,
+    // Port to listen on and announce
+    pub port: u16,
+    // Most peers to be connected to at once
+    pub max_peers: Option<usize>,
+    // How many peers to try to stay connected to, dialing known peers when we have fewer
+    pub target_peers: usize,
+    // Caps on connections, shared by every torrent
+    pub connection_limits: ConnectionLimits,
+    // How many peers to ask the tracker for in each announce
+    pub numwant: u32,
+    // How many peers we upload to at once, counting the optimistic unchoke
+    pub unchoke_slots: usize,
+    // How long peers that keep sending bad data are banned for
+    pub bad_data_ban: Duration,
+    // How long a peer has to send its handshake before the connection is dropped
+    pub handshake_timeout: Duration,
+    // Clients we refuse to talk to, by name and optionally version, like "Xunlei" or
+    // "qBittorrent 4.1"
+    pub banned_clients: Vec<String>,
+    // Ranges of addresses we never connect to or accept
+    pub blocklist: Blocklist,
+    // The DHT node to find peers through, if it is on
+    pub dht: Option<DhtHandle>,
+    // Looks up where peers are.  Every torrent of the session shares it
+    pub geoip: Option<Arc<GeoIp>>,
+    // Resolves when the process is asked to stop
+    pub shutdown: Option<Shutdown>,
+    // Limits on how fast all peers together may download and upload
+    pub download_throttle: Throttle,
+    pub upload_throttle: Throttle,
+    // How much of those limits this torrent gets next to the others
+    pub bandwidth_priority: ratelimit::Priority,
+    // Whether peer connections are encrypted
+    pub encryption: EncryptionPolicy,
+    // Threads that hash pieces, shared by every torrent
+    pub hash_pool: HashPool,
+}
 
 /// This is the server that will listen for and spawn peer connections, manage the tracker, and
 /// write pieces to the file.  This is "main" for a client
 pub struct Server {
     peer_id: [u8; 20],
     info_hash: [u8; 20],
-    uploaded: u64,
-    uploaded_stream: BoxedStream<u32>,
-    downloaded: u64,
-    downloaded_stream: BoxedStream<u32>,
+    // The truncated v2 hash of a hybrid torrent, which v2 peers handshake with
+    alt_info_hash: Option<[u8; 20]>,
+    // The name of the torrent
+    name: String,
+    // What the torrent logs as, see logging
+    log_target: String,
+    // Bytes moved, tagged with the peer that moved them.  Each peer's stream ends when it is gone
+    uploaded_stream: SelectAll<BoxedStream<(usize, u32)>>,
+    downloaded_stream: SelectAll<BoxedStream<(usize, u32)>>,
+    // Totals and rates, for the torrent and for each peer
+    stats: Stats,
+    // Where the latest snapshot of stats is published
+    stats_handle: StatsHandle,
+    stats_interval: Interval,
     left: u64,
-    listener: Incoming,
+    // The connections peers make to us, from the session's listener.  None if we don't accept
+    // incoming connections
+    incoming: Option<UnboundedReceiver<Incoming>>,
+    routes: Option<Routes>,
+    // Our routable IPv6 address, if we have one.  IPv6 peers are only used when we do
+    ipv6: Option<Ipv6Addr>,
+    port: u16,
+    // Every peer with a running task
+    connected: HashSet<usize>,
+    max_peers: Option<usize>,
     tracker: Tracker,
-    piece_stream: BoxedStream<(Piece, Sender<Piece>, BitVec)>,
+    // Every tracker we know about for this torrent
+    trackers: TrackerTiers,
+    // Where the torrent's files are on disk.  Shared with the storage thread
+    files: Arc<Mutex<FileMap>>,
+    // Finished pieces go to the storage thread to be checked and written
+    storage: Sender<Piece>,
+    // Pieces waiting for room in the storage thread's queue
+    unwritten: VecDeque<Piece>,
+    written_stream: BoxedStream<Written>,
+    // Reads blocks peers ask for from disk
+    reader: Sender<ReadRequest>,
+    read_stats: Arc<ReadStats>,
+    // Buffers uploaded blocks are read into, shared with the reader and the peers
+    buffers: BufferPool,
+    // What every peer tells us.  Each peer's stream ends with its Gone
+    piece_stream: SelectAll<BoxedStream<PeerEvent>>,
+    // Expected hash of each piece
+    piece_hashes: Vec<[u8; 20]>,
+    piece_length: u64,
+    download_size: u64,
+    // What each connected peer has and is downloading
+    peers: HashMap<usize, PeerPieces>,
+    // Peers waiting for a piece, and where to send it
+    waiting: VecDeque<(usize, Sender<Piece>)>,
+    // Pieces handed back by snubbing peers, with the blocks they did send, for another peer to
+    // finish
+    partial: HashMap<u32, Piece>,
+    // Pieces sent to the storage thread that haven't been written yet.  In endgame a piece can
+    // be finished by more than one peer, and only the first copy is kept
+    finishing: HashSet<u32>,
+    next_peer_id: usize,
+    // Addresses of the connected peers that are on the internet
+    peer_addresses: HashMap<usize, SocketAddr>,
+    // The clients the connected peers run, from their peer ids
+    peer_clients: HashMap<usize, ClientIdent>,
+    // Peers we heard about from trackers and other peers, and which of them to connect to
+    dialer: Dialer,
+    // The peer ids trackers gave for addresses, so we can check we reached the right peer
+    tracker_peer_ids: HashMap<SocketAddr, [u8; 20]>,
+    // How many peers to try to stay connected to
+    target_peers: usize,
+    connection_limits: ConnectionLimits,
+    // Each connected peer's place under the connection limit
+    permits: HashMap<usize, Permit>,
+    // Off for private torrents, and on I2P where addresses mustn't leak
+    pex: bool,
+    pex_senders: Vec<Sender<PexSnapshot>>,
+    // Off for private torrents, which must only use the trackers in the torrent
+    tex: bool,
+    tex_senders: Vec<Sender<TexSnapshot>>,
+    // How many trackers peers have added
+    tex_trackers: usize,
+    // Where to send ut_holepunch messages for each peer, and the peers that support it
+    holepunch_senders: HashMap<usize, Sender<HolepunchMessage>>,
+    holepunch_peers: HashSet<usize>,
+    // Connections we are opening, to known peers or because a relay told us to so the peer at
+    // the other end can get through its NAT
+    dials: Vec<Dial>,
+    pex_interval: Interval,
+    // None for private torrents and on I2P, like pex
+    dht: Option<DhtHandle>,
+    // The DHT search that is running, if any
+    dht_search: Option<BoxedStream<Vec<SocketAddr>>>,
+    dht_interval: Interval,
+    // Optional databases used to tag peers with where they are
+    geoip: Option<Arc<GeoIp>>,
+    // Peers we refuse to talk to, shared by every torrent of the session
+    bans: Arc<Mutex<BanList>>,
+    bad_data: BadData,
+    bad_data_ban: Duration,
+    handshake_timeout: Duration,
+    banned_clients: Vec<String>,
+    blocklist: Blocklist,
+    // Who sent the blocks of each piece waiting to be checked and written
+    piece_sources: HashMap<u32, Vec<IpAddr>>,
+    // Limits how much piece data can be held in memory at once
+    memory_budget: MemoryBudget,
+    // Used to tell each connected peer about pieces we finish
+    have_outboxes: HashMap<usize, HaveOutbox>,
+    suppress_redundant_haves: bool,
+    // Whether peers can connect to the session's listener.  None if we don't accept incoming
+    // connections
+    reachability: Option<Reachability>,
+    // Our address as seen from the internet, from the user or else from a tracker
+    external_ip: Option<IpAddr>,
+    webhooks: Webhooks,
+    events: Broadcast,
+    // Whether the webhooks have been told the torrent was added
+    added_notified: bool,
+    peer_dscp: Option<Dscp>,
+    proxy: Option<ProxyConfig>,
+    tracker_tls: TlsOptions,
+    seed_strategy: SeedStrategy,
+    seed_limits: SeedLimits,
+    // Time spent seeding over every run, counted up to seed_clock
+    seed_time: Duration,
+    seed_clock: Instant,
+    // Set once the seed limits are reached, so a torrent resumed by hand isn't paused again
+    seed_limit_reached: bool,
+    // Decides which peers we upload to
+    choker: Choker,
+    choke_senders: HashMap<usize, Sender<bool>>,
+    choke_interval: Interval,
+    // Decides which pieces to download next
+    picker: Picker,
+    // Shared with every other torrent, so our lifetime stats survive restarts
+    session: Arc<Mutex<Session>>,
+    // How much of uploaded/downloaded has been added to the session
+    recorded_uploaded: u64,
+    recorded_downloaded: u64,
+    recorded_seed_time: Duration,
+    // Where the torrent's progress is saved between runs
+    resume_path: PathBuf,
+    last_session_save: Instant,
+    // Set for SSL torrents, where every peer connection has to go through SSL
+    tls: Option<SwarmTls>,
+    // Passkeys for private trackers, shared with every other torrent.  The tracker urls we keep
+    // are templates that the passkeys are filled into
+    passkeys: Arc<Mutex<Passkeys>>,
+    // The version of the passkeys our tracker url was built from
+    passkeys_version: u64,
+    // Set if the torrent is on I2P instead of the internet
+    i2p: Option<I2pTransport>,
+    // False until the first announce to the current tracker.  On I2P we can't announce until we
+    // have a session
+    tracker_started: bool,
+    // True once the trackers have been told we completed, or if we started out complete, so the
+    // completed announce only goes out for a download that finishes, and only once
+    completed_announced: bool,
+    // The completed announce waiting on the tracker to be started
+    completed_pending: bool,
+    // Sent with every announce, to whichever tracker, so trackers know us if our address changes
+    tracker_key: u32,
+    // How many peers to ask the tracker for
+    numwant: u32,
+    // True once every piece is on disk, and we only upload
+    seeding: bool,
+    // Set while super-seeding, for which piece each peer is offered
+    super_seed: Option<SuperSeed>,
+    // How much each file is wanted, by file index
+    file_priorities: Vec<Priority>,
+    // While paused no pieces are requested, every peer is choked, and we aren't announced
+    paused: bool,
+    // A running recheck of the data on disk, and the pieces finished since it started
+    verifying: Option<(oneshot::Receiver<Report>, Vec<u32>)>,
+    hash_pool: HashPool,
+    // Files with all their pieces on disk, by file index
+    finished_files: HashSet<usize>,
+    // The MD5 sum of each file, if it has one and we check them
+    md5sums: Vec<Option<String>>,
+    // Sums of finished files come back through here from the threads computing them
+    md5_sender: UnboundedSender<(usize, io::Result<String>)>,
+    md5_results: UnboundedReceiver<(usize, io::Result<String>)>,
+    // Files whose data doesn't match their MD5 sum
+    md5_mismatches: BTreeSet<usize>,
+    // Commands from handles, and a handle to give out
+    commands: UnboundedReceiver<Command>,
+    handle: ServerHandle,
+    // When to send the next regular announce.  Set once the tracker answers
+    announce_timer: Option<Pin<Box<Sleep>>>,
+    // Set after a tracker failed, for when to try again
+    tracker_backoff: Option<Pin<Box<Sleep>>>,
+    // How often each tracker has failed in a row
+    tracker_failures: Backoff,
+    // The last answer from each tracker, so switching back to one keeps to its min interval
+    tracker_cache: ResponseCache,
+    shutdown: Option<Shutdown>,
+    // Set once we are stopping
+    stopping: Option<Stopping>,
+    // Set while we wait to find out if the swarm has a seed.  Nothing is announced until then
+    seed_check: Option<SeedCheck>,
+    // Shared with every other torrent, so the limits apply to all of them together
+    download_throttle: Throttle,
+    upload_throttle: Throttle,
+    // Shared with our peer connections, so changing it changes their share of the limits
+    bandwidth_priority: PriorityClass,
+    encryption: EncryptionPolicy,
 }
 
 impl Server {
-    pub fn new(peer_id: [u8; 20], meta: MetaInfo) -> Self {
-        let address = SocketAddr::from_str("0.0.0.0:6888").unwrap();
+    pub fn new(peer_id: [u8; 20], meta: MetaInfo, mut files: FileMap, bans: Arc<Mutex<BanList>>,
+               session: Arc<Mutex<Session>>, passkeys: Arc<Mutex<Passkeys>>, config: Config) -> Self {
         let download_size = meta.info.file_info.size() as u64;
-        let mut tracker = Tracker::new(
-            peer_id.clone(),
-            meta.announce,
-            meta.info_hash.clone(),
-            6888,
-        );
+        let (mut tracker, trackers, passkeys_version) = {
+            let mut passkeys = passkeys.lock().unwrap();
+            let announce = passkeys.templatize(&meta.announce);
+            let announce_list = meta.announce_list.as_ref().map(|list| {
+                list.iter().map(|(tier, url)| (*tier, passkeys.templatize(url))).collect()
+            });
+            let mut trackers = TrackerTiers::new(&announce, &announce_list);
+            trackers.shuffle();
+            for url in &config.extra_trackers {
+                trackers.add(None, &passkeys.templatize(url));
+            }
+            if config.i2p.is_some() {
+                // Announcing to a tracker on the internet would tie our I2P destination to our ip
+                let clearnet: Vec<String> = trackers.tiers().iter()
+                    .flatten()
+                    .filter(|url| !i2p::is_i2p(url))
+                    .cloned()
+                    .collect();
+                for url in clearnet {
+                    trackers.remove(&url);
+                }
+                if trackers.first().is_none() {
+                    warn!("{} has no I2P trackers", meta.info.file_info.name());
+                }
+            }
+            let tracker = Tracker::new(
+                peer_id,
+                passkeys.fill(trackers.first().unwrap_or(&announce)),
+                meta.info_hash,
+                config.port,
+            );
+            (tracker, trackers, passkeys.version())
+        };
         let info_hash = meta.info_hash;
-        tracker.start(download_size);
-        Server {
+        let alt_info_hash = meta.alt_info_hash();
+        let name = meta.info.file_info.name().to_owned();
+        let num_pieces = meta.info.pieces.len();
+        let piece_length = meta.info.piece_length as u64;
+        files.set_allocation(config.allocation);
+        if let Some(dir) = &config.incomplete_dir {
+            files.set_incomplete_dir(dir, config.part_suffix);
+        }
+        for (i, priority) in config.file_priorities.iter().enumerate() {
+            files.set_skipped(i, *priority == Priority::Skip);
+        }
+        // Pick up where the last run left off.  Resume data is kept in the state directory, but
+        // older runs left it next to the download
+        let legacy_resume_path = ResumeData::path(files.download_dir(), &name);
+        let resume_path = match session.lock().unwrap().dir() {
+            Some(dir) => session::resume_path(dir, &info_hash),
+            None => legacy_resume_path.clone(),
+        };
+        let loaded = match ResumeData::load(&resume_path) {
+            Ok(None) if resume_path != legacy_resume_path => ResumeData::load(&legacy_resume_path),
+            loaded => loaded,
+        };
+        let resume = match loaded {
+            Ok(Some(resume)) => {
+                if resume.info_hash == info_hash && resume.pieces.len() == num_pieces && resume.matches(&files) {
+                    Some(resume)
+                } else {
+                    warn!("The resume data of {} doesn't match its files, so it starts over", name);
+                    None
+                }
+            }
+            Ok(None) => None,
+            Err(e) => {
+                warn!("Could not read the resume data of {}: {:?}", name, e);
+                None
+            }
+        };
+        let mut picker = Picker::new(num_pieces, config.disk_locality);
+        picker.set_sequential(config.sequential);
+        picker.set_priorities(picker::piece_priorities(files.files(), piece_length, num_pieces,
+                                                       &config.file_priorities));
+        let (uploaded, downloaded) = match &resume {
+            Some(resume) => {
+                for (index, have) in resume.pieces.iter().enumerate() {
+                    if have {
+                        picker.finish(index as u32);
+                    }
+                }
+                tracker.set_tracker_id(resume.tracker_id.clone());
+                (resume.uploaded, resume.downloaded)
+            }
+            None => (0, 0),
+        };
+        let tls = meta.info.ssl_cert.as_ref().map(|ca| {
+            let ssl = config.ssl.as_ref()
+                .expect("This is an SSL torrent, so --ssl-cert and --ssl-key are needed");
+            SwarmTls::new(ca, ssl, &info_hash).expect("Failed to set up SSL")
+        });
+        // Looking up our address would go around the proxy
+        let ipv6 = if config.i2p.is_none() && config.proxy.is_none() {
+            listen::global_ipv6()
+        } else {
+            None
+        };
+        let info_hashes: Vec<[u8; 20]> = Some(info_hash).into_iter().chain(alt_info_hash).collect();
+        let incoming = config.routes.as_ref().map(|routes| routes.add(&info_hashes, tls.is_some()));
+        // Skipped pieces don't count towards what is left
+        let left = picker.missing().iter()
+            .map(|index| piece_length.min(download_size - *index as u64 * piece_length))
+            .sum::<u64>();
+        // Seeding doesn't need anyone else to have the torrent
+        let seed_check = if config.check_seeds && left > 0 {
+            Some(SeedCheck::Due)
+        } else {
+            None
+        };
+        let tracker_key = resume.as_ref().and_then(|resume| resume.tracker_key)
+            .unwrap_or_else(rand::random);
+        tracker.set_key(tracker_key);
+        tracker.set_numwant(config.numwant);
+        let tracker_started = config.i2p.is_none() && seed_check.is_none();
+        if tracker_started {
+            tracker.start(left);
+        }
+        let i2p = config.i2p.as_ref().map(|sam| {
+            let id = format!("boosttorrent-{:02x}{:02x}{:02x}{:02x}-{:08x}", info_hash[0], info_hash[1],
+                             info_hash[2], info_hash[3], rand::random::<u32>());
+            I2pTransport::new(sam, &id)
+        });
+        // Without resume data, files left by an earlier download may already hold some pieces
+        let recheck = resume.is_none() && (0..files.files().len())
+            .any(|i| !files.is_skipped(i) && files.disk_path(i).is_some_and(|path| path.exists()));
+        let num_files = files.files().len();
+        let md5sums = if config.check_md5 {
+            meta.info.file_info.files().into_iter().map(|file| file.md5sum.clone()).collect()
+        } else {
+            Vec::new()
+        };
+        let (md5_sender, md5_results) = unbounded();
+        let files = Arc::new(Mutex::new(files));
+        let (handle, commands) = ServerHandle::new();
+        let (storage, written_stream) = writer::spawn(files.clone(), meta.info.piece_length as u64,
+                                                      &config.hash_pool, config.write_cache)
+            .expect("Failed to create the torrent's files");
+        let buffers = BufferPool::new(UPLOAD_BUFFERS);
+        let (reader, read_stats) = reader::spawn(files.clone(), meta.info.piece_length as u64, config.read_cache,
+                                                 buffers.clone())
+            .expect("Failed to start reading from the torrent's files");
+        // Like pex, the DHT would leak the swarm of a private torrent, or our ip on I2P
+        let dht = if meta.info.private || config.i2p.is_some() {
+            None
+        } else {
+            config.dht
+        };
+        let mut server = Server {
             peer_id,
             info_hash,
-            uploaded: 0,
-            uploaded_stream: Box::new(stream::empty()),
-            downloaded: 0,
-            downloaded_stream: Box::new(stream::empty()),
-            left: download_size,
-            listener: TcpListener::bind(&address).expect("Failed to open TCP listener").incoming(),
+            alt_info_hash,
+            log_target: logging::torrent_target(&info_hash),
+            name,
+            uploaded_stream: SelectAll::new(),
+            downloaded_stream: SelectAll::new(),
+            stats: Stats::new(uploaded, downloaded, Instant::now()),
+            stats_handle: StatsHandle::default(),
+            stats_interval: time::interval(SNAPSHOT_INTERVAL),
+            left,
+            incoming,
+            routes: config.routes,
+            ipv6,
+            port: config.port,
+            connected: HashSet::new(),
+            max_peers: config.max_peers,
             tracker,
-            piece_stream: Box::new(stream::empty()),
+            trackers,
+            files,
+            storage,
+            unwritten: VecDeque::new(),
+            partial: HashMap::new(),
+            written_stream: written_stream.boxed(),
+            reader,
+            read_stats,
+            buffers,
+            piece_stream: SelectAll::new(),
+            piece_hashes: meta.info.pieces.iter()
+                .map(|hash| session::unhex(hash).unwrap_or([0; 20]))
+                .collect(),
+            piece_length: meta.info.piece_length as u64,
+            download_size,
+            peers: HashMap::new(),
+            waiting: VecDeque::new(),
+            finishing: HashSet::new(),
+            next_peer_id: 0,
+            peer_addresses: HashMap::new(),
+            peer_clients: HashMap::new(),
+            dialer: Dialer::new(),
+            tracker_peer_ids: HashMap::new(),
+            target_peers: config.target_peers,
+            connection_limits: config.connection_limits,
+            permits: HashMap::new(),
+            pex: !meta.info.private && config.i2p.is_none(),
+            pex_senders: Vec::new(),
+            tex: !meta.info.private,
+            tex_senders: Vec::new(),
+            tex_trackers: 0,
+            holepunch_senders: HashMap::new(),
+            holepunch_peers: HashSet::new(),
+            dials: Vec::new(),
+            pex_interval: time::interval_at(time::Instant::now() + PEX_INTERVAL, PEX_INTERVAL),
+            dht,
+            dht_search: None,
+            dht_interval: time::interval(DHT_INTERVAL),
+            geoip: config.geoip,
+            bans,
+            bad_data: BadData::new(),
+            bad_data_ban: config.bad_data_ban,
+            handshake_timeout: config.handshake_timeout,
+            banned_clients: config.banned_clients,
+            blocklist: config.blocklist,
+            piece_sources: HashMap::new(),
+            memory_budget: config.piece_memory,
+            have_outboxes: HashMap::new(),
+            suppress_redundant_haves: config.suppress_redundant_haves,
+            reachability: config.reachability,
+            external_ip: config.external_ip,
+            webhooks: Webhooks::new(config.webhook_urls, config.webhook_events),
+            events: config.events,
+            added_notified: false,
+            peer_dscp: config.peer_dscp,
+            proxy: config.proxy,
+            tracker_tls: config.tracker_tls,
+            seed_strategy: config.seed_strategy,
+            seed_limits: config.seed_limits,
+            seed_time: config.seed_time,
+            seed_clock: Instant::now(),
+            seed_limit_reached: false,
+            choker: Choker::new(config.unchoke_slots),
+            choke_senders: HashMap::new(),
+            choke_interval: time::interval(UNCHOKE_INTERVAL),
+            picker,
+            session,
+            // The session already has the totals from earlier runs
+            recorded_uploaded: uploaded,
+            recorded_downloaded: downloaded,
+            recorded_seed_time: config.seed_time,
+            resume_path,
+            last_session_save: Instant::now(),
+            tls,
+            passkeys,
+            passkeys_version,
+            i2p,
+            tracker_started,
+            completed_announced: left == 0,
+            completed_pending: false,
+            tracker_key,
+            numwant: config.numwant,
+            seeding: false,
+            super_seed: None,
+            file_priorities: config.file_priorities,
+            paused: config.paused,
+            verifying: None,
+            hash_pool: config.hash_pool,
+            finished_files: HashSet::new(),
+            md5sums,
+            md5_sender,
+            md5_results,
+            md5_mismatches: BTreeSet::new(),
+            commands,
+            handle,
+            announce_timer: None,
+            tracker_backoff: None,
+            tracker_failures: Backoff::new(),
+            tracker_cache: ResponseCache::new(),
+            shutdown: config.shutdown,
+            download_throttle: config.download_throttle,
+            upload_throttle: config.upload_throttle,
+            bandwidth_priority: PriorityClass::new(config.bandwidth_priority),
+            encryption: config.encryption,
+            stopping: None,
+            seed_check,
+        };
+        if let Some(ip) = server.external_ip {
+            server.dialer.set_own_address(SocketAddr::new(ip, server.port));
+        }
+        // Files may have finished just before the last run stopped.  Their sums were checked then
+        server.finish_files(0..num_files, false);
+        if recheck {
+            server.verify();
         }
+        server
     }
-}
 
-impl Future for Server {
-    type Item = ();
-    type Error = ();
+    /// Spin up a task to talk to a peer.  `dialed` is the address we connected to if we opened
+    /// the connection, or None if the peer connected to us
+    fn add_peer(&mut self, conn: NewConnection) {
+        let dialed = match &conn {
+            NewConnection::Dialed(_, address) => Some(*address),
+            _ => None,
+        };
+        let initiates = dialed.is_some();
+        let permit = match self.connection_limits.peer() {
+            Some(permit) => permit,
+            None => {
+                debug!(target: &self.log_target, "Dropping a peer connection, the torrents are at the connection limit");
+                return;
+            }
+        };
+        if let Some(dscp) = self.peer_dscp {
+            if let Err(e) = dscp::set_dscp(conn.tcp(), dscp) {
+                warn!(target: &self.log_target, "Could not set DSCP on peer connection: {}", e);
+            }
+        }
+        let (up_sender, up_receiver) = channel(10);
+        let (down_sender, down_receiver) = channel(10);
+        let (piece_sender, piece_receiver) = channel(10);
+        let (have_sender, have_receiver) = channel(10);
+        let (choke_sender, choke_receiver) = channel(10);
+        let pex_receiver = if self.pex {
+            let (pex_sender, pex_receiver) = channel(1);
+            self.pex_senders.push(pex_sender);
+            Some(pex_receiver)
+        } else {
+            None
+        };
+        let tex_receiver = if self.tex {
+            let (tex_sender, tex_receiver) = channel(1);
+            self.tex_senders.push(tex_sender);
+            Some(tex_receiver)
+        } else {
+            None
+        };
+        // Addresses of I2P streams are the SAM bridge, not the peer, and proxied connections are
+        // to the proxy
+        let address = if self.i2p.is_none() {
+            dialed.or_else(|| conn.tcp().peer_addr().ok())
+        } else {
+            None
+        };
+        let id = self.next_peer_id;
+        self.permits.insert(id, permit);
+        self.have_outboxes.insert(id, HaveOutbox::new(have_sender));
+        // Holepunching needs the peer's real address
+        let holepunch_receiver = address.map(|_| {
+            let (holepunch_sender, holepunch_receiver) = channel(4);
+            self.holepunch_senders.insert(id, holepunch_sender);
+            holepunch_receiver
+        });
 
-    /// This is the main event loop for the client.  It returns Ok(Ready(())) Only when the download
-    /// is complete.
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        trace!("Start Loop");
-        // check on the tracker response
-        match self.tracker.poll() {
-            Err(e) => {
-                error!("Something went wrong in making a request to the tracker: {:?}", e);
-                return Err(());
+        self.next_peer_id += 1;
+        self.events.send(Event::PeerConnected { info_hash: self.info_hash, peer: id, address });
+        self.uploaded_stream.push(up_receiver.map(move |bytes| (id, bytes)).boxed());
+        self.downloaded_stream.push(down_receiver.map(move |bytes| (id, bytes)).boxed());
+        // Gone comes once the peer's channel is closed, so after everything else it sent.  A peer
+        // that never started had no pieces
+        let (gone_sender, gone_receiver) = oneshot::channel();
+        let gone = gone_receiver.map(move |pieces| PeerEvent::Gone {
+            peer: id,
+            pieces: pieces.unwrap_or_else(|_| PieceField::new(0)),
+        });
+        self.piece_stream.push(piece_receiver.chain(gone.into_stream()).boxed());
+        let suppress_redundant_haves = self.suppress_redundant_haves;
+        let info_hash = self.info_hash;
+        let alt_info_hash = self.alt_info_hash;
+        let peer_id = self.peer_id;
+        let expected_peer_id = dialed.and_then(|address| self.tracker_peer_ids.get(&address).cloned());
+        // Super-seeding peers only see the pieces they are offered
+        let super_seeding = self.super_seed.is_some();
+        let handshake_timeout = self.handshake_timeout;
+        let our_pieces = if super_seeding {
+            PieceField::new(self.piece_hashes.len())
+        } else {
+            PieceField::from(self.picker.have().clone())
+        };
+        let reader = self.reader.clone();
+        let buffers = self.buffers.clone();
+        // The DHT node shares the port peer connections come in on
+        let dht_port = self.dht.as_ref().map(|_| self.port);
+        let (piece_length, download_size) = (self.piece_length, self.download_size);
+        self.connected.insert(id);
+        self.choker.add_peer(id);
+        self.stats.add_peer(id, Instant::now());
+        self.choke_senders.insert(id, choke_sender);
+        if let Some(address) = address {
+            self.peer_addresses.insert(id, address);
+        }
+        let download_throttle = self.download_throttle.clone();
+        let upload_throttle = self.upload_throttle.clone();
+        let bandwidth_priority = self.bandwidth_priority.clone();
+        let log_target = peer::log_target(&info_hash, id, address);
+        let config = PeerConfig {
+            uploaded_sender: up_sender,
+            downloaded_sender: down_sender,
+            id,
+            event_sender: piece_sender,
+            gone_sender,
+            have_receiver,
+            suppress_redundant_haves,
+            info_hash,
+            alt_info_hash,
+            peer_id,
+            expected_peer_id,
+            initiates,
+            address,
+            pex_receiver,
+            choke_receiver,
+            our_pieces,
+            piece_length,
+            download_size,
+            reader,
+            buffers,
+            holepunch_receiver,
+            tex_receiver,
+            dht_port,
+            super_seeding,
+            handshake_timeout,
+        };
+        let peer = move |conn: Box<dyn Connection>| {
+            let conn = Throttled::new(conn, &download_throttle, &upload_throttle, &bandwidth_priority);
+            Peer::new(Box::new(conn), config)
+        };
+        match &self.tls {
+            Some(tls) => {
+                let handshake = match conn {
+                    NewConnection::Dialed(conn, _) => tls.connect(Encrypted::plaintext(conn)),
+                    NewConnection::Accepted(conn) => tls.accept(Encrypted::plaintext(conn)),
+                    NewConnection::Routed(incoming) => tls.accept(incoming.conn),
+                };
+                match handshake {
+                    Ok(handshake) => {
+                        spawn(async move {
+                            match handshake.await {
+                                Ok(conn) => {
+                                    let _res = peer(Box::new(conn)).await;
+                                }
+                                Err(e) => debug!(target: &log_target, "SSL handshake with peer failed: {}", e),
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!(target: &self.log_target, "Could not start SSL with peer: {:?}", e);
+                        self.connected.remove(&id);
+                    }
+                }
+            }
+            None => {
+                let handshake: BoxFuture<'static, io::Result<Encrypted<TcpStream>>> = match conn {
+                    NewConnection::Dialed(conn, _) => mse::connect(conn, info_hash, self.encryption).boxed(),
+                    NewConnection::Accepted(conn) => {
+                        let info_hashes = Some(info_hash).into_iter().chain(alt_info_hash).collect();
+                        mse::accept(conn, Vec::new(), info_hashes, self.encryption).map_ok(|(conn, _)| conn).boxed()
+                    }
+                    // The listener already worked out whether it's encrypted
+                    NewConnection::Routed(incoming) => future::ok(incoming.conn).boxed(),
+                };
+                spawn(async move {
+                    match handshake.await {
+                        Ok(conn) => {
+                            debug!(target: &log_target, "Peer connection is {}", if conn.is_encrypted() { "encrypted" } else { "plaintext" });
+                            let _res = peer(Box::new(conn)).await;
+                        }
+                        Err(e) => debug!(target: &log_target, "Encrypted handshake with peer failed: {}", e),
+                    }
+                });
+            }
+        }
+    }
+
+    /// Whether we are connected to as many peers as we are allowed, or every torrent together is
+    fn at_peer_limit(&self) -> bool {
+        self.max_peers.is_some_and(|max| self.connected.len() >= max)
+            || self.connection_limits.peers_full()
+    }
+
+    /// At the peer limit, a new connection to `address` takes the place of the peer with the
+    /// lowest BEP 40 priority, if the new one ranks higher.  Returns true if a peer was dropped
+    /// to make room
+    fn make_room_for(&mut self, address: SocketAddr) -> bool {
+        let own = match self.external_ip {
+            Some(ip) => SocketAddr::new(ip, self.port),
+            None => return false,
+        };
+        let mut addresses: Vec<SocketAddr> = self.connected.iter()
+            .filter_map(|peer| self.peer_addresses.get(peer).cloned())
+            .collect();
+        addresses.push(address);
+        priority::rank(own, &mut addresses);
+        let lowest = match addresses.last() {
+            Some(lowest) if *lowest != address => *lowest,
+            _ => return false,
+        };
+        let peer = match self.connected.iter().find(|peer| self.peer_addresses.get(peer) == Some(&lowest)) {
+            Some(peer) => *peer,
+            None => return false,
+        };
+        debug!(target: &self.log_target, "Dropping peer {} to make room for {}, which ranks higher", lowest, address);
+        // The peer closes its connection once it can't hear from us.  Its place is free right away
+        self.choke_senders.remove(&peer);
+        self.connected.remove(&peer);
+        self.permits.remove(&peer);
+        true
+    }
+
+    /// Tell the webhooks that something happened
+    fn notify(&self, kind: EventKind, message: Option<String>) {
+        let info_hash = self.info_hash;
+        match kind {
+            EventKind::Completed => self.events.send(Event::Completed { info_hash }),
+            EventKind::SeedLimitReached => self.events.send(Event::SeedLimitReached { info_hash }),
+            EventKind::Error | EventKind::Md5Mismatch => {
+                self.events.send(Event::Error { info_hash, message: message.clone().unwrap_or_default() });
+            }
+            // Sent with the tracker's url where the tracker failed
+            EventKind::TrackerFailure | EventKind::TorrentAdded => (),
+        }
+        self.webhooks.notify(Notification {
+            kind,
+            torrent: self.name.clone(),
+            info_hash: self.info_hash,
+            message,
+        });
+    }
+
+    /// Add a tracker while the torrent is running.  A tier of None adds the tracker in a new
+    /// tier after all of the others.
+    pub fn add_tracker(&mut self, tier: Option<usize>, url: &str) -> Result<(), String> {
+        let template = self.passkeys.lock().unwrap().templatize(url);
+        if !self.trackers.add(tier, &template) {
+            return Err(format!("Already using tracker {}", passkey::redact(url)));
+        }
+        self.switch_to_preferred_tracker();
+        Ok(())
+    }
+
+    /// Stop using a tracker while the torrent is running
+    pub fn remove_tracker(&mut self, url: &str) -> Result<(), String> {
+        let template = self.passkeys.lock().unwrap().templatize(url);
+        if self.trackers.len() == 1 && self.trackers.contains(&template) {
+            return Err("Can't remove the last tracker".to_string());
+        }
+        if !self.trackers.remove(&template) {
+            return Err(format!("Not using tracker {}", passkey::redact(url)));
+        }
+        let filled = self.passkeys.lock().unwrap().fill(&template);
+        self.tracker_cache.remove(&filled);
+        self.switch_to_preferred_tracker();
+        Ok(())
+    }
+
+    /// If the tracker list changed so that we aren't talking to the most preferred tracker, start
+    /// talking to it right away.  This also picks up passkey changes
+    fn switch_to_preferred_tracker(&mut self) {
+        let preferred = match self.trackers.first() {
+            Some(template) => self.passkeys.lock().unwrap().fill(template),
+            None => return,
+        };
+        if preferred == self.tracker.uri() {
+            return;
+        }
+        info!(target: &self.log_target, "Switching to tracker {}", passkey::redact(&preferred));
+        self.tracker = Tracker::new(self.peer_id, preferred, self.info_hash, self.port);
+        self.tracker_started = false;
+        self.start_tracker();
+    }
+
+    /// The tracker list entry of the tracker we are talking to
+    fn current_template(&self) -> Option<String> {
+        let passkeys = self.passkeys.lock().unwrap();
+        self.trackers.tiers().iter()
+            .flatten()
+            .find(|template| passkeys.fill(template) == self.tracker.uri())
+            .cloned()
+    }
+
+    /// Use the peers from an announce, and keep the answer in case we come back to this tracker
+    fn tracker_responded(&mut self, resp: TrackerSuccessResponse) {
+        trace!(target: &self.log_target, "tracker response: {:?}", resp);
+        self.tracker_cache.insert(self.tracker.uri(), &resp, Instant::now());
+        self.log_peers(&resp);
+        if let Some(ip) = resp.external_ip {
+            self.learned_external_ip(ip);
+        }
+        self.events.send(Event::TrackerAnnounce {
+            info_hash: self.info_hash,
+            tracker: passkey::redact(self.tracker.uri()),
+            peers: resp.peers.len(),
+        });
+        self.add_tracker_peers(resp.peers);
+        self.tracker_answered();
+    }
+
+    /// Tell the user why the tracker didn't give us peers, then move on as for any failure
+    fn tracker_refused(&mut self, message: String) {
+        let tracker = passkey::redact(self.tracker.uri());
+        self.events.send(Event::TrackerFailure {
+            info_hash: self.info_hash,
+            tracker: tracker.clone(),
+            message: message.clone(),
+        });
+        self.notify(EventKind::TrackerFailure, Some(format!("{}: {}", tracker, message)));
+        self.tracker_failed();
+    }
+
+    /// A tracker told us our address.  If the user didn't, use it to check whether peers can reach
+    /// us.  Through I2P or a proxy the tracker only sees the proxy, so it is ignored then
+    fn learned_external_ip(&mut self, ip: IpAddr) {
+        if self.external_ip.is_some() || self.i2p.is_some() || self.proxy.is_some() {
+            return;
+        }
+        info!(target: &self.log_target, "Tracker {} says our address is {}", passkey::redact(self.tracker.uri()), ip);
+        self.external_ip = Some(ip);
+        self.dialer.set_own_address(SocketAddr::new(ip, self.port));
+        // Only the first torrent to learn it starts the test
+        if let Some(reachability) = &self.reachability {
+            reachability.test(SocketAddr::new(ip, self.port));
+        }
+    }
+
+    /// The tracker answered, so it is the first one we try from now on
+    fn tracker_answered(&mut self) {
+        if let Some(template) = self.current_template() {
+            self.tracker_failures.succeeded(&template);
+            self.trackers.promote(&template);
+        }
+        // We completed before this tracker was started
+        if self.completed_pending {
+            self.announce_completed();
+            return;
+        }
+        // The answer to the stopped announce of a pause
+        if !self.paused {
+            self.schedule_announce();
+        }
+    }
+
+    /// The tracker failed.  It is retried after a growing delay until it has failed too often in
+    /// a row, then we move on to the next tracker that isn't down, in tier order.  Once every
+    /// tracker is down we wait a while and start over from the first one
+    fn tracker_failed(&mut self) {
+        if self.paused {
+            return;
+        }
+        let now = Instant::now();
+        let template = self.current_template();
+        if let Some(template) = &template {
+            match self.tracker_failures.failed(template, now) {
+                Retry::After(wait) => {
+                    info!(target: &self.log_target, "Tracker {} failed {} times in a row, retrying in {} seconds",
+                          passkey::redact(template), self.tracker_failures.failures(template),
+                          wait.as_secs());
+                    self.tracker_backoff = Some(Box::pin(time::sleep(wait)));
+                    self.tracker_started = false;
+                    self.start_tracker();
+                    return;
+                }
+                Retry::Down => warn!(target: &self.log_target, "Tracker {} has failed {} times in a row, marking it down",
+                                     passkey::redact(template), backoff::MAX_FAILURES),
+            }
+        }
+        let mut next = template.as_ref().and_then(|template| self.trackers.after(template));
+        while let Some(url) = next {
+            if !self.tracker_failures.is_down(url, now) {
+                break;
+            }
+            next = self.trackers.after(url);
+        }
+        let next = match next {
+            Some(next) => next.to_owned(),
+            None => {
+                self.tracker_failures.reset();
+                self.tracker_backoff = Some(Box::pin(time::sleep(backoff::jitter(TRACKER_RETRY_INTERVAL))));
+                match self.trackers.first() {
+                    Some(first) => first.to_owned(),
+                    None => return,
+                }
+            }
+        };
+        let url = self.passkeys.lock().unwrap().fill(&next);
+        info!(target: &self.log_target, "Trying tracker {}", passkey::redact(&url));
+        self.tracker = Tracker::new(self.peer_id, url, self.info_hash, self.port);
+        self.tracker_started = false;
+        self.start_tracker();
+    }
+
+    /// Send the first announce to the current tracker, unless we are still waiting on the I2P
+    /// session to announce through
+    fn start_tracker(&mut self) {
+        if self.seed_check.is_some() {
+            return;
+        }
+        if self.tracker_backoff.as_ref().is_some_and(|backoff| backoff.deadline() > time::Instant::now()) {
+            return;
+        }
+        self.tracker_backoff = None;
+        if let Some(i2p) = &self.i2p {
+            match i2p.session() {
+                Some(session) => self.tracker.set_i2p(session.clone()),
+                None => return,
+            }
+        }
+        self.tracker.set_proxy(self.proxy.clone());
+        self.tracker.set_tls(self.tracker_tls.clone());
+        self.tracker.set_ipv6(self.ipv6);
+        self.tracker.set_key(self.tracker_key);
+        self.tracker.set_numwant(self.numwant);
+        self.restore_tracker();
+        self.tracker.start(self.left);
+        self.tracker_started = true;
+        // A regular announce would replace the start before the tracker answers it
+        self.announce_timer = None;
+    }
+
+    /// If the tracker answered us recently, keep to its min interval and use the peers it gave us
+    /// until we can announce again
+    fn restore_tracker(&mut self) {
+        let now = Instant::now();
+        let (age, peers) = match self.tracker_cache.get(self.tracker.uri()) {
+            Some(cached) => {
+                self.tracker.restore(cached);
+                (now.saturating_duration_since(cached.received), cached.fresh_peers(now).map(<[PeerInfo]>::to_vec))
+            }
+            None => return,
+        };
+        if let Some(wait) = self.tracker.time_until_announce_allowed() {
+            info!(target: &self.log_target, "Tracker {} answered us {} seconds ago, waiting {} seconds to announce again",
+                  passkey::redact(self.tracker.uri()), age.as_secs(), wait.as_secs());
+            if let Some(peers) = peers {
+                self.add_tracker_peers(peers);
+            }
+        }
+    }
+
+    /// Announce right away, for when the peer list is stale.  `url` picks which tracker to
+    /// announce to, or the one we are using if None.
+    pub fn reannounce(&mut self, url: Option<&str>) -> Result<(), String> {
+        if let Some(url) = url {
+            let filled = self.passkeys.lock().unwrap().fill(url);
+            if url != self.tracker.uri() && filled != self.tracker.uri() {
+                return Err(format!("Not currently announcing to {}", passkey::redact(url)));
+            }
+        }
+        self.tracker.force_refresh(self.left, self.stats.uploaded(), self.stats.downloaded())
+            .map_err(|wait| format!("The tracker asks us to wait {} more seconds before announcing",
+                                    wait.as_secs()))
+    }
+
+    /// Give a file a new name while the torrent is running
+    pub fn rename_file(&mut self, index: usize, name: &str) -> Result<(), String> {
+        self.files.lock().unwrap().rename_file(index, name)
+    }
+
+    /// Change how much a file is wanted while the torrent is running.  Skipping a file stops new
+    /// pieces of it being picked, but pieces already on disk are kept
+    pub fn set_file_priority(&mut self, index: usize, priority: Priority) -> Result<(), String> {
+        let mut files = self.files.lock().unwrap();
+        if index >= files.files().len() {
+            return Err(format!("There is no file {}", index));
+        }
+        files.set_skipped(index, priority == Priority::Skip);
+        if self.file_priorities.len() <= index {
+            self.file_priorities.resize(index + 1, Priority::Normal);
+        }
+        self.file_priorities[index] = priority;
+        self.picker.set_priorities(picker::piece_priorities(files.files(), self.piece_length,
+                                                            self.picker.num_pieces(), &self.file_priorities));
+        drop(files);
+        self.recompute_left();
+        // A file that was skipped may already have all its pieces
+        self.finish_files(Some(index), true);
+        self.session.lock().unwrap().set_file_priorities(&self.info_hash, self.file_priorities.clone())
+            .map_err(|e| format!("Could not save the session: {:?}", e))
+    }
+
+    /// Change how much of the global rate limits this torrent gets, for connections already open too
+    pub fn set_bandwidth_priority(&mut self, priority: ratelimit::Priority) -> Result<(), String> {
+        self.bandwidth_priority.set(priority);
+        self.session.lock().unwrap().set_bandwidth_priority(&self.info_hash, priority)
+            .map_err(|e| format!("Could not save the session: {:?}", e))
+    }
+
+    /// Give the root directory a new name while the torrent is running
+    pub fn rename_root(&mut self, name: &str) -> Result<(), String> {
+        self.files.lock().unwrap().rename_root(name)
+    }
+
+    /// A handle for steering the torrent once it is running on its own task
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Stop downloading and uploading without stopping the torrent.  Every peer is choked, or
+    /// disconnected if `drop_peers` is set, and the tracker is told we stopped.  Pieces peers are
+    /// in the middle of still finish
+    pub fn pause(&mut self, drop_peers: bool) {
+        if self.paused {
+            return;
+        }
+        info!(target: &self.log_target, "Pausing {}", self.name);
+        self.paused = true;
+        self.session.lock().unwrap().set_paused(&self.info_hash, true).unwrap_or_else(|e| {
+            warn!(target: &self.log_target, "Could not save the session: {:?}", e);
+        });
+        if drop_peers {
+            // Peers close their connections once they can't hear from us
+            self.choke_senders.clear();
+        } else {
+            for sender in self.choke_senders.values_mut() {
+                let _res = sender.try_send(true);
+            }
+        }
+        self.choker.choke_all();
+        self.dht_search = None;
+        self.announce_timer = None;
+        if self.tracker_started {
+            self.tracker.cancel(self.left, self.stats.uploaded(), self.stats.downloaded());
+        }
+        self.save_session(true);
+        self.publish_stats();
+        self.events.send(Event::Paused { info_hash: self.info_hash });
+    }
+
+    /// Pick up where a pause left off
+    pub fn resume(&mut self) {
+        if !self.paused {
+            return;
+        }
+        info!(target: &self.log_target, "Resuming {}", self.name);
+        self.paused = false;
+        self.session.lock().unwrap().set_paused(&self.info_hash, false).unwrap_or_else(|e| {
+            warn!(target: &self.log_target, "Could not save the session: {:?}", e);
+        });
+        if self.tracker_started {
+            self.start_tracker();
+        }
+        self.recompute_chokes();
+        self.assign_pieces();
+        self.publish_stats();
+        self.events.send(Event::Resumed { info_hash: self.info_hash });
+    }
+
+    /// Hash the data on disk again on a thread of its own, for when it may have been changed or
+    /// damaged.  Pieces that fail are downloaded again.  Peers were already told we have them,
+    /// but they will find out when we can't send them
+    pub fn verify(&mut self) {
+        if self.verifying.is_some() {
+            return;
+        }
+        info!(target: &self.log_target, "Rechecking {}", self.name);
+        let files = self.files.lock().unwrap().clone();
+        let hashes = self.piece_hashes.clone();
+        let piece_length = self.piece_length;
+        let pool = self.hash_pool.clone();
+        let (sender, receiver) = oneshot::channel();
+        let spawned = thread::Builder::new()
+            .name("recheck".to_string())
+            .spawn(move || {
+                let _res = sender.send(verify::verify(&files, piece_length, &hashes, &pool));
+            });
+        match spawned {
+            Ok(_) => self.verifying = Some((receiver, Vec::new())),
+            Err(e) => warn!(target: &self.log_target, "Could not start rechecking {}: {}", self.name, e),
+        }
+    }
+
+    /// A recheck finished, so what we have is what was found on disk
+    fn verified(&mut self, report: Report, finished: Vec<u32>) {
+        let mut have = report.have;
+        for index in finished {
+            have.set(index as usize, true);
+        }
+        if !report.corrupt.is_empty() {
+            warn!(target: &self.log_target, "{} pieces of {} failed the recheck", report.corrupt.len(), self.name);
+            self.notify(EventKind::Error, Some(format!("{} pieces failed the recheck", report.corrupt.len())));
+        }
+        info!(target: &self.log_target, "Rechecked {}: {} of {} pieces are good", self.name, have.iter().filter(|x| *x).count(), have.len());
+        self.picker.set_have(have);
+        self.recompute_left();
+        let num_files = self.files.lock().unwrap().files().len();
+        self.finished_files.clear();
+        self.finish_files(0..num_files, true);
+        self.save_resume();
+        self.publish_stats();
+        self.assign_pieces();
+    }
+
+    /// Work out how many wanted bytes we are missing, after what we have or want changed
+    fn recompute_left(&mut self) {
+        self.left = self.picker.missing().iter().map(|index| self.piece_size(*index)).sum();
+        if self.left > 0 {
+            self.seeding = false;
+            self.super_seed = None;
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Take a snapshot of the stats, with our progress, for the stats handle
+    fn publish_stats(&mut self) {
+        self.count_seed_time();
+        let mut snapshot = self.stats.snapshot(&self.peer_addresses, Instant::now());
+        for peer in snapshot.peers.iter_mut() {
+            peer.client = self.peer_clients.get(&peer.id).map(ClientIdent::to_string);
+            if let (Some(geoip), Some(address)) = (&self.geoip, peer.address) {
+                let location = geoip.lookup(address.ip());
+                peer.country = location.country;
+                peer.asn = location.asn;
+            }
+        }
+        snapshot.seed_time = self.seed_time;
+        snapshot.size = self.download_size;
+        snapshot.left = self.left;
+        snapshot.paused = self.paused;
+        snapshot.port_status = self.port_status();
+        snapshot.read_cache_hits = self.read_stats.hits();
+        snapshot.read_cache_misses = self.read_stats.misses();
+        let buffers = self.buffers.stats();
+        snapshot.buffers_reused = buffers.reused;
+        snapshot.buffers_allocated = buffers.allocated;
+        snapshot.md5_mismatches = {
+            let files = self.files.lock().unwrap();
+            self.md5_mismatches.iter().map(|index| files.files()[*index].torrent_path.clone()).collect()
+        };
+        self.stats_handle.publish(snapshot);
+    }
+
+    /// Where the torrent's stats are published, for anything that wants to show them
+    pub fn stats(&self) -> StatsHandle {
+        self.stats_handle.clone()
+    }
+
+    /// Whether we are holding off on the torrent because its swarm has no seeds
+    pub fn waiting_for_seeds(&self) -> bool {
+        self.seed_check.is_some()
+    }
+
+    /// Scrape the tracker to see if the swarm has a seed, and keep checking while it doesn't.
+    /// Returns true once we can join the swarm.
+    fn poll_seed_check(&mut self, cx: &mut Context<'_>) -> bool {
+        loop {
+            let next = match self.seed_check.as_mut() {
+                None => return true,
+                Some(SeedCheck::Due) => {
+                    if let Some(i2p) = &self.i2p {
+                        match i2p.session() {
+                            Some(session) => self.tracker.set_i2p(session.clone()),
+                            None => return false,
+                        }
+                    }
+                    SeedCheck::Scraping(self.tracker.scrape())
+                }
+                Some(SeedCheck::Scraping(scrape)) => match scrape.poll_unpin(cx) {
+                    Poll::Ready(Ok(info)) => match self.scraped(info) {
+                        Some(next) => next,
+                        None => return true,
+                    },
+                    Poll::Pending => return false,
+                    Poll::Ready(Err(e)) => match &self.dht {
+                        Some(dht) => {
+                            debug!(target: &self.log_target, "Could not scrape the tracker, asking the DHT: {:?}", e);
+                            SeedCheck::ScrapingDht(dht.scrape(self.info_hash))
+                        }
+                        None => {
+                            warn!(target: &self.log_target, "Could not check {} for seeds, starting anyway: {:?}", self.name, e);
+                            self.seed_check = None;
+                            return true;
+                        }
+                    },
+                },
+                Some(SeedCheck::ScrapingDht(scrape)) => match scrape.poll_unpin(cx) {
+                    Poll::Ready(Ok(info)) => match self.scraped(info) {
+                        Some(next) => next,
+                        None => return true,
+                    },
+                    Poll::Pending => return false,
+                    Poll::Ready(Err(_)) => {
+                        warn!(target: &self.log_target, "Could not check {} for seeds, starting anyway: no DHT node answered", self.name);
+                        self.seed_check = None;
+                        return true;
+                    }
+                },
+                Some(SeedCheck::Waiting(delay)) => match delay.as_mut().poll(cx) {
+                    Poll::Pending => return false,
+                    Poll::Ready(()) => SeedCheck::Due,
+                },
+            };
+            self.seed_check = Some(next);
+        }
+    }
+
+    /// Join the swarm if a scrape found a seed, or wait for one to show up.  Returns what to do
+    /// next, or None when done checking
+    fn scraped(&mut self, info: ScrapeInfo) -> Option<SeedCheck> {
+        if info.complete > 0 {
+            info!(target: &self.log_target, "{} has {} seeds", self.name, info.complete);
+            self.seed_check = None;
+            None
+        } else {
+            info!(target: &self.log_target, "{} has no seeds, waiting for one to show up", self.name);
+            Some(SeedCheck::Waiting(Box::pin(time::sleep(SEED_RECHECK_INTERVAL))))
+        }
+    }
+
+    /// Whether peers outside our network can connect to us
+    pub fn port_status(&self) -> PortStatus {
+        self.reachability.as_ref().map_or(PortStatus::Unknown, Reachability::status)
+    }
+
+    /// How many bytes are in a piece.  The last piece is usually short
+    fn piece_size(&self, index: u32) -> u64 {
+        self.piece_length.min(self.download_size - index as u64 * self.piece_length)
+    }
+
+    /// Handle a peer asking for work or going away
+    fn handle_peer_event(&mut self, event: PeerEvent) {
+        match event {
+            PeerEvent::WantPiece { peer, finished, pieces, reply } => {
+                let state = self.peers.entry(peer).or_insert(PeerPieces {
+                    pieces: PieceField::new(0),
+                    current: None,
+                    arrivals: None,
+                    last_piece: None,
+                    snubbed: false,
+                });
+                self.picker.remove_peer(state.pieces.bits());
+                self.picker.add_peer(pieces.bits());
+                if !pieces.is_empty() {
+                    self.choker.set_completion(peer, pieces.count() as f32 / pieces.len() as f32);
+                }
+                state.pieces = pieces;
+                state.current = None;
+                state.arrivals = None;
+                if let Some(piece) = finished {
+                    let index = piece.index();
+                    if self.picker.have().get(index as usize).unwrap_or(true) || !self.finishing.insert(index) {
+                        debug!(target: &self.log_target, "Dropping a second copy of piece {}", index);
+                        self.stats.record_waste(self.piece_size(index));
+                    } else {
+                        let sources = piece.sources().into_iter()
+                            .filter_map(|(source, _)| self.peer_addresses.get(&source))
+                            .map(SocketAddr::ip)
+                            .collect();
+                        self.piece_sources.insert(index, sources);
+                        self.unwritten.push_back(piece);
+                    }
+                }
+                self.waiting.push_back((peer, reply));
             }
-            Ok(Async::Ready(TrackerResponse::Failure(msg))) => error!("The tracker responded with an error: {}", msg),
-            Ok(Async::Ready(TrackerResponse::Warning(msg, resp))) => {
-                warn!("The tracker responeded with a warning: {}", msg);
-                // do something with the response
-                trace!("tracker response: {:?}", resp)
+            PeerEvent::Gone { peer, pieces } => {
+                self.connected.remove(&peer);
+                self.permits.remove(&peer);
+                self.have_outboxes.remove(&peer);
+                if let Some(super_seed) = self.super_seed.as_mut() {
+                    super_seed.remove_peer(peer);
+                }
+                self.peer_clients.remove(&peer);
+                let address = self.peer_addresses.remove(&peer);
+                if let Some(address) = address {
+                    self.dialer.disconnected(address, Instant::now());
+                }
+                self.events.send(Event::PeerDisconnected { info_hash: self.info_hash, peer, address });
+                self.picker.remove_peer(pieces.bits());
+                if let Some(index) = self.peers.remove(&peer).and_then(|state| state.current) {
+                    // In endgame someone else may still be downloading it
+                    if !self.peers.values().any(|other| other.current == Some(index)) {
+                        self.picker.abandon(index);
+                    }
+                }
+                self.waiting.retain(|(waiting, _)| *waiting != peer);
+                self.choker.remove_peer(peer);
+                self.stats.remove_peer(peer);
+                self.choke_senders.remove(&peer);
+                self.holepunch_senders.remove(&peer);
+                self.holepunch_peers.remove(&peer);
+            }
+            PeerEvent::Snubbed { peer, piece, reply } => {
+                debug!(target: &self.log_target, "Peer {} is snubbing us", peer);
+                self.choker.set_snubbed(peer, true);
+                if let Some(state) = self.peers.get_mut(&peer) {
+                    state.snubbed = true;
+                    state.current = None;
+                    state.arrivals = None;
+                }
+                if let Some(piece) = piece {
+                    let index = piece.index();
+                    // In endgame someone else may still be downloading it
+                    if !self.peers.values().any(|other| other.current == Some(index)) {
+                        self.picker.abandon(index);
+                        self.partial.insert(index, piece);
+                    }
+                }
+                self.waiting.push_back((peer, reply));
+            }
+            PeerEvent::Unsnubbed(peer) => {
+                self.choker.set_snubbed(peer, false);
+                if let Some(state) = self.peers.get_mut(&peer) {
+                    state.snubbed = false;
+                }
             }
-            Ok(Async::Ready(TrackerResponse::Success(resp))) => {
-                // do something with the response
-                trace!("tracker response: {:?}", resp)
+            PeerEvent::SupportsHolepunch(peer) => {
+                self.holepunch_peers.insert(peer);
             }
-            _ => () // not ready
+            PeerEvent::Holepunch { peer, message } => match message {
+                HolepunchMessage::Rendezvous(target) => self.rendezvous(peer, target),
+                HolepunchMessage::Connect(address) => self.holepunch_connect(address),
+                HolepunchMessage::Error(address, error) => {
+                    debug!(target: &self.log_target, "Peer {} could not put us in touch with {}: {:?}", peer, address, error);
+                }
+            },
+            PeerEvent::Wasted { peer, bytes } => {
+                trace!(target: &self.log_target, "Peer {} sent {} bytes we had no use for", peer, bytes);
+                self.stats.record_waste(bytes as u64);
+            }
+            PeerEvent::Interest { peer, interested } => self.choker.set_interested(peer, interested),
+            PeerEvent::Has { peer, pieces } => {
+                let offer = match self.super_seed.as_mut() {
+                    Some(super_seed) => super_seed.update(peer, &pieces, self.picker.have()),
+                    None => None,
+                };
+                if let Some(index) = offer {
+                    trace!(target: &self.log_target, "Offering piece {} to peer {}", index, peer);
+                    if let Some(outbox) = self.have_outboxes.get_mut(&peer) {
+                        outbox.send(HaveBroadcast::new(index));
+                    }
+                }
+            }
+            PeerEvent::Discovered { peer, peers } => {
+                trace!(target: &self.log_target, "Peer {} told us about {} peers", peer, peers.len());
+                self.add_known_peers(peers);
+            }
+            PeerEvent::Trackers { peer, urls } => {
+                trace!(target: &self.log_target, "Peer {} told us about {} trackers", peer, urls.len());
+                self.add_tex_trackers(urls);
+            }
+            PeerEvent::DhtNode { peer, node } => {
+                trace!(target: &self.log_target, "Peer {} runs a DHT node at {}", peer, node);
+                if let Some(dht) = &self.dht {
+                    dht.add_node(node);
+                }
+            }
+            PeerEvent::Handshake { peer, peer_id } => {
+                if let Some(client) = client_ident::identify(&peer_id) {
+                    self.identified(peer, client);
+                }
+            }
+            PeerEvent::Ourselves(peer) => {
+                if let Some(address) = self.peer_addresses.get(&peer) {
+                    info!(target: &self.log_target, "{} is our own address, never dialing it again", address);
+                    self.dialer.never_dial(*address);
+                }
+            }
+        }
+    }
+
+    /// A peer asked us to relay a ut_holepunch rendezvous with `target`.  If we are connected to
+    /// both, each is told to connect to the other
+    fn rendezvous(&mut self, peer: usize, target: SocketAddr) {
+        let initiator = match self.peer_addresses.get(&peer) {
+            Some(address) => *address,
+            None => return,
         };
-        // poll for new connections, spin up new peer tasks
+        let target_id = self.peer_addresses.iter()
+            .find(|(_, address)| **address == target)
+            .map(|(id, _)| *id);
+        let relayed = match target_id {
+            _ if initiator == target => Err(HolepunchError::NoSelf),
+            None => Err(HolepunchError::NoSuchPeer),
+            // Its choke sender is only gone when the connection is being dropped
+            Some(id) if !self.choke_senders.contains_key(&id) => Err(HolepunchError::NotConnected),
+            Some(id) if !self.holepunch_peers.contains(&id) => Err(HolepunchError::NoSupport),
+            Some(id) => Ok(id),
+        };
+        let replies = match relayed {
+            Ok(id) => {
+                debug!(target: &self.log_target, "Relaying a holepunch between {} and {}", initiator, target);
+                vec![(id, HolepunchMessage::Connect(initiator)), (peer, HolepunchMessage::Connect(target))]
+            }
+            Err(error) => vec![(peer, HolepunchMessage::Error(target, error))],
+        };
+        for (id, message) in replies {
+            if let Some(sender) = self.holepunch_senders.get_mut(&id) {
+                let _res = sender.try_send(message);
+            }
+        }
+    }
+
+    /// A relay told us to connect to a peer, which is connecting to us at the same time
+    fn holepunch_connect(&mut self, address: SocketAddr) {
+        // Through a proxy our connection wouldn't open our NAT
+        if self.paused || self.proxy.is_some() || self.at_peer_limit()
+            || self.peer_addresses.values().any(|connected| *connected == address)
+            || self.bans.lock().unwrap().is_banned(address.ip()) || self.blocklist.is_blocked(address.ip()) {
+            return;
+        }
+        let permit = match self.connection_limits.dial() {
+            Some(permit) => permit,
+            None => return,
+        };
+        debug!(target: &self.log_target, "Connecting to {} for a holepunch", address);
+        self.dials.push((address, permit, dial_timeout(TcpStream::connect(address)).boxed()));
+    }
+
+    /// Dial known peers until we have the number of peers we want, counting the connections
+    /// still opening
+    fn dial_peers(&mut self) {
+        // On I2P peers are found by destination, not address
+        if self.paused || self.stopping.is_some() || self.seed_check.is_some() || self.i2p.is_some()
+            || self.at_peer_limit() {
+            return;
+        }
+        let target = self.max_peers.map_or(self.target_peers, |max| self.target_peers.min(max));
+        let wanted = target.saturating_sub(self.connected.len() + self.dials.len());
+        // Past the half-open limit, dials wait for others to finish
+        let permits: Vec<Permit> = (0..wanted).map_while(|_| self.connection_limits.dial()).collect();
+        if permits.is_empty() {
+            return;
+        }
+        let addresses = self.dialer.next(permits.len(), Instant::now());
+        for (address, permit) in addresses.into_iter().zip(permits) {
+            if self.peer_addresses.values().any(|connected| *connected == address) {
+                self.dialer.connected(address);
+                continue;
+            }
+            trace!(target: &self.log_target, "Dialing peer {}, {} dials in flight", address, self.dialer.dialing());
+            let conn = match &self.proxy {
+                Some(proxy) => dial_timeout(socks::connect(proxy, address)).boxed(),
+                None => dial_timeout(TcpStream::connect(address)).boxed(),
+            };
+            self.dials.push((address, permit, conn));
+        }
+    }
+
+    /// Start peers on the connections we opened
+    fn poll_dials(&mut self, cx: &mut Context<'_>) {
+        let mut i = 0;
+        while i < self.dials.len() {
+            match self.dials[i].2.poll_unpin(cx) {
+                Poll::Pending => i += 1,
+                Poll::Ready(Ok(conn)) => {
+                    let (address, _, _) = self.dials.swap_remove(i);
+                    self.dialer.connected(address);
+                    if !self.paused && (!self.at_peer_limit() || self.make_room_for(address)) {
+                        self.add_peer(NewConnection::Dialed(conn, address));
+                    } else {
+                        // Try again once there is room
+                        self.dialer.disconnected(address, Instant::now());
+                    }
+                }
+                Poll::Ready(Err(e)) => {
+                    let (address, _, _) = self.dials.swap_remove(i);
+                    self.dialer.failed(address, Instant::now());
+                    debug!(target: &self.log_target, "Could not connect to {}: {}", address, e);
+                }
+            }
+        }
+    }
+
+    /// Remember peers to connect to later
+    fn add_known_peers<I: IntoIterator<Item=SocketAddr>>(&mut self, peers: I) {
+        for address in peers {
+            if self.dialer.len() >= MAX_KNOWN_PEERS {
+                break;
+            }
+            // We can't reach IPv6 peers without an IPv6 address of our own
+            if address.is_ipv6() && self.ipv6.is_none() {
+                continue;
+            }
+            if !self.bans.lock().unwrap().is_banned(address.ip()) && !self.blocklist.is_blocked(address.ip()) {
+                self.dialer.add(address);
+            }
+        }
+    }
+
+    /// Remember peers from a tracker, and the peer ids it gave for them
+    fn add_tracker_peers(&mut self, peers: Vec<PeerInfo>) {
+        for peer in &peers {
+            if let Some(peer_id) = peer.peer_id {
+                if self.tracker_peer_ids.len() < MAX_KNOWN_PEERS || self.tracker_peer_ids.contains_key(&peer.address) {
+                    self.tracker_peer_ids.insert(peer.address, peer_id);
+                }
+            }
+        }
+        self.add_known_peers(peers.into_iter().map(|peer| peer.address));
+    }
+
+    /// Start a DHT search for peers, unless one is still running.  If peers can connect to us we
+    /// join the swarm in the DHT too
+    fn search_dht(&mut self) {
+        if self.paused || self.dht_search.is_some() {
+            return;
+        }
+        let announce_port = self.incoming.as_ref().map(|_| self.port);
+        if let Some(dht) = &self.dht {
+            self.dht_search = Some(dht.get_peers(self.info_hash, announce_port, self.seeding).boxed());
+        }
+    }
+
+    /// Announce again after the interval the tracker asked for
+    fn schedule_announce(&mut self) {
+        self.announce_timer = Some(Box::pin(time::sleep(self.tracker.announce_interval())));
+    }
+
+    /// Start stopping the torrent.  Peers are disconnected right away, then the pieces we
+    /// finished are written out, our progress is saved, and the tracker is told we left
+    fn stop(&mut self) {
+        info!(target: &self.log_target, "Stopping {}", self.name);
+        self.incoming = None;
+        if let Some(routes) = &self.routes {
+            let info_hashes: Vec<[u8; 20]> = Some(self.info_hash).into_iter().chain(self.alt_info_hash).collect();
+            routes.remove(&info_hashes);
+        }
+        // Peers close their connections once they can't hear from us
+        self.choke_senders.clear();
+        self.stopping = Some(Stopping::Flushing(Box::pin(time::sleep(FLUSH_TIMEOUT))));
+    }
+
+    /// Drive the torrent to a stop.  Ready once there is nothing left to wait for
+    fn poll_stopping(&mut self, cx: &mut Context<'_>) -> Poll<()> {
         loop {
-            match self.listener.poll() {
-                Ok(Async::Ready(Some(conn))) => {
-                    let (up_sender, up_receiver) = channel(10);
-                    let (down_sender, down_receiver) = channel(10);
-                    let (piece_sender, piece_receiver) = channel(10);
-
-                    replace_with(&mut self.uploaded_stream,
-                                 /* default, in case replacement panics */ || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(up_receiver)));
-                    replace_with(&mut self.downloaded_stream,
-                                 || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(down_receiver)));
-                    replace_with(&mut self.piece_stream,
-                                 || Box::new(stream::empty()),
-                                 |s| Box::new(s.select(piece_receiver)));
-                    let peer = Peer::new(conn,
-                                         up_sender,
-                                         down_sender,
-                                         piece_sender,
-                                         self.info_hash.clone(),
-                                         self.peer_id.clone(),
-                                         false);
-                    spawn(peer);
+            match self.stopping.as_mut() {
+                Some(Stopping::Flushing(deadline)) => {
+                    let timed_out = deadline.as_mut().poll(cx).is_ready();
+                    self.write_pieces(cx);
+                    while let Poll::Ready(Some(written)) = self.written_stream.poll_next_unpin(cx) {
+                        self.piece_written(written);
+                    }
+                    if !self.finishing.is_empty() && !timed_out {
+                        return Poll::Pending;
+                    }
+                    if timed_out {
+                        warn!(target: &self.log_target, "Gave up waiting for {} pieces to be written", self.finishing.len());
+                    }
+                    self.save_session(true);
+                    self.publish_stats();
+                    if !self.tracker_started {
+                        return Poll::Ready(());
+                    }
+                    self.tracker.cancel(self.left, self.stats.uploaded(), self.stats.downloaded());
+                    self.stopping = Some(Stopping::Announcing(Box::pin(time::sleep(STOP_TIMEOUT))));
+                }
+                Some(Stopping::Announcing(deadline)) => {
+                    if self.tracker.poll_unpin(cx).is_pending() && deadline.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    return Poll::Ready(());
+                }
+                None => return Poll::Ready(()),
+            }
+        }
+    }
+
+    /// The last piece is on disk, so tell the tracker and start seeding
+    fn finish(&mut self) {
+        trace!(target: &self.log_target, "Finished");
+        self.seeding = true;
+        // Peers that are already connected have seen our pieces, so only new ones are super-seeded
+        if self.seed_strategy == SeedStrategy::SuperSeed {
+            self.super_seed = Some(SuperSeed::new(self.piece_hashes.len()));
+        }
+        if !self.completed_announced {
+            self.completed_announced = true;
+            if self.tracker_started {
+                self.announce_completed();
+            } else {
+                self.completed_pending = true;
+            }
+        }
+        self.notify(EventKind::Completed, None);
+        self.save_session(true);
+        // Choke with the seeding order right away instead of waiting for the next recompute
+        self.recompute_chokes();
+    }
+
+    /// Tell the tracker we are talking to that we completed, and the first tracker of every other
+    /// tier as well, since we only ever talk to one of them
+    fn announce_completed(&mut self) {
+        self.completed_pending = false;
+        let (uploaded, downloaded) = (self.stats.uploaded(), self.stats.downloaded());
+        self.tracker.finish(self.left, uploaded, downloaded);
+        let current = self.current_template().unwrap_or_default();
+        let urls: Vec<_> = {
+            let passkeys = self.passkeys.lock().unwrap();
+            self.trackers.other_tiers(&current).into_iter().map(|template| passkeys.fill(template)).collect()
+        };
+        for url in urls {
+            let mut tracker = Tracker::new(self.peer_id, url.clone(), self.info_hash, self.port);
+            if let Some(session) = self.i2p.as_ref().and_then(I2pTransport::session) {
+                tracker.set_i2p(session.clone());
+            }
+            tracker.set_proxy(self.proxy.clone());
+            tracker.set_tls(self.tracker_tls.clone());
+            tracker.set_ipv6(self.ipv6);
+            tracker.set_key(self.tracker_key);
+            tracker.set_numwant(self.numwant);
+            tracker.finish(self.left, uploaded, downloaded);
+            let log_target = self.log_target.clone();
+            spawn(tracker.map(move |result| {
+                match result {
+                    Ok(TrackerResponse::Failure(msg)) =>
+                        warn!(target: &log_target, "Tracker {} refused our completed announce: {}", passkey::redact(&url), msg),
+                    Err(e) =>
+                        warn!(target: &log_target, "Couldn't send the completed announce to tracker {}: {:?}", passkey::redact(&url), e),
+                    Ok(_) => debug!(target: &log_target, "Tracker {} knows we completed", passkey::redact(&url)),
+                }
+            }));
+        }
+    }
+
+    /// Add the time since we last counted to the seed time, if we were seeding all along
+    fn count_seed_time(&mut self) {
+        let now = Instant::now();
+        if self.seeding && !self.paused && self.stopping.is_none() {
+            self.seed_time += now - self.seed_clock;
+        }
+        self.seed_clock = now;
+    }
+
+    /// Pause or stop the torrent once it has seeded enough
+    fn check_seed_limits(&mut self) {
+        if !self.seeding || self.paused || self.stopping.is_some() || self.seed_limit_reached {
+            return;
+        }
+        let ratio = share_ratio(self.stats.uploaded(), self.stats.downloaded(), self.download_size);
+        if !self.seed_limits.reached(ratio, self.seed_time) {
+            return;
+        }
+        info!(target: &self.log_target, "{} reached its seed limit with a ratio of {:.2} after seeding {} minutes",
+              self.name, ratio, self.seed_time.as_secs() / 60);
+        self.seed_limit_reached = true;
+        self.notify(EventKind::SeedLimitReached, None);
+        // Either way the tracker is told we stopped
+        match self.seed_limits.action {
+            SeedLimitAction::Pause => self.pause(false),
+            SeedLimitAction::Stop => self.stop(),
+        }
+    }
+
+    /// Pick which peers to upload to, and tell the ones whose state changed
+    fn recompute_chokes(&mut self) {
+        if self.paused {
+            return;
+        }
+        let now = Instant::now();
+        for (peer, upload_rate, download_rate) in self.stats.peer_rates(now) {
+            self.choker.set_rates(peer, upload_rate, download_rate);
+        }
+        let changes = self.choker.recompute(self.seeding, self.seed_strategy, now);
+        for (peer, choke) in changes {
+            if let Some(sender) = self.choke_senders.get_mut(&peer) {
+                // A decision goes out every ten seconds at most, so the channel never fills
+                let _res = sender.try_send(choke);
+            }
+        }
+    }
+
+    /// Send every peer the list of peers we are connected to, for ut_pex
+    fn broadcast_pex(&mut self) {
+        let snapshot: PexSnapshot = Arc::new(self.peer_addresses.values().cloned().collect());
+        self.pex_senders.retain(|sender| match sender.clone().try_send(snapshot.clone()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        });
+    }
+
+    /// Send every peer the trackers we use, for lt_tex.  Trackers with passkeys are kept to ourselves
+    fn broadcast_tex(&mut self) {
+        let snapshot: TexSnapshot = Arc::new(self.trackers.tiers().iter()
+            .flatten()
+            .filter(|template| !template.contains(passkey::PLACEHOLDER))
+            .cloned()
+            .collect());
+        self.tex_senders.retain(|sender| match sender.clone().try_send(snapshot.clone()) {
+            Ok(()) => true,
+            Err(e) => !e.is_disconnected(),
+        });
+    }
+
+    /// Add trackers peers told us about in a new tier after the others, so the torrent's own
+    /// trackers stay preferred
+    fn add_tex_trackers(&mut self, urls: Vec<String>) {
+        for url in urls {
+            if self.tex_trackers >= MAX_TEX_TRACKERS {
+                return;
+            }
+            // A passkey from a peer belongs to somebody else's account
+            if passkey::redact(&url) != url {
+                continue;
+            }
+            // On I2P only I2P trackers keep our ip hidden, and off it I2P trackers can't be reached.
+            // UDP can't go through the proxy
+            if i2p::is_i2p(&url) != self.i2p.is_some()
+                || (url.starts_with("udp://") && (self.i2p.is_some() || self.proxy.is_some())) {
+                continue;
+            }
+            if self.add_tracker(None, &url).is_ok() {
+                info!(target: &self.log_target, "Peer told us about tracker {}", url);
+                self.tex_trackers += 1;
+            }
+        }
+    }
+
+    /// Hand pieces to the storage thread, as far as its queue allows
+    fn write_pieces(&mut self, cx: &mut Context<'_>) {
+        while let Some(piece) = self.unwritten.pop_front() {
+            if self.storage.poll_ready(cx).is_pending() {
+                self.unwritten.push_front(piece);
+                break;
+            }
+            match self.storage.try_send(piece) {
+                Ok(()) => (),
+                Err(e) if e.is_full() => {
+                    self.unwritten.push_front(e.into_inner());
+                    break;
                 }
                 Err(e) => {
-                    error!("TCP Listener closed unexpectedly with error: {}", e);
-                    return Err(());
+                    error!(target: &self.log_target, "The storage thread stopped");
+                    self.picker.abandon(e.into_inner().index());
                 }
-                _ => break,
             }
         }
+    }
 
-        // get uploaded/downloaded statistic updates
+    /// The storage thread is done with a piece
+    fn piece_written(&mut self, written: Written) {
+        let index = written.index;
+        self.finishing.remove(&index);
+        let sources = self.piece_sources.remove(&index).unwrap_or_default();
+        match written.result {
+            Ok(()) => {
+                debug!(target: &self.log_target, "Finished piece {}", index);
+                self.bad_data.passed(&sources);
+                self.picker.finish(index);
+                // A piece of a file that was skipped while it downloaded was never counted
+                if self.picker.is_wanted(index as usize) {
+                    self.left -= self.piece_size(index);
+                }
+                if let Some((_, finished)) = &mut self.verifying {
+                    finished.push(index);
+                }
+                let start = index as u64 * self.piece_length;
+                let touched: Vec<usize> = self.files.lock().unwrap().spans(start, self.piece_size(index))
+                    .into_iter()
+                    .map(|(file, _, _)| file)
+                    .collect();
+                self.finish_files(touched, true);
+                self.broadcast_have(index);
+                self.events.send(Event::PieceVerified { info_hash: self.info_hash, index });
+            }
+            Err(StorageError::HashMismatch) => {
+                warn!(target: &self.log_target, "Piece {} failed its hash check", index);
+                self.stats.record_waste(self.piece_size(index));
+                self.events.send(Event::PieceFailed { info_hash: self.info_hash, index });
+                self.picker.abandon(index);
+                for ip in self.bad_data.failed(&sources) {
+                    warn!(target: &self.log_target, "Banning {} for sending bad data", ip);
+                    self.ban_ip(ip, "sent data that failed hash checks");
+                }
+            }
+            Err(StorageError::Io(e)) => {
+                error!(target: &self.log_target, "Could not write piece {}: {}", index, e);
+                self.notify(EventKind::Error, Some(format!("Could not write piece {}: {}", index, e)));
+                self.picker.abandon(index);
+            }
+        }
+    }
+
+    /// See which of the files among `candidates` now have all their pieces on disk.  Those are
+    /// moved out of the incomplete directory, and their MD5 sums are checked if `check_md5` is
+    /// set.  Skipped files are left alone
+    fn finish_files<I: IntoIterator<Item=usize>>(&mut self, candidates: I, check_md5: bool) {
+        let mut files = self.files.lock().unwrap();
+        for index in candidates {
+            if self.finished_files.contains(&index) || files.is_skipped(index) {
+                continue;
+            }
+            let file = &files.files()[index];
+            let finished = file.length == 0 || {
+                let first = (file.offset / self.piece_length) as usize;
+                let last = ((file.offset + file.length - 1) / self.piece_length) as usize;
+                (first..=last).all(|piece| self.picker.have().get(piece) == Some(true))
+            };
+            if !finished {
+                continue;
+            }
+            self.finished_files.insert(index);
+            let name = file.torrent_path.clone();
+            if files.is_incomplete(index) {
+                match files.complete_file(index) {
+                    Ok(()) => debug!(target: &self.log_target, "Moved {} out of the incomplete directory", name),
+                    Err(e) => {
+                        let e = format!("Could not move {} out of the incomplete directory: {}", name, e);
+                        error!(target: &self.log_target, "{}", e);
+                        self.notify(EventKind::Error, Some(e));
+                    }
+                }
+            }
+            if check_md5 && self.md5sums.get(index).is_some_and(Option::is_some) {
+                let snapshot = files.clone();
+                let sender = self.md5_sender.clone();
+                let spawned = thread::Builder::new()
+                    .name("md5-check".to_string())
+                    .spawn(move || {
+                        let _res = sender.unbounded_send((index, verify::file_md5(&snapshot, index)));
+                    });
+                if let Err(e) = spawned {
+                    warn!(target: &self.log_target, "Could not start checking the MD5 sum of {}: {}", name, e);
+                }
+            }
+        }
+    }
+
+    /// Compare the MD5 sums computed for finished files with the ones in the metainfo
+    fn poll_md5_checks(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some((index, result))) = self.md5_results.poll_next_unpin(cx) {
+            let name = self.files.lock().unwrap().files()[index].torrent_path.clone();
+            let expected = match self.md5sums.get(index) {
+                Some(Some(expected)) => expected,
+                _ => continue,
+            };
+            match result {
+                Ok(sum) if sum.eq_ignore_ascii_case(expected.trim()) => {
+                    debug!(target: &self.log_target, "{} matches its MD5 sum", name);
+                    self.md5_mismatches.remove(&index);
+                }
+                Ok(sum) => {
+                    warn!(target: &self.log_target, "{} doesn't match its MD5 sum: expected {}, found {}", name, expected, sum);
+                    self.md5_mismatches.insert(index);
+                    self.notify(EventKind::Md5Mismatch, Some(name));
+                }
+                Err(e) => warn!(target: &self.log_target, "Could not check the MD5 sum of {}: {}", name, e),
+            }
+        }
+    }
+
+    /// Note which client a peer runs, and drop it if the client is banned
+    fn identified(&mut self, peer: usize, client: ClientIdent) {
+        debug!(target: &self.log_target, "Peer {} runs {}", peer, client);
+        if self.banned_clients.iter().any(|pattern| client.matches(pattern)) {
+            match self.peer_addresses.get(&peer).cloned() {
+                Some(address) => {
+                    warn!(target: &self.log_target, "Banning {} for running {}", address.ip(), client);
+                    self.ban_ip(address.ip(), &format!("runs a banned client ({})", client));
+                }
+                None => {
+                    debug!(target: &self.log_target, "Dropping peer {}, it runs {}", peer, client);
+                    self.choke_senders.remove(&peer);
+                }
+            }
+        }
+        self.peer_clients.insert(peer, client);
+    }
+
+    /// Ban an address for as long as peers sending bad data are banned, and drop every
+    /// connection to it
+    fn ban_ip(&mut self, ip: IpAddr, reason: &str) {
+        self.bans.lock().unwrap().ban(ip, reason, Some(self.bad_data_ban));
+        let connections: Vec<usize> = self.peer_addresses.iter()
+            .filter(|(_, address)| address.ip() == ip)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in connections {
+            // The peer's task ends when it loses its choke sender
+            self.choke_senders.remove(&id);
+        }
+        self.dialer.remove_ip(ip);
+    }
+
+    /// Hand pieces to the peers waiting for one.  Peers that have nothing we need are let go, and
+    /// the rest keep waiting for a piece to free up or for memory to be written out
+    fn assign_pieces(&mut self) {
+        // Peers asking for work wait until we resume, or until a recheck is done
+        if self.paused || self.verifying.is_some() {
+            return;
+        }
+        let mut still_waiting = VecDeque::new();
+        while let Some((peer, mut reply)) = self.waiting.pop_front() {
+            let (pick, interesting) = match self.peers.get(&peer) {
+                // A snubbing peer doesn't get back a piece someone else could finish
+                Some(state) => (self.picker.pick(state.pieces.bits(), state.last_piece)
+                                    .filter(|index| !(state.snubbed && self.partial.contains_key(index))),
+                                self.picker.interesting(state.pieces.bits())),
+                None => continue,
+            };
+            let index = match pick.or_else(|| self.pick_endgame(peer)) {
+                Some(index) => index,
+                None => {
+                    if interesting {
+                        still_waiting.push_back((peer, reply));
+                    }
+                    continue;
+                }
+            };
+            let hash = self.piece_hashes[index as usize];
+            let piece = self.partial.remove(&index)
+                .or_else(|| Piece::with_budget(index, self.piece_size(index) as u32, hash, &self.memory_budget));
+            // In endgame the piece shares its blocks with the copies other peers have
+            let arrivals = self.peers.values()
+                .filter(|other| other.current == Some(index))
+                .find_map(|other| other.arrivals.clone());
+            let piece = match (piece, arrivals) {
+                (Some(piece), Some(arrivals)) => piece.share(arrivals),
+                (Some(piece), None) => piece,
+                (None, _) => {
+                    // Nothing else fits either, so everyone keeps waiting
+                    still_waiting.push_back((peer, reply));
+                    still_waiting.extend(self.waiting.drain(..));
+                    break;
+                }
+            };
+            let arrivals = piece.arrivals();
+            if reply.try_send(piece).is_ok() {
+                trace!(target: &self.log_target, "Gave piece {} to peer {}", index, peer);
+                self.picker.start(index);
+                if let Some(state) = self.peers.get_mut(&peer) {
+                    state.current = Some(index);
+                    state.arrivals = Some(arrivals);
+                    state.last_piece = Some(index);
+                }
+            }
+        }
+        self.waiting = still_waiting;
+    }
+
+    /// In endgame, pick a piece someone else is downloading for a peer that has nothing else to
+    /// do.  The piece with the fewest peers on it is picked
+    fn pick_endgame(&self, peer: usize) -> Option<u32> {
+        let endgame = self.picker.all_started() || self.left <= ENDGAME_BLOCKS * BLOCK_SIZE as u64;
+        if !endgame {
+            return None;
+        }
+        let state = self.peers.get(&peer)?;
+        self.picker.in_progress(state.pieces.bits()).into_iter()
+            .filter(|index| !self.finishing.contains(index))
+            .min_by_key(|index| self.peers.values().filter(|other| other.current == Some(*index)).count())
+    }
+
+    /// Tell every connected peer that we have a piece.  The message is encoded once and shared
+    pub fn broadcast_have(&mut self, index: u32) {
+        let have = HaveBroadcast::new(index);
+        self.have_outboxes.retain(|_, outbox| outbox.send(have.clone()));
+    }
+
+    /// Add what we have transferred since last time to the session and save it, along with the
+    /// torrent's resume data.  Unless `force` is true, this is only done every
+    /// SESSION_SAVE_INTERVAL so we aren't always writing to disk
+    fn save_session(&mut self, force: bool) {
+        if !force && self.last_session_save.elapsed() < SESSION_SAVE_INTERVAL {
+            return;
+        }
+        self.last_session_save = Instant::now();
+        self.count_seed_time();
+        let mut session = self.session.lock().unwrap();
+        session.record_transfer(&self.info_hash,
+                                self.stats.uploaded() - self.recorded_uploaded,
+                                self.stats.downloaded() - self.recorded_downloaded);
+        self.recorded_uploaded = self.stats.uploaded();
+        self.recorded_downloaded = self.stats.downloaded();
+        // Only whole seconds are recorded, the rest is left for next time
+        let seconds = (self.seed_time - self.recorded_seed_time).as_secs();
+        session.record_seed_time(&self.info_hash, seconds);
+        self.recorded_seed_time += Duration::from_secs(seconds);
+        if let Err(e) = session.save() {
+            warn!(target: &self.log_target, "Could not save the session: {:?}", e);
+        }
+        drop(session);
+        self.save_resume();
+    }
+
+    /// Save which pieces we have, so a restart doesn't download them again
+    fn save_resume(&self) {
+        let files = self.files.lock().unwrap();
+        let resume = ResumeData {
+            info_hash: self.info_hash,
+            pieces: self.picker.have().clone(),
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            tracker_id: self.tracker.tracker_id().map(str::to_owned),
+            tracker_key: Some(self.tracker_key),
+            file_lengths: files.files().iter().map(|file| file.length).collect(),
+        };
+        if let Err(e) = resume.save(&self.resume_path) {
+            warn!(target: &self.log_target, "Could not save the resume data of {}: {:?}", self.name, e);
+        }
+    }
+
+    /// Logs the peers the tracker gave us, along with where they are if we have a geoip database
+    fn log_peers(&self, resp: &TrackerSuccessResponse) {
+        for peer in &resp.peers {
+            match &self.geoip {
+                Some(geoip) => debug!(target: &self.log_target, "peer {} [{}]", peer.address, geoip.lookup(peer.address.ip())),
+                None => debug!(target: &self.log_target, "peer {}", peer.address),
+            }
+        }
+    }
+}
+
+impl Future for Server {
+    type Output = Result<(), ()>;
+
+    /// This is the main event loop for the client.  It returns Ready(Ok(())) Only when the download
+    /// is complete.
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        if this.stopping.is_some() {
+            return this.poll_stopping(cx).map(Ok);
+        }
+        if let Some(Poll::Ready(_)) = this.shutdown.as_mut().map(|shutdown| shutdown.poll_unpin(cx)) {
+            this.stop();
+            return this.poll_stopping(cx).map(Ok);
+        }
+        trace!(target: &this.log_target, "Start Loop");
+        if !this.added_notified {
+            this.added_notified = true;
+            this.notify(EventKind::TorrentAdded, None);
+        }
+        // if a passkey changed, announce with the new one
+        let passkeys_version = this.passkeys.lock().unwrap().version();
+        if passkeys_version != this.passkeys_version {
+            this.passkeys_version = passkeys_version;
+            this.switch_to_preferred_tracker();
+        }
+        // bring up I2P, and take the streams peers open to us through it
+        while let Some(poll) = this.i2p.as_mut().map(|i2p| i2p.poll_next_unpin(cx)) {
+            match poll {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if this.at_peer_limit() {
+                        debug!(target: &this.log_target, "Refusing I2P connection, already at the peer limit");
+                        continue;
+                    }
+                    this.add_peer(NewConnection::Accepted(conn));
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    error!(target: &this.log_target, "Lost the I2P session: {}", e);
+                    this.notify(EventKind::Error, Some(format!("Lost the I2P session: {}", e)));
+                    return Poll::Ready(Err(()));
+                }
+                _ => break,
+            }
+        }
+        // hold off on joining the swarm until we know it has a seed
+        if !this.poll_seed_check(cx) {
+            return Poll::Pending;
+        }
         loop {
-            match self.uploaded_stream.poll() {
-                Ok(Async::Ready(Some(update))) => self.uploaded += update as u64,
+            match this.commands.poll_next_unpin(cx) {
+                Poll::Ready(Some(Command::Pause { drop_peers })) => this.pause(drop_peers),
+                Poll::Ready(Some(Command::Resume)) => this.resume(),
+                Poll::Ready(Some(Command::Verify)) => this.verify(),
+                Poll::Ready(Some(Command::AddTracker { tier, url, reply })) => {
+                    let _res = reply.send(this.add_tracker(tier, &url));
+                }
+                Poll::Ready(Some(Command::RemoveTracker { url, reply })) => {
+                    let _res = reply.send(this.remove_tracker(&url));
+                }
+                Poll::Ready(Some(Command::Reannounce { url, reply })) => {
+                    let _res = reply.send(this.reannounce(url.as_deref()));
+                }
+                Poll::Ready(Some(Command::RenameFile { index, name, reply })) => {
+                    let _res = reply.send(this.rename_file(index, &name));
+                }
+                Poll::Ready(Some(Command::RenameRoot { name, reply })) => {
+                    let _res = reply.send(this.rename_root(&name));
+                }
+                Poll::Ready(Some(Command::SetFilePriority { index, priority, reply })) => {
+                    let _res = reply.send(this.set_file_priority(index, priority));
+                }
+                Poll::Ready(Some(Command::SetBandwidthPriority { priority, reply })) => {
+                    let _res = reply.send(this.set_bandwidth_priority(priority));
+                }
+                Poll::Ready(Some(Command::Stop)) => {
+                    this.stop();
+                    return this.poll_stopping(cx).map(Ok);
+                }
                 _ => break,
             }
         }
+        let verified = match this.verifying.as_mut().map(|(receiver, _)| receiver.poll_unpin(cx)) {
+            Some(Poll::Pending) | None => None,
+            Some(Poll::Ready(result)) => this.verifying.take().map(|(_, finished)| (result, finished)),
+        };
+        match verified {
+            Some((Ok(report), finished)) => this.verified(report, finished),
+            Some(_) => warn!(target: &this.log_target, "The recheck of {} stopped part way through", this.name),
+            None => (),
+        }
+        this.poll_md5_checks(cx);
+        // Wakes us once a tracker backoff is over
+        if let Some(Poll::Ready(())) = this.tracker_backoff.as_mut().map(|backoff| backoff.as_mut().poll(cx)) {
+            this.tracker_backoff = None;
+        }
+        if !this.tracker_started && !this.paused {
+            this.start_tracker();
+        }
+        // check on the tracker response
+        let tracker_poll = if this.tracker_started {
+            this.tracker.poll_unpin(cx)
+        } else {
+            Poll::Pending
+        };
+        match tracker_poll {
+            Poll::Ready(Err(e)) => {
+                error!(target: &this.log_target, "Something went wrong in making a request to the tracker: {:?}", e);
+                this.tracker_refused(format!("{:?}", e));
+            }
+            Poll::Ready(Ok(TrackerResponse::Failure(msg))) => {
+                error!(target: &this.log_target, "The tracker responded with an error: {}", msg);
+                this.tracker_refused(msg);
+            }
+            Poll::Ready(Ok(TrackerResponse::Warning(msg, resp))) => {
+                warn!(target: &this.log_target, "The tracker responeded with a warning: {}", msg);
+                this.events.send(Event::TrackerWarning {
+                    info_hash: this.info_hash,
+                    tracker: passkey::redact(this.tracker.uri()),
+                    message: msg,
+                });
+                this.tracker_responded(resp);
+            }
+            Poll::Ready(Ok(TrackerResponse::Success(resp))) => this.tracker_responded(resp),
+            Poll::Pending => () // not ready
+        };
+        // keep the tracker up to date, and get more peers
+        if let Some(Poll::Ready(())) = this.announce_timer.as_mut().map(|timer| timer.as_mut().poll(cx)) {
+            this.announce_timer = None;
+            this.tracker.refresh(this.left, this.stats.uploaded(), this.stats.downloaded());
+        }
+
+        // spin up peer tasks for the connections the session's listener handed us
         loop {
-            match self.downloaded_stream.poll() {
-                Ok(Async::Ready(Some(update))) => self.downloaded += update as u64,
+            let incoming = match this.incoming.as_mut().map(|incoming| incoming.poll_next_unpin(cx)) {
+                Some(Poll::Ready(Some(incoming))) => incoming,
                 _ => break,
+            };
+            if this.paused {
+                debug!(target: &this.log_target, "Refusing connection, the torrent is paused");
+                continue;
+            }
+            if this.at_peer_limit() && !this.make_room_for(incoming.address) {
+                debug!(target: &this.log_target, "Refusing connection, already at the peer limit");
+                continue;
             }
+            this.add_peer(NewConnection::Routed(Box::new(incoming)));
         }
 
+        this.dial_peers();
+        this.poll_dials(cx);
+
+        // get uploaded/downloaded statistic updates
+        while let Poll::Ready(Some((peer, update))) = this.uploaded_stream.poll_next_unpin(cx) {
+            this.stats.record_upload(peer, update as u64, Instant::now());
+        }
+        while let Poll::Ready(Some((peer, update))) = this.downloaded_stream.poll_next_unpin(cx) {
+            this.stats.record_download(peer, update as u64, Instant::now());
+        }
+
+        this.save_session(false);
+
         // Get finished pieces and request new pieces
-        loop {
-            match self.piece_stream.poll() {
-                Ok(Async::Ready(Some((_finished_piece, _new_piece_sender, _availible_pieces)))) => {
-                    // TODO verify and write off the finished piece and either kill the peer or give
-                    // them a new piece
-                }
-                _ => break
+        while let Poll::Ready(Some(event)) = this.piece_stream.poll_next_unpin(cx) {
+            this.handle_peer_event(event);
+        }
+        this.write_pieces(cx);
+        // pass on Haves that were waiting for room in a peer's channel
+        this.have_outboxes.retain(|_, outbox| outbox.flush(cx));
+        // tell peers who else is in the swarm
+        while this.pex_interval.poll_tick(cx).is_ready() {
+            this.broadcast_pex();
+            this.broadcast_tex();
+        }
+        while this.choke_interval.poll_tick(cx).is_ready() {
+            this.recompute_chokes();
+        }
+        while this.dht_interval.poll_tick(cx).is_ready() {
+            this.search_dht();
+        }
+        while this.stats_interval.poll_tick(cx).is_ready() {
+            this.publish_stats();
+            this.check_seed_limits();
+        }
+        while let Some(search) = &mut this.dht_search {
+            match search.poll_next_unpin(cx) {
+                Poll::Ready(Some(peers)) => this.add_known_peers(peers),
+                Poll::Pending => break,
+                Poll::Ready(None) => this.dht_search = None,
             }
         }
+        while let Poll::Ready(Some(written)) = this.written_stream.poll_next_unpin(cx) {
+            this.piece_written(written);
+        }
+        this.assign_pieces();
 
-        // This future only finishes normally when the download is complete
-        if self.left == 0 {
-            trace!("Finished");
-            Ok(Async::Ready(()))
-        } else {
-            trace!("Did a loop");
-            Ok(Async::NotReady)
+        if this.left == 0 && !this.seeding && !this.paused {
+            this.finish();
         }
+        trace!(target: &this.log_target, "Did a loop");
+        // Once the download is done we seed until we are stopped
+        Poll::Pending
     }
 }
\ No newline at end of file
@@ -0,0 +1,470 @@
+//! session remembers every torrent we have been given, so they all start again after a restart
+//! without having to be added by hand.  The session lives in the state directory: a list of
+//! torrents in queue order, plus a copy of each torrent's metainfo file, each torrent's resume
+//! data, the transfer totals of every run, and the rate limits last set.
+use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::picker::Priority;
+use crate::ratelimit;
+use derive_error::Error;
+use log::warn;
+use maplit::hashmap;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::{
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    /// The session could not be read or written
+    Io(io::Error),
+    /// The session file could not be bdecoded
+    DecodeError(DecodeError),
+    /// The contents of the session file are not correct
+    #[error(non_std, no_from)]
+    InvalidSession(String),
+}
+
+/// A way to move a torrent in the queue
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+impl FromStr for QueueMove {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(QueueMove::Up),
+            "down" => Ok(QueueMove::Down),
+            "top" => Ok(QueueMove::Top),
+            "bottom" => Ok(QueueMove::Bottom),
+            _ => Err(format!("Invalid queue move: {}", s)),
+        }
+    }
+}
+
+/// A torrent in the session
+#[derive(Debug, PartialEq, Clone)]
+pub struct TorrentEntry {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    // Our copy of the torrent's metainfo file
+    pub torrent_file: PathBuf,
+    // Where the torrent's files are downloaded to
+    pub download_dir: String,
+    // Bytes transferred over every run of this torrent
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Seconds spent seeding over every run
+    pub seed_time: u64,
+    // If true, the torrent runs even if it is past the limit on active torrents
+    pub force_start: bool,
+    // How much each file is wanted, by file index.  Files past the end are normal
+    pub file_priorities: Vec<Priority>,
+    // If true, the torrent was paused when it last ran, and starts paused
+    pub paused: bool,
+    // The torrent's share of the rate limits, if it was changed from the command line's
+    pub bandwidth_priority: Option<ratelimit::Priority>,
+}
+
+impl TorrentEntry {
+    fn to_value(&self) -> Value {
+        let mut map = hashmap! {
+            Vec::from("info_hash") => Value::BString(Vec::from(hex(&self.info_hash))),
+            Vec::from("name") => Value::BString(Vec::from(self.name.as_bytes())),
+            Vec::from("torrent_file") => Value::BString(Vec::from(self.torrent_file.to_string_lossy().as_bytes())),
+            Vec::from("download_dir") => Value::BString(Vec::from(self.download_dir.as_bytes())),
+            Vec::from("uploaded") => Value::Integer(self.uploaded as i64),
+            Vec::from("downloaded") => Value::Integer(self.downloaded as i64),
+            Vec::from("seed_time") => Value::Integer(self.seed_time as i64),
+            Vec::from("force_start") => Value::Integer(self.force_start as i64),
+            Vec::from("paused") => Value::Integer(self.paused as i64),
+        };
+        if !self.file_priorities.is_empty() {
+            map.insert(Vec::from("file_priorities"), Value::List(self.file_priorities.iter()
+                .map(|priority| Value::BString(Vec::from(priority.name())))
+                .collect()));
+        }
+        if let Some(priority) = self.bandwidth_priority {
+            map.insert(Vec::from("bandwidth_priority"), Value::BString(Vec::from(priority.name())));
+        }
+        Value::Dict(map)
+    }
+}
+
+impl FromValue for TorrentEntry {
+    type Error = String;
+
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Torrent entry not a dictionary".to_string())?;
+
+        let info_hash = map.get("info_hash".as_bytes()).and_then(Value::bstring_utf8)
+            .and_then(|s| unhex(&s))
+            .ok_or("Missing key: info_hash".to_string())?;
+
+        let name = map.get("name".as_bytes()).and_then(Value::bstring_utf8)
+            .unwrap_or_default();
+
+        let torrent_file = map.get("torrent_file".as_bytes()).and_then(Value::bstring_utf8)
+            .map(PathBuf::from)
+            .ok_or("Missing key: torrent_file".to_string())?;
+
+        let download_dir = map.get("download_dir".as_bytes()).and_then(Value::bstring_utf8)
+            .ok_or("Missing key: download_dir".to_string())?;
+
+        let stat = |key: &str| map.get(key.as_bytes()).and_then(counter)
+            .unwrap_or(0);
+
+        let force_start = map.get("force_start".as_bytes()).and_then(Value::integer)
+            .is_some_and(|i| *i == 1);
+
+        let paused = map.get("paused".as_bytes()).and_then(Value::integer)
+            .is_some_and(|i| *i == 1);
+
+        let file_priorities = map.get("file_priorities".as_bytes()).and_then(Value::list)
+            .map(|list| list.iter()
+                .map(|priority| priority.bstring_utf8().ok_or("Invalid file priority".to_string())
+                    .and_then(|priority| priority.parse()))
+                .collect::<Result<Vec<_>, _>>())
+            .unwrap_or(Ok(Vec::new()))?;
+
+        let bandwidth_priority = map.get("bandwidth_priority".as_bytes()).and_then(Value::bstring_utf8)
+            .map(|priority| priority.parse())
+            .transpose()?;
+
+        Ok(TorrentEntry {
+            info_hash,
+            name,
+            torrent_file,
+            download_dir,
+            uploaded: stat("uploaded"),
+            downloaded: stat("downloaded"),
+            seed_time: stat("seed_time"),
+            force_start,
+            file_priorities,
+            paused,
+            bandwidth_priority,
+        })
+    }
+}
+
+/// Every torrent we are running, backed by the state directory
+pub struct Session {
+    // The state directory.  None if the session only lives in memory
+    dir: Option<PathBuf>,
+    torrents: Vec<TorrentEntry>,
+    // Bytes transferred by every torrent over every run, including torrents since removed
+    uploaded: u64,
+    downloaded: u64,
+    // The rate limits last set, in bytes per second.  None is unlimited
+    download_limit: Option<u64>,
+    upload_limit: Option<u64>,
+}
+
+impl Session {
+    /// Create an empty session that is never saved
+    pub fn new() -> Self {
+        Session {
+            dir: None,
+            torrents: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            download_limit: None,
+            upload_limit: None,
+        }
+    }
+
+    /// Load the session saved in the state directory `dir`.  A missing session is treated as
+    /// empty.
+    pub fn load<P: AsRef<Path>>(dir: P) -> Result<Self, SessionError> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut session = Session::new();
+        let bytes = match fs::read(dir.join("session")) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                session.dir = Some(dir);
+                return Ok(session);
+            }
+            Err(e) => return Err(SessionError::Io(e)),
+        };
+        let val = Value::decode(&bytes)?;
+        let map = val.dict()
+            .ok_or(SessionError::InvalidSession("Session not a dictionary".to_string()))?;
+        session.torrents = map.get("torrents".as_bytes())
+            .and_then(Value::list)
+            .ok_or(SessionError::InvalidSession("Missing key: torrents".to_string()))?
+            .iter()
+            .map(TorrentEntry::from_value)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(SessionError::InvalidSession)?;
+        // Sessions saved before these were kept start from nothing
+        let number = |key: &str| map.get(key.as_bytes()).and_then(counter);
+        session.uploaded = number("uploaded").unwrap_or(0);
+        session.downloaded = number("downloaded").unwrap_or(0);
+        session.download_limit = number("download_limit");
+        session.upload_limit = number("upload_limit");
+        session.dir = Some(dir);
+        Ok(session)
+    }
+
+    /// Write the session to disk
+    pub fn save(&self) -> Result<(), SessionError> {
+        match &self.dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                // Write then rename, so a crash part way through doesn't lose every torrent
+                let tmp = dir.join("session.tmp");
+                fs::write(&tmp, self.to_value().encode())?;
+                fs::rename(tmp, dir.join("session"))?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The state directory, if the session is saved
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+
+    /// The torrents in queue order
+    pub fn torrents(&self) -> &[TorrentEntry] {
+        &self.torrents
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<&TorrentEntry> {
+        self.torrents.iter().find(|t| &t.info_hash == info_hash)
+    }
+
+    /// Add a torrent to the end of the queue, keeping a copy of its metainfo file.  Returns false
+    /// if the torrent is already in the session.
+    pub fn add(&mut self, info_hash: [u8; 20], name: &str, metainfo: &[u8], download_dir: &str)
+               -> Result<bool, SessionError> {
+        if self.get(&info_hash).is_some() {
+            return Ok(false);
+        }
+        let torrent_file = match &self.dir {
+            Some(dir) => {
+                let torrent_dir = dir.join("torrents");
+                fs::create_dir_all(&torrent_dir)?;
+                let path = torrent_dir.join(format!("{}.torrent", hex(&info_hash)));
+                fs::write(&path, metainfo)?;
+                path
+            }
+            None => PathBuf::new(),
+        };
+        self.torrents.push(TorrentEntry {
+            info_hash,
+            name: name.to_owned(),
+            torrent_file,
+            download_dir: download_dir.to_owned(),
+            uploaded: 0,
+            downloaded: 0,
+            seed_time: 0,
+            force_start: false,
+            file_priorities: Vec::new(),
+            paused: false,
+            bandwidth_priority: None,
+        });
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Forget a torrent.  Its downloaded files are left alone
+    pub fn remove(&mut self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        if let Some(i) = self.position(info_hash) {
+            let entry = self.torrents.remove(i);
+            if let Some(dir) = &self.dir {
+                if let Err(e) = fs::remove_file(&entry.torrent_file) {
+                    warn!("Could not remove {}: {}", entry.torrent_file.display(), e);
+                }
+                let _res = fs::remove_file(resume_path(dir, info_hash));
+            }
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Move a torrent to `position` in the queue.  Positions past the end move it to the end
+    pub fn move_to(&mut self, info_hash: &[u8; 20], position: usize) -> Result<(), SessionError> {
+        if let Some(i) = self.position(info_hash) {
+            let entry = self.torrents.remove(i);
+            let position = position.min(self.torrents.len());
+            self.torrents.insert(position, entry);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Move a torrent one place or all the way up or down the queue
+    pub fn move_torrent(&mut self, info_hash: &[u8; 20], direction: QueueMove) -> Result<(), SessionError> {
+        let i = match self.position(info_hash) {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+        let position = match direction {
+            QueueMove::Up => i.saturating_sub(1),
+            QueueMove::Down => i + 1,
+            QueueMove::Top => 0,
+            QueueMove::Bottom => self.torrents.len(),
+        };
+        self.move_to(info_hash, position)
+    }
+
+    /// Let a torrent run no matter how many others are active, or put it back in the queue
+    pub fn set_force_start(&mut self, info_hash: &[u8; 20], force_start: bool) -> Result<(), SessionError> {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.force_start = force_start;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remember whether a torrent is paused, so it comes back that way
+    pub fn set_paused(&mut self, info_hash: &[u8; 20], paused: bool) -> Result<(), SessionError> {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.paused = paused;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Remember how much each of a torrent's files is wanted
+    pub fn set_file_priorities(&mut self, info_hash: &[u8; 20], priorities: Vec<Priority>) -> Result<(), SessionError> {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.file_priorities = priorities;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn set_bandwidth_priority(&mut self, info_hash: &[u8; 20], priority: ratelimit::Priority)
+                                  -> Result<(), SessionError> {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.bandwidth_priority = Some(priority);
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The torrents that should be running, in queue order.  Force started torrents always run,
+    /// and so do paused ones and the `finished` ones, which only seed.  Of the rest, the first
+    /// `limit` in the queue run.  A limit of None runs everything.
+    pub fn active(&self, limit: Option<usize>, finished: &HashSet<[u8; 20]>) -> Vec<&TorrentEntry> {
+        let mut places = limit.unwrap_or(self.torrents.len());
+        self.torrents.iter()
+            .filter(|t| {
+                if t.force_start || t.paused || finished.contains(&t.info_hash) {
+                    return true;
+                }
+                if places == 0 {
+                    return false;
+                }
+                places -= 1;
+                true
+            })
+            .collect()
+    }
+
+    /// Add to a torrent's lifetime transfer stats.  The stats are saved the next time the
+    /// session is.
+    pub fn record_transfer(&mut self, info_hash: &[u8; 20], uploaded: u64, downloaded: u64) {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.uploaded += uploaded;
+            entry.downloaded += downloaded;
+        }
+        self.uploaded += uploaded;
+        self.downloaded += downloaded;
+    }
+
+    /// Add to the time a torrent has spent seeding.  Saved the next time the session is
+    pub fn record_seed_time(&mut self, info_hash: &[u8; 20], seconds: u64) {
+        if let Some(entry) = self.torrents.iter_mut().find(|t| &t.info_hash == info_hash) {
+            entry.seed_time += seconds;
+        }
+    }
+
+    /// Bytes uploaded and downloaded by every torrent over every run
+    pub fn totals(&self) -> (u64, u64) {
+        (self.uploaded, self.downloaded)
+    }
+
+    /// The download and upload limits last set
+    pub fn limits(&self) -> (Option<u64>, Option<u64>) {
+        (self.download_limit, self.upload_limit)
+    }
+
+    /// Remember the rate limits, so they are kept after a restart
+    pub fn set_limits(&mut self, download: Option<u64>, upload: Option<u64>) -> Result<(), SessionError> {
+        self.download_limit = download;
+        self.upload_limit = upload;
+        self.save()
+    }
+
+    fn position(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.torrents.iter().position(|t| &t.info_hash == info_hash)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut map = hashmap! {
+            Vec::from("torrents") => Value::List(self.torrents.iter().map(TorrentEntry::to_value).collect()),
+            Vec::from("uploaded") => Value::Integer(self.uploaded as i64),
+            Vec::from("downloaded") => Value::Integer(self.downloaded as i64),
+        };
+        if let Some(limit) = self.download_limit {
+            map.insert(Vec::from("download_limit"), Value::Integer(limit as i64));
+        }
+        if let Some(limit) = self.upload_limit {
+            map.insert(Vec::from("upload_limit"), Value::Integer(limit as i64));
+        }
+        Value::Dict(map)
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+/// Where the resume data of a torrent is kept in the state directory `dir`
+pub fn resume_path<P: AsRef<Path>>(dir: P, info_hash: &[u8; 20]) -> PathBuf {
+    dir.as_ref().join("resume").join(format!("{}.resume", hex(info_hash)))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Read a counter like a transfer total.  Files written before bencoded integers were 64 bits hold
+/// counters as strings of digits
+pub fn counter(val: &Value) -> Option<u64> {
+    match val {
+        Value::Integer(i) => u64::try_from(*i).ok(),
+        _ => val.bstring_utf8()?.parse().ok(),
+    }
+}
+
+/// Parse a 40 character hex string, like an info hash or piece hash
+pub fn unhex(s: &str) -> Option<[u8; 20]> {
+    if s.len() != 40 || !s.is_ascii() {
+        return None;
+    }
+    let mut res = [0; 20];
+    for (i, byte) in res.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(res)
+}
@@ -0,0 +1,146 @@
+use std::env;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::*;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    env::temp_dir().join(format!("boosttorrent2-test-{}-{}", name, now))
+}
+
+#[test]
+fn test_session_round_trip() {
+    let dir = temp_dir("session");
+    {
+        let mut session = Session::load(&dir).unwrap();
+        assert!(session.add([1; 20], "first", b"d4:infode", "downloads").unwrap());
+        assert!(session.add([2; 20], "second", b"d4:infode", ".").unwrap());
+        assert!(!session.add([1; 20], "first", b"d4:infode", "downloads").unwrap());
+        session.record_transfer(&[1; 20], 5_000_000_000, 10);
+        session.record_seed_time(&[1; 20], 90);
+        session.set_file_priorities(&[2; 20], vec![Priority::Skip, Priority::High]).unwrap();
+        session.set_paused(&[2; 20], true).unwrap();
+        session.set_bandwidth_priority(&[2; 20], ratelimit::Priority::High).unwrap();
+        session.set_limits(Some(50 * 1024), None).unwrap();
+        session.save().unwrap();
+    }
+
+    let session = Session::load(&dir).unwrap();
+    let first = session.torrents()[0].clone();
+    assert_eq!(session.torrents().len(), 2);
+    assert_eq!(first.name, "first");
+    assert_eq!(first.download_dir, "downloads");
+    assert_eq!(first.uploaded, 5_000_000_000);
+    assert_eq!(first.downloaded, 10);
+    assert_eq!(first.seed_time, 90);
+    assert_eq!(fs::read(&first.torrent_file).unwrap(), b"d4:infode".to_vec());
+    assert_eq!(first.file_priorities, vec![]);
+    assert_eq!(session.torrents()[1].file_priorities, vec![Priority::Skip, Priority::High]);
+    assert!(!first.paused);
+    assert!(session.torrents()[1].paused);
+    assert_eq!(first.bandwidth_priority, None);
+    assert_eq!(session.torrents()[1].bandwidth_priority, Some(ratelimit::Priority::High));
+    assert_eq!(session.totals(), (5_000_000_000, 10));
+    assert_eq!(session.limits(), (Some(50 * 1024), None));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_queue_order() {
+    let mut session = Session::new();
+    for i in 1..4 {
+        session.add([i; 20], "", b"", ".").unwrap();
+    }
+    session.move_to(&[3; 20], 0).unwrap();
+    session.move_to(&[1; 20], 10).unwrap();
+    session.remove(&[2; 20]).unwrap();
+    let order: Vec<_> = session.torrents().iter().map(|t| t.info_hash[0]).collect();
+    assert_eq!(order, vec![3, 1]);
+}
+
+#[test]
+fn test_hex_round_trip() {
+    let hash = [0xab; 20];
+    assert_eq!(unhex(&hex(&hash)), Some(hash));
+    assert_eq!(unhex("zz"), None);
+}
+
+#[test]
+fn test_queue_moves() {
+    let mut session = Session::new();
+    for i in 1..5 {
+        session.add([i; 20], "", b"", ".").unwrap();
+    }
+    session.move_torrent(&[4; 20], QueueMove::Top).unwrap();
+    session.move_torrent(&[1; 20], QueueMove::Down).unwrap();
+    session.move_torrent(&[3; 20], QueueMove::Up).unwrap();
+    session.move_torrent(&[4; 20], QueueMove::Bottom).unwrap();
+    let order: Vec<_> = session.torrents().iter().map(|t| t.info_hash[0]).collect();
+    assert_eq!(order, vec![2, 3, 1, 4]);
+}
+
+#[test]
+fn test_force_start_ignores_active_limit() {
+    let mut session = Session::new();
+    for i in 1..4 {
+        session.add([i; 20], "", b"", ".").unwrap();
+    }
+    session.set_force_start(&[3; 20], true).unwrap();
+    let active: Vec<_> = session.active(Some(1), &HashSet::new()).iter().map(|t| t.info_hash[0]).collect();
+    assert_eq!(active, vec![1, 3]);
+    assert_eq!(session.active(None, &HashSet::new()).len(), 3);
+}
+
+#[test]
+fn test_paused_and_finished_torrents_give_up_their_place() {
+    let mut session = Session::new();
+    for i in 1..5 {
+        session.add([i; 20], "", b"", ".").unwrap();
+    }
+    let active = |session: &Session, finished: &HashSet<[u8; 20]>| -> Vec<u8> {
+        session.active(Some(1), finished).iter().map(|t| t.info_hash[0]).collect()
+    };
+    assert_eq!(active(&session, &HashSet::new()), vec![1]);
+    session.set_paused(&[1; 20], true).unwrap();
+    assert_eq!(active(&session, &HashSet::new()), vec![1, 2]);
+    let finished = vec![[2; 20]].into_iter().collect();
+    assert_eq!(active(&session, &finished), vec![1, 2, 3]);
+    session.remove(&[3; 20]).unwrap();
+    assert_eq!(active(&session, &finished), vec![1, 2, 4]);
+}
+
+#[test]
+fn test_totals_outlive_removed_torrents() {
+    let dir = temp_dir("session-totals");
+    let mut session = Session::load(&dir).unwrap();
+    session.add([1; 20], "first", b"d4:infode", ".").unwrap();
+    session.record_transfer(&[1; 20], 100, 200);
+    let resume = resume_path(&dir, &[1; 20]);
+    fs::create_dir_all(resume.parent().unwrap()).unwrap();
+    fs::write(&resume, b"de").unwrap();
+    session.remove(&[1; 20]).unwrap();
+    assert!(!resume.exists());
+
+    let session = Session::load(&dir).unwrap();
+    assert!(session.torrents().is_empty());
+    assert_eq!(session.totals(), (100, 200));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_entry_reads_string_counters() {
+    // Sessions used to hold the counters as strings
+    let val = Value::Dict(hashmap! {
+        Vec::from("info_hash") => Value::BString(Vec::from(hex(&[1; 20]))),
+        Vec::from("torrent_file") => Value::BString(Vec::from("first.torrent")),
+        Vec::from("download_dir") => Value::BString(Vec::from(".")),
+        Vec::from("uploaded") => Value::BString(Vec::from("5000000000")),
+        Vec::from("downloaded") => Value::Integer(10),
+    });
+    let entry = TorrentEntry::from_value(&val).unwrap();
+    assert_eq!(entry.uploaded, 5_000_000_000);
+    assert_eq!(entry.downloaded, 10);
+    assert_eq!(entry.to_value().dict().unwrap().get("uploaded".as_bytes()), Some(&Value::Integer(5_000_000_000)));
+}
@@ -0,0 +1,53 @@
+//! shutdown turns SIGINT and SIGTERM into a future, so torrents can tell their trackers they are
+//! stopping before the process exits
+use futures::{
+    channel::oneshot,
+    future::Shared,
+    FutureExt,
+};
+use log::info;
+use std::io;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::thread;
+
+/// Resolves once the process is asked to stop.  Clones all resolve together
+pub type Shutdown = Shared<oneshot::Receiver<()>>;
+
+/// Start waiting for SIGINT and SIGTERM on a thread of their own.  Threads inherit the signals
+/// they block, so this has to be called before any other thread starts.  A second signal exits
+/// right away, in case the first one is stuck waiting on a tracker
+pub fn listen() -> io::Result<Shutdown> {
+    let signals = unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        let res = libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        if res != 0 {
+            return Err(io::Error::from_raw_os_error(res));
+        }
+        signals
+    };
+    let (sender, receiver) = oneshot::channel();
+    thread::Builder::new()
+        .name("signals".to_string())
+        .spawn(move || {
+            let mut sender = Some(sender);
+            loop {
+                let mut signal = 0;
+                if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+                    continue;
+                }
+                match sender.take() {
+                    Some(sender) => {
+                        info!("Stopping, signal again to quit right away");
+                        let _res = sender.send(());
+                    }
+                    None => process::exit(1),
+                }
+            }
+        })?;
+    Ok(receiver.shared())
+}
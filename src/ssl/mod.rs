@@ -0,0 +1,131 @@
+//! ssl implements SSL torrents (BEP 35).  These torrents carry a CA certificate in their info
+//! dictionary, and only peers holding a certificate signed by that CA may join the swarm.  Both
+//! ends of every connection have to prove who they are.
+use derive_error::Error;
+use openssl::error::ErrorStack;
+use futures::ready;
+use openssl::ssl::{
+    Ssl,
+    SslContext,
+    SslFiletype,
+    SslMethod,
+    SslVerifyMode,
+};
+use openssl::x509::X509;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{
+    Context,
+    Poll,
+};
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+    ReadBuf,
+};
+use tokio_openssl::SslStream;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, Error)]
+pub enum SslError {
+    /// OpenSSL rejected a certificate, key or connection
+    Openssl(ErrorStack),
+    /// The torrent's CA certificate could not be read
+    #[error(non_std, no_from)]
+    InvalidCaCertificate(String),
+}
+
+/// The certificate we show other peers in SSL torrents, and its private key.  Both are PEM files
+#[derive(Debug, PartialEq, Clone)]
+pub struct SslConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Makes SSL connections to the other members of one SSL torrent's swarm
+pub struct SwarmTls {
+    context: SslContext,
+    // Sent as the server name when we connect, so a peer running many torrents knows which one
+    // we want
+    server_name: String,
+}
+
+impl SwarmTls {
+    /// `ca_cert` is the PEM certificate from the torrent's info dictionary
+    pub fn new(ca_cert: &[u8], config: &SslConfig, info_hash: &[u8; 20]) -> Result<Self, SslError> {
+        let ca = X509::from_pem(ca_cert)
+            .map_err(|e| SslError::InvalidCaCertificate(e.to_string()))?;
+        let mut builder = SslContext::builder(SslMethod::tls())?;
+        // Only the torrent's CA is trusted, not the system's
+        builder.cert_store_mut().add_cert(ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        builder.set_certificate_file(&config.cert, SslFiletype::PEM)?;
+        builder.set_private_key_file(&config.key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        Ok(SwarmTls {
+            context: builder.build(),
+            server_name: info_hash.iter().map(|b| format!("{:02x}", b)).collect(),
+        })
+    }
+
+    /// Start an SSL handshake on a connection we opened
+    pub fn connect<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<Handshake<S>, SslError> {
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_hostname(&self.server_name)?;
+        ssl.set_connect_state();
+        Ok(Handshake(Some(SslStream::new(ssl, stream)?)))
+    }
+
+    /// Start an SSL handshake on a connection a peer opened
+    pub fn accept<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) -> Result<Handshake<S>, SslError> {
+        let mut ssl = Ssl::new(&self.context)?;
+        ssl.set_accept_state();
+        Ok(Handshake(Some(SslStream::new(ssl, stream)?)))
+    }
+}
+
+/// Resolves to the SSL stream once the handshake is done and the peer's certificate checks out
+pub struct Handshake<S>(Option<SslStream<S>>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = io::Result<TlsStream<S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stream = self.0.as_mut().expect("Handshake polled after completion");
+        let res = ready!(Pin::new(stream).poll_do_handshake(cx));
+        // The connection is dropped as soon as the handshake fails, so the peer hears about it
+        let stream = self.0.take().unwrap();
+        Poll::Ready(match res {
+            Ok(()) => Ok(TlsStream(stream)),
+            Err(e) => Err(e.into_io_error()
+                .unwrap_or_else(io::Error::other)),
+        })
+    }
+}
+
+/// An established SSL connection to a peer
+pub struct TlsStream<S>(SslStream<S>);
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
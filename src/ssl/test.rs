@@ -0,0 +1,110 @@
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{
+    EcGroup,
+    EcKey,
+};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{
+    PKey,
+    Private,
+};
+use openssl::x509::X509NameBuilder;
+use openssl::x509::extension::BasicConstraints;
+use futures::future;
+use std::env;
+use std::fs;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use super::*;
+use tokio::net::UnixStream;
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+/// Make a certificate for `key`, signed by `issuer`, or a self signed CA if there is no issuer
+fn cert(name: &str, key: &PKey<Private>, issuer: Option<(&X509, &PKey<Private>)>) -> X509 {
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    match issuer {
+        Some((issuer, _)) => builder.set_issuer_name(issuer.subject_name()).unwrap(),
+        None => builder.set_issuer_name(&subject).unwrap(),
+    }
+    if issuer.is_none() {
+        builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+    }
+    builder.set_pubkey(key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    let signer = issuer.map_or(key, |(_, key)| key);
+    builder.sign(signer, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+/// Write a peer's certificate and key to disk, since that's where SslConfig reads them from
+fn peer_config(name: &str, ca: (&X509, &PKey<Private>)) -> SslConfig {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-ssl-{}-{}", name, now));
+    fs::create_dir_all(&dir).unwrap();
+    let key = key();
+    let config = SslConfig {
+        cert: dir.join("cert.pem"),
+        key: dir.join("key.pem"),
+    };
+    fs::write(&config.cert, cert(name, &key, Some(ca)).to_pem().unwrap()).unwrap();
+    fs::write(&config.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    config
+}
+
+/// Run a handshake between two peers, returning whether each side accepted the other
+async fn handshake(server: SwarmTls, client: SwarmTls) -> (bool, bool) {
+    let (server_sock, client_sock) = UnixStream::pair().unwrap();
+    let (server, client) = future::join(server.accept(server_sock).unwrap(), client.connect(client_sock).unwrap())
+        .await;
+    (server.is_ok(), client.is_ok())
+}
+
+#[tokio::test]
+async fn test_swarm_members_connect() {
+    let ca_key = key();
+    let ca = cert("swarm ca", &ca_key, None);
+    let ca_pem = ca.to_pem().unwrap();
+    let server = SwarmTls::new(&ca_pem, &peer_config("server", (&ca, &ca_key)), &[1; 20]).unwrap();
+    let client = SwarmTls::new(&ca_pem, &peer_config("client", (&ca, &ca_key)), &[1; 20]).unwrap();
+    assert_eq!(handshake(server, client).await, (true, true));
+}
+
+#[tokio::test]
+async fn test_outsiders_are_refused() {
+    let ca_key = key();
+    let ca = cert("swarm ca", &ca_key, None);
+    let other_key = key();
+    let other = cert("other ca", &other_key, None);
+    let server = SwarmTls::new(&ca.to_pem().unwrap(), &peer_config("server", (&ca, &ca_key)), &[1; 20])
+        .unwrap();
+    // The outsider trusts the swarm's CA, but its own certificate comes from somewhere else
+    let outsider = SwarmTls::new(&ca.to_pem().unwrap(), &peer_config("outsider", (&other, &other_key)), &[1; 20])
+        .unwrap();
+    assert!(!handshake(server, outsider).await.0);
+}
+
+#[test]
+fn test_invalid_ca_certificate() {
+    let ca_key = key();
+    let ca = cert("swarm ca", &ca_key, None);
+    let config = peer_config("invalid", (&ca, &ca_key));
+    match SwarmTls::new(b"not a certificate", &config, &[1; 20]) {
+        Err(SslError::InvalidCaCertificate(_)) => (),
+        _ => panic!("Expected an invalid CA certificate error"),
+    }
+}
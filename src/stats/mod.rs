@@ -0,0 +1,259 @@
+//! stats measures how fast data moves to and from each peer, and adds it up for the torrent.
+//! Rates are averaged over the last 20 seconds, so they don't jump around with every block.
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use crate::reachability::PortStatus;
+use crate::seedlimit::share_ratio;
+
+#[cfg(test)]
+mod test;
+
+/// How far back rates are averaged over
+pub const RATE_WINDOW: Duration = Duration::from_secs(20);
+
+/// How often the server publishes a new snapshot
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+// One bucket for every second of the window
+const BUCKETS: usize = 20;
+
+/// Bytes moved over the last RATE_WINDOW, counted in one second buckets
+pub struct RollingRate {
+    buckets: [u64; BUCKETS],
+    start: Instant,
+    // The second, counted from start, that the newest bucket holds
+    newest: u64,
+}
+
+impl RollingRate {
+    pub fn new(now: Instant) -> Self {
+        RollingRate {
+            buckets: [0; BUCKETS],
+            start: now,
+            newest: 0,
+        }
+    }
+
+    /// Empty the buckets of seconds that fell out of the window
+    fn advance(&mut self, now: Instant) {
+        let second = now.duration_since(self.start).as_secs();
+        let stale = (second.saturating_sub(self.newest) as usize).min(BUCKETS);
+        for i in 1..=stale {
+            self.buckets[(self.newest as usize + i) % BUCKETS] = 0;
+        }
+        self.newest = self.newest.max(second);
+    }
+
+    pub fn add(&mut self, bytes: u64, now: Instant) {
+        self.advance(now);
+        self.buckets[self.newest as usize % BUCKETS] += bytes;
+    }
+
+    /// Bytes per second over the window.  Until a full window has passed, the average is over
+    /// the time since we started counting
+    pub fn rate(&mut self, now: Instant) -> u64 {
+        self.advance(now);
+        let elapsed = now.duration_since(self.start).min(RATE_WINDOW);
+        let millis = (elapsed.as_millis() as u64).max(1000);
+        self.buckets.iter().sum::<u64>() * 1000 / millis
+    }
+}
+
+/// What has moved to and from one peer
+struct Transfer {
+    uploaded: u64,
+    downloaded: u64,
+    upload: RollingRate,
+    download: RollingRate,
+}
+
+/// The numbers for one peer at the time of a snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerSnapshot {
+    pub id: usize,
+    pub address: Option<SocketAddr>,
+    // The client the peer runs, from its peer id.  Filled in by the server
+    pub client: Option<String>,
+    // Where the peer is, if we have GeoIP databases.  Filled in by the server
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Bytes per second
+    pub upload_rate: u64,
+    pub download_rate: u64,
+}
+
+/// The numbers for a torrent and its peers at one point in time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    // Totals for the torrent, including earlier runs
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Bytes per second, over every peer
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    // Bytes downloaded this run that were thrown away: blocks that arrived after we cancelled
+    // them, second copies from endgame, and pieces that failed their hash check
+    pub wasted: u64,
+    pub peers: Vec<PeerSnapshot>,
+    // Bytes in the torrent, and wanted bytes we don't have yet.  Filled in by the server
+    pub size: u64,
+    pub left: u64,
+    pub paused: bool,
+    // Blocks uploaded from the read cache, and blocks that had to be read from disk
+    pub read_cache_hits: u64,
+    pub read_cache_misses: u64,
+    // Upload buffers taken from the pool, and buffers allocated because the pool was empty
+    pub buffers_reused: u64,
+    pub buffers_allocated: u64,
+    // Files whose data doesn't match the MD5 sum in the metainfo
+    pub md5_mismatches: Vec<String>,
+    // Time spent seeding, over every run
+    pub seed_time: Duration,
+    // Whether peers outside our network can connect to us
+    pub port_status: PortStatus,
+}
+
+impl Snapshot {
+    /// How many times over we uploaded what we downloaded
+    pub fn ratio(&self) -> f64 {
+        share_ratio(self.uploaded, self.downloaded, self.size)
+    }
+}
+
+/// Counts the bytes moved for a torrent, by peer
+pub struct Stats {
+    uploaded: u64,
+    downloaded: u64,
+    upload: RollingRate,
+    download: RollingRate,
+    wasted: u64,
+    peers: HashMap<usize, Transfer>,
+}
+
+impl Stats {
+    /// `uploaded` and `downloaded` are the totals from earlier runs
+    pub fn new(uploaded: u64, downloaded: u64, now: Instant) -> Self {
+        Stats {
+            uploaded,
+            downloaded,
+            upload: RollingRate::new(now),
+            download: RollingRate::new(now),
+            wasted: 0,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn add_peer(&mut self, id: usize, now: Instant) {
+        self.peers.insert(id, Transfer {
+            uploaded: 0,
+            downloaded: 0,
+            upload: RollingRate::new(now),
+            download: RollingRate::new(now),
+        });
+    }
+
+    pub fn remove_peer(&mut self, id: usize) {
+        self.peers.remove(&id);
+    }
+
+    pub fn record_upload(&mut self, id: usize, bytes: u64, now: Instant) {
+        self.uploaded += bytes;
+        self.upload.add(bytes, now);
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.uploaded += bytes;
+            peer.upload.add(bytes, now);
+        }
+    }
+
+    pub fn record_download(&mut self, id: usize, bytes: u64, now: Instant) {
+        self.downloaded += bytes;
+        self.download.add(bytes, now);
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.downloaded += bytes;
+            peer.download.add(bytes, now);
+        }
+    }
+
+    /// Count downloaded bytes we had no use for.  They were already counted as downloaded
+    pub fn record_waste(&mut self, bytes: u64) {
+        self.wasted += bytes;
+    }
+
+    /// Bytes downloaded this run that were thrown away
+    pub fn wasted(&self) -> u64 {
+        self.wasted
+    }
+
+    /// Total bytes uploaded for the torrent
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// Total bytes downloaded for the torrent
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// The upload and download rate of every peer, for the choker
+    pub fn peer_rates(&mut self, now: Instant) -> Vec<(usize, u64, u64)> {
+        self.peers.iter_mut()
+            .map(|(id, peer)| (*id, peer.upload.rate(now), peer.download.rate(now)))
+            .collect()
+    }
+
+    /// Everything we know, with the peers' addresses filled in from `addresses`
+    pub fn snapshot(&mut self, addresses: &HashMap<usize, SocketAddr>, now: Instant) -> Snapshot {
+        let mut peers: Vec<PeerSnapshot> = self.peers.iter_mut()
+            .map(|(id, peer)| PeerSnapshot {
+                id: *id,
+                address: addresses.get(id).cloned(),
+                client: None,
+                country: None,
+                asn: None,
+                uploaded: peer.uploaded,
+                downloaded: peer.downloaded,
+                upload_rate: peer.upload.rate(now),
+                download_rate: peer.download.rate(now),
+            })
+            .collect();
+        peers.sort_by_key(|peer| peer.id);
+        Snapshot {
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            upload_rate: self.upload.rate(now),
+            download_rate: self.download.rate(now),
+            wasted: self.wasted,
+            peers,
+            ..Snapshot::default()
+        }
+    }
+}
+
+/// Lets something outside the server, such as a UI, read a torrent's latest snapshot
+#[derive(Clone, Default)]
+pub struct StatsHandle {
+    latest: Arc<Mutex<Snapshot>>,
+}
+
+impl StatsHandle {
+    pub fn latest(&self) -> Snapshot {
+        self.latest.lock().map(|latest| latest.clone()).unwrap_or_default()
+    }
+
+    pub fn publish(&self, snapshot: Snapshot) {
+        if let Ok(mut latest) = self.latest.lock() {
+            *latest = snapshot;
+        }
+    }
+}
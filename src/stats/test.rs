@@ -0,0 +1,58 @@
+use super::*;
+
+#[test]
+fn test_rolling_rate() {
+    let start = Instant::now();
+    let mut rate = RollingRate::new(start);
+    rate.add(10_000, start);
+    // Before a full window passes, the average is over the time so far
+    assert_eq!(rate.rate(start + Duration::from_secs(10)), 1_000);
+    rate.add(30_000, start + Duration::from_secs(15));
+    assert_eq!(rate.rate(start + Duration::from_secs(19)), 40_000 * 1000 / 19_000);
+    // The first 10KB falls out of the window
+    assert_eq!(rate.rate(start + Duration::from_secs(20)), 1_500);
+    assert_eq!(rate.rate(start + Duration::from_secs(100)), 0);
+}
+
+#[test]
+fn test_new_rates_arent_inflated() {
+    let start = Instant::now();
+    let mut rate = RollingRate::new(start);
+    rate.add(500, start);
+    assert_eq!(rate.rate(start + Duration::from_millis(10)), 500);
+}
+
+#[test]
+fn test_stats() {
+    let start = Instant::now();
+    let mut stats = Stats::new(100, 200, start);
+    stats.add_peer(1, start);
+    stats.add_peer(2, start);
+    stats.record_download(1, 4_000, start);
+    stats.record_upload(2, 2_000, start);
+    stats.remove_peer(2);
+    // Bytes from peers that left still count towards the torrent
+    stats.record_download(2, 1_000, start);
+    stats.record_waste(1_000);
+
+    let mut addresses = HashMap::new();
+    let address: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    addresses.insert(1, address);
+    let snapshot = stats.snapshot(&addresses, start + Duration::from_secs(2));
+    assert_eq!(snapshot.uploaded, 2_100);
+    assert_eq!(snapshot.downloaded, 5_200);
+    assert_eq!(snapshot.download_rate, 2_500);
+    assert_eq!(snapshot.wasted, 1_000);
+    assert_eq!(snapshot.peers, vec![PeerSnapshot {
+        id: 1,
+        address: Some(address),
+        client: None,
+        country: None,
+        asn: None,
+        uploaded: 0,
+        downloaded: 4_000,
+        upload_rate: 0,
+        download_rate: 2_000,
+    }]);
+    assert_eq!(stats.peer_rates(start + Duration::from_secs(2)), vec![(1, 0, 2_000)]);
+}
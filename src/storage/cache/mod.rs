@@ -0,0 +1,181 @@
+//! cache keeps pieces in memory to spare the disk.  The write cache holds checked pieces for a
+//! while before they are written, so pieces that are next to each other on disk go out in one
+//! write instead of many small ones.  Spinning disks spend most of their time seeking, so fewer,
+//! larger writes are much faster.  The read cache keeps the pieces peers asked for most recently,
+//! so a popular piece is read once instead of once for every peer that wants it.
+use crate::piece::Piece;
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// When cached pieces are written out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheConfig {
+    // Write everything once the cache holds more than this many bytes.  0 writes each piece as
+    // soon as it is checked
+    pub max_bytes: usize,
+    // Write everything once the oldest piece has waited this long
+    pub flush_interval: Duration,
+}
+
+/// Pieces that are next to each other on disk, to be written in one go
+pub struct Run {
+    pub offset: u64,
+    pub pieces: Vec<Piece>,
+}
+
+impl Run {
+    /// The blocks of every piece in the run, end to end
+    pub fn blocks(&self) -> Vec<&[u8]> {
+        self.pieces.iter().flat_map(Piece::blocks).collect()
+    }
+}
+
+pub struct WriteCache {
+    config: CacheConfig,
+    // Cached pieces by where they go on disk
+    pieces: BTreeMap<u64, Piece>,
+    bytes: usize,
+    // When the oldest cached piece came in
+    oldest: Option<Instant>,
+}
+
+impl WriteCache {
+    pub fn new(config: CacheConfig) -> Self {
+        WriteCache {
+            config,
+            pieces: BTreeMap::new(),
+            bytes: 0,
+            oldest: None,
+        }
+    }
+
+    /// Hold a checked piece that goes at `offset` on disk
+    pub fn insert(&mut self, offset: u64, piece: Piece, now: Instant) {
+        self.bytes += piece.size() as usize;
+        if let Some(old) = self.pieces.insert(offset, piece) {
+            self.bytes -= old.size() as usize;
+        }
+        self.oldest.get_or_insert(now);
+    }
+
+    /// Whether the cache is full or has held a piece long enough
+    pub fn should_flush(&self, now: Instant) -> bool {
+        !self.pieces.is_empty()
+            && (self.bytes > self.config.max_bytes
+                || self.oldest.is_some_and(|oldest| now.saturating_duration_since(oldest) >= self.config.flush_interval))
+    }
+
+    /// How long until the cache has to be flushed, or None if it is empty
+    pub fn time_until_flush(&self, now: Instant) -> Option<Duration> {
+        self.oldest.map(|oldest| (oldest + self.config.flush_interval).saturating_duration_since(now))
+    }
+
+    /// Take every cached piece, in order on disk, grouped into runs of adjacent pieces
+    pub fn drain(&mut self) -> Vec<Run> {
+        self.bytes = 0;
+        self.oldest = None;
+        let mut runs: Vec<Run> = Vec::new();
+        let mut end = None;
+        for (offset, piece) in std::mem::take(&mut self.pieces) {
+            let length = piece.size() as u64;
+            match runs.last_mut() {
+                Some(run) if end == Some(offset) => run.pieces.push(piece),
+                _ => runs.push(Run {
+                    offset,
+                    pieces: vec![piece],
+                }),
+            }
+            end = Some(offset + length);
+        }
+        runs
+    }
+}
+
+/// How often reads were answered from the read cache, shared with whoever wants to show it
+#[derive(Debug, Default)]
+pub struct ReadStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ReadStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Whole pieces read from disk, dropping the least recently used once over its size
+pub struct ReadCache {
+    max_bytes: usize,
+    // Each piece's data, and when it was last used
+    pieces: HashMap<u32, (Vec<u8>, u64)>,
+    bytes: usize,
+    // Counts up on every use, to order the pieces by when they were last used
+    clock: u64,
+}
+
+impl ReadCache {
+    pub fn new(max_bytes: usize) -> Self {
+        ReadCache {
+            max_bytes,
+            pieces: HashMap::new(),
+            bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// The data of a piece if it is cached, counting a hit or a miss in `stats`
+    pub fn get(&mut self, index: u32, stats: &ReadStats) -> Option<&[u8]> {
+        self.clock += 1;
+        match self.pieces.get_mut(&index) {
+            Some((data, last_used)) => {
+                stats.hits.fetch_add(1, Ordering::Relaxed);
+                *last_used = self.clock;
+                Some(data)
+            }
+            None => {
+                stats.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Keep a piece that was just read, making room for it if needed.  Pieces bigger than the
+    /// whole cache aren't kept
+    pub fn insert(&mut self, index: u32, data: Vec<u8>) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+        while self.bytes + data.len() > self.max_bytes {
+            let oldest = self.pieces.iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(index, _)| *index);
+            match oldest.and_then(|oldest| self.pieces.remove(&oldest)) {
+                Some((old, _)) => self.bytes -= old.len(),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.bytes += data.len();
+        if let Some((old, _)) = self.pieces.insert(index, (data, self.clock)) {
+            self.bytes -= old.len();
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use bytes::Bytes;
+use super::*;
+
+fn piece(index: u32, data: &[u8]) -> Piece {
+    let mut piece = Piece::new(index, data.len() as u32, [0; 20]);
+    piece.add_block(0, Bytes::copy_from_slice(data));
+    piece
+}
+
+fn config(max_bytes: usize) -> CacheConfig {
+    CacheConfig {
+        max_bytes,
+        flush_interval: Duration::from_secs(5),
+    }
+}
+
+#[test]
+fn test_write_through_flushes_every_piece() {
+    let mut cache = WriteCache::new(config(0));
+    let now = Instant::now();
+    assert!(!cache.should_flush(now));
+    cache.insert(0, piece(0, b"abcd"), now);
+    assert!(cache.should_flush(now));
+}
+
+#[test]
+fn test_flushes_when_full() {
+    let mut cache = WriteCache::new(config(8));
+    let now = Instant::now();
+    cache.insert(0, piece(0, b"abcd"), now);
+    cache.insert(4, piece(1, b"efgh"), now);
+    assert!(!cache.should_flush(now));
+    cache.insert(8, piece(2, b"ijkl"), now);
+    assert!(cache.should_flush(now));
+}
+
+#[test]
+fn test_flushes_after_interval() {
+    let mut cache = WriteCache::new(config(1024));
+    let now = Instant::now();
+    assert_eq!(cache.time_until_flush(now), None);
+    cache.insert(0, piece(0, b"abcd"), now);
+    // A later piece doesn't push the deadline back
+    cache.insert(8, piece(2, b"ijkl"), now + Duration::from_secs(3));
+    assert_eq!(cache.time_until_flush(now + Duration::from_secs(3)), Some(Duration::from_secs(2)));
+    assert!(!cache.should_flush(now + Duration::from_secs(3)));
+    assert!(cache.should_flush(now + Duration::from_secs(5)));
+}
+
+#[test]
+fn test_drain_coalesces_adjacent_pieces() {
+    let mut cache = WriteCache::new(config(1024));
+    let now = Instant::now();
+    cache.insert(8, piece(2, b"ij"), now);
+    cache.insert(0, piece(0, b"abcd"), now);
+    cache.insert(12, piece(3, b"mnop"), now);
+    cache.insert(4, piece(1, b"efgh"), now);
+    let runs = cache.drain();
+    assert!(!cache.should_flush(now + Duration::from_secs(60)));
+    assert_eq!(cache.time_until_flush(now), None);
+    let runs: Vec<(u64, Vec<u8>, Vec<u32>)> = runs.iter()
+        .map(|run| (run.offset, run.blocks().concat(), run.pieces.iter().map(Piece::index).collect()))
+        .collect();
+    // The short piece 2 leaves a gap before piece 3
+    assert_eq!(runs, vec![
+        (0, b"abcdefghij".to_vec(), vec![0, 1, 2]),
+        (12, b"mnop".to_vec(), vec![3]),
+    ]);
+}
+
+#[test]
+fn test_read_cache_counts_hits_and_misses() {
+    let mut cache = ReadCache::new(8);
+    let stats = ReadStats::default();
+    assert_eq!(cache.get(0, &stats), None);
+    cache.insert(0, b"abcd".to_vec());
+    assert_eq!(cache.get(0, &stats), Some(&b"abcd"[..]));
+    assert_eq!((stats.hits(), stats.misses()), (1, 1));
+}
+
+#[test]
+fn test_read_cache_drops_least_recently_used() {
+    let mut cache = ReadCache::new(8);
+    let stats = ReadStats::default();
+    cache.insert(0, b"abcd".to_vec());
+    cache.insert(1, b"efgh".to_vec());
+    // Piece 0 is used again, so piece 1 is the one to go
+    cache.get(0, &stats);
+    cache.insert(2, b"ijkl".to_vec());
+    assert!(cache.get(0, &stats).is_some());
+    assert!(cache.get(1, &stats).is_none());
+    assert!(cache.get(2, &stats).is_some());
+}
+
+#[test]
+fn test_read_cache_skips_pieces_too_big() {
+    let mut cache = ReadCache::new(2);
+    let stats = ReadStats::default();
+    cache.insert(0, b"abcd".to_vec());
+    assert!(cache.get(0, &stats).is_none());
+}
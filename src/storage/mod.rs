@@ -0,0 +1,464 @@
+//! storage maps the torrent's files onto the disk.  Files can be renamed, so the path of a file
+//! in the torrent and its path on disk don't have to match.  Files can also be downloaded into a
+//! separate directory and moved into place once they are complete.
+use crate::metainfo::FileInfo;
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::fs::{
+    self,
+    File,
+    OpenOptions,
+};
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+    Write,
+};
+use std::path::{
+    Component,
+    Path,
+    PathBuf,
+};
+use std::str::FromStr;
+
+pub mod cache;
+pub mod reader;
+pub mod verify;
+pub mod writer;
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileEntry {
+    // Path of the file inside the torrent, relative to the root directory
+    pub torrent_path: String,
+    // File size
+    pub length: u64,
+    // Where the file's data starts in the torrent's byte stream
+    pub offset: u64,
+}
+
+impl FileEntry {
+    /// Whether the file only pads the next file out to a piece boundary (BEP 47).  Padding is all
+    /// zeroes, so it is never stored.  Padding files are recognised by the names clients give them
+    pub fn is_padding(&self) -> bool {
+        self.torrent_path.starts_with(".pad/") || self.torrent_path.starts_with("_____padding_file_")
+    }
+}
+
+/// How files take up space on disk before their data is written
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Allocation {
+    /// Files are created at their full length without writing anything, so they only take up
+    /// space as pieces arrive.  Quick to start, but the files may end up fragmented
+    #[default]
+    Sparse,
+    /// All of a file's space is reserved up front, so it is laid out in one piece on disk.  Slow
+    /// to start where the filesystem has to write zeroes to reserve space
+    Full,
+    /// Files are created empty and grow as pieces are written
+    None,
+}
+
+impl FromStr for Allocation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(Allocation::Sparse),
+            "full-preallocate" => Ok(Allocation::Full),
+            "none" => Ok(Allocation::None),
+            _ => Err(format!("Invalid allocation: {}", s)),
+        }
+    }
+}
+
+/// Translates between torrent paths and byte offsets and where the data lives on disk
+#[derive(Debug, Clone)]
+pub struct FileMap {
+    // Directory the torrent is downloaded into
+    download_dir: PathBuf,
+    // The root directory of a multi file torrent.  None for single file torrents
+    root: Option<String>,
+    files: Vec<FileEntry>,
+    // New names for files, relative to the root directory, by file index
+    renames: HashMap<usize, String>,
+    // Files the user doesn't want, by file index.  They aren't created on disk
+    skipped: HashSet<usize>,
+    allocation: Allocation,
+    // Where files are kept until they are complete, and whether their names get a .part suffix
+    // there.  None if files are downloaded in place
+    incomplete_dir: Option<(PathBuf, bool)>,
+    // Files that have been moved out of the incomplete directory, by file index
+    completed: HashSet<usize>,
+}
+
+impl FileMap {
+    pub fn new<P: AsRef<Path>>(download_dir: P, file_info: &FileInfo) -> Self {
+        let root = match file_info {
+            FileInfo::Single(_) => None,
+            FileInfo::Multi(m) => Some(m.root_dir_name.clone()),
+        };
+        let mut offset = 0;
+        let files = file_info.files().into_iter().map(|file| {
+            let entry = FileEntry {
+                torrent_path: file.file_name.clone(),
+                length: file.length as u64,
+                offset,
+            };
+            offset += file.length as u64;
+            entry
+        }).collect();
+
+        FileMap {
+            download_dir: download_dir.as_ref().to_path_buf(),
+            root,
+            files,
+            renames: HashMap::new(),
+            skipped: HashSet::new(),
+            allocation: Allocation::default(),
+            incomplete_dir: None,
+            completed: HashSet::new(),
+        }
+    }
+
+    /// The directory the torrent is downloaded into
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// The new names given to files, by file index
+    pub fn renames(&self) -> &HashMap<usize, String> {
+        &self.renames
+    }
+
+    /// The name of the root directory, or None for single file torrents
+    pub fn root(&self) -> Option<&str> {
+        self.root.as_deref()
+    }
+
+    /// Where a file is stored on disk
+    pub fn disk_path(&self, index: usize) -> Option<PathBuf> {
+        if self.is_incomplete(index) {
+            self.incomplete_path(index)
+        } else {
+            self.final_path(index)
+        }
+    }
+
+    /// Where a file ends up once it is complete
+    fn final_path(&self, index: usize) -> Option<PathBuf> {
+        let file = self.files.get(index)?;
+        let name = self.renames.get(&index).unwrap_or(&file.torrent_path);
+        Some(self.root_dir().join(name))
+    }
+
+    /// Where a file is kept in the incomplete directory
+    fn incomplete_path(&self, index: usize) -> Option<PathBuf> {
+        let (dir, part_suffix) = self.incomplete_dir.as_ref()?;
+        let file = self.files.get(index)?;
+        let name = self.renames.get(&index).unwrap_or(&file.torrent_path);
+        let path = match &self.root {
+            Some(root) => dir.join(root).join(name),
+            None => dir.join(name),
+        };
+        if *part_suffix {
+            let mut path = path.into_os_string();
+            path.push(".part");
+            Some(path.into())
+        } else {
+            Some(path)
+        }
+    }
+
+    /// Download files into `dir`, and only move them into the download directory once they are
+    /// complete.  If `part_suffix` is set, files in `dir` have .part added to their names.  Files
+    /// already in the download directory but not in `dir` are taken to be complete
+    pub fn set_incomplete_dir<P: AsRef<Path>>(&mut self, dir: P, part_suffix: bool) {
+        self.incomplete_dir = Some((dir.as_ref().to_path_buf(), part_suffix));
+        self.completed = (0..self.files.len())
+            .filter(|i| !self.incomplete_path(*i).unwrap().exists() && self.final_path(*i).unwrap().exists())
+            .collect();
+    }
+
+    /// Whether a file still lives in the incomplete directory
+    pub fn is_incomplete(&self, index: usize) -> bool {
+        self.incomplete_dir.is_some() && index < self.files.len() && !self.completed.contains(&index)
+    }
+
+    /// Move a file whose pieces are all written from the incomplete directory into place
+    pub fn complete_file(&mut self, index: usize) -> io::Result<()> {
+        let from = match self.incomplete_path(index) {
+            Some(path) if self.is_incomplete(index) => path,
+            _ => return Ok(()),
+        };
+        let to = self.final_path(index).unwrap();
+        if self.is_stored(index) {
+            move_file(&from, &to)?;
+        }
+        self.completed.insert(index);
+        Ok(())
+    }
+
+    /// The directory file names are relative to
+    fn root_dir(&self) -> PathBuf {
+        match &self.root {
+            Some(root) => self.download_dir.join(root),
+            None => self.download_dir.clone(),
+        }
+    }
+
+    /// Give a file a new name, relative to the root directory.  If the file is already on disk
+    /// it is moved.
+    pub fn rename_file(&mut self, index: usize, name: &str) -> Result<(), String> {
+        check_relative_path(name)?;
+        let old_path = self.disk_path(index).ok_or(format!("No file with index {}", index))?;
+        let new_final_path = self.root_dir().join(name);
+        if (0..self.files.len()).any(|i| i != index && self.final_path(i).as_ref() == Some(&new_final_path)) {
+            return Err(format!("Another file is already named {}", name));
+        }
+
+        let previous = self.renames.insert(index, name.to_owned());
+        let new_path = self.disk_path(index).unwrap();
+        if let Err(e) = move_if_exists(&old_path, &new_path) {
+            // Undo the rename so the map still points at the data
+            match previous {
+                Some(previous) => self.renames.insert(index, previous),
+                None => self.renames.remove(&index),
+            };
+            return Err(format!("Could not move {:?} to {:?}: {}", old_path, new_path, e));
+        }
+        Ok(())
+    }
+
+    /// Give the root directory of a multi file torrent a new name.  If the directory is already on
+    /// disk it is moved.
+    pub fn rename_root(&mut self, name: &str) -> Result<(), String> {
+        check_relative_path(name)?;
+        if self.root.is_none() {
+            return Err("Single file torrents don't have a root directory".to_string());
+        }
+        let mut dirs = vec![&self.download_dir];
+        dirs.extend(self.incomplete_dir.as_ref().map(|(dir, _)| dir));
+        for (i, dir) in dirs.iter().enumerate() {
+            let old_root = dir.join(self.root.as_ref().unwrap());
+            let new_root = dir.join(name);
+            if let Err(e) = move_if_exists(&old_root, &new_root) {
+                // Put back the roots already moved, so the files stay together
+                for dir in &dirs[..i] {
+                    let _res = move_if_exists(&dir.join(name), &dir.join(self.root.as_ref().unwrap()));
+                }
+                return Err(format!("Could not move {:?} to {:?}: {}", old_root, new_root, e));
+            }
+        }
+        self.root = Some(name.to_owned());
+        Ok(())
+    }
+
+    /// Choose whether a file is downloaded.  Skipped files aren't created, and the parts of
+    /// pieces that fall in them are thrown away unless the file is already on disk
+    pub fn set_skipped(&mut self, index: usize, skipped: bool) {
+        if skipped {
+            self.skipped.insert(index);
+        } else {
+            self.skipped.remove(&index);
+        }
+    }
+
+    pub fn is_skipped(&self, index: usize) -> bool {
+        self.skipped.contains(&index)
+    }
+
+    /// Whether a file is never put on disk, because it is skipped or is padding
+    fn is_stored(&self, index: usize) -> bool {
+        !self.is_skipped(index) && !self.files[index].is_padding()
+    }
+
+    /// Splits a range of the torrent's byte stream into the parts that belong to each file.
+    /// Returns (file index, offset in the file, length) for each part.
+    pub fn spans(&self, offset: u64, length: u64) -> Vec<(usize, u64, u64)> {
+        let end = offset + length;
+        self.files.iter().enumerate()
+            .filter(|(_, file)| file.offset < end && file.offset + file.length > offset)
+            .map(|(i, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (i, start - file.offset, stop - start)
+            })
+            .collect()
+    }
+
+    /// Choose how `allocate` sets up each file
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+    }
+
+    /// Create every file, taking up space as the allocation says, so pieces can be written in any
+    /// order.  Files that already exist keep their data.  Skipped and padding files aren't created
+    pub fn allocate(&self) -> io::Result<()> {
+        for (i, file) in self.files.iter().enumerate() {
+            if !self.is_stored(i) {
+                continue;
+            }
+            let path = self.disk_path(i).unwrap();
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let handle = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+            let length = handle.metadata()?.len();
+            if length > file.length {
+                handle.set_len(file.length)?;
+            } else if length < file.length {
+                match self.allocation {
+                    Allocation::Sparse => handle.set_len(file.length)?,
+                    Allocation::Full => preallocate(&handle, length, file.length)?,
+                    Allocation::None => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write data that starts at `offset` in the torrent's byte stream into the files it belongs to
+    pub fn write(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.write_blocks(offset, &[data])
+    }
+
+    /// Write blocks that follow each other, starting at `offset`, without joining them first
+    pub fn write_blocks(&self, offset: u64, blocks: &[&[u8]]) -> io::Result<()> {
+        let total = blocks.iter().map(|block| block.len() as u64).sum();
+        // Where we are in the blocks: which one, and how far into it
+        let (mut block, mut within) = (0, 0);
+        for (index, file_offset, length) in self.spans(offset, total) {
+            let path = self.disk_path(index).unwrap();
+            // Pieces on the edge of a skipped file only go into it if it was kept from before
+            let mut handle = if self.is_stored(index) || (self.is_skipped(index) && path.exists()) {
+                let mut handle = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+                handle.seek(SeekFrom::Start(file_offset))?;
+                Some(handle)
+            } else {
+                None
+            };
+            let mut left = length as usize;
+            while left > 0 {
+                let part = &blocks[block][within..(within + left).min(blocks[block].len())];
+                if let Some(handle) = handle.as_mut() {
+                    handle.write_all(part)?;
+                }
+                left -= part.len();
+                within += part.len();
+                if within == blocks[block].len() {
+                    block += 1;
+                    within = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes starting at `offset` in the torrent's byte stream from the files they
+    /// belong to
+    pub fn read(&self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; length as usize];
+        self.read_into(offset, &mut data)?;
+        Ok(data)
+    }
+
+    /// Fill `data` with the bytes starting at `offset` in the torrent's byte stream
+    pub fn read_into(&self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let mut start = 0;
+        for (index, file_offset, length) in self.spans(offset, data.len() as u64) {
+            let part = &mut data[start..start + length as usize];
+            start += part.len();
+            if self.files[index].is_padding() {
+                part.iter_mut().for_each(|byte| *byte = 0);
+                continue;
+            }
+            let mut handle = OpenOptions::new().read(true).open(self.disk_path(index).unwrap())?;
+            handle.seek(SeekFrom::Start(file_offset))?;
+            handle.read_exact(part)?;
+        }
+        if start != data.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the end of the torrent"));
+        }
+        Ok(())
+    }
+}
+
+/// Makes sure a name can't be used to write outside of the download directory
+fn check_relative_path(name: &str) -> Result<(), String> {
+    let path = Path::new(name);
+    if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!("Invalid file name: {}", name));
+    }
+    Ok(())
+}
+
+fn move_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+}
+
+/// Move a file, copying it where it can't be renamed, such as onto another filesystem.  A copy is
+/// made under a temporary name first, so the file never shows up half written
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let mut copy = to.as_os_str().to_owned();
+    copy.push(".moving");
+    let copy = PathBuf::from(copy);
+    fs::copy(from, &copy)?;
+    fs::rename(&copy, to)?;
+    fs::remove_file(from)
+}
+
+/// Reserve the space from `start` to `end` of a file.  Where the filesystem can't reserve space
+/// without writing, zeroes are written instead
+#[cfg(target_os = "linux")]
+fn preallocate(handle: &File, start: u64, end: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Unlike most calls, posix_fallocate returns the error instead of setting errno
+    match unsafe { libc::posix_fallocate(handle.as_raw_fd(), start as libc::off_t, (end - start) as libc::off_t) } {
+        0 => Ok(()),
+        libc::EINVAL | libc::EOPNOTSUPP => write_zeroes(handle, start, end),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(handle: &File, start: u64, end: u64) -> io::Result<()> {
+    write_zeroes(handle, start, end)
+}
+
+/// Fill a file with zeroes from `start` to `end`
+fn write_zeroes(mut handle: &File, start: u64, end: u64) -> io::Result<()> {
+    const CHUNK: u64 = 1024 * 1024;
+
+    let zeroes = vec![0; CHUNK.min(end - start) as usize];
+    handle.seek(SeekFrom::Start(start))?;
+    let mut position = start;
+    while position < end {
+        let length = CHUNK.min(end - position) as usize;
+        handle.write_all(&zeroes[..length])?;
+        position += length as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! reader reads blocks that peers ask for from disk on its own thread, so uploading doesn't
+//! block the peers.  Whole pieces are read and kept in a read cache, since peers ask for a piece
+//! a block at a time and popular pieces are asked for by many peers.  Blocks are read into
+//! buffers from the torrent's pool, which the peers give back once the blocks are sent
+use bytes::{
+    Bytes,
+    BytesMut,
+};
+use crate::bufpool::BufferPool;
+use futures::{
+    channel::{
+        mpsc::{
+            channel,
+            Receiver,
+            Sender,
+        },
+        oneshot,
+    },
+    executor::block_on_stream,
+};
+use std::io;
+use std::sync::{
+    Arc,
+    Mutex,
+};
+use std::thread;
+use super::cache::{
+    ReadCache,
+    ReadStats,
+};
+use super::FileMap;
+
+#[cfg(test)]
+mod test;
+
+/// How many reads can wait before peers are held up
+const QUEUE_LENGTH: usize = 64;
+
+/// A block to read, and where to send it
+pub struct ReadRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+    pub reply: oneshot::Sender<io::Result<Bytes>>,
+}
+
+/// Start the reader thread.  Blocks asked for through the returned sender are read from `files`
+/// and sent back through each request's reply.  Up to `cache_bytes` of pieces are cached; 0 reads
+/// every block straight from disk.  The returned stats count how often the cache was used
+pub fn spawn(files: Arc<Mutex<FileMap>>, piece_length: u64, cache_bytes: usize, pool: BufferPool)
+             -> io::Result<(Sender<ReadRequest>, Arc<ReadStats>)> {
+    let (sender, receiver) = channel(QUEUE_LENGTH);
+    let stats = Arc::new(ReadStats::default());
+    let thread_stats = stats.clone();
+    thread::Builder::new()
+        .name("storage-reader".to_string())
+        .spawn(move || run(files, piece_length, cache_bytes, &pool, &thread_stats, receiver))?;
+    Ok((sender, stats))
+}
+
+/// Read blocks until every sender is gone
+fn run(files: Arc<Mutex<FileMap>>, piece_length: u64, cache_bytes: usize, pool: &BufferPool,
+       stats: &ReadStats, requests: Receiver<ReadRequest>) {
+    let total_length = {
+        let files = files.lock().unwrap();
+        files.files().last().map_or(0, |file| file.offset + file.length)
+    };
+    let mut cache = ReadCache::new(cache_bytes);
+    for request in block_on_stream(requests) {
+        let mut block = pool.get();
+        let result = if cache_bytes == 0 {
+            let offset = request.index as u64 * piece_length + request.begin as u64;
+            block.resize(request.length as usize, 0);
+            files.lock().unwrap().read_into(offset, &mut block)
+        } else {
+            read_cached(&files, &mut cache, stats, piece_length, total_length, &request, &mut block)
+        };
+        let result = result.map(|()| block.freeze());
+        // The peer may have gone while we were reading
+        let _res = request.reply.send(result);
+    }
+}
+
+/// Copy a block into `block` out of its piece in the cache, reading the whole piece into the
+/// cache first if it isn't there
+fn read_cached(files: &Mutex<FileMap>, cache: &mut ReadCache, stats: &ReadStats, piece_length: u64,
+               total_length: u64, request: &ReadRequest, block: &mut BytesMut) -> io::Result<()> {
+    let start = request.index as u64 * piece_length;
+    let length = piece_length.min(total_length.saturating_sub(start));
+    let (begin, end) = (request.begin as usize, request.begin as usize + request.length as usize);
+    if end as u64 > length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Read past the end of the piece"));
+    }
+    if let Some(piece) = cache.get(request.index, stats) {
+        block.extend_from_slice(&piece[begin..end]);
+        return Ok(());
+    }
+    let piece = files.lock().unwrap().read(start, length)?;
+    block.extend_from_slice(&piece[begin..end]);
+    cache.insert(request.index, piece);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use crate::metainfo::{
+    FileInfo,
+    MultiFile,
+    SingleFile,
+};
+use futures::{
+    executor::block_on,
+    SinkExt,
+};
+use std::env;
+use std::fs;
+use super::*;
+
+#[test]
+fn test_read_blocks() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-reader-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let files = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a", 6), file("b", 4)],
+    }));
+    files.allocate().unwrap();
+    files.write(0, b"abcdefghij").unwrap();
+    let (mut sender, _) = spawn(Arc::new(Mutex::new(files)), 4, 0, BufferPool::new(4)).unwrap();
+
+    let (reply, straddling) = oneshot::channel();
+    block_on(sender.send(ReadRequest { index: 1, begin: 1, length: 3, reply })).unwrap();
+    let (reply, past_end) = oneshot::channel();
+    block_on(sender.send(ReadRequest { index: 2, begin: 0, length: 4, reply })).unwrap();
+    drop(sender);
+    let straddling = block_on(straddling).unwrap();
+    let past_end = block_on(past_end).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    // Piece 1 starts at byte 4, and its block straddles both files
+    assert_eq!(&straddling.unwrap()[..], b"fgh");
+    // Only two bytes of the block exist, so it can't be read
+    assert!(past_end.is_err());
+}
+
+#[test]
+fn test_read_blocks_cached() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-reader-cached-{}", std::process::id()));
+    let files = FileMap::new(&dir, &FileInfo::Single(SingleFile {
+        file_name: "a".to_string(),
+        length: 10,
+        md5sum: None,
+    }));
+    files.allocate().unwrap();
+    files.write(0, b"abcdefghij").unwrap();
+    let (mut sender, stats) = spawn(Arc::new(Mutex::new(files)), 4, 1024, BufferPool::new(4)).unwrap();
+
+    let mut read = |index, begin, length| {
+        let (reply, block) = oneshot::channel();
+        block_on(sender.send(ReadRequest { index, begin, length, reply })).unwrap();
+        block_on(block).unwrap()
+    };
+    let first = read(1, 0, 2);
+    let second = read(1, 2, 2);
+    let last = read(2, 0, 2);
+    let past_end = read(2, 0, 4);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(&first.unwrap()[..], b"ef");
+    assert_eq!(&second.unwrap()[..], b"gh");
+    // The last piece is shorter than the others
+    assert_eq!(&last.unwrap()[..], b"ij");
+    assert!(past_end.is_err());
+    // The second block came out of the piece read for the first
+    assert_eq!((stats.hits(), stats.misses()), (1, 2));
+}
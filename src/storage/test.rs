@@ -0,0 +1,171 @@
+use crate::metainfo::{
+    MultiFile,
+    SingleFile,
+};
+use std::env;
+use super::*;
+
+fn file_map<P: AsRef<Path>>(download_dir: P) -> FileMap {
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    FileMap::new(download_dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a.mp3", 100), file("b.mp3", 50), file("c.mp3", 200)],
+    }))
+}
+
+#[test]
+fn test_spans() {
+    let map = file_map("downloads");
+    assert_eq!(map.spans(0, 10), vec![(0, 0, 10)]);
+    assert_eq!(map.spans(90, 100), vec![(0, 90, 10), (1, 0, 50), (2, 0, 40)]);
+    assert_eq!(map.spans(340, 100), vec![(2, 190, 10)]);
+}
+
+#[test]
+fn test_rename() {
+    let mut map = file_map("downloads");
+    map.rename_file(1, "bonus/b.mp3").unwrap();
+    map.rename_root("Album (2018)").unwrap();
+    assert_eq!(map.disk_path(0), Some(PathBuf::from("downloads/Album (2018)/a.mp3")));
+    assert_eq!(map.disk_path(1), Some(PathBuf::from("downloads/Album (2018)/bonus/b.mp3")));
+}
+
+#[test]
+fn test_rename_rejects_escaping_paths() {
+    let mut map = file_map("downloads");
+    assert!(map.rename_file(0, "../a.mp3").is_err());
+    assert!(map.rename_file(0, "/etc/passwd").is_err());
+    assert!(map.rename_file(0, "c.mp3").is_err());
+    assert!(map.rename_root("").is_err());
+}
+
+#[test]
+fn test_rename_moves_data() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-rename-{}", std::process::id()));
+    let mut map = file_map(&dir);
+    let old_path = map.disk_path(0).unwrap();
+    fs::create_dir_all(old_path.parent().unwrap()).unwrap();
+    fs::write(&old_path, b"data").unwrap();
+
+    map.rename_file(0, "renamed.mp3").unwrap();
+    let contents = fs::read(map.disk_path(0).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(contents.unwrap(), b"data");
+}
+
+#[test]
+fn test_allocate_and_write() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-write-{}", std::process::id()));
+    let map = file_map(&dir);
+    map.allocate().unwrap();
+    map.write(95, &[1; 10]).unwrap();
+    let a = fs::read(map.disk_path(0).unwrap()).unwrap();
+    let b = fs::read(map.disk_path(1).unwrap()).unwrap();
+    let c_len = fs::metadata(map.disk_path(2).unwrap()).unwrap().len();
+    let read = map.read(93, 14).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(a.len(), 100);
+    assert_eq!(&a[95..], &[1; 5]);
+    assert_eq!(&b[..6], &[1, 1, 1, 1, 1, 0]);
+    assert_eq!(c_len, 200);
+    assert_eq!(read, vec![0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0]);
+}
+
+#[test]
+fn test_write_blocks() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-blocks-{}", std::process::id()));
+    let map = file_map(&dir);
+    map.allocate().unwrap();
+    // The middle block straddles the end of the first file
+    map.write_blocks(95, &[&[1; 3], &[2; 4], &[3; 3]]).unwrap();
+    let read = map.read(95, 10);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(read.unwrap(), vec![1, 1, 1, 2, 2, 2, 2, 3, 3, 3]);
+}
+
+#[test]
+fn test_skipped_and_padding_files() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-skip-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let mut map = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a.mp3", 10), file(".pad/6", 6), file("b.mp3", 16)],
+    }));
+    map.set_skipped(2, true);
+    map.allocate().unwrap();
+    let pad_exists = map.disk_path(1).unwrap().exists();
+    let b_exists = map.disk_path(2).unwrap().exists();
+    map.write(0, &[1; 32]).unwrap();
+    let b_written = map.disk_path(2).unwrap().exists();
+    let read = map.read(8, 8).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(map.files()[1].is_padding());
+    assert!(!pad_exists);
+    assert!(!b_exists);
+    assert!(!b_written);
+    assert_eq!(read, vec![1, 1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_allocation_from_str() {
+    assert_eq!("sparse".parse(), Ok(Allocation::Sparse));
+    assert_eq!("full-preallocate".parse(), Ok(Allocation::Full));
+    assert_eq!("none".parse(), Ok(Allocation::None));
+    assert!("fast".parse::<Allocation>().is_err());
+}
+
+#[test]
+fn test_allocation() {
+    let lengths = |allocation, name: &str| {
+        let dir = env::temp_dir().join(format!("boosttorrent2-test-{}-{}", name, std::process::id()));
+        let mut map = file_map(&dir);
+        map.set_allocation(allocation);
+        map.allocate().unwrap();
+        // Writing past the end of a file that isn't allocated grows it
+        map.write(95, &[1; 10]).unwrap();
+        let lengths: Vec<u64> = (0..3)
+            .map(|i| fs::metadata(map.disk_path(i).unwrap()).unwrap().len())
+            .collect();
+        let read = map.read(95, 10).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read, vec![1; 10]);
+        lengths
+    };
+    assert_eq!(lengths(Allocation::Sparse, "sparse"), vec![100, 50, 200]);
+    assert_eq!(lengths(Allocation::Full, "full"), vec![100, 50, 200]);
+    assert_eq!(lengths(Allocation::None, "none"), vec![100, 5, 0]);
+}
+
+#[test]
+fn test_incomplete_dir() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-incomplete-{}", std::process::id()));
+    let mut map = file_map(dir.join("done"));
+    map.set_incomplete_dir(dir.join("incomplete"), true);
+    assert_eq!(map.disk_path(1), Some(dir.join("incomplete/album/b.mp3.part")));
+    map.allocate().unwrap();
+    map.write(100, &[1; 50]).unwrap();
+    map.rename_root("Album (2018)").unwrap();
+    map.complete_file(1).unwrap();
+    let moved = fs::read(dir.join("done/Album (2018)/b.mp3"));
+    let part_left = dir.join("incomplete/Album (2018)/b.mp3.part").exists();
+    let a_in_place = map.disk_path(0) == Some(dir.join("incomplete/Album (2018)/a.mp3.part"));
+    // A later run finds the moved file where it was moved to
+    let mut later = file_map(dir.join("done"));
+    later.rename_root("Album (2018)").unwrap();
+    later.set_incomplete_dir(dir.join("incomplete"), true);
+    let later_incomplete: Vec<bool> = (0..3).map(|i| later.is_incomplete(i)).collect();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(moved.unwrap(), vec![1; 50]);
+    assert!(!part_left);
+    assert!(a_in_place);
+    assert_eq!(map.disk_path(1), Some(dir.join("done/Album (2018)/b.mp3")));
+    assert_eq!(later_incomplete, vec![true, false, true]);
+}
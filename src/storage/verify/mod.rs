@@ -0,0 +1,91 @@
+//! verify hashes the data already on disk, for when the resume data is missing or can't be
+//! trusted.  Pieces are read and hashed in parallel on the hashing pool.  Whole files can also be
+//! checked against the MD5 sums some torrents carry.
+use crate::hasher::HashPool;
+use crate::metainfo::sha1_hash;
+use bit_vec::BitVec;
+use crypto::digest::Digest;
+use crypto::md5::Md5;
+use std::io;
+use std::sync::{
+    mpsc::channel,
+    Arc,
+};
+use super::FileMap;
+
+#[cfg(test)]
+mod test;
+
+/// What a recheck found
+#[derive(Debug, PartialEq, Clone)]
+pub struct Report {
+    // The pieces whose data matches their hash
+    pub have: BitVec,
+    // Pieces with data that doesn't match.  Pieces that can't be read or are all zeroes were
+    // never downloaded, so they aren't counted as corrupt
+    pub corrupt: Vec<u32>,
+}
+
+/// Hash every piece of the torrent mapped by `files`, one job per piece on `pool`.  Must not be
+/// called from one of the pool's threads
+pub fn verify(files: &FileMap, piece_length: u64, hashes: &[[u8; 20]], pool: &HashPool) -> Report {
+    let size = files.files().iter().map(|file| file.length).sum::<u64>();
+    let files = Arc::new(files.clone());
+    let (sender, results) = channel();
+    for (index, hash) in hashes.iter().enumerate() {
+        let files = files.clone();
+        let sender = sender.clone();
+        let hash = *hash;
+        pool.execute(move || {
+            let offset = index as u64 * piece_length;
+            let length = piece_length.min(size.saturating_sub(offset));
+            let _res = sender.send((index, check(&files, offset, length, &hash)));
+        });
+    }
+    drop(sender);
+
+    let mut report = Report {
+        have: BitVec::from_elem(hashes.len(), false),
+        corrupt: Vec::new(),
+    };
+    // A job only goes missing if reading panics, which leaves its piece unverified
+    for (index, checked) in results {
+        match checked {
+            Check::Good => report.have.set(index, true),
+            Check::Corrupt => report.corrupt.push(index as u32),
+            Check::Missing => (),
+        }
+    }
+    report.corrupt.sort();
+    report
+}
+
+enum Check {
+    Good,
+    Corrupt,
+    Missing,
+}
+
+fn check(files: &FileMap, offset: u64, length: u64, hash: &[u8; 20]) -> Check {
+    match files.read(offset, length) {
+        Ok(data) if sha1_hash(&data) == *hash => Check::Good,
+        Ok(ref data) if data.iter().all(|byte| *byte == 0) => Check::Missing,
+        Ok(_) => Check::Corrupt,
+        Err(_) => Check::Missing,
+    }
+}
+
+/// The MD5 sum of one of the torrent's files, in hex like the md5sum of the metainfo
+pub fn file_md5(files: &FileMap, index: usize) -> io::Result<String> {
+    const CHUNK: u64 = 1024 * 1024;
+
+    let file = &files.files()[index];
+    let mut md5 = Md5::new();
+    let mut read = 0;
+    while read < file.length {
+        let length = CHUNK.min(file.length - read);
+        md5.input(&files.read(file.offset + read, length)?);
+        read += length;
+    }
+    Ok(md5.result_str())
+}
@@ -0,0 +1,60 @@
+use crate::hasher::HashPool;
+use crate::metainfo::{
+    FileInfo,
+    MultiFile,
+    SingleFile,
+};
+use std::env;
+use std::fs;
+use super::*;
+
+#[test]
+fn test_verify() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-verify-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let files = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a", 6), file("b", 8), file("c", 2)],
+    }));
+    let data = b"abcdefghijklmnop";
+    let hashes: Vec<[u8; 20]> = data.chunks(4).map(sha1_hash).collect();
+    files.allocate().unwrap();
+    // Piece 0 is good, 1 is corrupt and spans a and b, 2 was never written, and c is gone
+    files.write(0, b"abcdef").unwrap();
+    files.write(6, b"XX").unwrap();
+    fs::remove_file(files.disk_path(2).unwrap()).unwrap();
+
+    let report = verify(&files, 4, &hashes, &HashPool::new(3).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+    let have: Vec<bool> = report.have.iter().collect();
+    assert_eq!(have, vec![true, false, false, false]);
+    assert_eq!(report.corrupt, vec![1]);
+}
+
+#[test]
+fn test_file_md5() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-md5-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let files = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a", 14), file("b", 2)],
+    }));
+    files.allocate().unwrap();
+    files.write(0, b"abcdefghijklmnop").unwrap();
+    let a = file_md5(&files, 0);
+    let b = file_md5(&files, 1);
+    fs::remove_file(files.disk_path(1).unwrap()).unwrap();
+    let gone = file_md5(&files, 1);
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(a.unwrap(), "0845a5972cd9ad4a46bad66f1253581f");
+    assert_eq!(b.unwrap(), "11d8c28a64490a987612f2332502467f");
+    assert!(gone.is_err());
+}
@@ -0,0 +1,158 @@
+//! writer checks finished pieces on the hashing pool and writes them to disk on its own thread, so
+//! neither hashing nor slow disks hold up the peers.  Checked pieces can wait in a write cache, so
+//! pieces next to each other on disk are written together
+use crate::hasher::HashPool;
+use crate::piece::Piece;
+use derive_error::Error;
+use futures::{
+    channel::mpsc::{
+        channel,
+        Receiver,
+        Sender,
+    },
+    executor::{
+        block_on,
+        block_on_stream,
+    },
+    SinkExt,
+};
+use log::debug;
+use std::io;
+use std::sync::{
+    mpsc::{
+        self,
+        RecvTimeoutError,
+    },
+    Arc,
+    Mutex,
+};
+use std::thread;
+use std::time::Instant;
+use super::cache::{
+    CacheConfig,
+    WriteCache,
+};
+use super::FileMap;
+
+#[cfg(test)]
+mod test;
+
+/// How many finished pieces can wait to be written before peers are held up
+const QUEUE_LENGTH: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    /// The piece could not be written
+    Io(io::Error),
+    /// The piece's data doesn't match its hash
+    HashMismatch,
+}
+
+/// What happened to a piece sent to the writer
+#[derive(Debug)]
+pub struct Written {
+    pub index: u32,
+    pub result: Result<(), StorageError>,
+}
+
+/// A piece being hashed on the pool, and whether it matched once it is done
+type Hashing = mpsc::Receiver<(Piece, bool)>;
+
+/// Start the writer threads.  Pieces sent to the returned sender are checked on `pool` and written
+/// to `files` as `cache` allows, and the outcome for each comes out of the returned receiver once
+/// it is on disk.  With a write-through cache the outcomes come out in the order the pieces were
+/// sent.  The files are created at their full length before anything is written.
+pub fn spawn(files: Arc<Mutex<FileMap>>,
+             piece_length: u64,
+             pool: &HashPool,
+             cache: CacheConfig) -> io::Result<(Sender<Piece>, Receiver<Written>)> {
+    files.lock().unwrap().allocate()?;
+    let (piece_sender, piece_receiver) = channel(QUEUE_LENGTH);
+    let (written_sender, written_receiver) = channel(QUEUE_LENGTH);
+    let (hashing_sender, hashing_receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    let pool = pool.clone();
+    thread::Builder::new()
+        .name("storage".to_string())
+        .spawn(move || hash(&pool, piece_receiver, hashing_sender))?;
+    thread::Builder::new()
+        .name("storage-writer".to_string())
+        .spawn(move || run(files, piece_length, hashing_receiver, written_sender, cache))?;
+    Ok((piece_sender, written_receiver))
+}
+
+/// Hand pieces to the pool until every sender is gone, or the writer has stopped
+fn hash(pool: &HashPool, pieces: Receiver<Piece>, hashing: mpsc::SyncSender<Hashing>) {
+    for mut piece in block_on_stream(pieces) {
+        let (done, result) = mpsc::channel();
+        // Queue the result before the job so the writer keeps the pieces in order
+        if hashing.send(result).is_err() {
+            break;
+        }
+        pool.execute(move || {
+            let matches = piece.verify();
+            let _res = done.send((piece, matches));
+        });
+    }
+}
+
+/// Write hashed pieces until the hashing thread stops, or nobody is listening for the results.
+/// Whatever is cached when the hashing thread stops is written before returning
+fn run(files: Arc<Mutex<FileMap>>,
+       piece_length: u64,
+       hashing: mpsc::Receiver<Hashing>,
+       mut written: Sender<Written>,
+       cache: CacheConfig) {
+    let mut cache = WriteCache::new(cache);
+    loop {
+        let next = match cache.time_until_flush(Instant::now()) {
+            Some(wait) => hashing.recv_timeout(wait),
+            None => hashing.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let stopping = match next {
+            Ok(hashed) => match hashed.recv() {
+                Ok((piece, true)) => {
+                    cache.insert(piece.index() as u64 * piece_length, piece, Instant::now());
+                    false
+                }
+                Ok((piece, false)) => {
+                    let index = piece.index();
+                    drop(piece);
+                    if block_on(written.send(Written { index, result: Err(StorageError::HashMismatch) })).is_err() {
+                        break;
+                    }
+                    false
+                }
+                Err(_) => true,
+            },
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        if (stopping || cache.should_flush(Instant::now())) && !flush(&files, &mut cache, &mut written) {
+            break;
+        }
+        if stopping {
+            break;
+        }
+    }
+}
+
+/// Write out everything in the cache and send the results.  Returns false if nobody is listening
+fn flush(files: &Mutex<FileMap>, cache: &mut WriteCache, written: &mut Sender<Written>) -> bool {
+    for run in cache.drain() {
+        let result = files.lock().unwrap().write_blocks(run.offset, &run.blocks());
+        debug!("Wrote {} pieces at {}: {:?}", run.pieces.len(), run.offset, result);
+        // The pieces' memory goes back to the budget once they are written
+        let indices: Vec<u32> = run.pieces.iter().map(Piece::index).collect();
+        drop(run);
+        for index in indices {
+            let result = match &result {
+                Ok(()) => Ok(()),
+                Err(e) => Err(StorageError::Io(io::Error::new(e.kind(), e.to_string()))),
+            };
+            if block_on(written.send(Written { index, result })).is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,96 @@
+use bytes::Bytes;
+use crate::hasher::HashPool;
+use crate::metainfo::{
+    FileInfo,
+    MultiFile,
+    SingleFile,
+};
+use crypto::{
+    digest::Digest,
+    sha1::Sha1,
+};
+use std::env;
+use std::fs;
+use std::time::Duration;
+use super::*;
+
+fn hash(data: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(data);
+    let mut hash = [0; 20];
+    hasher.result(&mut hash);
+    hash
+}
+
+#[test]
+fn test_write_pieces() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-writer-{}", std::process::id()));
+    let file = |name: &str, length| SingleFile {
+        file_name: name.to_string(),
+        length,
+        md5sum: None,
+    };
+    let files = FileMap::new(&dir, &FileInfo::Multi(MultiFile {
+        root_dir_name: "album".to_string(),
+        files: vec![file("a", 6), file("b", 4)],
+    }));
+    let (mut sender, receiver) = spawn(Arc::new(Mutex::new(files)), 4, &HashPool::new(2).unwrap(),
+                                       CacheConfig { max_bytes: 0, flush_interval: Duration::from_secs(0) }).unwrap();
+    // The files are full length before any piece arrives
+    assert_eq!(fs::metadata(dir.join("album/a")).unwrap().len(), 6);
+
+    let mut good = Piece::new(1, 4, hash(b"efgh"));
+    good.add_block(0, Bytes::from(&b"efgh"[..]));
+    let mut bad = Piece::new(0, 4, hash(b"abcd"));
+    bad.add_block(0, Bytes::from(&b"xxxx"[..]));
+    block_on(sender.send(good)).unwrap();
+    block_on(sender.send(bad)).unwrap();
+    drop(sender);
+
+    let results: Vec<_> = block_on_stream(receiver).collect();
+    let a = fs::read(dir.join("album/a")).unwrap();
+    let b = fs::read(dir.join("album/b")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(results.len(), 2);
+    assert_eq!((results[0].index, results[0].result.is_ok()), (1, true));
+    match results[1].result {
+        Err(StorageError::HashMismatch) => (),
+        ref other => panic!("Expected a hash mismatch, got {:?}", other),
+    }
+    // Piece 1 straddles both files
+    assert_eq!(a, b"\0\0\0\0ef");
+    assert_eq!(b, b"gh\0\0");
+}
+
+#[test]
+fn test_cached_pieces_written_when_stopping() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-writer-cache-{}", std::process::id()));
+    let files = FileMap::new(&dir, &FileInfo::Single(SingleFile {
+        file_name: "a".to_string(),
+        length: 10,
+        md5sum: None,
+    }));
+    let cache = CacheConfig {
+        max_bytes: 1024,
+        flush_interval: Duration::from_secs(60),
+    };
+    let (mut sender, receiver) = spawn(Arc::new(Mutex::new(files)), 4, &HashPool::new(2).unwrap(), cache).unwrap();
+    for (index, data) in [(2, &b"ij"[..]), (0, &b"abcd"[..]), (1, &b"efgh"[..])] {
+        let mut piece = Piece::new(index, data.len() as u32, hash(data));
+        piece.add_block(0, Bytes::from(data));
+        block_on(sender.send(piece)).unwrap();
+    }
+    // Nothing is written until the cache is flushed, which happens when the pieces stop coming
+    drop(sender);
+    let mut results: Vec<u32> = block_on_stream(receiver)
+        .inspect(|written| assert!(written.result.is_ok()))
+        .map(|written| written.index)
+        .collect();
+    let a = fs::read(dir.join("a")).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    results.sort();
+    assert_eq!(results, vec![0, 1, 2]);
+    assert_eq!(a, b"abcdefghij");
+}
@@ -0,0 +1,47 @@
+//! superseed spreads a torrent from its first seed as quickly as possible, as described in BEP 16.
+//! Instead of showing peers every piece, each peer is offered one piece at a time, the piece
+//! offered least so far, and is only offered another once it has that one.  Peers then get the
+//! rest from each other, so the seed uploads each piece about once.
+use crate::piecefield::PieceField;
+use bit_vec::BitVec;
+use std::collections::HashMap;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug)]
+pub struct SuperSeed {
+    // The piece on offer to each peer
+    offers: HashMap<usize, u32>,
+    // How many times each piece has been offered
+    offered: Vec<u32>,
+}
+
+impl SuperSeed {
+    pub fn new(num_pieces: usize) -> Self {
+        SuperSeed {
+            offers: HashMap::new(),
+            offered: vec![0; num_pieces],
+        }
+    }
+
+    /// The pieces a peer has changed.  If it has nothing on offer, or now has the piece it was
+    /// offered, returns the next piece to offer it: the one of ours offered least that it lacks
+    pub fn update(&mut self, peer: usize, peers_pieces: &PieceField, have: &BitVec) -> Option<u32> {
+        if let Some(offer) = self.offers.get(&peer) {
+            if !peers_pieces.has(*offer) {
+                return None;
+            }
+        }
+        let next = (0..self.offered.len())
+            .filter(|index| have.get(*index).unwrap_or(false) && !peers_pieces.has(*index as u32))
+            .min_by_key(|index| self.offered[*index])?;
+        self.offered[next] += 1;
+        self.offers.insert(peer, next as u32);
+        Some(next as u32)
+    }
+
+    pub fn remove_peer(&mut self, peer: usize) {
+        self.offers.remove(&peer);
+    }
+}
@@ -0,0 +1,46 @@
+use super::*;
+
+#[test]
+fn test_offers_least_offered_piece() {
+    let mut super_seed = SuperSeed::new(3);
+    let have = BitVec::from_elem(3, true);
+    let nothing = PieceField::new(3);
+    assert_eq!(super_seed.update(0, &nothing, &have), Some(0));
+    assert_eq!(super_seed.update(1, &nothing, &have), Some(1));
+    assert_eq!(super_seed.update(2, &nothing, &have), Some(2));
+    assert_eq!(super_seed.update(3, &nothing, &have), Some(0));
+}
+
+#[test]
+fn test_next_offer_once_peer_has_piece() {
+    let mut super_seed = SuperSeed::new(3);
+    let have = BitVec::from_elem(3, true);
+    let mut pieces = PieceField::new(3);
+    assert_eq!(super_seed.update(0, &pieces, &have), Some(0));
+    // Still downloading the first one
+    assert_eq!(super_seed.update(0, &pieces, &have), None);
+    pieces.set(0).unwrap();
+    assert_eq!(super_seed.update(0, &pieces, &have), Some(1));
+}
+
+#[test]
+fn test_skips_pieces_peer_or_we_lack() {
+    let mut super_seed = SuperSeed::new(3);
+    let mut have = BitVec::from_elem(3, true);
+    have.set(1, false);
+    let mut pieces = PieceField::new(3);
+    pieces.set(0).unwrap();
+    assert_eq!(super_seed.update(0, &pieces, &have), Some(2));
+    pieces.set(2).unwrap();
+    assert_eq!(super_seed.update(0, &pieces, &have), None);
+}
+
+#[test]
+fn test_remove_peer_starts_over() {
+    let mut super_seed = SuperSeed::new(2);
+    let have = BitVec::from_elem(2, true);
+    let nothing = PieceField::new(2);
+    assert_eq!(super_seed.update(0, &nothing, &have), Some(0));
+    super_seed.remove_peer(0);
+    assert_eq!(super_seed.update(0, &nothing, &have), Some(1));
+}
@@ -0,0 +1,102 @@
+//! Failure accounting for trackers.  A tracker that fails is retried after a delay that doubles
+//! with each failure in a row, and after enough failures it is marked down so we move on to the
+//! next tracker.  Delays are jittered so clients that lost the tracker at the same time don't all
+//! come back to it at the same moment.
+use rand::Rng;
+use std::collections::HashMap;
+use std::time::{
+    Duration,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How long to wait before the first retry of a tracker
+pub const BASE_DELAY: Duration = Duration::from_secs(15);
+
+/// The longest we will wait between retries of a tracker
+pub const MAX_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// How many failures in a row before a tracker is marked down
+pub const MAX_FAILURES: u32 = 4;
+
+/// How long a tracker stays down before we try it again
+pub const DOWN_TIME: Duration = Duration::from_secs(60 * 60);
+
+/// Delays are moved by up to this fraction either way
+const JITTER: f64 = 0.25;
+
+/// What to do after a tracker failed
+#[derive(Debug, PartialEq)]
+pub enum Retry {
+    /// Try the same tracker again after this long
+    After(Duration),
+    /// The tracker has failed too often, so move on to the next one
+    Down,
+}
+
+#[derive(Debug, Default)]
+struct Failures {
+    // How many announces in a row have failed
+    count: u32,
+    // When the tracker was marked down, if it is
+    down_since: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct Backoff {
+    failures: HashMap<String, Failures>,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff::default()
+    }
+
+    /// Count a failed announce to a tracker and work out what to do next
+    pub fn failed(&mut self, url: &str, now: Instant) -> Retry {
+        let failures = self.failures.entry(url.to_owned()).or_default();
+        failures.count += 1;
+        if failures.count >= MAX_FAILURES {
+            failures.down_since = Some(now);
+            Retry::Down
+        } else {
+            Retry::After(jitter(delay(failures.count)))
+        }
+    }
+
+    /// The tracker answered, so forget its failures
+    pub fn succeeded(&mut self, url: &str) {
+        self.failures.remove(url);
+    }
+
+    /// Whether the tracker is marked down and shouldn't be tried yet
+    pub fn is_down(&self, url: &str, now: Instant) -> bool {
+        self.failures.get(url)
+            .and_then(|failures| failures.down_since)
+            .is_some_and(|since| now.saturating_duration_since(since) < DOWN_TIME)
+    }
+
+    /// How many announces in a row to the tracker have failed
+    pub fn failures(&self, url: &str) -> u32 {
+        self.failures.get(url).map_or(0, |failures| failures.count)
+    }
+
+    /// Bring every tracker back up, for when all of them are down and we have to start over
+    pub fn reset(&mut self) {
+        self.failures.clear();
+    }
+}
+
+/// The delay before retrying after this many failures in a row, without jitter
+pub fn delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (BASE_DELAY * 2u32.pow(doublings)).min(MAX_DELAY)
+}
+
+/// Move a delay a random amount either way, by up to a quarter of it
+pub fn jitter(delay: Duration) -> Duration {
+    let factor = rand::thread_rng().gen_range(1.0 - JITTER, 1.0 + JITTER);
+    delay.mul_f64(factor)
+}
@@ -0,0 +1,65 @@
+use super::*;
+
+const URL: &str = "http://tracker";
+
+#[test]
+fn test_delay_doubles_up_to_max() {
+    assert_eq!(delay(1), BASE_DELAY);
+    assert_eq!(delay(2), BASE_DELAY * 2);
+    assert_eq!(delay(3), BASE_DELAY * 4);
+    assert_eq!(delay(100), MAX_DELAY);
+}
+
+#[test]
+fn test_jitter_stays_in_range() {
+    let base = Duration::from_secs(100);
+    for _ in 0..100 {
+        let jittered = jitter(base);
+        assert!(jittered >= Duration::from_secs(75));
+        assert!(jittered <= Duration::from_secs(125));
+    }
+}
+
+#[test]
+fn test_failed_retries_then_marks_down() {
+    let mut backoff = Backoff::new();
+    let now = Instant::now();
+    for failures in 1..MAX_FAILURES {
+        match backoff.failed(URL, now) {
+            Retry::After(wait) => assert!(wait <= delay(failures).mul_f64(1.25)),
+            Retry::Down => panic!("marked down after {} failures", failures),
+        }
+        assert!(!backoff.is_down(URL, now));
+    }
+    assert_eq!(backoff.failed(URL, now), Retry::Down);
+    assert!(backoff.is_down(URL, now));
+    assert!(!backoff.is_down("http://other", now));
+    assert!(!backoff.is_down(URL, now + DOWN_TIME));
+}
+
+#[test]
+fn test_succeeded_resets() {
+    let mut backoff = Backoff::new();
+    let now = Instant::now();
+    backoff.failed(URL, now);
+    backoff.failed(URL, now);
+    assert_eq!(backoff.failures(URL), 2);
+    backoff.succeeded(URL);
+    assert_eq!(backoff.failures(URL), 0);
+    match backoff.failed(URL, now) {
+        Retry::After(wait) => assert!(wait <= BASE_DELAY.mul_f64(1.25)),
+        Retry::Down => panic!("marked down after a reset"),
+    }
+}
+
+#[test]
+fn test_reset_brings_trackers_up() {
+    let mut backoff = Backoff::new();
+    let now = Instant::now();
+    for _ in 0..MAX_FAILURES {
+        backoff.failed(URL, now);
+    }
+    assert!(backoff.is_down(URL, now));
+    backoff.reset();
+    assert!(!backoff.is_down(URL, now));
+}
@@ -0,0 +1,64 @@
+//! cache keeps the last answer from each tracker, so switching back to a tracker or resuming a
+//! torrent doesn't announce before the tracker's min interval.  Strict trackers ban clients that
+//! do.  Until the next announce is allowed, the cached peers stand in for a fresh answer
+use std::collections::HashMap;
+use std::time::{
+    Duration,
+    Instant,
+};
+use super::{
+    PeerInfo,
+    TrackerSuccessResponse,
+};
+
+#[cfg(test)]
+mod test;
+
+/// A tracker's last successful answer, and when it came
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedResponse {
+    pub received: Instant,
+    pub response: TrackerSuccessResponse,
+}
+
+impl CachedResponse {
+    /// The peers, while the tracker would still give us the same ones.  After the interval the
+    /// tracker asked for, they are too old to be worth trying
+    pub fn fresh_peers(&self, now: Instant) -> Option<&[PeerInfo]> {
+        let age = now.saturating_duration_since(self.received);
+        if age < Duration::from_secs(self.response.interval as u64) {
+            Some(&self.response.peers)
+        } else {
+            None
+        }
+    }
+}
+
+/// The last answer from each tracker, by announce url
+#[derive(Default)]
+pub struct ResponseCache {
+    responses: HashMap<String, CachedResponse>,
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        ResponseCache::default()
+    }
+
+    /// Keep an answer from `url`, replacing the one before it
+    pub fn insert(&mut self, url: &str, response: &TrackerSuccessResponse, now: Instant) {
+        self.responses.insert(url.to_owned(), CachedResponse {
+            received: now,
+            response: response.clone(),
+        });
+    }
+
+    pub fn get(&self, url: &str) -> Option<&CachedResponse> {
+        self.responses.get(url)
+    }
+
+    /// Forget a tracker, such as one taken out of the tracker list
+    pub fn remove(&mut self, url: &str) {
+        self.responses.remove(url);
+    }
+}
@@ -0,0 +1,42 @@
+use super::*;
+
+fn response(interval: u32, peers: Vec<PeerInfo>) -> TrackerSuccessResponse {
+    TrackerSuccessResponse {
+        interval,
+        min_interval: Some(60),
+        tracker_id: None,
+        complete: 1,
+        incomplete: 2,
+        peers,
+        external_ip: None,
+    }
+}
+
+#[test]
+fn test_insert_replaces() {
+    let now = Instant::now();
+    let mut cache = ResponseCache::new();
+    cache.insert("http://a/announce", &response(1800, Vec::new()), now);
+    cache.insert("http://a/announce", &response(900, Vec::new()), now + Duration::from_secs(5));
+    let cached = cache.get("http://a/announce").unwrap();
+    assert_eq!(cached.response.interval, 900);
+    assert_eq!(cached.received, now + Duration::from_secs(5));
+    assert!(cache.get("http://b/announce").is_none());
+    cache.remove("http://a/announce");
+    assert!(cache.get("http://a/announce").is_none());
+}
+
+#[test]
+fn test_fresh_peers() {
+    let now = Instant::now();
+    let peer = PeerInfo {
+        peer_id: None,
+        address: "10.0.0.1:6881".parse().unwrap(),
+    };
+    let mut cache = ResponseCache::new();
+    cache.insert("http://a/announce", &response(1800, vec![peer]), now);
+    let cached = cache.get("http://a/announce").unwrap();
+    assert_eq!(cached.fresh_peers(now + Duration::from_secs(60)), Some(&[peer][..]));
+    // Once the tracker would have told us about new peers, the old ones are stale
+    assert_eq!(cached.fresh_peers(now + Duration::from_secs(1800)), None);
+}
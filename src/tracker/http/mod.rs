@@ -0,0 +1,248 @@
+//! Announcing to trackers over HTTP and HTTPS (BEP 3), optionally through I2P or a SOCKS5 proxy.
+//! How https trackers' certificates are checked is up to the user, since private trackers with
+//! their own CA or broken certificates are common
+use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::boostencode::stream::{
+    Decoder,
+    Limits,
+};
+use crate::i2p::{
+    SamConnector,
+    SamSession,
+};
+use crate::socks::{
+    ProxyConfig,
+    SocksConnector,
+};
+use futures::FutureExt;
+use hyper::{
+    body::HttpBody,
+    Body,
+    Client,
+    client::{
+        HttpConnector,
+        connect::Connection,
+    },
+    Response,
+    service::Service,
+    StatusCode,
+    Uri,
+};
+use hyper_tls::HttpsConnector;
+use log::trace;
+use maplit::hashmap;
+use native_tls::{
+    Certificate,
+    TlsConnector,
+};
+use percent_encoding::{
+    percent_encode,
+    QUERY_ENCODE_SET,
+};
+use super::{
+    Announce,
+    Announcer,
+    BoxedFuture,
+    ScrapeInfo,
+    TrackerError,
+    TrackerResponse,
+};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use tokio::io::{
+    AsyncRead,
+    AsyncWrite,
+};
+
+#[cfg(test)]
+mod test;
+
+/// How to check the certificates of https trackers
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    // A certificate authority to trust on top of the system's, for trackers with their own
+    pub ca: Option<Certificate>,
+    // Accept any certificate.  Only for trackers with broken certificates, since anyone between
+    // us and the tracker could then read our passkey
+    pub insecure: bool,
+    // Leave the tracker's name out of the handshake, for servers that fail when it is sent
+    pub no_sni: bool,
+}
+
+impl TlsOptions {
+    fn connector(&self) -> Result<TlsConnector, native_tls::Error> {
+        let mut builder = TlsConnector::builder();
+        if let Some(ca) = &self.ca {
+            builder.add_root_certificate(ca.clone());
+        }
+        builder.danger_accept_invalid_certs(self.insecure);
+        builder.use_sni(!self.no_sni);
+        builder.build()
+    }
+}
+
+/// Read a PEM certificate, for TlsOptions::ca
+pub fn read_certificate(path: &Path) -> Result<Certificate, String> {
+    let pem = fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    Certificate::from_pem(&pem).map_err(|e| format!("{} is not a PEM certificate: {}", path.display(), e))
+}
+
+pub struct HttpAnnouncer {
+    // The announce url of the tracker
+    uri: String,
+    // If set, requests go through this I2P session
+    i2p: Option<SamSession>,
+    // If set, requests go through this SOCKS5 proxy
+    proxy: Option<ProxyConfig>,
+    tls: TlsOptions,
+}
+
+impl HttpAnnouncer {
+    pub fn new(uri: String, i2p: Option<SamSession>, proxy: Option<ProxyConfig>, tls: TlsOptions) -> Self {
+        HttpAnnouncer {
+            uri,
+            i2p,
+            proxy,
+            tls,
+        }
+    }
+
+    /// The url of an announce, with the query string filled in
+    fn announce_uri(&self, announce: &Announce) -> String {
+        // build the tracker query string
+        let mut req_uri = self.uri.clone();
+        let encoded_info_hash = percent_encode(&announce.info_hash, QUERY_ENCODE_SET).to_string();
+        let encoded_peer_id = percent_encode(&announce.peer_id, QUERY_ENCODE_SET).to_string();
+        let query = hashmap! {
+            "info_hash" => encoded_info_hash,
+            "peer_id" => encoded_peer_id,
+            "port" => announce.port.to_string(),
+            "uploaded" => announce.uploaded.to_string(),
+            "downloaded" => announce.downloaded.to_string(),
+            "left" => announce.left.to_string(),
+            "compact" => 1.to_string(),
+            "no_peer_id" => 1.to_string(),
+            "key" => format!("{:08x}", announce.key),
+            "numwant" => announce.numwant.to_string(),
+        };
+        req_uri.push('?');
+        query.iter().fold(&mut req_uri, |s, (k, v)| {
+            s.push_str(k);
+            s.push('=');
+            s.push_str(&v);
+            s.push('&');
+            s
+        });
+        match announce.event {
+            Some(e) => {
+                req_uri.push_str("event");
+                req_uri.push('=');
+                req_uri.push_str(&e.to_string());
+                req_uri.push('&')
+            }
+            None => ()
+        }
+        if let Some(ipv6) = announce.ipv6 {
+            req_uri.push_str("ipv6=");
+            req_uri.push_str(&ipv6.to_string());
+            req_uri.push('&');
+        }
+        match &announce.tracker_id {
+            Some(id) => {
+                req_uri.push_str("trackerid");
+                req_uri.push('=');
+                req_uri.push_str(&percent_encode(id.as_bytes(), QUERY_ENCODE_SET).to_string());
+                req_uri.push('&')
+            }
+            None => ()
+        }
+        let _ = req_uri.pop();
+        req_uri
+    }
+}
+
+impl Announcer for HttpAnnouncer {
+    fn announce(&self, announce: &Announce) -> BoxedFuture<TrackerResponse> {
+        let req_uri = self.announce_uri(announce);
+        let response = get(req_uri, self.i2p.clone(), self.proxy.clone(), self.tls.clone());
+        async move {
+            let val = response.await?;
+            trace!("response: {:?}", val);
+            TrackerResponse::from_value(&val)
+                .map_err(|_| TrackerError::InvalidResponse)
+        }.boxed()
+    }
+
+    fn scrape(&self, info_hash: [u8; 20]) -> BoxedFuture<ScrapeInfo> {
+        let req_uri = scrape_uri(&self.uri).map(|uri| {
+            let separator = if uri.contains('?') { '&' } else { '?' };
+            format!("{}{}info_hash={}", uri, separator, percent_encode(&info_hash, QUERY_ENCODE_SET))
+        });
+        let i2p = self.i2p.clone();
+        let proxy = self.proxy.clone();
+        let tls = self.tls.clone();
+        async move {
+            let req_uri = req_uri.ok_or(TrackerError::ScrapeUnsupported)?;
+            let val = get(req_uri, i2p, proxy, tls).await?;
+            val.dict()
+                .and_then(|map| map.get("files".as_bytes()))
+                .and_then(Value::dict)
+                .and_then(|files| files.get(&info_hash[..]))
+                .ok_or(TrackerError::InvalidResponse)
+                .and_then(|info| ScrapeInfo::from_value(info).map_err(|_| TrackerError::InvalidResponse))
+        }.boxed()
+    }
+}
+
+/// Work out a tracker's scrape url from its announce url, as described in BEP 48.  Only trackers
+/// whose announce url ends in a path segment starting with "announce" can be scraped
+pub fn scrape_uri(announce: &str) -> Option<String> {
+    let (path, query) = match announce.find('?') {
+        Some(i) => (&announce[..i], &announce[i..]),
+        None => (announce, ""),
+    };
+    let last_slash = path.rfind('/')?;
+    let last = &path[last_slash + 1..];
+    last.strip_prefix("announce").map(|rest| format!("{}/scrape{}{}", &path[..last_slash], rest, query))
+}
+
+/// Send a GET request through `connector`, in TLS for https urls
+async fn request<C>(connector: C, tls: &TlsOptions, uri: Uri) -> Result<Response<Body>, TrackerError>
+    where C: Service<Uri> + Clone + Send + Sync + 'static,
+          C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+          C::Future: Send + Unpin + 'static,
+          C::Error: Into<Box<dyn Error + Send + Sync>> {
+    let tls = tls.connector().map_err(TrackerError::TlsError)?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::from((connector, tls.into())));
+    client.get(uri).await.map_err(TrackerError::ConnectionError)
+}
+
+/// Make a GET request to a tracker and bdecode the response
+async fn get(req_uri: String, i2p: Option<SamSession>, proxy: Option<ProxyConfig>, tls: TlsOptions)
+             -> Result<Value, TrackerError> {
+    let uri: Uri = req_uri.parse().map_err(TrackerError::InvalidURI)?;
+    let response = match (i2p, proxy) {
+        (Some(session), _) => request(SamConnector(session), &tls, uri).await?,
+        (None, Some(proxy)) => request(SocksConnector(proxy), &tls, uri).await?,
+        (None, None) => {
+            let mut http = HttpConnector::new();
+            // https urls are let through for the TLS connector to wrap
+            http.enforce_http(false);
+            request(http, &tls, uri).await?
+        }
+    };
+    if response.status() != StatusCode::OK {
+        return Err(TrackerError::ResponseError(response.status().as_u16()));
+    }
+    // Decode the response as it arrives, so a tracker can't make us buffer an endless body
+    let mut body = response.into_body();
+    let mut decoder = Decoder::new(Limits::default());
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(TrackerError::ConnectionError)?;
+        if let Some(value) = decoder.feed(&chunk).map_err(TrackerError::DecodeError)? {
+            return Ok(value);
+        }
+    }
+    Err(TrackerError::DecodeError(DecodeError::InvalidValue))
+}
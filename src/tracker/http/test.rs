@@ -0,0 +1,84 @@
+use openssl::asn1::Asn1Time;
+use openssl::ec::{
+    EcGroup,
+    EcKey,
+};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::{
+    X509,
+    X509NameBuilder,
+};
+use std::env;
+use super::*;
+use super::super::Event;
+
+#[test]
+fn test_announce_uri() {
+    let announcer = HttpAnnouncer::new("http://localhost:8888/announce".to_owned(), None, None, TlsOptions::default());
+    let mut announce = Announce {
+        info_hash: [0; 20],
+        peer_id: [0; 20],
+        port: 8888,
+        uploaded: 0,
+        downloaded: 0,
+        left: 1000,
+        event: Some(Event::Started),
+        key: 0xbeef,
+        numwant: 80,
+        tracker_id: Some("an id".to_owned()),
+        ipv6: None,
+    };
+    let uri = announcer.announce_uri(&announce);
+    assert!(uri.starts_with("http://localhost:8888/announce?"));
+    let query: Vec<&str> = uri.split('?').nth(1).unwrap().split('&').collect();
+    for param in &["compact=1", "no_peer_id=1", "key=0000beef", "numwant=80", "event=started",
+                   "trackerid=an%20id", "left=1000", "port=8888"] {
+        assert!(query.contains(param), "{} is missing from {}", param, uri);
+    }
+    announce.event = None;
+    announce.ipv6 = Some("2001::1".parse().unwrap());
+    let uri = announcer.announce_uri(&announce);
+    assert!(!uri.contains("event="));
+    assert!(uri.split('&').any(|param| param == "ipv6=2001::1"));
+}
+
+/// A self signed certificate, in PEM form
+fn ca_pem() -> Vec<u8> {
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap())
+        .unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "tracker ca").unwrap();
+    let name = name.build();
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    builder.build().to_pem().unwrap()
+}
+
+#[test]
+fn test_tls_options() {
+    let dir = env::temp_dir().join(format!("boosttorrent2-test-tracker-ca-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("ca.pem"), ca_pem()).unwrap();
+    fs::write(dir.join("garbage.pem"), b"not a certificate").unwrap();
+    let ca = read_certificate(&dir.join("ca.pem"));
+    let garbage = read_certificate(&dir.join("garbage.pem"));
+    let missing = read_certificate(&dir.join("missing.pem"));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(garbage.is_err());
+    assert!(missing.is_err());
+    let options = TlsOptions {
+        ca: Some(ca.unwrap()),
+        insecure: true,
+        no_sni: true,
+    };
+    assert!(options.connector().is_ok());
+}
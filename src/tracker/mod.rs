@@ -1,35 +1,93 @@
+//! Announcing to trackers.  Tracker keeps what announces to one tracker share, such as its
+//! intervals and tracker id, and hands each request to the Announcer for the url's protocol
 use crate::boostencode::{DecodeError, FromValue, Value};
+use crate::i2p::SamSession;
+use crate::socks::ProxyConfig;
+pub use crate::types::PeerInfo;
 use hyper;
-use hyper::{
-    Client,
-    http::uri::InvalidUri,
-    StatusCode,
+use hyper::http::uri::InvalidUri;
+use self::cache::CachedResponse;
+use self::http::{
+    HttpAnnouncer,
+    TlsOptions,
 };
-use log::trace;
-use maplit::hashmap;
-use percent_encoding::{
-    percent_encode,
-    QUERY_ENCODE_SET,
+pub use self::http::scrape_uri;
+use futures::{
+    future::{
+        self,
+        BoxFuture,
+    },
+    ready,
+    FutureExt,
 };
+use self::udp::UdpAnnouncer;
 use std::fmt;
+use std::future::Future;
+use std::io;
 use std::net::{
     IpAddr,
-    SocketAddr,
+    Ipv6Addr,
 };
-use tokio::prelude::{
-    Async,
-    Future,
-    future::{
-        empty,
-        err,
-        ok,
-    },
-    Stream,
+use std::pin::Pin;
+use std::task::{
+    Context,
+    Poll,
+};
+use std::time::{
+    Duration,
+    Instant,
 };
+use tokio::time;
 
 #[cfg(test)]
 mod test;
+pub mod backoff;
+pub mod cache;
+pub mod http;
+pub mod passkey;
+pub mod tiers;
+pub mod udp;
+
+/// How long to wait between announces the user asks for, if the tracker doesn't say
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait between regular announces, if the tracker doesn't say
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How many peers to ask for in each announce, unless told otherwise
+pub const DEFAULT_NUMWANT: u32 = 50;
+
+type BoxedFuture<T> = BoxFuture<'static, Result<T, TrackerError>>;
+
+/// A tracker protocol.  Each request is independent, so an announcer keeps no state between them
+pub trait Announcer: Send {
+    /// Send an announce, and get the tracker's answer
+    fn announce(&self, announce: &Announce) -> BoxedFuture<TrackerResponse>;
+
+    /// Ask how many seeds and leechers a torrent has, without joining its swarm
+    fn scrape(&self, info_hash: [u8; 20]) -> BoxedFuture<ScrapeInfo>;
+}
 
+/// Everything we tell a tracker in an announce
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    // The port we will be listening on for peer connections
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<Event>,
+    // A random number that lets the tracker know it is still us if our address changes
+    pub key: u32,
+    // How many peers to ask for
+    pub numwant: u32,
+    // A string the tracker asked us to send on subsequent announcements
+    pub tracker_id: Option<String>,
+    // Our routable IPv6 address, so the tracker can hand it to IPv6 peers
+    pub ipv6: Option<Ipv6Addr>,
+}
 
 pub struct Tracker {
     // The 20 byte unique identifier for this instance of the client
@@ -42,20 +100,30 @@ pub struct Tracker {
     port: u16,
     // A string the client should send on subsequent announcements
     tracker_id: Option<String>,
+    // A random number that lets the tracker know it is still us if our address changes
+    key: u32,
+    // How many peers to ask for
+    numwant: u32,
+    // When the tracker last answered an announce
+    last_announce: Option<Instant>,
+    // The least time the tracker wants between announces
+    min_interval: Option<Duration>,
+    // How long the tracker wants between regular announces
+    interval: Option<Duration>,
+    // If set, announces go through this I2P session
+    i2p: Option<SamSession>,
+    // If set, announces go through this SOCKS5 proxy
+    proxy: Option<ProxyConfig>,
+    // How to check an https tracker's certificate
+    tls: TlsOptions,
+    // Our routable IPv6 address, so the tracker can hand it to IPv6 peers
+    ipv6: Option<Ipv6Addr>,
     // The shared state of the client
     // A future of the must recent tracker request
-    request: Box<dyn Future<Item=TrackerResponse, Error=TrackerError> + Send>,
+    request: BoxedFuture<TrackerResponse>,
 }
 
-#[derive(Debug, PartialEq)]
-pub struct PeerInfo {
-    // the unique identifier for this peer
-    pub peer_id: Option<[u8; 20]>,
-    // The ip/port of this peer
-    pub address: SocketAddr,
-}
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TrackerSuccessResponse {
     // The number of seconds the client should wait before sending a regular request to the tracker
     pub interval: u32,
@@ -69,6 +137,19 @@ pub struct TrackerSuccessResponse {
     pub incomplete: u32,
     // A list of peers that we could connect to
     pub peers: Vec<PeerInfo>,
+    // Our address as the tracker sees it (BEP 24)
+    pub external_ip: Option<IpAddr>,
+}
+
+/// How healthy a swarm is, from a scrape
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ScrapeInfo {
+    // Number of seeders
+    pub complete: u32,
+    // Number of leechers
+    pub incomplete: u32,
+    // Number of times the torrent has been downloaded
+    pub downloaded: u32,
 }
 
 #[derive(Debug, PartialEq)]
@@ -92,9 +173,26 @@ pub enum TrackerError {
     DecodeError(DecodeError),
     /// The contents of the response are not correct
     InvalidResponse,
+    /// The tracker's url doesn't say where to scrape it
+    ScrapeUnsupported,
+    /// Could not talk to a UDP tracker
+    IoError(io::Error),
+    /// Could not set up TLS for an https tracker
+    TlsError(native_tls::Error),
+    /// The tracker's address could not be found
+    #[error(non_std, no_from)]
+    UnknownHost(String),
+    /// The UDP tracker didn't answer
+    Timeout,
+    /// The tracker answered with an error
+    #[error(non_std, no_from)]
+    Refused(String),
+    /// UDP trackers can't be reached through I2P or a SOCKS proxy without giving our address away
+    UdpProxied,
 }
 
-enum Event {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
     Started,
     Stopped,
     Completed,
@@ -110,30 +208,20 @@ impl fmt::Display for Event {
     }
 }
 
-impl FromValue for PeerInfo {
+impl FromValue for ScrapeInfo {
     type Error = String;
 
     fn from_value(val: &Value) -> Result<Self, Self::Error> {
         let map = val.dict().ok_or("Not a dictionary".to_string())?;
 
-        let mut peer_id: [u8; 20] = [0; 20];
-        map.get("peer id".as_bytes()).and_then(Value::bstring)
-            .map(|bytes| peer_id.copy_from_slice(bytes))
-            .ok_or("Missing key: peer id".to_string())?;
-        let peer_id = Some(peer_id);
-
-        let ip = map.get("ip".as_bytes()).and_then(Value::bstring_utf8)
-            .map(|s| s.parse())
-            .ok_or("Missing key: ip".to_string())?
-            .map_err(|_| "Invalid ip addr".to_string())?;
-
-        let port = map.get("port".as_bytes()).and_then(Value::integer)
-            .map(|i| *i as u16)
-            .ok_or("Missing key: port".to_string())?;
+        let count = |key: &str| map.get(key.as_bytes()).and_then(Value::integer)
+            .map(|i| *i as u32)
+            .ok_or(format!("Missing key: {}", key));
 
-        Ok(PeerInfo {
-            peer_id,
-            address: SocketAddr::new(ip, port),
+        Ok(ScrapeInfo {
+            complete: count("complete")?,
+            incomplete: count("incomplete")?,
+            downloaded: count("downloaded").unwrap_or(0),
         })
     }
 }
@@ -145,7 +233,7 @@ impl FromValue for TrackerResponse {
         let map = val.dict().ok_or("Not a dictionary".to_string())?;
 
         if let Some(msg) = map.get("failure reason".as_bytes()) {
-            return Err(msg.bstring_utf8().unwrap_or("unknown failure reason".to_string()));
+            return Ok(TrackerResponse::Failure(msg.bstring_utf8().unwrap_or("unknown failure reason".to_string())));
         };
 
         let warning_msg = map.get("warning message".as_bytes()).and_then(Value::bstring_utf8);
@@ -168,26 +256,34 @@ impl FromValue for TrackerResponse {
             .map(|i| *i as u32)
             .ok_or("Missing key: incomplete".to_string())?;
 
-        let peers = match map.get("peers".as_bytes()).ok_or("Missing key: peers".to_string())? {
+        // Trackers that only have IPv6 peers may leave out peers and just send peers6
+        let peers6 = map.get("peers6".as_bytes());
+        let mut peers = match (map.get("peers".as_bytes()), peers6) {
             // Dictionary model
-            Value::List(peers) => peers.iter()
+            (Some(Value::List(peers)), _) => peers.iter()
                 .map(PeerInfo::from_value)
                 .collect::<Result<Vec<_>, _>>()?,
             // Binary model
-            Value::BString(peers) => peers.chunks(6)
-                .map(|peer_slice| {
-                    // port is in big endian.  multiply instead of bitshift so you can't mess up endianness
-                    let port = (peer_slice[0] as u16 * 256) + peer_slice[1] as u16;
-                    let mut ip_bytes: [u8; 4] = [0; 4];
-                    ip_bytes.copy_from_slice(&peer_slice[..4]);
-                    let ip: IpAddr = ip_bytes.into();
-                    PeerInfo {
-                        peer_id: None,
-                        address: (ip, port).into(),
-                    }
-                })
-                .collect(),
-            _ => return Err("peers is not in the correct form".to_owned())
+            (Some(Value::BString(peers)), _) => PeerInfo::from_compact(peers),
+            (Some(_), _) => return Err("peers is not in the correct form".to_owned()),
+            (None, Some(_)) => Vec::new(),
+            (None, None) => return Err("Missing key: peers".to_string()),
+        };
+        match peers6 {
+            Some(Value::BString(peers6)) => peers.extend(PeerInfo::from_compact6(peers6)),
+            Some(_) => return Err("peers6 is not in the correct form".to_owned()),
+            None => (),
+        }
+
+        // Sent as 4 or 16 bytes, like the addresses in compact peer lists
+        let external_ip = match map.get("external ip".as_bytes()).and_then(Value::bstring) {
+            Some(ip) if ip.len() == 4 => Some(IpAddr::from([ip[0], ip[1], ip[2], ip[3]])),
+            Some(ip) if ip.len() == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                Some(IpAddr::from(octets))
+            }
+            _ => None,
         };
 
         let res = TrackerSuccessResponse {
@@ -197,6 +293,7 @@ impl FromValue for TrackerResponse {
             complete,
             incomplete,
             peers,
+            external_ip,
         };
 
         match warning_msg {
@@ -219,127 +316,221 @@ impl Tracker {
             info_hash,
             port,
             tracker_id: None,
-            request: Box::new(err(TrackerError::InvalidResponse)),
+            key: rand::random(),
+            numwant: DEFAULT_NUMWANT,
+            last_announce: None,
+            min_interval: None,
+            interval: None,
+            i2p: None,
+            proxy: None,
+            tls: TlsOptions::default(),
+            ipv6: None,
+            request: future::err(TrackerError::InvalidResponse).boxed(),
         }
     }
 
+    /// The uri of the tracker
+    pub fn uri(&self) -> &str {
+        &self.tracker_uri
+    }
+
+    /// Announce through I2P instead of over the internet
+    pub fn set_i2p(&mut self, session: SamSession) {
+        self.i2p = Some(session);
+    }
+
+    /// Announce through a SOCKS5 proxy.  I2P takes priority if both are set
+    pub fn set_proxy(&mut self, proxy: Option<ProxyConfig>) {
+        self.proxy = proxy;
+    }
+
+    /// Check an https tracker's certificate this way instead of against the system's CAs
+    pub fn set_tls(&mut self, tls: TlsOptions) {
+        self.tls = tls;
+    }
+
+    /// Tell the tracker our IPv6 address, so IPv6 peers can reach us
+    pub fn set_ipv6(&mut self, ipv6: Option<Ipv6Addr>) {
+        self.ipv6 = ipv6;
+    }
+
+    /// The id the tracker asked us to send, if any
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+
+    /// Use a tracker id from an earlier run
+    pub fn set_tracker_id(&mut self, tracker_id: Option<String>) {
+        self.tracker_id = tracker_id;
+    }
+
+    /// Send a key kept from earlier, so the tracker knows us across trackers and restarts
+    pub fn set_key(&mut self, key: u32) {
+        self.key = key;
+    }
+
+    /// Ask for this many peers in each announce
+    pub fn set_numwant(&mut self, numwant: u32) {
+        self.numwant = numwant;
+    }
+
     /// Tell the tracker that you are starting your download
     pub fn start(&mut self, download_size: u64) {
-        self.request = Box::new(self.announce(Some(Event::Started), download_size, 0, 0))
+        self.request = self.announce(Some(Event::Started), download_size, 0, 0)
     }
 
     /// Tell the tracker that you are stopping your download without finishing.
     pub fn cancel(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(Some(Event::Stopped), left, uploaded, downloaded))
+        self.request = self.announce(Some(Event::Stopped), left, uploaded, downloaded)
     }
 
     /// Tell the tracker that you have completed the download
     pub fn finish(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(Some(Event::Completed), left, uploaded, downloaded))
+        self.request = self.announce(Some(Event::Completed), left, uploaded, downloaded)
     }
 
     /// Update the tracker on your download status, and get more peers
     pub fn refresh(&mut self, left: u64, uploaded: u64, downloaded: u64) {
-        self.request = Box::new(self.announce(None, left, uploaded, downloaded))
+        self.request = self.announce(None, left, uploaded, downloaded)
     }
 
-    fn announce(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> impl Future<Item=TrackerResponse, Error=TrackerError> {
-        // build the tracker query string
-        let mut req_uri = self.tracker_uri.clone();
-        let encoded_info_hash = percent_encode(&self.info_hash, QUERY_ENCODE_SET).to_string();
-        let encoded_peer_id = percent_encode(&self.peer_id, QUERY_ENCODE_SET).to_string();
-        let query = hashmap! {
-            "info_hash" => encoded_info_hash,
-            "peer_id" => encoded_peer_id,
-            "port" => self.port.to_string(),
-            "uploaded" => uploaded.to_string(),
-            "downloaded" => downloaded.to_string(),
-            "left" => left.to_string(),
-            "compact" => 0.to_string(),
-        };
-        req_uri.push('?');
-        query.iter().fold(&mut req_uri, |s, (k, v)| {
-            s.push_str(k);
-            s.push('=');
-            s.push_str(&v);
-            s.push('&');
-            s
-        });
-        match event {
-            Some(e) => {
-                req_uri.push_str("event");
-                req_uri.push('=');
-                req_uri.push_str(&e.to_string());
-                req_uri.push('&')
+    /// Update the tracker right away because the user asked to.  Trackers ban clients that
+    /// announce too often, so if it is too soon this does nothing and returns how long to wait.
+    pub fn force_refresh(&mut self, left: u64, uploaded: u64, downloaded: u64) -> Result<(), Duration> {
+        match self.time_until_announce_allowed() {
+            Some(wait) => Err(wait),
+            None => {
+                self.refresh(left, uploaded, downloaded);
+                Ok(())
             }
-            None => ()
         }
-        match &self.tracker_id {
-            Some(id) => {
-                req_uri.push_str("trackerid");
-                req_uri.push('=');
-                req_uri.push_str(&percent_encode(id.as_bytes(), QUERY_ENCODE_SET).to_string());
-                req_uri.push('&')
+    }
+
+    /// How long to wait after an announce before the next regular one.  This is the interval the
+    /// tracker asked for, but never less than its min interval
+    pub fn announce_interval(&self) -> Duration {
+        let interval = self.interval.unwrap_or(DEFAULT_INTERVAL);
+        match self.min_interval {
+            Some(min_interval) => interval.max(min_interval),
+            None => interval,
+        }
+    }
+
+    /// Pick up where an earlier Tracker for the same url left off, so its min interval still
+    /// holds
+    pub fn restore(&mut self, cached: &CachedResponse) {
+        self.remember(&cached.response);
+        self.last_announce = Some(cached.received);
+    }
+
+    /// How much longer until the tracker's min interval has passed, if it hasn't yet
+    pub fn time_until_announce_allowed(&self) -> Option<Duration> {
+        let elapsed = self.last_announce?.elapsed();
+        let min_interval = self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL);
+        if elapsed < min_interval {
+            Some(min_interval - elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// The announcer for the tracker's protocol.  UDP can't be proxied, so a UDP tracker is only
+    /// used when announces go straight over the internet
+    fn announcer(&self) -> Result<Box<dyn Announcer>, TrackerError> {
+        if self.tracker_uri.starts_with("udp://") {
+            if self.i2p.is_some() || self.proxy.is_some() {
+                return Err(TrackerError::UdpProxied);
             }
-            None => ()
+            Ok(Box::new(UdpAnnouncer::new(&self.tracker_uri)?))
+        } else {
+            Ok(Box::new(HttpAnnouncer::new(self.tracker_uri.clone(), self.i2p.clone(), self.proxy.clone(), self.tls.clone())))
         }
-        let _ = req_uri.pop();
-        let client = Client::new();
-        let uri = match hyper::http::HttpTryFrom::try_from(&req_uri) {
-            Ok(uri) => ok(uri),
-            Err(e) => err(TrackerError::InvalidURI(e))
+    }
+
+    /// Send an announce.  Leaving and finishing are always announced right away, but other
+    /// announces wait until the tracker's min interval has passed
+    fn announce(&mut self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> BoxedFuture<TrackerResponse> {
+        let announce = self.announce_request(event, left, uploaded, downloaded);
+        let announcer = match self.announcer() {
+            Ok(announcer) => announcer,
+            Err(e) => return future::err(e).boxed(),
         };
-        // Start the tracker query future
-        uri.and_then(move |uri| {
-            client.get(uri).map_err(|e| TrackerError::ConnectionError(e))
-        }).and_then(|get_response| {
-            if get_response.status() == StatusCode::OK {
-                Ok(get_response.into_body())
-            } else {
-                Err(TrackerError::ResponseError(get_response.status().as_u16()))
-            }
-        }).and_then(|body| {
-            body.map(|chunk| {
-                Vec::from(&*chunk)
-            }).concat2()
-                .map_err(|e| TrackerError::ConnectionError(e))
-        }).and_then(|resp_bytes| {
-            Value::decode(&resp_bytes).map_err(|e| TrackerError::DecodeError(e))
-        }).and_then(|val| {
-            trace!("response: {:?}", val);
-            TrackerResponse::from_value(&val)
-                .map_err(|_| TrackerError::InvalidResponse)
-        })
+        let wait = match event {
+            Some(Event::Stopped) | Some(Event::Completed) => None,
+            _ => self.time_until_announce_allowed(),
+        };
+        match wait {
+            Some(wait) => async move {
+                time::sleep(wait).await;
+                let request = announcer.announce(&announce);
+                request.await
+            }.boxed(),
+            None => announcer.announce(&announce),
+        }
     }
 
-    /// Updates the tracker id based on a tracker response
+    /// What to tell the tracker in an announce
+    fn announce_request(&self, event: Option<Event>, left: u64, uploaded: u64, downloaded: u64) -> Announce {
+        Announce {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded,
+            downloaded,
+            left,
+            event,
+            key: self.key,
+            // We are leaving, so have no use for peers
+            numwant: match event {
+                Some(Event::Stopped) => 0,
+                _ => self.numwant,
+            },
+            tracker_id: self.tracker_id.clone(),
+            ipv6: self.ipv6,
+        }
+    }
+
+    /// Ask the tracker how many seeds and leechers the torrent has, without joining the swarm
+    pub fn scrape(&self) -> BoxedFuture<ScrapeInfo> {
+        match self.announcer() {
+            Ok(announcer) => announcer.scrape(self.info_hash),
+            Err(e) => future::err(e).boxed(),
+        }
+    }
+
+    /// Updates the tracker id and intervals based on a tracker response
     fn update_tracker_id(&mut self, response: &TrackerResponse) {
         match response {
             TrackerResponse::Success(r) | TrackerResponse::Warning(_, r) => {
-                match &r.tracker_id {
-                    Some(id) => self.tracker_id = Some(id.clone()),
-                    None => ()
-                }
+                self.remember(r);
+                self.last_announce = Some(Instant::now());
             }
             _ => ()
         }
     }
+
+    /// Keep the tracker id and intervals the tracker asked for
+    fn remember(&mut self, r: &TrackerSuccessResponse) {
+        match &r.tracker_id {
+            Some(id) => self.tracker_id = Some(id.clone()),
+            None => ()
+        }
+        self.min_interval = r.min_interval.map(|i| Duration::from_secs(i as u64));
+        self.interval = Some(Duration::from_secs(r.interval as u64));
+    }
 }
 
 impl Future for Tracker {
-    type Item = TrackerResponse;
-    type Error = TrackerError;
+    type Output = Result<TrackerResponse, TrackerError>;
 
-    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
-        let poll = self.request.poll();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = ready!(self.request.as_mut().poll(cx));
         // if ready, update the tracker id to the response value, and set it up so that subsequent
         // polls will return not ready
-        if let Ok(Async::Ready(res)) = poll {
-            self.update_tracker_id(&res);
-            self.request = Box::new(empty());
-            Ok(Async::Ready(res))
-        } else {
-            poll
+        if let Ok(response) = &res {
+            self.update_tracker_id(response);
+            self.request = future::pending().boxed();
         }
+        Poll::Ready(res)
     }
-}
\ No newline at end of file
+}
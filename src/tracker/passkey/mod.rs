@@ -0,0 +1,197 @@
+//! Private trackers put a secret passkey in their announce urls.  Passkeys are kept out of the
+//! tracker list here: trackers are stored as templates with a {passkey} placeholder, and the keys
+//! themselves live in their own file in the state directory, one per tracker host.  That way a
+//! passkey never ends up in logs or events, and changing one changes it for every torrent.
+use crate::boostencode::{DecodeError, Value};
+use derive_error::Error;
+use log::warn;
+use maplit::hashmap;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+#[cfg(test)]
+mod test;
+
+/// Where the passkey goes in a tracker template
+pub const PLACEHOLDER: &str = "{passkey}";
+
+/// Query parameters private trackers put passkeys in
+const PASSKEY_PARAMS: [&str; 4] = ["passkey", "authkey", "torrent_pass", "pk"];
+
+/// Path segments at least this long and made only of letters and digits are taken to be passkeys
+const MIN_PATH_PASSKEY_LEN: usize = 16;
+
+#[derive(Debug, Error)]
+pub enum PasskeyError {
+    /// The passkey file could not be read or written
+    Io(io::Error),
+    /// The passkey file could not be bdecoded
+    DecodeError(DecodeError),
+    /// The contents of the passkey file are not correct
+    #[error(non_std, no_from)]
+    InvalidPasskeys(String),
+}
+
+/// Passkeys by tracker host, backed by a file
+pub struct Passkeys {
+    // Where the passkeys are saved.  None if they only live in memory
+    path: Option<PathBuf>,
+    keys: HashMap<String, String>,
+    // Bumped every time a passkey changes, so torrents can tell when to rebuild their urls
+    version: u64,
+}
+
+impl Passkeys {
+    /// Create an empty set of passkeys that is never saved
+    pub fn new() -> Self {
+        Passkeys {
+            path: None,
+            keys: HashMap::new(),
+            version: 0,
+        }
+    }
+
+    /// Load the passkeys saved at `path`.  A missing file is treated as having no passkeys
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, PasskeyError> {
+        let path = path.as_ref().to_path_buf();
+        let keys = match fs::read(&path) {
+            Ok(bytes) => {
+                let val = Value::decode(&bytes)?;
+                val.dict()
+                    .and_then(|map| map.get("passkeys".as_bytes()))
+                    .and_then(Value::dict)
+                    .ok_or(PasskeyError::InvalidPasskeys("Missing key: passkeys".to_string()))?
+                    .iter()
+                    .map(|(host, key)| {
+                        let key = key.bstring_utf8()
+                            .ok_or(PasskeyError::InvalidPasskeys("Passkey not a string".to_string()))?;
+                        Ok((String::from_utf8_lossy(host).into_owned(), key))
+                    })
+                    .collect::<Result<HashMap<_, _>, PasskeyError>>()?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(PasskeyError::Io(e)),
+        };
+        Ok(Passkeys {
+            path: Some(path),
+            keys,
+            version: 0,
+        })
+    }
+
+    /// Write the passkeys to disk
+    pub fn save(&self) -> Result<(), PasskeyError> {
+        match &self.path {
+            Some(path) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                let keys = self.keys.iter()
+                    .map(|(host, key)| (Vec::from(host.as_bytes()), Value::BString(Vec::from(key.as_bytes()))))
+                    .collect();
+                fs::write(path, Value::Dict(hashmap! {
+                    Vec::from("passkeys") => Value::Dict(keys),
+                }).encode())?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Set the passkey for a tracker host, replacing the old one in every torrent using it
+    pub fn set(&mut self, host: &str, key: &str) {
+        if self.keys.get(host).map(String::as_str) == Some(key) {
+            return;
+        }
+        self.keys.insert(host.to_owned(), key.to_owned());
+        self.version += 1;
+        if let Err(e) = self.save() {
+            warn!("Could not save passkeys: {:?}", e);
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Turn an announce url with a passkey in it into a template, remembering the passkey if we
+    /// don't have one for the tracker yet.  Urls without passkeys are returned as they are.
+    pub fn templatize(&mut self, url: &str) -> String {
+        let (range, host) = match (find_passkey(url), host(url)) {
+            (Some(range), Some(host)) => (range, host.to_owned()),
+            _ => return url.to_owned(),
+        };
+        if !self.keys.contains_key(&host) {
+            self.set(&host, &url[range.clone()]);
+        }
+        format!("{}{}{}", &url[..range.start], PLACEHOLDER, &url[range.end..])
+    }
+
+    /// Put the passkey for the template's tracker into the template
+    pub fn fill(&self, template: &str) -> String {
+        match host(template).and_then(|host| self.keys.get(host)) {
+            Some(key) => template.replace(PLACEHOLDER, key),
+            None => template.to_owned(),
+        }
+    }
+}
+
+impl Default for Passkeys {
+    fn default() -> Self {
+        Passkeys::new()
+    }
+}
+
+/// Hide any passkey in `url`, for when it has to be shown to someone
+pub fn redact(url: &str) -> String {
+    match find_passkey(url) {
+        Some(range) => format!("{}REDACTED{}", &url[..range.start], &url[range.end..]),
+        None => url.to_owned(),
+    }
+}
+
+/// The host and port of a url
+fn host(url: &str) -> Option<&str> {
+    let start = url.find("://")? + 3;
+    let end = url[start..].find(['/', '?']).map_or(url.len(), |i| start + i);
+    Some(&url[start..end])
+}
+
+/// Where the passkey is in a url
+fn find_passkey(url: &str) -> Option<Range<usize>> {
+    let (path_end, query) = match url.find('?') {
+        Some(i) => (i, Some(i + 1)),
+        None => (url.len(), None),
+    };
+
+    if let Some(query) = query {
+        let mut start = query;
+        for param in url[query..].split('&') {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            if let Some(value) = parts.next() {
+                if PASSKEY_PARAMS.contains(&name) && !value.is_empty() {
+                    let value_start = start + name.len() + 1;
+                    return Some(value_start..value_start + value.len());
+                }
+            }
+            start += param.len() + 1;
+        }
+    }
+
+    let path_start = url.find("://").map_or(0, |i| i + 3);
+    let mut start = path_start;
+    for segment in url[path_start..path_end].split('/') {
+        if segment.len() >= MIN_PATH_PASSKEY_LEN && segment.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Some(start..start + segment.len());
+        }
+        start += segment.len() + 1;
+    }
+    None
+}
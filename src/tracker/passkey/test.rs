@@ -0,0 +1,36 @@
+use super::*;
+
+const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+#[test]
+fn test_redact() {
+    assert_eq!(redact(&format!("https://t.example/{}/announce", KEY)),
+               "https://t.example/REDACTED/announce");
+    assert_eq!(redact(&format!("https://t.example/announce.php?passkey={}&x=1", KEY)),
+               "https://t.example/announce.php?passkey=REDACTED&x=1");
+    assert_eq!(redact("http://tracker.example:6969/announce"), "http://tracker.example:6969/announce");
+}
+
+#[test]
+fn test_templatize_and_fill() {
+    let mut passkeys = Passkeys::new();
+    let url = format!("https://t.example/announce?torrent_pass={}", KEY);
+    let template = passkeys.templatize(&url);
+    assert_eq!(template, "https://t.example/announce?torrent_pass={passkey}");
+    assert_eq!(passkeys.fill(&template), url);
+    assert_eq!(passkeys.templatize("udp://open.example:1337"), "udp://open.example:1337");
+}
+
+#[test]
+fn test_rotate_passkey() {
+    let mut passkeys = Passkeys::new();
+    let first = passkeys.templatize(&format!("https://t.example/{}/announce", KEY));
+    // A second torrent from the same tracker with an old passkey uses the one we already have
+    let second = passkeys.templatize("https://t.example/ffffffffffffffffffffffffffffffff/announce");
+    assert_eq!(first, second);
+
+    let version = passkeys.version();
+    passkeys.set("t.example", "fedcba9876543210fedcba9876543210");
+    assert!(passkeys.version() > version);
+    assert_eq!(passkeys.fill(&first), "https://t.example/fedcba9876543210fedcba9876543210/announce");
+}
@@ -4,15 +4,22 @@ use hyper::{
     Request,
     Response,
     server::Server,
+    service::{
+        make_service_fn,
+        service_fn,
+    },
 };
 use maplit::hashmap;
-use std::net::IpAddr;
+use std::convert::Infallible;
+use std::net::{
+    IpAddr,
+    SocketAddr,
+};
 use std::sync::{
     Arc,
     RwLock,
 };
 use super::*;
-use tokio;
 
 
 fn service_handler(_request_: Request<Body>) -> Response<Body> {
@@ -35,16 +42,14 @@ fn service_handler(_request_: Request<Body>) -> Response<Body> {
         .body(res.into()).unwrap()
 }
 
-#[test]
-fn test_announce() {
+#[tokio::test]
+async fn test_announce() {
     let address: (IpAddr, u16) = ([127, 0, 0, 1].into(), 8888);
     let test_server = Server::bind(&address.into())
-        .serve(|| {
-            hyper::service::service_fn_ok(service_handler)
-        }).map_err(|_| ());
-    let mut runtime = tokio::runtime::Runtime::new().unwrap();
-    let executor = runtime.executor();
-    executor.spawn(test_server);
+        .serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request| async { Ok::<_, Infallible>(service_handler(request)) }))
+        }));
+    tokio::spawn(test_server);
 
     let mut tracker = Tracker::new(
         [0; 20],
@@ -52,7 +57,7 @@ fn test_announce() {
         [0; 20],
         8888);
     tracker.start(1000);
-    let start_resp = runtime.block_on(tracker).expect("start should not return error");
+    let start_resp = tracker.await.expect("start should not return error");
 
     assert_eq!(start_resp, TrackerResponse::Success(
         TrackerSuccessResponse {
@@ -65,8 +70,179 @@ fn test_announce() {
                 peer_id: Some([1; 20]),
                 address: address.into(),
             }],
+            external_ip: None,
         }
     ));
+}
+
+#[test]
+fn test_force_refresh_respects_min_interval() {
+    let mut tracker = Tracker::new(
+        [0; 20],
+        "http://localhost:8888".to_owned(),
+        [0; 20],
+        8888);
+    tracker.last_announce = Some(Instant::now());
+    tracker.min_interval = Some(Duration::from_secs(30));
+    let wait = tracker.force_refresh(1000, 0, 0).expect_err("should be too soon to announce");
+    assert!(wait <= Duration::from_secs(30));
+}
+
+#[test]
+fn test_scrape_uri() {
+    assert_eq!(scrape_uri("http://example.com/announce"), Some("http://example.com/scrape".to_string()));
+    assert_eq!(scrape_uri("http://example.com/x/announce.php?passkey=abc"),
+               Some("http://example.com/x/scrape.php?passkey=abc".to_string()));
+    assert_eq!(scrape_uri("http://example.com/a"), None);
+    assert_eq!(scrape_uri("http://example.com/announce/x"), None);
+}
+
+#[test]
+fn test_scrape_info_from_value() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("complete") => Value::Integer(0),
+        Vec::from("incomplete") => Value::Integer(3),
+    });
+    assert_eq!(ScrapeInfo::from_value(&val), Ok(ScrapeInfo {
+        complete: 0,
+        incomplete: 3,
+        downloaded: 0,
+    }));
+}
+
+#[test]
+fn test_announce_interval() {
+    let mut tracker = Tracker::new(
+        [0; 20],
+        "http://localhost:8888".to_owned(),
+        [0; 20],
+        8888);
+    assert_eq!(tracker.announce_interval(), DEFAULT_INTERVAL);
+    let response = |interval, min_interval| TrackerResponse::Success(TrackerSuccessResponse {
+        interval,
+        min_interval,
+        tracker_id: Some("id".to_owned()),
+        complete: 0,
+        incomplete: 0,
+        peers: Vec::new(),
+        external_ip: None,
+    });
+    tracker.update_tracker_id(&response(1800, Some(60)));
+    assert_eq!(tracker.announce_interval(), Duration::from_secs(1800));
+    assert_eq!(tracker.tracker_id(), Some("id"));
+    // A tracker that asks for announces more often than it allows gets the min interval
+    tracker.update_tracker_id(&response(10, Some(60)));
+    assert_eq!(tracker.announce_interval(), Duration::from_secs(60));
+}
+
+#[test]
+fn test_compact_peers() {
+    let mut peers6 = vec![0; 16];
+    peers6[0] = 0x20;
+    peers6[1] = 0x01;
+    peers6[15] = 1;
+    peers6.extend_from_slice(&[0x1a, 0xe1]);
+    let val = Value::Dict(hashmap! {
+        Vec::from("interval") => Value::Integer(10),
+        Vec::from("complete") => Value::Integer(1),
+        Vec::from("incomplete") => Value::Integer(1),
+        Vec::from("peers") => Value::BString(vec![10, 0, 0, 1, 0x1a, 0xe1]),
+        Vec::from("peers6") => Value::BString(peers6),
+    });
+    let peers = match TrackerResponse::from_value(&val) {
+        Ok(TrackerResponse::Success(response)) => response.peers,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    let addresses: Vec<SocketAddr> = peers.iter().map(|peer| peer.address).collect();
+    assert_eq!(addresses, vec!["10.0.0.1:6881".parse().unwrap(), "[2001::1]:6881".parse().unwrap()]);
+}
+
+#[test]
+fn test_announce_request() {
+    let mut tracker = Tracker::new(
+        [0; 20],
+        "http://localhost:8888/announce".to_owned(),
+        [0; 20],
+        8888);
+    tracker.set_key(0xbeef);
+    tracker.set_numwant(80);
+    tracker.set_tracker_id(Some("id".to_owned()));
+    let announce = tracker.announce_request(Some(Event::Started), 1000, 0, 0);
+    assert_eq!((announce.key, announce.numwant, announce.left), (0xbeef, 80, 1000));
+    assert_eq!(announce.tracker_id, Some("id".to_owned()));
+    assert_eq!(tracker.announce_request(Some(Event::Stopped), 1000, 0, 0).numwant, 0);
+}
 
-    runtime.shutdown_now();
-}
\ No newline at end of file
+#[test]
+fn test_udp_trackers_arent_proxied() {
+    let mut tracker = Tracker::new(
+        [0; 20],
+        "udp://localhost:8888/announce".to_owned(),
+        [0; 20],
+        8888);
+    assert!(tracker.announcer().is_ok());
+    tracker.set_proxy(Some("socks5://127.0.0.1:1080".parse().unwrap()));
+    match tracker.announcer() {
+        Err(TrackerError::UdpProxied) => (),
+        _ => panic!("a udp tracker should not be used through a proxy"),
+    }
+}
+
+#[test]
+fn test_restore() {
+    let mut tracker = Tracker::new(
+        [0; 20],
+        "http://localhost:8888".to_owned(),
+        [0; 20],
+        8888);
+    assert_eq!(tracker.time_until_announce_allowed(), None);
+    tracker.restore(&cache::CachedResponse {
+        received: Instant::now(),
+        response: TrackerSuccessResponse {
+            interval: 1800,
+            min_interval: Some(600),
+            tracker_id: Some("id".to_owned()),
+            complete: 0,
+            incomplete: 0,
+            peers: Vec::new(),
+            external_ip: None,
+        },
+    });
+    assert_eq!(tracker.tracker_id(), Some("id"));
+    assert_eq!(tracker.announce_interval(), Duration::from_secs(1800));
+    // The tracker answered just now, so it can't be asked again for most of its min interval
+    assert!(tracker.time_until_announce_allowed().unwrap() > Duration::from_secs(590));
+    assert!(tracker.force_refresh(1000, 0, 0).is_err());
+}
+
+#[test]
+fn test_failure_and_external_ip() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("failure reason") => Value::BString(Vec::from("unregistered torrent")),
+    });
+    assert_eq!(TrackerResponse::from_value(&val), Ok(TrackerResponse::Failure("unregistered torrent".to_owned())));
+
+    let val = |external_ip: Vec<u8>| Value::Dict(hashmap! {
+        Vec::from("interval") => Value::Integer(10),
+        Vec::from("complete") => Value::Integer(1),
+        Vec::from("incomplete") => Value::Integer(1),
+        Vec::from("peers") => Value::BString(Vec::new()),
+        Vec::from("external ip") => Value::BString(external_ip),
+        Vec::from("warning message") => Value::BString(Vec::from("slow down")),
+    });
+    let external_ip = |val| match TrackerResponse::from_value(&val) {
+        Ok(TrackerResponse::Warning(msg, response)) => {
+            assert_eq!(msg, "slow down");
+            response.external_ip
+        }
+        other => panic!("unexpected response: {:?}", other),
+    };
+    assert_eq!(external_ip(val(vec![203, 0, 113, 7])), Some("203.0.113.7".parse().unwrap()));
+    let mut ipv6 = vec![0; 16];
+    ipv6[0] = 0x20;
+    ipv6[1] = 0x01;
+    ipv6[15] = 1;
+    assert_eq!(external_ip(val(ipv6)), Some("2001::1".parse().unwrap()));
+    // Anything that isn't an address is ignored
+    assert_eq!(external_ip(val(vec![1, 2, 3])), None);
+}
@@ -0,0 +1,115 @@
+//! The list of trackers for a torrent, grouped into tiers as described in BEP 12.  Trackers in
+//! earlier tiers are preferred over trackers in later ones.
+use rand::Rng;
+
+#[cfg(test)]
+mod test;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+}
+
+impl TrackerTiers {
+    /// Build the tiers from the torrent file.  If there is an announce-list, the announce key is
+    /// ignored, as BEP 12 says.
+    pub fn new(announce: &str, announce_list: &Option<Vec<(usize, String)>>) -> Self {
+        let mut tiers: Vec<Vec<String>> = Vec::new();
+        match announce_list {
+            Some(list) if !list.is_empty() => {
+                for (tier, url) in list {
+                    while tiers.len() <= *tier {
+                        tiers.push(Vec::new());
+                    }
+                    tiers[*tier].push(url.clone());
+                }
+                tiers.retain(|tier| !tier.is_empty());
+            }
+            _ => tiers.push(vec![announce.to_owned()]),
+        }
+        TrackerTiers { tiers }
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// The most preferred tracker
+    pub fn first(&self) -> Option<&str> {
+        self.tiers.iter().flatten().next().map(String::as_str)
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.tiers.iter().flatten().any(|u| u == url)
+    }
+
+    /// Add a tracker to a tier.  A tier of None, or past the last tier, adds a new tier at the
+    /// end.  Returns false if we already have the tracker.
+    pub fn add(&mut self, tier: Option<usize>, url: &str) -> bool {
+        if self.contains(url) {
+            return false;
+        }
+        match tier {
+            Some(tier) if tier < self.tiers.len() => self.tiers[tier].push(url.to_owned()),
+            _ => self.tiers.push(vec![url.to_owned()]),
+        }
+        true
+    }
+
+    /// Remove a tracker, dropping its tier if it was the last one in it.  Returns false if we
+    /// didn't have the tracker.
+    pub fn remove(&mut self, url: &str) -> bool {
+        let before = self.len();
+        for tier in self.tiers.iter_mut() {
+            tier.retain(|u| u != url);
+        }
+        self.tiers.retain(|tier| !tier.is_empty());
+        self.len() != before
+    }
+
+    /// Shuffle the trackers within each tier, as BEP 12 asks, so clients spread out over them
+    pub fn shuffle(&mut self) {
+        let mut rng = rand::thread_rng();
+        for tier in self.tiers.iter_mut() {
+            rng.shuffle(tier);
+        }
+    }
+
+    /// Move a tracker that answered to the front of its tier, so it is tried first from now on
+    pub fn promote(&mut self, url: &str) {
+        for tier in self.tiers.iter_mut() {
+            if let Some(i) = tier.iter().position(|u| u == url) {
+                let url = tier.remove(i);
+                tier.insert(0, url);
+            }
+        }
+    }
+
+    /// The tracker to try when `url` fails: the next one in its tier, or else the first one in
+    /// the next tier.  None once every tracker has been tried
+    pub fn after(&self, url: &str) -> Option<&str> {
+        let mut trackers = self.tiers.iter().flatten();
+        trackers.position(|u| u == url)?;
+        trackers.next().map(String::as_str)
+    }
+
+    /// The first tracker of every tier that doesn't hold `url`, for events every tier should hear
+    /// about when `url` is the tracker we are talking to
+    pub fn other_tiers(&self, url: &str) -> Vec<&str> {
+        self.tiers.iter()
+            .filter(|tier| !tier.iter().any(|u| u == url))
+            .filter_map(|tier| tier.first())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The number of trackers in every tier
+    pub fn len(&self) -> usize {
+        self.tiers.iter().map(Vec::len).sum()
+    }
+
+    /// Whether there are no trackers at all
+    pub fn is_empty(&self) -> bool {
+        self.tiers.iter().all(Vec::is_empty)
+    }
+}
@@ -0,0 +1,83 @@
+use super::*;
+
+#[test]
+fn test_new_without_announce_list() {
+    let tiers = TrackerTiers::new("http://a", &None);
+    assert_eq!(tiers.tiers(), &[vec!["http://a".to_string()]]);
+}
+
+#[test]
+fn test_new_with_announce_list() {
+    let tiers = TrackerTiers::new("http://a", &Some(vec![
+        (0, "http://b".to_string()),
+        (0, "http://c".to_string()),
+        (1, "http://d".to_string()),
+    ]));
+    assert_eq!(tiers.first(), Some("http://b"));
+    assert_eq!(tiers.tiers(), &[
+        vec!["http://b".to_string(), "http://c".to_string()],
+        vec!["http://d".to_string()],
+    ]);
+}
+
+#[test]
+fn test_add_and_remove() {
+    let mut tiers = TrackerTiers::new("http://a", &None);
+    assert!(tiers.add(Some(0), "http://b"));
+    assert!(tiers.add(None, "http://c"));
+    assert!(!tiers.add(None, "http://c"));
+    assert_eq!(tiers.len(), 3);
+
+    assert!(tiers.remove("http://a"));
+    assert!(!tiers.remove("http://a"));
+    assert_eq!(tiers.first(), Some("http://b"));
+
+    assert!(tiers.remove("http://c"));
+    assert_eq!(tiers.tiers(), &[vec!["http://b".to_string()]]);
+}
+
+#[test]
+fn test_failover_order() {
+    let mut tiers = TrackerTiers::new("http://a", &Some(vec![
+        (0, "http://b".to_string()),
+        (0, "http://c".to_string()),
+        (1, "http://d".to_string()),
+    ]));
+    assert_eq!(tiers.after("http://b"), Some("http://c"));
+    // A tier that fails entirely falls back to the next one
+    assert_eq!(tiers.after("http://c"), Some("http://d"));
+    assert_eq!(tiers.after("http://d"), None);
+    assert_eq!(tiers.after("http://x"), None);
+
+    tiers.promote("http://c");
+    assert_eq!(tiers.first(), Some("http://c"));
+    assert_eq!(tiers.after("http://c"), Some("http://b"));
+}
+
+#[test]
+fn test_shuffle_keeps_tiers() {
+    let mut tiers = TrackerTiers::new("http://a", &Some(vec![
+        (0, "http://b".to_string()),
+        (0, "http://c".to_string()),
+        (1, "http://d".to_string()),
+    ]));
+    tiers.shuffle();
+    let mut first = tiers.tiers()[0].clone();
+    first.sort();
+    assert_eq!(first, vec!["http://b".to_string(), "http://c".to_string()]);
+    assert_eq!(tiers.tiers()[1], vec!["http://d".to_string()]);
+}
+
+#[test]
+fn test_other_tiers() {
+    let tiers = TrackerTiers::new("http://a", &Some(vec![
+        (0, "http://b".to_string()),
+        (0, "http://c".to_string()),
+        (1, "http://d".to_string()),
+        (1, "http://e".to_string()),
+        (2, "http://f".to_string()),
+    ]));
+    assert_eq!(tiers.other_tiers("http://c"), vec!["http://d", "http://f"]);
+    assert_eq!(tiers.other_tiers("http://f"), vec!["http://b", "http://d"]);
+    assert_eq!(tiers.other_tiers("http://z"), vec!["http://b", "http://d", "http://f"]);
+}
@@ -0,0 +1,254 @@
+//! Announcing to trackers over UDP (BEP 15).  Every request starts with a connect exchange that
+//! gets us a connection id, which proves to the tracker that we aren't spoofing our address
+use byteorder::{
+    ByteOrder,
+    NetworkEndian,
+};
+use crate::types::PeerInfo;
+use futures::FutureExt;
+use std::future::Future;
+use std::net::{
+    SocketAddr,
+    ToSocketAddrs,
+};
+use std::time::Duration;
+use super::{
+    Announce,
+    Announcer,
+    BoxedFuture,
+    Event,
+    ScrapeInfo,
+    TrackerError,
+    TrackerResponse,
+    TrackerSuccessResponse,
+};
+use tokio::net::UdpSocket;
+use tokio::time::{
+    self,
+    Instant,
+};
+
+#[cfg(test)]
+mod test;
+
+/// The magic number at the start of a connect request
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// BEP 41 option carrying the path and query of the announce url, which some trackers use for
+/// passkeys
+const OPTION_URL_DATA: u8 = 2;
+
+/// Largest packet we expect.  Enough for an announce answer with a few hundred peers
+const MAX_PACKET: usize = 4096;
+
+/// How many times to send a request before giving up on the tracker
+const MAX_ATTEMPTS: u32 = 3;
+
+/// How long to wait for an answer to the first try.  Each retry waits twice as long as the last
+const RETRY_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct UdpAnnouncer {
+    // host:port of the tracker
+    host: String,
+    // The path and query of the announce url, passed along as BEP 41 url data
+    url_data: String,
+}
+
+impl UdpAnnouncer {
+    /// Take the tracker's address out of a udp:// announce url
+    pub fn new(uri: &str) -> Result<Self, TrackerError> {
+        let rest = uri.strip_prefix("udp://").ok_or_else(|| TrackerError::UnknownHost(uri.to_owned()))?;
+        let end = rest.find(['/', '?']).unwrap_or(rest.len());
+        if end == 0 || !rest[..end].contains(':') {
+            return Err(TrackerError::UnknownHost(uri.to_owned()));
+        }
+        Ok(UdpAnnouncer {
+            host: rest[..end].to_owned(),
+            url_data: rest[end..].to_owned(),
+        })
+    }
+
+    /// Only get a connection id, sending the request once and waiting up to `timeout`, to see
+    /// whether the tracker answers.  Returns the address it answered from
+    pub async fn ping(&self, timeout: Duration) -> Result<SocketAddr, TrackerError> {
+        let (_socket, address, _connection_id) = self.connect(1, timeout).await?;
+        Ok(address)
+    }
+
+    /// Look the tracker up, and get a connection id from it.  `attempts` and `timeout` are as for
+    /// exchange
+    fn connect(&self, attempts: u32, timeout: Duration)
+               -> impl Future<Output=Result<(UdpSocket, SocketAddr, u64), TrackerError>> {
+        let host = self.host.clone();
+        async move {
+            let address = host.to_socket_addrs()
+                .map_err(|_| TrackerError::UnknownHost(host.clone()))?
+                .next()
+                .ok_or(TrackerError::UnknownHost(host))?;
+            let local: SocketAddr = if address.is_ipv4() {
+                ([0, 0, 0, 0], 0).into()
+            } else {
+                ([0u16; 8], 0).into()
+            };
+            let socket = UdpSocket::bind(local).await?;
+            let transaction_id = rand::random();
+            let request = connect_request(transaction_id);
+            let reply = exchange(&socket, address, &request, transaction_id, attempts, timeout).await?;
+            let connection_id = parse_connect(&reply)?;
+            Ok((socket, address, connection_id))
+        }
+    }
+}
+
+impl Announcer for UdpAnnouncer {
+    fn announce(&self, announce: &Announce) -> BoxedFuture<TrackerResponse> {
+        let announce = announce.clone();
+        let url_data = self.url_data.clone();
+        let connect = self.connect(MAX_ATTEMPTS, RETRY_TIMEOUT);
+        async move {
+            let (socket, address, connection_id) = connect.await?;
+            let transaction_id = rand::random();
+            let request = announce_request(connection_id, transaction_id, &announce, &url_data);
+            let reply = exchange(&socket, address, &request, transaction_id, MAX_ATTEMPTS, RETRY_TIMEOUT).await?;
+            parse_announce(&reply, address.is_ipv6())
+        }.boxed()
+    }
+
+    fn scrape(&self, info_hash: [u8; 20]) -> BoxedFuture<ScrapeInfo> {
+        let connect = self.connect(MAX_ATTEMPTS, RETRY_TIMEOUT);
+        async move {
+            let (socket, address, connection_id) = connect.await?;
+            let transaction_id = rand::random();
+            let request = scrape_request(connection_id, transaction_id, &info_hash);
+            let reply = exchange(&socket, address, &request, transaction_id, MAX_ATTEMPTS, RETRY_TIMEOUT).await?;
+            parse_scrape(&reply)
+        }.boxed()
+    }
+}
+
+/// Sends a request until the answer with the same transaction id comes back, or the tracker has
+/// been sent it `attempts` times.  The first try waits `timeout`, and each retry twice as long as
+/// the last
+async fn exchange(socket: &UdpSocket, address: SocketAddr, request: &[u8], transaction_id: u32, attempts: u32,
+                  timeout: Duration) -> Result<Vec<u8>, TrackerError> {
+    let mut buf = [0; MAX_PACKET];
+    for attempt in 0..attempts {
+        socket.send_to(request, address).await?;
+        let deadline = Instant::now() + timeout * 2u32.pow(attempt);
+        while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (length, from) = received?;
+            // Anything else is a stray answer to an earlier try, or not from the tracker at all
+            if from == address && length >= 8 && NetworkEndian::read_u32(&buf[4..8]) == transaction_id {
+                return Ok(buf[..length].to_vec());
+            }
+        }
+    }
+    Err(TrackerError::Timeout)
+}
+
+fn connect_request(transaction_id: u32) -> Vec<u8> {
+    let mut request = vec![0; 16];
+    NetworkEndian::write_u64(&mut request[..8], PROTOCOL_ID);
+    NetworkEndian::write_u32(&mut request[8..12], ACTION_CONNECT);
+    NetworkEndian::write_u32(&mut request[12..], transaction_id);
+    request
+}
+
+fn announce_request(connection_id: u64, transaction_id: u32, announce: &Announce, url_data: &str) -> Vec<u8> {
+    let mut request = vec![0; 98];
+    NetworkEndian::write_u64(&mut request[..8], connection_id);
+    NetworkEndian::write_u32(&mut request[8..12], ACTION_ANNOUNCE);
+    NetworkEndian::write_u32(&mut request[12..16], transaction_id);
+    request[16..36].copy_from_slice(&announce.info_hash);
+    request[36..56].copy_from_slice(&announce.peer_id);
+    NetworkEndian::write_u64(&mut request[56..64], announce.downloaded);
+    NetworkEndian::write_u64(&mut request[64..72], announce.left);
+    NetworkEndian::write_u64(&mut request[72..80], announce.uploaded);
+    NetworkEndian::write_u32(&mut request[80..84], match announce.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    });
+    // 84..88 is our ip, which is left for the tracker to work out
+    NetworkEndian::write_u32(&mut request[88..92], announce.key);
+    NetworkEndian::write_u32(&mut request[92..96], announce.numwant);
+    NetworkEndian::write_u16(&mut request[96..], announce.port);
+    // Options can only carry 255 bytes each, so long urls take several
+    for chunk in url_data.as_bytes().chunks(255) {
+        request.push(OPTION_URL_DATA);
+        request.push(chunk.len() as u8);
+        request.extend_from_slice(chunk);
+    }
+    request
+}
+
+fn scrape_request(connection_id: u64, transaction_id: u32, info_hash: &[u8; 20]) -> Vec<u8> {
+    let mut request = vec![0; 16];
+    NetworkEndian::write_u64(&mut request[..8], connection_id);
+    NetworkEndian::write_u32(&mut request[8..12], ACTION_SCRAPE);
+    NetworkEndian::write_u32(&mut request[12..], transaction_id);
+    request.extend_from_slice(info_hash);
+    request
+}
+
+/// The action of a reply, and what follows the transaction id
+fn split_reply(reply: &[u8]) -> Result<(u32, &[u8]), TrackerError> {
+    if reply.len() < 8 {
+        return Err(TrackerError::InvalidResponse);
+    }
+    Ok((NetworkEndian::read_u32(&reply[..4]), &reply[8..]))
+}
+
+fn error_message(body: &[u8]) -> String {
+    String::from_utf8_lossy(body).into_owned()
+}
+
+fn parse_connect(reply: &[u8]) -> Result<u64, TrackerError> {
+    match split_reply(reply)? {
+        (ACTION_CONNECT, body) if body.len() >= 8 => Ok(NetworkEndian::read_u64(body)),
+        (ACTION_ERROR, body) => Err(TrackerError::Refused(error_message(body))),
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
+
+/// Peers come back in the compact form of the address family we asked over
+fn parse_announce(reply: &[u8], ipv6: bool) -> Result<TrackerResponse, TrackerError> {
+    match split_reply(reply)? {
+        (ACTION_ANNOUNCE, body) if body.len() >= 12 => {
+            let peers = if ipv6 {
+                PeerInfo::from_compact6(&body[12..])
+            } else {
+                PeerInfo::from_compact(&body[12..])
+            };
+            Ok(TrackerResponse::Success(TrackerSuccessResponse {
+                interval: NetworkEndian::read_u32(&body[..4]),
+                min_interval: None,
+                tracker_id: None,
+                incomplete: NetworkEndian::read_u32(&body[4..8]),
+                complete: NetworkEndian::read_u32(&body[8..12]),
+                peers,
+                external_ip: None,
+            }))
+        }
+        (ACTION_ERROR, body) => Ok(TrackerResponse::Failure(error_message(body))),
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
+
+fn parse_scrape(reply: &[u8]) -> Result<ScrapeInfo, TrackerError> {
+    match split_reply(reply)? {
+        (ACTION_SCRAPE, body) if body.len() >= 12 => Ok(ScrapeInfo {
+            complete: NetworkEndian::read_u32(&body[..4]),
+            downloaded: NetworkEndian::read_u32(&body[4..8]),
+            incomplete: NetworkEndian::read_u32(&body[8..12]),
+        }),
+        (ACTION_ERROR, body) => Err(TrackerError::Refused(error_message(body))),
+        _ => Err(TrackerError::InvalidResponse),
+    }
+}
@@ -0,0 +1,137 @@
+use std::net::UdpSocket as StdUdpSocket;
+use std::thread;
+use super::*;
+
+fn announce() -> Announce {
+    Announce {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        port: 6881,
+        uploaded: 3,
+        downloaded: 4,
+        left: 5,
+        event: Some(Event::Started),
+        key: 0xbeef,
+        numwant: 50,
+        tracker_id: None,
+        ipv6: None,
+    }
+}
+
+#[test]
+fn test_new() {
+    let announcer = UdpAnnouncer::new("udp://tracker.example:1337/announce?passkey=abc").unwrap();
+    assert_eq!(announcer.host, "tracker.example:1337");
+    assert_eq!(announcer.url_data, "/announce?passkey=abc");
+    assert_eq!(UdpAnnouncer::new("udp://tracker.example:1337").unwrap().url_data, "");
+    // Unlike http, there is no default port
+    assert!(UdpAnnouncer::new("udp://tracker.example/announce").is_err());
+    assert!(UdpAnnouncer::new("http://tracker.example:80/announce").is_err());
+}
+
+#[test]
+fn test_announce_request() {
+    let request = announce_request(0x1234, 7, &announce(), "/announce");
+    assert_eq!(NetworkEndian::read_u64(&request[..8]), 0x1234);
+    assert_eq!(NetworkEndian::read_u32(&request[8..12]), ACTION_ANNOUNCE);
+    assert_eq!(NetworkEndian::read_u32(&request[12..16]), 7);
+    assert_eq!(&request[16..36], &[1; 20]);
+    assert_eq!(&request[36..56], &[2; 20]);
+    assert_eq!(NetworkEndian::read_u64(&request[56..64]), 4);
+    assert_eq!(NetworkEndian::read_u64(&request[64..72]), 5);
+    assert_eq!(NetworkEndian::read_u64(&request[72..80]), 3);
+    assert_eq!(NetworkEndian::read_u32(&request[80..84]), 2);
+    assert_eq!(NetworkEndian::read_u32(&request[88..92]), 0xbeef);
+    assert_eq!(NetworkEndian::read_u32(&request[92..96]), 50);
+    assert_eq!(NetworkEndian::read_u16(&request[96..98]), 6881);
+    assert_eq!(&request[98..], b"\x02\x09/announce");
+    // Url data longer than one option can hold is split over several
+    let request = announce_request(0x1234, 7, &announce(), &"a".repeat(300));
+    assert_eq!(request.len(), 98 + 2 + 255 + 2 + 45);
+    assert_eq!(request[98 + 2 + 255 + 1], 45);
+}
+
+#[test]
+fn test_parse_announce() {
+    let mut reply = vec![0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 7, 8, 0, 0, 0, 3, 0, 0, 0, 9];
+    reply.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+    let response = match parse_announce(&reply, false).unwrap() {
+        TrackerResponse::Success(response) => response,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    assert_eq!((response.interval, response.incomplete, response.complete), (1800, 3, 9));
+    assert_eq!(response.peers.iter().map(|peer| peer.address).collect::<Vec<_>>(),
+               vec!["10.0.0.1:6881".parse().unwrap()]);
+
+    let mut error = vec![0, 0, 0, 3, 0, 0, 0, 7];
+    error.extend_from_slice(b"unregistered torrent");
+    assert_eq!(parse_announce(&error, false).unwrap(), TrackerResponse::Failure("unregistered torrent".to_owned()));
+    assert!(parse_announce(&reply[..12], false).is_err());
+}
+
+#[test]
+fn test_parse_connect_and_scrape() {
+    assert_eq!(parse_connect(&[0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0x12, 0x34]).unwrap(), 0x1234);
+    assert!(parse_connect(&[0, 0, 0, 0, 0, 0, 0, 7]).is_err());
+    assert_eq!(parse_scrape(&[0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0, 4, 0, 0, 0, 10, 0, 0, 0, 6]).unwrap(), ScrapeInfo {
+        complete: 4,
+        incomplete: 6,
+        downloaded: 10,
+    });
+}
+
+#[tokio::test]
+async fn test_announce() {
+    let tracker = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = tracker.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut buf = [0; 1024];
+        // The connect request
+        let (length, from) = tracker.recv_from(&mut buf).unwrap();
+        assert_eq!(length, 16);
+        assert_eq!(NetworkEndian::read_u64(&buf[..8]), PROTOCOL_ID);
+        let mut reply = vec![0; 16];
+        reply[4..8].copy_from_slice(&buf[12..16]);
+        NetworkEndian::write_u64(&mut reply[8..], 0x1234);
+        tracker.send_to(&reply, from).unwrap();
+        // The announce, which has to carry the connection id
+        let (length, from) = tracker.recv_from(&mut buf).unwrap();
+        assert!(length >= 98);
+        assert_eq!(NetworkEndian::read_u64(&buf[..8]), 0x1234);
+        let mut reply = vec![0, 0, 0, 1];
+        reply.extend_from_slice(&buf[12..16]);
+        reply.extend_from_slice(&[0, 0, 0, 60, 0, 0, 0, 1, 0, 0, 0, 2, 127, 0, 0, 1, 0x1a, 0xe1]);
+        tracker.send_to(&reply, from).unwrap();
+    });
+
+    let announcer = UdpAnnouncer::new(&format!("udp://{}/announce", address)).unwrap();
+    let response = announcer.announce(&announce()).await.unwrap();
+    server.join().unwrap();
+    match response {
+        TrackerResponse::Success(response) => {
+            assert_eq!((response.interval, response.incomplete, response.complete), (60, 1, 2));
+            assert_eq!(response.peers[0].address, "127.0.0.1:6881".parse().unwrap());
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_ping() {
+    let tracker = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = tracker.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut buf = [0; 1024];
+        let (_length, from) = tracker.recv_from(&mut buf).unwrap();
+        let mut reply = vec![0; 16];
+        reply[4..8].copy_from_slice(&buf[12..16]);
+        tracker.send_to(&reply, from).unwrap();
+        // Nothing answers the second ping
+        tracker.recv_from(&mut buf).unwrap();
+    });
+
+    let announcer = UdpAnnouncer::new(&format!("udp://{}", address)).unwrap();
+    assert_eq!(announcer.ping(Duration::from_secs(5)).await.unwrap(), address);
+    assert!(matches!(announcer.ping(Duration::from_millis(50)).await, Err(TrackerError::Timeout)));
+    server.join().unwrap();
+}
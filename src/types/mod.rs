@@ -0,0 +1,111 @@
+//! types holds the representation of peers shared by everything that finds them.  Trackers, the
+//! DHT and peer exchange all hand out peer addresses in the compact model, and trackers may also
+//! use the older dictionary model, so they all parse into the same PeerInfo.
+use crate::boostencode::{
+    FromValue,
+    Value,
+};
+use std::net::{
+    IpAddr,
+    SocketAddr,
+};
+
+#[cfg(test)]
+mod test;
+
+/// A peer we could connect to
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct PeerInfo {
+    // the unique identifier for this peer.  Only the dictionary model has it
+    pub peer_id: Option<[u8; 20]>,
+    // The ip/port of this peer
+    pub address: SocketAddr,
+}
+
+impl PeerInfo {
+    /// Parse the compact model of IPv4 peers, 4 bytes of address then 2 of port for each peer
+    pub fn from_compact(bytes: &[u8]) -> Vec<PeerInfo> {
+        decode_compact(bytes, 4).into_iter().map(PeerInfo::from).collect()
+    }
+
+    /// Parse the compact model of IPv6 peers from BEP 7, 16 bytes of address then 2 of port
+    pub fn from_compact6(bytes: &[u8]) -> Vec<PeerInfo> {
+        decode_compact(bytes, 16).into_iter().map(PeerInfo::from).collect()
+    }
+
+    /// The peer in the compact model, 6 bytes for IPv4 peers and 18 for IPv6 ones
+    pub fn to_compact(&self) -> Vec<u8> {
+        encode_compact(self.address)
+    }
+}
+
+impl From<SocketAddr> for PeerInfo {
+    fn from(address: SocketAddr) -> Self {
+        PeerInfo {
+            peer_id: None,
+            address,
+        }
+    }
+}
+
+impl FromValue for PeerInfo {
+    type Error = String;
+
+    /// Parse the dictionary model of a peer
+    fn from_value(val: &Value) -> Result<Self, Self::Error> {
+        let map = val.dict().ok_or("Not a dictionary".to_string())?;
+
+        // Left out when we ask with no_peer_id
+        let peer_id = match map.get("peer id".as_bytes()).and_then(Value::bstring) {
+            Some(bytes) if bytes.len() == 20 => {
+                let mut peer_id: [u8; 20] = [0; 20];
+                peer_id.copy_from_slice(bytes);
+                Some(peer_id)
+            }
+            Some(_) => return Err("Invalid peer id".to_string()),
+            None => None,
+        };
+
+        let ip = map.get("ip".as_bytes()).and_then(Value::bstring_utf8)
+            .map(|s| s.parse())
+            .ok_or("Missing key: ip".to_string())?
+            .map_err(|_| "Invalid ip addr".to_string())?;
+
+        let port = map.get("port".as_bytes()).and_then(Value::integer)
+            .map(|i| *i as u16)
+            .ok_or("Missing key: port".to_string())?;
+
+        Ok(PeerInfo {
+            peer_id,
+            address: SocketAddr::new(ip, port),
+        })
+    }
+}
+
+/// Encode an address in the compact model: the ip's octets, then the port in big endian
+pub fn encode_compact(address: SocketAddr) -> Vec<u8> {
+    let mut res = match address.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    res.extend_from_slice(&address.port().to_be_bytes());
+    res
+}
+
+/// Decode compact addresses with `ip_len` byte ips.  A partial entry at the end is ignored
+pub fn decode_compact(bytes: &[u8], ip_len: usize) -> Vec<SocketAddr> {
+    bytes.chunks_exact(ip_len + 2).map(|chunk| {
+        let ip: IpAddr = if ip_len == 4 {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&chunk[..4]);
+            octets.into()
+        } else {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&chunk[..16]);
+            octets.into()
+        };
+        // port is in big endian.  multiply instead of bitshift so you can't mess up endianness
+        let port = (chunk[ip_len] as u16 * 256) + chunk[ip_len + 1] as u16;
+        SocketAddr::new(ip, port)
+    }).collect()
+}
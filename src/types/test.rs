@@ -0,0 +1,53 @@
+use maplit::hashmap;
+use super::*;
+
+#[test]
+fn test_compact_round_trip() {
+    let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+    let v6: SocketAddr = "[2001::1]:6881".parse().unwrap();
+    assert_eq!(encode_compact(v4), vec![10, 0, 0, 1, 0x1a, 0xe1]);
+    assert_eq!(PeerInfo::from_compact(&encode_compact(v4)), vec![PeerInfo::from(v4)]);
+    assert_eq!(encode_compact(v6).len(), 18);
+    assert_eq!(PeerInfo::from_compact6(&PeerInfo::from(v6).to_compact()), vec![PeerInfo::from(v6)]);
+}
+
+#[test]
+fn test_compact_ignores_partial_entries() {
+    assert_eq!(PeerInfo::from_compact(&[10, 0, 0, 1, 0x1a, 0xe1, 10]).len(), 1);
+    assert!(PeerInfo::from_compact6(&[0; 17]).is_empty());
+}
+
+#[test]
+fn test_peer_info_from_value() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("peer id") => Value::BString(vec![1; 20]),
+        Vec::from("ip") => Value::BString(Vec::from("::1")),
+        Vec::from("port") => Value::Integer(6881),
+    });
+    assert_eq!(PeerInfo::from_value(&val), Ok(PeerInfo {
+        peer_id: Some([1; 20]),
+        address: "[::1]:6881".parse().unwrap(),
+    }));
+}
+
+#[test]
+fn test_peer_info_without_peer_id() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("ip") => Value::BString(Vec::from("127.0.0.1")),
+        Vec::from("port") => Value::Integer(6881),
+    });
+    assert_eq!(PeerInfo::from_value(&val), Ok(PeerInfo {
+        peer_id: None,
+        address: ([127, 0, 0, 1], 6881).into(),
+    }));
+}
+
+#[test]
+fn test_peer_info_bad_peer_id() {
+    let val = Value::Dict(hashmap! {
+        Vec::from("peer id") => Value::BString(vec![1; 4]),
+        Vec::from("ip") => Value::BString(Vec::from("127.0.0.1")),
+        Vec::from("port") => Value::Integer(6881),
+    });
+    assert!(PeerInfo::from_value(&val).is_err());
+}
@@ -0,0 +1,150 @@
+//! webhook tells other programs about things that happen in the client by POSTing JSON to urls
+//! the user gives us
+use hyper::{
+    Body,
+    Client,
+    client::HttpConnector,
+    Method,
+    Request,
+    Uri,
+};
+use log::warn;
+use std::fmt::Write;
+use std::str::FromStr;
+use tokio::spawn;
+
+#[cfg(test)]
+mod test;
+
+/// The kinds of event a webhook can be told about
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EventKind {
+    TorrentAdded,
+    Completed,
+    Error,
+    TrackerFailure,
+    Md5Mismatch,
+    SeedLimitReached,
+}
+
+impl EventKind {
+    pub fn all() -> Vec<EventKind> {
+        vec![EventKind::TorrentAdded, EventKind::Completed, EventKind::Error, EventKind::TrackerFailure,
+             EventKind::Md5Mismatch, EventKind::SeedLimitReached]
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EventKind::TorrentAdded => "torrent-added",
+            EventKind::Completed => "completed",
+            EventKind::Error => "error",
+            EventKind::TrackerFailure => "tracker-failure",
+            EventKind::Md5Mismatch => "md5-mismatch",
+            EventKind::SeedLimitReached => "seed-limit-reached",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        EventKind::all().into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or(format!("Unknown webhook event: {}", s))
+    }
+}
+
+/// Something that happened to a torrent
+#[derive(Debug, PartialEq, Clone)]
+pub struct Notification {
+    pub kind: EventKind,
+    // The name of the torrent
+    pub torrent: String,
+    pub info_hash: [u8; 20],
+    // More detail, like an error message
+    pub message: Option<String>,
+}
+
+impl Notification {
+    pub fn to_json(&self) -> String {
+        let info_hash = self.info_hash.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        let mut json = format!("{{\"event\":\"{}\",\"torrent\":{},\"info_hash\":\"{}\"",
+                               self.kind.name(), json_string(&self.torrent), info_hash);
+        if let Some(message) = &self.message {
+            let _ = write!(json, ",\"message\":{}", json_string(message));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// The webhooks the user set up, and which events they want
+pub struct Webhooks {
+    urls: Vec<Uri>,
+    events: Vec<EventKind>,
+    client: Client<HttpConnector>,
+}
+
+impl Webhooks {
+    pub fn new(urls: Vec<Uri>, events: Vec<EventKind>) -> Self {
+        Webhooks {
+            urls,
+            events,
+            client: Client::new(),
+        }
+    }
+
+    /// POST the notification to every webhook, if they asked for this kind of event.  This must
+    /// be called from inside a tokio task.  Failures are logged and otherwise ignored.
+    pub fn notify(&self, notification: Notification) {
+        if self.urls.is_empty() || !self.events.contains(&notification.kind) {
+            return;
+        }
+        let json = notification.to_json();
+        for url in &self.urls {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url.clone())
+                .header("Content-Type", "application/json")
+                .body(Body::from(json.clone()));
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("Could not build webhook request for {}: {}", url, e);
+                    continue;
+                }
+            };
+            let url = url.clone();
+            let response = self.client.request(request);
+            spawn(async move {
+                if let Err(e) = response.await {
+                    warn!("Webhook {} failed: {}", url, e);
+                }
+            });
+        }
+    }
+}
+
+/// Quote and escape a string for JSON
+pub fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(res, "\\u{:04x}", c as u32);
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
@@ -0,0 +1,25 @@
+use super::*;
+
+#[test]
+fn test_to_json() {
+    let notification = Notification {
+        kind: EventKind::TrackerFailure,
+        torrent: "my \"file\"".to_string(),
+        info_hash: [0xab; 20],
+        message: Some("line1\nline2".to_string()),
+    };
+    assert_eq!(notification.to_json(),
+               "{\"event\":\"tracker-failure\",\"torrent\":\"my \\\"file\\\"\",\
+               \"info_hash\":\"abababababababababababababababababababab\",\"message\":\"line1\\nline2\"}");
+}
+
+#[test]
+fn test_json_string_control_characters() {
+    assert_eq!(json_string("a\u{1}b"), "\"a\\u0001b\"");
+}
+
+#[test]
+fn test_parse_event_kind() {
+    assert_eq!("completed".parse(), Ok(EventKind::Completed));
+    assert!("finished".parse::<EventKind>().is_err());
+}